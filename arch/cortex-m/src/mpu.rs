@@ -317,6 +317,27 @@ impl CortexMRegion {
         }
     }
 
+    /// A region covering `[start, start + size)` that is only accessible in
+    /// privileged mode. `size` must be a power of two of at least 32 bytes,
+    /// and `start` a multiple of it.
+    fn guard(start: *const u8, size: usize, region_num: usize) -> CortexMRegion {
+        let base_address = RegionBaseAddress::ADDR.val((start as u32) >> 5)
+            + RegionBaseAddress::VALID::UseRBAR
+            + RegionBaseAddress::REGION.val(region_num as u32);
+
+        let size_value = math::log_base_two(size as u32) - 1;
+        let attributes = RegionAttributes::ENABLE::SET
+            + RegionAttributes::SIZE.val(size_value)
+            + RegionAttributes::AP::PrivilegedOnly
+            + RegionAttributes::XN::Disable;
+
+        CortexMRegion {
+            location: Some((start, size)),
+            base_address: base_address,
+            attributes: attributes,
+        }
+    }
+
     fn empty(region_num: usize) -> CortexMRegion {
         CortexMRegion {
             location: None,
@@ -511,6 +532,40 @@ impl<const NUM_REGIONS: usize> kernel::mpu::MPU for MPU<NUM_REGIONS> {
         Some(mpu::Region::new(start as *const u8, size))
     }
 
+    fn allocate_guard_region(
+        &self,
+        memory_start: *const u8,
+        region_end: *const u8,
+        min_region_size: usize,
+        config: &mut Self::MpuConfig,
+    ) -> Option<mpu::Region> {
+        // Regions must be a power of two of at least 32 bytes, aligned to
+        // their size. Higher numbered regions take precedence, so any region
+        // other than `APP_MEMORY_REGION_NUM` overrides app memory.
+        let size = cmp::max(
+            math::closest_power_of_two(min_region_size as u32) as usize,
+            32,
+        );
+        let end = region_end as usize;
+        if end % size != 0 || end < size || end - size < memory_start as usize {
+            return None;
+        }
+        let start = end - size;
+
+        // Apart from app memory, the guard may not overlap other regions.
+        for (number, region) in config.regions.iter().enumerate() {
+            if number != APP_MEMORY_REGION_NUM && region.overlaps(start as *const u8, size) {
+                return None;
+            }
+        }
+
+        let region_num = config.unused_region_number()?;
+        config.regions[region_num] = CortexMRegion::guard(start as *const u8, size, region_num);
+        config.is_dirty.set(true);
+
+        Some(mpu::Region::new(start as *const u8, size))
+    }
+
     fn allocate_app_memory_region(
         &self,
         unallocated_memory_start: *const u8,
@@ -702,3 +757,103 @@ impl<const NUM_REGIONS: usize> kernel::mpu::MPU for MPU<NUM_REGIONS> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::mpu::MPU as _;
+
+    const MEMORY_START: usize = 0x2000_0000;
+
+    /// A config with app memory allocated at `MEMORY_START`.
+    fn app_config(mpu: &MPU<8>) -> CortexMConfig<8> {
+        let mut config = CortexMConfig::default();
+        let (start, _) = mpu
+            .allocate_app_memory_region(
+                MEMORY_START as *const u8,
+                0x10000,
+                0x1000,
+                0x800,
+                0x400,
+                mpu::Permissions::ReadWriteOnly,
+                &mut config,
+            )
+            .unwrap();
+        assert_eq!(start as usize, MEMORY_START);
+        config
+    }
+
+    #[test]
+    fn guard_region_below_stack() {
+        let mpu = unsafe { MPU::<8>::new() };
+        let mut config = app_config(&mpu);
+
+        let guard = mpu
+            .allocate_guard_region(
+                MEMORY_START as *const u8,
+                (MEMORY_START + 0x400) as *const u8,
+                32,
+                &mut config,
+            )
+            .unwrap();
+        assert_eq!(guard.start_address() as usize, MEMORY_START + 0x3e0);
+        assert_eq!(guard.size(), 32);
+
+        // The guard takes a region numbered above app memory, so it takes
+        // precedence, and the app cannot access it.
+        let region = &config.regions[1];
+        assert_eq!(
+            region.location(),
+            Some(((MEMORY_START + 0x3e0) as *const u8, 32))
+        );
+        assert_eq!(region.attributes().read(RegionAttributes::AP), 0b001);
+        assert_eq!(
+            config.regions[APP_MEMORY_REGION_NUM].location().unwrap().0 as usize,
+            MEMORY_START
+        );
+    }
+
+    #[test]
+    fn guard_region_rejected() {
+        let mpu = unsafe { MPU::<8>::new() };
+        let mut config = app_config(&mpu);
+        let memory_start = MEMORY_START as *const u8;
+
+        // Not aligned to the region size.
+        assert!(mpu
+            .allocate_guard_region(
+                memory_start,
+                (MEMORY_START + 0x410) as *const u8,
+                32,
+                &mut config
+            )
+            .is_none());
+        // Would start below the lowest address it may cover.
+        assert!(mpu
+            .allocate_guard_region(
+                (MEMORY_START + 0x20) as *const u8,
+                (MEMORY_START + 0x40) as *const u8,
+                64,
+                &mut config
+            )
+            .is_none());
+        // Overlaps another guard.
+        assert!(mpu
+            .allocate_guard_region(
+                memory_start,
+                (MEMORY_START + 0x400) as *const u8,
+                32,
+                &mut config
+            )
+            .is_some());
+        assert!(mpu
+            .allocate_guard_region(
+                memory_start,
+                (MEMORY_START + 0x400) as *const u8,
+                64,
+                &mut config
+            )
+            .is_none());
+        assert!(config.regions[2].location().is_none());
+    }
+}
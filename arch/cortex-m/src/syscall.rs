@@ -5,6 +5,8 @@ use core::fmt::Write;
 use core::mem;
 use core::ptr::{read_volatile, write_volatile};
 
+use kernel::ErrorCode;
+
 /// This is used in the syscall handler. When set to 1 this means the
 /// svc_handler was called. Marked `pub` because it is used in the cortex-m*
 /// specific handler.
@@ -344,4 +346,95 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
             },
         ));
     }
+
+    unsafe fn core_registers(
+        &self,
+        accessible_memory_start: *const u8,
+        app_brk: *const u8,
+        state: &CortexMStoredState,
+    ) -> Option<kernel::syscall::CoreRegisters> {
+        // Validate the stored stack pointer is valid.
+        if state.psp < accessible_memory_start as usize
+            || (state.psp + SVC_FRAME_SIZE) > app_brk as usize
+        {
+            return None;
+        }
+
+        // The hardware stacked r0-r3, r12, lr, pc and xPSR; the kernel saved
+        // r4-r11 in the stored state.
+        let stack_pointer = state.psp as *const usize;
+        let frame = |i| read_volatile(stack_pointer.offset(i)) as u32;
+        let xpsr = frame(7);
+        // Bit 9 of the stacked xPSR is set if the hardware aligned the stack
+        // before stacking the frame.
+        let sp = state.psp + SVC_FRAME_SIZE + if xpsr & (1 << 9) != 0 { 4 } else { 0 };
+
+        // ARM `elf_gregset_t`: r0-r15, cpsr, orig_r0.
+        let mut registers = [0; kernel::syscall::MAX_CORE_REGISTERS];
+        registers[0] = frame(0);
+        registers[1] = frame(1);
+        registers[2] = frame(2);
+        registers[3] = frame(3);
+        for i in 0..8 {
+            registers[4 + i] = state.regs[i] as u32;
+        }
+        registers[12] = frame(4);
+        registers[13] = sp as u32;
+        registers[14] = frame(5);
+        registers[15] = frame(6);
+        registers[16] = xpsr;
+        registers[17] = frame(0);
+        Some(kernel::syscall::CoreRegisters {
+            machine: 40, // EM_ARM
+            registers,
+            count: 18,
+        })
+    }
+
+    unsafe fn set_core_registers(
+        &self,
+        accessible_memory_start: *const u8,
+        app_brk: *const u8,
+        state: &mut CortexMStoredState,
+        registers: &kernel::syscall::CoreRegisters,
+    ) -> Result<(), ErrorCode> {
+        if registers.machine != 40 || registers.count < 17 {
+            return Err(ErrorCode::INVAL);
+        }
+        // Validate the stored stack pointer is valid.
+        if state.psp < accessible_memory_start as usize
+            || (state.psp + SVC_FRAME_SIZE) > app_brk as usize
+        {
+            return Err(ErrorCode::FAIL);
+        }
+
+        let stack_pointer = state.psp as *mut usize;
+        let xpsr = read_volatile(stack_pointer.offset(7));
+        let alignment = xpsr & (1 << 9);
+        let sp = state.psp + SVC_FRAME_SIZE + if alignment != 0 { 4 } else { 0 };
+        // The hardware frame sits right below the stack pointer, so moving
+        // the stack pointer would mean moving the frame.
+        if registers.registers[13] as usize != sp {
+            return Err(ErrorCode::INVAL);
+        }
+
+        let r = |i: usize| registers.registers[i] as usize;
+        write_volatile(stack_pointer.offset(0), r(0));
+        write_volatile(stack_pointer.offset(1), r(1));
+        write_volatile(stack_pointer.offset(2), r(2));
+        write_volatile(stack_pointer.offset(3), r(3));
+        for i in 0..8 {
+            state.regs[i] = r(4 + i);
+        }
+        write_volatile(stack_pointer.offset(4), r(12));
+        write_volatile(stack_pointer.offset(5), r(14));
+        write_volatile(stack_pointer.offset(6), r(15) & !1);
+        // Keep the alignment bit the hardware uses to restore the stack
+        // pointer, and the Thumb bit without which the process faults.
+        let xpsr = (r(16) & !(1 << 9)) | alignment | (1 << 24);
+        write_volatile(stack_pointer.offset(7), xpsr);
+        state.yield_pc = r(15) & !1;
+        state.psr = xpsr;
+        Ok(())
+    }
 }
@@ -176,6 +176,15 @@ impl PMPRegion {
         }
     }
 
+    /// A region covering `[start, start + size)` that user mode cannot
+    /// access.
+    fn guard(start: *const u8, size: usize) -> PMPRegion {
+        PMPRegion {
+            location: (start, size),
+            cfg: pmpcfg::r::CLEAR + pmpcfg::w::CLEAR + pmpcfg::x::CLEAR + pmpcfg::a::TOR,
+        }
+    }
+
     fn location(&self) -> (*const u8, usize) {
         self.location
     }
@@ -407,6 +416,53 @@ impl<const MAX_AVAILABLE_REGIONS_OVER_TWO: usize> kernel::mpu::MPU
         Some(mpu::Region::new(start as *const u8, size))
     }
 
+    fn allocate_guard_region(
+        &self,
+        memory_start: *const u8,
+        region_end: *const u8,
+        min_region_size: usize,
+        config: &mut Self::MpuConfig,
+    ) -> Option<mpu::Region> {
+        // TOR regions are aligned to 4 bytes and at least 8 bytes long.
+        let mut size = cmp::max(min_region_size, 8);
+        if size % 4 != 0 {
+            size += 4 - (size % 4);
+        }
+        let end = region_end as usize;
+        if end % 4 != 0 || end < size || end - size < memory_start as usize {
+            return None;
+        }
+        let start = end - size;
+
+        // Apart from app memory, the guard may not overlap other regions.
+        for (number, region) in config.regions.iter().enumerate() {
+            if let Some(region) = region {
+                if !config.app_memory_region.contains(&number)
+                    && region.overlaps(start as *const u8, size)
+                {
+                    return None;
+                }
+            }
+        }
+
+        // The lowest numbered matching entry takes precedence, so the guard
+        // must come before app memory. If the free entry is after it, move
+        // app memory there and put the guard in its place.
+        let mut region_num = config.unused_region_number(self.locked_region_mask.get())?;
+        if let Some(app_region_num) = config.app_memory_region.extract() {
+            if app_region_num < region_num {
+                config.regions[region_num] = config.regions[app_region_num];
+                config.app_memory_region.set(region_num);
+                region_num = app_region_num;
+            }
+        }
+
+        config.regions[region_num] = Some(PMPRegion::guard(start as *const u8, size));
+        config.is_dirty.set(true);
+
+        Some(mpu::Region::new(start as *const u8, size))
+    }
+
     fn allocate_app_memory_region(
         &self,
         unallocated_memory_start: *const u8,
@@ -694,3 +750,109 @@ impl<const MAX_AVAILABLE_REGIONS_OVER_TWO: usize> kernel::mpu::KernelMPU
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::mpu::MPU as _;
+
+    const MEMORY_START: usize = 0x8000_4000;
+
+    /// A PMP with every region available, without probing the hardware.
+    fn pmp() -> PMP<8> {
+        PMP {
+            last_configured_for: MapCell::empty(),
+            locked_region_mask: Cell::new(0),
+            num_regions: 16,
+        }
+    }
+
+    /// A config with app memory allocated at `MEMORY_START`.
+    fn app_config(pmp: &PMP<8>) -> PMPConfig<8> {
+        let mut config = PMPConfig::default();
+        pmp.allocate_app_memory_region(
+            MEMORY_START as *const u8,
+            0x10000,
+            0x1000,
+            0x800,
+            0x400,
+            mpu::Permissions::ReadWriteOnly,
+            &mut config,
+        )
+        .unwrap();
+        config
+    }
+
+    #[test]
+    fn guard_region_below_stack() {
+        let pmp = pmp();
+        let mut config = app_config(&pmp);
+        assert!(config.app_memory_region.contains(&0));
+
+        let guard = pmp
+            .allocate_guard_region(
+                MEMORY_START as *const u8,
+                (MEMORY_START + 0x204) as *const u8,
+                32,
+                &mut config,
+            )
+            .unwrap();
+        assert_eq!(guard.start_address() as usize, MEMORY_START + 0x1e4);
+        assert_eq!(guard.size(), 32);
+
+        // The lowest matching entry takes precedence, so app memory moves
+        // past the guard, which the app cannot access.
+        assert!(config.app_memory_region.contains(&1));
+        let region = config.regions[0].unwrap();
+        assert_eq!(region.location(), ((MEMORY_START + 0x1e4) as *const u8, 32));
+        assert_eq!(u8::from(region.cfg) & 0b111, 0);
+        assert_eq!(
+            config.regions[1].unwrap().location().0 as usize,
+            MEMORY_START
+        );
+    }
+
+    #[test]
+    fn guard_region_rejected() {
+        let pmp = pmp();
+        let mut config = app_config(&pmp);
+        let memory_start = MEMORY_START as *const u8;
+
+        // Not aligned to 4 bytes.
+        assert!(pmp
+            .allocate_guard_region(
+                memory_start,
+                (MEMORY_START + 0x202) as *const u8,
+                32,
+                &mut config
+            )
+            .is_none());
+        // Would start below the lowest address it may cover.
+        assert!(pmp
+            .allocate_guard_region(
+                memory_start,
+                (MEMORY_START + 0x10) as *const u8,
+                32,
+                &mut config
+            )
+            .is_none());
+        // Overlaps another guard.
+        assert!(pmp
+            .allocate_guard_region(
+                memory_start,
+                (MEMORY_START + 0x200) as *const u8,
+                32,
+                &mut config
+            )
+            .is_some());
+        assert!(pmp
+            .allocate_guard_region(
+                memory_start,
+                (MEMORY_START + 0x210) as *const u8,
+                32,
+                &mut config
+            )
+            .is_none());
+        assert!(config.regions[2].is_none());
+    }
+}
@@ -507,4 +507,36 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
             state.mtval,
         ));
     }
+
+    unsafe fn core_registers(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &Riscv32iStoredState,
+    ) -> Option<kernel::syscall::CoreRegisters> {
+        // RISC-V `elf_gregset_t`: pc, then x1-x31.
+        let mut registers = [0; kernel::syscall::MAX_CORE_REGISTERS];
+        registers[0] = state.pc;
+        registers[1..].copy_from_slice(&state.regs);
+        Some(kernel::syscall::CoreRegisters {
+            machine: 243, // EM_RISCV
+            registers,
+            count: 32,
+        })
+    }
+
+    unsafe fn set_core_registers(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut Riscv32iStoredState,
+        registers: &kernel::syscall::CoreRegisters,
+    ) -> Result<(), kernel::ErrorCode> {
+        if registers.machine != 243 || registers.count < 32 {
+            return Err(kernel::ErrorCode::INVAL);
+        }
+        state.pc = registers.registers[0];
+        state.regs.copy_from_slice(&registers.registers[1..32]);
+        Ok(())
+    }
 }
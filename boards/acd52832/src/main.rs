@@ -275,8 +275,12 @@ pub unsafe fn main() {
 
     // RTT communication channel
     let rtt_memory = components::segger_rtt::SeggerRttMemoryComponent::new().finalize(());
-    let rtt = components::segger_rtt::SeggerRttComponent::new(mux_alarm, rtt_memory)
-        .finalize(components::segger_rtt_component_helper!(nrf52832::rtc::Rtc));
+    let rtt = components::segger_rtt::SeggerRttComponent::new(
+        mux_alarm,
+        rtt_memory,
+        capsules::segger_rtt::DEBUG_CHANNEL,
+    )
+    .finalize(components::segger_rtt_component_helper!(nrf52832::rtc::Rtc));
 
     //
     // Virtual UART
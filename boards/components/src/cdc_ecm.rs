@@ -0,0 +1,99 @@
+//! Component for an Ethernet interface over USB (CDC-ECM).
+//!
+//! This provides a component for presenting a USB network interface to the
+//! host. The returned device is an `EthernetDevice`, normally handed to
+//! `capsules::net::ipv6::ipv6_bridge::IP6Bridge` to route between the host
+//! and the 6LoWPAN network.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 4] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "Border Router",  // Product
+//!     "Serial No. 5",   // Serial number
+//!     "02000000000a",   // Host MAC address
+//! ];
+//! let ecm = components::cdc_ecm::CdcEcmComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x2341,
+//!     0x005c,
+//!     STRINGS,
+//!     EthernetAddress([0x02, 0, 0, 0, 0, 0x0b]),
+//! )
+//! .finalize(components::cdc_ecm_component_helper!(nrf52::usbd::Usbd));
+//! ecm.enable();
+//! ecm.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::net::ethernet::EthernetAddress;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! cdc_ecm_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<capsules::usb::cdc_ecm::CdcEcm<'static, $U>> =
+            MaybeUninit::uninit();
+        &mut BUF1
+    };};
+}
+
+pub struct CdcEcmComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 4],
+    mac_address: EthernetAddress,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> CdcEcmComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 4],
+        mac_address: EthernetAddress,
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            mac_address,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for CdcEcmComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<capsules::usb::cdc_ecm::CdcEcm<'static, U>>;
+    type Output = &'static capsules::usb::cdc_ecm::CdcEcm<'static, U>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let ecm = static_init_half!(
+            s,
+            capsules::usb::cdc_ecm::CdcEcm<'static, U>,
+            capsules::usb::cdc_ecm::CdcEcm::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                self.mac_address,
+                &mut capsules::usb::cdc_ecm::RX_BUFFER,
+            )
+        );
+        self.usb.set_client(ecm);
+
+        ecm
+    }
+}
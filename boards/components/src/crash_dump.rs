@@ -0,0 +1,60 @@
+//! Component for crash dumps of kernel panics.
+//!
+//! This provides one Component, CrashDumpComponent, which reserves the crash
+//! dump area that kernel panics are saved into and creates the reporter that
+//! prints the dump of a panic from before the last reset over a UART.
+//!
+//! The area should be placed in the `.crash_dump` section of the generic
+//! linker script, which is not zeroed at boot.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! #[link_section = ".crash_dump"]
+//! static mut CRASH_DUMP_AREA: [u8; 2048] = [0; 2048];
+//!
+//! let crash_dump = CrashDumpComponent::new(uart_mux, &mut CRASH_DUMP_AREA).finalize(());
+//! let _ = crash_dump.report();
+//! ```
+
+use capsules::crash_dump::{CrashDumpReporter, WRITE_BUF};
+use capsules::virtual_uart::{MuxUart, UartDevice};
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init;
+
+pub struct CrashDumpComponent {
+    uart_mux: &'static MuxUart<'static>,
+    area: &'static mut [u8],
+}
+
+impl CrashDumpComponent {
+    pub fn new(uart_mux: &'static MuxUart, area: &'static mut [u8]) -> CrashDumpComponent {
+        CrashDumpComponent { uart_mux, area }
+    }
+}
+
+/// Top of the kernel stack, defined in the linker script.
+extern "C" {
+    static _estack: u8;
+}
+
+impl Component for CrashDumpComponent {
+    type StaticInput = ();
+    type Output = &'static CrashDumpReporter<'static>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        kernel::crash_dump::set_area(self.area, &_estack as *const u8);
+
+        let uart = static_init!(UartDevice, UartDevice::new(self.uart_mux, false));
+        uart.setup();
+
+        let reporter = static_init!(
+            CrashDumpReporter<'static>,
+            CrashDumpReporter::new(uart, &mut WRITE_BUF)
+        );
+        hil::uart::Transmit::set_transmit_client(uart, reporter);
+
+        reporter
+    }
+}
@@ -0,0 +1,76 @@
+//! Component for the GDB remote protocol stub.
+//!
+//! This provides one Component, GdbStubComponent, which lets gdb debug a
+//! single process over a UART, typically one not shared with the console,
+//! such as a second UART or USB CDC. Breakpoints are written to flash
+//! through `flash`, which must cover the flash the processes are stored in.
+//!
+//! The board must also pass process faults to the stub from its
+//! `Platform::process_fault_hook()` implementation.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let gdb_stub = GdbStubComponent::new(board_kernel, cdc_mux, nv_to_page).finalize(());
+//! let _ = gdb_stub.start();
+//! ```
+
+use capsules::gdb_stub::{self, GdbStub};
+use capsules::virtual_uart::{MuxUart, UartDevice};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init;
+
+pub struct GdbStubComponent {
+    board_kernel: &'static kernel::Kernel,
+    uart_mux: &'static MuxUart<'static>,
+    flash: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+}
+
+impl GdbStubComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        uart_mux: &'static MuxUart,
+        flash: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    ) -> GdbStubComponent {
+        GdbStubComponent {
+            board_kernel,
+            uart_mux,
+            flash,
+        }
+    }
+}
+
+pub struct Capability;
+unsafe impl capabilities::ProcessManagementCapability for Capability {}
+
+impl Component for GdbStubComponent {
+    type StaticInput = ();
+    type Output = &'static GdbStub<'static, Capability>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let uart = static_init!(UartDevice, UartDevice::new(self.uart_mux, true));
+        uart.setup();
+
+        let stub = static_init!(
+            GdbStub<'static, Capability>,
+            GdbStub::new(
+                uart,
+                self.flash,
+                &mut gdb_stub::RX_BUF,
+                &mut gdb_stub::PACKET_BUF,
+                &mut gdb_stub::OUT_BUF,
+                &mut gdb_stub::TX_BUF,
+                &mut gdb_stub::FLASH_BUF,
+                self.board_kernel,
+                Capability,
+            )
+        );
+        hil::uart::Transmit::set_transmit_client(uart, stub);
+        hil::uart::Receive::set_receive_client(uart, stub);
+        self.flash.set_client(stub);
+
+        stub
+    }
+}
@@ -29,7 +29,7 @@ use kernel::capabilities;
 use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::component::Component;
 use kernel::hil::radio;
-use kernel::hil::symmetric_encryption::{self, AES128Ctr, AES128, AES128CBC, AES128CCM};
+use kernel::hil::symmetric_encryption::{self, AES128Ctr, AES128, AES128CBC, AES128CCM, AES128ECB};
 use kernel::{create_capability, static_init, static_init_half};
 
 // Setup static space for the objects.
@@ -56,7 +56,7 @@ macro_rules! ieee802154_component_helper {
 
 pub struct Ieee802154Component<
     R: 'static + kernel::hil::radio::Radio,
-    A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
+    A: 'static + AES128<'static> + AES128Ctr + AES128CBC + AES128ECB,
 > {
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
@@ -69,7 +69,7 @@ pub struct Ieee802154Component<
 
 impl<
         R: 'static + kernel::hil::radio::Radio,
-        A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
+        A: 'static + AES128<'static> + AES128Ctr + AES128CBC + AES128ECB,
     > Ieee802154Component<R, A>
 {
     pub fn new(
@@ -105,7 +105,7 @@ static mut CRYPT_BUF: [u8; CRYPT_SIZE] = [0x00; CRYPT_SIZE];
 
 impl<
         R: 'static + kernel::hil::radio::Radio,
-        A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
+        A: 'static + AES128<'static> + AES128Ctr + AES128CBC + AES128ECB,
     > Component for Ieee802154Component<R, A>
 {
     type StaticInput = (
@@ -154,7 +154,7 @@ impl<
             >,
             capsules::ieee802154::framer::Framer::new(awake_mac, aes_ccm)
         );
-        aes_ccm.set_client(mac_device);
+        AES128CCM::set_client(aes_ccm, mac_device);
         awake_mac.set_transmit_client(mac_device);
         awake_mac.set_receive_client(mac_device);
         awake_mac.set_config_client(mac_device);
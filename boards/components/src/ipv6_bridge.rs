@@ -0,0 +1,205 @@
+//! Component for an IPv6 border router between 6LoWPAN and Ethernet.
+//!
+//! This provides one Component, IP6BridgeComponent, which routes IPv6 between
+//! the 6LoWPAN network set up by `UDPMuxComponent` and an `EthernetDevice`
+//! (normally the USB CDC-ECM interface from `CdcEcmComponent`). The bridge
+//! takes over as the receive client of the 6LoWPAN state and passes packets
+//! for this node on to its IPv6 receiver. Packets forwarded to the mesh are
+//! sent by a dedicated IPv6 sender, whose packet buffer holds a full IPv6
+//! minimum MTU.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (udp_send_mux, udp_recv_mux, udp_port_table, sixlowpan, ip_receive) =
+//!     components::udp_mux::UDPMuxComponent::new(/* ... */)
+//!         .finalize(components::udp_mux_component_helper!(nrf52840::rtc::Rtc));
+//!
+//! let bridge = components::ipv6_bridge::IP6BridgeComponent::new(
+//!     ecm,
+//!     mux_mac,
+//!     sixlowpan,
+//!     ip_receive,
+//!     local_ip_ifaces,
+//!     MESH_PREFIX,
+//!     MESH_PREFIX_LEN,
+//!     DST_MAC_ADDR,
+//!     src_mac_from_serial_num,
+//!     mux_alarm,
+//! )
+//! .finalize(components::ipv6_bridge_component_helper!(
+//!     nrf52840::rtc::Rtc,
+//!     capsules::usb::cdc_ecm::CdcEcm<'static, nrf52840::usbd::Usbd<'static>>
+//! ));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::ieee802154::device::MacDevice;
+use capsules::net::ethernet::EthernetDevice;
+use capsules::net::ieee802154::MacAddress;
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::ipv6::ipv6_bridge::IP6Bridge;
+use capsules::net::ipv6::ipv6_recv::IP6RecvStruct;
+use capsules::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender};
+use capsules::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules::net::network_capabilities::IpVisibilityCapability;
+use capsules::net::sixlowpan::sixlowpan_state;
+use capsules::net::udp::UDPHeader;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::radio;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+/// Largest transport payload forwarded to the mesh: the IPv6 minimum MTU less
+/// the IPv6 header.
+pub const MAX_FORWARD_PAYLOAD_LEN: usize = 1280 - 40;
+
+static mut RADIO_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+static mut FORWARD_DGRAM: [u8; MAX_FORWARD_PAYLOAD_LEN] = [0; MAX_FORWARD_PAYLOAD_LEN];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ipv6_bridge_component_helper {
+    ($A:ty, $E:ty $(,)?) => {{
+        use capsules::net::ipv6::ipv6_bridge::IP6Bridge;
+        use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<capsules::ieee802154::virtual_mac::MacUser<'static>> =
+            MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<
+            IP6Bridge<'static, $E, IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>>,
+        > = MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3)
+    };};
+}
+
+pub struct IP6BridgeComponent<A: Alarm<'static> + 'static, E: EthernetDevice<'static> + 'static> {
+    ethernet: &'static E,
+    mux_mac: &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+    sixlowpan: &'static dyn sixlowpan_state::SixlowpanState<'static>,
+    ip_receive: &'static IP6RecvStruct<'static>,
+    interface_list: &'static [IPAddr],
+    mesh_prefix: IPAddr,
+    mesh_prefix_len: u8,
+    dst_mac_addr: MacAddress,
+    src_mac_addr: MacAddress,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static, E: EthernetDevice<'static> + 'static> IP6BridgeComponent<A, E> {
+    pub fn new(
+        ethernet: &'static E,
+        mux_mac: &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+        sixlowpan: &'static dyn sixlowpan_state::SixlowpanState<'static>,
+        ip_receive: &'static IP6RecvStruct<'static>,
+        interface_list: &'static [IPAddr],
+        mesh_prefix: IPAddr,
+        mesh_prefix_len: u8,
+        dst_mac_addr: MacAddress,
+        src_mac_addr: MacAddress,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            ethernet,
+            mux_mac,
+            sixlowpan,
+            ip_receive,
+            interface_list,
+            mesh_prefix,
+            mesh_prefix_len,
+            dst_mac_addr,
+            src_mac_addr,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static, E: EthernetDevice<'static> + 'static> Component
+    for IP6BridgeComponent<A, E>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<capsules::ieee802154::virtual_mac::MacUser<'static>>,
+        &'static mut MaybeUninit<IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<
+            IP6Bridge<'static, E, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        >,
+    );
+    type Output =
+        &'static IP6Bridge<'static, E, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let forward_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let forward_mac = static_init_half!(
+            static_buffer.1,
+            capsules::ieee802154::virtual_mac::MacUser<'static>,
+            capsules::ieee802154::virtual_mac::MacUser::new(self.mux_mac)
+        );
+        self.mux_mac.add_user(forward_mac);
+
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let ip_vis = static_init!(
+            IpVisibilityCapability,
+            IpVisibilityCapability::new(&create_cap)
+        );
+
+        // The bridge sets the transport header of each forwarded packet.
+        let ip_pyld: IPPayload = IPPayload {
+            header: TransportHeader::UDP(UDPHeader::new()),
+            payload: &mut FORWARD_DGRAM,
+        };
+        let ip6_dg = static_init!(IP6Packet<'static>, IP6Packet::new(ip_pyld));
+
+        let forward_send = static_init_half!(
+            static_buffer.2,
+            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+            IP6SendStruct::new(
+                ip6_dg,
+                forward_alarm,
+                &mut RADIO_BUF,
+                sixlowpan_state::TxState::new(self.sixlowpan),
+                forward_mac,
+                self.dst_mac_addr,
+                self.src_mac_addr,
+                ip_vis,
+            )
+        );
+        forward_alarm.set_alarm_client(forward_send);
+        forward_mac.set_transmit_client(forward_send);
+
+        let bridge = static_init_half!(
+            static_buffer.3,
+            IP6Bridge<'static, E, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+            IP6Bridge::new(
+                self.ethernet,
+                forward_send,
+                self.ip_receive,
+                self.interface_list,
+                self.mesh_prefix,
+                self.mesh_prefix_len,
+                &mut capsules::net::ipv6::ipv6_bridge::ETHERNET_TX_BUF,
+                &mut capsules::net::ipv6::ipv6_bridge::FORWARD_BUF,
+            )
+        );
+        // The bridge replaces the IPv6 receiver as the 6LoWPAN receive client
+        // and passes it the packets for this node.
+        self.sixlowpan.set_rx_client(bridge);
+        self.ethernet.set_client(bridge);
+        forward_send.set_client(bridge);
+
+        bridge
+    }
+}
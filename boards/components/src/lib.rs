@@ -8,7 +8,9 @@ pub mod app_flash_driver;
 pub mod bus;
 pub mod button;
 pub mod cdc;
+pub mod cdc_ecm;
 pub mod console;
+pub mod crash_dump;
 pub mod crc;
 pub mod ctap;
 pub mod debug_queue;
@@ -16,6 +18,7 @@ pub mod debug_writer;
 pub mod digest;
 pub mod ft6x06;
 pub mod fxos8700;
+pub mod gdb_stub;
 pub mod gpio;
 pub mod hd44780;
 pub mod hmac;
@@ -23,6 +26,7 @@ pub mod hts221;
 pub mod humidity;
 pub mod i2c;
 pub mod ieee802154;
+pub mod ipv6_bridge;
 pub mod isl29035;
 pub mod l3gd20;
 pub mod led;
@@ -31,12 +35,14 @@ pub mod lldb;
 pub mod lsm303agr;
 pub mod lsm303dlhc;
 pub mod mlx90614;
+pub mod msc;
 pub mod mx25r6435f;
 pub mod ninedof;
 pub mod nonvolatile_storage;
 pub mod nrf51822;
 pub mod panic_button;
 pub mod process_console;
+pub mod process_core_dump;
 pub mod rng;
 pub mod sched;
 pub mod screen;
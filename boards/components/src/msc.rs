@@ -0,0 +1,122 @@
+//! Component for Mass Storage over USB support.
+//!
+//! This provides a component for exposing nonvolatile storage to a USB host
+//! as a disk.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "Log Storage",    // Product
+//!     "Serial No. 5",   // Serial number
+//! ];
+//! let msc = components::msc::UsbMscComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::msc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x2341,
+//!     0x005b,
+//!     STRINGS,
+//!     nv_to_page,
+//!     0x60000,       // Start address of the disk in storage
+//!     0x20000 / 512, // Number of 512 byte blocks
+//!     true,          // Read-only
+//! )
+//! .finalize(components::usb_msc_component_helper!(
+//!     nrf52::usbd::Usbd,
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages<'static, nrf52::nvmc::Nvmc>
+//! ));
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_msc_component_helper {
+    ($U:ty, $S:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<capsules::usb::msc::MassStorage<'static, $U, $S>> =
+            MaybeUninit::uninit();
+        &mut BUF1
+    };};
+}
+
+pub struct UsbMscComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    S: 'static + NonvolatileStorage<'static>,
+> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    storage: &'static S,
+    start_address: usize,
+    num_blocks: u32,
+    read_only: bool,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, S: 'static + NonvolatileStorage<'static>>
+    UsbMscComponent<U, S>
+{
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'static S,
+        start_address: usize,
+        num_blocks: u32,
+        read_only: bool,
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            storage,
+            start_address,
+            num_blocks,
+            read_only,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, S: 'static + NonvolatileStorage<'static>>
+    Component for UsbMscComponent<U, S>
+{
+    type StaticInput = &'static mut MaybeUninit<capsules::usb::msc::MassStorage<'static, U, S>>;
+    type Output = &'static capsules::usb::msc::MassStorage<'static, U, S>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let msc = static_init_half!(
+            s,
+            capsules::usb::msc::MassStorage<'static, U, S>,
+            capsules::usb::msc::MassStorage::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                self.storage,
+                &mut capsules::usb::msc::BUFFER,
+                self.start_address,
+                self.num_blocks,
+                self.read_only,
+            )
+        );
+        self.usb.set_client(msc);
+        self.storage.set_client(msc);
+
+        msc
+    }
+}
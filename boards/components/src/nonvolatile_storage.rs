@@ -13,13 +13,15 @@
 //!     0x20000,
 //!     &_sstorage as *const u8 as usize,
 //!     &_estorage as *const u8 as usize,
+//!     0x1000,
+//!     &STORAGE_GRANTS,
 //! )
 //! .finalize(components::nv_storage_component_helper!(
 //!     sam4l::flashcalw::FLASHCALW
 //! ));
 //! ```
 
-use capsules::nonvolatile_storage_driver::NonvolatileStorage;
+use capsules::nonvolatile_storage_driver::{NonvolatileStorage, StorageGrant};
 use capsules::nonvolatile_to_pages::NonvolatileToPages;
 use core::mem::MaybeUninit;
 use kernel::capabilities;
@@ -51,6 +53,8 @@ pub struct NonvolatileStorageComponent<
     userspace_length: usize,
     kernel_start: usize,
     kernel_length: usize,
+    default_region_length: usize,
+    storage_grants: &'static [StorageGrant],
 }
 
 impl<
@@ -67,6 +71,8 @@ impl<
         userspace_length: usize,
         kernel_start: usize,
         kernel_length: usize,
+        default_region_length: usize,
+        storage_grants: &'static [StorageGrant],
     ) -> Self {
         Self {
             board_kernel,
@@ -76,6 +82,8 @@ impl<
             userspace_length,
             kernel_start,
             kernel_length,
+            default_region_length,
+            storage_grants,
         }
     }
 }
@@ -117,7 +125,10 @@ impl<
                 self.userspace_length, // Length of userspace accessible region
                 self.kernel_start,    // Start address of kernel region
                 self.kernel_length,   // Length of kernel region
-                &mut capsules::nonvolatile_storage_driver::BUFFER
+                self.default_region_length, // Region length for apps without a request
+                self.storage_grants,  // Apps that may use userspace storage
+                &mut capsules::nonvolatile_storage_driver::BUFFER,
+                &mut capsules::nonvolatile_storage_driver::REGION_TABLE
             )
         );
         hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, nonvolatile_storage);
@@ -9,6 +9,17 @@
 //! ```rust
 //! let pconsole = ProcessConsoleComponent::new(board_kernel, uart_mux).finalize(());
 //! ```
+//!
+//! The `baud` command reconfigures `uart_mux`. To enable `reboot`, the board
+//! passes a function that resets the chip:
+//!
+//! ```rust
+//! fn reset() -> ! {
+//!     unsafe { cortexm4::scb::reset() };
+//!     loop {}
+//! }
+//! pconsole.set_reset_function(reset);
+//! ```
 
 // Author: Philip Levis <pal@cs.stanford.edu>
 // Last modified: 6/20/2018
@@ -85,6 +96,7 @@ impl Component for ProcessConsoleComponent {
                 &mut process_console::READ_BUF,
                 &mut process_console::QUEUE_BUF,
                 &mut process_console::COMMAND_BUF,
+                &mut process_console::HISTORY_BUF,
                 self.board_kernel,
                 kernel_addresses,
                 Capability,
@@ -92,6 +104,7 @@ impl Component for ProcessConsoleComponent {
         );
         hil::uart::Transmit::set_transmit_client(console_uart, console);
         hil::uart::Receive::set_receive_client(console_uart, console);
+        console.set_uart_configure(self.uart_mux);
 
         console
     }
@@ -0,0 +1,52 @@
+//! Component for printing core dumps of faulted processes.
+//!
+//! This provides one Component, ProcessCoreDumpComponent, which creates a
+//! `CoreDumpConsole` that prints the ELF core file of a faulted process over
+//! a UART. The board passes it to the `CoreDumpFaultPolicy` it loads its
+//! processes with.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static FAULT_RESPONSE: kernel::procs::CoreDumpFaultPolicy<kernel::procs::StopFaultPolicy> =
+//!     kernel::procs::CoreDumpFaultPolicy::new(kernel::procs::StopFaultPolicy {});
+//! static mut CORE_DUMP_BUFFER: [u8; 0x4000] = [0; 0x4000];
+//!
+//! let core_dump = ProcessCoreDumpComponent::new(uart_mux, &mut CORE_DUMP_BUFFER).finalize(());
+//! FAULT_RESPONSE.set_client(core_dump);
+//! ```
+
+use capsules::process_core_dump::{CoreDumpConsole, WRITE_BUF};
+use capsules::virtual_uart::{MuxUart, UartDevice};
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init;
+
+pub struct ProcessCoreDumpComponent {
+    uart_mux: &'static MuxUart<'static>,
+    staging: &'static mut [u8],
+}
+
+impl ProcessCoreDumpComponent {
+    pub fn new(uart_mux: &'static MuxUart, staging: &'static mut [u8]) -> ProcessCoreDumpComponent {
+        ProcessCoreDumpComponent { uart_mux, staging }
+    }
+}
+
+impl Component for ProcessCoreDumpComponent {
+    type StaticInput = ();
+    type Output = &'static CoreDumpConsole<'static>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let uart = static_init!(UartDevice, UartDevice::new(self.uart_mux, false));
+        uart.setup();
+
+        let console = static_init!(
+            CoreDumpConsole<'static>,
+            CoreDumpConsole::new(uart, self.staging, &mut WRITE_BUF)
+        );
+        hil::uart::Transmit::set_transmit_client(uart, console);
+
+        console
+    }
+}
@@ -9,18 +9,27 @@
 //! -----
 //! ```rust
 //! let rtt_memory = components::segger_rtt::SeggerRttMemoryComponent::new().finalize(());
-//! let rtt = components::segger_rtt::SeggerRttComponent::new(mux_alarm, rtt_memory)
-//!     .finalize(components::segger_rtt_component_helper!(nrf52832::rtc::Rtc));
+//! let rtt = components::segger_rtt::SeggerRttComponent::new(
+//!     mux_alarm,
+//!     rtt_memory,
+//!     capsules::segger_rtt::DEBUG_CHANNEL,
+//! )
+//! .finalize(components::segger_rtt_component_helper!(nrf52832::rtc::Rtc));
 //! ```
+//!
+//! Each of the `NUM_CHANNELS` channels of the RTT memory can be instantiated
+//! once, for example to run the process console on `CONSOLE_CHANNEL` while
+//! debug output uses `DEBUG_CHANNEL`.
 
 // Author: Guillaume Endignoux <guillaumee@google.com>
 // Last modified: 07/02/2020
 
 use capsules::segger_rtt::{
-    SeggerRtt, SeggerRttMemory, DEFAULT_DOWN_BUFFER_LENGTH, DEFAULT_UP_BUFFER_LENGTH,
+    SeggerRtt, SeggerRttMemory, DEFAULT_DOWN_BUFFER_LENGTH, DEFAULT_UP_BUFFER_LENGTH, NUM_CHANNELS,
 };
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use core::mem::MaybeUninit;
+use kernel::common::cells::TakeCell;
 use kernel::component::Component;
 use kernel::hil::time::{self, Alarm};
 use kernel::{static_init, static_init_half};
@@ -40,14 +49,16 @@ macro_rules! segger_rtt_component_helper {
 }
 
 pub struct SeggerRttMemoryRefs<'a> {
-    rtt_memory: &'a mut SeggerRttMemory<'a>,
-    up_buffer: &'a mut [u8],
-    down_buffer: &'a mut [u8],
+    rtt_memory: &'a SeggerRttMemory<'a>,
+    up_buffers: [TakeCell<'a, [u8]>; NUM_CHANNELS],
+    down_buffers: [TakeCell<'a, [u8]>; NUM_CHANNELS],
 }
 
 impl<'a> SeggerRttMemoryRefs<'a> {
-    pub unsafe fn get_rtt_memory_ptr(&mut self) -> *mut SeggerRttMemory<'a> {
-        self.rtt_memory as *mut _
+    /// The RTT memory, for example for the panic handler to print to the
+    /// debug channel.
+    pub fn rtt_memory(&self) -> &'a SeggerRttMemory<'a> {
+        self.rtt_memory
     }
 }
 
@@ -61,53 +72,75 @@ impl SeggerRttMemoryComponent {
 
 impl Component for SeggerRttMemoryComponent {
     type StaticInput = ();
-    type Output = SeggerRttMemoryRefs<'static>;
+    type Output = &'static SeggerRttMemoryRefs<'static>;
 
     unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
-        let name = b"Terminal\0";
-        let up_buffer_name = name;
-        let down_buffer_name = name;
-        let up_buffer = static_init!(
-            [u8; DEFAULT_UP_BUFFER_LENGTH],
-            [0; DEFAULT_UP_BUFFER_LENGTH]
+        let names: [&'static [u8]; NUM_CHANNELS] = [b"Terminal\0", b"Console\0", b"Trace\0"];
+        let up_buffers = static_init!(
+            [[u8; DEFAULT_UP_BUFFER_LENGTH]; NUM_CHANNELS],
+            [[0; DEFAULT_UP_BUFFER_LENGTH]; NUM_CHANNELS]
         );
-        let down_buffer = static_init!(
-            [u8; DEFAULT_DOWN_BUFFER_LENGTH],
-            [0; DEFAULT_DOWN_BUFFER_LENGTH]
+        let down_buffers = static_init!(
+            [[u8; DEFAULT_DOWN_BUFFER_LENGTH]; NUM_CHANNELS],
+            [[0; DEFAULT_DOWN_BUFFER_LENGTH]; NUM_CHANNELS]
         );
 
+        let up = |channel: usize| {
+            (
+                names[channel],
+                up_buffers[channel].as_ptr(),
+                up_buffers[channel].len(),
+            )
+        };
+        let down = |channel: usize| {
+            (
+                names[channel],
+                down_buffers[channel].as_ptr(),
+                down_buffers[channel].len(),
+            )
+        };
         let rtt_memory = static_init!(
             SeggerRttMemory,
-            SeggerRttMemory::new_raw(
-                up_buffer_name,
-                up_buffer.as_ptr(),
-                up_buffer.len(),
-                down_buffer_name,
-                down_buffer.as_ptr(),
-                down_buffer.len()
-            )
+            SeggerRttMemory::new_raw([up(0), up(1), up(2)], [down(0), down(1), down(2)])
         );
-        SeggerRttMemoryRefs {
-            rtt_memory,
-            up_buffer,
-            down_buffer,
-        }
+
+        let [up_0, up_1, up_2] = up_buffers;
+        let [down_0, down_1, down_2] = down_buffers;
+        static_init!(
+            SeggerRttMemoryRefs<'static>,
+            SeggerRttMemoryRefs {
+                rtt_memory,
+                up_buffers: [
+                    TakeCell::new(up_0),
+                    TakeCell::new(up_1),
+                    TakeCell::new(up_2)
+                ],
+                down_buffers: [
+                    TakeCell::new(down_0),
+                    TakeCell::new(down_1),
+                    TakeCell::new(down_2)
+                ],
+            }
+        )
     }
 }
 
 pub struct SeggerRttComponent<A: 'static + time::Alarm<'static>> {
     mux_alarm: &'static MuxAlarm<'static, A>,
-    rtt_memory_refs: SeggerRttMemoryRefs<'static>,
+    rtt_memory_refs: &'static SeggerRttMemoryRefs<'static>,
+    channel: usize,
 }
 
 impl<A: 'static + time::Alarm<'static>> SeggerRttComponent<A> {
     pub fn new(
         mux_alarm: &'static MuxAlarm<'static, A>,
-        rtt_memory_refs: SeggerRttMemoryRefs<'static>,
+        rtt_memory_refs: &'static SeggerRttMemoryRefs<'static>,
+        channel: usize,
     ) -> SeggerRttComponent<A> {
         SeggerRttComponent {
             mux_alarm,
             rtt_memory_refs,
+            channel,
         }
     }
 }
@@ -120,6 +153,13 @@ impl<A: 'static + time::Alarm<'static>> Component for SeggerRttComponent<A> {
     type Output = &'static capsules::segger_rtt::SeggerRtt<'static, VirtualMuxAlarm<'static, A>>;
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let up_buffer = self.rtt_memory_refs.up_buffers[self.channel]
+            .take()
+            .expect("RTT channel already in use");
+        let down_buffer = self.rtt_memory_refs.down_buffers[self.channel]
+            .take()
+            .expect("RTT channel already in use");
+
         let virtual_alarm_rtt = static_init_half!(
             static_buffer.0,
             VirtualMuxAlarm<'static, A>,
@@ -133,8 +173,9 @@ impl<A: 'static + time::Alarm<'static>> Component for SeggerRttComponent<A> {
             SeggerRtt::new(
                 virtual_alarm_rtt,
                 self.rtt_memory_refs.rtt_memory,
-                self.rtt_memory_refs.up_buffer,
-                self.rtt_memory_refs.down_buffer
+                self.channel,
+                up_buffer,
+                down_buffer
             )
         );
 
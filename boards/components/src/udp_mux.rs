@@ -2,12 +2,14 @@
 //!
 //! This provides one Component, UDPMuxComponent. This component
 //! exposes a MuxUdpSender that other components can implement
-//! UDPSenders on top of to use the UDP/6Lowpan stack.
+//! UDPSenders on top of to use the UDP/6Lowpan stack. It also returns the
+//! 6LoWPAN state and the IPv6 receiver, for components (like the IPv6
+//! bridge) that need to sit between them.
 //!
 //! Usage
 //! -----
 //! ```rust
-//!    let (udp_mux, udp_recv) = UDPMuxComponent::new(
+//!    let (udp_mux, udp_recv, port_table, sixlowpan, ip_receive) = UDPMuxComponent::new(
 //!        mux_mac,
 //!        DEFAULT_CTX_PREFIX_LEN,
 //!        DEFAULT_CTX_PREFIX,
@@ -27,7 +29,7 @@ use capsules;
 use capsules::ieee802154::device::MacDevice;
 use capsules::net::ieee802154::MacAddress;
 use capsules::net::ipv6::ip_utils::IPAddr;
-use capsules::net::ipv6::ipv6_recv::IP6Receiver;
+use capsules::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
 use capsules::net::ipv6::ipv6_send::IP6SendStruct;
 use capsules::net::ipv6::ipv6_send::IP6Sender;
 use capsules::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
@@ -170,6 +172,8 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
         &'static MuxUdpReceiver<'static>,
         &'static UdpPortManager,
+        &'static dyn sixlowpan_state::SixlowpanState<'static>,
+        &'static IP6RecvStruct<'static>,
     );
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
@@ -259,10 +263,7 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         ip_send.set_addr(self.interface_list[0]);
         udp_mac.set_transmit_client(ip_send);
 
-        let ip_receive = static_init!(
-            capsules::net::ipv6::ipv6_recv::IP6RecvStruct<'static>,
-            capsules::net::ipv6::ipv6_recv::IP6RecvStruct::new()
-        );
+        let ip_receive = static_init!(IP6RecvStruct<'static>, IP6RecvStruct::new());
         sixlowpan_state.set_rx_client(ip_receive);
         let udp_recv_mux = static_init!(MuxUdpReceiver<'static>, MuxUdpReceiver::new());
         ip_receive.set_client(udp_recv_mux);
@@ -283,6 +284,12 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
             UdpPortManager::new(&create_table_cap, &mut USED_KERNEL_PORTS, udp_vis)
         );
 
-        (udp_send_mux, udp_recv_mux, udp_port_table)
+        (
+            udp_send_mux,
+            udp_recv_mux,
+            udp_port_table,
+            sixlowpan_state,
+            ip_receive,
+        )
     }
 }
@@ -1,4 +1,6 @@
 fn main() {
     println!("cargo:rerun-if-changed=layout.ld");
+    println!("cargo:rerun-if-changed=layout_bank_b.ld");
+    println!("cargo:rerun-if-changed=kernel_update_layout.ld");
     println!("cargo:rerun-if-changed=../kernel_layout.ld");
 }
@@ -10,6 +10,8 @@
 #![test_runner(test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use capsules::aes_gcm::Aes128Gcm;
+use capsules::virtual_aes_ccm::{MuxAES128CCM, VirtualAES128CCM};
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_hmac::VirtualMuxHmac;
 use capsules::virtual_sha::VirtualMuxSha;
@@ -22,6 +24,8 @@ use kernel::hil;
 use kernel::hil::digest::Digest;
 use kernel::hil::i2c::I2CMaster;
 use kernel::hil::led::LedHigh;
+use kernel::hil::public_key_crypto::SignatureVerify;
+use kernel::hil::symmetric_encryption::{AES128, AES128CCM, AES128GCM};
 use kernel::hil::time::Alarm;
 use kernel::mpu::KernelMPU;
 use kernel::Platform;
@@ -80,6 +84,41 @@ const FAULT_RESPONSE: kernel::procs::PanicFaultPolicy = kernel::procs::PanicFaul
 #[link_section = ".stack_buffer"]
 pub static mut STACK_MEMORY: [u8; 0x1000] = [0; 0x1000];
 
+/// Ed25519 public key kernel updates must be signed with. This is the key of
+/// test vector 1 of RFC 8032, for development only; production images must
+/// use the key of their own signing authority.
+const KERNEL_UPDATE_PUBLIC_KEY: [u8; 32] = [
+    0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a,
+    0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a,
+];
+
+/// Starts the kernel in an update bank. Like the image the boot ROM starts,
+/// each bank begins with a flash header holding the kernel's entry point.
+struct BankChainloader {
+    banks: [(usize, usize); 2],
+}
+
+impl capsules::kernel_update::Chainloader for BankChainloader {
+    fn chainload(&self, bank: capsules::kernel_update::Bank) -> kernel::ErrorCode {
+        let (first_page, pages) = self.banks[bank as usize];
+        let start = first_page * lowrisc::flash_ctrl::PAGE_SIZE;
+        let end = start + pages * lowrisc::flash_ctrl::PAGE_SIZE;
+        let entry = unsafe { (start as *const usize).read_volatile() };
+        // Kernels are not position independent, so an image linked for the
+        // other bank would run the code there instead.
+        if entry <= start || entry >= end {
+            return kernel::ErrorCode::INVAL;
+        }
+        unsafe {
+            let entry: extern "C" fn() -> ! = core::mem::transmute(entry);
+            // The other kernel sets up the hart from scratch, so it must not
+            // take interrupts meant for this one.
+            csr::CSR.mstatus.modify(csr::mstatus::mstatus::mie::CLEAR);
+            entry()
+        }
+    }
+}
+
 /// A structure representing this platform that holds references to all
 /// capsules for this platform. We've included an alarm and console.
 struct EarlGreyNexysVideo {
@@ -116,6 +155,15 @@ struct EarlGreyNexysVideo {
         capsules::virtual_uart::UartDevice<'static>,
     >,
     i2c_master: &'static capsules::i2c_master::I2CMasterDriver<'static, lowrisc::i2c::I2c<'static>>,
+    kernel_update: &'static capsules::kernel_update::KernelUpdate<
+        'static,
+        capsules::virtual_flash::FlashUser<'static, lowrisc::flash_ctrl::FlashCtrl<'static>>,
+        capsules::virtual_digest::VirtualMuxDigest<'static, lowrisc::hmac::Hmac<'static>, 32>,
+        capsules::public_key_crypto::ed25519::Ed25519Software<'static, 32>,
+    >,
+    aes: &'static capsules::aes::AesDriver<
+        Aes128Gcm<'static, VirtualAES128CCM<'static, earlgrey::aes::Aes<'static>>>,
+    >,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -133,6 +181,8 @@ impl Platform for EarlGreyNexysVideo {
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::low_level_debug::DRIVER_NUM => f(Some(self.lldb)),
             capsules::i2c_master::DRIVER_NUM => f(Some(self.i2c_master)),
+            capsules::kernel_update::DRIVER_NUM => f(Some(self.kernel_update)),
+            capsules::aes::DRIVER_NUM => f(Some(self.aes)),
             _ => f(None),
         }
     }
@@ -158,7 +208,7 @@ unsafe fn setup() -> (
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 3], Default::default());
+        static_init!([DynamicDeferredCallClientState; 5], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -295,7 +345,8 @@ unsafe fn setup() -> (
         components::digest_component_helper!(lowrisc::hmac::Hmac, 32,),
     );
 
-    peripherals.hmac.set_client(digest);
+    // The mux routes callbacks to whichever digest user is running.
+    peripherals.hmac.set_client(mux_digest);
 
     let hmac_key_buffer = static_init!([u8; 32], [0; 32]);
     let hmac_data_buffer = static_init!([u8; 64], [0; 64]);
@@ -355,6 +406,42 @@ unsafe fn setup() -> (
             .expect("dynamic deferred caller out of slots"),
     );
 
+    // AES. The hardware handles at most 128 bytes per operation, which
+    // bounds the buffers below.
+    let aes_mux = static_init!(
+        MuxAES128CCM<'static, earlgrey::aes::Aes>,
+        MuxAES128CCM::new(&peripherals.aes, dynamic_deferred_caller)
+    );
+    aes_mux.initialize_callback_handle(
+        dynamic_deferred_caller
+            .register(aes_mux)
+            .expect("no deferred call slot available for aes mux"),
+    );
+    AES128::set_client(&peripherals.aes, aes_mux);
+    let ccm_crypt_buf = static_init!([u8; 128], [0; 128]);
+    let aes_user = static_init!(
+        VirtualAES128CCM<'static, earlgrey::aes::Aes>,
+        VirtualAES128CCM::new(aes_mux, ccm_crypt_buf)
+    );
+    aes_user.setup();
+    let gcm_crypt_buf = static_init!([u8; 128], [0; 128]);
+    let aes_gcm = static_init!(
+        Aes128Gcm<'static, VirtualAES128CCM<'static, earlgrey::aes::Aes>>,
+        Aes128Gcm::new(aes_user, gcm_crypt_buf)
+    );
+    aes_gcm.setup();
+    let aes = static_init!(
+        capsules::aes::AesDriver<Aes128Gcm<'static, VirtualAES128CCM<'static, earlgrey::aes::Aes>>>,
+        capsules::aes::AesDriver::new(
+            aes_gcm,
+            &mut capsules::aes::CRYPT_BUF,
+            board_kernel.create_grant(capsules::aes::DRIVER_NUM, &memory_allocation_cap)
+        )
+    );
+    AES128::set_client(aes_gcm, aes);
+    AES128CCM::set_client(aes_gcm, aes);
+    AES128GCM::set_client(aes_gcm, aes);
+
     // USB support is currently broken in the OpenTitan hardware
     // See https://github.com/lowRISC/opentitan/issues/2598 for more details
     // let usb = usb::UsbComponent::new(board_kernel).finalize(());
@@ -394,8 +481,97 @@ unsafe fn setup() -> (
     ));
     hil::flash::HasClient::set_client(&peripherals.flash_ctrl, mux_flash);
 
-    let _mux_otbn = crate::otbn::AccelMuxComponent::new(&peripherals.otbn)
+    // Kernel update. The boot ROM starts the kernel in bank A at the start of
+    // flash, and updates are staged in bank B at the start of the second flash
+    // bank. The banks and the record page are laid out in
+    // kernel_update_layout.ld, which keeps them out of app flash.
+    extern "C" {
+        static _sbank_a: u8;
+        static _ebank_a: u8;
+        static _sbank_b: u8;
+        static _ebank_b: u8;
+        static _sboot_record: u8;
+    }
+    let bank_pages = |start: &u8, end: &u8| {
+        let start = start as *const u8 as usize;
+        let end = end as *const u8 as usize;
+        (
+            start / lowrisc::flash_ctrl::PAGE_SIZE,
+            (end - start) / lowrisc::flash_ctrl::PAGE_SIZE,
+        )
+    };
+    let update_banks = [
+        bank_pages(&_sbank_a, &_ebank_a),
+        bank_pages(&_sbank_b, &_ebank_b),
+    ];
+    let running_bank = capsules::kernel_update::Bank::containing(
+        &update_banks,
+        lowrisc::flash_ctrl::PAGE_SIZE,
+        &_stext as *const u8 as usize,
+    )
+    .expect("kernel is not in an update bank");
+    let update_flash = static_init!(
+        capsules::virtual_flash::FlashUser<'static, lowrisc::flash_ctrl::FlashCtrl>,
+        capsules::virtual_flash::FlashUser::new(mux_flash)
+    );
+    let update_page_buffer = static_init!(
+        lowrisc::flash_ctrl::LowRiscPage,
+        lowrisc::flash_ctrl::LowRiscPage::default()
+    );
+    let update_digest_key_buffer = static_init!([u8; 32], [0; 32]);
+    let update_digest =
+        components::digest::DigestComponent::new(&mux_digest, update_digest_key_buffer).finalize(
+            components::digest_component_helper!(lowrisc::hmac::Hmac, 32,),
+        );
+    let update_verifier = static_init!(
+        capsules::public_key_crypto::ed25519::Ed25519Software<'static, 32>,
+        capsules::public_key_crypto::ed25519::Ed25519Software::new(dynamic_deferred_caller)
+    );
+    update_verifier.initialize_callback_handle(
+        dynamic_deferred_caller
+            .register(update_verifier)
+            .expect("dynamic deferred caller out of slots"),
+    );
+    update_verifier
+        .set_public_key(&KERNEL_UPDATE_PUBLIC_KEY)
+        .unwrap();
+    let update_chainloader = static_init!(
+        BankChainloader,
+        BankChainloader {
+            banks: update_banks
+        }
+    );
+    let kernel_update = static_init!(
+        capsules::kernel_update::KernelUpdate<
+            'static,
+            capsules::virtual_flash::FlashUser<'static, lowrisc::flash_ctrl::FlashCtrl>,
+            capsules::virtual_digest::VirtualMuxDigest<'static, lowrisc::hmac::Hmac, 32>,
+            capsules::public_key_crypto::ed25519::Ed25519Software<'static, 32>,
+        >,
+        capsules::kernel_update::KernelUpdate::new(
+            update_flash,
+            update_digest,
+            update_verifier,
+            update_page_buffer,
+            &mut capsules::kernel_update::DATA_BUFFER,
+            &mut capsules::kernel_update::DIGEST_BUFFER,
+            &mut capsules::kernel_update::SIGNATURE_BUFFER,
+            update_banks,
+            &_sboot_record as *const u8 as usize / lowrisc::flash_ctrl::PAGE_SIZE,
+            running_bank,
+            3,
+            &["kernel_updater"],
+            update_chainloader,
+            board_kernel.create_grant(capsules::kernel_update::DRIVER_NUM, &memory_allocation_cap),
+        )
+    );
+    hil::flash::HasClient::set_client(update_flash, kernel_update);
+    update_digest.set_sha_client(kernel_update);
+    update_verifier.set_verify_client(kernel_update);
+
+    let mux_otbn = crate::otbn::AccelMuxComponent::new(&peripherals.otbn)
         .finalize(otbn_mux_component_helper!(1024));
+    peripherals.otbn.set_client(mux_otbn);
 
     peripherals.otbn.initialise(
         dynamic_deferred_caller
@@ -444,6 +620,8 @@ unsafe fn setup() -> (
             sha,
             lldb: lldb,
             i2c_master,
+            kernel_update,
+            aes,
         }
     );
 
@@ -534,6 +712,10 @@ pub unsafe fn main() {
             components::sched::priority::PriorityComponent::new(board_kernel).finalize(());
         let main_loop_cap = create_capability!(capabilities::MainLoopCapability);
 
+        // We made it to the main loop, so this kernel image is good, unless
+        // the boot-control record selects the kernel in the other bank.
+        let _ = earlgrey_nexysvideo.kernel_update.boot();
+
         board_kernel.kernel_loop(
             earlgrey_nexysvideo,
             chip,
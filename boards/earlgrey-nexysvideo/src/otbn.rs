@@ -3,8 +3,9 @@
 //! Usage
 //! -----
 //! ```rust
-//!     let _mux_otbn = crate::otbn::AccelMuxComponent::new(&peripherals.otbn)
+//!     let mux_otbn = crate::otbn::AccelMuxComponent::new(&peripherals.otbn)
 //!         .finalize(otbn_mux_component_helper!(1024));
+//!     peripherals.otbn.set_client(mux_otbn);
 //!
 //!     peripherals.otbn.initialise(
 //!         dynamic_deferred_caller
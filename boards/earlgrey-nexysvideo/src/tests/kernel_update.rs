@@ -0,0 +1,102 @@
+//! Test the kernel update boot-control record, bank selection and boot
+//! confirmation.
+
+use crate::tests::run_kernel_op;
+use crate::PLATFORM;
+use capsules::kernel_update::{Bank, BootControlRecord, BootState};
+use kernel::debug;
+
+#[test_case]
+fn kernel_update_record_round_trip() {
+    debug!("check kernel update record round trip... ");
+    run_kernel_op(100);
+
+    let record = BootControlRecord {
+        active_bank: Bank::B,
+        state: BootState::Pending,
+        boot_attempts: 3,
+        image_length: 0x1234,
+        digest: [0xa5; 32],
+    };
+    let mut buf = [0xff; BootControlRecord::LENGTH];
+    record.serialize(&mut buf);
+
+    let parsed = BootControlRecord::parse(&buf).unwrap();
+    assert_eq!(parsed.active_bank, Bank::B);
+    assert_eq!(parsed.state, BootState::Pending);
+    assert_eq!(parsed.boot_attempts, 3);
+    assert_eq!(parsed.image_length, 0x1234);
+    assert_eq!(parsed.digest, [0xa5; 32]);
+
+    // An erased page does not hold a record.
+    assert!(BootControlRecord::parse(&[0xff; BootControlRecord::LENGTH]).is_none());
+
+    debug!("    [ok]");
+    run_kernel_op(100);
+}
+
+#[test_case]
+fn kernel_update_rollback() {
+    debug!("check kernel update rollback... ");
+    run_kernel_op(100);
+
+    let mut record = BootControlRecord {
+        active_bank: Bank::A,
+        state: BootState::Pending,
+        boot_attempts: 2,
+        image_length: 0,
+        digest: [0; 32],
+    };
+
+    // The pending bank is tried until the attempts run out...
+    assert_eq!(record.select_boot_bank(), Bank::B);
+    assert_eq!(record.select_boot_bank(), Bank::B);
+    assert_eq!(record.state, BootState::Pending);
+
+    // ...and then the bootloader returns to the active bank for good.
+    assert_eq!(record.select_boot_bank(), Bank::A);
+    assert_eq!(record.state, BootState::RolledBack);
+    assert_eq!(record.select_boot_bank(), Bank::A);
+
+    debug!("    [ok]");
+    run_kernel_op(100);
+}
+
+#[test_case]
+fn kernel_update_running_bank() {
+    debug!("check kernel update running bank... ");
+    run_kernel_op(100);
+
+    let banks = [(0x100, 0x80), (0x400, 0x80)];
+    assert_eq!(Bank::containing(&banks, 512, 0x100 * 512), Some(Bank::A));
+    assert_eq!(
+        Bank::containing(&banks, 512, 0x180 * 512 - 1),
+        Some(Bank::A)
+    );
+    assert_eq!(Bank::containing(&banks, 512, 0x180 * 512), None);
+    assert_eq!(
+        Bank::containing(&banks, 512, 0x420 * 512 + 4),
+        Some(Bank::B)
+    );
+    assert_eq!(Bank::containing(&banks, 512, 0), None);
+
+    debug!("    [ok]");
+    run_kernel_op(100);
+}
+
+#[test_case]
+fn kernel_update_boot() {
+    let kernel_update = unsafe { PLATFORM.unwrap().kernel_update };
+
+    debug!("check kernel update boot... ");
+    run_kernel_op(100);
+
+    assert_eq!(kernel_update.boot(), Ok(()));
+
+    run_kernel_op(10000);
+    #[cfg(feature = "hardware_tests")]
+    assert_eq!(kernel_update.boot_state(), BootState::Confirmed);
+
+    debug!("    [ok]");
+    run_kernel_op(100);
+}
@@ -50,5 +50,6 @@ fn trivial_assertion() {
 
 mod aes_test;
 mod hmac;
+mod kernel_update;
 mod multi_alarm;
 mod otbn;
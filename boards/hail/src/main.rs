@@ -460,28 +460,32 @@ pub unsafe fn main() {
         kernel::procs::ThresholdRestartThenPanicFaultPolicy::new(4)
     );
 
-    let hail = Hail {
-        console,
-        gpio,
-        alarm,
-        ambient_light,
-        temp,
-        humidity,
-        ninedof,
-        spi: spi_syscalls,
-        nrf51822: nrf_serialization,
-        adc,
-        led,
-        button,
-        rng,
-        ipc: kernel::ipc::IPC::new(
-            board_kernel,
-            kernel::ipc::DRIVER_NUM,
-            &memory_allocation_capability,
-        ),
-        crc,
-        dac,
-    };
+    let hail = static_init!(
+        Hail,
+        Hail {
+            console,
+            gpio,
+            alarm,
+            ambient_light,
+            temp,
+            humidity,
+            ninedof,
+            spi: spi_syscalls,
+            nrf51822: nrf_serialization,
+            adc,
+            led,
+            button,
+            rng,
+            ipc: kernel::ipc::IPC::new(
+                board_kernel,
+                kernel::ipc::DRIVER_NUM,
+                &memory_allocation_capability,
+            ),
+            crc,
+            dac,
+        }
+    );
+    process_console.set_driver_lookup(hail);
 
     // Setup the UART bus for nRF51 serialization..
     hail.nrf51822.initialize();
@@ -528,7 +532,7 @@ pub unsafe fn main() {
     let scheduler = components::sched::round_robin::RoundRobinComponent::new(&PROCESSES)
         .finalize(components::rr_component_helper!(NUM_PROCS));
     board_kernel.kernel_loop(
-        &hail,
+        hail,
         chip,
         Some(&hail.ipc),
         scheduler,
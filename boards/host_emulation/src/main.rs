@@ -0,0 +1,316 @@
+//! Board file for running the Tock kernel as a Linux process.
+//!
+//! The board wires the emulated peripherals of the `host` chip to the console,
+//! alarm and nonvolatile storage capsules, and loads RISC-V processes from TBF
+//! files into app flash. App flash and memory are at the same addresses as on
+//! the OpenTitan Earl Grey board, so processes linked for it run unchanged.
+//!
+//! ```text
+//! host_emulation [--apps FILE[,FILE...]] [--storage NAME[,NAME...]] [--flash FILE]
+//!                [--uart DEVICE] [--exit-when-idle]
+//! ```
+//!
+//! - `--apps`: TBF files of the processes to load, in order.
+//! - `--storage`: package names of the processes granted userspace storage.
+//!   Each gets its own region, identified by its position in the list.
+//! - `--flash`: file backing the emulated flash, so storage persists across
+//!   runs (default: host memory).
+//! - `--uart`: terminal device, such as a pty, to use instead of stdio.
+//! - `--exit-when-idle`: exit once no process can run and all output has been
+//!   written. The exit status is 1 if any process faulted, 0 otherwise.
+
+use std::process;
+
+use capsules::nonvolatile_storage_driver::StorageGrant;
+use capsules::virtual_alarm::VirtualMuxAlarm;
+use host::alarm::HostAlarm;
+use host::chip::{HostChip, HostDefaultPeripherals, Wakeup};
+use host::flash::{HostFlash, PAGE_SIZE};
+use host::uart::HostUart;
+use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+use kernel::component::Component;
+use kernel::hil::time::Alarm;
+use kernel::procs::State;
+use kernel::Chip;
+use kernel::Platform;
+use kernel::{create_capability, debug, static_init};
+
+mod tbf;
+
+const NUM_PROCS: usize = 4;
+const NUM_UPCALLS_IPC: usize = NUM_PROCS + 1;
+
+/// Process flash and memory, as on Earl Grey.
+const APP_FLASH_START: usize = 0x2003_0000;
+const APP_FLASH_SIZE: usize = 0x5_0000;
+const APP_MEMORY_START: usize = 0x1000_5000;
+const APP_MEMORY_SIZE: usize = 0xB000;
+
+/// Size of the emulated flash, in pages.
+const FLASH_PAGES: usize = 64;
+
+type HostEmulationChip = HostChip<'static, VirtualMuxAlarm<'static, HostAlarm<'static>>>;
+
+// Actual memory for holding the active process structures.
+static mut PROCESSES: [Option<&'static dyn kernel::procs::Process>; NUM_PROCS] = [None; NUM_PROCS];
+
+// How should the kernel respond when a process faults.
+const FAULT_RESPONSE: kernel::procs::StopWithDebugFaultPolicy =
+    kernel::procs::StopWithDebugFaultPolicy {};
+
+struct HostEmulation {
+    console: &'static capsules::console::Console<'static>,
+    alarm: &'static capsules::alarm::AlarmDriver<
+        'static,
+        VirtualMuxAlarm<'static, HostAlarm<'static>>,
+    >,
+    nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+}
+
+/// Mapping of integer syscalls to objects that implement syscalls.
+impl Platform for HostEmulation {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+    where
+        F: FnOnce(Option<&dyn kernel::Driver>) -> R,
+    {
+        match driver_num {
+            capsules::console::DRIVER_NUM => f(Some(self.console)),
+            capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
+            capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
+            _ => f(None),
+        }
+    }
+}
+
+struct Options {
+    apps: Vec<String>,
+    storage: Vec<String>,
+    flash: Option<String>,
+    uart: Option<String>,
+    exit_when_idle: bool,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("host_emulation: {}", message);
+    eprintln!(
+        "usage: host_emulation [--apps FILE[,FILE...]] [--storage NAME[,NAME...]] \
+         [--flash FILE] [--uart DEVICE] [--exit-when-idle]"
+    );
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        apps: Vec::new(),
+        storage: Vec::new(),
+        flash: None,
+        uart: None,
+        exit_when_idle: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)))
+        };
+        let mut list = || -> Vec<String> {
+            value()
+                .split(',')
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        match arg.as_str() {
+            "--apps" => options.apps = list(),
+            "--storage" => options.storage = list(),
+            "--flash" => options.flash = Some(value()),
+            "--uart" => options.uart = Some(value()),
+            "--exit-when-idle" => options.exit_when_idle = true,
+            _ => usage_error(&format!("unknown argument `{}`", arg)),
+        }
+    }
+    options
+}
+
+/// Grant userspace storage to the packages named in `names`.
+fn storage_grants(names: &[String]) -> &'static [StorageGrant] {
+    let grants: Vec<StorageGrant> = names
+        .iter()
+        .zip(1..)
+        .map(|(name, storage_id)| StorageGrant {
+            package_name: Box::leak(name.clone().into_boxed_str()),
+            storage_id,
+        })
+        .collect();
+    Box::leak(grants.into_boxed_slice())
+}
+
+/// Map app flash, and write the TBF images in the files at `paths` to it.
+fn load_app_flash(paths: &[String]) -> &'static [u8] {
+    let images: Vec<(String, &'static [u8])> = paths
+        .iter()
+        .map(|path| match std::fs::read(path) {
+            Ok(image) => (path.clone(), &*Box::leak(image.into_boxed_slice())),
+            Err(e) => usage_error(&format!("cannot read {}: {}", path, e)),
+        })
+        .collect();
+    let flash = host::memory::map_fixed(APP_FLASH_START, APP_FLASH_SIZE)
+        .unwrap_or_else(|e| usage_error(&format!("cannot map app flash: {}", e)));
+    // Erased flash ends the list of apps.
+    flash.fill(0xff);
+    tbf::write_images(&images, flash, APP_FLASH_START).unwrap_or_else(|e| usage_error(&e));
+    flash
+}
+
+/// If no process can run any more, the exit status for `--exit-when-idle`.
+unsafe fn idle_exit_status(
+    board_kernel: &'static kernel::Kernel,
+    chip: &HostEmulationChip,
+) -> Option<i32> {
+    let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
+    let runnable = core::cell::Cell::new(false);
+    let faulted = core::cell::Cell::new(false);
+    board_kernel.process_each_capability(&process_mgmt_cap, |process| match process.get_state() {
+        State::Faulted => faulted.set(true),
+        State::Terminated | State::StoppedRunning | State::StoppedYielded => {}
+        _ => runnable.set(true),
+    });
+
+    let work_pending = chip.has_pending_interrupts()
+        || DynamicDeferredCall::global_instance_calls_pending().unwrap_or(false);
+    if runnable.get() || work_pending {
+        None
+    } else {
+        Some(if faulted.get() { 1 } else { 0 })
+    }
+}
+
+fn main() {
+    let options = parse_options();
+    unsafe { start(options) }
+}
+
+unsafe fn start(options: Options) {
+    let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
+    let main_loop_cap = create_capability!(capabilities::MainLoopCapability);
+
+    let wakeup = Wakeup::new();
+    let uart = match &options.uart {
+        Some(device) => HostUart::open(device, wakeup.clone())
+            .unwrap_or_else(|e| usage_error(&format!("cannot open {}: {}", device, e))),
+        None => HostUart::stdio(wakeup.clone()),
+    };
+    let flash = match &options.flash {
+        Some(path) => HostFlash::open(path, FLASH_PAGES)
+            .unwrap_or_else(|e| usage_error(&format!("cannot open {}: {}", path, e))),
+        None => HostFlash::in_memory(FLASH_PAGES),
+    };
+    let peripherals = static_init!(
+        HostDefaultPeripherals,
+        HostDefaultPeripherals::new(uart, flash)
+    );
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+
+    let dynamic_deferred_call_clients =
+        static_init!([DynamicDeferredCallClientState; 2], Default::default());
+    let dynamic_deferred_caller = static_init!(
+        DynamicDeferredCall,
+        DynamicDeferredCall::new(dynamic_deferred_call_clients)
+    );
+    DynamicDeferredCall::set_global_instance(dynamic_deferred_caller);
+
+    // Create a shared UART channel for the console and for kernel debug.
+    let uart_mux = components::console::UartMuxComponent::new(
+        &peripherals.uart,
+        115200,
+        dynamic_deferred_caller,
+    )
+    .finalize(());
+
+    let console = components::console::ConsoleComponent::new(
+        board_kernel,
+        capsules::console::DRIVER_NUM,
+        uart_mux,
+    )
+    .finalize(());
+    components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
+
+    let mux_alarm = components::alarm::AlarmMuxComponent::new(&peripherals.alarm)
+        .finalize(components::alarm_mux_component_helper!(HostAlarm));
+    let scheduler_timer_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, HostAlarm>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let chip = static_init!(
+        HostEmulationChip,
+        HostChip::new(peripherals, scheduler_timer_virtual_alarm, wakeup)
+    );
+    scheduler_timer_virtual_alarm.set_alarm_client(chip.scheduler_timer());
+    let alarm = components::alarm::AlarmDriverComponent::new(
+        board_kernel,
+        capsules::alarm::DRIVER_NUM,
+        mux_alarm,
+    )
+    .finalize(components::alarm_component_helper!(HostAlarm));
+
+    // The first three quarters of flash belong to processes, the rest to the
+    // kernel.
+    let flash_size = FLASH_PAGES * PAGE_SIZE;
+    let nonvolatile_storage = components::nonvolatile_storage::NonvolatileStorageComponent::new(
+        board_kernel,
+        capsules::nonvolatile_storage_driver::DRIVER_NUM,
+        &peripherals.flash,
+        0,                  // Start address for userspace accessible region
+        flash_size / 4 * 3, // Length of userspace accessible region
+        flash_size / 4 * 3, // Start address of kernel region
+        flash_size / 4,     // Length of kernel region
+        0x400,              // Region length for apps without a request
+        storage_grants(&options.storage),
+    )
+    .finalize(components::nv_storage_component_helper!(HostFlash));
+
+    let platform = HostEmulation {
+        console,
+        alarm,
+        nonvolatile_storage,
+    };
+
+    debug!("Host emulation initialization complete. Entering main loop.");
+
+    let app_flash = load_app_flash(&options.apps);
+    let app_memory = host::memory::map_fixed(APP_MEMORY_START, APP_MEMORY_SIZE)
+        .unwrap_or_else(|e| usage_error(&format!("cannot map app memory: {}", e)));
+    kernel::procs::load_processes(
+        board_kernel,
+        chip,
+        app_flash,
+        app_memory,
+        &mut PROCESSES,
+        &FAULT_RESPONSE,
+        &process_mgmt_cap,
+    )
+    .unwrap_or_else(|err| {
+        debug!("Error loading processes!");
+        debug!("{:?}", err);
+    });
+
+    let scheduler = components::sched::round_robin::RoundRobinComponent::new(&PROCESSES)
+        .finalize(components::rr_component_helper!(NUM_PROCS));
+
+    loop {
+        if options.exit_when_idle {
+            if let Some(status) = idle_exit_status(board_kernel, chip) {
+                process::exit(status);
+            }
+        }
+        board_kernel.kernel_loop_operation(
+            &platform,
+            chip,
+            None::<&kernel::ipc::IPC<NUM_PROCS, NUM_UPCALLS_IPC>>,
+            scheduler,
+            false,
+            &main_loop_cap,
+        );
+    }
+}
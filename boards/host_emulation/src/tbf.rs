@@ -0,0 +1,59 @@
+//! Placing TBF images in app flash.
+//!
+//! Images are placed back to back in the order given, as tockloader would. An
+//! image whose header fixes its flash address is placed at that address, after
+//! a padding entry if there is a gap, so that processes linked for a fixed
+//! address run unchanged.
+
+use std::convert::TryInto;
+
+use tock_tbf::{parse, serialize};
+
+/// Size of a padding entry's header, the smallest possible padding.
+const MIN_PADDING: usize = 16;
+
+/// Copy `images`, given with their names, into `flash`, which is mapped at
+/// `flash_start`. Returns a message for the first image that is not a TBF or
+/// cannot be placed.
+pub fn write_images(
+    images: &[(String, &'static [u8])],
+    flash: &mut [u8],
+    flash_start: usize,
+) -> Result<(), String> {
+    let mut offset = 0;
+    for (name, image) in images {
+        let lengths: &'static [u8; 8] = image
+            .get(0..8)
+            .and_then(|lengths| lengths.try_into().ok())
+            .ok_or_else(|| format!("{}: not a TBF image", name))?;
+        let (version, header_length, total_size) = parse::parse_tbf_header_lengths(lengths)
+            .map_err(|_| format!("{}: not a TBF image", name))?;
+        let image = image
+            .get(..total_size as usize)
+            .ok_or_else(|| format!("{}: image is shorter than its header says", name))?;
+
+        let header = parse::parse_tbf_header(&image[..header_length as usize], version)
+            .map_err(|_| format!("{}: invalid TBF header", name))?;
+        if let Some(address) = header.get_fixed_address_flash() {
+            // The address is that of the code, after the protected region.
+            let target = (address as usize)
+                .checked_sub(header.get_protected_size() as usize + flash_start)
+                .filter(|&target| target == offset || target >= offset + MIN_PADDING)
+                .ok_or_else(|| format!("{}: cannot be placed at {:#x}", name, address))?;
+            if target > offset {
+                let padding = &mut flash[offset..];
+                serialize::serialize_tbf_padding((target - offset) as u32, padding)
+                    .map_err(|_| format!("{}: cannot be placed at {:#x}", name, address))?;
+                offset = target;
+            }
+        }
+
+        let end = offset + image.len();
+        flash
+            .get_mut(offset..end)
+            .ok_or_else(|| format!("{}: does not fit in app flash", name))?
+            .copy_from_slice(image);
+        offset = end;
+    }
+    Ok(())
+}
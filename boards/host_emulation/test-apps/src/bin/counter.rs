@@ -0,0 +1,38 @@
+//! Count how many times this process has run, in nonvolatile storage.
+
+#![no_std]
+#![no_main]
+
+use core::cell::Cell;
+use host_emulation_test_apps::*;
+
+#[no_mangle]
+extern "C" fn main(_flash_start: usize, _memory_start: usize) {
+    let read = Cell::new(false);
+    let written = Cell::new(false);
+    let mut count = [0; 4];
+    subscribe(NONVOLATILE_STORAGE, 0, set_flag, flag_data(&read));
+    subscribe(NONVOLATILE_STORAGE, 1, set_flag, flag_data(&written));
+
+    allow_readwrite(NONVOLATILE_STORAGE, 0, &mut count);
+    if !is_success(command(NONVOLATILE_STORAGE, 2, 0, 4)) {
+        print(b"Counter: storage unavailable\r\n");
+        return;
+    }
+    wait_for(&read);
+    allow_readwrite(NONVOLATILE_STORAGE, 0, &mut []);
+
+    // Erased storage reads as all ones.
+    let runs = match u32::from_le_bytes(count) {
+        u32::MAX => 1,
+        n => n + 1,
+    };
+    count = runs.to_le_bytes();
+
+    allow_readonly(NONVOLATILE_STORAGE, 0, &count);
+    if is_success(command(NONVOLATILE_STORAGE, 3, 0, 4)) {
+        wait_for(&written);
+    }
+    allow_readonly(NONVOLATILE_STORAGE, 0, &[]);
+    print_number(b"Counter: run ", runs, b"\r\n");
+}
@@ -0,0 +1,11 @@
+//! Write to this process's own flash, which the MPU does not allow.
+
+#![no_std]
+#![no_main]
+
+use host_emulation_test_apps as _;
+
+#[no_mangle]
+extern "C" fn main(flash_start: usize, _memory_start: usize) {
+    unsafe { core::ptr::write_volatile(flash_start as *mut u32, 0) };
+}
@@ -0,0 +1,11 @@
+//! Print a greeting and exit.
+
+#![no_std]
+#![no_main]
+
+use host_emulation_test_apps::print;
+
+#[no_mangle]
+extern "C" fn main(_flash_start: usize, _memory_start: usize) {
+    print(b"Hello from a RISC-V process!\r\n");
+}
@@ -0,0 +1,15 @@
+//! Compute for a while without making a system call, then print.
+
+#![no_std]
+#![no_main]
+
+use host_emulation_test_apps::print;
+
+#[no_mangle]
+extern "C" fn main(_flash_start: usize, _memory_start: usize) {
+    let mut i = 0u32;
+    while unsafe { core::ptr::read_volatile(&i) } < 3_000_000 {
+        i += 1;
+    }
+    print(b"Spin done\r\n");
+}
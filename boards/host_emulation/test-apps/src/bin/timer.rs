@@ -0,0 +1,21 @@
+//! Wait for the alarm twice, printing each time, then exit.
+
+#![no_std]
+#![no_main]
+
+use core::cell::Cell;
+use host_emulation_test_apps::ALARM;
+use host_emulation_test_apps::{command, flag_data, print_number, set_flag, subscribe, wait_for};
+
+#[no_mangle]
+extern "C" fn main(_flash_start: usize, _memory_start: usize) {
+    let fired = Cell::new(false);
+    subscribe(ALARM, 0, set_flag, flag_data(&fired));
+    for i in 1..=2 {
+        fired.set(false);
+        // The alarm runs at 1 MHz: wait 10 ms.
+        command(ALARM, 5, 10_000, 0);
+        wait_for(&fired);
+        print_number(b"Timer fired ", i, b"\r\n");
+    }
+}
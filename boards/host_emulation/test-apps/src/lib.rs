@@ -0,0 +1,195 @@
+//! Runtime for the processes the host emulation tests run.
+//!
+//! A process starts at `_start` with the arguments of the kernel's init call,
+//! moves its break to take the first [`STACK_SIZE`] bytes of its memory as
+//! its stack, and calls `main(flash_start, memory_start)`, which each binary
+//! defines. Returning from `main` exits the process. System calls use the
+//! RISC-V ABI of TRD 104.
+//!
+//! Processes keep all of their state on the stack, so they only need to be
+//! linked for their flash address.
+
+#![no_std]
+#![feature(asm, global_asm)]
+
+use core::cell::Cell;
+use core::panic::PanicInfo;
+
+/// Bytes at the start of process memory used as the stack.
+pub const STACK_SIZE: usize = 2048;
+
+global_asm!(
+    "
+    .section .start, \"ax\"
+    .globl _start
+_start:
+    // a0: flash start, a1: memory start, a2: memory size, a3: break
+    mv   s0, a0
+    mv   s1, a1
+    // memop(brk, memory start + STACK_SIZE)
+    li   a0, 0
+    li   t0, 2048
+    add  a1, s1, t0
+    li   a4, 5
+    ecall
+    li   t0, 2048
+    add  sp, s1, t0
+    mv   a0, s0
+    mv   a1, s1
+    call main
+    // exit-terminate(0)
+    li   a0, 0
+    li   a1, 0
+    li   a4, 6
+    ecall
+    "
+);
+
+pub const CONSOLE: usize = 1;
+pub const ALARM: usize = 0;
+pub const NONVOLATILE_STORAGE: usize = 0x50001;
+
+/// An upcall function. The last argument is the application data passed to
+/// `subscribe`.
+pub type Upcall = extern "C" fn(u32, u32, u32, usize);
+
+fn syscall(class: u32, arguments: [usize; 4]) -> [u32; 4] {
+    let [mut r0, mut r1, mut r2, mut r3] = arguments;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") r0,
+            inlateout("a1") r1,
+            inlateout("a2") r2,
+            inlateout("a3") r3,
+            in("a4") class,
+        );
+    }
+    [r0 as u32, r1 as u32, r2 as u32, r3 as u32]
+}
+
+/// Whether the return registers hold one of the success variants.
+pub fn is_success(registers: [u32; 4]) -> bool {
+    registers[0] >= 128
+}
+
+/// Block until an upcall has run.
+pub fn yield_wait() {
+    // The upcall runs before `ecall` returns, so every register it may
+    // change is clobbered.
+    unsafe {
+        asm!(
+            "ecall",
+            lateout("x1") _,
+            lateout("x5") _,
+            lateout("x6") _,
+            lateout("x7") _,
+            inlateout("x10") 1 => _,
+            lateout("x11") _,
+            lateout("x12") _,
+            lateout("x13") _,
+            inlateout("x14") 0 => _,
+            lateout("x15") _,
+            lateout("x16") _,
+            lateout("x17") _,
+            lateout("x28") _,
+            lateout("x29") _,
+            lateout("x30") _,
+            lateout("x31") _,
+        );
+    }
+}
+
+pub fn subscribe(driver: usize, subscribe_num: usize, upcall: Upcall, data: usize) -> [u32; 4] {
+    syscall(1, [driver, subscribe_num, upcall as usize, data])
+}
+
+pub fn command(driver: usize, command_num: usize, arg0: usize, arg1: usize) -> [u32; 4] {
+    syscall(2, [driver, command_num, arg0, arg1])
+}
+
+pub fn allow_readwrite(driver: usize, allow_num: usize, buffer: &mut [u8]) -> [u32; 4] {
+    syscall(
+        3,
+        [
+            driver,
+            allow_num,
+            buffer.as_mut_ptr() as usize,
+            buffer.len(),
+        ],
+    )
+}
+
+pub fn allow_readonly(driver: usize, allow_num: usize, buffer: &[u8]) -> [u32; 4] {
+    syscall(
+        4,
+        [driver, allow_num, buffer.as_ptr() as usize, buffer.len()],
+    )
+}
+
+pub fn exit_terminate(completion_code: usize) -> ! {
+    syscall(6, [0, completion_code, 0, 0]);
+    // The kernel does not return from exit.
+    loop {
+        yield_wait();
+    }
+}
+
+/// An upcall that sets the `Cell<bool>` its application data points to.
+pub extern "C" fn set_flag(_: u32, _: u32, _: u32, flag: usize) {
+    unsafe { (*(flag as *const Cell<bool>)).set(true) };
+}
+
+/// The application data that makes `set_flag` set `flag`.
+pub fn flag_data(flag: &Cell<bool>) -> usize {
+    flag as *const Cell<bool> as usize
+}
+
+/// Yield until `flag` is set.
+pub fn wait_for(flag: &Cell<bool>) {
+    while !flag.get() {
+        yield_wait();
+    }
+}
+
+/// Write `text` to the console and wait until it has been sent.
+pub fn print(text: &[u8]) {
+    let done = Cell::new(false);
+    subscribe(CONSOLE, 1, set_flag, flag_data(&done));
+    allow_readonly(CONSOLE, 1, text);
+    if is_success(command(CONSOLE, 1, text.len(), 0)) {
+        wait_for(&done);
+    }
+    allow_readonly(CONSOLE, 1, &[]);
+}
+
+/// Format `n` in decimal at the end of `buffer`, returning the digits.
+pub fn format_u32(mut n: u32, buffer: &mut [u8; 10]) -> &[u8] {
+    let mut start = buffer.len();
+    loop {
+        start -= 1;
+        buffer[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            return &buffer[start..];
+        }
+    }
+}
+
+/// Print `prefix`, `n` and `suffix`.
+pub fn print_number(prefix: &[u8], n: u32, suffix: &[u8]) {
+    let mut line = [0; 64];
+    let mut digits = [0; 10];
+    let digits = format_u32(n, &mut digits);
+    let mut len = 0;
+    for part in [prefix, digits, suffix].iter() {
+        line[len..len + part.len()].copy_from_slice(part);
+        len += part.len();
+    }
+    print(&line[..len]);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    exit_terminate(1)
+}
@@ -0,0 +1,213 @@
+//! Run the emulator with the RISC-V processes in `test-apps` and check their
+//! output.
+//!
+//! The processes are built with the `riscv32imc-unknown-none-elf` target,
+//! each linked for its own slot in app flash, and packaged as TBF files with a
+//! fixed flash address so the board places them in their slot.
+
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Once;
+
+use tock_tbf::serialize;
+use tock_tbf::types::{TbfHeaderV2, TbfHeaderV2Main};
+
+/// The processes and the flash address of their TBF. The board places images
+/// in the order given, so tests list processes in address order.
+const APPS: &[(&str, u32)] = &[
+    ("hello", 0x2003_0000),
+    ("timer", 0x2003_1000),
+    ("counter", 0x2003_2000),
+    ("fault", 0x2003_3000),
+    ("spin", 0x2003_4000),
+];
+
+/// Bytes from the start of a TBF to its code: the header, padded with
+/// protected space.
+const CODE_OFFSET: u32 = 0x80;
+
+const MINIMUM_RAM_SIZE: u32 = 4096;
+
+static BUILD: Once = Once::new();
+
+fn test_apps() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("test-apps")
+}
+
+/// Where the TBF files are written.
+fn apps_dir() -> PathBuf {
+    test_apps().join("target/tbf")
+}
+
+/// The code of an ELF executable as a flat binary, and its load address.
+fn flatten_elf(elf: &[u8]) -> (u32, Vec<u8>) {
+    let u16_at = |offset: usize| u16::from_le_bytes(elf[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(elf[offset..offset + 4].try_into().unwrap());
+    assert_eq!(&elf[..5], b"\x7fELF\x01", "not a 32-bit ELF file");
+
+    const PT_LOAD: u32 = 1;
+    let phoff = u32_at(0x1c) as usize;
+    let phentsize = u16_at(0x2a) as usize;
+    let phnum = u16_at(0x2c) as usize;
+    let segments: Vec<(u32, &[u8])> = (0..phnum)
+        .map(|i| phoff + i * phentsize)
+        .filter(|&ph| u32_at(ph) == PT_LOAD && u32_at(ph + 0x10) > 0)
+        .map(|ph| {
+            let offset = u32_at(ph + 0x4) as usize;
+            let filesz = u32_at(ph + 0x10) as usize;
+            (u32_at(ph + 0xc), &elf[offset..offset + filesz])
+        })
+        .collect();
+
+    let start = segments.iter().map(|&(address, _)| address).min().unwrap();
+    let mut binary = Vec::new();
+    for (address, data) in segments {
+        let offset = (address - start) as usize;
+        if binary.len() < offset + data.len() {
+            binary.resize(offset + data.len(), 0);
+        }
+        binary[offset..offset + data.len()].copy_from_slice(data);
+    }
+    (start, binary)
+}
+
+/// Build `name` for the code address `code_address` and package it as a TBF.
+fn build_tbf(name: &'static str, code_address: u32) -> Vec<u8> {
+    let status = Command::new(env!("CARGO"))
+        .current_dir(test_apps())
+        .args(&["rustc", "--release", "--bin", name, "--", "-C"])
+        .arg(format!("link-arg=--defsym=FLASH_START={:#x}", code_address))
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "failed to build {}", name);
+    let elf = std::fs::read(
+        test_apps()
+            .join("target/riscv32imc-unknown-none-elf/release")
+            .join(name),
+    )
+    .unwrap();
+    let (start, binary) = flatten_elf(&elf);
+    assert_eq!(start, code_address, "{} is not linked for its slot", name);
+
+    let total_size = CODE_OFFSET + (binary.len() as u32 + 3) / 4 * 4;
+    let mut header = TbfHeaderV2::new(total_size, true);
+    header.set_package_name(Some(name));
+    header.set_fixed_addresses(None, Some(code_address));
+    // Sized with a placeholder, since the values do not change the size.
+    header.set_main(Some(TbfHeaderV2Main::new(0, 0, MINIMUM_RAM_SIZE)));
+    let header_size = serialize::tbf_header_size(&header).unwrap() as u32;
+    // The init function offset is from the end of the header, and the
+    // entry point is the start of the code.
+    let protected_size = CODE_OFFSET - header_size;
+    header.set_main(Some(TbfHeaderV2Main::new(
+        protected_size,
+        protected_size,
+        MINIMUM_RAM_SIZE,
+    )));
+
+    let mut tbf = vec![0; total_size as usize];
+    serialize::serialize_tbf_header(&header, &mut tbf).unwrap();
+    tbf[CODE_OFFSET as usize..][..binary.len()].copy_from_slice(&binary);
+    tbf
+}
+
+/// The path of the TBF of `name`, building every process the first time.
+fn app(name: &str) -> PathBuf {
+    BUILD.call_once(|| {
+        std::fs::create_dir_all(apps_dir()).unwrap();
+        for &(app, address) in APPS {
+            let tbf = build_tbf(app, address + CODE_OFFSET);
+            std::fs::write(apps_dir().join(format!("{}.tbf", app)), tbf).unwrap();
+        }
+    });
+    apps_dir().join(format!("{}.tbf", name))
+}
+
+fn run(apps: &[&str], args: &[&str]) -> (Output, String) {
+    let apps: Vec<String> = apps
+        .iter()
+        .map(|name| app(name).to_str().unwrap().to_string())
+        .collect();
+    let output = Command::new(env!("CARGO_BIN_EXE_host_emulation"))
+        .arg("--apps")
+        .arg(apps.join(","))
+        .args(args)
+        .arg("--exit-when-idle")
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run the emulator");
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    (output, stdout)
+}
+
+#[test]
+fn hello() {
+    let (output, stdout) = run(&["hello"], &[]);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("Host emulation initialization complete."));
+    assert!(
+        stdout.contains("Hello from a RISC-V process!"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn alarm_and_console_together() {
+    let (output, stdout) = run(&["hello", "timer"], &[]);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("Hello from a RISC-V process!"),
+        "{}",
+        stdout
+    );
+    let first = stdout.find("Timer fired 1").expect("first alarm missing");
+    let second = stdout.find("Timer fired 2").expect("second alarm missing");
+    assert!(first < second);
+}
+
+#[test]
+fn storage_persists_in_flash_file() {
+    let path = std::env::temp_dir().join(format!("host-emulation-{}.flash", std::process::id()));
+    let flash = path.to_str().unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    let (output, stdout) = run(&["counter"], &["--storage", "counter", "--flash", flash]);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("Counter: run 1"), "{}", stdout);
+
+    let (output, stdout) = run(&["counter"], &["--storage", "counter", "--flash", flash]);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("Counter: run 2"), "{}", stdout);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn faulting_process_is_stopped() {
+    // The process writes to its own flash, which the MPU does not allow.
+    let (output, stdout) = run(&["hello", "fault"], &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(
+        stdout.contains("Process fault faulted and was stopped."),
+        "{}",
+        stdout
+    );
+    // The other process is unaffected.
+    assert!(
+        stdout.contains("Hello from a RISC-V process!"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn busy_process_is_preempted() {
+    let (output, stdout) = run(&["timer", "spin"], &[]);
+    assert!(output.status.success(), "{}", stdout);
+    let timer = stdout.find("Timer fired 2").expect("alarm missing");
+    let spin = stdout.find("Spin done").expect("spin missing");
+    // The alarm process runs while the other one computes.
+    assert!(timer < spin, "{}", stdout);
+}
@@ -252,6 +252,12 @@ unsafe fn set_pin_primary_functions(peripherals: &Sam4lDefaultPeripherals) {
     peripherals.pc[31].configure(None); //... D2          -- GPIO Pin
 }
 
+/// Resets the chip for the process console's `reboot` command.
+fn reset() -> ! {
+    unsafe { cortexm4::scb::reset() };
+    loop {}
+}
+
 /// Main function.
 ///
 /// This is called after RAM initialization is complete.
@@ -316,6 +322,7 @@ pub unsafe fn main() {
         UartMuxComponent::new(&peripherals.usart3, 115200, dynamic_deferred_caller).finalize(());
 
     let pconsole = ProcessConsoleComponent::new(board_kernel, uart_mux).finalize(());
+    pconsole.set_reset_function(reset);
     let console =
         ConsoleComponent::new(board_kernel, capsules::console::DRIVER_NUM, uart_mux).finalize(());
     DebugWriterComponent::new(uart_mux).finalize(());
@@ -526,6 +533,8 @@ pub unsafe fn main() {
         0x20000,                          // Length of userspace accessible region
         &_sstorage as *const u8 as usize, //start address of kernel region
         &_estorage as *const u8 as usize - &_sstorage as *const u8 as usize, // length of kernel region
+        0x2000, // Region length for apps that do not request one
+        &[],    // No apps are granted userspace storage
     )
     .finalize(components::nv_storage_component_helper!(
         sam4l::flashcalw::FLASHCALW
@@ -546,17 +555,18 @@ pub unsafe fn main() {
         ]
     );
 
-    let (udp_send_mux, udp_recv_mux, udp_port_table) = components::udp_mux::UDPMuxComponent::new(
-        mux_mac,
-        DEFAULT_CTX_PREFIX_LEN,
-        DEFAULT_CTX_PREFIX,
-        DST_MAC_ADDR,
-        src_mac_from_serial_num, //comment out for dual rx test only
-        //MacAddress::Short(49138), //comment in for dual rx test only
-        local_ip_ifaces,
-        mux_alarm,
-    )
-    .finalize(components::udp_mux_component_helper!(sam4l::ast::Ast));
+    let (udp_send_mux, udp_recv_mux, udp_port_table, _, _) =
+        components::udp_mux::UDPMuxComponent::new(
+            mux_mac,
+            DEFAULT_CTX_PREFIX_LEN,
+            DEFAULT_CTX_PREFIX,
+            DST_MAC_ADDR,
+            src_mac_from_serial_num, //comment out for dual rx test only
+            //MacAddress::Short(49138), //comment in for dual rx test only
+            local_ip_ifaces,
+            mux_alarm,
+        )
+        .finalize(components::udp_mux_component_helper!(sam4l::ast::Ast));
 
     // UDP driver initialization happens here
     let udp_driver = components::udp_driver::UDPDriverComponent::new(
@@ -569,28 +579,31 @@ pub unsafe fn main() {
     )
     .finalize(components::udp_driver_component_helper!(sam4l::ast::Ast));
 
-    let imix = Imix {
-        pconsole,
-        console,
-        alarm,
-        gpio,
-        temp,
-        humidity,
-        ambient_light,
-        adc,
-        led,
-        button,
-        rng,
-        analog_comparator,
-        crc,
-        spi: spi_syscalls,
-        ipc: kernel::ipc::IPC::new(board_kernel, kernel::ipc::DRIVER_NUM, &grant_cap),
-        ninedof,
-        udp_driver,
-        usb_driver,
-        nrf51822: nrf_serialization,
-        nonvolatile_storage,
-    };
+    let imix = static_init!(
+        Imix,
+        Imix {
+            pconsole,
+            console,
+            alarm,
+            gpio,
+            temp,
+            humidity,
+            ambient_light,
+            adc,
+            led,
+            button,
+            rng,
+            analog_comparator,
+            crc,
+            spi: spi_syscalls,
+            ipc: kernel::ipc::IPC::new(board_kernel, kernel::ipc::DRIVER_NUM, &grant_cap),
+            ninedof,
+            udp_driver,
+            usb_driver,
+            nrf51822: nrf_serialization,
+            nonvolatile_storage,
+        }
+    );
 
     // Need to initialize the UART for the nRF51 serialization.
     imix.nrf51822.initialize();
@@ -600,6 +613,7 @@ pub unsafe fn main() {
     let _ = rf233.reset();
     let _ = rf233.start();
 
+    imix.pconsole.set_driver_lookup(imix);
     let _ = imix.pconsole.start();
 
     // Optional kernel tests. Note that these might conflict
@@ -684,5 +698,5 @@ pub unsafe fn main() {
 
     let scheduler = components::sched::round_robin::RoundRobinComponent::new(&PROCESSES)
         .finalize(components::rr_component_helper!(NUM_PROCS));
-    board_kernel.kernel_loop(&imix, chip, Some(&imix.ipc), scheduler, &main_cap);
+    board_kernel.kernel_loop(imix, chip, Some(&imix.ipc), scheduler, &main_cap);
 }
@@ -531,27 +531,31 @@ pub unsafe fn main() {
     while !base_peripherals.clock.low_started() {}
     while !base_peripherals.clock.high_started() {}
 
-    let microbit = MicroBit {
-        ble_radio: ble_radio,
-        console: console,
-        gpio: gpio,
-        button: button,
-        led: led,
-        rng: rng,
-        temperature: temperature,
-        lsm303agr: lsm303agr,
-        ninedof: ninedof,
-        buzzer: buzzer,
-        sound_pressure: sound_pressure,
-        adc: adc_syscall,
-        alarm: alarm,
-        app_flash: app_flash,
-        ipc: kernel::ipc::IPC::new(
-            board_kernel,
-            kernel::ipc::DRIVER_NUM,
-            &memory_allocation_capability,
-        ),
-    };
+    let microbit = static_init!(
+        MicroBit,
+        MicroBit {
+            ble_radio: ble_radio,
+            console: console,
+            gpio: gpio,
+            button: button,
+            led: led,
+            rng: rng,
+            temperature: temperature,
+            lsm303agr: lsm303agr,
+            ninedof: ninedof,
+            buzzer: buzzer,
+            sound_pressure: sound_pressure,
+            adc: adc_syscall,
+            alarm: alarm,
+            app_flash: app_flash,
+            ipc: kernel::ipc::IPC::new(
+                board_kernel,
+                kernel::ipc::DRIVER_NUM,
+                &memory_allocation_capability,
+            ),
+        }
+    );
+    process_console.set_driver_lookup(microbit);
 
     let chip = static_init!(
         nrf52833::chip::NRF52<Nrf52833DefaultPeripherals>,
@@ -600,7 +604,7 @@ pub unsafe fn main() {
     let scheduler = components::sched::round_robin::RoundRobinComponent::new(&PROCESSES)
         .finalize(components::rr_component_helper!(NUM_PROCS));
     board_kernel.kernel_loop(
-        &microbit,
+        microbit,
         chip,
         Some(&microbit.ipc),
         scheduler,
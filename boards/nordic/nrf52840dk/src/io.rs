@@ -25,9 +25,7 @@ fn wait() {
 }
 
 /// Set the RTT memory buffer used to output panic messages.
-pub unsafe fn set_rtt_memory(
-    rtt_memory: &'static mut capsules::segger_rtt::SeggerRttMemory<'static>,
-) {
+pub unsafe fn set_rtt_memory(rtt_memory: &'static capsules::segger_rtt::SeggerRttMemory<'static>) {
     WRITER = Writer::WriterRtt(rtt_memory);
 }
 
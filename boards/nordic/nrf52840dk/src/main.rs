@@ -125,6 +125,11 @@ const DST_MAC_ADDR: capsules::net::ieee802154::MacAddress =
     capsules::net::ieee802154::MacAddress::Short(49138);
 const DEFAULT_CTX_PREFIX_LEN: u8 = 8; //Length of context for 6LoWPAN compression
 const DEFAULT_CTX_PREFIX: [u8; 16] = [0x0 as u8; 16]; //Context for 6LoWPAN Compression
+/// Prefix of the 6LoWPAN network routed to the USB Ethernet link.
+const MESH_PREFIX: IPAddr = IPAddr([
+    0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+]);
+const MESH_PREFIX_LEN: u8 = 64;
 
 /// Debug Writer
 pub mod io;
@@ -151,6 +156,10 @@ static mut CHIP: Option<&'static nrf52840::chip::NRF52<Nrf52840DefaultPeripheral
 #[link_section = ".stack_buffer"]
 pub static mut STACK_MEMORY: [u8; 0x2000] = [0; 0x2000];
 
+/// Area that kernel panics are saved into, kept across resets.
+#[link_section = ".crash_dump"]
+static mut CRASH_DUMP_AREA: [u8; 0x800] = [0; 0x800];
+
 /// Supported drivers by the platform
 pub struct Platform {
     ble_radio: &'static capsules::ble_advertising_driver::BLE<
@@ -243,13 +252,8 @@ pub unsafe fn main() {
 
     let uart_channel = if USB_DEBUGGING {
         // Initialize early so any panic beyond this point can use the RTT memory object.
-        let mut rtt_memory_refs =
-            components::segger_rtt::SeggerRttMemoryComponent::new().finalize(());
-
-        // XXX: This is inherently unsafe as it aliases the mutable reference to rtt_memory. This
-        // aliases reference is only used inside a panic handler, which should be OK, but maybe we
-        // should use a const reference to rtt_memory and leverage interior mutability instead.
-        self::io::set_rtt_memory(&mut *rtt_memory_refs.get_rtt_memory_ptr());
+        let rtt_memory_refs = components::segger_rtt::SeggerRttMemoryComponent::new().finalize(());
+        self::io::set_rtt_memory(rtt_memory_refs.rtt_memory());
 
         UartChannel::Rtt(rtt_memory_refs)
     } else {
@@ -380,6 +384,12 @@ pub unsafe fn main() {
         components::console::UartMuxComponent::new(channel, 115200, dynamic_deferred_caller)
             .finalize(());
 
+    // Report the crash dump of a panic from before the last reset, if any.
+    let crash_dump =
+        components::crash_dump::CrashDumpComponent::new(uart_mux, &mut CRASH_DUMP_AREA)
+            .finalize(());
+    let _ = crash_dump.report();
+
     let pconsole =
         components::process_console::ProcessConsoleComponent::new(board_kernel, uart_mux)
             .finalize(());
@@ -447,16 +457,17 @@ pub unsafe fn main() {
         ]
     );
 
-    let (udp_send_mux, udp_recv_mux, udp_port_table) = components::udp_mux::UDPMuxComponent::new(
-        mux_mac,
-        DEFAULT_CTX_PREFIX_LEN,
-        DEFAULT_CTX_PREFIX,
-        DST_MAC_ADDR,
-        src_mac_from_serial_num,
-        local_ip_ifaces,
-        mux_alarm,
-    )
-    .finalize(components::udp_mux_component_helper!(nrf52840::rtc::Rtc));
+    let (udp_send_mux, udp_recv_mux, udp_port_table, sixlowpan, ip_receive) =
+        components::udp_mux::UDPMuxComponent::new(
+            mux_mac,
+            DEFAULT_CTX_PREFIX_LEN,
+            DEFAULT_CTX_PREFIX,
+            DST_MAC_ADDR,
+            src_mac_from_serial_num,
+            local_ip_ifaces,
+            mux_alarm,
+        )
+        .finalize(components::udp_mux_component_helper!(nrf52840::rtc::Rtc));
 
     // UDP driver initialization happens here
     let udp_driver = components::udp_driver::UDPDriverComponent::new(
@@ -469,6 +480,47 @@ pub unsafe fn main() {
     )
     .finalize(components::udp_driver_component_helper!(nrf52840::rtc::Rtc));
 
+    // USB Ethernet (CDC-ECM) link to the host, bridged to the 6LoWPAN network
+    // so the board acts as its border router.
+    let ecm_strings = static_init!(
+        [&str; 4],
+        [
+            "Nordic Semiconductor", // Manufacturer
+            "nRF52840dk - TockOS",  // Product
+            "serial0001",           // Serial number
+            "02000000000a",         // Host MAC address
+        ]
+    );
+    let ecm = components::cdc_ecm::CdcEcmComponent::new(
+        &nrf52840_peripherals.usbd,
+        capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+        0x1915, // Nordic Semiconductor
+        0x503a, // lowRISC generic FS USB
+        ecm_strings,
+        capsules::net::ethernet::EthernetAddress([0x02, 0, 0, 0, 0, 0x0b]),
+    )
+    .finalize(components::cdc_ecm_component_helper!(
+        nrf52840::usbd::Usbd<'static>
+    ));
+    components::ipv6_bridge::IP6BridgeComponent::new(
+        ecm,
+        mux_mac,
+        sixlowpan,
+        ip_receive,
+        local_ip_ifaces,
+        MESH_PREFIX,
+        MESH_PREFIX_LEN,
+        DST_MAC_ADDR,
+        src_mac_from_serial_num,
+        mux_alarm,
+    )
+    .finalize(components::ipv6_bridge_component_helper!(
+        nrf52840::rtc::Rtc,
+        capsules::usb::cdc_ecm::CdcEcm<'static, nrf52840::usbd::Usbd<'static>>
+    ));
+    ecm.enable();
+    ecm.attach();
+
     let temp = components::temperature::TemperatureComponent::new(
         board_kernel,
         capsules::temperature::DRIVER_NUM,
@@ -524,6 +576,8 @@ pub unsafe fn main() {
         0x20000, // Length of userspace accessible region
         0,       // Start address of kernel region
         0x60000, // Length of kernel region
+        0x2000,  // Region length for apps that do not request one
+        &[],     // No apps are granted userspace storage
     )
     .finalize(components::nv_storage_component_helper!(
         capsules::mx25r6435f::MX25R6435F<
@@ -148,7 +148,7 @@ impl UartPins {
 /// enabled.
 pub enum UartChannel<'a> {
     Pins(UartPins),
-    Rtt(components::segger_rtt::SeggerRttMemoryRefs<'a>),
+    Rtt(&'a components::segger_rtt::SeggerRttMemoryRefs<'a>),
 }
 
 pub struct UartChannelComponent {
@@ -187,9 +187,12 @@ impl Component for UartChannelComponent {
                 self.uarte0
             }
             UartChannel::Rtt(rtt_memory) => {
-                let rtt =
-                    components::segger_rtt::SeggerRttComponent::new(self.mux_alarm, rtt_memory)
-                        .finalize(components::segger_rtt_component_helper!(nrf52::rtc::Rtc));
+                let rtt = components::segger_rtt::SeggerRttComponent::new(
+                    self.mux_alarm,
+                    rtt_memory,
+                    capsules::segger_rtt::DEBUG_CHANNEL,
+                )
+                .finalize(components::segger_rtt_component_helper!(nrf52::rtc::Rtc));
                 rtt
             }
         }
@@ -388,19 +388,23 @@ pub unsafe fn main() {
             .finalize(());
     let _ = process_console.start();
 
-    let raspberry_pi_pico = RaspberryPiPico {
-        ipc: kernel::ipc::IPC::new(
-            board_kernel,
-            kernel::ipc::DRIVER_NUM,
-            &memory_allocation_capability,
-        ),
-        alarm: alarm,
-        gpio: gpio,
-        led: led,
-        console: console,
-        adc: adc_syscall,
-        temperature: temp,
-    };
+    let raspberry_pi_pico = static_init!(
+        RaspberryPiPico,
+        RaspberryPiPico {
+            ipc: kernel::ipc::IPC::new(
+                board_kernel,
+                kernel::ipc::DRIVER_NUM,
+                &memory_allocation_capability,
+            ),
+            alarm: alarm,
+            gpio: gpio,
+            led: led,
+            console: console,
+            adc: adc_syscall,
+            temperature: temp,
+        }
+    );
+    process_console.set_driver_lookup(raspberry_pi_pico);
 
     let platform_type = match peripherals.sysinfo.get_platform() {
         sysinfo::Platform::Asic => "ASIC",
@@ -450,7 +454,7 @@ pub unsafe fn main() {
         .finalize(components::rr_component_helper!(NUM_PROCS));
 
     board_kernel.kernel_loop(
-        &raspberry_pi_pico,
+        raspberry_pi_pico,
         chip,
         Some(&raspberry_pi_pico.ipc),
         scheduler,
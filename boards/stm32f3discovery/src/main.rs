@@ -729,6 +729,8 @@ pub unsafe fn main() {
         0x8000,     // Length of userspace accesible region (16 pages)
         &_sstorage as *const u8 as usize,
         &_estorage as *const u8 as usize - &_sstorage as *const u8 as usize,
+        0x800, // Region length for apps that do not request one
+        &[],   // No apps are granted userspace storage
     )
     .finalize(components::nv_storage_component_helper!(
         stm32f303xc::flash::Flash
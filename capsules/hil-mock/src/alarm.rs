@@ -0,0 +1,159 @@
+//! Mock alarm with manually controlled time.
+//!
+//! Time only moves when the test calls [`MockAlarm::advance`] or
+//! [`MockAlarm::set_now`]. Advancing past the armed expiration fires the
+//! client at exactly the expiration time, so a client that re-arms from its
+//! callback sees a consistent `now()`.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm, Frequency, Ticks, Ticks32, Time};
+use kernel::ErrorCode;
+
+pub struct MockAlarm<'a, F: Frequency = time::Freq1KHz> {
+    now: Cell<u32>,
+    reference: Cell<u32>,
+    dt: Cell<u32>,
+    armed: Cell<bool>,
+    fired: Cell<usize>,
+    client: OptionalCell<&'a dyn time::AlarmClient>,
+    _frequency: PhantomData<F>,
+}
+
+impl<'a, F: Frequency> MockAlarm<'a, F> {
+    pub fn new() -> MockAlarm<'a, F> {
+        MockAlarm {
+            now: Cell::new(0),
+            reference: Cell::new(0),
+            dt: Cell::new(0),
+            armed: Cell::new(false),
+            fired: Cell::new(0),
+            client: OptionalCell::empty(),
+            _frequency: PhantomData,
+        }
+    }
+
+    /// Ticks until the alarm expires, or `None` if it is not armed. An alarm
+    /// set in the past has zero ticks remaining.
+    pub fn remaining(&self) -> Option<u32> {
+        if self.armed.get() {
+            let elapsed = self.now.get().wrapping_sub(self.reference.get());
+            Some(self.dt.get().saturating_sub(elapsed))
+        } else {
+            None
+        }
+    }
+
+    /// Move time forward by `ticks`, firing the alarm each time an armed
+    /// expiration is reached. Returns how many times the alarm fired.
+    pub fn advance(&self, ticks: u32) -> usize {
+        let fired_before = self.fired.get();
+        let mut budget = ticks;
+        while let Some(remaining) = self.remaining().filter(|r| *r <= budget) {
+            budget -= remaining;
+            self.now.set(self.now.get().wrapping_add(remaining));
+            self.fire();
+        }
+        self.now.set(self.now.get().wrapping_add(budget));
+        self.fired.get() - fired_before
+    }
+
+    /// Jump to `now`, firing the alarm if it expires on the way there.
+    pub fn set_now(&self, now: u32) -> usize {
+        self.advance(now.wrapping_sub(self.now.get()))
+    }
+
+    /// Fire the alarm immediately, whatever the time. Returns `false` if it
+    /// was not armed.
+    pub fn fire(&self) -> bool {
+        if !self.armed.get() {
+            return false;
+        }
+        self.armed.set(false);
+        self.fired.set(self.fired.get() + 1);
+        self.client.map(|client| client.alarm());
+        true
+    }
+
+    /// Total number of times the alarm has fired.
+    pub fn fired_count(&self) -> usize {
+        self.fired.get()
+    }
+}
+
+impl<F: Frequency> Time for MockAlarm<'_, F> {
+    type Frequency = F;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32::from(self.now.get())
+    }
+}
+
+impl<'a, F: Frequency> Alarm<'a> for MockAlarm<'a, F> {
+    fn set_alarm_client(&'a self, client: &'a dyn time::AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) {
+        self.reference.set(reference.into_u32());
+        self.dt.set(dt.into_u32());
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> Self::Ticks {
+        Ticks32::from(self.reference.get().wrapping_add(self.dt.get()))
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.armed.set(false);
+        Ok(())
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    fn minimum_dt(&self) -> Self::Ticks {
+        Ticks32::from(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(Cell<usize>);
+
+    impl time::AlarmClient for Counter {
+        fn alarm(&self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn fires_once_expiration_is_reached() {
+        let alarm: MockAlarm = MockAlarm::new();
+        let counter = Counter(Cell::new(0));
+        alarm.set_alarm_client(&counter);
+        alarm.set_alarm(alarm.now(), Ticks32::from(10));
+
+        assert_eq!(alarm.advance(9), 0);
+        assert_eq!(alarm.remaining(), Some(1));
+        assert_eq!(alarm.advance(5), 1);
+        assert_eq!(counter.0.get(), 1);
+        assert_eq!(alarm.now().into_u32(), 14);
+        assert!(!alarm.is_armed());
+    }
+
+    #[test]
+    fn wraps_like_a_hardware_counter() {
+        let alarm: MockAlarm = MockAlarm::new();
+        alarm.set_now(u32::MAX - 2);
+        alarm.set_alarm(alarm.now(), Ticks32::from(5));
+        assert_eq!(alarm.advance(5), 1);
+        assert_eq!(alarm.now().into_u32(), 2);
+    }
+}
@@ -0,0 +1,287 @@
+//! Mock flash backed by host memory.
+//!
+//! Operations take effect, and their callback is delivered, only when the
+//! test calls [`MockFlash::complete`]. [`MockFlash::fail_next`] makes the next
+//! operation report `FlashError` without touching the contents.
+
+use core::cell::{Cell, RefCell};
+use core::ops::{Index, IndexMut};
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ErrorCode;
+
+pub const PAGE_SIZE: usize = 512;
+
+/// Value of an erased flash byte.
+const ERASED: u8 = 0xFF;
+
+pub struct MockPage(pub [u8; PAGE_SIZE]);
+
+impl Default for MockPage {
+    fn default() -> Self {
+        Self { 0: [0; PAGE_SIZE] }
+    }
+}
+
+impl Index<usize> for MockPage {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        &self.0[idx]
+    }
+}
+
+impl IndexMut<usize> for MockPage {
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        &mut self.0[idx]
+    }
+}
+
+impl AsMut<[u8]> for MockPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashOperation {
+    Read(usize),
+    Write(usize),
+    Erase(usize),
+}
+
+pub struct MockFlash<'a> {
+    client: OptionalCell<&'a dyn hil::flash::Client<MockFlash<'a>>>,
+    contents: RefCell<Vec<u8>>,
+    pending: Cell<Option<FlashOperation>>,
+    buffer: TakeCell<'static, MockPage>,
+    fail_next: Cell<bool>,
+    operations: RefCell<Vec<FlashOperation>>,
+}
+
+impl<'a> MockFlash<'a> {
+    /// Create an erased flash of `num_pages` pages.
+    pub fn new(num_pages: usize) -> MockFlash<'a> {
+        MockFlash {
+            client: OptionalCell::empty(),
+            contents: RefCell::new(vec![ERASED; num_pages * PAGE_SIZE]),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            fail_next: Cell::new(false),
+            operations: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// A copy of the whole flash.
+    pub fn contents(&self) -> Vec<u8> {
+        self.contents.borrow().clone()
+    }
+
+    /// Overwrite flash starting at byte `offset`.
+    pub fn set_contents(&self, offset: usize, data: &[u8]) {
+        self.contents.borrow_mut()[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Make the next operation fail when it completes.
+    pub fn fail_next(&self) {
+        self.fail_next.set(true);
+    }
+
+    pub fn pending(&self) -> Option<FlashOperation> {
+        self.pending.get()
+    }
+
+    /// Return and forget the operations started so far.
+    pub fn take_operations(&self) -> Vec<FlashOperation> {
+        self.operations.replace(Vec::new())
+    }
+
+    /// Carry out the outstanding operation and deliver its callback. Returns
+    /// `false` if there was none.
+    pub fn complete(&self) -> bool {
+        let operation = match self.pending.take() {
+            Some(operation) => operation,
+            None => return false,
+        };
+        let error = if self.fail_next.replace(false) {
+            hil::flash::Error::FlashError
+        } else {
+            hil::flash::Error::CommandComplete
+        };
+        let succeeded = error == hil::flash::Error::CommandComplete;
+        match operation {
+            FlashOperation::Read(page) => {
+                let buffer = self.buffer.take().unwrap();
+                if succeeded {
+                    buffer
+                        .0
+                        .copy_from_slice(&self.contents.borrow()[Self::range(page)]);
+                }
+                self.client
+                    .map(move |client| client.read_complete(buffer, error));
+            }
+            FlashOperation::Write(page) => {
+                let buffer = self.buffer.take().unwrap();
+                if succeeded {
+                    self.contents.borrow_mut()[Self::range(page)].copy_from_slice(&buffer.0);
+                }
+                self.client
+                    .map(move |client| client.write_complete(buffer, error));
+            }
+            FlashOperation::Erase(page) => {
+                if succeeded {
+                    for byte in &mut self.contents.borrow_mut()[Self::range(page)] {
+                        *byte = ERASED;
+                    }
+                }
+                self.client.map(|client| client.erase_complete(error));
+            }
+        }
+        true
+    }
+
+    fn range(page: usize) -> core::ops::Range<usize> {
+        page * PAGE_SIZE..(page + 1) * PAGE_SIZE
+    }
+
+    fn start(&self, operation: FlashOperation, page: usize) -> Result<(), ErrorCode> {
+        if self.pending.get().is_some() {
+            Err(ErrorCode::BUSY)
+        } else if (page + 1) * PAGE_SIZE > self.contents.borrow().len() {
+            Err(ErrorCode::INVAL)
+        } else {
+            self.operations.borrow_mut().push(operation);
+            self.pending.set(Some(operation));
+            Ok(())
+        }
+    }
+}
+
+impl<'a, C: hil::flash::Client<Self>> hil::flash::HasClient<'a, C> for MockFlash<'a> {
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl hil::flash::Flash for MockFlash<'_> {
+    type Page = MockPage;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(FlashOperation::Read(page_number), page_number) {
+            Ok(()) => {
+                self.buffer.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(FlashOperation::Write(page_number), page_number) {
+            Ok(()) => {
+                self.buffer.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        self.start(FlashOperation::Erase(page_number), page_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::hil::flash::{Flash, HasClient};
+
+    struct Client {
+        results: RefCell<Vec<hil::flash::Error>>,
+        page: TakeCell<'static, MockPage>,
+    }
+
+    impl Client {
+        fn new() -> Client {
+            Client {
+                results: RefCell::new(Vec::new()),
+                page: TakeCell::empty(),
+            }
+        }
+    }
+
+    impl hil::flash::Client<MockFlash<'_>> for Client {
+        fn read_complete(&self, buffer: &'static mut MockPage, error: hil::flash::Error) {
+            self.results.borrow_mut().push(error);
+            self.page.replace(buffer);
+        }
+
+        fn write_complete(&self, buffer: &'static mut MockPage, error: hil::flash::Error) {
+            self.results.borrow_mut().push(error);
+            self.page.replace(buffer);
+        }
+
+        fn erase_complete(&self, error: hil::flash::Error) {
+            self.results.borrow_mut().push(error);
+        }
+    }
+
+    #[test]
+    fn write_then_read_back() {
+        let flash = MockFlash::new(4);
+        let client = Client::new();
+        flash.set_client(&client);
+
+        let page = Box::leak(Box::new(MockPage::default()));
+        page[0] = 0x42;
+        assert!(flash.write_page(2, page).is_ok());
+        // Nothing changes until the operation completes.
+        assert_eq!(flash.contents()[2 * PAGE_SIZE], ERASED);
+        assert!(flash.erase_page(1).is_err());
+        assert!(flash.complete());
+        assert_eq!(flash.contents()[2 * PAGE_SIZE], 0x42);
+
+        let page = client.page.take().unwrap();
+        page[0] = 0;
+        assert!(flash.read_page(2, page).is_ok());
+        assert!(flash.complete());
+        assert_eq!(client.page.map(|page| page[0]), Some(0x42));
+        assert_eq!(
+            flash.take_operations(),
+            vec![FlashOperation::Write(2), FlashOperation::Read(2)]
+        );
+    }
+
+    #[test]
+    fn injected_failure_leaves_contents_alone() {
+        let flash = MockFlash::new(1);
+        let client = Client::new();
+        flash.set_client(&client);
+        flash.set_contents(0, &[1, 2, 3]);
+
+        flash.fail_next();
+        assert!(flash.erase_page(0).is_ok());
+        assert!(flash.complete());
+        assert_eq!(flash.contents()[..3], [1, 2, 3]);
+        assert!(flash.erase_page(0).is_ok());
+        assert!(flash.complete());
+        assert_eq!(flash.contents()[..3], [ERASED; 3]);
+        assert_eq!(
+            *client.results.borrow(),
+            vec![
+                hil::flash::Error::FlashError,
+                hil::flash::Error::CommandComplete
+            ]
+        );
+        assert!(flash.erase_page(1).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+//! Mock GPIO pin.
+//!
+//! The test drives the pin's external input level with
+//! [`MockPin::set_input`], which fires the interrupt client if interrupts are
+//! enabled for that edge, and observes the level the capsule drives with
+//! [`MockPin::output_level`]. Reading the pin returns the driven level while
+//! it is configured as an output, and the external input level otherwise.
+
+use core::cell::Cell;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::gpio::{
+    self, Configuration, Configure, FloatingState, Input, InterruptEdge, Output,
+};
+
+pub struct MockPin<'a> {
+    client: OptionalCell<&'a dyn gpio::Client>,
+    input_enabled: Cell<bool>,
+    output_enabled: Cell<bool>,
+    floating_state: Cell<FloatingState>,
+    output_level: Cell<bool>,
+    input_level: Cell<bool>,
+    // (rising, falling) edges that fire interrupts, if enabled.
+    interrupt_edges: Cell<Option<(bool, bool)>>,
+    interrupts: Cell<usize>,
+}
+
+impl<'a> MockPin<'a> {
+    pub fn new() -> MockPin<'a> {
+        MockPin {
+            client: OptionalCell::empty(),
+            input_enabled: Cell::new(false),
+            output_enabled: Cell::new(false),
+            floating_state: Cell::new(FloatingState::PullNone),
+            output_level: Cell::new(false),
+            input_level: Cell::new(false),
+            interrupt_edges: Cell::new(None),
+            interrupts: Cell::new(0),
+        }
+    }
+
+    /// Set the level of the signal driving the pin from outside, firing an
+    /// interrupt if the change is an enabled edge. Returns whether an
+    /// interrupt fired.
+    pub fn set_input(&self, level: bool) -> bool {
+        let previous = self.input_level.replace(level);
+        let fire = match self.interrupt_edges.get() {
+            Some((rising, falling)) => {
+                (rising && !previous && level) || (falling && previous && !level)
+            }
+            None => false,
+        };
+        if fire {
+            self.interrupts.set(self.interrupts.get() + 1);
+            self.client.map(|client| client.fired());
+        }
+        fire
+    }
+
+    /// The level the pin is driving as an output.
+    pub fn output_level(&self) -> bool {
+        self.output_level.get()
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupt_edges.get().is_some()
+    }
+
+    /// Total number of interrupts fired.
+    pub fn interrupt_count(&self) -> usize {
+        self.interrupts.get()
+    }
+}
+
+impl Configure for MockPin<'_> {
+    fn configuration(&self) -> Configuration {
+        match (self.input_enabled.get(), self.output_enabled.get()) {
+            (true, true) => Configuration::InputOutput,
+            (true, false) => Configuration::Input,
+            (false, true) => Configuration::Output,
+            (false, false) => Configuration::LowPower,
+        }
+    }
+
+    fn make_output(&self) -> Configuration {
+        self.output_enabled.set(true);
+        self.configuration()
+    }
+
+    fn disable_output(&self) -> Configuration {
+        self.output_enabled.set(false);
+        self.configuration()
+    }
+
+    fn make_input(&self) -> Configuration {
+        self.input_enabled.set(true);
+        self.configuration()
+    }
+
+    fn disable_input(&self) -> Configuration {
+        self.input_enabled.set(false);
+        self.configuration()
+    }
+
+    fn deactivate_to_low_power(&self) {
+        self.input_enabled.set(false);
+        self.output_enabled.set(false);
+    }
+
+    fn set_floating_state(&self, state: FloatingState) {
+        self.floating_state.set(state);
+    }
+
+    fn floating_state(&self) -> FloatingState {
+        self.floating_state.get()
+    }
+}
+
+impl Output for MockPin<'_> {
+    fn set(&self) {
+        self.output_level.set(true);
+    }
+
+    fn clear(&self) {
+        self.output_level.set(false);
+    }
+
+    fn toggle(&self) -> bool {
+        let level = !self.output_level.get();
+        self.output_level.set(level);
+        level
+    }
+}
+
+impl Input for MockPin<'_> {
+    fn read(&self) -> bool {
+        if self.output_enabled.get() {
+            self.output_level.get()
+        } else {
+            self.input_level.get()
+        }
+    }
+}
+
+impl<'a> gpio::Interrupt<'a> for MockPin<'a> {
+    fn set_client(&self, client: &'a dyn gpio::Client) {
+        self.client.set(client);
+    }
+
+    fn enable_interrupts(&self, mode: InterruptEdge) {
+        self.interrupt_edges.set(Some(match mode {
+            InterruptEdge::RisingEdge => (true, false),
+            InterruptEdge::FallingEdge => (false, true),
+            InterruptEdge::EitherEdge => (true, true),
+        }));
+    }
+
+    fn disable_interrupts(&self) {
+        self.interrupt_edges.set(None);
+    }
+
+    fn is_pending(&self) -> bool {
+        // Interrupts are delivered as soon as the input changes.
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::hil::gpio::Interrupt;
+
+    struct Counter(Cell<usize>);
+
+    impl gpio::Client for Counter {
+        fn fired(&self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn interrupts_fire_on_enabled_edges() {
+        let pin = MockPin::new();
+        let counter = Counter(Cell::new(0));
+        pin.set_client(&counter);
+        pin.make_input();
+
+        assert!(!pin.set_input(true));
+        pin.enable_interrupts(InterruptEdge::FallingEdge);
+        assert!(!pin.set_input(true));
+        assert!(pin.set_input(false));
+        assert!(pin.read() == false);
+        pin.disable_interrupts();
+        assert!(!pin.set_input(true));
+        assert_eq!(counter.0.get(), 1);
+    }
+
+    #[test]
+    fn output_is_observable() {
+        let pin = MockPin::new();
+        pin.make_output();
+        pin.set();
+        assert!(pin.output_level());
+        assert!(!pin.toggle());
+        assert!(!pin.read());
+        assert!(pin.is_output() && !pin.is_input());
+    }
+}
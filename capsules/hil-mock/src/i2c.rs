@@ -0,0 +1,187 @@
+//! Mock I2C device with scripted responses.
+//!
+//! Every transaction the capsule starts is logged as an [`I2CTransaction`].
+//! [`MockI2CDevice::complete`] finishes the outstanding transaction with the
+//! next scripted response: its data is copied to the start of the buffer (up
+//! to the read length) and its status is passed to the client. With no
+//! response scripted, transactions succeed and reads leave the buffer as is.
+
+use core::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::i2c::{Error, I2CClient, I2CDevice};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum I2CTransaction {
+    Write(Vec<u8>),
+    Read(usize),
+    WriteRead(Vec<u8>, usize),
+}
+
+pub struct MockI2CDevice<'a> {
+    client: OptionalCell<&'a dyn I2CClient>,
+    enabled: Cell<bool>,
+    buffer: TakeCell<'static, [u8]>,
+    read_len: Cell<usize>,
+    transactions: RefCell<Vec<I2CTransaction>>,
+    responses: RefCell<VecDeque<Result<Vec<u8>, Error>>>,
+}
+
+impl<'a> MockI2CDevice<'a> {
+    pub fn new() -> MockI2CDevice<'a> {
+        MockI2CDevice {
+            client: OptionalCell::empty(),
+            enabled: Cell::new(false),
+            buffer: TakeCell::empty(),
+            read_len: Cell::new(0),
+            transactions: RefCell::new(Vec::new()),
+            responses: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn I2CClient) {
+        self.client.set(client);
+    }
+
+    /// Script the result of a future transaction. Responses are used in the
+    /// order they were added.
+    pub fn push_response(&self, response: Result<Vec<u8>, Error>) {
+        self.responses.borrow_mut().push_back(response);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// Return and forget the transactions started so far.
+    pub fn take_transactions(&self) -> Vec<I2CTransaction> {
+        self.transactions.replace(Vec::new())
+    }
+
+    /// Finish the outstanding transaction. Returns `false` if there was none.
+    pub fn complete(&self) -> bool {
+        self.buffer
+            .take()
+            .map(|buffer| {
+                let response = self.responses.borrow_mut().pop_front();
+                let status = match response.unwrap_or(Ok(Vec::new())) {
+                    Ok(data) => {
+                        let len = data.len().min(self.read_len.get()).min(buffer.len());
+                        buffer[..len].copy_from_slice(&data[..len]);
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                };
+                self.client
+                    .map(move |client| client.command_complete(buffer, status));
+            })
+            .is_some()
+    }
+
+    fn start(
+        &self,
+        buffer: &'static mut [u8],
+        transaction: I2CTransaction,
+        read_len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.buffer.is_some() {
+            return Err((Error::Busy, buffer));
+        }
+        self.transactions.borrow_mut().push(transaction);
+        self.read_len.set(read_len);
+        self.buffer.replace(buffer);
+        Ok(())
+    }
+}
+
+impl I2CDevice for MockI2CDevice<'_> {
+    fn enable(&self) {
+        self.enabled.set(true);
+    }
+
+    fn disable(&self) {
+        self.enabled.set(false);
+    }
+
+    fn write_read(
+        &self,
+        data: &'static mut [u8],
+        write_len: u8,
+        read_len: u8,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        let write_len = (write_len as usize).min(data.len());
+        let transaction = I2CTransaction::WriteRead(data[..write_len].to_vec(), read_len as usize);
+        self.start(data, transaction, read_len as usize)
+    }
+
+    fn write(&self, data: &'static mut [u8], len: u8) -> Result<(), (Error, &'static mut [u8])> {
+        let len = (len as usize).min(data.len());
+        let transaction = I2CTransaction::Write(data[..len].to_vec());
+        self.start(data, transaction, 0)
+    }
+
+    fn read(&self, buffer: &'static mut [u8], len: u8) -> Result<(), (Error, &'static mut [u8])> {
+        self.start(buffer, I2CTransaction::Read(len as usize), len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Client {
+        results: RefCell<Vec<(Vec<u8>, Result<(), Error>)>>,
+    }
+
+    impl I2CClient for Client {
+        fn command_complete(&self, buffer: &'static mut [u8], status: Result<(), Error>) {
+            self.results.borrow_mut().push((buffer.to_vec(), status));
+        }
+    }
+
+    #[test]
+    fn write_read_returns_scripted_data() {
+        let device = MockI2CDevice::new();
+        let client = Client::default();
+        device.set_client(&client);
+        device.push_response(Ok(vec![0xAB, 0xCD, 0xEF]));
+
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0x0F, 0, 0]));
+        assert!(device.write_read(buffer, 1, 2).is_ok());
+        assert!(device.is_busy());
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0; 1]));
+        assert!(matches!(device.read(buffer, 1), Err((Error::Busy, _))));
+
+        assert!(device.complete());
+        assert_eq!(
+            device.take_transactions(),
+            vec![I2CTransaction::WriteRead(vec![0x0F], 2)]
+        );
+        assert_eq!(
+            *client.results.borrow(),
+            vec![(vec![0xAB, 0xCD, 0], Ok(()))]
+        );
+    }
+
+    #[test]
+    fn scripted_errors_are_reported() {
+        let device = MockI2CDevice::new();
+        let client = Client::default();
+        device.set_client(&client);
+        device.push_response(Err(Error::AddressNak));
+
+        let buffer: &'static mut [u8] = Box::leak(Box::new([1, 2]));
+        assert!(device.write(buffer, 2).is_ok());
+        assert!(device.complete());
+        assert_eq!(
+            *client.results.borrow(),
+            vec![(vec![1, 2], Err(Error::AddressNak))]
+        );
+    }
+}
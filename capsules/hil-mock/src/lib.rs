@@ -0,0 +1,27 @@
+//! Scriptable mock implementations of kernel HILs for testing capsules.
+//!
+//! The mocks let a capsule's state machine be driven deterministically under
+//! `cargo test` on the host, without hardware. Each mock records the requests
+//! a capsule makes of it and holds on to the outstanding operation. Nothing
+//! completes on its own: the test decides when a "hardware" event happens by
+//! calling a method on the mock (`advance`, `complete`, `receive`, `set_input`
+//! and so on), which invokes the capsule's client callback synchronously,
+//! just as an interrupt handler would.
+//!
+//! Capsules keep `&'static` buffers, so tests typically leak them:
+//!
+//! ```rust,ignore
+//! let uart = Box::leak(Box::new(hil_mock::uart::MockUart::new()));
+//! let buffer: &'static mut [u8] = Box::leak(Box::new([0; 16]));
+//! ```
+//!
+//! This crate uses `std` and is meant only as a dev-dependency.
+
+pub mod alarm;
+pub mod flash;
+pub mod gpio;
+pub mod i2c;
+pub mod screen;
+pub mod spi;
+pub mod uart;
+pub mod usb;
@@ -0,0 +1,283 @@
+//! Mock screen that records drawing commands.
+//!
+//! The screen accepts one operation at a time. Each operation is logged as a
+//! [`ScreenOperation`] when it starts, and its callback is delivered when the
+//! test calls [`MockScreen::complete`]. Setup operations (resolution, pixel
+//! format, rotation) complete to the `ScreenSetupClient`, all others to the
+//! `ScreenClient`.
+
+use core::cell::{Cell, RefCell};
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::screen::{
+    Screen, ScreenClient, ScreenPixelFormat, ScreenRotation, ScreenSetup, ScreenSetupClient,
+};
+use kernel::ErrorCode;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScreenOperation {
+    SetWriteFrame {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+    Write(Vec<u8>),
+    WriteContinue(Vec<u8>),
+    SetBrightness(usize),
+    InvertOn,
+    InvertOff,
+    SetResolution((usize, usize)),
+    SetPixelFormat(usize),
+    SetRotation(usize),
+}
+
+/// Pixel formats the mock screen supports.
+const PIXEL_FORMATS: [ScreenPixelFormat; 2] = [ScreenPixelFormat::Mono, ScreenPixelFormat::RGB_565];
+
+pub struct MockScreen {
+    client: OptionalCell<&'static dyn ScreenClient>,
+    setup_client: OptionalCell<&'static dyn ScreenSetupClient>,
+    resolution: Cell<(usize, usize)>,
+    pixel_format: Cell<ScreenPixelFormat>,
+    rotation: Cell<ScreenRotation>,
+    pending: Cell<Option<bool>>,
+    buffer: TakeCell<'static, [u8]>,
+    operations: RefCell<Vec<ScreenOperation>>,
+}
+
+impl MockScreen {
+    pub fn new(resolution: (usize, usize)) -> MockScreen {
+        MockScreen {
+            client: OptionalCell::empty(),
+            setup_client: OptionalCell::empty(),
+            resolution: Cell::new(resolution),
+            pixel_format: Cell::new(ScreenPixelFormat::Mono),
+            rotation: Cell::new(ScreenRotation::Normal),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            operations: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Tell the client the screen has finished initializing.
+    pub fn ready(&self) {
+        self.client.map(|client| client.screen_is_ready());
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.pending.get().is_some()
+    }
+
+    /// Return and forget the operations started so far.
+    pub fn take_operations(&self) -> Vec<ScreenOperation> {
+        self.operations.replace(Vec::new())
+    }
+
+    /// Finish the outstanding operation with `result`. Returns `false` if
+    /// there was none.
+    pub fn complete(&self, result: Result<(), ErrorCode>) -> bool {
+        match self.pending.take() {
+            Some(true) => {
+                self.setup_client
+                    .map(|client| client.command_complete(result));
+            }
+            Some(false) => match self.buffer.take() {
+                Some(buffer) => {
+                    self.client
+                        .map(move |client| client.write_complete(buffer, result));
+                }
+                None => {
+                    self.client.map(|client| client.command_complete(result));
+                }
+            },
+            None => return false,
+        }
+        true
+    }
+
+    fn start(&self, operation: ScreenOperation, setup: bool) -> Result<(), ErrorCode> {
+        if self.pending.get().is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.operations.borrow_mut().push(operation);
+        self.pending.set(Some(setup));
+        Ok(())
+    }
+
+    fn start_write(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+        continued: bool,
+    ) -> Result<(), ErrorCode> {
+        let data = buffer[..len.min(buffer.len())].to_vec();
+        let operation = if continued {
+            ScreenOperation::WriteContinue(data)
+        } else {
+            ScreenOperation::Write(data)
+        };
+        self.start(operation, false)?;
+        self.buffer.replace(buffer);
+        Ok(())
+    }
+}
+
+impl Screen for MockScreen {
+    fn get_resolution(&self) -> (usize, usize) {
+        self.resolution.get()
+    }
+
+    fn get_pixel_format(&self) -> ScreenPixelFormat {
+        self.pixel_format.get()
+    }
+
+    fn get_rotation(&self) -> ScreenRotation {
+        self.rotation.get()
+    }
+
+    fn set_write_frame(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<(), ErrorCode> {
+        let (screen_width, screen_height) = self.resolution.get();
+        if x.saturating_add(width) > screen_width || y.saturating_add(height) > screen_height {
+            return Err(ErrorCode::INVAL);
+        }
+        self.start(
+            ScreenOperation::SetWriteFrame {
+                x,
+                y,
+                width,
+                height,
+            },
+            false,
+        )
+    }
+
+    fn write(&self, buffer: &'static mut [u8], len: usize) -> Result<(), ErrorCode> {
+        self.start_write(buffer, len, false)
+    }
+
+    fn write_continue(&self, buffer: &'static mut [u8], len: usize) -> Result<(), ErrorCode> {
+        self.start_write(buffer, len, true)
+    }
+
+    fn set_client(&self, client: Option<&'static dyn ScreenClient>) {
+        self.client.insert(client);
+    }
+
+    fn set_brightness(&self, brightness: usize) -> Result<(), ErrorCode> {
+        self.start(ScreenOperation::SetBrightness(brightness), false)
+    }
+
+    fn invert_on(&self) -> Result<(), ErrorCode> {
+        self.start(ScreenOperation::InvertOn, false)
+    }
+
+    fn invert_off(&self) -> Result<(), ErrorCode> {
+        self.start(ScreenOperation::InvertOff, false)
+    }
+}
+
+impl ScreenSetup for MockScreen {
+    fn set_client(&self, client: Option<&'static dyn ScreenSetupClient>) {
+        self.setup_client.insert(client);
+    }
+
+    fn set_resolution(&self, resolution: (usize, usize)) -> Result<(), ErrorCode> {
+        self.start(ScreenOperation::SetResolution(resolution), true)?;
+        self.resolution.set(resolution);
+        Ok(())
+    }
+
+    fn set_pixel_format(&self, depth: ScreenPixelFormat) -> Result<(), ErrorCode> {
+        if !PIXEL_FORMATS.contains(&depth) {
+            return Err(ErrorCode::INVAL);
+        }
+        self.start(ScreenOperation::SetPixelFormat(depth as usize), true)?;
+        self.pixel_format.set(depth);
+        Ok(())
+    }
+
+    fn set_rotation(&self, rotation: ScreenRotation) -> Result<(), ErrorCode> {
+        self.start(ScreenOperation::SetRotation(rotation as usize), true)?;
+        self.rotation.set(rotation);
+        Ok(())
+    }
+
+    fn get_num_supported_resolutions(&self) -> usize {
+        1
+    }
+
+    fn get_supported_resolution(&self, index: usize) -> Option<(usize, usize)> {
+        match index {
+            0 => Some(self.resolution.get()),
+            _ => None,
+        }
+    }
+
+    fn get_num_supported_pixel_formats(&self) -> usize {
+        PIXEL_FORMATS.len()
+    }
+
+    fn get_supported_pixel_format(&self, index: usize) -> Option<ScreenPixelFormat> {
+        PIXEL_FORMATS.get(index).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Client {
+        commands: Cell<usize>,
+        writes: Cell<usize>,
+    }
+
+    impl ScreenClient for Client {
+        fn command_complete(&self, _r: Result<(), ErrorCode>) {
+            self.commands.set(self.commands.get() + 1);
+        }
+
+        fn write_complete(&self, _buffer: &'static mut [u8], _r: Result<(), ErrorCode>) {
+            self.writes.set(self.writes.get() + 1);
+        }
+
+        fn screen_is_ready(&self) {}
+    }
+
+    #[test]
+    fn operations_complete_one_at_a_time() {
+        let screen = MockScreen::new((16, 8));
+        let client: &'static Client = Box::leak(Box::new(Client::default()));
+        Screen::set_client(&screen, Some(client));
+
+        assert_eq!(screen.set_write_frame(0, 0, 17, 1), Err(ErrorCode::INVAL));
+        assert_eq!(screen.set_write_frame(0, 0, 8, 8), Ok(()));
+        assert_eq!(screen.invert_on(), Err(ErrorCode::BUSY));
+        assert!(screen.complete(Ok(())));
+        assert_eq!(client.commands.get(), 1);
+
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0xAA; 4]));
+        assert_eq!(screen.write(buffer, 2), Ok(()));
+        assert!(screen.complete(Ok(())));
+        assert_eq!(client.writes.get(), 1);
+        assert_eq!(
+            screen.take_operations(),
+            vec![
+                ScreenOperation::SetWriteFrame {
+                    x: 0,
+                    y: 0,
+                    width: 8,
+                    height: 8
+                },
+                ScreenOperation::Write(vec![0xAA, 0xAA]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Mock SPI master device with scripted responses.
+//!
+//! The bytes written by each transfer are logged when it starts.
+//! [`MockSpiMasterDevice::complete`] finishes the outstanding transfer,
+//! copying the next scripted response into the read buffer (if the transfer
+//! has one). With no response scripted, the read buffer is left as is.
+
+use core::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::spi::{ClockPhase, ClockPolarity, SpiMasterClient, SpiMasterDevice};
+use kernel::ErrorCode;
+
+pub struct MockSpiMasterDevice<'a> {
+    client: OptionalCell<&'a dyn SpiMasterClient>,
+    polarity: Cell<ClockPolarity>,
+    phase: Cell<ClockPhase>,
+    rate: Cell<u32>,
+    write_buffer: TakeCell<'static, [u8]>,
+    read_buffer: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    written: RefCell<Vec<Vec<u8>>>,
+    responses: RefCell<VecDeque<Vec<u8>>>,
+}
+
+impl<'a> MockSpiMasterDevice<'a> {
+    pub fn new() -> MockSpiMasterDevice<'a> {
+        MockSpiMasterDevice {
+            client: OptionalCell::empty(),
+            polarity: Cell::new(ClockPolarity::IdleLow),
+            phase: Cell::new(ClockPhase::SampleLeading),
+            rate: Cell::new(0),
+            write_buffer: TakeCell::empty(),
+            read_buffer: TakeCell::empty(),
+            len: Cell::new(0),
+            written: RefCell::new(Vec::new()),
+            responses: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn SpiMasterClient) {
+        self.client.set(client);
+    }
+
+    /// Script the bytes read by a future transfer. Responses are used in the
+    /// order they were added.
+    pub fn push_response(&self, response: Vec<u8>) {
+        self.responses.borrow_mut().push_back(response);
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.write_buffer.is_some()
+    }
+
+    /// Return and forget the bytes written by each transfer so far.
+    pub fn take_written(&self) -> Vec<Vec<u8>> {
+        self.written.replace(Vec::new())
+    }
+
+    /// Finish the outstanding transfer. Returns `false` if there was none.
+    pub fn complete(&self) -> bool {
+        self.write_buffer
+            .take()
+            .map(|write_buffer| {
+                let len = self.len.get();
+                let response = self.responses.borrow_mut().pop_front();
+                let read_buffer = self.read_buffer.take().map(|read_buffer| {
+                    if let Some(data) = response {
+                        let n = data.len().min(len);
+                        read_buffer[..n].copy_from_slice(&data[..n]);
+                    }
+                    read_buffer
+                });
+                self.client
+                    .map(move |client| client.read_write_done(write_buffer, read_buffer, len));
+            })
+            .is_some()
+    }
+}
+
+impl SpiMasterDevice for MockSpiMasterDevice<'_> {
+    fn configure(&self, cpol: ClockPolarity, cpal: ClockPhase, rate: u32) {
+        self.polarity.set(cpol);
+        self.phase.set(cpal);
+        self.rate.set(rate);
+    }
+
+    fn read_write_bytes(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.write_buffer.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let len = read_buffer
+            .as_ref()
+            .map_or(len, |read_buffer| len.min(read_buffer.len()))
+            .min(write_buffer.len());
+        self.written.borrow_mut().push(write_buffer[..len].to_vec());
+        self.len.set(len);
+        self.write_buffer.replace(write_buffer);
+        self.read_buffer.put(read_buffer);
+        Ok(())
+    }
+
+    fn set_polarity(&self, cpol: ClockPolarity) {
+        self.polarity.set(cpol);
+    }
+
+    fn set_phase(&self, cpal: ClockPhase) {
+        self.phase.set(cpal);
+    }
+
+    fn set_rate(&self, rate: u32) {
+        self.rate.set(rate);
+    }
+
+    fn get_polarity(&self) -> ClockPolarity {
+        self.polarity.get()
+    }
+
+    fn get_phase(&self) -> ClockPhase {
+        self.phase.get()
+    }
+
+    fn get_rate(&self) -> u32 {
+        self.rate.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Client {
+        read: RefCell<Option<Vec<u8>>>,
+        len: Cell<usize>,
+    }
+
+    impl SpiMasterClient for Client {
+        fn read_write_done(
+            &self,
+            _write_buffer: &'static mut [u8],
+            read_buffer: Option<&'static mut [u8]>,
+            len: usize,
+        ) {
+            self.len.set(len);
+            *self.read.borrow_mut() = read_buffer.map(|buffer| buffer.to_vec());
+        }
+    }
+
+    #[test]
+    fn transfer_reads_scripted_bytes() {
+        let spi = MockSpiMasterDevice::new();
+        let client = Client::default();
+        spi.set_client(&client);
+        spi.configure(
+            ClockPolarity::IdleHigh,
+            ClockPhase::SampleTrailing,
+            1_000_000,
+        );
+        spi.push_response(vec![7, 8, 9]);
+
+        let write: &'static mut [u8] = Box::leak(Box::new([1, 2, 3, 4]));
+        let read: &'static mut [u8] = Box::leak(Box::new([0; 2]));
+        assert_eq!(spi.read_write_bytes(write, Some(read), 4), Ok(()));
+        assert_eq!(spi.take_written(), vec![vec![1, 2]]);
+        assert!(spi.complete());
+        assert_eq!(client.len.get(), 2);
+        assert_eq!(*client.read.borrow(), Some(vec![7, 8]));
+        assert_eq!(spi.get_rate(), 1_000_000);
+    }
+}
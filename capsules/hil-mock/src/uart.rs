@@ -0,0 +1,288 @@
+//! Mock UART that records transmitted bytes and injects received ones.
+//!
+//! A transmission is recorded as soon as it starts, and completes when the
+//! test calls [`MockUart::complete_transmit`]. Bytes passed to
+//! [`MockUart::receive`] fill the outstanding receive buffer, completing it
+//! once it is full; bytes received with no buffer outstanding are queued
+//! until the next call to `receive`.
+
+use core::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::uart;
+use kernel::ErrorCode;
+
+pub struct MockUart<'a> {
+    parameters: Cell<Option<uart::Parameters>>,
+    tx_client: OptionalCell<&'a dyn uart::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_aborted: Cell<bool>,
+    transmitted: RefCell<Vec<u8>>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_filled: Cell<usize>,
+    rx_aborted: Cell<bool>,
+    rx_queue: RefCell<VecDeque<u8>>,
+}
+
+impl<'a> MockUart<'a> {
+    pub fn new() -> MockUart<'a> {
+        MockUart {
+            parameters: Cell::new(None),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_aborted: Cell::new(false),
+            transmitted: RefCell::new(Vec::new()),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_filled: Cell::new(0),
+            rx_aborted: Cell::new(false),
+            rx_queue: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    /// The parameters from the last call to `configure`.
+    pub fn parameters(&self) -> Option<uart::Parameters> {
+        self.parameters.get()
+    }
+
+    pub fn is_transmitting(&self) -> bool {
+        self.tx_buffer.is_some()
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.rx_buffer.is_some()
+    }
+
+    /// Return and forget everything transmitted so far.
+    pub fn take_transmitted(&self) -> Vec<u8> {
+        self.transmitted.replace(Vec::new())
+    }
+
+    /// Complete the outstanding transmission, with `CANCEL` if it was
+    /// aborted. Returns `false` if there was none.
+    pub fn complete_transmit(&self) -> bool {
+        self.tx_buffer
+            .take()
+            .map(|buffer| {
+                let rval = if self.tx_aborted.replace(false) {
+                    Err(ErrorCode::CANCEL)
+                } else {
+                    Ok(())
+                };
+                self.tx_client
+                    .map(move |client| client.transmitted_buffer(buffer, self.tx_len.get(), rval));
+            })
+            .is_some()
+    }
+
+    /// Receive `bytes`. Returns how many bytes are still queued afterwards.
+    pub fn receive(&self, bytes: &[u8]) -> usize {
+        self.rx_queue.borrow_mut().extend(bytes);
+        while self.rx_buffer.is_some() && !self.rx_queue.borrow().is_empty() {
+            let full = self
+                .rx_buffer
+                .map(|buffer| {
+                    let mut queue = self.rx_queue.borrow_mut();
+                    while self.rx_filled.get() < self.rx_len.get() {
+                        match queue.pop_front() {
+                            Some(byte) => {
+                                buffer[self.rx_filled.get()] = byte;
+                                self.rx_filled.set(self.rx_filled.get() + 1);
+                            }
+                            None => break,
+                        }
+                    }
+                    self.rx_filled.get() == self.rx_len.get()
+                })
+                .unwrap_or(false);
+            if full {
+                self.finish_receive(Ok(()), uart::Error::None);
+            }
+        }
+        self.rx_queue.borrow().len()
+    }
+
+    /// Deliver the callback for a receive that was aborted, with the bytes
+    /// received so far. Returns `false` if no receive was aborted.
+    pub fn complete_receive_abort(&self) -> bool {
+        if self.rx_aborted.get() && self.rx_buffer.is_some() {
+            self.finish_receive(Err(ErrorCode::CANCEL), uart::Error::Aborted);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn finish_receive(&self, rval: Result<(), ErrorCode>, error: uart::Error) {
+        self.rx_aborted.set(false);
+        if let Some(buffer) = self.rx_buffer.take() {
+            let len = self.rx_filled.get();
+            self.rx_client
+                .map(move |client| client.received_buffer(buffer, len, rval, error));
+        }
+    }
+}
+
+impl uart::Configure for MockUart<'_> {
+    fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
+        self.parameters.set(Some(params));
+        Ok(())
+    }
+}
+
+impl<'a> uart::Transmit<'a> for MockUart<'a> {
+    fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        if tx_len == 0 || tx_len > tx_buffer.len() {
+            return Err((ErrorCode::SIZE, tx_buffer));
+        }
+        self.transmitted
+            .borrow_mut()
+            .extend_from_slice(&tx_buffer[..tx_len]);
+        self.tx_len.set(tx_len);
+        self.tx_buffer.replace(tx_buffer);
+        Ok(())
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        if self.tx_buffer.is_some() {
+            self.tx_aborted.set(true);
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a> uart::Receive<'a> for MockUart<'a> {
+    fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, rx_buffer));
+        }
+        self.rx_len.set(rx_len);
+        self.rx_filled.set(0);
+        self.rx_buffer.replace(rx_buffer);
+        Ok(())
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        if self.rx_buffer.is_some() {
+            self.rx_aborted.set(true);
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::hil::uart::{Receive, Transmit};
+
+    #[derive(Default)]
+    struct Client {
+        received: RefCell<Vec<(Vec<u8>, Result<(), ErrorCode>)>>,
+        transmitted: Cell<usize>,
+    }
+
+    impl uart::TransmitClient for Client {
+        fn transmitted_buffer(&self, _: &'static mut [u8], _: usize, _: Result<(), ErrorCode>) {
+            self.transmitted.set(self.transmitted.get() + 1);
+        }
+    }
+
+    impl uart::ReceiveClient for Client {
+        fn received_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            rx_len: usize,
+            rval: Result<(), ErrorCode>,
+            _error: uart::Error,
+        ) {
+            self.received
+                .borrow_mut()
+                .push((rx_buffer[..rx_len].to_vec(), rval));
+        }
+    }
+
+    #[test]
+    fn transmit_completes_when_told() {
+        let uart = MockUart::new();
+        let client = Client::default();
+        uart.set_transmit_client(&client);
+
+        let buffer: &'static mut [u8] = Box::leak(Box::new(*b"hello"));
+        assert!(uart.transmit_buffer(buffer, 4).is_ok());
+        assert_eq!(uart.take_transmitted(), b"hell");
+        assert_eq!(client.transmitted.get(), 0);
+        assert!(uart.complete_transmit());
+        assert_eq!(client.transmitted.get(), 1);
+        assert!(!uart.complete_transmit());
+    }
+
+    #[test]
+    fn received_bytes_are_queued_until_a_buffer_is_ready() {
+        let uart = MockUart::new();
+        let client = Client::default();
+        uart.set_receive_client(&client);
+
+        assert_eq!(uart.receive(b"abc"), 3);
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0; 2]));
+        assert!(uart.receive_buffer(buffer, 2).is_ok());
+        assert_eq!(uart.receive(&[]), 1);
+        assert_eq!(*client.received.borrow(), vec![(b"ab".to_vec(), Ok(()))]);
+    }
+
+    #[test]
+    fn aborted_receive_returns_partial_data() {
+        let uart = MockUart::new();
+        let client = Client::default();
+        uart.set_receive_client(&client);
+
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0; 4]));
+        assert!(uart.receive_buffer(buffer, 4).is_ok());
+        uart.receive(b"x");
+        assert_eq!(uart.receive_abort(), Err(ErrorCode::BUSY));
+        assert!(uart.complete_receive_abort());
+        assert_eq!(
+            *client.received.borrow(),
+            vec![(b"x".to_vec(), Err(ErrorCode::CANCEL))]
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Mock USB device controller.
+//!
+//! The test plays the host by calling the client's `hil::usb::Client` methods
+//! (`ctrl_setup`, `packet_in`, `packet_out` and so on) directly, reading and
+//! writing the endpoint buffers the client owns. The mock only records what
+//! the client asks of the controller: [`MockUsbController::in_resumes`] and
+//! [`MockUsbController::out_resumes`] count the transfers the client resumed
+//! on each endpoint.
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, VolatileCell};
+use kernel::hil::usb::{self, DeviceSpeed, TransferType};
+
+/// Number of endpoints the mock keeps track of, including endpoint 0.
+pub const N_ENDPOINTS: usize = 8;
+
+pub struct MockUsbController<'a> {
+    client: OptionalCell<&'a dyn usb::Client<'a>>,
+    attached: Cell<bool>,
+    address: Cell<u16>,
+    in_resumes: [Cell<usize>; N_ENDPOINTS],
+    out_resumes: [Cell<usize>; N_ENDPOINTS],
+}
+
+impl<'a> MockUsbController<'a> {
+    pub fn new() -> MockUsbController<'a> {
+        const ZERO: Cell<usize> = Cell::new(0);
+        MockUsbController {
+            client: OptionalCell::empty(),
+            attached: Cell::new(false),
+            address: Cell::new(0),
+            in_resumes: [ZERO; N_ENDPOINTS],
+            out_resumes: [ZERO; N_ENDPOINTS],
+        }
+    }
+
+    pub fn attached(&self) -> bool {
+        self.attached.get()
+    }
+
+    /// The address the client was assigned with `set_address`.
+    pub fn address(&self) -> u16 {
+        self.address.get()
+    }
+
+    /// Number of times the client resumed IN transfers on `endpoint`.
+    pub fn in_resumes(&self, endpoint: usize) -> usize {
+        self.in_resumes[endpoint].get()
+    }
+
+    /// Number of times the client resumed OUT transfers on `endpoint`.
+    pub fn out_resumes(&self, endpoint: usize) -> usize {
+        self.out_resumes[endpoint].get()
+    }
+}
+
+impl<'a> usb::UsbController<'a> for MockUsbController<'a> {
+    fn set_client(&self, client: &'a dyn usb::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, _buf: &'a [VolatileCell<u8>]) {}
+
+    fn endpoint_set_in_buffer(&self, _endpoint: usize, _buf: &'a [VolatileCell<u8>]) {}
+
+    fn endpoint_set_out_buffer(&self, _endpoint: usize, _buf: &'a [VolatileCell<u8>]) {}
+
+    fn enable_as_device(&self, _speed: DeviceSpeed) {}
+
+    fn attach(&self) {
+        self.attached.set(true);
+    }
+
+    fn detach(&self) {
+        self.attached.set(false);
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.address.set(addr);
+    }
+
+    fn enable_address(&self) {}
+
+    fn endpoint_in_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+
+    fn endpoint_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+
+    fn endpoint_in_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+
+    fn endpoint_resume_in(&self, endpoint: usize) {
+        let resumes = &self.in_resumes[endpoint];
+        resumes.set(resumes.get() + 1);
+    }
+
+    fn endpoint_resume_out(&self, endpoint: usize) {
+        let resumes = &self.out_resumes[endpoint];
+        resumes.set(resumes.get() + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::hil::usb::UsbController;
+
+    #[test]
+    fn records_resumes_and_address() {
+        let usb = MockUsbController::new();
+        usb.attach();
+        usb.set_address(5);
+        usb.endpoint_resume_in(1);
+        usb.endpoint_resume_in(1);
+        usb.endpoint_resume_out(2);
+
+        assert!(usb.attached());
+        assert_eq!(usb.address(), 5);
+        assert_eq!(usb.in_resumes(1), 2);
+        assert_eq!(usb.out_resumes(1), 0);
+        assert_eq!(usb.out_resumes(2), 1);
+    }
+}
@@ -0,0 +1,658 @@
+//! Provides userspace with access to AES encryption and decryption.
+//!
+//! A process selects a mode with command 1, shares a key, an IV or nonce, a
+//! source buffer and a destination buffer, and starts the operation with
+//! command 3. The ECB, CBC and CTR modes are streamed through the kernel
+//! buffer one chunk at a time, so their messages can be of any length. The
+//! authenticated modes, CCM and GCM, process the whole message at once, and
+//! fail with `SIZE` if it does not fit in the kernel buffer.
+//!
+//! For CCM and GCM the source is the additional authenticated data followed
+//! by the message and, when decrypting, the tag. The destination receives
+//! the AAD, the encrypted or decrypted message and, when encrypting, the
+//! tag. Decrypted messages are only copied out if their tag verifies;
+//! otherwise the destination is zeroed.
+//!
+//! Operations from several processes are queued and run one at a time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let aes = static_init!(
+//!     capsules::aes::AesDriver<Aes128Gcm<'static, VirtualAES128CCM<'static, Aes>>>,
+//!     capsules::aes::AesDriver::new(
+//!         aes_gcm,
+//!         &mut capsules::aes::CRYPT_BUF,
+//!         board_kernel.create_grant(capsules::aes::DRIVER_NUM, &grant_cap),
+//!     )
+//! );
+//! AES128::set_client(aes_gcm, aes);
+//! AES128CCM::set_client(aes_gcm, aes);
+//! AES128GCM::set_client(aes_gcm, aes);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, CCMClient, Client, GCMClient, AES128, AES128CBC, AES128CCM, AES128ECB, AES128GCM,
+    AES128_BLOCK_SIZE, AES128_KEY_SIZE, CCM_NONCE_LENGTH, GCM_IV_LENGTH, GCM_TAG_LENGTH,
+};
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId};
+use kernel::{ReadOnlyProcessBuffer, ReadWriteProcessBuffer};
+use kernel::{ReadableProcessBuffer, WriteableProcessBuffer};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Aes as usize;
+
+/// Default kernel buffer. It bounds the size of CCM and GCM messages, and of
+/// the chunks the other modes pass to the hardware.
+pub static mut CRYPT_BUF: [u8; 128] = [0; 128];
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Mode {
+    Ecb,
+    Cbc,
+    Ctr,
+    Ccm,
+    Gcm,
+}
+
+impl Mode {
+    fn from_usize(mode: usize) -> Option<Mode> {
+        match mode {
+            0 => Some(Mode::Ecb),
+            1 => Some(Mode::Cbc),
+            2 => Some(Mode::Ctr),
+            3 => Some(Mode::Ccm),
+            4 => Some(Mode::Gcm),
+            _ => None,
+        }
+    }
+}
+
+/// How the operation being started is processed.
+enum Operation {
+    /// A block mode over `len` bytes of the source.
+    Stream(usize),
+    /// An authenticated mode over the kernel buffer.
+    Aead {
+        mode: Mode,
+        encrypting: bool,
+        aad_len: usize,
+        message_len: usize,
+        tag_len: usize,
+    },
+}
+
+#[derive(Default)]
+pub struct App {
+    key: ReadOnlyProcessBuffer,
+    iv: ReadOnlyProcessBuffer,
+    source: ReadOnlyProcessBuffer,
+    dest: ReadWriteProcessBuffer,
+    /// The mode and whether to encrypt, set with command 1.
+    mode: Cell<Option<(Mode, bool)>>,
+    aad_len: Cell<usize>,
+    tag_len: Cell<usize>,
+    /// The process asked for an operation that has not started yet.
+    pending: Cell<bool>,
+}
+
+pub struct AesDriver<
+    A: 'static
+        + AES128<'static>
+        + AES128Ctr
+        + AES128CBC
+        + AES128ECB
+        + AES128CCM<'static>
+        + AES128GCM<'static>,
+> {
+    aes: &'static A,
+    apps: Grant<App, 1>,
+    /// The process whose operation is in progress.
+    current: OptionalCell<ProcessId>,
+
+    buf: TakeCell<'static, [u8]>,
+    /// `(offset, len)` of a streamed operation.
+    progress: Cell<(usize, usize)>,
+    /// Bytes of the source in the chunk being processed.
+    chunk: Cell<usize>,
+    /// Bytes to copy out when an authenticated operation finishes.
+    output_len: Cell<usize>,
+    /// Whether the authenticated operation in progress is a decryption, so
+    /// its output must only be released if the tag verifies.
+    decrypting: Cell<bool>,
+}
+
+impl<
+        A: 'static
+            + AES128<'static>
+            + AES128Ctr
+            + AES128CBC
+            + AES128ECB
+            + AES128CCM<'static>
+            + AES128GCM<'static>,
+    > AesDriver<A>
+{
+    pub fn new(aes: &'static A, buf: &'static mut [u8], grant: Grant<App, 1>) -> AesDriver<A> {
+        AesDriver {
+            aes,
+            apps: grant,
+            current: OptionalCell::empty(),
+            buf: TakeCell::new(buf),
+            progress: Cell::new((0, 0)),
+            chunk: Cell::new(0),
+            output_len: Cell::new(0),
+            decrypting: Cell::new(false),
+        }
+    }
+
+    /// Program the process's key, IV and mode, and check its buffers.
+    /// Authenticated operations are copied into the kernel buffer.
+    fn configure(&self, app: &App) -> Result<Operation, ErrorCode> {
+        let (mode, encrypting) = app.mode.get().ok_or(ErrorCode::RESERVE)?;
+
+        let mut key = [0u8; AES128_KEY_SIZE];
+        app.key
+            .enter(|data| {
+                if data.len() != AES128_KEY_SIZE {
+                    return Err(ErrorCode::INVAL);
+                }
+                data.copy_to_slice(&mut key);
+                Ok(())
+            })
+            .unwrap_or(Err(ErrorCode::RESERVE))?;
+
+        let iv_len = match mode {
+            Mode::Ecb => 0,
+            Mode::Cbc | Mode::Ctr => AES128_BLOCK_SIZE,
+            Mode::Ccm => CCM_NONCE_LENGTH,
+            Mode::Gcm => GCM_IV_LENGTH,
+        };
+        let mut iv = [0u8; AES128_BLOCK_SIZE];
+        if iv_len != 0 {
+            app.iv
+                .enter(|data| {
+                    if data.len() != iv_len {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    data.copy_to_slice(&mut iv[..iv_len]);
+                    Ok(())
+                })
+                .unwrap_or(Err(ErrorCode::RESERVE))?;
+        }
+        let iv = &iv[..iv_len];
+
+        match mode {
+            Mode::Ecb => self.aes.set_mode_aes128ecb(encrypting)?,
+            Mode::Cbc => self.aes.set_mode_aes128cbc(encrypting)?,
+            Mode::Ctr => self.aes.set_mode_aes128ctr(encrypting)?,
+            Mode::Ccm => {
+                AES128CCM::set_key(self.aes, &key)?;
+                self.aes.set_nonce(iv)?;
+            }
+            Mode::Gcm => {
+                AES128GCM::set_key(self.aes, &key)?;
+                AES128GCM::set_iv(self.aes, iv)?;
+            }
+        }
+
+        let source_len = app.source.len();
+        let dest_len = app.dest.len();
+        match mode {
+            Mode::Ecb | Mode::Cbc | Mode::Ctr => {
+                // CTR is a stream cipher; the other modes need whole blocks.
+                if mode != Mode::Ctr && source_len % AES128_BLOCK_SIZE != 0 {
+                    return Err(ErrorCode::INVAL);
+                }
+                if dest_len < source_len {
+                    return Err(ErrorCode::SIZE);
+                }
+                AES128::set_key(self.aes, &key)?;
+                if iv_len != 0 {
+                    AES128::set_iv(self.aes, iv)?;
+                }
+                Ok(Operation::Stream(source_len))
+            }
+            Mode::Ccm | Mode::Gcm => {
+                let aad_len = app.aad_len.get();
+                let tag_len = if mode == Mode::Gcm {
+                    GCM_TAG_LENGTH
+                } else {
+                    app.tag_len.get()
+                };
+                let message_len = if encrypting {
+                    source_len.checked_sub(aad_len)
+                } else {
+                    source_len.checked_sub(aad_len + tag_len)
+                }
+                .ok_or(ErrorCode::INVAL)?;
+                let output_len = aad_len + message_len + if encrypting { tag_len } else { 0 };
+                if dest_len < output_len {
+                    return Err(ErrorCode::SIZE);
+                }
+
+                self.buf.map_or(Err(ErrorCode::BUSY), |buf| {
+                    if buf.len() < aad_len + message_len + tag_len {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    app.source
+                        .enter(|data| data.copy_to_slice(&mut buf[..source_len]))
+                        .map_err(ErrorCode::from)
+                })?;
+                self.output_len.set(output_len);
+                Ok(Operation::Aead {
+                    mode,
+                    encrypting,
+                    aad_len,
+                    message_len,
+                    tag_len,
+                })
+            }
+        }
+    }
+
+    /// Copy the next chunk of a streamed operation into the kernel buffer
+    /// and encrypt or decrypt it.
+    fn crypt_chunk(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let (offset, len) = self.progress.get();
+        let padded = self
+            .apps
+            .enter(appid, |app, _| {
+                self.buf.map_or(Err(ErrorCode::BUSY), |buf| {
+                    let room = buf.len() / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE;
+                    let n = cmp::min(len - offset, room);
+                    let padded =
+                        (n + AES128_BLOCK_SIZE - 1) / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE;
+                    // The process may have allowed a shorter source since
+                    // the operation started.
+                    app.source
+                        .enter(|data| {
+                            data.get(offset..offset + n)
+                                .map(|chunk| chunk.copy_to_slice(&mut buf[..n]))
+                                .ok_or(ErrorCode::SIZE)
+                        })
+                        .unwrap_or_else(|err| Err(err.into()))?;
+                    buf[n..padded].iter_mut().for_each(|b| *b = 0);
+                    self.chunk.set(n);
+                    Ok(padded)
+                })
+            })
+            .unwrap_or_else(|err| Err(err.into()))?;
+
+        let buf = self.buf.take().ok_or(ErrorCode::BUSY)?;
+        match AES128::crypt(self.aes, None, buf, 0, padded) {
+            None => Ok(()),
+            Some((res, _, buf)) => {
+                self.buf.replace(buf);
+                Err(res.err().unwrap_or(ErrorCode::FAIL))
+            }
+        }
+    }
+
+    /// Start the process's operation.
+    fn start(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let operation = self
+            .apps
+            .enter(appid, |app, _| self.configure(app))
+            .unwrap_or_else(|err| Err(err.into()))?;
+
+        match operation {
+            Operation::Stream(len) => {
+                if len == 0 {
+                    return Err(ErrorCode::INVAL);
+                }
+                self.progress.set((0, len));
+                self.aes.start_message();
+                self.crypt_chunk(appid)?;
+            }
+            Operation::Aead {
+                mode,
+                encrypting,
+                aad_len,
+                message_len,
+                tag_len,
+            } => {
+                let buf = self.buf.take().ok_or(ErrorCode::BUSY)?;
+                self.decrypting.set(!encrypting);
+                let res = if mode == Mode::Ccm {
+                    AES128CCM::crypt(
+                        self.aes,
+                        buf,
+                        0,
+                        aad_len,
+                        message_len,
+                        tag_len,
+                        true,
+                        encrypting,
+                    )
+                } else {
+                    AES128GCM::crypt(self.aes, buf, 0, aad_len, message_len, encrypting)
+                };
+                res.map_err(|(e, buf)| {
+                    self.buf.replace(buf);
+                    e
+                })?;
+            }
+        }
+        self.current.set(appid);
+        Ok(())
+    }
+
+    /// Start the next queued operation, if any.
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let appid = cntr.processid();
+            let pending = cntr.enter(|app, _| app.pending.replace(false));
+            if pending {
+                if let Err(e) = self.start(appid) {
+                    let _ = self.apps.enter(appid, |_, upcalls| {
+                        upcalls
+                            .schedule_upcall(0, kernel::into_statuscode(Err(e)), 0, 0)
+                            .ok();
+                    });
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Report the end of the current operation and start the next one.
+    fn finish(&self, result: Result<usize, ErrorCode>, tag_is_valid: bool) {
+        self.current.take().map(|appid| {
+            let _ = self.apps.enter(appid, |_, upcalls| {
+                let (status, len) = match result {
+                    Ok(len) => (Ok(()), len),
+                    Err(e) => (Err(e), 0),
+                };
+                upcalls
+                    .schedule_upcall(
+                        0,
+                        kernel::into_statuscode(status),
+                        len,
+                        tag_is_valid as usize,
+                    )
+                    .ok();
+            });
+        });
+        self.check_queue();
+    }
+
+    /// Copy the result of an authenticated operation to the process.
+    ///
+    /// Decrypted messages whose tag does not verify are not released: the
+    /// destination is zeroed instead, and no bytes are reported.
+    fn aead_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        let len = self.output_len.get();
+        let release = tag_is_valid || !self.decrypting.get();
+        let result = res.and_then(|()| {
+            self.current.map_or(Err(ErrorCode::FAIL), |appid| {
+                self.apps
+                    .enter(*appid, |app, _| {
+                        app.dest
+                            .mut_enter(|dest| {
+                                // The process may have allowed a shorter
+                                // destination since the operation started.
+                                let dest = dest.get(0..len).ok_or(ErrorCode::SIZE)?;
+                                if release {
+                                    dest.copy_from_slice(&buf[..len]);
+                                } else {
+                                    dest.iter().for_each(|b| b.set(0));
+                                }
+                                Ok(())
+                            })
+                            .unwrap_or_else(|err| Err(err.into()))
+                    })
+                    .unwrap_or_else(|err| Err(err.into()))
+            })
+        });
+        if !release {
+            buf.iter_mut().for_each(|b| *b = 0);
+        }
+        self.buf.replace(buf);
+        self.finish(result.map(|()| if release { len } else { 0 }), tag_is_valid);
+    }
+}
+
+impl<
+        A: 'static
+            + AES128<'static>
+            + AES128Ctr
+            + AES128CBC
+            + AES128ECB
+            + AES128CCM<'static>
+            + AES128GCM<'static>,
+    > Client<'static> for AesDriver<A>
+{
+    fn crypt_done(&'static self, _source: Option<&'static mut [u8]>, dest: &'static mut [u8]) {
+        let (offset, len) = self.progress.get();
+        let n = self.chunk.get();
+        let copied = self.current.map_or(Err(ErrorCode::FAIL), |appid| {
+            self.apps
+                .enter(*appid, |app, _| {
+                    // The process may have allowed a shorter destination
+                    // since the operation started.
+                    app.dest
+                        .mut_enter(|data| {
+                            data.get(offset..offset + n)
+                                .map(|chunk| chunk.copy_from_slice(&dest[..n]))
+                                .ok_or(ErrorCode::SIZE)
+                        })
+                        .unwrap_or_else(|err| Err(err.into()))
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        });
+        self.buf.replace(dest);
+
+        let result = copied.and_then(|()| {
+            self.progress.set((offset + n, len));
+            if offset + n < len {
+                self.current
+                    .map_or(Err(ErrorCode::FAIL), |appid| self.crypt_chunk(*appid))
+                    .map(|()| None)
+            } else {
+                Ok(Some(len))
+            }
+        });
+        match result {
+            Ok(None) => {}
+            Ok(Some(len)) => self.finish(Ok(len), false),
+            Err(e) => self.finish(Err(e), false),
+        }
+    }
+}
+
+impl<
+        A: 'static
+            + AES128<'static>
+            + AES128Ctr
+            + AES128CBC
+            + AES128ECB
+            + AES128CCM<'static>
+            + AES128GCM<'static>,
+    > CCMClient for AesDriver<A>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        self.aead_done(buf, res, tag_is_valid);
+    }
+}
+
+impl<
+        A: 'static
+            + AES128<'static>
+            + AES128Ctr
+            + AES128CBC
+            + AES128ECB
+            + AES128CCM<'static>
+            + AES128GCM<'static>,
+    > GCMClient for AesDriver<A>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        self.aead_done(buf, res, tag_is_valid);
+    }
+}
+
+impl<
+        A: 'static
+            + AES128<'static>
+            + AES128Ctr
+            + AES128CBC
+            + AES128ECB
+            + AES128CCM<'static>
+            + AES128GCM<'static>,
+    > Driver for AesDriver<A>
+{
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The key. Must be 16 bytes.
+    /// - `1`: The IV (CBC and CTR, 16 bytes) or nonce (CCM, 13 bytes; GCM,
+    ///        12 bytes). Not used for ECB.
+    /// - `2`: The source.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyProcessBuffer,
+    ) -> Result<ReadOnlyProcessBuffer, (ReadOnlyProcessBuffer, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.key);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut slice, &mut app.iv);
+                    Ok(())
+                }
+                2 => {
+                    mem::swap(&mut slice, &mut app.source);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup shared kernel-writable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The destination.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteProcessBuffer,
+    ) -> Result<ReadWriteProcessBuffer, (ReadWriteProcessBuffer, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.dest);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    // Setup callbacks.
+    //
+    // ### `subscribe_num`
+    //
+    // - `0`: An operation finished. The arguments are the status, the number
+    //        of bytes written to the destination, and for CCM and GCM `1` if
+    //        the tag is valid. If a decrypted tag is not valid, no bytes are
+    //        written and the destination is zeroed. The status is `SIZE` if
+    //        the process allowed a shorter buffer during the operation.
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Select the mode in `data1`: `0` ECB, `1` CBC, `2` CTR, `3` CCM
+    ///        or `4` GCM. `data2` is `1` to encrypt and `0` to decrypt.
+    /// - `2`: Set the length of the additional authenticated data at the
+    ///        start of the source to `data1`, and the CCM tag length to
+    ///        `data2`. GCM tags are always 16 bytes.
+    /// - `3`: Encrypt or decrypt the source into the destination. If another
+    ///        operation is in progress the request is queued.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        if command_num == 0 {
+            return CommandReturn::success();
+        }
+        if self.current.contains(&appid) {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+
+        let ret = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.pending.get() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                match command_num {
+                    1 => match Mode::from_usize(data1) {
+                        Some(mode) => {
+                            app.mode.set(Some((mode, data2 != 0)));
+                            CommandReturn::success()
+                        }
+                        None => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                    },
+
+                    2 => {
+                        let tag_ok = data2 == 0 || (data2 >= 4 && data2 <= 16 && data2 % 2 == 0);
+                        if !tag_ok {
+                            return CommandReturn::failure(ErrorCode::INVAL);
+                        }
+                        app.aad_len.set(data1);
+                        app.tag_len.set(data2);
+                        CommandReturn::success()
+                    }
+
+                    3 => {
+                        app.pending.set(true);
+                        CommandReturn::success()
+                    }
+
+                    _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+
+        // Start the request now if nothing else is running.
+        if command_num == 3 && self.current.is_none() {
+            self.check_queue();
+        }
+        ret
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::procs::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
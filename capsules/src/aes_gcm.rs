@@ -0,0 +1,865 @@
+//! Implements AES-GCM authenticated encryption on top of an AES-CTR
+//! implementation.
+//!
+//! NIST SP 800-38D. The hash subkey `H` is the encryption of the zero block,
+//! which is computed as the first CTR keystream block for an all-zero
+//! counter. The message is then encrypted with CTR mode starting from the
+//! pre-counter block `J0 = IV || 0^31 || 1`. An extra zero block is prepended
+//! to the first chunk of the message so that the same pass produces
+//! `E(K, J0)`, which masks the tag:
+//!
+//! ```text
+//! crypt_buf: [ 0 blk | -------- PData/CData (chunk) -------- ]
+//! aes_ctr:    \ J0  / \ inc32(J0) ...                       /
+//! ```
+//!
+//! The message is processed in chunks as large as `crypt_buf` allows. GHASH
+//! is computed in software over the AAD and the ciphertext, which is the
+//! input to CTR when decrypting and its output when encrypting.
+//!
+//! `Aes128Gcm` also passes the `AES128`, CTR, CBC, ECB and CCM* interfaces of
+//! the underlying implementation through, so a single virtual AES device can
+//! serve all modes. GCM reprograms the key, mode and IV of the underlying
+//! device, so `AES128` clients must set them again after a GCM operation.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let aes_gcm = static_init!(
+//!     capsules::aes_gcm::Aes128Gcm<'static, VirtualAES128CCM<'static, Aes<'static>>>,
+//!     capsules::aes_gcm::Aes128Gcm::new(ccm_client, &mut GCM_CRYPT_BUF)
+//! );
+//! aes_gcm.setup();
+//! AES128GCM::set_client(aes_gcm, driver);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::symmetric_encryption::{
+    self, AES128Ctr, AES128, AES128CBC, AES128CCM, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+    GCM_IV_LENGTH, GCM_TAG_LENGTH,
+};
+use kernel::ErrorCode;
+
+use crate::constant_time::constant_time_eq;
+
+/// Multiplication in GF(2^128) with the bit order and reduction polynomial
+/// used by GCM.
+///
+/// One operand is always the secret hash subkey, so this does not branch on
+/// the bits of either: each conditional XOR is applied through a mask.
+fn gf128_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xe1 << 120;
+    let mut z = 0;
+    let mut v = y;
+    for i in (0..128).rev() {
+        z ^= v & ((x >> i) & 1).wrapping_neg();
+        v = (v >> 1) ^ (R & (v & 1).wrapping_neg());
+    }
+    z
+}
+
+/// The GHASH function, computed incrementally.
+#[derive(Copy, Clone)]
+pub struct Ghash {
+    h: u128,
+    y: u128,
+}
+
+impl Ghash {
+    pub fn new(h: &[u8; AES128_BLOCK_SIZE]) -> Ghash {
+        Ghash {
+            h: u128::from_be_bytes(*h),
+            y: 0,
+        }
+    }
+
+    /// Hash `data`, zero-padding a final partial block. Only the last update
+    /// of the AAD and of the ciphertext may have a length that is not a
+    /// multiple of the block size.
+    pub fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(AES128_BLOCK_SIZE) {
+            let mut block = [0u8; AES128_BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.y = gf128_mul(self.y ^ u128::from_be_bytes(block), self.h);
+        }
+    }
+
+    /// Hash the lengths block and return the result.
+    pub fn finish(&self, aad_len: usize, text_len: usize) -> [u8; AES128_BLOCK_SIZE] {
+        let lengths = ((aad_len as u128 * 8) << 64) | (text_len as u128 * 8);
+        gf128_mul(self.y ^ lengths, self.h).to_be_bytes()
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum GcmState {
+    Idle,
+    /// Computing the hash subkey.
+    Hash,
+    /// Encrypting or decrypting a chunk of the message.
+    Crypt,
+    /// An operation passed through for the `AES128` client.
+    Aes,
+}
+
+pub struct Aes128Gcm<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>> {
+    aes: &'a A,
+    crypt_buf: TakeCell<'a, [u8]>,
+    aes_client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+    gcm_client: OptionalCell<&'a dyn symmetric_encryption::GCMClient>,
+
+    state: Cell<GcmState>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    iv: Cell<[u8; GCM_IV_LENGTH]>,
+    encrypting: Cell<bool>,
+
+    buf: TakeCell<'static, [u8]>,
+    /// `(aad_offset, message_offset, message_len)` of the operation.
+    pos: Cell<(usize, usize, usize)>,
+    /// Bytes of the message processed so far.
+    done: Cell<usize>,
+    /// `(prefix, len)` of the chunk in `crypt_buf`.
+    chunk: Cell<(usize, usize)>,
+    ghash: Cell<Ghash>,
+    tag_mask: Cell<[u8; AES128_BLOCK_SIZE]>,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>> Aes128Gcm<'a, A> {
+    /// `crypt_buf` must hold at least two blocks; longer buffers let larger
+    /// chunks of the message be processed per call to the underlying device.
+    pub fn new(aes: &'a A, crypt_buf: &'static mut [u8]) -> Aes128Gcm<'a, A> {
+        Aes128Gcm {
+            aes,
+            crypt_buf: TakeCell::new(crypt_buf),
+            aes_client: OptionalCell::empty(),
+            gcm_client: OptionalCell::empty(),
+            state: Cell::new(GcmState::Idle),
+            key: Cell::new(Default::default()),
+            iv: Cell::new(Default::default()),
+            encrypting: Cell::new(false),
+            buf: TakeCell::empty(),
+            pos: Cell::new((0, 0, 0)),
+            done: Cell::new(0),
+            chunk: Cell::new((0, 0)),
+            ghash: Cell::new(Ghash::new(&[0; AES128_BLOCK_SIZE])),
+            tag_mask: Cell::new(Default::default()),
+        }
+    }
+
+    /// Register as the client of the underlying device. Should be called
+    /// after `static_init!`.
+    pub fn setup(&'a self) {
+        AES128::set_client(self.aes, self);
+    }
+
+    fn start_crypt_buf(&self, len: usize) -> Result<(), ErrorCode> {
+        let crypt_buf = self.crypt_buf.take().ok_or(ErrorCode::NOMEM)?;
+        match AES128::crypt(self.aes, None, crypt_buf, 0, len) {
+            None => Ok(()),
+            Some((res, _, crypt_buf)) => {
+                self.crypt_buf.replace(crypt_buf);
+                Err(res.err().unwrap_or(ErrorCode::FAIL))
+            }
+        }
+    }
+
+    /// Compute the hash subkey `H = E(K, 0^128)`.
+    fn start_hash(&self) -> Result<(), ErrorCode> {
+        AES128::set_key(self.aes, &self.key.get())?;
+        self.aes.set_mode_aes128ctr(true)?;
+        AES128::set_iv(self.aes, &[0; AES128_BLOCK_SIZE])?;
+        self.aes.start_message();
+        self.crypt_buf
+            .map(|cbuf| cbuf[..AES128_BLOCK_SIZE].iter_mut().for_each(|b| *b = 0));
+        self.start_crypt_buf(AES128_BLOCK_SIZE)?;
+        self.state.set(GcmState::Hash);
+        Ok(())
+    }
+
+    /// Start CTR mode from `J0`, with the first chunk of the message.
+    fn start_crypt(&self) -> Result<(), ErrorCode> {
+        let mut j0 = [0u8; AES128_BLOCK_SIZE];
+        j0[..GCM_IV_LENGTH].copy_from_slice(&self.iv.get());
+        j0[AES128_BLOCK_SIZE - 1] = 1;
+        AES128::set_iv(self.aes, &j0)?;
+        self.aes.start_message();
+        self.next_chunk(true)
+    }
+
+    fn next_chunk(&self, first: bool) -> Result<(), ErrorCode> {
+        let prefix = if first { AES128_BLOCK_SIZE } else { 0 };
+        let (_, m_off, m_len) = self.pos.get();
+        let done = self.done.get();
+
+        let len = self.crypt_buf.map_or(Err(ErrorCode::NOMEM), |cbuf| {
+            let room = cbuf.len() / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE - prefix;
+            let n = core::cmp::min(m_len - done, room);
+            let padded = (n + AES128_BLOCK_SIZE - 1) / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE;
+            cbuf[..prefix].iter_mut().for_each(|b| *b = 0);
+            self.buf.map(|buf| {
+                let input = &buf[m_off + done..m_off + done + n];
+                cbuf[prefix..prefix + n].copy_from_slice(input);
+                if !self.encrypting.get() {
+                    let mut ghash = self.ghash.get();
+                    ghash.update(input);
+                    self.ghash.set(ghash);
+                }
+            });
+            cbuf[prefix + n..prefix + padded]
+                .iter_mut()
+                .for_each(|b| *b = 0);
+            self.chunk.set((prefix, n));
+            Ok(prefix + padded)
+        })?;
+
+        self.start_crypt_buf(len)?;
+        self.state.set(GcmState::Crypt);
+        Ok(())
+    }
+
+    fn hash_done(&self) -> Result<(), ErrorCode> {
+        let mut h = [0u8; AES128_BLOCK_SIZE];
+        self.crypt_buf
+            .map(|cbuf| h.copy_from_slice(&cbuf[..AES128_BLOCK_SIZE]));
+        let mut ghash = Ghash::new(&h);
+        let (a_off, m_off, _) = self.pos.get();
+        self.buf.map(|buf| ghash.update(&buf[a_off..m_off]));
+        self.ghash.set(ghash);
+        self.start_crypt()
+    }
+
+    /// Copy out a processed chunk, and start the next one if the message is
+    /// not finished. Returns whether the operation continues.
+    fn chunk_done(&self) -> Result<bool, ErrorCode> {
+        let (prefix, n) = self.chunk.get();
+        let (_, m_off, m_len) = self.pos.get();
+        let done = self.done.get();
+        self.crypt_buf.map(|cbuf| {
+            if prefix != 0 {
+                let mut mask = [0u8; AES128_BLOCK_SIZE];
+                mask.copy_from_slice(&cbuf[..AES128_BLOCK_SIZE]);
+                self.tag_mask.set(mask);
+            }
+            let output = &cbuf[prefix..prefix + n];
+            self.buf.map(|buf| {
+                buf[m_off + done..m_off + done + n].copy_from_slice(output);
+            });
+            if self.encrypting.get() {
+                let mut ghash = self.ghash.get();
+                ghash.update(output);
+                self.ghash.set(ghash);
+            }
+        });
+        self.done.set(done + n);
+
+        if done + n < m_len {
+            self.next_chunk(false).map(|()| true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn finish(&self, res: Result<(), ErrorCode>) {
+        self.state.set(GcmState::Idle);
+        let tag_is_valid = res.is_ok()
+            && self.buf.map_or(false, |buf| {
+                let (a_off, m_off, m_len) = self.pos.get();
+                let mut tag = self.ghash.get().finish(m_off - a_off, m_len);
+                tag.iter_mut()
+                    .zip(self.tag_mask.get().iter())
+                    .for_each(|(t, m)| *t ^= *m);
+
+                let tag_range = m_off + m_len..m_off + m_len + GCM_TAG_LENGTH;
+                if self.encrypting.get() {
+                    buf[tag_range].copy_from_slice(&tag);
+                    true
+                } else {
+                    constant_time_eq(&buf[tag_range], &tag)
+                }
+            });
+        self.buf.take().map(|buf| {
+            self.gcm_client.map(move |client| {
+                client.crypt_done(buf, res, tag_is_valid);
+            });
+        });
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>>
+    symmetric_encryption::AES128GCM<'a> for Aes128Gcm<'a, A>
+{
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::GCMClient) {
+        self.gcm_client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if key.len() != AES128_KEY_SIZE {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut new_key = [0u8; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        Ok(())
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+        if iv.len() != GCM_IV_LENGTH {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut new_iv = [0u8; GCM_IV_LENGTH];
+        new_iv.copy_from_slice(iv);
+        self.iv.set(new_iv);
+        Ok(())
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        aad_offset: usize,
+        message_offset: usize,
+        message_len: usize,
+        encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != GcmState::Idle {
+            return Err((ErrorCode::BUSY, buf));
+        }
+        if !(aad_offset <= message_offset
+            && message_offset + message_len + GCM_TAG_LENGTH <= buf.len())
+        {
+            return Err((ErrorCode::INVAL, buf));
+        }
+        if self
+            .crypt_buf
+            .map_or(true, |cbuf| cbuf.len() < 2 * AES128_BLOCK_SIZE)
+        {
+            return Err((ErrorCode::NOMEM, buf));
+        }
+
+        self.encrypting.set(encrypting);
+        self.pos.set((aad_offset, message_offset, message_len));
+        self.done.set(0);
+        self.buf.replace(buf);
+        match self.start_hash() {
+            Ok(()) => Ok(()),
+            Err(e) => Err((e, self.buf.take().unwrap())),
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>>
+    symmetric_encryption::Client<'a> for Aes128Gcm<'a, A>
+{
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        match self.state.get() {
+            GcmState::Idle => {}
+            GcmState::Aes => {
+                self.state.set(GcmState::Idle);
+                self.aes_client.map(move |client| {
+                    client.crypt_done(source, dest);
+                });
+            }
+            GcmState::Hash => {
+                self.crypt_buf.replace(dest);
+                if let Err(e) = self.hash_done() {
+                    self.finish(Err(e));
+                }
+            }
+            GcmState::Crypt => {
+                self.crypt_buf.replace(dest);
+                match self.chunk_done() {
+                    Ok(true) => {}
+                    Ok(false) => self.finish(Ok(())),
+                    Err(e) => self.finish(Err(e)),
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>> AES128<'a>
+    for Aes128Gcm<'a, A>
+{
+    fn enable(&self) {
+        self.aes.enable();
+    }
+
+    fn disable(&self) {
+        self.aes.disable();
+    }
+
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+        self.aes_client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if self.state.get() != GcmState::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        AES128::set_key(self.aes, key)
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+        if self.state.get() != GcmState::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        AES128::set_iv(self.aes, iv)
+    }
+
+    fn start_message(&self) {
+        if self.state.get() == GcmState::Idle {
+            self.aes.start_message();
+        }
+    }
+
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(Result<(), ErrorCode>, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.state.get() != GcmState::Idle {
+            return Some((Err(ErrorCode::BUSY), source, dest));
+        }
+        let res = AES128::crypt(self.aes, source, dest, start_index, stop_index);
+        if res.is_none() {
+            self.state.set(GcmState::Aes);
+        }
+        res
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>> AES128Ctr
+    for Aes128Gcm<'a, A>
+{
+    fn set_mode_aes128ctr(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        if self.state.get() != GcmState::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.aes.set_mode_aes128ctr(encrypting)
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>> AES128CBC
+    for Aes128Gcm<'a, A>
+{
+    fn set_mode_aes128cbc(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        if self.state.get() != GcmState::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.aes.set_mode_aes128cbc(encrypting)
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>> AES128ECB
+    for Aes128Gcm<'a, A>
+{
+    fn set_mode_aes128ecb(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        if self.state.get() != GcmState::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.aes.set_mode_aes128ecb(encrypting)
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'a>> AES128CCM<'a>
+    for Aes128Gcm<'a, A>
+{
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::CCMClient) {
+        AES128CCM::set_client(self.aes, client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        AES128CCM::set_key(self.aes, key)
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode> {
+        self.aes.set_nonce(nonce)
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        mic_len: usize,
+        confidential: bool,
+        encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        AES128CCM::crypt(
+            self.aes,
+            buf,
+            a_off,
+            m_off,
+            m_len,
+            mic_len,
+            confidential,
+            encrypting,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use kernel::hil::symmetric_encryption::{CCMClient, GCMClient, AES128GCM};
+    use std::boxed::Box;
+
+    fn from_hex(hex: &str, out: &mut [u8]) {
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+    }
+
+    // Test case 2 from the GCM specification: one block of ciphertext and no
+    // AAD.
+    #[test]
+    fn ghash_one_block() {
+        let mut h = [0u8; 16];
+        from_hex("66e94bd4ef8a2c3b884cfa59ca342b2e", &mut h);
+        let mut c = [0u8; 16];
+        from_hex("0388dace60b6a392f328c2b971b2fe78", &mut c);
+        let mut expected = [0u8; 16];
+        from_hex("f38cbb1ad69223dcc3457ae5b6b0f885", &mut expected);
+
+        let mut ghash = Ghash::new(&h);
+        ghash.update(&c);
+        assert_eq!(ghash.finish(0, c.len()), expected);
+    }
+
+    // Test case 4 from the GCM specification: partial final blocks of both
+    // the AAD and the ciphertext, with the ciphertext hashed in two chunks.
+    #[test]
+    fn ghash_partial_blocks() {
+        let mut h = [0u8; 16];
+        from_hex("b83b533708bf535d0aa6e52980d53b78", &mut h);
+        let mut a = [0u8; 20];
+        from_hex("feedfacedeadbeeffeedfacedeadbeefabaddad2", &mut a);
+        let mut c = [0u8; 60];
+        from_hex(
+            "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+             21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091",
+            &mut c,
+        );
+        let mut expected = [0u8; 16];
+        from_hex("698e57f70e6ecc7fd9463b7260a9ae5f", &mut expected);
+
+        let mut ghash = Ghash::new(&h);
+        ghash.update(&a);
+        ghash.update(&c[..32]);
+        ghash.update(&c[32..]);
+        assert_eq!(ghash.finish(a.len(), c.len()), expected);
+    }
+
+    #[rustfmt::skip]
+    const SBOX: [u8; 256] = [
+        0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+        0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+        0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+        0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+        0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+        0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+        0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+        0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+        0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+        0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+        0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+        0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+        0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+        0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+        0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+        0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+    ];
+
+    fn xtime(x: u8) -> u8 {
+        (x << 1) ^ (0x1b & (x >> 7).wrapping_neg())
+    }
+
+    /// A plain software AES-128 block encryption, as the device under GCM.
+    fn aes128_encrypt(key: &[u8; 16], block: &mut [u8; 16]) {
+        let mut round_keys = [[0u8; 16]; 11];
+        round_keys[0] = *key;
+        let mut rcon = 1;
+        for r in 1..11 {
+            let prev = round_keys[r - 1];
+            let mut word = [
+                SBOX[prev[13] as usize] ^ rcon,
+                SBOX[prev[14] as usize],
+                SBOX[prev[15] as usize],
+                SBOX[prev[12] as usize],
+            ];
+            rcon = xtime(rcon);
+            for i in 0..16 {
+                word[i % 4] ^= prev[i];
+                round_keys[r][i] = word[i % 4];
+            }
+        }
+
+        block
+            .iter_mut()
+            .zip(&round_keys[0])
+            .for_each(|(b, k)| *b ^= k);
+        for (r, round_key) in round_keys.iter().enumerate().skip(1) {
+            block.iter_mut().for_each(|b| *b = SBOX[*b as usize]);
+            let state = *block;
+            for c in 0..4 {
+                for row in 0..4 {
+                    block[4 * c + row] = state[4 * ((c + row) % 4) + row];
+                }
+            }
+            if r != 10 {
+                for col in block.chunks_mut(4) {
+                    let a = [col[0], col[1], col[2], col[3]];
+                    let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+                    for i in 0..4 {
+                        col[i] = a[i] ^ all ^ xtime(a[i] ^ a[(i + 1) % 4]);
+                    }
+                }
+            }
+            block.iter_mut().zip(round_key).for_each(|(b, k)| *b ^= k);
+        }
+    }
+
+    /// AES-CTR that, like hardware, completes each `crypt` when the test
+    /// calls `complete`.
+    struct MockCtr {
+        client: OptionalCell<&'static dyn symmetric_encryption::Client<'static>>,
+        key: Cell<[u8; 16]>,
+        counter: Cell<u128>,
+        pending: TakeCell<'static, [u8]>,
+        range: Cell<(usize, usize)>,
+    }
+
+    impl MockCtr {
+        fn new() -> MockCtr {
+            MockCtr {
+                client: OptionalCell::empty(),
+                key: Cell::new([0; 16]),
+                counter: Cell::new(0),
+                pending: TakeCell::empty(),
+                range: Cell::new((0, 0)),
+            }
+        }
+
+        /// Finish the pending operation. Returns whether there was one.
+        fn complete(&self) -> bool {
+            let dest = match self.pending.take() {
+                Some(dest) => dest,
+                None => return false,
+            };
+            let (start, stop) = self.range.get();
+            for block in dest[start..stop].chunks_mut(16) {
+                let mut keystream = self.counter.get().to_be_bytes();
+                aes128_encrypt(&self.key.get(), &mut keystream);
+                block.iter_mut().zip(&keystream).for_each(|(b, k)| *b ^= k);
+                self.counter.set(self.counter.get().wrapping_add(1));
+            }
+            self.client.map(move |client| client.crypt_done(None, dest));
+            true
+        }
+    }
+
+    impl AES128<'static> for MockCtr {
+        fn enable(&self) {}
+
+        fn disable(&self) {}
+
+        fn set_client(&'static self, client: &'static dyn symmetric_encryption::Client<'static>) {
+            self.client.set(client);
+        }
+
+        fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+            let mut new_key = [0; 16];
+            new_key.copy_from_slice(key);
+            self.key.set(new_key);
+            Ok(())
+        }
+
+        fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+            let mut counter = [0; 16];
+            counter.copy_from_slice(iv);
+            self.counter.set(u128::from_be_bytes(counter));
+            Ok(())
+        }
+
+        fn start_message(&self) {}
+
+        fn crypt(
+            &'static self,
+            source: Option<&'static mut [u8]>,
+            dest: &'static mut [u8],
+            start_index: usize,
+            stop_index: usize,
+        ) -> Option<(
+            Result<(), ErrorCode>,
+            Option<&'static mut [u8]>,
+            &'static mut [u8],
+        )> {
+            assert!(source.is_none());
+            self.range.set((start_index, stop_index));
+            self.pending.replace(dest);
+            None
+        }
+    }
+
+    impl AES128Ctr for MockCtr {
+        fn set_mode_aes128ctr(&self, _encrypting: bool) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    impl AES128CBC for MockCtr {
+        fn set_mode_aes128cbc(&self, _encrypting: bool) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+    }
+
+    impl AES128ECB for MockCtr {
+        fn set_mode_aes128ecb(&self, _encrypting: bool) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+    }
+
+    impl AES128CCM<'static> for MockCtr {
+        fn set_client(&'static self, _client: &'static dyn CCMClient) {}
+
+        fn set_key(&self, _key: &[u8]) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn set_nonce(&self, _nonce: &[u8]) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn crypt(
+            &self,
+            buf: &'static mut [u8],
+            _a_off: usize,
+            _m_off: usize,
+            _m_len: usize,
+            _mic_len: usize,
+            _confidential: bool,
+            _encrypting: bool,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::NOSUPPORT, buf))
+        }
+    }
+
+    struct MockGcmClient {
+        buf: TakeCell<'static, [u8]>,
+        result: Cell<Option<(Result<(), ErrorCode>, bool)>>,
+    }
+
+    impl GCMClient for MockGcmClient {
+        fn crypt_done(
+            &self,
+            buf: &'static mut [u8],
+            res: Result<(), ErrorCode>,
+            tag_is_valid: bool,
+        ) {
+            self.buf.replace(buf);
+            self.result.set(Some((res, tag_is_valid)));
+        }
+    }
+
+    // Test case 4 from the GCM specification.
+    const KEY: &str = "feffe9928665731c6d6a8f9467308308";
+    const IV: &str = "cafebabefacedbaddecaf888";
+    const AAD: &str = "feedfacedeadbeeffeedfacedeadbeefabaddad2";
+    const PLAINTEXT: &str = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+                             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39";
+    const CIPHERTEXT: &str = "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+                              21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091";
+    const TAG: &str = "5bc94fbc3221a5db94fae95ae7121a47";
+    const AAD_LEN: usize = 20;
+    const TEXT_LEN: usize = 60;
+
+    /// Run one GCM operation over `buf`, which holds the AAD, then the
+    /// message, then the tag. `crypt_buf` is two blocks, so the message takes
+    /// several chunks.
+    fn run_gcm(buf: &'static mut [u8], encrypting: bool) -> (&'static mut [u8], bool) {
+        let aes = Box::leak(Box::new(MockCtr::new()));
+        let crypt_buf = Box::leak(Box::new([0u8; 32]));
+        let gcm = Box::leak(Box::new(Aes128Gcm::new(&*aes, crypt_buf)));
+        gcm.setup();
+        let client = Box::leak(Box::new(MockGcmClient {
+            buf: TakeCell::empty(),
+            result: Cell::new(None),
+        }));
+        AES128GCM::set_client(&*gcm, &*client);
+
+        let mut key = [0u8; 16];
+        from_hex(KEY, &mut key);
+        let mut iv = [0u8; GCM_IV_LENGTH];
+        from_hex(IV, &mut iv);
+        AES128GCM::set_key(&*gcm, &key).unwrap();
+        AES128GCM::set_iv(&*gcm, &iv).unwrap();
+
+        AES128GCM::crypt(&*gcm, buf, 0, AAD_LEN, TEXT_LEN, encrypting)
+            .map_err(|(err, _)| err)
+            .unwrap();
+        while aes.complete() {}
+
+        let (res, tag_is_valid) = client.result.get().unwrap();
+        assert_eq!(res, Ok(()));
+        (client.buf.take().unwrap(), tag_is_valid)
+    }
+
+    fn gcm_buffer(text: &str, tag: &str) -> &'static mut [u8] {
+        let buf = Box::leak(Box::new([0u8; AAD_LEN + TEXT_LEN + GCM_TAG_LENGTH]));
+        from_hex(AAD, &mut buf[..AAD_LEN]);
+        from_hex(text, &mut buf[AAD_LEN..AAD_LEN + TEXT_LEN]);
+        from_hex(tag, &mut buf[AAD_LEN + TEXT_LEN..]);
+        buf
+    }
+
+    #[test]
+    fn software_block_cipher() {
+        // FIPS 197, appendix C.1.
+        let mut key = [0u8; 16];
+        from_hex("000102030405060708090a0b0c0d0e0f", &mut key);
+        let mut block = [0u8; 16];
+        from_hex("00112233445566778899aabbccddeeff", &mut block);
+        let mut expected = [0u8; 16];
+        from_hex("69c4e0d86a7b0430d8cdb78070b4c55a", &mut expected);
+        aes128_encrypt(&key, &mut block);
+        assert_eq!(block, expected);
+    }
+
+    #[test]
+    fn gcm_encrypt() {
+        let buf = gcm_buffer(PLAINTEXT, TAG);
+        buf[AAD_LEN + TEXT_LEN..].iter_mut().for_each(|b| *b = 0);
+        let (buf, tag_is_valid) = run_gcm(buf, true);
+        assert!(tag_is_valid);
+        assert_eq!(&buf[..], &gcm_buffer(CIPHERTEXT, TAG)[..]);
+    }
+
+    #[test]
+    fn gcm_decrypt() {
+        let (buf, tag_is_valid) = run_gcm(gcm_buffer(CIPHERTEXT, TAG), false);
+        assert!(tag_is_valid);
+        assert_eq!(
+            &buf[AAD_LEN..AAD_LEN + TEXT_LEN],
+            &gcm_buffer(PLAINTEXT, TAG)[AAD_LEN..AAD_LEN + TEXT_LEN]
+        );
+    }
+
+    #[test]
+    fn gcm_decrypt_bad_tag() {
+        let buf = gcm_buffer(CIPHERTEXT, TAG);
+        buf[AAD_LEN + TEXT_LEN] ^= 1;
+        let (_, tag_is_valid) = run_gcm(buf, false);
+        assert!(!tag_is_valid);
+
+        let buf = gcm_buffer(CIPHERTEXT, TAG);
+        buf[0] ^= 1;
+        let (_, tag_is_valid) = run_gcm(buf, false);
+        assert!(!tag_is_valid);
+    }
+}
@@ -95,7 +95,13 @@ impl<'a> AppFlash<'a> {
                                         *c = d[i].get();
                                     }
 
-                                    self.driver.write(buffer, flash_address, length)
+                                    self.driver.write(buffer, flash_address, length).map_err(
+                                        |(e, buffer)| {
+                                            self.buffer.replace(buffer);
+                                            self.current_app.clear();
+                                            e
+                                        },
+                                    )
                                 })
                         })
                         .unwrap_or(Err(ErrorCode::RESERVE))
@@ -151,11 +157,12 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for AppFlash<'_
                                         *c = d[i].get();
                                     }
 
-                                    if let Ok(()) = self.driver.write(buffer, flash_address, length)
-                                    {
-                                        true
-                                    } else {
-                                        false
+                                    match self.driver.write(buffer, flash_address, length) {
+                                        Ok(()) => true,
+                                        Err((_, buffer)) => {
+                                            self.buffer.replace(buffer);
+                                            false
+                                        }
                                     }
                                 }
                             })
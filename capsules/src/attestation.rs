@@ -0,0 +1,648 @@
+//! Signed attestation reports of the images measured at boot.
+//!
+//! `kernel::procs::load_processes_measured()` records the flash region of
+//! every loaded process in a `MeasurementLog`, after the kernel image which
+//! the board records first. Once the kernel is running, `start()` hashes each
+//! image with SHA-256 through `hil::digest` and folds the digests into a hash
+//! chain:
+//!
+//! ```text
+//! chain_0 = 0^32
+//! chain_i = SHA-256(chain_{i-1} || SHA-256(image_i))
+//! ```
+//!
+//! A process can then ask for a report binding the chain and the individual
+//! digests to a nonce of its choosing, signed with the Ed25519 device key.
+//! Only the processes whose package names the board lists may ask for
+//! reports, as each one is a signature with the device key over a nonce the
+//! process chooses. Reports are signed in a deferred call, one per call, so
+//! that signing does not run in the system call.
+//! A host verifier checks the signature with the device public key, checks
+//! the nonce, recomputes the chain from the listed digests and compares the
+//! digests with the images it expects to be running.
+//!
+//! Report format
+//! -------------
+//!
+//! All integers are little endian.
+//!
+//! | Offset | Length | Field                                          |
+//! |--------|--------|------------------------------------------------|
+//! | 0      | 4      | Magic, `TKAT`                                  |
+//! | 4      | 1      | Version, `1`                                   |
+//! | 5      | 1      | Flags: bit 0 is set if the log was truncated   |
+//! | 6      | 2      | Number of entries                              |
+//! | 8      | 32     | Nonce                                          |
+//! | 40     | 32     | Final value of the chain                       |
+//! | 72     | 52 * n | Entries: name (16, zero padded), image length  |
+//! |        |        | (4) and SHA-256 of the image (32)              |
+//! | ...    | 64     | Ed25519 signature of all preceding bytes       |
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let measurement_log = static_init!(
+//!     kernel::procs::MeasurementLog,
+//!     kernel::procs::MeasurementLog::new()
+//! );
+//! measurement_log.record("kernel", kernel_image);
+//! kernel::procs::load_processes_measured(
+//!     board_kernel,
+//!     chip,
+//!     app_flash,
+//!     app_memory,
+//!     &mut PROCESSES,
+//!     &FAULT_RESPONSE,
+//!     measurement_log,
+//!     &process_mgmt_cap,
+//! )
+//! .unwrap_or_else(|err| {
+//!     debug!("Error loading processes!");
+//!     debug!("{:?}", err);
+//! });
+//!
+//! let attestation = static_init!(
+//!     capsules::attestation::Attestation<'static, lowrisc::hmac::Hmac<'static>>,
+//!     capsules::attestation::Attestation::new(
+//!         &peripherals.hmac,
+//!         measurement_log,
+//!         &DEVICE_KEY,
+//!         &["org.tockos.examples.attestation"],
+//!         dynamic_deferred_caller,
+//!         &mut capsules::attestation::DATA,
+//!         &mut capsules::attestation::DIGEST,
+//!         &mut capsules::attestation::DIGESTS,
+//!         &mut capsules::attestation::REPORT,
+//!         board_kernel.create_grant(capsules::attestation::DRIVER_NUM, &grant_cap),
+//!     )
+//! );
+//! attestation.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(attestation)
+//!         .expect("no deferred call slot available for attestation"),
+//! );
+//! digest::Digest::set_client(&peripherals.hmac, attestation);
+//! attestation.start().unwrap();
+//! ```
+
+use core::cmp;
+use core::mem;
+
+use crate::public_key_crypto::ed25519;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::procs::{MeasurementLog, MAX_MEASURED_IMAGES};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, ReadOnlyProcessBuffer,
+    ReadWriteProcessBuffer, ReadableProcessBuffer, WriteableProcessBuffer,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Attestation as usize;
+
+pub const DIGEST_LEN: usize = 32;
+pub const NONCE_LEN: usize = 32;
+/// Length of the name field of an entry. Longer names are truncated.
+pub const NAME_LEN: usize = 16;
+
+const MAGIC: [u8; 4] = *b"TKAT";
+const VERSION: u8 = 1;
+const FLAG_TRUNCATED: u8 = 1;
+const HEADER_LEN: usize = 8 + NONCE_LEN + DIGEST_LEN;
+const ENTRY_LEN: usize = NAME_LEN + 4 + DIGEST_LEN;
+
+/// Length of the largest report, for a full measurement log.
+pub const MAX_REPORT_LEN: usize =
+    HEADER_LEN + MAX_MEASURED_IMAGES * ENTRY_LEN + ed25519::SIGNATURE_LEN;
+
+pub static mut DATA: [u8; 64] = [0; 64];
+pub static mut DIGEST: [u8; DIGEST_LEN] = [0; DIGEST_LEN];
+pub static mut DIGESTS: [u8; MAX_MEASURED_IMAGES * DIGEST_LEN] =
+    [0; MAX_MEASURED_IMAGES * DIGEST_LEN];
+pub static mut REPORT: [u8; MAX_REPORT_LEN] = [0; MAX_REPORT_LEN];
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// `start()` has not been called.
+    Idle,
+    /// Hashing image `.0`, of which `.1` bytes have been added.
+    Image(usize, usize),
+    /// Extending the chain with the digest of image `.0`.
+    Chain(usize),
+    Done,
+    Failed,
+}
+
+#[derive(Default)]
+pub struct App {
+    nonce: ReadOnlyProcessBuffer,
+    report: ReadWriteProcessBuffer,
+    /// A report was requested and has not been written yet.
+    pending: bool,
+}
+
+pub struct Attestation<'a, D: digest::Digest<'a, DIGEST_LEN> + digest::Sha256> {
+    sha: &'a D,
+    log: &'a MeasurementLog,
+    device_key: &'a [u8; ed25519::KEY_LEN],
+    public_key: [u8; ed25519::KEY_LEN],
+    /// Package names of the processes that may request reports.
+    allowed: &'a [&'a str],
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    state: Cell<State>,
+    chain: Cell<[u8; DIGEST_LEN]>,
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; DIGEST_LEN]>,
+    digests: TakeCell<'static, [u8]>,
+    report: TakeCell<'static, [u8]>,
+    apps: Grant<App, 1>,
+}
+
+impl<'a, D: digest::Digest<'a, DIGEST_LEN> + digest::Sha256> Attestation<'a, D> {
+    pub fn new(
+        sha: &'a D,
+        log: &'a MeasurementLog,
+        device_key: &'a [u8; ed25519::KEY_LEN],
+        allowed: &'a [&'a str],
+        deferred_caller: &'a DynamicDeferredCall,
+        data: &'static mut [u8],
+        digest: &'static mut [u8; DIGEST_LEN],
+        digests: &'static mut [u8; MAX_MEASURED_IMAGES * DIGEST_LEN],
+        report: &'static mut [u8; MAX_REPORT_LEN],
+        grant: Grant<App, 1>,
+    ) -> Attestation<'a, D> {
+        Attestation {
+            sha,
+            log,
+            device_key,
+            public_key: ed25519::public_key(device_key),
+            allowed,
+            deferred_caller,
+            handle: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            chain: Cell::new([0; DIGEST_LEN]),
+            data: TakeCell::new(data),
+            digest: TakeCell::new(digest),
+            digests: TakeCell::new(digests),
+            report: TakeCell::new(report),
+            apps: grant,
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Start measuring the images in the log. Reports requested before the
+    /// measurements are complete are delivered once they are.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.measure(0).map_err(|e| {
+            self.fail();
+            e
+        })
+    }
+
+    /// Start hashing image `index`, or finish if there are no more.
+    fn measure(&self, index: usize) -> Result<(), ErrorCode> {
+        if index >= self.log.len() {
+            self.state.set(State::Done);
+            self.schedule_delivery();
+            return Ok(());
+        }
+        self.sha.set_mode_sha256()?;
+        self.state.set(State::Image(index, 0));
+        self.add_image_chunk()
+    }
+
+    /// Add the next chunk of the image being hashed, or compute its digest
+    /// once it has all been added.
+    fn add_image_chunk(&self) -> Result<(), ErrorCode> {
+        let (index, offset) = match self.state.get() {
+            State::Image(index, offset) => (index, offset),
+            _ => return Err(ErrorCode::FAIL),
+        };
+        let image = self.log.get(index).ok_or(ErrorCode::FAIL)?.image;
+        if offset >= image.len() {
+            return self.run();
+        }
+
+        let data = self.data.take().ok_or(ErrorCode::BUSY)?;
+        let n = cmp::min(image.len() - offset, data.len());
+        data[..n].copy_from_slice(&image[offset..offset + n]);
+        self.state.set(State::Image(index, offset + n));
+        self.add_data(data, n)
+    }
+
+    /// Hash the chain so far followed by the digest of image `index`.
+    fn extend_chain(&self, index: usize) -> Result<(), ErrorCode> {
+        self.sha.set_mode_sha256()?;
+        let data = self.data.take().ok_or(ErrorCode::BUSY)?;
+        data[..DIGEST_LEN].copy_from_slice(&self.chain.get());
+        let copied = self.digests.map_or(Err(ErrorCode::FAIL), |digests| {
+            let start = index * DIGEST_LEN;
+            data[DIGEST_LEN..2 * DIGEST_LEN].copy_from_slice(&digests[start..start + DIGEST_LEN]);
+            Ok(())
+        });
+        if let Err(e) = copied {
+            self.data.replace(data);
+            return Err(e);
+        }
+        self.state.set(State::Chain(index));
+        self.add_data(data, 2 * DIGEST_LEN)
+    }
+
+    fn add_data(&self, data: &'static mut [u8], len: usize) -> Result<(), ErrorCode> {
+        let mut lease = LeasableBuffer::new(data);
+        lease.slice(..len);
+        self.sha.add_data(lease).map(|_| ()).map_err(|(e, data)| {
+            self.data.replace(data);
+            e
+        })
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        let digest = self.digest.take().ok_or(ErrorCode::BUSY)?;
+        self.sha.run(digest).map_err(|(e, digest)| {
+            self.digest.replace(digest);
+            e
+        })
+    }
+
+    fn fail(&self) {
+        self.sha.clear_data();
+        self.state.set(State::Failed);
+        self.schedule_delivery();
+    }
+
+    fn schedule_delivery(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    /// Write the report for one pending request, and schedule another
+    /// delivery if more requests are waiting.
+    fn deliver_pending(&self) {
+        let mut delivered = false;
+        for cntr in self.apps.iter() {
+            let more = cntr.enter(|app, upcalls| {
+                if !app.pending {
+                    return false;
+                }
+                if delivered {
+                    return true;
+                }
+                app.pending = false;
+                delivered = true;
+                let res = self.write_report(app);
+                upcalls
+                    .schedule_upcall(
+                        0,
+                        kernel::into_statuscode(res.map(|_| ())),
+                        res.unwrap_or(0),
+                        0,
+                    )
+                    .ok();
+                false
+            });
+            if more {
+                self.schedule_delivery();
+                break;
+            }
+        }
+    }
+
+    fn allowed(&self, appid: ProcessId) -> bool {
+        appid
+            .get_process_name()
+            .map_or(false, |name| self.allowed.contains(&name))
+    }
+
+    /// Write a signed report with the nonce of `app` to its report buffer.
+    fn write_report(&self, app: &App) -> Result<usize, ErrorCode> {
+        match self.state.get() {
+            State::Done => {}
+            State::Failed => return Err(ErrorCode::FAIL),
+            _ => return Err(ErrorCode::BUSY),
+        }
+        let mut nonce = [0; NONCE_LEN];
+        app.nonce
+            .enter(|buf| {
+                if buf.len() != NONCE_LEN {
+                    return Err(ErrorCode::INVAL);
+                }
+                buf.copy_to_slice(&mut nonce);
+                Ok(())
+            })
+            .unwrap_or(Err(ErrorCode::INVAL))?;
+
+        self.report.map_or(Err(ErrorCode::FAIL), |report| {
+            let len = self.digests.map_or(Err(ErrorCode::FAIL), |digests| {
+                Ok(build_report(
+                    report,
+                    self.device_key,
+                    &nonce,
+                    &self.chain.get(),
+                    self.log,
+                    digests,
+                ))
+            })?;
+            app.report
+                .mut_enter(|dest| {
+                    if dest.len() < len {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    dest[..len].copy_from_slice(&report[..len]);
+                    Ok(len)
+                })
+                .unwrap_or(Err(ErrorCode::INVAL))
+        })
+    }
+}
+
+/// Write the report for the measurements in `log` to `out` and sign it with
+/// `key`, returning its length. `out` must hold `MAX_REPORT_LEN` bytes.
+fn build_report(
+    out: &mut [u8],
+    key: &[u8; ed25519::KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    chain: &[u8; DIGEST_LEN],
+    log: &MeasurementLog,
+    digests: &[u8],
+) -> usize {
+    let count = log.len();
+    out[0..4].copy_from_slice(&MAGIC);
+    out[4] = VERSION;
+    out[5] = if log.truncated() { FLAG_TRUNCATED } else { 0 };
+    out[6..8].copy_from_slice(&(count as u16).to_le_bytes());
+    out[8..8 + NONCE_LEN].copy_from_slice(nonce);
+    out[8 + NONCE_LEN..HEADER_LEN].copy_from_slice(chain);
+
+    for index in 0..count {
+        let entry = &mut out[HEADER_LEN + index * ENTRY_LEN..HEADER_LEN + (index + 1) * ENTRY_LEN];
+        let (name, len) = log
+            .get(index)
+            .map_or(("", 0), |image| (image.name, image.image.len()));
+        let name_len = cmp::min(name.len(), NAME_LEN);
+        entry[..NAME_LEN].iter_mut().for_each(|b| *b = 0);
+        entry[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+        entry[NAME_LEN..NAME_LEN + 4].copy_from_slice(&(len as u32).to_le_bytes());
+        entry[NAME_LEN + 4..]
+            .copy_from_slice(&digests[index * DIGEST_LEN..(index + 1) * DIGEST_LEN]);
+    }
+
+    let len = HEADER_LEN + count * ENTRY_LEN;
+    let mut signature = [0; ed25519::SIGNATURE_LEN];
+    ed25519::sign(key, &out[..len], &mut signature);
+    out[len..len + ed25519::SIGNATURE_LEN].copy_from_slice(&signature);
+    len + ed25519::SIGNATURE_LEN
+}
+
+impl<'a, D: digest::Digest<'a, DIGEST_LEN> + digest::Sha256> digest::Client<'a, DIGEST_LEN>
+    for Attestation<'a, D>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.data.replace(data);
+        let res = result.and_then(|()| match self.state.get() {
+            State::Image(..) => self.add_image_chunk(),
+            State::Chain(_) => self.run(),
+            _ => Ok(()),
+        });
+        if res.is_err() {
+            self.fail();
+        }
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; DIGEST_LEN]) {
+        self.sha.clear_data();
+        let value = *digest;
+        self.digest.replace(digest);
+
+        let res = result.and_then(|()| match self.state.get() {
+            State::Image(index, _) => {
+                self.digests.map(|digests| {
+                    digests[index * DIGEST_LEN..(index + 1) * DIGEST_LEN].copy_from_slice(&value)
+                });
+                self.extend_chain(index)
+            }
+            State::Chain(index) => {
+                self.chain.set(value);
+                self.measure(index + 1)
+            }
+            _ => Ok(()),
+        });
+        if res.is_err() {
+            self.fail();
+        }
+    }
+}
+
+impl<'a, D: digest::Digest<'a, DIGEST_LEN> + digest::Sha256> DynamicDeferredCallClient
+    for Attestation<'a, D>
+{
+    fn call(&self, _handle: DeferredCallHandle) {
+        match self.state.get() {
+            State::Done | State::Failed => self.deliver_pending(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, D: digest::Digest<'a, DIGEST_LEN> + digest::Sha256> Driver for Attestation<'a, D> {
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The 32 byte nonce to include in the report.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyProcessBuffer,
+    ) -> Result<ReadOnlyProcessBuffer, (ReadOnlyProcessBuffer, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.nonce);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup shared kernel-writable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The buffer the report or the public key is written to.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteProcessBuffer,
+    ) -> Result<ReadWriteProcessBuffer, (ReadWriteProcessBuffer, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.report);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    // Setup callbacks.
+    //
+    // ### `subscribe_num`
+    //
+    // - `0`: A report was written. The arguments are the status and the
+    //        length of the report.
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Write a signed report to the read-write buffer, with the nonce
+    ///        in the read-only buffer. The report is delivered with the
+    ///        upcall, once the measurements are complete. Fails with `SIZE` if
+    ///        the buffer is smaller than the report, and with `NOSUPPORT` for
+    ///        processes the board does not allow to request reports.
+    /// - `2`: Write the 32 byte Ed25519 public key of the device to the
+    ///        read-write buffer.
+    fn command(
+        &self,
+        command_num: usize,
+        _data1: usize,
+        _data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => {
+                if !self.allowed(appid) {
+                    return CommandReturn::failure(ErrorCode::NOSUPPORT);
+                }
+                let res = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        if app.pending {
+                            return Err(ErrorCode::BUSY);
+                        }
+                        app.pending = true;
+                        Ok(())
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                if res.is_ok() {
+                    match self.state.get() {
+                        State::Done | State::Failed => self.schedule_delivery(),
+                        _ => {}
+                    }
+                }
+                CommandReturn::from(res)
+            }
+            2 => {
+                let public_key = self.public_key;
+                let res = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        app.report
+                            .mut_enter(|dest| {
+                                if dest.len() < public_key.len() {
+                                    return Err(ErrorCode::SIZE);
+                                }
+                                dest[..public_key.len()].copy_from_slice(&public_key);
+                                Ok(())
+                            })
+                            .unwrap_or(Err(ErrorCode::INVAL))
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                CommandReturn::from(res)
+            }
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::procs::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha256::Sha256State;
+
+    #[test]
+    fn report_is_signed_and_chained() {
+        let log = MeasurementLog::new();
+        log.record("kernel", b"kernel image");
+        log.record("a_rather_long_process_name", b"app");
+
+        // Digests and chain as the capsule computes them with `hil::digest`.
+        let mut digests = [0; MAX_MEASURED_IMAGES * DIGEST_LEN];
+        let mut chain = [0; DIGEST_LEN];
+        for (index, image) in [&b"kernel image"[..], &b"app"[..]].iter().enumerate() {
+            let mut digest = [0; DIGEST_LEN];
+            let mut sha = Sha256State::new();
+            sha.update(image);
+            sha.finish(&mut digest);
+            digests[index * DIGEST_LEN..(index + 1) * DIGEST_LEN].copy_from_slice(&digest);
+            let mut sha = Sha256State::new();
+            sha.update(&chain);
+            sha.update(&digest);
+            sha.finish(&mut chain);
+        }
+
+        let key = [7; ed25519::KEY_LEN];
+        let nonce = [0x5a; NONCE_LEN];
+        let mut report = [0; MAX_REPORT_LEN];
+        let len = build_report(&mut report, &key, &nonce, &chain, &log, &digests);
+        assert_eq!(len, HEADER_LEN + 2 * ENTRY_LEN + ed25519::SIGNATURE_LEN);
+
+        assert_eq!(&report[0..4], b"TKAT");
+        assert_eq!(report[4], VERSION);
+        assert_eq!(report[5], 0);
+        assert_eq!(&report[6..8], &[2, 0]);
+        assert_eq!(&report[8..40], &nonce[..]);
+        assert_eq!(&report[40..72], &chain[..]);
+        let second = &report[HEADER_LEN + ENTRY_LEN..HEADER_LEN + 2 * ENTRY_LEN];
+        assert_eq!(&second[..NAME_LEN], b"a_rather_long_pr");
+        assert_eq!(&second[NAME_LEN..NAME_LEN + 4], &[3, 0, 0, 0]);
+        assert_eq!(
+            &second[NAME_LEN + 4..],
+            &digests[DIGEST_LEN..2 * DIGEST_LEN]
+        );
+
+        let body = len - ed25519::SIGNATURE_LEN;
+        let mut signature = [0; ed25519::SIGNATURE_LEN];
+        signature.copy_from_slice(&report[body..len]);
+        let public_key = ed25519::public_key(&key);
+        assert!(ed25519::verify(&public_key, &report[..body], &signature));
+        report[8] ^= 1;
+        assert!(!ed25519::verify(&public_key, &report[..body], &signature));
+    }
+}
@@ -96,8 +96,7 @@ impl<'a> Console<'a> {
     fn send_new(&self, app_id: ProcessId, app: &mut App, len: usize) -> Result<(), ErrorCode> {
         app.write_len = cmp::min(len, app.write_buffer.len());
         app.write_remaining = app.write_len;
-        self.send(app_id, app);
-        Ok(())
+        self.send(app_id, app)
     }
 
     /// Internal helper function for continuing a previously set up transaction
@@ -108,19 +107,19 @@ impl<'a> Console<'a> {
         app: &mut App,
     ) -> Result<bool, Result<(), ErrorCode>> {
         if app.write_remaining > 0 {
-            self.send(app_id, app);
-            Ok(true)
+            self.send(app_id, app).map(|()| true).map_err(Err)
         } else {
             Ok(false)
         }
     }
 
     /// Internal helper function for sending data for an existing transaction.
-    /// Cannot fail. If can't send now, it will schedule for sending later.
-    fn send(&self, app_id: ProcessId, app: &mut App) {
+    /// If can't send now, it will schedule for sending later. Fails only if
+    /// the UART refuses the transmission, which ends the transaction.
+    fn send(&self, app_id: ProcessId, app: &mut App) -> Result<(), ErrorCode> {
         if self.tx_in_progress.is_none() {
             self.tx_in_progress.set(app_id);
-            self.tx_buffer.take().map(|buffer| {
+            self.tx_buffer.take().map_or(Ok(()), |buffer| {
                 let len = app.write_buffer.enter(|data| data.len()).unwrap_or(0);
                 if app.write_remaining > len {
                     // A slice has changed under us and is now smaller than
@@ -143,10 +142,21 @@ impl<'a> Console<'a> {
                     })
                     .unwrap_or(0);
                 app.write_remaining -= transaction_len;
-                let _ = self.uart.transmit_buffer(buffer, transaction_len);
-            });
+                self.uart
+                    .transmit_buffer(buffer, transaction_len)
+                    .map_err(|(e, buffer)| {
+                        // There will be no transmit callback, so nothing
+                        // would ever release the UART for other apps.
+                        self.tx_buffer.replace(buffer);
+                        self.tx_in_progress.clear();
+                        app.write_len = 0;
+                        app.write_remaining = 0;
+                        e
+                    })
+            })
         } else {
             app.pending_write = true;
+            Ok(())
         }
     }
 
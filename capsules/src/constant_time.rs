@@ -0,0 +1,26 @@
+//! Comparisons whose running time does not depend on the data compared.
+//!
+//! Capsules that check a secret, such as an authentication tag or a MAC, use
+//! these so that the time taken does not tell an attacker how much of a guess
+//! was right.
+
+/// Compare without leaking the position of the first difference.
+///
+/// Only the lengths are compared early, as they are not secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn compares_contents_and_length() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"tag", b"tag"));
+        assert!(!constant_time_eq(b"tag", b"tab"));
+        assert!(!constant_time_eq(b"xag", b"tag"));
+        assert!(!constant_time_eq(b"tag", b"tags"));
+    }
+}
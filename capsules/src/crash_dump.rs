@@ -0,0 +1,221 @@
+//! Report the crash dump left by a kernel panic before the last reset.
+//!
+//! At boot the reporter checks the crash dump area the board reserved with
+//! `kernel::crash_dump::set_area()`. If it holds a dump, the reporter prints
+//! the panic message followed by the whole dump as hex lines, then clears the
+//! area. The hex lines can be copied from the console log into
+//! `tools/crash-dump`, which decodes the dump and symbolicates it against the
+//! kernel ELF.
+//!
+//! ```text
+//! ---| Crash dump of a previous panic |---
+//! panicked at 'oops', capsules/src/foo.rs:42:9
+//!     Kernel version release-2.0
+//! CRASHDUMP 0000 544b4344010000004c0100009f3b6a1101002c0070616e69636b656420
+//! ...
+//! CRASHDUMP END
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let reporter = static_init!(
+//!     capsules::crash_dump::CrashDumpReporter<'static>,
+//!     capsules::crash_dump::CrashDumpReporter::new(
+//!         uart_device,
+//!         &mut capsules::crash_dump::WRITE_BUF,
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(uart_device, reporter);
+//! reporter.report();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::TakeCell;
+use kernel::crash_dump::{self, CrashDump, RecordKind};
+use kernel::hil::uart;
+use kernel::ErrorCode;
+
+/// Bytes of the dump on each hex line.
+const BYTES_PER_LINE: usize = 32;
+
+/// Longest hex line: "CRASHDUMP ", offset, space, hex bytes and line end.
+const LINE_LEN: usize = 17 + 2 * BYTES_PER_LINE;
+
+pub static mut WRITE_BUF: [u8; 128] = [0; 128];
+
+const BANNER: &[u8] = b"\r\n---| Crash dump of a previous panic |---\r\n";
+const NO_MESSAGE: &[u8] = b"(no panic message)";
+const VERSION: &[u8] = b"\r\n\tKernel version ";
+const END: &[u8] = b"CRASHDUMP END\r\n";
+
+/// A piece of the report, either borrowed from the dump or generated.
+enum Piece {
+    Bytes(&'static [u8]),
+    Line([u8; LINE_LEN], usize),
+}
+
+impl Piece {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Piece::Bytes(bytes) => bytes,
+            Piece::Line(line, len) => &line[..*len],
+        }
+    }
+}
+
+pub struct CrashDumpReporter<'a> {
+    uart: &'a dyn uart::Transmit<'a>,
+    buffer: TakeCell<'static, [u8]>,
+    dump: Cell<Option<CrashDump<'static>>>,
+    /// Index of the piece being printed.
+    piece: Cell<usize>,
+    /// Bytes of that piece already printed.
+    offset: Cell<usize>,
+}
+
+impl<'a> CrashDumpReporter<'a> {
+    pub fn new(
+        uart: &'a dyn uart::Transmit<'a>,
+        buffer: &'static mut [u8],
+    ) -> CrashDumpReporter<'a> {
+        CrashDumpReporter {
+            uart,
+            buffer: TakeCell::new(buffer),
+            dump: Cell::new(None),
+            piece: Cell::new(0),
+            offset: Cell::new(0),
+        }
+    }
+
+    /// Start printing the dump from before the last reset, if there is one.
+    ///
+    /// Returns `FAIL` if there is no dump and `BUSY` if a report is already
+    /// being printed.
+    pub fn report(&self) -> Result<(), ErrorCode> {
+        if self.dump.get().is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let dump = crash_dump::previous().ok_or(ErrorCode::FAIL)?;
+        self.dump.set(Some(dump));
+        self.piece.set(0);
+        self.offset.set(0);
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::BUSY), |buffer| self.send(buffer))
+    }
+
+    /// The `index`th piece of the report of `dump`.
+    fn piece(dump: &CrashDump<'static>, index: usize) -> Option<Piece> {
+        match index {
+            0 => Some(Piece::Bytes(BANNER)),
+            1 => Some(Piece::Bytes(
+                dump.find(RecordKind::PanicMessage).unwrap_or(NO_MESSAGE),
+            )),
+            2 => Some(Piece::Bytes(VERSION)),
+            3 => Some(Piece::Bytes(
+                dump.find(RecordKind::KernelVersion).unwrap_or(b"unknown"),
+            )),
+            4 => Some(Piece::Bytes(b"\r\n")),
+            _ => {
+                let bytes = dump.as_bytes();
+                let line = index - 5;
+                let lines = (bytes.len() + BYTES_PER_LINE - 1) / BYTES_PER_LINE;
+                if line < lines {
+                    let start = line * BYTES_PER_LINE;
+                    let end = cmp::min(start + BYTES_PER_LINE, bytes.len());
+                    Some(Self::hex_line(start, &bytes[start..end]))
+                } else if line == lines {
+                    Some(Piece::Bytes(END))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Format `bytes`, found at `offset` in the dump, as a hex line.
+    fn hex_line(offset: usize, bytes: &[u8]) -> Piece {
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+        let mut line = [0; LINE_LEN];
+        let mut len = 0;
+        let mut put = |byte: u8| {
+            line[len] = byte;
+            len += 1;
+        };
+        b"CRASHDUMP ".iter().for_each(|&byte| put(byte));
+        for shift in [12, 8, 4, 0].iter() {
+            put(HEX[(offset >> shift) & 0xF]);
+        }
+        put(b' ');
+        for byte in bytes {
+            put(HEX[(byte >> 4) as usize]);
+            put(HEX[(byte & 0xF) as usize]);
+        }
+        put(b'\r');
+        put(b'\n');
+        Piece::Line(line, len)
+    }
+
+    /// Fill `buffer` with the next part of the report and transmit it, or
+    /// finish the report if everything was printed.
+    fn send(&self, buffer: &'static mut [u8]) -> Result<(), ErrorCode> {
+        let dump = match self.dump.get() {
+            Some(dump) => dump,
+            None => {
+                self.buffer.replace(buffer);
+                return Ok(());
+            }
+        };
+
+        let mut len = 0;
+        while len < buffer.len() {
+            let piece = match Self::piece(&dump, self.piece.get()) {
+                Some(piece) => piece,
+                None => break,
+            };
+            let remaining = &piece.as_bytes()[self.offset.get()..];
+            let count = cmp::min(remaining.len(), buffer.len() - len);
+            buffer[len..len + count].copy_from_slice(&remaining[..count]);
+            len += count;
+            if count == remaining.len() {
+                self.piece.set(self.piece.get() + 1);
+                self.offset.set(0);
+            } else {
+                self.offset.set(self.offset.get() + count);
+            }
+        }
+
+        if len == 0 {
+            // Everything was printed, the dump is no longer needed.
+            self.dump.set(None);
+            crash_dump::clear();
+            self.buffer.replace(buffer);
+            return Ok(());
+        }
+
+        self.uart
+            .transmit_buffer(buffer, len)
+            .map_err(|(error, buffer)| {
+                self.dump.set(None);
+                self.buffer.replace(buffer);
+                error
+            })
+    }
+}
+
+impl uart::TransmitClient for CrashDumpReporter<'_> {
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        _rval: Result<(), ErrorCode>,
+    ) {
+        let _ = self.send(buffer);
+    }
+}
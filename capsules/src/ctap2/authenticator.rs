@@ -0,0 +1,1150 @@
+//! CTAP2 authenticator commands.
+//!
+//! This decodes `authenticatorMakeCredential`, `authenticatorGetAssertion`,
+//! `authenticatorGetNextAssertion`, `authenticatorGetInfo` and
+//! `authenticatorReset` requests and builds their responses. It does no I/O:
+//! `hid::CtapHidAuthenticator` waits for the user and accesses storage
+//! between parsing a request and building the response.
+//!
+//! Credentials are ES256 (COSE algorithm `-7`) or Ed25519 (`-8`) key pairs,
+//! whichever the relying party lists first, signed with the constant time
+//! implementations in `public_key_crypto`. Their private keys are not stored.
+//! A credential ID is the algorithm and a nonce together with a MAC, under
+//! the authenticator's secret, binding them to the relying party; the private
+//! key is derived from the secret, the nonce and the relying party. The
+//! authenticator can therefore use any number of credentials while only
+//! storing resident credentials, and it recognises credentials in allow and
+//! exclude lists without any storage.
+//!
+//! Every MAC also covers a generation number, which `authenticatorReset`
+//! increments. This invalidates all credentials created before the reset,
+//! including any resident credential records that could not be erased.
+//!
+//! Resident credentials are stored as `RECORD_LEN` byte records in
+//! `MAX_RESIDENT_CREDENTIALS` slots under `resident_key()`. A relying party
+//! can have a resident credential for each of its users; registering a user
+//! again replaces their credential.
+//!
+//! Attestation uses the `packed` format with self attestation. There is a
+//! single signature counter for all credentials, which the caller keeps in
+//! storage together with the generation and passes in for each signature.
+//! Client PINs and user verification are not supported.
+
+use core::cell::Cell;
+
+use super::cbor::{self, Reader, Writer};
+use crate::constant_time::constant_time_eq;
+use crate::public_key_crypto::{ed25519, p256};
+use crate::sha256::{HmacSha256State, Sha256State, SHA256_DIGEST_LEN};
+
+/// A CTAP2 status code, the first byte of every response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status(pub u8);
+
+impl Status {
+    pub const OK: Status = Status(0x00);
+    pub const INVALID_COMMAND: Status = Status(0x01);
+    pub const INVALID_PARAMETER: Status = Status(0x02);
+    pub const INVALID_LENGTH: Status = Status(0x03);
+    pub const CBOR_UNEXPECTED_TYPE: Status = Status(0x11);
+    pub const INVALID_CBOR: Status = Status(0x12);
+    pub const MISSING_PARAMETER: Status = Status(0x14);
+    pub const CREDENTIAL_EXCLUDED: Status = Status(0x19);
+    pub const UNSUPPORTED_ALGORITHM: Status = Status(0x26);
+    pub const KEY_STORE_FULL: Status = Status(0x28);
+    pub const UNSUPPORTED_OPTION: Status = Status(0x2b);
+    pub const INVALID_OPTION: Status = Status(0x2c);
+    pub const KEEPALIVE_CANCEL: Status = Status(0x2d);
+    pub const NO_CREDENTIALS: Status = Status(0x2e);
+    pub const USER_ACTION_TIMEOUT: Status = Status(0x2f);
+    pub const NOT_ALLOWED: Status = Status(0x30);
+    pub const PIN_NOT_SET: Status = Status(0x35);
+    pub const OTHER: Status = Status(0x7f);
+}
+
+impl From<cbor::Error> for Status {
+    fn from(error: cbor::Error) -> Status {
+        match error {
+            cbor::Error::UnexpectedType => Status::CBOR_UNEXPECTED_TYPE,
+            cbor::Error::Overflow => Status::OTHER,
+            _ => Status::INVALID_CBOR,
+        }
+    }
+}
+
+/// Command bytes.
+mod command {
+    pub const MAKE_CREDENTIAL: u8 = 0x01;
+    pub const GET_ASSERTION: u8 = 0x02;
+    pub const GET_INFO: u8 = 0x04;
+    pub const RESET: u8 = 0x07;
+    pub const GET_NEXT_ASSERTION: u8 = 0x08;
+}
+
+/// A credential's signature algorithm.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    /// ECDSA with P-256 and SHA-256.
+    Es256,
+    /// Ed25519.
+    EdDsa,
+}
+
+impl Algorithm {
+    fn from_cose(alg: i64) -> Option<Algorithm> {
+        match alg {
+            -7 => Some(Algorithm::Es256),
+            -8 => Some(Algorithm::EdDsa),
+            _ => None,
+        }
+    }
+
+    /// The COSE algorithm identifier.
+    fn cose(self) -> i64 {
+        match self {
+            Algorithm::Es256 => -7,
+            Algorithm::EdDsa => -8,
+        }
+    }
+
+    /// The first byte of the IDs of credentials using this algorithm.
+    fn tag(self) -> u8 {
+        match self {
+            Algorithm::Es256 => 1,
+            Algorithm::EdDsa => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Algorithm> {
+        match tag {
+            1 => Some(Algorithm::Es256),
+            2 => Some(Algorithm::EdDsa),
+            _ => None,
+        }
+    }
+}
+
+/// Authenticator data flags.
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// A credential ID is `NONCE_LEN` bytes, the algorithm tag followed by a
+/// nonce, and then a MAC of them.
+pub const CREDENTIAL_ID_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+pub const MAX_USER_ID_LEN: usize = 64;
+
+/// The longest signature: a DER encoded ECDSA signature with both integers
+/// padded to 33 bytes.
+const MAX_SIGNATURE_LEN: usize = 72;
+
+/// Layout of a resident credential record: a version byte, the signature
+/// counter when it was created, the relying party ID hash, the credential
+/// ID, and the length-prefixed user handle.
+const RECORD_VERSION: u8 = 2;
+pub const RECORD_LEN: usize = 1 + 4 + 32 + CREDENTIAL_ID_LEN + 1 + MAX_USER_ID_LEN;
+const RECORD_CREATED: usize = 1;
+const RECORD_RP_ID_HASH: usize = RECORD_CREATED + 4;
+const RECORD_ID: usize = RECORD_RP_ID_HASH + 32;
+const RECORD_USER_ID: usize = RECORD_ID + CREDENTIAL_ID_LEN;
+
+#[derive(Clone, Copy)]
+pub struct MakeCredential {
+    pub client_data_hash: [u8; 32],
+    pub rp_id_hash: [u8; 32],
+    pub user_id: [u8; MAX_USER_ID_LEN],
+    pub user_id_len: usize,
+    /// The first algorithm in the request that this authenticator supports.
+    pub algorithm: Algorithm,
+    /// The credential must be stored as a resident credential.
+    pub resident: bool,
+    /// The exclude list contains a credential this authenticator created for
+    /// the relying party.
+    pub excluded: bool,
+}
+
+impl MakeCredential {
+    /// Whether the new credential replaces `resident`, because it is for the
+    /// same user of the same relying party.
+    pub fn replaces(&self, resident: &Resident) -> bool {
+        resident.rp_id_hash == self.rp_id_hash
+            && resident.user_id[..resident.user_id_len] == self.user_id[..self.user_id_len]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct GetAssertion {
+    pub client_data_hash: [u8; 32],
+    pub rp_id_hash: [u8; 32],
+    /// The user must confirm their presence.
+    pub user_presence: bool,
+    /// The request had an allow list, so resident credentials are not used.
+    pub allow_list: bool,
+    /// The credential to sign with, from the allow list or storage.
+    pub credential: Option<[u8; CREDENTIAL_ID_LEN]>,
+    /// The user handle of a resident credential.
+    pub user_id: [u8; MAX_USER_ID_LEN],
+    pub user_id_len: usize,
+    /// The number of resident credentials to report in the response, or 0
+    /// to leave it out.
+    pub number_of_credentials: usize,
+}
+
+impl GetAssertion {
+    /// Sign with the resident credential `resident`.
+    pub fn use_resident(&mut self, resident: &Resident) {
+        self.credential = Some(resident.credential);
+        self.user_id = resident.user_id;
+        self.user_id_len = resident.user_id_len;
+    }
+}
+
+/// A resident credential read from storage.
+#[derive(Clone, Copy)]
+pub struct Resident {
+    pub rp_id_hash: [u8; 32],
+    pub credential: [u8; CREDENTIAL_ID_LEN],
+    /// The signature counter when the credential was created, which orders
+    /// credentials from newest to oldest.
+    pub created: u32,
+    pub user_id: [u8; MAX_USER_ID_LEN],
+    pub user_id_len: usize,
+}
+
+#[derive(Clone, Copy)]
+pub enum Command {
+    GetInfo,
+    MakeCredential(MakeCredential),
+    GetAssertion(GetAssertion),
+    GetNextAssertion,
+    Reset,
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; SHA256_DIGEST_LEN] {
+    let mut digest = [0; SHA256_DIGEST_LEN];
+    let mut sha = Sha256State::new();
+    for part in parts {
+        sha.update(part);
+    }
+    sha.finish(&mut digest);
+    digest
+}
+
+/// Read a 32-byte hash.
+fn hash(reader: &mut Reader) -> Result<[u8; 32], Status> {
+    let data = reader.bytes()?;
+    if data.len() != 32 {
+        return Err(Status::INVALID_LENGTH);
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(data);
+    Ok(hash)
+}
+
+/// Call `f` with the ID of every credential in a list of
+/// `PublicKeyCredentialDescriptor`s.
+fn credential_list(
+    reader: &mut Reader,
+    mut f: impl FnMut(&[u8]) -> Result<(), Status>,
+) -> Result<(), Status> {
+    for _ in 0..reader.array()? {
+        let mut id = None;
+        let mut public_key = false;
+        for _ in 0..reader.map()? {
+            match reader.text()? {
+                b"id" => id = Some(reader.bytes()?),
+                b"type" => public_key = reader.text()? == b"public-key",
+                _ => reader.skip()?,
+            }
+        }
+        match id {
+            Some(id) if public_key => f(id)?,
+            Some(_) => {}
+            None => return Err(Status::MISSING_PARAMETER),
+        }
+    }
+    Ok(())
+}
+
+/// Options of a request, `None` if not given.
+struct Options {
+    rk: Option<bool>,
+    up: Option<bool>,
+    uv: Option<bool>,
+}
+
+fn options(reader: &mut Reader) -> Result<Options, Status> {
+    let mut options = Options {
+        rk: None,
+        up: None,
+        uv: None,
+    };
+    for _ in 0..reader.map()? {
+        match reader.text()? {
+            b"rk" => options.rk = Some(reader.bool()?),
+            b"up" => options.up = Some(reader.bool()?),
+            b"uv" => options.uv = Some(reader.bool()?),
+            _ => reader.skip()?,
+        }
+    }
+    if options.uv == Some(true) {
+        return Err(Status::UNSUPPORTED_OPTION);
+    }
+    Ok(options)
+}
+
+/// Encode an ECDSA signature `r || s` as a DER `Ecdsa-Sig-Value`, the format
+/// WebAuthn uses for ES256, returning its length.
+fn der_signature(raw: &[u8; p256::SIGNATURE_LEN], out: &mut [u8; MAX_SIGNATURE_LEN]) -> usize {
+    let mut len = 2;
+    for integer in raw.chunks(32) {
+        // The shortest big-endian encoding, with a zero byte in front if the
+        // top bit is set so that it is not negative.
+        let zeros = integer.iter().take_while(|&&b| b == 0).count().min(31);
+        let integer = &integer[zeros..];
+        let pad = integer[0] >> 7;
+        out[len] = 0x02;
+        out[len + 1] = integer.len() as u8 + pad;
+        len += 2;
+        if pad == 1 {
+            out[len] = 0;
+            len += 1;
+        }
+        out[len..len + integer.len()].copy_from_slice(integer);
+        len += integer.len();
+    }
+    out[0] = 0x30;
+    out[1] = (len - 2) as u8;
+    len
+}
+
+/// Maximum number of resident credentials.
+pub const MAX_RESIDENT_CREDENTIALS: usize = 8;
+
+/// Key under which resident credential slot `slot` is stored.
+pub fn resident_key(slot: usize) -> [u8; 8] {
+    let digest = sha256(&[b"ctap2 resident credential", &[slot as u8]]);
+    let mut key = [0; 8];
+    key.copy_from_slice(&digest[..8]);
+    key
+}
+
+/// Number of storage slots the signature counter alternates between, so
+/// that the previous value survives a failed write.
+pub const COUNTER_SLOTS: usize = 2;
+/// Length of a stored signature counter: the counter and the generation, as
+/// little endian `u32`s.
+pub const COUNTER_LEN: usize = 8;
+
+/// Key under which slot `slot` of the signature counter is stored.
+pub fn counter_key(slot: usize) -> [u8; 8] {
+    let digest = sha256(&[b"ctap2 signature counter", &[slot as u8]]);
+    let mut key = [0; 8];
+    key.copy_from_slice(&digest[..8]);
+    key
+}
+
+pub struct Authenticator {
+    /// Secret from which all credential keys are derived.
+    secret: [u8; 32],
+    aaguid: [u8; 16],
+    /// Number of times the authenticator has been reset.
+    generation: Cell<u32>,
+}
+
+impl Authenticator {
+    pub const fn new(secret: [u8; 32], aaguid: [u8; 16]) -> Authenticator {
+        Authenticator {
+            secret,
+            aaguid,
+            generation: Cell::new(0),
+        }
+    }
+
+    /// Set the generation loaded from storage. Only credentials created in
+    /// this generation are recognised.
+    pub fn set_generation(&self, generation: u32) {
+        self.generation.set(generation);
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> [u8; SHA256_DIGEST_LEN] {
+        let mut mac = [0; SHA256_DIGEST_LEN];
+        let mut hmac = HmacSha256State::new(&self.secret);
+        hmac.update(&self.generation.get().to_le_bytes());
+        for part in parts {
+            hmac.update(part);
+        }
+        hmac.finish(&mut mac);
+        mac
+    }
+
+    /// The ID of a new credential. It is derived from the request, whose
+    /// client data hash includes the relying party's random challenge.
+    fn new_credential_id(&self, request: &MakeCredential) -> [u8; CREDENTIAL_ID_LEN] {
+        let nonce = self.mac(&[
+            b"nonce",
+            &request.rp_id_hash,
+            &request.client_data_hash,
+            &request.user_id[..request.user_id_len],
+        ]);
+        let mut id = [0; CREDENTIAL_ID_LEN];
+        id[0] = request.algorithm.tag();
+        id[1..NONCE_LEN].copy_from_slice(&nonce[1..NONCE_LEN]);
+        let tag = self.mac(&[b"id", &id[..NONCE_LEN], &request.rp_id_hash]);
+        id[NONCE_LEN..].copy_from_slice(&tag[..CREDENTIAL_ID_LEN - NONCE_LEN]);
+        id
+    }
+
+    /// Whether `id` is a credential this authenticator created for the
+    /// relying party.
+    fn is_credential(&self, rp_id_hash: &[u8; 32], id: &[u8]) -> bool {
+        if id.len() != CREDENTIAL_ID_LEN {
+            return false;
+        }
+        let tag = self.mac(&[b"id", &id[..NONCE_LEN], rp_id_hash]);
+        constant_time_eq(&id[NONCE_LEN..], &tag[..CREDENTIAL_ID_LEN - NONCE_LEN])
+            && Algorithm::from_tag(id[0]).is_some()
+    }
+
+    /// Sign `message` with credential `id`, which must be a credential of
+    /// the relying party, returning the length of the signature.
+    fn sign(
+        &self,
+        rp_id_hash: &[u8; 32],
+        id: &[u8; CREDENTIAL_ID_LEN],
+        message: &[u8],
+        signature: &mut [u8; MAX_SIGNATURE_LEN],
+    ) -> Result<usize, Status> {
+        let seed = self.mac(&[b"key", &id[..NONCE_LEN], rp_id_hash]);
+        match Algorithm::from_tag(id[0]).ok_or(Status::OTHER)? {
+            Algorithm::Es256 => {
+                let private_key = self.p256_private_key(&seed, rp_id_hash, id);
+                let mut raw = [0; p256::SIGNATURE_LEN];
+                p256::sign(&private_key, &sha256(&[message]), &mut raw)
+                    .map_err(|_| Status::OTHER)?;
+                Ok(der_signature(&raw, signature))
+            }
+            Algorithm::EdDsa => {
+                let mut raw = [0; ed25519::SIGNATURE_LEN];
+                ed25519::sign(&seed, message, &mut raw);
+                signature[..raw.len()].copy_from_slice(&raw);
+                Ok(raw.len())
+            }
+        }
+    }
+
+    /// Widen `seed` to the 64 bytes a P-256 private key is reduced from.
+    fn p256_private_key(
+        &self,
+        seed: &[u8; 32],
+        rp_id_hash: &[u8; 32],
+        id: &[u8; CREDENTIAL_ID_LEN],
+    ) -> [u8; p256::PRIVATE_KEY_LEN] {
+        let mut wide = [0; 64];
+        wide[..32].copy_from_slice(seed);
+        wide[32..].copy_from_slice(&self.mac(&[b"key high", &id[..NONCE_LEN], rp_id_hash]));
+        p256::private_key_from_bytes(&wide)
+    }
+
+    /// Write the public key of credential `id` as a COSE_Key.
+    fn write_public_key(
+        &self,
+        writer: &mut Writer,
+        rp_id_hash: &[u8; 32],
+        id: &[u8; CREDENTIAL_ID_LEN],
+    ) -> Result<(), Status> {
+        let seed = self.mac(&[b"key", &id[..NONCE_LEN], rp_id_hash]);
+        match Algorithm::from_tag(id[0]).ok_or(Status::OTHER)? {
+            Algorithm::Es256 => {
+                let private_key = self.p256_private_key(&seed, rp_id_hash, id);
+                let public_key = p256::public_key(&private_key).ok_or(Status::OTHER)?;
+                writer.map(5);
+                // kty: EC2
+                writer.int(1);
+                writer.int(2);
+                writer.int(3);
+                writer.int(Algorithm::Es256.cose());
+                // crv: P-256
+                writer.int(-1);
+                writer.int(1);
+                writer.int(-2);
+                writer.bytes(&public_key[..32]);
+                writer.int(-3);
+                writer.bytes(&public_key[32..]);
+            }
+            Algorithm::EdDsa => {
+                writer.map(4);
+                // kty: OKP
+                writer.int(1);
+                writer.int(1);
+                writer.int(3);
+                writer.int(Algorithm::EdDsa.cose());
+                // crv: Ed25519
+                writer.int(-1);
+                writer.int(6);
+                writer.int(-2);
+                writer.bytes(&ed25519::public_key(&seed));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode a request. The first byte is the command and the rest its CBOR
+    /// encoded parameters.
+    pub fn parse(&self, request: &[u8]) -> Result<Command, Status> {
+        let (command, parameters) = request.split_first().ok_or(Status::INVALID_LENGTH)?;
+        let reader = Reader::new(parameters);
+        match *command {
+            command::GET_INFO => Ok(Command::GetInfo),
+            command::MAKE_CREDENTIAL => {
+                Ok(Command::MakeCredential(self.parse_make_credential(reader)?))
+            }
+            command::GET_ASSERTION => Ok(Command::GetAssertion(self.parse_get_assertion(reader)?)),
+            command::GET_NEXT_ASSERTION => Ok(Command::GetNextAssertion),
+            command::RESET => Ok(Command::Reset),
+            _ => Err(Status::INVALID_COMMAND),
+        }
+    }
+
+    fn parse_make_credential(&self, mut reader: Reader) -> Result<MakeCredential, Status> {
+        let mut client_data_hash = None;
+        let mut rp_id_hash = None;
+        let mut user_id = None;
+        let mut algorithm = None;
+        // The exclude list is checked once the relying party is known.
+        let mut exclude_list = None;
+        let mut resident = false;
+
+        for _ in 0..reader.map()? {
+            match reader.int()? {
+                1 => client_data_hash = Some(hash(&mut reader)?),
+                2 => {
+                    for _ in 0..reader.map()? {
+                        match reader.text()? {
+                            b"id" => rp_id_hash = Some(sha256(&[reader.text()?])),
+                            _ => reader.skip()?,
+                        }
+                    }
+                }
+                3 => {
+                    for _ in 0..reader.map()? {
+                        match reader.text()? {
+                            b"id" => user_id = Some(reader.bytes()?),
+                            _ => reader.skip()?,
+                        }
+                    }
+                }
+                4 => {
+                    // The relying party lists algorithms from most to least
+                    // preferred.
+                    let mut supported = None;
+                    for _ in 0..reader.array()? {
+                        let mut alg = None;
+                        let mut public_key = false;
+                        for _ in 0..reader.map()? {
+                            match reader.text()? {
+                                b"alg" => alg = Some(reader.int()?),
+                                b"type" => public_key = reader.text()? == b"public-key",
+                                _ => reader.skip()?,
+                            }
+                        }
+                        if supported.is_none() && public_key {
+                            supported = alg.and_then(Algorithm::from_cose);
+                        }
+                    }
+                    algorithm = Some(supported);
+                }
+                5 => {
+                    exclude_list = Some(reader);
+                    reader.skip()?;
+                }
+                7 => {
+                    let options = options(&mut reader)?;
+                    if options.up == Some(false) {
+                        return Err(Status::INVALID_OPTION);
+                    }
+                    resident = options.rk.unwrap_or(false);
+                }
+                8 => return Err(Status::PIN_NOT_SET),
+                _ => reader.skip()?,
+            }
+        }
+
+        let (client_data_hash, rp_id_hash, user_id, algorithm) =
+            match (client_data_hash, rp_id_hash, user_id, algorithm) {
+                (Some(c), Some(r), Some(u), Some(a)) => (c, r, u, a),
+                _ => return Err(Status::MISSING_PARAMETER),
+            };
+        let algorithm = algorithm.ok_or(Status::UNSUPPORTED_ALGORITHM)?;
+        if user_id.len() > MAX_USER_ID_LEN {
+            return Err(Status::INVALID_LENGTH);
+        }
+
+        let mut excluded = false;
+        if let Some(mut reader) = exclude_list {
+            credential_list(&mut reader, |id| {
+                excluded |= self.is_credential(&rp_id_hash, id);
+                Ok(())
+            })?;
+        }
+
+        let mut request = MakeCredential {
+            client_data_hash,
+            rp_id_hash,
+            user_id: [0; MAX_USER_ID_LEN],
+            user_id_len: user_id.len(),
+            algorithm,
+            resident,
+            excluded,
+        };
+        request.user_id[..user_id.len()].copy_from_slice(user_id);
+        Ok(request)
+    }
+
+    fn parse_get_assertion(&self, mut reader: Reader) -> Result<GetAssertion, Status> {
+        let mut rp_id_hash = None;
+        let mut client_data_hash = None;
+        let mut allow_list = None;
+        let mut user_presence = true;
+
+        for _ in 0..reader.map()? {
+            match reader.int()? {
+                1 => rp_id_hash = Some(sha256(&[reader.text()?])),
+                2 => client_data_hash = Some(hash(&mut reader)?),
+                3 => {
+                    allow_list = Some(reader);
+                    reader.skip()?;
+                }
+                5 => {
+                    let options = options(&mut reader)?;
+                    if options.rk.is_some() {
+                        return Err(Status::INVALID_OPTION);
+                    }
+                    user_presence = options.up.unwrap_or(true);
+                }
+                6 => return Err(Status::PIN_NOT_SET),
+                _ => reader.skip()?,
+            }
+        }
+
+        let (rp_id_hash, client_data_hash) = match (rp_id_hash, client_data_hash) {
+            (Some(r), Some(c)) => (r, c),
+            _ => return Err(Status::MISSING_PARAMETER),
+        };
+
+        let mut credential = None;
+        if let Some(mut reader) = allow_list {
+            credential_list(&mut reader, |id| {
+                if credential.is_none() && self.is_credential(&rp_id_hash, id) {
+                    let mut found = [0; CREDENTIAL_ID_LEN];
+                    found.copy_from_slice(id);
+                    credential = Some(found);
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(GetAssertion {
+            client_data_hash,
+            rp_id_hash,
+            user_presence,
+            allow_list: allow_list.is_some(),
+            credential,
+            user_id: [0; MAX_USER_ID_LEN],
+            user_id_len: 0,
+            number_of_credentials: 0,
+        })
+    }
+
+    /// Write the response to `authenticatorGetInfo` to `out`, returning its
+    /// length. `max_message_size` is the longest request the transport
+    /// accepts.
+    pub fn get_info(&self, max_message_size: usize, out: &mut [u8]) -> Result<usize, Status> {
+        let (status, body) = out.split_first_mut().ok_or(Status::OTHER)?;
+        *status = Status::OK.0;
+        let mut writer = Writer::new(body);
+        writer.map(4);
+        writer.int(1);
+        writer.array(1);
+        writer.text("FIDO_2_0");
+        writer.int(3);
+        writer.bytes(&self.aaguid);
+        writer.int(4);
+        writer.map(3);
+        writer.text("rk");
+        writer.bool(true);
+        writer.text("up");
+        writer.bool(true);
+        writer.text("plat");
+        writer.bool(false);
+        writer.int(5);
+        writer.int(max_message_size as i64);
+        Ok(1 + writer.finish()?)
+    }
+
+    /// Write the response to `authenticatorMakeCredential` to `out`,
+    /// returning its length. The user must already have confirmed their
+    /// presence, and `sign_count` must already have been stored.
+    pub fn make_credential(
+        &self,
+        request: &MakeCredential,
+        sign_count: u32,
+        out: &mut [u8],
+    ) -> Result<usize, Status> {
+        let id = self.new_credential_id(request);
+
+        // Authenticator data, followed by the client data hash it is signed
+        // together with.
+        let mut signed = [0; 256];
+        let mut len = 0;
+        {
+            let mut writer = Writer::new(&mut signed);
+            writer.raw(&request.rp_id_hash);
+            writer.raw(&[FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL]);
+            writer.raw(&sign_count.to_be_bytes());
+            writer.raw(&self.aaguid);
+            writer.raw(&(CREDENTIAL_ID_LEN as u16).to_be_bytes());
+            writer.raw(&id);
+            self.write_public_key(&mut writer, &request.rp_id_hash, &id)?;
+            len += writer.finish()?;
+        }
+        let auth_data_len = len;
+        signed[len..len + 32].copy_from_slice(&request.client_data_hash);
+        len += 32;
+
+        let mut signature = [0; MAX_SIGNATURE_LEN];
+        let signature_len = self.sign(&request.rp_id_hash, &id, &signed[..len], &mut signature)?;
+
+        let (status, body) = out.split_first_mut().ok_or(Status::OTHER)?;
+        *status = Status::OK.0;
+        let mut writer = Writer::new(body);
+        writer.map(3);
+        writer.int(1);
+        writer.text("packed");
+        writer.int(2);
+        writer.bytes(&signed[..auth_data_len]);
+        writer.int(3);
+        writer.map(2);
+        writer.text("alg");
+        writer.int(request.algorithm.cose());
+        writer.text("sig");
+        writer.bytes(&signature[..signature_len]);
+        Ok(1 + writer.finish()?)
+    }
+
+    /// Write the response to `authenticatorGetAssertion` or
+    /// `authenticatorGetNextAssertion` to `out`, returning its length.
+    /// Returns `NO_CREDENTIALS` if the request did not name a credential of
+    /// this authenticator and none was loaded from storage. `sign_count`
+    /// must already have been stored.
+    pub fn get_assertion(
+        &self,
+        request: &GetAssertion,
+        sign_count: u32,
+        out: &mut [u8],
+    ) -> Result<usize, Status> {
+        let id = request.credential.ok_or(Status::NO_CREDENTIALS)?;
+
+        let mut signed = [0; 32 + 1 + 4 + 32];
+        signed[..32].copy_from_slice(&request.rp_id_hash);
+        signed[32] = if request.user_presence {
+            FLAG_USER_PRESENT
+        } else {
+            0
+        };
+        signed[33..37].copy_from_slice(&sign_count.to_be_bytes());
+        let auth_data_len = 32 + 1 + 4;
+        signed[auth_data_len..].copy_from_slice(&request.client_data_hash);
+
+        let mut signature = [0; MAX_SIGNATURE_LEN];
+        let signature_len = self.sign(&request.rp_id_hash, &id, &signed, &mut signature)?;
+
+        let resident = request.user_id_len > 0;
+        let (status, body) = out.split_first_mut().ok_or(Status::OTHER)?;
+        *status = Status::OK.0;
+        let mut writer = Writer::new(body);
+        writer.map(3 + resident as usize + (request.number_of_credentials > 0) as usize);
+        writer.int(1);
+        writer.map(2);
+        writer.text("id");
+        writer.bytes(&id);
+        writer.text("type");
+        writer.text("public-key");
+        writer.int(2);
+        writer.bytes(&signed[..auth_data_len]);
+        writer.int(3);
+        writer.bytes(&signature[..signature_len]);
+        if resident {
+            writer.int(4);
+            writer.map(1);
+            writer.text("id");
+            writer.bytes(&request.user_id[..request.user_id_len]);
+        }
+        if request.number_of_credentials > 0 {
+            writer.int(5);
+            writer.int(request.number_of_credentials as i64);
+        }
+        Ok(1 + writer.finish()?)
+    }
+
+    /// Write the resident credential record for `request`, created with
+    /// signature counter `created`, to `record`.
+    pub fn record(&self, request: &MakeCredential, created: u32, record: &mut [u8]) {
+        record[0] = RECORD_VERSION;
+        record[RECORD_CREATED..RECORD_RP_ID_HASH].copy_from_slice(&created.to_le_bytes());
+        record[RECORD_RP_ID_HASH..RECORD_ID].copy_from_slice(&request.rp_id_hash);
+        record[RECORD_ID..RECORD_USER_ID].copy_from_slice(&self.new_credential_id(request));
+        record[RECORD_USER_ID] = request.user_id_len as u8;
+        record[RECORD_USER_ID + 1..RECORD_LEN].copy_from_slice(&request.user_id);
+    }
+
+    /// Decode a resident credential record. Returns `None` if it is not a
+    /// valid credential of this authenticator, for example because it was
+    /// created before a reset.
+    pub fn resident(&self, record: &[u8]) -> Option<Resident> {
+        if record.len() < RECORD_LEN || record[0] != RECORD_VERSION {
+            return None;
+        }
+        let mut resident = Resident {
+            rp_id_hash: [0; 32],
+            credential: [0; CREDENTIAL_ID_LEN],
+            created: 0,
+            user_id: [0; MAX_USER_ID_LEN],
+            user_id_len: record[RECORD_USER_ID] as usize,
+        };
+        let mut created = [0; 4];
+        created.copy_from_slice(&record[RECORD_CREATED..RECORD_RP_ID_HASH]);
+        resident.created = u32::from_le_bytes(created);
+        resident
+            .rp_id_hash
+            .copy_from_slice(&record[RECORD_RP_ID_HASH..RECORD_ID]);
+        resident
+            .credential
+            .copy_from_slice(&record[RECORD_ID..RECORD_USER_ID]);
+        resident
+            .user_id
+            .copy_from_slice(&record[RECORD_USER_ID + 1..RECORD_LEN]);
+
+        if resident.user_id_len > MAX_USER_ID_LEN
+            || !self.is_credential(&resident.rp_id_hash, &resident.credential)
+        {
+            return None;
+        }
+        Some(resident)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_DATA_HASH: [u8; 32] = [0x11; 32];
+
+    fn make_credential_request(
+        algorithms: &[i64],
+        user: &[u8],
+        rk: bool,
+        exclude: Option<&[u8]>,
+    ) -> [u8; 256] {
+        let mut request = [0; 256];
+        request[0] = command::MAKE_CREDENTIAL;
+        let mut writer = Writer::new(&mut request[1..]);
+        writer.map(if exclude.is_some() { 6 } else { 5 });
+        writer.int(1);
+        writer.bytes(&CLIENT_DATA_HASH);
+        writer.int(2);
+        writer.map(1);
+        writer.text("id");
+        writer.text("example.com");
+        writer.int(3);
+        writer.map(2);
+        writer.text("id");
+        writer.bytes(user);
+        writer.text("name");
+        writer.text("alice");
+        writer.int(4);
+        writer.array(algorithms.len());
+        for alg in algorithms {
+            writer.map(2);
+            writer.text("alg");
+            writer.int(*alg);
+            writer.text("type");
+            writer.text("public-key");
+        }
+        if let Some(id) = exclude {
+            writer.int(5);
+            writer.array(1);
+            writer.map(2);
+            writer.text("id");
+            writer.bytes(id);
+            writer.text("type");
+            writer.text("public-key");
+        }
+        writer.int(7);
+        writer.map(1);
+        writer.text("rk");
+        writer.bool(rk);
+        writer.finish().unwrap();
+        request
+    }
+
+    fn parse_make_credential(authenticator: &Authenticator, request: &[u8]) -> MakeCredential {
+        match authenticator.parse(request).unwrap() {
+            Command::MakeCredential(request) => request,
+            _ => panic!("wrong command"),
+        }
+    }
+
+    fn get_assertion_request() -> GetAssertion {
+        let mut request = [0; 64];
+        request[0] = command::GET_ASSERTION;
+        let mut writer = Writer::new(&mut request[1..]);
+        writer.map(2);
+        writer.int(1);
+        writer.text("example.com");
+        writer.int(2);
+        writer.bytes(&CLIENT_DATA_HASH);
+        let len = writer.finish().unwrap() + 1;
+        match Authenticator::new([0; 32], [0; 16])
+            .parse(&request[..len])
+            .unwrap()
+        {
+            Command::GetAssertion(request) => request,
+            _ => panic!("wrong command"),
+        }
+    }
+
+    /// A credential public key, decoded from a COSE_Key.
+    enum PublicKey {
+        Es256([u8; 64]),
+        EdDsa([u8; 32]),
+    }
+
+    impl PublicKey {
+        fn verify(&self, auth_data: &[u8], signature: &[u8]) -> bool {
+            let mut signed = [0; 256];
+            signed[..auth_data.len()].copy_from_slice(auth_data);
+            signed[auth_data.len()..auth_data.len() + 32].copy_from_slice(&CLIENT_DATA_HASH);
+            let signed = &signed[..auth_data.len() + 32];
+            match self {
+                PublicKey::Es256(key) => {
+                    p256::verify(key, &sha256(&[signed]), &from_der(signature))
+                }
+                PublicKey::EdDsa(key) => {
+                    let mut raw = [0; 64];
+                    raw.copy_from_slice(signature);
+                    ed25519::verify(key, signed, &raw)
+                }
+            }
+        }
+    }
+
+    /// Decode a DER encoded ECDSA signature to `r || s`.
+    fn from_der(der: &[u8]) -> [u8; 64] {
+        assert_eq!((der[0], der[1] as usize), (0x30, der.len() - 2));
+        let mut raw = [0; 64];
+        let mut at = 2;
+        for half in raw.chunks_mut(32) {
+            assert_eq!(der[at], 0x02);
+            let len = der[at + 1] as usize;
+            let integer = &der[at + 2..at + 2 + len];
+            // Only a sign byte may be added, and only when needed.
+            assert!(len <= 32 || (integer[0] == 0 && integer[1] & 0x80 != 0));
+            let integer = &integer[len.saturating_sub(32)..];
+            half[32 - integer.len()..].copy_from_slice(integer);
+            at += 2 + len;
+        }
+        raw
+    }
+
+    /// Extract the credential ID and public key from attested credential
+    /// data.
+    fn attested_credential(auth_data: &[u8]) -> ([u8; 32], PublicKey) {
+        let mut id = [0; 32];
+        id.copy_from_slice(&auth_data[55..87]);
+        let mut reader = Reader::new(&auth_data[87..]);
+        let mut alg = 0;
+        let mut x = [0; 32];
+        let mut y = None;
+        for _ in 0..reader.map().unwrap() {
+            match reader.int().unwrap() {
+                3 => alg = reader.int().unwrap(),
+                -2 => x.copy_from_slice(reader.bytes().unwrap()),
+                -3 => y = Some(reader.bytes().unwrap()),
+                _ => reader.skip().unwrap(),
+            }
+        }
+        let public_key = match (alg, y) {
+            (-7, Some(y)) => {
+                let mut key = [0; 64];
+                key[..32].copy_from_slice(&x);
+                key[32..].copy_from_slice(y);
+                PublicKey::Es256(key)
+            }
+            (-8, None) => PublicKey::EdDsa(x),
+            _ => panic!("unexpected key"),
+        };
+        (id, public_key)
+    }
+
+    /// Check a `packed` self attestation response, returning the credential
+    /// ID and public key.
+    fn check_attestation(response: &[u8], alg: i64) -> ([u8; 32], PublicKey) {
+        assert_eq!(response[0], Status::OK.0);
+        let mut reader = Reader::new(&response[1..]);
+        assert_eq!(reader.map(), Ok(3));
+        assert_eq!(reader.int(), Ok(1));
+        assert_eq!(reader.text(), Ok(&b"packed"[..]));
+        assert_eq!(reader.int(), Ok(2));
+        let auth_data = reader.bytes().unwrap();
+        assert_eq!(auth_data[..32], sha256(&[b"example.com"]));
+        let (id, public_key) = attested_credential(auth_data);
+        reader.int().unwrap();
+        reader.map().unwrap();
+        reader.text().unwrap();
+        assert_eq!(reader.int(), Ok(alg));
+        reader.text().unwrap();
+        assert!(public_key.verify(auth_data, reader.bytes().unwrap()));
+        (id, public_key)
+    }
+
+    #[test]
+    fn make_credential_and_get_assertion() {
+        let authenticator = Authenticator::new([0x42; 32], [0x0a; 16]);
+        let request = make_credential_request(&[-7, -8], b"user 1", true, None);
+        let request = parse_make_credential(&authenticator, &request);
+        assert!(request.resident && !request.excluded);
+        assert_eq!(request.algorithm, Algorithm::Es256);
+
+        // The self attestation signature verifies with the credential key.
+        let mut response = [0; 512];
+        let len = authenticator
+            .make_credential(&request, 1, &mut response)
+            .unwrap();
+        assert_eq!(&response[1 + 12 + 33..1 + 12 + 37], &1u32.to_be_bytes());
+        let (id, public_key) = check_attestation(&response[..len], -7);
+
+        // A request with the new credential in its exclude list is refused.
+        let excluded = make_credential_request(&[-7], b"user 2", false, Some(&id));
+        assert!(parse_make_credential(&authenticator, &excluded).excluded);
+
+        // An assertion using the stored record is signed by the same key.
+        let mut record = [0; RECORD_LEN];
+        authenticator.record(&request, 1, &mut record);
+        let resident = authenticator.resident(&record).unwrap();
+        assert_eq!(resident.created, 1);
+        assert_eq!(resident.rp_id_hash, request.rp_id_hash);
+        assert!(request.replaces(&resident));
+        let other_user = make_credential_request(&[-7], b"user 2", true, None);
+        assert!(!parse_make_credential(&authenticator, &other_user).replaces(&resident));
+
+        let mut assertion = get_assertion_request();
+        assert_eq!(
+            authenticator.get_assertion(&assertion, 2, &mut response),
+            Err(Status::NO_CREDENTIALS)
+        );
+        assertion.use_resident(&resident);
+        assertion.number_of_credentials = 2;
+
+        let len = authenticator
+            .get_assertion(&assertion, 2, &mut response)
+            .unwrap();
+        let mut reader = Reader::new(&response[1..len]);
+        assert_eq!(reader.map(), Ok(5));
+        reader.int().unwrap();
+        reader.map().unwrap();
+        reader.text().unwrap();
+        assert_eq!(reader.bytes(), Ok(&id[..]));
+        reader.skip().unwrap();
+        reader.skip().unwrap();
+        reader.int().unwrap();
+        let auth_data = reader.bytes().unwrap();
+        assert_eq!(auth_data[32], FLAG_USER_PRESENT);
+        assert_eq!(&auth_data[33..37], &[0, 0, 0, 2]);
+        reader.int().unwrap();
+        assert!(public_key.verify(auth_data, reader.bytes().unwrap()));
+        reader.int().unwrap();
+        reader.map().unwrap();
+        reader.text().unwrap();
+        assert_eq!(reader.bytes(), Ok(&b"user 1"[..]));
+        assert_eq!(reader.int(), Ok(5));
+        assert_eq!(reader.int(), Ok(2));
+    }
+
+    #[test]
+    fn algorithm_preference() {
+        let authenticator = Authenticator::new([0x42; 32], [0x0a; 16]);
+        let mut response = [0; 512];
+
+        // The first supported algorithm the relying party lists is used.
+        let request = make_credential_request(&[-257, -8, -7], b"user", false, None);
+        let request = parse_make_credential(&authenticator, &request);
+        assert_eq!(request.algorithm, Algorithm::EdDsa);
+        let len = authenticator
+            .make_credential(&request, 1, &mut response)
+            .unwrap();
+        let (id, public_key) = check_attestation(&response[..len], -8);
+
+        let mut assertion = get_assertion_request();
+        assertion.credential = Some(id);
+        let len = authenticator
+            .get_assertion(&assertion, 2, &mut response)
+            .unwrap();
+        let mut reader = Reader::new(&response[1..len]);
+        assert_eq!(reader.map(), Ok(3));
+        reader.skip().unwrap();
+        reader.skip().unwrap();
+        reader.int().unwrap();
+        let auth_data = reader.bytes().unwrap();
+        reader.int().unwrap();
+        assert!(public_key.verify(auth_data, reader.bytes().unwrap()));
+
+        let request = make_credential_request(&[-257], b"user", false, None);
+        assert_eq!(
+            authenticator.parse(&request).err(),
+            Some(Status::UNSUPPORTED_ALGORITHM)
+        );
+    }
+
+    #[test]
+    fn reset_forgets_credentials() {
+        let authenticator = Authenticator::new([0x42; 32], [0x0a; 16]);
+        let request = make_credential_request(&[-7], b"user 1", true, None);
+        let request = parse_make_credential(&authenticator, &request);
+        let mut record = [0; RECORD_LEN];
+        authenticator.record(&request, 1, &mut record);
+        let id = authenticator.new_credential_id(&request);
+        assert!(authenticator.resident(&record).is_some());
+
+        // Credentials of the previous generation are no longer recognised,
+        // in storage or in exclude and allow lists.
+        authenticator.set_generation(1);
+        assert!(authenticator.resident(&record).is_none());
+        let excluded = make_credential_request(&[-7], b"user 1", false, Some(&id));
+        assert!(!parse_make_credential(&authenticator, &excluded).excluded);
+        assert_ne!(authenticator.new_credential_id(&request), id);
+
+        assert!(matches!(authenticator.parse(&[0x07]), Ok(Command::Reset)));
+        assert!(matches!(
+            authenticator.parse(&[0x08]),
+            Ok(Command::GetNextAssertion)
+        ));
+    }
+
+    #[test]
+    fn der_signatures() {
+        let mut raw = [0; 64];
+        raw[0] = 0x80;
+        raw[32 + 2] = 0x7f;
+        let mut der = [0; MAX_SIGNATURE_LEN];
+        let len = der_signature(&raw, &mut der);
+        // r gets a sign byte, s loses its leading zeros.
+        assert_eq!(&der[..6], &[0x30, 2 + 33 + 2 + 30, 0x02, 33, 0x00, 0x80]);
+        assert_eq!(&der[2 + 2 + 33..2 + 2 + 33 + 3], &[0x02, 30, 0x7f]);
+        assert_eq!(len, 2 + 35 + 32);
+        assert_eq!(from_der(&der[..len]), raw);
+
+        let len = der_signature(&[0xff; 64], &mut der);
+        assert_eq!(len, MAX_SIGNATURE_LEN);
+    }
+}
@@ -0,0 +1,370 @@
+//! The subset of CBOR (RFC 8949) used by CTAP2.
+//!
+//! CTAP2 messages only use definite-length items: integers, byte and text
+//! strings, arrays, maps, booleans and null. `Writer` encodes them into a
+//! buffer, and `Reader` decodes them one item header at a time so that
+//! requests can be parsed without allocating. Responses must use the CTAP2
+//! canonical encoding, so the caller writes map entries in canonical key
+//! order.
+
+/// Major types.
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+const FALSE: u8 = 20;
+const TRUE: u8 = 21;
+const NULL: u8 = 22;
+
+/// How deeply nested items `Reader::skip()` accepts. CTAP2 messages are at
+/// most four levels deep.
+const MAX_DEPTH: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The input ended in the middle of an item.
+    Truncated,
+    /// The input is not well-formed, or uses an encoding CTAP2 forbids.
+    Invalid,
+    /// The item is well-formed but not of the expected type.
+    UnexpectedType,
+    /// Items are nested more deeply than CTAP2 allows.
+    TooDeep,
+    /// The output buffer is too small.
+    Overflow,
+}
+
+/// A decoded item header. Arrays and maps carry their number of elements or
+/// entries, which the caller reads next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'b> {
+    Unsigned(u64),
+    /// A negative integer, `-1 - n` for the encoded `n`.
+    Negative(i64),
+    Bytes(&'b [u8]),
+    Text(&'b [u8]),
+    Array(usize),
+    Map(usize),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Clone, Copy)]
+pub struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    pub fn new(buf: &'b [u8]) -> Reader<'b> {
+        Reader { buf, pos: 0 }
+    }
+
+    /// Whether all of the input has been read.
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self.buf.get(self.pos).ok_or(Error::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'b [u8], Error> {
+        let end = self.pos.checked_add(len).ok_or(Error::Truncated)?;
+        let data = self.buf.get(self.pos..end).ok_or(Error::Truncated)?;
+        self.pos = end;
+        Ok(data)
+    }
+
+    /// Read the argument of an item header with additional information
+    /// `info`.
+    fn argument(&mut self, info: u8) -> Result<u64, Error> {
+        let len = match info {
+            0..=23 => return Ok(info as u64),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            // Reserved values and indefinite lengths.
+            _ => return Err(Error::Invalid),
+        };
+        let mut value = 0u64;
+        for byte in self.take(len)? {
+            value = (value << 8) | *byte as u64;
+        }
+        Ok(value)
+    }
+
+    fn length(&mut self, info: u8) -> Result<usize, Error> {
+        let len = self.argument(info)?;
+        if len > self.buf.len() as u64 {
+            // Every element takes at least one byte, so this cannot be
+            // valid.
+            return Err(Error::Truncated);
+        }
+        Ok(len as usize)
+    }
+
+    /// Read the next item header, and the contents of strings.
+    pub fn next(&mut self) -> Result<Value<'b>, Error> {
+        let initial = self.byte()?;
+        let info = initial & 0x1f;
+        match initial >> 5 {
+            UNSIGNED => Ok(Value::Unsigned(self.argument(info)?)),
+            NEGATIVE => {
+                let n = self.argument(info)?;
+                if n > i64::MAX as u64 {
+                    return Err(Error::Invalid);
+                }
+                Ok(Value::Negative(-1 - n as i64))
+            }
+            BYTES => {
+                let len = self.length(info)?;
+                Ok(Value::Bytes(self.take(len)?))
+            }
+            TEXT => {
+                let len = self.length(info)?;
+                Ok(Value::Text(self.take(len)?))
+            }
+            ARRAY => Ok(Value::Array(self.length(info)?)),
+            MAP => Ok(Value::Map(self.length(info)?)),
+            TAG => Err(Error::UnexpectedType),
+            SIMPLE => match info {
+                FALSE => Ok(Value::Bool(false)),
+                TRUE => Ok(Value::Bool(true)),
+                NULL => Ok(Value::Null),
+                _ => Err(Error::UnexpectedType),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Read an integer.
+    pub fn int(&mut self) -> Result<i64, Error> {
+        match self.next()? {
+            Value::Unsigned(n) if n <= i64::MAX as u64 => Ok(n as i64),
+            Value::Negative(n) => Ok(n),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'b [u8], Error> {
+        match self.next()? {
+            Value::Bytes(data) => Ok(data),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    pub fn text(&mut self) -> Result<&'b [u8], Error> {
+        match self.next()? {
+            Value::Text(data) => Ok(data),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        match self.next()? {
+            Value::Bool(value) => Ok(value),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    /// Read an array header, returning the number of elements.
+    pub fn array(&mut self) -> Result<usize, Error> {
+        match self.next()? {
+            Value::Array(len) => Ok(len),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    /// Read a map header, returning the number of entries.
+    pub fn map(&mut self) -> Result<usize, Error> {
+        match self.next()? {
+            Value::Map(len) => Ok(len),
+            _ => Err(Error::UnexpectedType),
+        }
+    }
+
+    /// Skip the next item, including any nested items.
+    pub fn skip(&mut self) -> Result<(), Error> {
+        self.skip_nested(0)
+    }
+
+    fn skip_nested(&mut self, depth: usize) -> Result<(), Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        match self.next()? {
+            Value::Array(len) => {
+                for _ in 0..len {
+                    self.skip_nested(depth + 1)?;
+                }
+            }
+            Value::Map(len) => {
+                for _ in 0..len * 2 {
+                    self.skip_nested(depth + 1)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+pub struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+    overflow: bool,
+}
+
+impl<'b> Writer<'b> {
+    pub fn new(buf: &'b mut [u8]) -> Writer<'b> {
+        Writer {
+            buf,
+            pos: 0,
+            overflow: false,
+        }
+    }
+
+    fn put(&mut self, data: &[u8]) {
+        match self.buf.get_mut(self.pos..self.pos + data.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(data);
+                self.pos += data.len();
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    /// Write an item header using the shortest encoding of `argument`, as
+    /// canonical CBOR requires.
+    fn header(&mut self, major: u8, argument: u64) {
+        let major = major << 5;
+        if argument < 24 {
+            self.put(&[major | argument as u8]);
+        } else if argument <= u8::MAX as u64 {
+            self.put(&[major | 24, argument as u8]);
+        } else if argument <= u16::MAX as u64 {
+            self.put(&[major | 25]);
+            self.put(&(argument as u16).to_be_bytes());
+        } else if argument <= u32::MAX as u64 {
+            self.put(&[major | 26]);
+            self.put(&(argument as u32).to_be_bytes());
+        } else {
+            self.put(&[major | 27]);
+            self.put(&argument.to_be_bytes());
+        }
+    }
+
+    pub fn int(&mut self, value: i64) {
+        if value >= 0 {
+            self.header(UNSIGNED, value as u64);
+        } else {
+            self.header(NEGATIVE, (-1 - value) as u64);
+        }
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.header(BYTES, data.len() as u64);
+        self.put(data);
+    }
+
+    pub fn text(&mut self, text: &str) {
+        self.header(TEXT, text.len() as u64);
+        self.put(text.as_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.put(&[(SIMPLE << 5) | if value { TRUE } else { FALSE }]);
+    }
+
+    /// Start an array of `len` elements, which are written next.
+    pub fn array(&mut self, len: usize) {
+        self.header(ARRAY, len as u64);
+    }
+
+    /// Start a map of `len` entries, whose keys and values are written next.
+    pub fn map(&mut self, len: usize) {
+        self.header(MAP, len as u64);
+    }
+
+    /// Write raw, already encoded, data.
+    pub fn raw(&mut self, data: &[u8]) {
+        self.put(data);
+    }
+
+    /// Return the number of bytes written, or `Overflow` if they did not fit.
+    pub fn finish(self) -> Result<usize, Error> {
+        if self.overflow {
+            Err(Error::Overflow)
+        } else {
+            Ok(self.pos)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0; 64];
+        let mut writer = Writer::new(&mut buf);
+        writer.map(3);
+        writer.int(1);
+        writer.bytes(&[0xaa; 3]);
+        writer.int(-8);
+        writer.array(2);
+        writer.bool(true);
+        writer.text("up");
+        writer.int(1000);
+        writer.int(-1000);
+        let len = writer.finish().unwrap();
+
+        assert_eq!(
+            &buf[..len],
+            &[
+                0xa3, 0x01, 0x43, 0xaa, 0xaa, 0xaa, 0x27, 0x82, 0xf5, 0x62, b'u', b'p', 0x19, 0x03,
+                0xe8, 0x39, 0x03, 0xe7
+            ]
+        );
+
+        let mut reader = Reader::new(&buf[..len]);
+        assert_eq!(reader.map(), Ok(3));
+        assert_eq!(reader.int(), Ok(1));
+        assert_eq!(reader.bytes(), Ok(&[0xaa; 3][..]));
+        assert_eq!(reader.int(), Ok(-8));
+        assert_eq!(reader.array(), Ok(2));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.text(), Ok(&b"up"[..]));
+        assert_eq!(reader.int(), Ok(1000));
+        assert_eq!(reader.int(), Ok(-1000));
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        // A byte string longer than the input.
+        assert_eq!(Reader::new(&[0x45, 0x00]).next(), Err(Error::Truncated));
+        // An indefinite-length array.
+        assert_eq!(Reader::new(&[0x9f, 0xff]).next(), Err(Error::Invalid));
+        // Five levels of nested arrays.
+        assert_eq!(
+            Reader::new(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x00]).skip(),
+            Err(Error::TooDeep)
+        );
+        // Writing past the end of the buffer.
+        let mut buf = [0; 2];
+        let mut writer = Writer::new(&mut buf);
+        writer.bytes(&[0; 2]);
+        assert_eq!(writer.finish(), Err(Error::Overflow));
+    }
+}
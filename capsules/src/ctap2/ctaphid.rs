@@ -0,0 +1,425 @@
+//! CTAPHID, the framing of CTAP messages in 64-byte HID reports.
+//!
+//! Every report starts with a four byte channel identifier (CID). A message
+//! is sent as an initialization packet, carrying the command and the total
+//! length of the message, followed by continuation packets carrying a
+//! sequence number. Hosts allocate a channel by sending `INIT` on the
+//! broadcast channel.
+//!
+//! `Receiver` reassembles request messages and allocates channels, and
+//! `Transmitter` splits a response into packets. Neither touches the USB
+//! device; `hid::CtapHidAuthenticator` moves the packets.
+
+use core::cmp;
+
+pub const PACKET_SIZE: usize = 64;
+/// Payload bytes in an initialization packet.
+const INIT_DATA: usize = PACKET_SIZE - 7;
+/// Payload bytes in a continuation packet.
+const CONT_DATA: usize = PACKET_SIZE - 5;
+/// The longest message that can be framed: one initialization packet and
+/// 128 continuation packets.
+pub const MAX_MESSAGE_SIZE: usize = INIT_DATA + 128 * CONT_DATA;
+
+pub const BROADCAST_CID: u32 = 0xffff_ffff;
+
+/// The CTAPHID protocol version reported by `INIT`.
+const PROTOCOL_VERSION: u8 = 2;
+/// Capability flags reported by `INIT`.
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+/// The authenticator does not implement CTAP1/U2F `MSG`.
+const CAPABILITY_NMSG: u8 = 0x08;
+
+pub mod command {
+    pub const PING: u8 = 0x01;
+    pub const MSG: u8 = 0x03;
+    pub const INIT: u8 = 0x06;
+    pub const WINK: u8 = 0x08;
+    pub const CBOR: u8 = 0x10;
+    pub const CANCEL: u8 = 0x11;
+    pub const KEEPALIVE: u8 = 0x3b;
+    pub const ERROR: u8 = 0x3f;
+}
+
+pub mod error {
+    pub const INVALID_CMD: u8 = 0x01;
+    pub const INVALID_PAR: u8 = 0x02;
+    pub const INVALID_LEN: u8 = 0x03;
+    pub const INVALID_SEQ: u8 = 0x04;
+    pub const MSG_TIMEOUT: u8 = 0x05;
+    pub const CHANNEL_BUSY: u8 = 0x06;
+    pub const INVALID_CHANNEL: u8 = 0x0b;
+    pub const OTHER: u8 = 0x7f;
+}
+
+/// Status codes of `KEEPALIVE` packets.
+pub mod keepalive {
+    pub const PROCESSING: u8 = 1;
+    pub const UPNEEDED: u8 = 2;
+}
+
+/// What a received packet asks the authenticator to do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Received {
+    /// Nothing: the packet was part of a message that is not complete yet,
+    /// or was ignored.
+    Nothing,
+    /// A complete request is in the first `len` bytes of the message buffer.
+    Message { cid: u32, cmd: u8, len: usize },
+    /// Reply to an `INIT` request with this packet.
+    Init { cid: u32, response: [u8; 17] },
+    /// The host cancelled the request on channel `cid`.
+    Cancel { cid: u32 },
+    /// Reply with an `ERROR` packet.
+    Error { cid: u32, code: u8 },
+}
+
+/// A request that is being reassembled.
+#[derive(Clone, Copy)]
+struct Transfer {
+    cid: u32,
+    cmd: u8,
+    len: usize,
+    received: usize,
+    seq: u8,
+}
+
+pub struct Receiver {
+    /// The channel that the next `INIT` on the broadcast channel allocates.
+    next_cid: u32,
+    transfer: Option<Transfer>,
+}
+
+impl Receiver {
+    pub const fn new() -> Receiver {
+        Receiver {
+            next_cid: 1,
+            transfer: None,
+        }
+    }
+
+    /// Whether a request is partially received.
+    pub fn receiving(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    /// Give up on the partially received request, returning its channel.
+    pub fn timeout(&mut self) -> Option<u32> {
+        self.transfer.take().map(|transfer| transfer.cid)
+    }
+
+    fn allocated(&self, cid: u32) -> bool {
+        cid != 0 && cid < self.next_cid
+    }
+
+    /// Handle a received packet. Request payloads are reassembled into
+    /// `message`, which is `None` while the previous request is still being
+    /// processed; new requests are then refused with `CHANNEL_BUSY`.
+    pub fn receive(&mut self, packet: &[u8; PACKET_SIZE], message: Option<&mut [u8]>) -> Received {
+        let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+
+        if packet[4] & 0x80 == 0 {
+            return self.continuation(cid, packet[4], &packet[5..], message);
+        }
+
+        let cmd = packet[4] & 0x7f;
+        let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+        let data = &packet[7..];
+
+        if cmd == command::INIT {
+            return self.init(cid, len, data);
+        }
+        if cid == BROADCAST_CID || !self.allocated(cid) {
+            return Received::Error {
+                cid,
+                code: error::INVALID_CHANNEL,
+            };
+        }
+        if cmd == command::CANCEL {
+            return Received::Cancel { cid };
+        }
+
+        let message = match (self.transfer, message) {
+            (None, Some(message)) => message,
+            (Some(transfer), _) if transfer.cid == cid => {
+                // A new request before the previous one was complete.
+                self.transfer = None;
+                return Received::Error {
+                    cid,
+                    code: error::INVALID_SEQ,
+                };
+            }
+            _ => {
+                return Received::Error {
+                    cid,
+                    code: error::CHANNEL_BUSY,
+                }
+            }
+        };
+        if len > message.len() || len > MAX_MESSAGE_SIZE {
+            return Received::Error {
+                cid,
+                code: error::INVALID_LEN,
+            };
+        }
+
+        let n = cmp::min(len, INIT_DATA);
+        message[..n].copy_from_slice(&data[..n]);
+        if n == len {
+            return Received::Message { cid, cmd, len };
+        }
+        self.transfer = Some(Transfer {
+            cid,
+            cmd,
+            len,
+            received: n,
+            seq: 0,
+        });
+        Received::Nothing
+    }
+
+    fn init(&mut self, cid: u32, len: usize, data: &[u8]) -> Received {
+        if len != 8 {
+            return Received::Error {
+                cid,
+                code: error::INVALID_LEN,
+            };
+        }
+        let new_cid = if cid == BROADCAST_CID {
+            let new_cid = self.next_cid;
+            self.next_cid = match new_cid.wrapping_add(1) {
+                BROADCAST_CID => 1,
+                next => next,
+            };
+            new_cid
+        } else if self.allocated(cid) {
+            // Resynchronize the channel, abandoning any request on it.
+            if self.transfer.map_or(false, |transfer| transfer.cid == cid) {
+                self.transfer = None;
+            }
+            cid
+        } else {
+            return Received::Error {
+                cid,
+                code: error::INVALID_CHANNEL,
+            };
+        };
+
+        let mut response = [0; 17];
+        response[..8].copy_from_slice(&data[..8]);
+        response[8..12].copy_from_slice(&new_cid.to_be_bytes());
+        response[12] = PROTOCOL_VERSION;
+        // Device version numbers: major, minor and build.
+        response[13..16].copy_from_slice(&[2, 0, 0]);
+        response[16] = CAPABILITY_WINK | CAPABILITY_CBOR | CAPABILITY_NMSG;
+        Received::Init { cid, response }
+    }
+
+    fn continuation(
+        &mut self,
+        cid: u32,
+        seq: u8,
+        data: &[u8],
+        message: Option<&mut [u8]>,
+    ) -> Received {
+        let mut transfer = match self.transfer {
+            Some(transfer) if transfer.cid == cid => transfer,
+            // Continuation packets that do not belong to the current request
+            // are ignored.
+            _ => return Received::Nothing,
+        };
+        let message = match message {
+            Some(message) => message,
+            None => return Received::Nothing,
+        };
+        if seq != transfer.seq {
+            self.transfer = None;
+            return Received::Error {
+                cid,
+                code: error::INVALID_SEQ,
+            };
+        }
+
+        let n = cmp::min(transfer.len - transfer.received, CONT_DATA);
+        message[transfer.received..transfer.received + n].copy_from_slice(&data[..n]);
+        transfer.received += n;
+        transfer.seq += 1;
+
+        if transfer.received == transfer.len {
+            self.transfer = None;
+            Received::Message {
+                cid,
+                cmd: transfer.cmd,
+                len: transfer.len,
+            }
+        } else {
+            self.transfer = Some(transfer);
+            Received::Nothing
+        }
+    }
+}
+
+/// Splits a response message into packets.
+#[derive(Clone, Copy)]
+pub struct Transmitter {
+    cid: u32,
+    cmd: u8,
+    len: usize,
+    sent: usize,
+    seq: u8,
+    started: bool,
+}
+
+impl Transmitter {
+    pub fn new(cid: u32, cmd: u8, len: usize) -> Transmitter {
+        Transmitter {
+            cid,
+            cmd,
+            len: cmp::min(len, MAX_MESSAGE_SIZE),
+            sent: 0,
+            seq: 0,
+            started: false,
+        }
+    }
+
+    /// Write the next packet of the response in `message` to `packet`.
+    /// Returns `false` once the whole response has been written.
+    pub fn next_packet(&mut self, message: &[u8], packet: &mut [u8; PACKET_SIZE]) -> bool {
+        if self.started && self.sent == self.len {
+            return false;
+        }
+
+        for byte in packet.iter_mut() {
+            *byte = 0;
+        }
+        packet[..4].copy_from_slice(&self.cid.to_be_bytes());
+        let data = if !self.started {
+            self.started = true;
+            packet[4] = 0x80 | self.cmd;
+            packet[5..7].copy_from_slice(&(self.len as u16).to_be_bytes());
+            &mut packet[7..]
+        } else {
+            packet[4] = self.seq;
+            self.seq += 1;
+            &mut packet[5..]
+        };
+
+        let n = cmp::min(self.len - self.sent, data.len());
+        data[..n].copy_from_slice(&message[self.sent..self.sent + n]);
+        self.sent += n;
+        true
+    }
+}
+
+/// Write a response that fits in a single packet, such as `ERROR` or
+/// `KEEPALIVE`.
+pub fn single_packet(packet: &mut [u8; PACKET_SIZE], cid: u32, cmd: u8, data: &[u8]) {
+    let mut transmitter = Transmitter::new(cid, cmd, cmp::min(data.len(), INIT_DATA));
+    transmitter.next_packet(data, packet);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_packet(cid: u32, cmd: u8, len: usize, data: &[u8]) -> [u8; PACKET_SIZE] {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..4].copy_from_slice(&cid.to_be_bytes());
+        packet[4] = 0x80 | cmd;
+        packet[5..7].copy_from_slice(&(len as u16).to_be_bytes());
+        packet[7..7 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn allocates_channels() {
+        let mut receiver = Receiver::new();
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        let packet = init_packet(BROADCAST_CID, command::INIT, 8, &nonce);
+        let cid = match receiver.receive(&packet, None) {
+            Received::Init { cid, response } => {
+                assert_eq!(cid, BROADCAST_CID);
+                assert_eq!(&response[..8], &nonce);
+                u32::from_be_bytes([response[8], response[9], response[10], response[11]])
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_ne!(cid, BROADCAST_CID);
+
+        // Requests on channels that were never allocated are refused.
+        let mut message = [0; 256];
+        let packet = init_packet(cid + 1, command::PING, 1, &[0]);
+        assert_eq!(
+            receiver.receive(&packet, Some(&mut message)),
+            Received::Error {
+                cid: cid + 1,
+                code: error::INVALID_CHANNEL
+            }
+        );
+
+        // While a request is processed other requests are refused.
+        let packet = init_packet(cid, command::PING, 1, &[0]);
+        assert_eq!(
+            receiver.receive(&packet, None),
+            Received::Error {
+                cid,
+                code: error::CHANNEL_BUSY
+            }
+        );
+    }
+
+    #[test]
+    fn fragments_and_reassembles() {
+        let mut receiver = Receiver::new();
+        let packet = init_packet(BROADCAST_CID, command::INIT, 8, &[0; 8]);
+        let cid = match receiver.receive(&packet, None) {
+            Received::Init { response, .. } => {
+                u32::from_be_bytes([response[8], response[9], response[10], response[11]])
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let mut request = [0; 200];
+        for (i, byte) in request.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        // Packets produced by the transmitter are accepted by the receiver.
+        let mut transmitter = Transmitter::new(cid, command::PING, request.len());
+        let mut message = [0; 256];
+        let mut packet = [0; PACKET_SIZE];
+        let mut packets = 0;
+        let mut result = Received::Nothing;
+        while transmitter.next_packet(&request, &mut packet) {
+            packets += 1;
+            result = receiver.receive(&packet, Some(&mut message));
+        }
+        assert_eq!(packets, 4);
+        assert_eq!(
+            result,
+            Received::Message {
+                cid,
+                cmd: command::PING,
+                len: request.len()
+            }
+        );
+        assert_eq!(&message[..request.len()], &request[..]);
+
+        // A continuation packet out of sequence aborts the request.
+        let mut transmitter = Transmitter::new(cid, command::PING, request.len());
+        transmitter.next_packet(&request, &mut packet);
+        receiver.receive(&packet, Some(&mut message));
+        transmitter.next_packet(&request, &mut packet);
+        transmitter.next_packet(&request, &mut packet);
+        assert_eq!(
+            receiver.receive(&packet, Some(&mut message)),
+            Received::Error {
+                cid,
+                code: error::INVALID_SEQ
+            }
+        );
+        assert!(!receiver.receiving());
+    }
+}
@@ -0,0 +1,1981 @@
+//! A CTAP2 authenticator on a USB HID interface.
+//!
+//! This connects the CTAPHID framing in `ctaphid` to the commands in
+//! `authenticator`. It allocates channels, answers `PING` and `WINK`, and
+//! runs CTAP2 requests one at a time; requests on other channels are refused
+//! with `CHANNEL_BUSY` until the response has been sent.
+//!
+//! Requests that need the user's presence wait for the button to be pressed,
+//! sending `KEEPALIVE` packets every `KEEPALIVE_INTERVAL_MS` meanwhile, and
+//! fail with `USER_ACTION_TIMEOUT` after `USER_PRESENCE_TIMEOUT_MS`. Resident
+//! credentials are kept in a `hil::kv_system::KVSystem` such as
+//! `capsules::tickv::TicKVStore`, one per slot. Registering a resident
+//! credential reads every slot to find the user's previous credential or a
+//! free slot, and so does an assertion without an allow list to find the
+//! relying party's credentials. The newest is used, and the others are
+//! returned by `authenticatorGetNextAssertion` for `NEXT_ASSERTION_TIMEOUT_MS`
+//! afterwards.
+//!
+//! The signature counter is kept in the same store, together with the
+//! authenticator's generation, and is loaded before the first request is
+//! handled. Each signature stores the next value of the counter before the
+//! response is sent, alternating between `authenticator::COUNTER_SLOTS` keys
+//! so that the previous value is only removed once a larger one has been
+//! written. After a restart the largest stored value is used.
+//!
+//! `authenticatorReset` is only accepted within `RESET_WINDOW_MS` of
+//! `start()`, and needs the user's presence. It stores the next generation,
+//! which invalidates every credential, and then erases the resident
+//! credential slots.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ctap2 = static_init!(
+//!     capsules::ctap2::hid::CtapHidAuthenticator<
+//!         'static,
+//!         capsules::usb::ctap::CtapHid<'static, lowrisc::usbdev::Usb<'static>>,
+//!         capsules::tickv::TicKVStore<'static, lowrisc::flash_ctrl::FlashCtrl<'static>>,
+//!         lowrisc::gpio::GpioPin<'static>,
+//!         VirtualMuxAlarm<'static, earlgrey::timer::RvTimer<'static>>,
+//!     >,
+//!     capsules::ctap2::hid::CtapHidAuthenticator::new(
+//!         ctap_hid,
+//!         tickv,
+//!         &peripherals.gpio_port[0],
+//!         kernel::hil::gpio::ActivationMode::ActiveLow,
+//!         kernel::hil::gpio::FloatingState::PullUp,
+//!         ctap2_alarm,
+//!         capsules::ctap2::authenticator::Authenticator::new(DEVICE_SECRET, AAGUID),
+//!         &mut capsules::ctap2::hid::MESSAGE,
+//!         &mut capsules::ctap2::hid::RECORD,
+//!         &mut capsules::ctap2::hid::KEY,
+//!         &mut capsules::ctap2::hid::COUNTER,
+//!         &mut capsules::ctap2::hid::SEND_BUFFER,
+//!         &mut capsules::ctap2::hid::RECV_BUFFER,
+//!     )
+//! );
+//! ctap_hid.set_client(ctap2);
+//! tickv.set_client(ctap2);
+//! peripherals.gpio_port[0].set_client(ctap2);
+//! ctap2_alarm.set_alarm_client(ctap2);
+//! ctap_hid.enable();
+//! ctap_hid.attach();
+//! ctap2.start().unwrap();
+//! ```
+
+use super::authenticator::{self, Authenticator, Command, Status, MAX_RESIDENT_CREDENTIALS};
+use super::ctaphid::{self, command, error, keepalive, Received, Receiver, Transmitter};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::kv_system::{self, KVSystem};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::hil::usb_hid::{self, UsbHid};
+use kernel::ErrorCode;
+
+pub const KEEPALIVE_INTERVAL_MS: u32 = 100;
+pub const USER_PRESENCE_TIMEOUT_MS: u32 = 30_000;
+/// How long the host may take between the packets of a request.
+pub const MESSAGE_TIMEOUT_MS: u32 = 500;
+/// How long after `start()` `authenticatorReset` is accepted.
+pub const RESET_WINDOW_MS: u32 = 10_000;
+/// How long after an assertion the next one can be requested.
+pub const NEXT_ASSERTION_TIMEOUT_MS: u32 = 30_000;
+
+pub static mut MESSAGE: [u8; 1024] = [0; 1024];
+pub static mut RECORD: [u8; authenticator::RECORD_LEN] = [0; authenticator::RECORD_LEN];
+pub static mut KEY: [u8; 8] = [0; 8];
+pub static mut COUNTER: [u8; authenticator::COUNTER_LEN] = [0; authenticator::COUNTER_LEN];
+pub static mut SEND_BUFFER: [u8; ctaphid::PACKET_SIZE] = [0; ctaphid::PACKET_SIZE];
+pub static mut RECV_BUFFER: [u8; ctaphid::PACKET_SIZE] = [0; ctaphid::PACKET_SIZE];
+
+/// A stored signature counter.
+#[derive(Clone, Copy, PartialEq)]
+struct Counter {
+    count: u32,
+    /// Number of times the authenticator has been reset.
+    generation: u32,
+    /// The slot it is stored in.
+    slot: usize,
+}
+
+impl Counter {
+    fn decode(bytes: &[u8; authenticator::COUNTER_LEN], slot: usize) -> Counter {
+        Counter {
+            count: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            generation: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            slot,
+        }
+    }
+
+    fn encode(&self) -> [u8; authenticator::COUNTER_LEN] {
+        let mut bytes = [0; authenticator::COUNTER_LEN];
+        bytes[..4].copy_from_slice(&self.count.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.generation.to_le_bytes());
+        bytes
+    }
+}
+
+/// What was found in the resident credential slots.
+#[derive(Clone, Copy)]
+struct Scan {
+    /// Slots holding credentials of the relying party that have not been
+    /// signed with, and when each was created.
+    matches: u32,
+    created: [u32; MAX_RESIDENT_CREDENTIALS],
+    /// The slot in use: the credential to sign with, or where to store the
+    /// new credential.
+    chosen: Option<usize>,
+    /// The first slot without a valid credential.
+    free: Option<usize>,
+}
+
+impl Scan {
+    const EMPTY: Scan = Scan {
+        matches: 0,
+        created: [0; MAX_RESIDENT_CREDENTIALS],
+        chosen: None,
+        free: None,
+    };
+}
+
+/// The credentials left for `authenticatorGetNextAssertion`.
+#[derive(Clone, Copy)]
+struct NextAssertions<T: Ticks> {
+    request: authenticator::GetAssertion,
+    scan: Scan,
+    /// When the last assertion was sent.
+    since: T,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// Waiting for a request.
+    Idle,
+    /// Reading slot `.0` of the signature counter before handling the `.2`
+    /// byte request in the message buffer. `.1` is the largest value read so
+    /// far.
+    LoadingCount(usize, Option<Counter>, usize),
+    /// Reading resident credential slot `.0`.
+    Scanning(usize),
+    /// Waiting for the button to be pressed.
+    UserPresence,
+    /// Reading the resident credential for the next assertion.
+    Loading,
+    /// Removing the previous record in the chosen slot before storing a
+    /// resident credential created with signature counter `.0` there.
+    Invalidating(u32),
+    /// Storing a resident credential created with signature counter `.0`.
+    Storing(u32),
+    /// Removing the stale signature counter in the slot of `.0` before
+    /// storing `.0` there.
+    InvalidatingCount(Counter),
+    /// Storing signature counter `.0`.
+    StoringCount(Counter),
+    /// Erasing resident credential slot `.0` after a reset.
+    Erasing(usize),
+    /// Sending the response from the message buffer.
+    Sending,
+}
+
+pub struct CtapHidAuthenticator<
+    'a,
+    U: UsbHid<'a, [u8; ctaphid::PACKET_SIZE]>,
+    K: KVSystem<'a, K = [u8; 8]>,
+    IP: gpio::InterruptPin<'a>,
+    A: Alarm<'a>,
+> {
+    usb: &'a U,
+    kv: &'a K,
+    button: &'a IP,
+    button_mode: gpio::ActivationMode,
+    alarm: &'a A,
+    authenticator: Authenticator,
+
+    state: Cell<State>,
+    receiver: MapCell<Receiver>,
+    /// The channel of the request being processed.
+    cid: Cell<u32>,
+    /// The request being processed, while waiting for the user or storage.
+    request: OptionalCell<Command>,
+    /// The resident credentials of the request being processed.
+    scan: Cell<Scan>,
+    /// The remaining credentials of the last assertion.
+    next: OptionalCell<NextAssertions<A::Ticks>>,
+    /// Number of `KEEPALIVE` intervals spent waiting for the user.
+    waited: Cell<u32>,
+    /// When `start()` was called, and whether `authenticatorReset` is still
+    /// accepted.
+    started: Cell<A::Ticks>,
+    reset_window: Cell<bool>,
+    /// The last stored signature counter, once it is known.
+    sign_count: OptionalCell<Counter>,
+    transmitter: OptionalCell<Transmitter>,
+    /// A single-packet response to send before anything else.
+    control: OptionalCell<[u8; ctaphid::PACKET_SIZE]>,
+
+    message: TakeCell<'static, [u8]>,
+    record: TakeCell<'static, [u8]>,
+    key: TakeCell<'static, [u8; 8]>,
+    counter: TakeCell<'static, [u8; authenticator::COUNTER_LEN]>,
+    send_buffer: TakeCell<'static, [u8; ctaphid::PACKET_SIZE]>,
+    recv_buffer: TakeCell<'static, [u8; ctaphid::PACKET_SIZE]>,
+}
+
+impl<
+        'a,
+        U: UsbHid<'a, [u8; ctaphid::PACKET_SIZE]>,
+        K: KVSystem<'a, K = [u8; 8]>,
+        IP: gpio::InterruptPin<'a>,
+        A: Alarm<'a>,
+    > CtapHidAuthenticator<'a, U, K, IP, A>
+{
+    pub fn new(
+        usb: &'a U,
+        kv: &'a K,
+        button: &'a IP,
+        button_mode: gpio::ActivationMode,
+        floating_state: gpio::FloatingState,
+        alarm: &'a A,
+        authenticator: Authenticator,
+        message: &'static mut [u8],
+        record: &'static mut [u8; authenticator::RECORD_LEN],
+        key: &'static mut [u8; 8],
+        counter: &'static mut [u8; authenticator::COUNTER_LEN],
+        send_buffer: &'static mut [u8; ctaphid::PACKET_SIZE],
+        recv_buffer: &'static mut [u8; ctaphid::PACKET_SIZE],
+    ) -> CtapHidAuthenticator<'a, U, K, IP, A> {
+        button.make_input();
+        button.set_floating_state(floating_state);
+
+        CtapHidAuthenticator {
+            usb,
+            kv,
+            button,
+            button_mode,
+            alarm,
+            authenticator,
+            state: Cell::new(State::Idle),
+            receiver: MapCell::new(Receiver::new()),
+            cid: Cell::new(0),
+            request: OptionalCell::empty(),
+            scan: Cell::new(Scan::EMPTY),
+            next: OptionalCell::empty(),
+            waited: Cell::new(0),
+            started: Cell::new(A::Ticks::from(0)),
+            reset_window: Cell::new(false),
+            sign_count: OptionalCell::empty(),
+            transmitter: OptionalCell::empty(),
+            control: OptionalCell::empty(),
+            message: TakeCell::new(message),
+            record: TakeCell::new(record),
+            key: TakeCell::new(key),
+            counter: TakeCell::new(counter),
+            send_buffer: TakeCell::new(send_buffer),
+            recv_buffer: TakeCell::new(recv_buffer),
+        }
+    }
+
+    /// Start receiving requests. `authenticatorReset` is accepted for
+    /// `RESET_WINDOW_MS` from now, so this should be called at boot.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        let buffer = self.recv_buffer.take().ok_or(ErrorCode::ALREADY)?;
+        self.usb.receive_buffer(buffer).map_err(|(e, buffer)| {
+            self.recv_buffer.replace(buffer);
+            e
+        })?;
+        self.started.set(self.alarm.now());
+        self.reset_window.set(true);
+        self.rearm();
+        Ok(())
+    }
+
+    /// The time left until `ms` after `since`, or `None` if it has passed.
+    fn remaining(&self, since: A::Ticks, ms: u32) -> Option<A::Ticks> {
+        let elapsed = self.alarm.now().wrapping_sub(since);
+        let window = A::ticks_from_ms(ms);
+        if elapsed < window {
+            Some(window.wrapping_sub(elapsed))
+        } else {
+            None
+        }
+    }
+
+    /// Close the reset window and forget the remaining assertions once their
+    /// time is up.
+    fn expire(&self) {
+        if self.reset_window.get()
+            && self
+                .remaining(self.started.get(), RESET_WINDOW_MS)
+                .is_none()
+        {
+            self.reset_window.set(false);
+        }
+        let expired = self.next.map_or(false, |next| {
+            self.remaining(next.since, NEXT_ASSERTION_TIMEOUT_MS)
+                .is_none()
+        });
+        if expired {
+            self.next.clear();
+        }
+    }
+
+    /// Set the alarm for the end of the reset window or of the remaining
+    /// assertions, whichever is first, replacing a keepalive or message
+    /// timeout. Ticks wrap around, so these are only expired reliably if
+    /// the alarm fires when they end.
+    fn rearm(&self) {
+        self.expire();
+        let reset = if self.reset_window.get() {
+            self.remaining(self.started.get(), RESET_WINDOW_MS)
+        } else {
+            None
+        };
+        let next = self
+            .next
+            .and_then(|next| self.remaining(next.since, NEXT_ASSERTION_TIMEOUT_MS));
+        let dt = match (reset, next) {
+            (Some(reset), Some(next)) => Some(reset.min(next)),
+            (reset, next) => reset.or(next),
+        };
+        match dt {
+            Some(dt) => self.alarm.set_alarm(self.alarm.now(), dt),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+
+    /// Send the pending control packet, or the next packet of the response.
+    fn send_next(&self) {
+        let packet = match self.send_buffer.take() {
+            Some(packet) => packet,
+            // A packet is being sent; this is called again once it is done.
+            None => return,
+        };
+
+        let ready = if let Some(control) = self.control.take() {
+            packet.copy_from_slice(&control);
+            true
+        } else if let Some(mut transmitter) = self.transmitter.take() {
+            let more = self
+                .message
+                .map_or(false, |message| transmitter.next_packet(message, packet));
+            if more {
+                self.transmitter.set(transmitter);
+            } else {
+                // The whole response has been sent.
+                self.state.set(State::Idle);
+            }
+            more
+        } else {
+            false
+        };
+
+        if ready {
+            if let Err((_, packet)) = self.usb.send_buffer(packet) {
+                self.send_buffer.replace(packet);
+            }
+        } else {
+            self.send_buffer.replace(packet);
+        }
+    }
+
+    fn send_control(&self, cid: u32, cmd: u8, data: &[u8]) {
+        let mut packet = [0; ctaphid::PACKET_SIZE];
+        ctaphid::single_packet(&mut packet, cid, cmd, data);
+        self.control.set(packet);
+        self.send_next();
+    }
+
+    /// Send the first `len` bytes of the message buffer as the response.
+    fn respond(&self, cmd: u8, len: usize) {
+        self.state.set(State::Sending);
+        self.transmitter
+            .set(Transmitter::new(self.cid.get(), cmd, len));
+        self.send_next();
+    }
+
+    /// Respond to a CTAP2 request with a response written by `f`, which
+    /// returns the length of the response.
+    fn respond_cbor(&self, f: impl FnOnce(&mut [u8]) -> Result<usize, Status>) {
+        let len = self.message.map_or(0, |message| match f(message) {
+            Ok(len) => len,
+            Err(status) => {
+                message[0] = status.0;
+                1
+            }
+        });
+        self.respond(command::CBOR, len);
+    }
+
+    fn respond_status(&self, status: Status) {
+        self.respond_cbor(|_| Err(status));
+    }
+
+    fn handle(&self, received: Received) {
+        match received {
+            Received::Nothing => {
+                if self.state.get() == State::Idle
+                    && self.receiver.map_or(false, |receiver| receiver.receiving())
+                {
+                    self.alarm
+                        .set_alarm(self.alarm.now(), A::ticks_from_ms(MESSAGE_TIMEOUT_MS));
+                }
+            }
+            Received::Message { cid, cmd, len } => {
+                self.rearm();
+                self.cid.set(cid);
+                match cmd {
+                    command::PING => self.respond(command::PING, len),
+                    command::WINK => self.respond(command::WINK, 0),
+                    command::CBOR => self.cbor(len),
+                    _ => self.send_control(cid, command::ERROR, &[error::INVALID_CMD]),
+                }
+            }
+            Received::Init { cid, response } => {
+                if self.state.get() == State::UserPresence && self.cid.get() == cid {
+                    // The channel was resynchronized, abandoning the request.
+                    self.stop_waiting();
+                    self.request.clear();
+                    self.state.set(State::Idle);
+                }
+                self.send_control(cid, command::INIT, &response);
+            }
+            Received::Cancel { cid } => {
+                if self.state.get() == State::UserPresence && self.cid.get() == cid {
+                    self.stop_waiting();
+                    self.request.clear();
+                    self.respond_status(Status::KEEPALIVE_CANCEL);
+                }
+            }
+            Received::Error { cid, code } => self.send_control(cid, command::ERROR, &[code]),
+        }
+    }
+
+    fn cbor(&self, len: usize) {
+        // Credentials can only be recognised once the generation is known.
+        if self.sign_count.is_none() {
+            return self.load_count(0, None, len);
+        }
+
+        // Any other command ends a sequence of assertions.
+        self.expire();
+        let next = self.next.take();
+        self.scan.set(Scan::EMPTY);
+
+        let request = self.message.map_or(Err(Status::OTHER), |message| {
+            self.authenticator.parse(&message[..len])
+        });
+
+        match request {
+            Err(status) => self.respond_status(status),
+            Ok(Command::GetInfo) => {
+                let max_message_size = self.message.map_or(0, |message| message.len());
+                self.respond_cbor(|out| self.authenticator.get_info(max_message_size, out));
+            }
+            Ok(Command::MakeCredential(request)) => {
+                self.request.set(Command::MakeCredential(request));
+                if request.resident && !request.excluded {
+                    self.scan_slot(0);
+                } else {
+                    self.wait_for_user();
+                }
+            }
+            Ok(Command::GetAssertion(request)) => {
+                if request.allow_list {
+                    self.assert(request);
+                } else {
+                    self.request.set(Command::GetAssertion(request));
+                    self.scan_slot(0);
+                }
+            }
+            Ok(Command::GetNextAssertion) => match next {
+                Some(next) => self.next_assertion(next),
+                None => self.respond_status(Status::NOT_ALLOWED),
+            },
+            Ok(Command::Reset) => {
+                if self.reset_window.get() {
+                    self.request.set(Command::Reset);
+                    self.wait_for_user();
+                } else {
+                    self.respond_status(Status::NOT_ALLOWED);
+                }
+            }
+        }
+    }
+
+    /// Read resident credential slot `slot`, looking for the credentials
+    /// relevant to the request being processed.
+    fn scan_slot(&self, slot: usize) {
+        if slot == MAX_RESIDENT_CREDENTIALS {
+            return self.scanned();
+        }
+        match (self.key.take(), self.record.take()) {
+            (Some(key), Some(record)) => {
+                *key = authenticator::resident_key(slot);
+                self.state.set(State::Scanning(slot));
+                if let Err((key, record, result)) = self.kv.get_value(key, record) {
+                    self.key.replace(key);
+                    self.record.replace(record);
+                    self.slot_read(slot, result);
+                }
+            }
+            (key, record) => {
+                key.map(|key| self.key.replace(key));
+                record.map(|record| self.record.replace(record));
+                self.request.clear();
+                self.respond_status(Status::OTHER);
+            }
+        }
+    }
+
+    /// Resident credential slot `slot` was read into the record buffer,
+    /// unless `result` is an error.
+    fn slot_read(&self, slot: usize, result: Result<(), ErrorCode>) {
+        // Slots that were never written, or hold a credential from before a
+        // reset, are free.
+        let resident = match result {
+            Ok(()) => self
+                .record
+                .map_or(None, |record| self.authenticator.resident(record)),
+            Err(_) => None,
+        };
+
+        let mut scan = self.scan.get();
+        match (resident, self.request.extract()) {
+            (None, _) => {
+                scan.free = scan.free.or(Some(slot));
+            }
+            (Some(resident), Some(Command::MakeCredential(request))) => {
+                if request.replaces(&resident) {
+                    scan.chosen = Some(slot);
+                }
+            }
+            (Some(resident), Some(Command::GetAssertion(mut request))) => {
+                if resident.rp_id_hash == request.rp_id_hash {
+                    scan.matches |= 1 << slot;
+                    scan.created[slot] = resident.created;
+                    // Sign with the newest credential first.
+                    let newest = scan
+                        .chosen
+                        .map_or(true, |chosen| resident.created > scan.created[chosen]);
+                    if newest {
+                        scan.chosen = Some(slot);
+                        request.use_resident(&resident);
+                        self.request.set(Command::GetAssertion(request));
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.scan.set(scan);
+        self.scan_slot(slot + 1);
+    }
+
+    /// Every resident credential slot has been read.
+    fn scanned(&self) {
+        let scan = self.scan.get();
+        match self.request.take() {
+            Some(Command::MakeCredential(request)) => {
+                // Replace the user's credential, or use a free slot.
+                match scan.chosen.or(scan.free) {
+                    Some(slot) => {
+                        self.scan.set(Scan {
+                            chosen: Some(slot),
+                            ..scan
+                        });
+                        self.request.set(Command::MakeCredential(request));
+                        self.wait_for_user();
+                    }
+                    None => self.respond_status(Status::KEY_STORE_FULL),
+                }
+            }
+            Some(Command::GetAssertion(mut request)) => match scan.chosen {
+                Some(chosen) => {
+                    let remaining = scan.matches & !(1 << chosen);
+                    if remaining != 0 {
+                        request.number_of_credentials = scan.matches.count_ones() as usize;
+                    }
+                    self.scan.set(Scan {
+                        matches: remaining,
+                        ..scan
+                    });
+                    self.assert(request);
+                }
+                None => self.respond_status(Status::NO_CREDENTIALS),
+            },
+            _ => self.respond_status(Status::OTHER),
+        }
+    }
+
+    /// Sign with the newest remaining credential of the last assertion.
+    fn next_assertion(&self, next: NextAssertions<A::Ticks>) {
+        let mut scan = next.scan;
+        let slot = (0..MAX_RESIDENT_CREDENTIALS)
+            .filter(|slot| scan.matches & (1 << slot) != 0)
+            .max_by_key(|slot| scan.created[*slot]);
+        match (slot, self.key.take(), self.record.take()) {
+            (Some(slot), Some(key), Some(record)) => {
+                scan.matches &= !(1 << slot);
+                scan.chosen = Some(slot);
+                self.scan.set(scan);
+                let mut request = next.request;
+                request.number_of_credentials = 0;
+                self.request.set(Command::GetAssertion(request));
+
+                *key = authenticator::resident_key(slot);
+                self.state.set(State::Loading);
+                if let Err((key, record, result)) = self.kv.get_value(key, record) {
+                    self.key.replace(key);
+                    self.record.replace(record);
+                    self.loaded(result);
+                }
+            }
+            (_, key, record) => {
+                key.map(|key| self.key.replace(key));
+                record.map(|record| self.record.replace(record));
+                self.respond_status(Status::NOT_ALLOWED);
+            }
+        }
+    }
+
+    /// The resident credential for the next assertion was read into the
+    /// record buffer, unless `result` is an error.
+    fn loaded(&self, result: Result<(), ErrorCode>) {
+        if let Some(Command::GetAssertion(mut request)) = self.request.take() {
+            let resident = match result {
+                Ok(()) => self
+                    .record
+                    .map_or(None, |record| self.authenticator.resident(record)),
+                Err(_) => None,
+            };
+            match resident {
+                Some(resident) if resident.rp_id_hash == request.rp_id_hash => {
+                    // The user's presence was confirmed for the first
+                    // assertion.
+                    request.use_resident(&resident);
+                    self.request.set(Command::GetAssertion(request));
+                    self.count();
+                }
+                _ => self.respond_status(Status::NO_CREDENTIALS),
+            }
+        }
+    }
+
+    fn assert(&self, request: authenticator::GetAssertion) {
+        if request.credential.is_none() {
+            self.respond_status(Status::NO_CREDENTIALS);
+        } else if request.user_presence {
+            self.request.set(Command::GetAssertion(request));
+            self.wait_for_user();
+        } else {
+            self.request.set(Command::GetAssertion(request));
+            self.count();
+        }
+    }
+
+    fn wait_for_user(&self) {
+        self.state.set(State::UserPresence);
+        self.waited.set(0);
+        self.button
+            .enable_interrupts(gpio::InterruptEdge::EitherEdge);
+        self.send_control(self.cid.get(), command::KEEPALIVE, &[keepalive::UPNEEDED]);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(KEEPALIVE_INTERVAL_MS));
+    }
+
+    fn stop_waiting(&self) {
+        self.button.disable_interrupts();
+        self.rearm();
+    }
+
+    fn user_present(&self) {
+        self.stop_waiting();
+        match self.request.take() {
+            Some(Command::MakeCredential(request)) if request.excluded => {
+                self.respond_status(Status::CREDENTIAL_EXCLUDED);
+            }
+            Some(request) => {
+                self.request.set(request);
+                self.count();
+            }
+            None => self.state.set(State::Idle),
+        }
+    }
+
+    /// Store the resident credential created by the request being processed
+    /// in the slot chosen for it, recording signature counter `count` as its
+    /// creation time.
+    fn store(&self, count: u32) {
+        let slot = match self.scan.get().chosen {
+            Some(slot) => slot,
+            None => return self.stored(Err(ErrorCode::FAIL)),
+        };
+        if let Some(Command::MakeCredential(request)) = self.request.extract() {
+            self.record
+                .map(|record| self.authenticator.record(&request, count, record));
+        }
+        match self.key.take() {
+            Some(key) => {
+                *key = authenticator::resident_key(slot);
+                self.state.set(State::Invalidating(count));
+                if let Err((key, _)) = self.kv.invalidate_key(key) {
+                    // The slot may never have been written.
+                    self.append(key);
+                }
+            }
+            None => self.stored(Err(ErrorCode::BUSY)),
+        }
+    }
+
+    fn append(&self, key: &'static mut [u8; 8]) {
+        let count = match self.state.get() {
+            State::Invalidating(count) => count,
+            _ => {
+                self.key.replace(key);
+                return;
+            }
+        };
+        match self.record.take() {
+            Some(record) => {
+                self.state.set(State::Storing(count));
+                if let Err((key, record, result)) = self.kv.append_key(key, record) {
+                    self.key.replace(key);
+                    self.record.replace(record);
+                    self.stored(result);
+                }
+            }
+            None => {
+                self.key.replace(key);
+                self.stored(Err(ErrorCode::BUSY));
+            }
+        }
+    }
+
+    fn stored(&self, result: Result<(), ErrorCode>) {
+        match (self.request.take(), self.state.get(), result) {
+            (Some(Command::MakeCredential(request)), State::Storing(count), Ok(())) => {
+                self.respond_cbor(|out| self.authenticator.make_credential(&request, count, out));
+            }
+            (_, _, Err(ErrorCode::NOMEM)) => self.respond_status(Status::KEY_STORE_FULL),
+            _ => self.respond_status(Status::OTHER),
+        }
+    }
+
+    /// Erase resident credential slot `slot` and those after it, once a
+    /// reset has been stored.
+    fn erase(&self, slot: usize) {
+        if slot == MAX_RESIDENT_CREDENTIALS {
+            return self.respond_status(Status::OK);
+        }
+        match self.key.take() {
+            Some(key) => {
+                *key = authenticator::resident_key(slot);
+                self.state.set(State::Erasing(slot));
+                if let Err((key, _)) = self.kv.invalidate_key(key) {
+                    // The slot may never have been written.
+                    self.key.replace(key);
+                    self.erase(slot + 1);
+                }
+            }
+            None => self.respond_status(Status::OTHER),
+        }
+    }
+
+    /// Store the next value of the signature counter, with the next
+    /// generation if the request being processed is a reset, then finish
+    /// the request.
+    fn count(&self) {
+        // The counter is reloaded with the next request if storing the next
+        // value fails.
+        match self.sign_count.take() {
+            Some(current) => self.increment_count(current),
+            None => self.counted(Err(ErrorCode::FAIL)),
+        }
+    }
+
+    /// Read slot `slot` of the signature counter before handling the `len`
+    /// byte request in the message buffer. `best` is the largest value read
+    /// so far.
+    fn load_count(&self, slot: usize, best: Option<Counter>, len: usize) {
+        match (self.key.take(), self.counter.take()) {
+            (Some(key), Some(counter)) => {
+                *key = authenticator::counter_key(slot);
+                self.state.set(State::LoadingCount(slot, best, len));
+                if let Err((key, counter, result)) = self.kv.get_value(key, counter) {
+                    self.key.replace(key);
+                    self.replace_counter(counter);
+                    self.count_loaded(result);
+                }
+            }
+            (key, counter) => {
+                key.map(|key| self.key.replace(key));
+                counter.map(|counter| self.counter.replace(counter));
+                self.respond_status(Status::OTHER);
+            }
+        }
+    }
+
+    fn count_loaded(&self, result: Result<(), ErrorCode>) {
+        let (slot, best, len) = match self.state.get() {
+            State::LoadingCount(slot, best, len) => (slot, best, len),
+            _ => return,
+        };
+        let value = match result {
+            Ok(()) => self.counter.map(|counter| Counter::decode(counter, slot)),
+            // Nothing has been stored in this slot yet.
+            Err(ErrorCode::INVAL) => None,
+            Err(_) => return self.respond_status(Status::OTHER),
+        };
+        let best = match (best, value) {
+            (Some(best), Some(value)) if best.count >= value.count => Some(best),
+            (_, Some(value)) => Some(value),
+            (best, None) => best,
+        };
+        if slot + 1 < authenticator::COUNTER_SLOTS {
+            self.load_count(slot + 1, best, len);
+        } else {
+            // A new counter starts at zero, stored as if in the last slot.
+            let counter = best.unwrap_or(Counter {
+                count: 0,
+                generation: 0,
+                slot: authenticator::COUNTER_SLOTS - 1,
+            });
+            self.sign_count.set(counter);
+            self.authenticator.set_generation(counter.generation);
+            self.cbor(len);
+        }
+    }
+
+    /// Store the value after `current` in the slot after its slot, leaving
+    /// `current` in place until the new value has been written.
+    fn increment_count(&self, current: Counter) {
+        let reset = matches!(self.request.extract(), Some(Command::Reset));
+        let next = match (
+            current.count.checked_add(1),
+            current.generation.checked_add(reset as u32),
+        ) {
+            (Some(count), Some(generation)) => Counter {
+                count,
+                generation,
+                slot: (current.slot + 1) % authenticator::COUNTER_SLOTS,
+            },
+            _ => return self.counted(Err(ErrorCode::FAIL)),
+        };
+        match self.key.take() {
+            Some(key) => {
+                *key = authenticator::counter_key(next.slot);
+                self.state.set(State::InvalidatingCount(next));
+                if let Err((key, _)) = self.kv.invalidate_key(key) {
+                    // The slot may never have been written.
+                    self.append_count(key);
+                }
+            }
+            None => self.counted(Err(ErrorCode::BUSY)),
+        }
+    }
+
+    fn append_count(&self, key: &'static mut [u8; 8]) {
+        let next = match self.state.get() {
+            State::InvalidatingCount(next) => next,
+            _ => {
+                self.key.replace(key);
+                return;
+            }
+        };
+        match self.counter.take() {
+            Some(counter) => {
+                *counter = next.encode();
+                self.state.set(State::StoringCount(next));
+                if let Err((key, counter, result)) = self.kv.append_key(key, counter) {
+                    self.key.replace(key);
+                    self.replace_counter(counter);
+                    self.count_stored(result);
+                }
+            }
+            None => {
+                self.key.replace(key);
+                self.counted(Err(ErrorCode::BUSY));
+            }
+        }
+    }
+
+    fn count_stored(&self, result: Result<(), ErrorCode>) {
+        if let State::StoringCount(counter) = self.state.get() {
+            match result {
+                Ok(()) => {
+                    self.sign_count.set(counter);
+                    self.authenticator.set_generation(counter.generation);
+                    self.counted(Ok(counter.count));
+                }
+                Err(e) => self.counted(Err(e)),
+            }
+        }
+    }
+
+    /// Finish the request being processed, now that signature counter
+    /// `count` has been stored.
+    fn counted(&self, count: Result<u32, ErrorCode>) {
+        match (self.request.take(), count) {
+            (Some(Command::MakeCredential(request)), Ok(count)) => {
+                if request.resident {
+                    self.request.set(Command::MakeCredential(request));
+                    self.store(count);
+                } else {
+                    self.respond_cbor(|out| {
+                        self.authenticator.make_credential(&request, count, out)
+                    });
+                }
+            }
+            (Some(Command::GetAssertion(request)), Ok(count)) => {
+                self.respond_cbor(|out| self.authenticator.get_assertion(&request, count, out));
+                let scan = self.scan.get();
+                if scan.matches != 0 {
+                    self.next.set(NextAssertions {
+                        request,
+                        scan,
+                        since: self.alarm.now(),
+                    });
+                    self.rearm();
+                }
+            }
+            (Some(Command::Reset), Ok(_)) => self.erase(0),
+            _ => self.respond_status(Status::OTHER),
+        }
+    }
+
+    /// Put the counter buffer back after the KV system returned it.
+    fn replace_counter(&self, buffer: &'static mut [u8]) {
+        use core::convert::TryInto;
+        if let Ok(counter) = buffer.try_into() {
+            self.counter.replace(counter);
+        }
+    }
+}
+
+impl<
+        'a,
+        U: UsbHid<'a, [u8; ctaphid::PACKET_SIZE]>,
+        K: KVSystem<'a, K = [u8; 8]>,
+        IP: gpio::InterruptPin<'a>,
+        A: Alarm<'a>,
+    > usb_hid::Client<'a, [u8; ctaphid::PACKET_SIZE]> for CtapHidAuthenticator<'a, U, K, IP, A>
+{
+    fn packet_received(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; ctaphid::PACKET_SIZE],
+        _endpoint: usize,
+    ) {
+        if result.is_ok() {
+            // Requests are only reassembled into the message buffer while no
+            // other request is being processed.
+            let mut message = if self.state.get() == State::Idle {
+                self.message.take()
+            } else {
+                None
+            };
+            let received = self
+                .receiver
+                .map(|receiver| receiver.receive(buffer, message.as_mut().map(|m| &mut m[..])));
+            message.map(|message| self.message.replace(message));
+            received.map(|received| self.handle(received));
+        }
+
+        if let Err((_, buffer)) = self.usb.receive_buffer(buffer) {
+            self.recv_buffer.replace(buffer);
+        }
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        _result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; ctaphid::PACKET_SIZE],
+        _endpoint: usize,
+    ) {
+        self.send_buffer.replace(buffer);
+        self.send_next();
+    }
+
+    fn can_receive(&'a self) -> bool {
+        // Packets are always handled immediately, if only to refuse them.
+        true
+    }
+}
+
+impl<
+        'a,
+        U: UsbHid<'a, [u8; ctaphid::PACKET_SIZE]>,
+        K: KVSystem<'a, K = [u8; 8]>,
+        IP: gpio::InterruptPin<'a>,
+        A: Alarm<'a>,
+    > gpio::Client for CtapHidAuthenticator<'a, U, K, IP, A>
+{
+    fn fired(&self) {
+        if self.state.get() == State::UserPresence
+            && self.button.read_activation(self.button_mode) == gpio::ActivationState::Active
+        {
+            self.user_present();
+        }
+    }
+}
+
+impl<
+        'a,
+        U: UsbHid<'a, [u8; ctaphid::PACKET_SIZE]>,
+        K: KVSystem<'a, K = [u8; 8]>,
+        IP: gpio::InterruptPin<'a>,
+        A: Alarm<'a>,
+    > time::AlarmClient for CtapHidAuthenticator<'a, U, K, IP, A>
+{
+    fn alarm(&self) {
+        self.expire();
+        match self.state.get() {
+            State::UserPresence => {
+                let waited = self.waited.get() + 1;
+                self.waited.set(waited);
+                if waited * KEEPALIVE_INTERVAL_MS >= USER_PRESENCE_TIMEOUT_MS {
+                    self.stop_waiting();
+                    self.request.clear();
+                    self.respond_status(Status::USER_ACTION_TIMEOUT);
+                } else {
+                    self.send_control(self.cid.get(), command::KEEPALIVE, &[keepalive::UPNEEDED]);
+                    self.alarm
+                        .set_alarm(self.alarm.now(), A::ticks_from_ms(KEEPALIVE_INTERVAL_MS));
+                }
+            }
+            State::Idle => {
+                if let Some(Some(cid)) = self.receiver.map(|receiver| receiver.timeout()) {
+                    self.send_control(cid, command::ERROR, &[error::MSG_TIMEOUT]);
+                }
+                self.rearm();
+            }
+            _ => self.rearm(),
+        }
+    }
+}
+
+impl<
+        'a,
+        U: UsbHid<'a, [u8; ctaphid::PACKET_SIZE]>,
+        K: KVSystem<'a, K = [u8; 8]>,
+        IP: gpio::InterruptPin<'a>,
+        A: Alarm<'a>,
+    > kv_system::Client<[u8; 8]> for CtapHidAuthenticator<'a, U, K, IP, A>
+{
+    fn generate_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _unhashed_key: &'static [u8],
+        _key_buf: &'static [u8; 8],
+    ) {
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut [u8; 8],
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        match self.state.get() {
+            State::StoringCount(..) => {
+                self.replace_counter(value);
+                self.count_stored(result);
+            }
+            state => {
+                self.record.replace(value);
+                if let State::Storing(_) = state {
+                    self.stored(result);
+                }
+            }
+        }
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut [u8; 8],
+        ret_buf: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        match self.state.get() {
+            State::LoadingCount(..) => {
+                self.replace_counter(ret_buf);
+                self.count_loaded(result);
+            }
+            state => {
+                self.record.replace(ret_buf);
+                match state {
+                    State::Scanning(slot) => self.slot_read(slot, result),
+                    State::Loading => self.loaded(result),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn invalidate_key_complete(&self, _result: Result<(), ErrorCode>, key: &'static mut [u8; 8]) {
+        // It does not matter whether there was a previous value.
+        match self.state.get() {
+            State::Invalidating(_) => self.append(key),
+            State::InvalidatingCount(..) => self.append_count(key),
+            State::Erasing(slot) => {
+                self.key.replace(key);
+                self.erase(slot + 1);
+            }
+            _ => {
+                self.key.replace(key);
+            }
+        }
+    }
+
+    fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::ctap2::cbor::{Reader, Writer};
+    use crate::public_key_crypto::ed25519;
+    use kernel::hil::time::{Freq1KHz, Ticks32, Time};
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    type Packet = [u8; ctaphid::PACKET_SIZE];
+
+    /// Stands in for the USB HID device: the test plays the host by handing
+    /// packets to the authenticator and collecting the ones it sends.
+    struct FakeUsb {
+        client: OptionalCell<&'static dyn usb_hid::Client<'static, Packet>>,
+        sending: TakeCell<'static, Packet>,
+        receiving: TakeCell<'static, Packet>,
+    }
+
+    impl UsbHid<'static, Packet> for FakeUsb {
+        fn send_buffer(
+            &'static self,
+            send: &'static mut Packet,
+        ) -> Result<usize, (ErrorCode, &'static mut Packet)> {
+            let len = send.len();
+            self.sending.replace(send);
+            Ok(len)
+        }
+
+        fn send_cancel(&'static self) -> Result<&'static mut Packet, ErrorCode> {
+            self.sending.take().ok_or(ErrorCode::INVAL)
+        }
+
+        fn receive_buffer(
+            &'static self,
+            recv: &'static mut Packet,
+        ) -> Result<(), (ErrorCode, &'static mut Packet)> {
+            self.receiving.replace(recv);
+            Ok(())
+        }
+
+        fn receive_cancel(&'static self) -> Result<&'static mut Packet, ErrorCode> {
+            self.receiving.take().ok_or(ErrorCode::INVAL)
+        }
+    }
+
+    enum KvOperation {
+        Append(&'static mut [u8; 8], &'static mut [u8]),
+        Get(&'static mut [u8; 8], &'static mut [u8]),
+        Invalidate(&'static mut [u8; 8]),
+    }
+
+    /// A key-value store whose operations complete when the test calls
+    /// `complete()`.
+    struct FakeKv {
+        client: OptionalCell<&'static dyn kv_system::Client<[u8; 8]>>,
+        entries: RefCell<Vec<([u8; 8], Vec<u8>)>>,
+        pending: RefCell<Option<KvOperation>>,
+    }
+
+    impl FakeKv {
+        fn start(&self, operation: KvOperation) -> Result<(), KvOperation> {
+            if self.pending.borrow().is_some() {
+                return Err(operation);
+            }
+            *self.pending.borrow_mut() = Some(operation);
+            Ok(())
+        }
+
+        fn complete(&self) -> bool {
+            let operation = match self.pending.borrow_mut().take() {
+                Some(operation) => operation,
+                None => return false,
+            };
+            let client = self.client.extract().unwrap();
+            let mut entries = self.entries.borrow_mut();
+            let position = |key: &[u8; 8]| entries.iter().position(|(k, _)| k == key);
+            match operation {
+                KvOperation::Append(key, value) => {
+                    let result = match position(key) {
+                        Some(_) => Err(ErrorCode::ALREADY),
+                        None => {
+                            entries.push((*key, value.to_vec()));
+                            Ok(())
+                        }
+                    };
+                    drop(entries);
+                    client.append_key_complete(result, key, value);
+                }
+                KvOperation::Get(key, buf) => {
+                    let result = match position(key) {
+                        Some(i) => {
+                            let value = &entries[i].1;
+                            buf[..value.len()].copy_from_slice(value);
+                            Ok(())
+                        }
+                        None => Err(ErrorCode::INVAL),
+                    };
+                    drop(entries);
+                    client.get_value_complete(result, key, buf);
+                }
+                KvOperation::Invalidate(key) => {
+                    let result = match position(key) {
+                        Some(i) => {
+                            entries.remove(i);
+                            Ok(())
+                        }
+                        None => Err(ErrorCode::INVAL),
+                    };
+                    drop(entries);
+                    client.invalidate_key_complete(result, key);
+                }
+            }
+            true
+        }
+    }
+
+    impl KVSystem<'static> for FakeKv {
+        type K = [u8; 8];
+
+        fn set_client(&self, client: &'static dyn kv_system::Client<[u8; 8]>) {
+            self.client.set(client);
+        }
+
+        fn generate_key(
+            &self,
+            unhashed_key: &'static mut [u8],
+            key_buf: &'static mut [u8; 8],
+        ) -> Result<
+            (),
+            (
+                &'static mut [u8],
+                &'static mut [u8; 8],
+                Result<(), ErrorCode>,
+            ),
+        > {
+            Err((unhashed_key, key_buf, Err(ErrorCode::NOSUPPORT)))
+        }
+
+        fn append_key(
+            &self,
+            key: &'static mut [u8; 8],
+            value: &'static mut [u8],
+        ) -> Result<
+            (),
+            (
+                &'static mut [u8; 8],
+                &'static mut [u8],
+                Result<(), ErrorCode>,
+            ),
+        > {
+            match self.start(KvOperation::Append(key, value)) {
+                Err(KvOperation::Append(key, value)) => Err((key, value, Err(ErrorCode::BUSY))),
+                _ => Ok(()),
+            }
+        }
+
+        fn get_value(
+            &self,
+            key: &'static mut [u8; 8],
+            ret_buf: &'static mut [u8],
+        ) -> Result<
+            (),
+            (
+                &'static mut [u8; 8],
+                &'static mut [u8],
+                Result<(), ErrorCode>,
+            ),
+        > {
+            match self.start(KvOperation::Get(key, ret_buf)) {
+                Err(KvOperation::Get(key, buf)) => Err((key, buf, Err(ErrorCode::BUSY))),
+                _ => Ok(()),
+            }
+        }
+
+        fn invalidate_key(
+            &self,
+            key: &'static mut [u8; 8],
+        ) -> Result<(), (&'static mut [u8; 8], Result<(), ErrorCode>)> {
+            match self.start(KvOperation::Invalidate(key)) {
+                Err(KvOperation::Invalidate(key)) => Err((key, Err(ErrorCode::BUSY))),
+                _ => Ok(()),
+            }
+        }
+
+        fn garbage_collect(&self) -> Result<usize, Result<(), ErrorCode>> {
+            Ok(0)
+        }
+    }
+
+    /// An active-high button.
+    struct FakeButton {
+        client: OptionalCell<&'static dyn gpio::Client>,
+        pressed: Cell<bool>,
+        interrupts: Cell<bool>,
+    }
+
+    impl FakeButton {
+        fn press(&self) {
+            self.pressed.set(true);
+            if self.interrupts.get() {
+                self.client.map(|client| client.fired());
+            }
+            self.pressed.set(false);
+        }
+    }
+
+    impl gpio::Input for FakeButton {
+        fn read(&self) -> bool {
+            self.pressed.get()
+        }
+    }
+
+    impl gpio::Output for FakeButton {
+        fn set(&self) {}
+        fn clear(&self) {}
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl gpio::Configure for FakeButton {
+        fn configuration(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+        fn make_output(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+        fn disable_output(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+        fn make_input(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+        fn disable_input(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+        fn deactivate_to_low_power(&self) {}
+        fn set_floating_state(&self, _state: gpio::FloatingState) {}
+        fn floating_state(&self) -> gpio::FloatingState {
+            gpio::FloatingState::PullNone
+        }
+    }
+
+    impl gpio::Interrupt<'static> for FakeButton {
+        fn set_client(&self, client: &'static dyn gpio::Client) {
+            self.client.set(client);
+        }
+        fn enable_interrupts(&self, _mode: gpio::InterruptEdge) {
+            self.interrupts.set(true);
+        }
+        fn disable_interrupts(&self) {
+            self.interrupts.set(false);
+        }
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    /// An alarm that only fires when the test calls `fire()`, on a clock
+    /// that only moves when the test calls `advance()`.
+    struct FakeAlarm {
+        client: OptionalCell<&'static dyn time::AlarmClient>,
+        armed: Cell<bool>,
+        now: Cell<u32>,
+    }
+
+    impl FakeAlarm {
+        fn advance(&self, ms: u32) {
+            self.now.set(self.now.get() + ms);
+        }
+
+        fn fire(&self) {
+            if self.armed.replace(false) {
+                self.client.map(|client| client.alarm());
+            }
+        }
+    }
+
+    impl Time for FakeAlarm {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(self.now.get())
+        }
+    }
+
+    impl Alarm<'static> for FakeAlarm {
+        fn set_alarm_client(&'static self, client: &'static dyn time::AlarmClient) {
+            self.client.set(client);
+        }
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {
+            self.armed.set(true);
+        }
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            self.armed.set(false);
+            Ok(())
+        }
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(1)
+        }
+    }
+
+    type Device = CtapHidAuthenticator<'static, FakeUsb, FakeKv, FakeButton, FakeAlarm>;
+
+    /// The host side of the test: a FIDO client talking CTAPHID.
+    struct Host {
+        usb: &'static FakeUsb,
+        kv: &'static FakeKv,
+        button: &'static FakeButton,
+        alarm: &'static FakeAlarm,
+    }
+
+    fn leak<T>(value: T) -> &'static mut T {
+        Box::leak(Box::new(value))
+    }
+
+    fn setup() -> Host {
+        setup_with(Vec::new())
+    }
+
+    /// Set up a device whose storage already holds `entries`.
+    fn setup_with(entries: Vec<([u8; 8], Vec<u8>)>) -> Host {
+        let usb = leak(FakeUsb {
+            client: OptionalCell::empty(),
+            sending: TakeCell::empty(),
+            receiving: TakeCell::empty(),
+        });
+        let kv = leak(FakeKv {
+            client: OptionalCell::empty(),
+            entries: RefCell::new(entries),
+            pending: RefCell::new(None),
+        });
+        let button = leak(FakeButton {
+            client: OptionalCell::empty(),
+            pressed: Cell::new(false),
+            interrupts: Cell::new(false),
+        });
+        let alarm = leak(FakeAlarm {
+            client: OptionalCell::empty(),
+            armed: Cell::new(false),
+            now: Cell::new(0),
+        });
+        let device: &'static Device = leak(CtapHidAuthenticator::new(
+            &*usb,
+            &*kv,
+            &*button,
+            gpio::ActivationMode::ActiveHigh,
+            gpio::FloatingState::PullNone,
+            &*alarm,
+            Authenticator::new([0x5a; 32], [0xa5; 16]),
+            leak([0; 1024]),
+            leak([0; authenticator::RECORD_LEN]),
+            leak([0; 8]),
+            leak([0; authenticator::COUNTER_LEN]),
+            leak([0; ctaphid::PACKET_SIZE]),
+            leak([0; ctaphid::PACKET_SIZE]),
+        ));
+        usb.client.set(device);
+        kv.set_client(device);
+        gpio::Interrupt::set_client(button, device);
+        alarm.set_alarm_client(device);
+        device.start().unwrap();
+        Host {
+            usb,
+            kv,
+            button,
+            alarm,
+        }
+    }
+
+    impl Host {
+        fn send_packet(&self, packet: &Packet) {
+            let buffer = self.usb.receiving.take().expect("not receiving");
+            buffer.copy_from_slice(packet);
+            self.usb
+                .client
+                .map(move |client| client.packet_received(Ok(()), buffer, 0));
+        }
+
+        fn send(&self, cid: u32, cmd: u8, data: &[u8]) {
+            let mut transmitter = Transmitter::new(cid, cmd, data.len());
+            let mut packet = [0; ctaphid::PACKET_SIZE];
+            while transmitter.next_packet(data, &mut packet) {
+                self.send_packet(&packet);
+            }
+        }
+
+        /// Take the next packet from the device, letting storage operations
+        /// finish if it has none to send.
+        fn next_packet(&self) -> Option<Packet> {
+            loop {
+                if let Some(buffer) = self.usb.sending.take() {
+                    let packet = *buffer;
+                    self.usb
+                        .client
+                        .map(move |client| client.packet_transmitted(Ok(()), buffer, 0));
+                    return Some(packet);
+                }
+                if !self.kv.complete() {
+                    return None;
+                }
+            }
+        }
+
+        /// Read a response, skipping `KEEPALIVE` packets. Returns the channel,
+        /// command and payload.
+        fn receive(&self) -> (u32, u8, Vec<u8>) {
+            let packet = loop {
+                let packet = self.next_packet().expect("no response");
+                if packet[4] != 0x80 | command::KEEPALIVE {
+                    break packet;
+                }
+            };
+            let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+            let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+            let mut data = packet[7..].to_vec();
+            while data.len() < len {
+                let packet = self.next_packet().expect("truncated response");
+                data.extend_from_slice(&packet[5..]);
+            }
+            data.truncate(len);
+            (cid, packet[4] & 0x7f, data)
+        }
+
+        /// Wait until the device asks for the user's presence.
+        fn expect_keepalive(&self, cid: u32) {
+            let packet = self.next_packet().expect("no keepalive");
+            assert_eq!(
+                u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]),
+                cid
+            );
+            assert_eq!(packet[4], 0x80 | command::KEEPALIVE);
+            assert_eq!(packet[7], keepalive::UPNEEDED);
+        }
+
+        /// Number of stored resident credentials.
+        fn credentials(&self) -> usize {
+            let counters: Vec<_> = (0..authenticator::COUNTER_SLOTS)
+                .map(authenticator::counter_key)
+                .collect();
+            self.kv
+                .entries
+                .borrow()
+                .iter()
+                .filter(|(key, _)| !counters.contains(key))
+                .count()
+        }
+
+        /// The signature counter stored in slot `slot`.
+        fn counter(&self, slot: usize) -> Option<u32> {
+            let key = authenticator::counter_key(slot);
+            self.kv
+                .entries
+                .borrow()
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+        }
+
+        /// Register a resident credential for `user`, returning its
+        /// authenticator data.
+        fn register(&self, cid: u32, user: &[u8]) -> Vec<u8> {
+            self.send(cid, command::CBOR, &make_credential(user));
+            self.expect_keepalive(cid);
+            self.button.press();
+            let (_, _, response) = self.receive();
+            assert_eq!(response[0], Status::OK.0);
+            let mut reader = Reader::new(&response[1..]);
+            reader.map().unwrap();
+            reader.skip().unwrap();
+            reader.skip().unwrap();
+            reader.int().unwrap();
+            reader.bytes().unwrap().to_vec()
+        }
+
+        fn allocate_channel(&self) -> u32 {
+            let nonce = [9, 8, 7, 6, 5, 4, 3, 2];
+            self.send(ctaphid::BROADCAST_CID, command::INIT, &nonce);
+            let (cid, cmd, data) = self.receive();
+            assert_eq!((cid, cmd), (ctaphid::BROADCAST_CID, command::INIT));
+            assert_eq!(&data[..8], &nonce);
+            u32::from_be_bytes([data[8], data[9], data[10], data[11]])
+        }
+    }
+
+    const CLIENT_DATA_HASH: [u8; 32] = [0x33; 32];
+
+    fn make_credential(user: &[u8]) -> Vec<u8> {
+        let mut request = [0; 256];
+        request[0] = 0x01;
+        let mut writer = Writer::new(&mut request[1..]);
+        writer.map(5);
+        writer.int(1);
+        writer.bytes(&CLIENT_DATA_HASH);
+        writer.int(2);
+        writer.map(1);
+        writer.text("id");
+        writer.text("tockos.org");
+        writer.int(3);
+        writer.map(1);
+        writer.text("id");
+        writer.bytes(user);
+        writer.int(4);
+        writer.array(1);
+        writer.map(2);
+        writer.text("alg");
+        writer.int(-8);
+        writer.text("type");
+        writer.text("public-key");
+        writer.int(7);
+        writer.map(1);
+        writer.text("rk");
+        writer.bool(true);
+        let len = writer.finish().unwrap();
+        request[..len + 1].to_vec()
+    }
+
+    fn get_assertion() -> Vec<u8> {
+        let mut request = [0; 64];
+        request[0] = 0x02;
+        let mut writer = Writer::new(&mut request[1..]);
+        writer.map(2);
+        writer.int(1);
+        writer.text("tockos.org");
+        writer.int(2);
+        writer.bytes(&CLIENT_DATA_HASH);
+        let len = writer.finish().unwrap();
+        request[..len + 1].to_vec()
+    }
+
+    /// Check an assertion made with the Ed25519 credential whose attested
+    /// credential data ends `registration`, returning the user ID and the
+    /// number of credentials if given.
+    fn check_assertion(response: &[u8], registration: &[u8]) -> (Vec<u8>, Option<i64>) {
+        assert_eq!(response[0], Status::OK.0);
+        let mut reader = Reader::new(&response[1..]);
+        let entries = reader.map().unwrap();
+        reader.int().unwrap();
+        reader.map().unwrap();
+        reader.text().unwrap();
+        let id = reader.bytes().unwrap();
+        assert_eq!(id, &registration[55..87]);
+        reader.skip().unwrap();
+        reader.skip().unwrap();
+        reader.int().unwrap();
+        let auth_data = reader.bytes().unwrap();
+        reader.int().unwrap();
+        let mut signature = [0; 64];
+        signature.copy_from_slice(reader.bytes().unwrap());
+        let mut signed = auth_data.to_vec();
+        signed.extend_from_slice(&CLIENT_DATA_HASH);
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(&registration[registration.len() - 32..]);
+        assert!(ed25519::verify(&public_key, &signed, &signature));
+        reader.int().unwrap();
+        reader.map().unwrap();
+        reader.text().unwrap();
+        let user = reader.bytes().unwrap().to_vec();
+        let count = if entries == 5 {
+            assert_eq!(reader.int(), Ok(5));
+            Some(reader.int().unwrap())
+        } else {
+            None
+        };
+        (user, count)
+    }
+
+    #[test]
+    fn register_and_authenticate() {
+        let host = setup();
+        let cid = host.allocate_channel();
+
+        host.send(cid, command::PING, &[0xee; 100]);
+        assert_eq!(host.receive(), (cid, command::PING, [0xee; 100].to_vec()));
+
+        host.send(cid, command::CBOR, &[0x04]);
+        let (_, cmd, info) = host.receive();
+        assert_eq!((cmd, info[0]), (command::CBOR, 0));
+        let mut reader = Reader::new(&info[1..]);
+        reader.map().unwrap();
+        reader.int().unwrap();
+        assert_eq!(reader.array(), Ok(1));
+        assert_eq!(reader.text(), Ok(&b"FIDO_2_0"[..]));
+
+        // Registering a resident credential waits for the button.
+        host.send(cid, command::CBOR, &make_credential(b"tock user"));
+        host.expect_keepalive(cid);
+        host.alarm.fire();
+        host.expect_keepalive(cid);
+        host.button.press();
+        let (_, _, response) = host.receive();
+        assert_eq!(response[0], Status::OK.0);
+        let mut reader = Reader::new(&response[1..]);
+        reader.map().unwrap();
+        reader.skip().unwrap();
+        reader.skip().unwrap();
+        reader.int().unwrap();
+        let auth_data = reader.bytes().unwrap();
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(&auth_data[auth_data.len() - 32..]);
+        assert_eq!(&auth_data[33..37], &1u32.to_be_bytes());
+        assert_eq!(host.credentials(), 1);
+
+        // Registering again replaces the resident credential.
+        let auth_data = host.register(cid, b"tock user");
+        assert_eq!(&auth_data[33..37], &2u32.to_be_bytes());
+        assert_eq!(host.credentials(), 1);
+
+        // Authenticating without an allow list finds the resident credential.
+        host.send(cid, command::CBOR, &get_assertion());
+        host.expect_keepalive(cid);
+        host.button.press();
+        let (_, _, response) = host.receive();
+        assert_eq!(response[0], Status::OK.0);
+        let mut reader = Reader::new(&response[1..]);
+        assert_eq!(reader.map(), Ok(4));
+        reader.skip().unwrap();
+        reader.skip().unwrap();
+        reader.int().unwrap();
+        let auth_data = reader.bytes().unwrap();
+        assert_eq!(&auth_data[33..37], &3u32.to_be_bytes());
+        reader.int().unwrap();
+        let mut signature = [0; 64];
+        signature.copy_from_slice(reader.bytes().unwrap());
+        let mut signed = auth_data.to_vec();
+        signed.extend_from_slice(&CLIENT_DATA_HASH);
+        assert!(ed25519::verify(&public_key, &signed, &signature));
+        reader.int().unwrap();
+        reader.map().unwrap();
+        reader.text().unwrap();
+        assert_eq!(reader.bytes(), Ok(&b"tock user"[..]));
+    }
+
+    #[test]
+    fn busy_cancel_and_timeout() {
+        let host = setup();
+        let cid = host.allocate_channel();
+        let other = host.allocate_channel();
+        assert_ne!(cid, other);
+
+        // Other channels are refused while a request waits for the user, and
+        // the request can be cancelled.
+        host.send(cid, command::CBOR, &make_credential(b"tock user"));
+        host.expect_keepalive(cid);
+        host.send(other, command::PING, &[1]);
+        assert_eq!(
+            host.receive(),
+            (other, command::ERROR, [error::CHANNEL_BUSY].to_vec())
+        );
+        host.send(cid, command::CANCEL, &[]);
+        assert_eq!(
+            host.receive(),
+            (cid, command::CBOR, [Status::KEEPALIVE_CANCEL.0].to_vec())
+        );
+        host.button.press();
+        assert!(host.next_packet().is_none());
+
+        // Without a resident credential there is nothing to sign with.
+        host.send(other, command::CBOR, &get_assertion());
+        assert_eq!(
+            host.receive(),
+            (other, command::CBOR, [Status::NO_CREDENTIALS.0].to_vec())
+        );
+
+        // Nobody presses the button.
+        host.send(cid, command::CBOR, &make_credential(b"tock user"));
+        host.expect_keepalive(cid);
+        for _ in 1..USER_PRESENCE_TIMEOUT_MS / KEEPALIVE_INTERVAL_MS {
+            host.alarm.fire();
+            host.expect_keepalive(cid);
+        }
+        host.alarm.fire();
+        assert_eq!(
+            host.receive(),
+            (cid, command::CBOR, [Status::USER_ACTION_TIMEOUT.0].to_vec())
+        );
+        assert!(host.kv.entries.borrow().is_empty());
+    }
+
+    #[test]
+    fn signature_counter_survives_reset() {
+        // Slot 0 holds the larger counter, slot 1 a stale one.
+        let host = setup_with(
+            [(0, 7u32), (1, 5u32)]
+                .iter()
+                .map(|&(slot, count)| {
+                    (
+                        authenticator::counter_key(slot),
+                        [count.to_le_bytes(), 0u32.to_le_bytes()].concat(),
+                    )
+                })
+                .collect(),
+        );
+        let cid = host.allocate_channel();
+
+        // The next value replaces the stale one, keeping the larger until it
+        // has been written.
+        let auth_data = host.register(cid, b"tock user");
+        assert_eq!(&auth_data[33..37], &8u32.to_be_bytes());
+        assert_eq!((host.counter(0), host.counter(1)), (Some(7), Some(8)));
+
+        let auth_data = host.register(cid, b"tock user");
+        assert_eq!(&auth_data[33..37], &9u32.to_be_bytes());
+        assert_eq!((host.counter(0), host.counter(1)), (Some(9), Some(8)));
+
+        // A new device on the same storage carries on from there.
+        let entries = host.kv.entries.borrow().clone();
+        let host = setup_with(entries);
+        let cid = host.allocate_channel();
+        let auth_data = host.register(cid, b"tock user");
+        assert_eq!(&auth_data[33..37], &10u32.to_be_bytes());
+        assert_eq!((host.counter(0), host.counter(1)), (Some(9), Some(10)));
+    }
+
+    #[test]
+    fn several_users_and_next_assertion() {
+        let host = setup();
+        let cid = host.allocate_channel();
+
+        // Each user of a relying party has their own credential, and
+        // registering a user again replaces theirs.
+        host.register(cid, b"alice");
+        let bob = host.register(cid, b"bob");
+        let alice = host.register(cid, b"alice");
+        assert_eq!(host.credentials(), 2);
+
+        // The newest credential is used first, and the host is told how many
+        // there are.
+        host.send(cid, command::CBOR, &get_assertion());
+        host.expect_keepalive(cid);
+        host.button.press();
+        let (_, _, response) = host.receive();
+        assert_eq!(
+            check_assertion(&response, &alice),
+            (b"alice".to_vec(), Some(2))
+        );
+
+        // The others follow without asking for the user's presence again.
+        host.send(cid, command::CBOR, &[0x08]);
+        let (_, _, response) = host.receive();
+        assert_eq!(check_assertion(&response, &bob), (b"bob".to_vec(), None));
+        host.send(cid, command::CBOR, &[0x08]);
+        assert_eq!(
+            host.receive(),
+            (cid, command::CBOR, [Status::NOT_ALLOWED.0].to_vec())
+        );
+
+        // They are forgotten after a while.
+        host.send(cid, command::CBOR, &get_assertion());
+        host.expect_keepalive(cid);
+        host.button.press();
+        host.receive();
+        host.alarm.advance(NEXT_ASSERTION_TIMEOUT_MS);
+        host.alarm.fire();
+        host.send(cid, command::CBOR, &[0x08]);
+        assert_eq!(
+            host.receive(),
+            (cid, command::CBOR, [Status::NOT_ALLOWED.0].to_vec())
+        );
+
+        // Once every slot is used, new users are refused.
+        for user in 2..MAX_RESIDENT_CREDENTIALS {
+            host.register(cid, &[b'u', user as u8]);
+        }
+        assert_eq!(host.credentials(), MAX_RESIDENT_CREDENTIALS);
+        host.send(cid, command::CBOR, &make_credential(b"mallory"));
+        assert_eq!(
+            host.receive(),
+            (cid, command::CBOR, [Status::KEY_STORE_FULL.0].to_vec())
+        );
+        host.register(cid, b"bob");
+        assert_eq!(host.credentials(), MAX_RESIDENT_CREDENTIALS);
+    }
+
+    #[test]
+    fn reset() {
+        let host = setup();
+        let cid = host.allocate_channel();
+        host.register(cid, b"tock user");
+
+        // A reset soon after starting needs the user's presence, and removes
+        // every credential.
+        host.send(cid, command::CBOR, &[0x07]);
+        host.expect_keepalive(cid);
+        host.button.press();
+        assert_eq!(
+            host.receive(),
+            (cid, command::CBOR, [Status::OK.0].to_vec())
+        );
+        assert_eq!(host.credentials(), 0);
+        host.send(cid, command::CBOR, &get_assertion());
+        assert_eq!(
+            host.receive(),
+            (cid, command::CBOR, [Status::NO_CREDENTIALS.0].to_vec())
+        );
+
+        // The device carries on from the new generation after a restart.
+        let entries = host.kv.entries.borrow().clone();
+        let generation = |entries: &Vec<([u8; 8], Vec<u8>)>| {
+            let keys: Vec<_> = (0..authenticator::COUNTER_SLOTS)
+                .map(authenticator::counter_key)
+                .collect();
+            entries
+                .iter()
+                .filter(|(key, _)| keys.contains(key))
+                .map(|(_, value)| u32::from_le_bytes([value[4], value[5], value[6], value[7]]))
+                .max()
+        };
+        assert_eq!(generation(&entries), Some(1));
+        let host = setup_with(entries);
+        let cid = host.allocate_channel();
+        let registration = host.register(cid, b"tock user");
+        host.send(cid, command::CBOR, &get_assertion());
+        host.expect_keepalive(cid);
+        host.button.press();
+        let (_, _, response) = host.receive();
+        assert_eq!(
+            check_assertion(&response, &registration),
+            (b"tock user".to_vec(), None)
+        );
+
+        // Later resets are refused.
+        host.alarm.advance(RESET_WINDOW_MS);
+        host.alarm.fire();
+        host.send(cid, command::CBOR, &[0x07]);
+        assert_eq!(
+            host.receive(),
+            (cid, command::CBOR, [Status::NOT_ALLOWED.0].to_vec())
+        );
+        assert_eq!(host.credentials(), 1);
+    }
+}
@@ -0,0 +1,14 @@
+//! A CTAP2 (FIDO2) authenticator.
+//!
+//! `capsules::usb::ctap` only moves HID reports between the host and a
+//! process. The modules here implement the authenticator itself in the
+//! kernel: `ctaphid` frames messages in HID reports, `cbor` encodes and
+//! decodes them, `authenticator` implements the CTAP2 commands, and `hid`
+//! ties them together with a USB HID device, a button for user presence and
+//! a key-value store for resident credentials. `tools/ctap2-fido2` tests them
+//! with libfido2.
+
+pub mod authenticator;
+pub mod cbor;
+pub mod ctaphid;
+pub mod hid;
@@ -0,0 +1,646 @@
+//! NIST SP 800-90A deterministic random bit generator seeded from an entropy
+//! source.
+//!
+//! `capsules::rng` hands the output of an entropy source to processes as-is.
+//! This capsule instead gives every process its own HMAC_DRBG instance
+//! (SP 800-90A section 10.1.2, with HMAC-SHA256), instantiated and reseeded
+//! from an `hil::entropy::Entropy32`. Only HMAC_DRBG is provided: it is built
+//! on the synchronous `sha256::HmacSha256State`, while a CTR_DRBG would need
+//! a synchronous block cipher, which the kernel does not have.
+//!
+//! The pieces are:
+//!
+//! - `HmacDrbg`: the DRBG mechanism itself, a plain state machine that can
+//!   also be used by other capsules.
+//! - `HealthTests`: the SP 800-90B section 4.4 continuous health tests
+//!   (repetition count and adaptive proportion) on raw entropy samples.
+//! - `EntropySeeder`: collects seed material from the entropy source,
+//!   passing every sample through the health tests.
+//! - `DrbgDriver`: the syscall driver, with the same interface as
+//!   `capsules::rng::RngDriver` so it can be registered in its place.
+//!
+//! Seeding and fallback
+//! --------------------
+//!
+//! The seed assumes the source provides at least 8 bits of min-entropy per
+//! 32 bit sample, so `SEED_WORDS` samples give 256 bits for the entropy input
+//! and 128 bits for the nonce. A process instance is instantiated the first
+//! time the process asks for random bytes, with the process identifier as
+//! personalization string. After `RESEED_SOFT` generate requests a reseed is
+//! requested in the background, and after `RESEED_INTERVAL` requests the
+//! instance refuses to generate until it has been reseeded.
+//!
+//! If the entropy source reports an error, or its samples fail the health
+//! tests `MAX_HEALTH_FAILURES` times in a row, seed material is not
+//! delivered. Instances that are already seeded keep generating until they
+//! reach `RESEED_INTERVAL`, after which requests fail with `FAIL`; output is
+//! never generated from an unseeded instance. After the health tests have
+//! failed the seeder stops using the source altogether.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let seeder = static_init!(
+//!     capsules::drbg::EntropySeeder<'static>,
+//!     capsules::drbg::EntropySeeder::new(&sam4l::trng::TRNG)
+//! );
+//! sam4l::trng::TRNG.set_client(seeder);
+//! let drbg = static_init!(
+//!     capsules::drbg::DrbgDriver<'static>,
+//!     capsules::drbg::DrbgDriver::new(
+//!         seeder,
+//!         board_kernel.create_grant(capsules::rng::DRIVER_NUM, &grant_cap)
+//!     )
+//! );
+//! seeder.set_client(drbg);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use crate::sha256::{HmacSha256State, SHA256_DIGEST_LEN};
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::hil::entropy::{self, Entropy32};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, ReadWriteProcessBuffer,
+    WriteableProcessBuffer,
+};
+
+/// Number of generate requests after which an instance must be reseeded.
+pub const RESEED_INTERVAL: u32 = 1 << 16;
+/// Number of generate requests after which a reseed is requested in the
+/// background.
+pub const RESEED_SOFT: u32 = 1 << 10;
+/// Largest number of bytes a single generate request may produce
+/// (2^19 bits).
+pub const MAX_REQUEST_LEN: usize = 1 << 16;
+
+/// Entropy samples collected for one seed.
+pub const SEED_WORDS: usize = 48;
+/// Length of the seed material passed to `SeedClient`.
+pub const SEED_LEN: usize = SEED_WORDS * 4;
+/// The part of the seed used as entropy input; the rest is the nonce.
+pub const ENTROPY_INPUT_LEN: usize = 128;
+
+/// Repetition count test cutoff, `1 + ceil(20 / H)` for H = 8 bits of
+/// min-entropy per sample and a false positive rate of 2^-20.
+const RCT_CUTOFF: u32 = 4;
+/// Adaptive proportion test window for non-binary samples.
+const APT_WINDOW: u32 = 512;
+/// Adaptive proportion test cutoff for H = 8 and a false positive rate of
+/// 2^-20.
+const APT_CUTOFF: u32 = 13;
+/// Consecutive health test failures after which the source is considered
+/// broken.
+pub const MAX_HEALTH_FAILURES: usize = 3;
+
+/// An HMAC_DRBG instance using HMAC-SHA256.
+#[derive(Clone, Copy)]
+pub struct HmacDrbg {
+    key: [u8; SHA256_DIGEST_LEN],
+    value: [u8; SHA256_DIGEST_LEN],
+    reseed_counter: u32,
+}
+
+impl HmacDrbg {
+    /// Instantiate from `entropy` input, a `nonce` and an optional
+    /// `personalization` string.
+    pub fn new(entropy: &[u8], nonce: &[u8], personalization: &[u8]) -> HmacDrbg {
+        let mut drbg = HmacDrbg {
+            key: [0; SHA256_DIGEST_LEN],
+            value: [1; SHA256_DIGEST_LEN],
+            reseed_counter: 1,
+        };
+        drbg.update(&[entropy, nonce, personalization]);
+        drbg
+    }
+
+    fn update(&mut self, data: &[&[u8]]) {
+        let provided = data.iter().any(|d| !d.is_empty());
+        for round in 0..2u8 {
+            if round == 1 && !provided {
+                break;
+            }
+            let mut hmac = HmacSha256State::new(&self.key);
+            hmac.update(&self.value);
+            hmac.update(&[round]);
+            for d in data {
+                hmac.update(d);
+            }
+            hmac.finish(&mut self.key);
+
+            let mut hmac = HmacSha256State::new(&self.key);
+            hmac.update(&self.value);
+            hmac.finish(&mut self.value);
+        }
+    }
+
+    /// Mix fresh `entropy` input and optional `additional` input into the
+    /// state and reset the reseed counter.
+    pub fn reseed(&mut self, entropy: &[u8], additional: &[u8]) {
+        self.update(&[entropy, additional]);
+        self.reseed_counter = 1;
+    }
+
+    /// Number of generate requests since the last (re)seed.
+    pub fn requests_since_reseed(&self) -> u32 {
+        self.reseed_counter - 1
+    }
+
+    /// Whether the instance has reached `RESEED_INTERVAL`.
+    pub fn reseed_required(&self) -> bool {
+        self.reseed_counter > RESEED_INTERVAL
+    }
+
+    /// Fill `out` with pseudorandom bytes.
+    ///
+    /// Returns `RESERVE` if the instance must be reseeded first, and `SIZE`
+    /// if `out` is longer than `MAX_REQUEST_LEN`.
+    pub fn generate(&mut self, out: &mut [u8], additional: &[u8]) -> Result<(), ErrorCode> {
+        let mut pos = 0;
+        self.generate_with(out.len(), additional, |block| {
+            out[pos..pos + block.len()].copy_from_slice(block);
+            pos += block.len();
+        })
+    }
+
+    /// Generate `len` bytes, passing them to `output` one block at a time.
+    pub fn generate_with<F: FnMut(&[u8])>(
+        &mut self,
+        len: usize,
+        additional: &[u8],
+        mut output: F,
+    ) -> Result<(), ErrorCode> {
+        if self.reseed_required() {
+            return Err(ErrorCode::RESERVE);
+        }
+        if len > MAX_REQUEST_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+
+        if !additional.is_empty() {
+            self.update(&[additional]);
+        }
+        let mut remaining = len;
+        while remaining > 0 {
+            let mut hmac = HmacSha256State::new(&self.key);
+            hmac.update(&self.value);
+            hmac.finish(&mut self.value);
+            let n = cmp::min(remaining, SHA256_DIGEST_LEN);
+            output(&self.value[..n]);
+            remaining -= n;
+        }
+        self.update(&[additional]);
+        self.reseed_counter += 1;
+        Ok(())
+    }
+}
+
+/// SP 800-90B continuous health tests on 32 bit entropy samples.
+#[derive(Clone, Copy, Default)]
+pub struct HealthTests {
+    last: u32,
+    repeats: u32,
+    reference: u32,
+    matches: u32,
+    window_pos: u32,
+}
+
+impl HealthTests {
+    /// Run both tests on the next `sample`, returning `FAIL` if either of
+    /// them detects a failure. The tests restart after a failure.
+    pub fn check(&mut self, sample: u32) -> Result<(), ErrorCode> {
+        if self.repeats > 0 && sample == self.last {
+            self.repeats += 1;
+        } else {
+            self.last = sample;
+            self.repeats = 1;
+        }
+
+        if self.window_pos == 0 {
+            self.reference = sample;
+            self.matches = 1;
+        } else if sample == self.reference {
+            self.matches += 1;
+        }
+        self.window_pos = (self.window_pos + 1) % APT_WINDOW;
+
+        if self.repeats >= RCT_CUTOFF || self.matches >= APT_CUTOFF {
+            *self = HealthTests::default();
+            Err(ErrorCode::FAIL)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub trait SeedClient {
+    /// Called when seed material requested with `EntropySeeder::request()`
+    /// is ready, or with `FAIL` if the entropy source could not provide it.
+    fn seed_available(&self, seed: Result<&[u8; SEED_LEN], ErrorCode>);
+}
+
+/// Collects health-tested seed material from an entropy source.
+pub struct EntropySeeder<'a> {
+    entropy: &'a dyn Entropy32<'a>,
+    client: OptionalCell<&'a dyn SeedClient>,
+    health: MapCell<HealthTests>,
+    seed: MapCell<[u8; SEED_LEN]>,
+    collected: Cell<usize>,
+    busy: Cell<bool>,
+    /// Set while the client is being called back from `entropy_available`.
+    delivering: Cell<bool>,
+    failures: Cell<usize>,
+    failed: Cell<bool>,
+}
+
+impl<'a> EntropySeeder<'a> {
+    pub fn new(entropy: &'a dyn Entropy32<'a>) -> EntropySeeder<'a> {
+        EntropySeeder {
+            entropy,
+            client: OptionalCell::empty(),
+            health: MapCell::new(HealthTests::default()),
+            seed: MapCell::new([0; SEED_LEN]),
+            collected: Cell::new(0),
+            busy: Cell::new(false),
+            delivering: Cell::new(false),
+            failures: Cell::new(0),
+            failed: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn SeedClient) {
+        self.client.set(client);
+    }
+
+    /// Whether the health tests have failed and the source is no longer
+    /// used.
+    pub fn failed(&self) -> bool {
+        self.failed.get()
+    }
+
+    /// Start collecting one seed.
+    pub fn request(&self) -> Result<(), ErrorCode> {
+        if self.failed.get() {
+            return Err(ErrorCode::FAIL);
+        }
+        if self.busy.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        // From within the callback, keep the current request to the source
+        // going rather than starting a new one.
+        if !self.delivering.get() {
+            self.entropy.get()?;
+        }
+        self.busy.set(true);
+        self.collected.set(0);
+        Ok(())
+    }
+
+    fn deliver(&self, seed: Result<&[u8; SEED_LEN], ErrorCode>) -> entropy::Continue {
+        self.busy.set(false);
+        self.delivering.set(true);
+        self.client.map(|client| client.seed_available(seed));
+        self.delivering.set(false);
+        if self.busy.get() {
+            entropy::Continue::More
+        } else {
+            entropy::Continue::Done
+        }
+    }
+}
+
+impl entropy::Client32 for EntropySeeder<'_> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> entropy::Continue {
+        if !self.busy.get() {
+            return entropy::Continue::Done;
+        }
+        if let Err(e) = error {
+            return self.deliver(Err(e));
+        }
+
+        for sample in entropy {
+            let healthy = self
+                .health
+                .map_or(Err(ErrorCode::FAIL), |h| h.check(sample));
+            if healthy.is_err() {
+                // Discard everything collected from the failing source.
+                self.collected.set(0);
+                self.failures.set(self.failures.get() + 1);
+                if self.failures.get() >= MAX_HEALTH_FAILURES {
+                    self.failed.set(true);
+                    return self.deliver(Err(ErrorCode::FAIL));
+                }
+                continue;
+            }
+
+            let word = self.collected.get();
+            self.seed.map(|seed| {
+                seed[word * 4..word * 4 + 4].copy_from_slice(&sample.to_le_bytes());
+            });
+            self.collected.set(word + 1);
+            if word + 1 == SEED_WORDS {
+                self.failures.set(0);
+                let mut seed = [0; SEED_LEN];
+                self.seed.map(|s| {
+                    seed.copy_from_slice(s);
+                    *s = [0; SEED_LEN];
+                });
+                return self.deliver(Ok(&seed));
+            }
+        }
+        entropy::Continue::More
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    buffer: ReadWriteProcessBuffer,
+    drbg: Option<HmacDrbg>,
+    /// Bytes requested by the outstanding command, if any.
+    remaining: usize,
+    /// The instance is waiting for seed material.
+    needs_seed: bool,
+}
+
+/// Syscall driver giving each process its own `HmacDrbg`.
+pub struct DrbgDriver<'a> {
+    seeder: &'a EntropySeeder<'a>,
+    apps: Grant<App, 1>,
+    /// The process the seeder is collecting seed material for.
+    seeding: OptionalCell<ProcessId>,
+}
+
+impl<'a> DrbgDriver<'a> {
+    pub fn new(seeder: &'a EntropySeeder<'a>, grant: Grant<App, 1>) -> DrbgDriver<'a> {
+        DrbgDriver {
+            seeder,
+            apps: grant,
+            seeding: OptionalCell::empty(),
+        }
+    }
+
+    /// Fill the process buffer for the outstanding request. Returns `None`
+    /// if the instance must be (re)seeded first.
+    fn serve(app: &mut App) -> Option<Result<usize, ErrorCode>> {
+        let drbg = match app.drbg.as_mut() {
+            Some(drbg) if !drbg.reseed_required() => drbg,
+            _ => return None,
+        };
+        let requested = mem::replace(&mut app.remaining, 0);
+        let res = app
+            .buffer
+            .mut_enter(|buffer| {
+                let len = cmp::min(cmp::min(requested, buffer.len()), MAX_REQUEST_LEN);
+                let mut pos = 0;
+                drbg.generate_with(len, &[], |block| {
+                    buffer[pos..pos + block.len()].copy_from_slice(block);
+                    pos += block.len();
+                })
+                .map(|()| len)
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if drbg.requests_since_reseed() >= RESEED_SOFT {
+            app.needs_seed = true;
+        }
+        Some(res)
+    }
+
+    /// Start collecting seed material for the next instance that needs it.
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            if self.seeding.is_some() {
+                return;
+            }
+            let appid = cntr.processid();
+            if cntr.enter(|app, _| app.needs_seed) {
+                match self.seeder.request() {
+                    Ok(()) => self.seeding.set(appid),
+                    Err(e) => self.seeded(appid, Err(e)),
+                }
+            }
+        }
+    }
+
+    fn seeded(&self, appid: ProcessId, seed: Result<&[u8; SEED_LEN], ErrorCode>) {
+        let _ = self.apps.enter(appid, |app, upcalls| {
+            app.needs_seed = false;
+            if let Ok(seed) = seed {
+                let (entropy, nonce) = seed.split_at(ENTROPY_INPUT_LEN);
+                match app.drbg.as_mut() {
+                    Some(drbg) => drbg.reseed(seed, &[]),
+                    None => {
+                        app.drbg = Some(HmacDrbg::new(entropy, nonce, &appid.id().to_le_bytes()))
+                    }
+                }
+            }
+            // Without fresh seed material an instance keeps going until it
+            // reaches the reseed interval.
+            if app.remaining > 0 {
+                let res = Self::serve(app).unwrap_or_else(|| {
+                    app.remaining = 0;
+                    Err(ErrorCode::FAIL)
+                });
+                upcalls
+                    .schedule_upcall(
+                        0,
+                        kernel::into_statuscode(res.map(|_| ())),
+                        res.unwrap_or(0),
+                        0,
+                    )
+                    .ok();
+            }
+        });
+    }
+}
+
+impl SeedClient for DrbgDriver<'_> {
+    fn seed_available(&self, seed: Result<&[u8; SEED_LEN], ErrorCode>) {
+        self.seeding.take().map(|appid| self.seeded(appid, seed));
+        self.check_queue();
+    }
+}
+
+impl Driver for DrbgDriver<'_> {
+    /// Setup a buffer to fill with random bytes.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The buffer to fill.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteProcessBuffer,
+    ) -> Result<ReadWriteProcessBuffer, (ReadWriteProcessBuffer, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    mem::swap(&mut app.buffer, &mut slice);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Fill the buffer with up to `data` random bytes. The upcall
+    ///   receives the status and the number of bytes written.
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        _: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app, upcalls| {
+                        if app.remaining > 0 {
+                            return Err(ErrorCode::BUSY);
+                        }
+                        app.remaining = cmp::max(data, 1);
+                        match Self::serve(app) {
+                            Some(res) => {
+                                upcalls
+                                    .schedule_upcall(
+                                        0,
+                                        kernel::into_statuscode(res.map(|_| ())),
+                                        res.unwrap_or(0),
+                                        0,
+                                    )
+                                    .ok();
+                            }
+                            None => app.needs_seed = true,
+                        }
+                        Ok(())
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                if res.is_ok() {
+                    self.check_queue();
+                }
+                CommandReturn::from(res)
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::procs::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str, out: &mut [u8]) {
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+    }
+
+    /// HMAC_DRBG SHA-256 CAVP vector without prediction resistance,
+    /// personalization or additional input (COUNT 0).
+    #[test]
+    fn cavp_no_reseed() {
+        let mut entropy = [0; 32];
+        let mut nonce = [0; 16];
+        let mut expected = [0; 128];
+        unhex(
+            "ca851911349384bffe89de1cbdc46e6831e44d34a4fb935ee285dd14b71a7488",
+            &mut entropy,
+        );
+        unhex("659ba96c601dc69fc902940805ec0ca8", &mut nonce);
+        unhex(
+            "e528e9abf2dece54d47c7e75e5fe302149f817ea9fb4bee6f4199697d04d5b89\
+             d54fbb978a15b5c443c9ec21036d2460b6f73ebad0dc2aba6e624abf07745bc1\
+             07694bb7547bb0995f70de25d6b29e2d3011bb19d27676c07162c8b5ccde0668\
+             961df86803482cb37ed6d5c0bb8d50cf1f50d476aa0458bdaba806f48be9dcb8",
+            &mut expected,
+        );
+
+        let mut drbg = HmacDrbg::new(&entropy, &nonce, &[]);
+        let mut out = [0; 128];
+        drbg.generate(&mut out, &[]).unwrap();
+        drbg.generate(&mut out, &[]).unwrap();
+        assert_eq!(&out[..], &expected[..]);
+        assert_eq!(drbg.requests_since_reseed(), 2);
+    }
+
+    #[test]
+    fn reseed_and_additional_input() {
+        let mut seed = [0; 80];
+        for (i, b) in seed.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut expected = [0; 64];
+        unhex(
+            "f6d20425191c4fcc6a5a1ba7737a4e1971a08913e4ea5edc5049d6e5b175776a\
+             469dd213fca2492e67032a9134b8d499676bb7ef1a55ea7d556db14544048b25",
+            &mut expected,
+        );
+
+        let mut drbg = HmacDrbg::new(&seed[..32], &seed[32..48], b"tock");
+        drbg.reseed(&seed[48..], b"more");
+        let mut out = [0; 64];
+        drbg.generate(&mut out, b"add").unwrap();
+        assert_eq!(out, expected);
+
+        // An instance past its reseed interval refuses to generate.
+        drbg.reseed_counter = RESEED_INTERVAL + 1;
+        assert_eq!(drbg.generate(&mut out, &[]), Err(ErrorCode::RESERVE));
+        drbg.reseed(&seed[48..], &[]);
+        assert_eq!(drbg.generate(&mut out, &[]), Ok(()));
+    }
+
+    #[test]
+    fn health_tests() {
+        let mut health = HealthTests::default();
+        // A value repeated RCT_CUTOFF times in a row.
+        assert_eq!(health.check(7), Ok(()));
+        assert_eq!(health.check(7), Ok(()));
+        assert_eq!(health.check(7), Ok(()));
+        assert_eq!(health.check(7), Err(ErrorCode::FAIL));
+
+        // The first value of a window appearing APT_CUTOFF times in it,
+        // without consecutive repeats.
+        let mut health = HealthTests::default();
+        let mut result = Ok(());
+        for i in 0..(APT_CUTOFF * 2) {
+            result = health.check(if i % 2 == 0 { 5 } else { 100 + i });
+            if result.is_err() {
+                assert_eq!(i, (APT_CUTOFF - 1) * 2);
+                break;
+            }
+        }
+        assert_eq!(result, Err(ErrorCode::FAIL));
+
+        // Distinct values pass for several windows.
+        let mut health = HealthTests::default();
+        for i in 0..(APT_WINDOW * 4) {
+            assert_eq!(health.check(i.wrapping_mul(0x9e3779b9)), Ok(()));
+        }
+    }
+}
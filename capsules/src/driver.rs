@@ -3,10 +3,25 @@
 use enum_primitive::cast::FromPrimitive;
 use enum_primitive::enum_from_primitive;
 
-enum_from_primitive! {
-#[derive(Debug, PartialEq)]
+/// Define `NUM` from its variants, with `NUM::ALL` listing them.
+macro_rules! driver_nums {
+    ($($name:ident = $value:expr,)*) => {
+        enum_from_primitive! {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum NUM {
+            $($name = $value,)*
+        }
+        }
+
+        impl NUM {
+            /// Every driver number, in order.
+            pub const ALL: &'static [NUM] = &[$(NUM::$name,)*];
+        }
+    };
+}
+
 // syscall driver numbers
-pub enum NUM {
+driver_nums! {
     // Base
     Alarm                 = 0x00000,
     Console               = 0x00001,
@@ -38,11 +53,16 @@ pub enum NUM {
     Hmac                  = 0x40003,
     CtapHid               = 0x40004,
     Sha                   = 0x40005,
+    Signature             = 0x40006,
+    Aes                   = 0x40007,
+    Keystore              = 0x40008,
+    Attestation           = 0x40009,
 
     // Storage
     AppFlash              = 0x50000,
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
+    KernelUpdate          = 0x50003,
 
     // Sensors
     Temperature           = 0x60000,
@@ -73,4 +93,3 @@ pub enum NUM {
     Touch                 = 0x90002,
     TextScreen            = 0x90003,
 }
-}
@@ -42,7 +42,6 @@
 
 use core::cell::Cell;
 use core::cmp;
-use core::convert::TryInto;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil;
 use kernel::ErrorCode;
@@ -140,59 +139,74 @@ impl<'a, S: hil::spi::SpiMasterDevice> FM25CL<'a, S> {
         address: u16,
         buffer: &'static mut [u8],
         len: u16,
-    ) -> Result<(), ErrorCode> {
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         self.configure_spi();
 
-        self.txbuffer
-            .take()
-            .map_or(Err(ErrorCode::RESERVE), move |txbuffer| {
-                txbuffer[0] = Opcodes::WriteEnable as u8;
+        let txbuffer = match self.txbuffer.take() {
+            Some(txbuffer) => txbuffer,
+            None => return Err((ErrorCode::RESERVE, buffer)),
+        };
+        txbuffer[0] = Opcodes::WriteEnable as u8;
 
-                let write_len = cmp::min(txbuffer.len(), len as usize);
+        let write_len = cmp::min(txbuffer.len(), len as usize);
 
-                // Need to save the buffer passed to us so we can give it back.
-                self.client_buffer.replace(buffer);
-                // Also save address and len for the actual write.
-                self.client_write_address.set(address);
-                self.client_write_len.set(write_len as u16);
-
-                self.state.set(State::WriteEnable);
-                let res = self.spi.read_write_bytes(txbuffer, None, 1);
-                match res {
-                    Ok(()) => Ok(()),
-                    rc => Err(rc.try_into().unwrap()),
-                }
-            })
+        // Save address and len for the actual write.
+        self.client_write_address.set(address);
+        self.client_write_len.set(write_len as u16);
+
+        self.state.set(State::WriteEnable);
+        self.start(buffer, txbuffer, None, 1)
     }
 
-    pub fn read(&self, address: u16, buffer: &'static mut [u8], len: u16) -> Result<(), ErrorCode> {
+    pub fn read(
+        &self,
+        address: u16,
+        buffer: &'static mut [u8],
+        len: u16,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         self.configure_spi();
 
-        self.txbuffer
-            .take()
-            .map_or(Err(ErrorCode::RESERVE), |txbuffer| {
-                self.rxbuffer
-                    .take()
-                    .map_or(Err(ErrorCode::RESERVE), move |rxbuffer| {
-                        txbuffer[0] = Opcodes::ReadMemory as u8;
-                        txbuffer[1] = ((address >> 8) & 0xFF) as u8;
-                        txbuffer[2] = (address & 0xFF) as u8;
-
-                        // Save the user buffer for later
-                        self.client_buffer.replace(buffer);
-
-                        let read_len = cmp::min(rxbuffer.len() - 3, len as usize);
-
-                        self.state.set(State::ReadMemory);
-                        let res = self
-                            .spi
-                            .read_write_bytes(txbuffer, Some(rxbuffer), read_len + 3);
-                        match res {
-                            Ok(()) => Ok(()),
-                            rc => Err(rc.try_into().unwrap()),
-                        }
-                    })
-            })
+        let txbuffer = match self.txbuffer.take() {
+            Some(txbuffer) => txbuffer,
+            None => return Err((ErrorCode::RESERVE, buffer)),
+        };
+        let rxbuffer = match self.rxbuffer.take() {
+            Some(rxbuffer) => rxbuffer,
+            None => {
+                self.txbuffer.replace(txbuffer);
+                return Err((ErrorCode::RESERVE, buffer));
+            }
+        };
+        txbuffer[0] = Opcodes::ReadMemory as u8;
+        txbuffer[1] = ((address >> 8) & 0xFF) as u8;
+        txbuffer[2] = (address & 0xFF) as u8;
+
+        let read_len = cmp::min(rxbuffer.len() - 3, len as usize);
+
+        self.state.set(State::ReadMemory);
+        self.start(buffer, txbuffer, Some(rxbuffer), read_len + 3)
+    }
+
+    /// Start the first SPI transfer of a client read or write. The client
+    /// buffer is saved so it can be given back when the operation finishes,
+    /// or handed back right away if the transfer does not start.
+    fn start(
+        &self,
+        buffer: &'static mut [u8],
+        txbuffer: &'static mut [u8],
+        rxbuffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        match self.spi.read_write_bytes(txbuffer, rxbuffer, len) {
+            Ok(()) => {
+                self.client_buffer.replace(buffer);
+                Ok(())
+            }
+            Err(e) => {
+                self.state.set(State::Idle);
+                Err((e, buffer))
+            }
+        }
     }
 }
 
@@ -319,7 +333,7 @@ impl<S: hil::spi::SpiMasterDevice> hil::nonvolatile_storage::NonvolatileStorage<
         buffer: &'static mut [u8],
         address: usize,
         length: usize,
-    ) -> Result<(), ErrorCode> {
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         self.read(address as u16, buffer, length as u16)
     }
 
@@ -328,7 +342,7 @@ impl<S: hil::spi::SpiMasterDevice> hil::nonvolatile_storage::NonvolatileStorage<
         buffer: &'static mut [u8],
         address: usize,
         length: usize,
-    ) -> Result<(), ErrorCode> {
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         self.write(address as u16, buffer, length as u16)
     }
 }
@@ -0,0 +1,1498 @@
+//! GDB remote serial protocol stub for debugging a single process.
+//!
+//! The stub speaks the GDB remote protocol over a UART (or anything else that
+//! implements the UART HIL, such as USB CDC) and attaches to one process. It
+//! stops only that process: the kernel and the other processes keep running.
+//! Through the `Process` trait it reads and writes the process's registers
+//! and memory, and it sets software breakpoints by writing breakpoint
+//! instructions into the process's flash.
+//!
+//! Supported are reading and writing registers and memory, breakpoints,
+//! continuing, single-stepping, interrupting with Ctrl-C, detaching and
+//! killing, which restarts the process. Single steps are done by placing
+//! temporary breakpoints on every instruction the current one can continue
+//! at, as the processors cannot step one process while the kernel runs.
+//!
+//! A process that hits a breakpoint faults into the kernel, so the board must
+//! pass faults to the stub from `Platform::process_fault_hook()`. Faults of
+//! the attached process are reported to gdb instead of being handled by the
+//! fault policy. Breakpoint instructions only fault when no hardware debugger
+//! has halting debug enabled; otherwise the hardware debugger stops the
+//! whole chip.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{capabilities, hil, static_init};
+//! # use capsules::gdb_stub::GdbStub;
+//!
+//! pub struct Capability;
+//! unsafe impl capabilities::ProcessManagementCapability for Capability {}
+//!
+//! let gdb_stub = static_init!(
+//!     GdbStub<'static, Capability>,
+//!     GdbStub::new(
+//!         uart_device,
+//!         nv_to_page,
+//!         &mut capsules::gdb_stub::RX_BUF,
+//!         &mut capsules::gdb_stub::PACKET_BUF,
+//!         &mut capsules::gdb_stub::OUT_BUF,
+//!         &mut capsules::gdb_stub::TX_BUF,
+//!         &mut capsules::gdb_stub::FLASH_BUF,
+//!         board_kernel,
+//!         Capability,
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(uart_device, gdb_stub);
+//! hil::uart::Receive::set_receive_client(uart_device, gdb_stub);
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, gdb_stub);
+//! gdb_stub.start();
+//!
+//! impl Platform for MyPlatform {
+//!     fn process_fault_hook(&self, process: &dyn Process) -> Result<(), ()> {
+//!         self.gdb_stub.process_fault_hook(process)
+//!     }
+//! }
+//! ```
+//!
+//! The nonvolatile storage must cover the flash the processes are stored in,
+//! addressed by the physical address of the flash.
+//!
+//! Debugging
+//! ---------
+//!
+//! With `target remote`, the stub attaches to the first process. With
+//! `target extended-remote`, `attach <n>` attaches to the process with the
+//! PID `n - 1` in the process console, as gdb does not take 0 as a process
+//! number:
+//!
+//! ```text
+//! (gdb) target extended-remote /dev/ttyACM1
+//! (gdb) attach 2
+//! (gdb) monitor info
+//! ```
+//!
+//! Position-independent apps are not running at the addresses in their ELF
+//! file. `monitor info` prints where the process is, which is the address to
+//! give `add-symbol-file` for the app's code.
+
+use core::cell::Cell;
+use core::cmp;
+use core::fmt::{self, Write};
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::uart;
+use kernel::procs::{Process, State};
+use kernel::syscall::CoreRegisters;
+use kernel::{ErrorCode, Kernel, ProcessId};
+
+/// Longest packet gdb may send.
+pub const PACKET_LEN: usize = 512;
+
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+pub static mut PACKET_BUF: [u8; PACKET_LEN] = [0; PACKET_LEN];
+pub static mut OUT_BUF: [u8; 2 * PACKET_LEN] = [0; 2 * PACKET_LEN];
+pub static mut TX_BUF: [u8; 2 * PACKET_LEN] = [0; 2 * PACKET_LEN];
+pub static mut FLASH_BUF: [u8; 4] = [0; 4];
+
+/// Breakpoints set by gdb, plus the two a single step may need.
+const MAX_BREAKPOINTS: usize = 10;
+
+const EM_ARM: u16 = 40;
+const EM_RISCV: u16 = 243;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const TARGET_XML_ARM: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target>
+<architecture>arm</architecture>
+<feature name="org.gnu.gdb.arm.m-profile">
+<reg name="r0" bitsize="32"/>
+<reg name="r1" bitsize="32"/>
+<reg name="r2" bitsize="32"/>
+<reg name="r3" bitsize="32"/>
+<reg name="r4" bitsize="32"/>
+<reg name="r5" bitsize="32"/>
+<reg name="r6" bitsize="32"/>
+<reg name="r7" bitsize="32"/>
+<reg name="r8" bitsize="32"/>
+<reg name="r9" bitsize="32"/>
+<reg name="r10" bitsize="32"/>
+<reg name="r11" bitsize="32"/>
+<reg name="r12" bitsize="32"/>
+<reg name="sp" bitsize="32" type="data_ptr"/>
+<reg name="lr" bitsize="32"/>
+<reg name="pc" bitsize="32" type="code_ptr"/>
+<reg name="xpsr" bitsize="32"/>
+</feature>
+</target>
+"#;
+
+const TARGET_XML_RISCV: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target>
+<architecture>riscv:rv32</architecture>
+<feature name="org.gnu.gdb.riscv.cpu">
+<reg name="zero" bitsize="32" type="int"/>
+<reg name="ra" bitsize="32" type="code_ptr"/>
+<reg name="sp" bitsize="32" type="data_ptr"/>
+<reg name="gp" bitsize="32" type="data_ptr"/>
+<reg name="tp" bitsize="32" type="data_ptr"/>
+<reg name="t0" bitsize="32" type="int"/>
+<reg name="t1" bitsize="32" type="int"/>
+<reg name="t2" bitsize="32" type="int"/>
+<reg name="fp" bitsize="32" type="data_ptr"/>
+<reg name="s1" bitsize="32" type="int"/>
+<reg name="a0" bitsize="32" type="int"/>
+<reg name="a1" bitsize="32" type="int"/>
+<reg name="a2" bitsize="32" type="int"/>
+<reg name="a3" bitsize="32" type="int"/>
+<reg name="a4" bitsize="32" type="int"/>
+<reg name="a5" bitsize="32" type="int"/>
+<reg name="a6" bitsize="32" type="int"/>
+<reg name="a7" bitsize="32" type="int"/>
+<reg name="s2" bitsize="32" type="int"/>
+<reg name="s3" bitsize="32" type="int"/>
+<reg name="s4" bitsize="32" type="int"/>
+<reg name="s5" bitsize="32" type="int"/>
+<reg name="s6" bitsize="32" type="int"/>
+<reg name="s7" bitsize="32" type="int"/>
+<reg name="s8" bitsize="32" type="int"/>
+<reg name="s9" bitsize="32" type="int"/>
+<reg name="s10" bitsize="32" type="int"/>
+<reg name="s11" bitsize="32" type="int"/>
+<reg name="t3" bitsize="32" type="int"/>
+<reg name="t4" bitsize="32" type="int"/>
+<reg name="t5" bitsize="32" type="int"/>
+<reg name="t6" bitsize="32" type="int"/>
+<reg name="pc" bitsize="32" type="code_ptr"/>
+</feature>
+</target>
+"#;
+
+#[derive(Copy, Clone, PartialEq)]
+enum RxState {
+    /// Waiting for the `$` starting a packet.
+    Idle,
+    /// Reading the packet data.
+    Data,
+    /// Reading the first checksum digit.
+    Checksum,
+    /// Reading the second checksum digit, after the first.
+    Checksum2(u8),
+}
+
+/// What to do once the breakpoints in flash match the breakpoint table.
+#[derive(Copy, Clone, PartialEq)]
+enum AfterSync {
+    Nothing,
+    ReplyOk,
+    /// Resume the process for a single step.
+    Step,
+    /// Report that the process stopped with a signal.
+    Report(u8),
+    Detach,
+    Kill {
+        reply: bool,
+    },
+}
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    address: usize,
+    /// Length of the breakpoint instruction, 2 or 4 bytes.
+    len: usize,
+    /// The flash contents the breakpoint instruction replaces.
+    original: [u8; 4],
+    /// Placed for a single step rather than by gdb.
+    temporary: bool,
+    /// Whether the breakpoint should be in flash.
+    wanted: bool,
+    /// Whether the breakpoint is in flash.
+    inserted: bool,
+}
+
+/// Builds a packet: `$<data>#<checksum>`.
+struct PacketWriter<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+    checksum: u8,
+    overflow: bool,
+}
+
+impl<'b> PacketWriter<'b> {
+    fn new(buffer: &'b mut [u8]) -> PacketWriter<'b> {
+        let mut writer = PacketWriter {
+            buffer,
+            len: 0,
+            checksum: 0,
+            overflow: false,
+        };
+        writer.raw(b'$');
+        writer
+    }
+
+    fn raw(&mut self, byte: u8) {
+        match self.buffer.get_mut(self.len) {
+            Some(slot) => {
+                *slot = byte;
+                self.len += 1;
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.checksum = self.checksum.wrapping_add(byte);
+            self.raw(byte);
+        }
+    }
+
+    fn hex(&mut self, data: &[u8]) {
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+        for byte in data {
+            self.bytes(&[HEX[(byte >> 4) as usize], HEX[(byte & 0xF) as usize]]);
+        }
+    }
+
+    /// Finish the packet and return its length, or 0 if it did not fit.
+    fn finish(mut self) -> usize {
+        let checksum = self.checksum;
+        self.raw(b'#');
+        self.hex(&[checksum]);
+        if self.overflow {
+            0
+        } else {
+            self.len
+        }
+    }
+}
+
+impl Write for PacketWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(field: &[u8]) -> Option<usize> {
+    if field.is_empty() || field.len() > 8 {
+        return None;
+    }
+    field.iter().try_fold(0, |value, &byte| {
+        hex_digit(byte).map(|digit| value << 4 | digit as usize)
+    })
+}
+
+/// Decode hex pairs from `hex` into `buffer`. Returns false unless exactly
+/// fills `buffer`.
+fn decode_hex(hex: &[u8], buffer: &mut [u8]) -> bool {
+    if hex.len() != 2 * buffer.len() {
+        return false;
+    }
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        match (hex_digit(hex[2 * i]), hex_digit(hex[2 * i + 1])) {
+            (Some(high), Some(low)) => *byte = high << 4 | low,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Split `data` at the first `separator`.
+fn split(data: &[u8], separator: u8) -> (&[u8], &[u8]) {
+    match data.iter().position(|&byte| byte == separator) {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => (data, &[]),
+    }
+}
+
+/// Number of registers gdb knows, in the target descriptions above.
+fn gdb_register_count(machine: u16) -> usize {
+    match machine {
+        EM_ARM => 17,
+        EM_RISCV => 33,
+        _ => 0,
+    }
+}
+
+/// gdb's register `n`, from registers in the layout of `CoreRegisters`.
+fn gdb_register(registers: &CoreRegisters, n: usize) -> Option<u32> {
+    match (registers.machine, n) {
+        (EM_ARM, 0..=16) => Some(registers.registers[n]),
+        (EM_RISCV, 0) => Some(0),
+        (EM_RISCV, 1..=31) => Some(registers.registers[n]),
+        (EM_RISCV, 32) => Some(registers.registers[0]),
+        _ => None,
+    }
+}
+
+fn set_gdb_register(registers: &mut CoreRegisters, n: usize, value: u32) -> bool {
+    match (registers.machine, n) {
+        (EM_ARM, 0..=16) => registers.registers[n] = value,
+        // x0 is always zero.
+        (EM_RISCV, 0) => {}
+        (EM_RISCV, 1..=31) => registers.registers[n] = value,
+        (EM_RISCV, 32) => registers.registers[0] = value,
+        _ => return false,
+    }
+    true
+}
+
+fn program_counter(registers: &CoreRegisters) -> Option<usize> {
+    match registers.machine {
+        EM_ARM => Some(registers.registers[15] as usize & !1),
+        EM_RISCV => Some(registers.registers[0] as usize),
+        _ => None,
+    }
+}
+
+/// The breakpoint instruction of `len` bytes for the architecture.
+fn breakpoint_instruction(machine: u16, len: usize) -> [u8; 4] {
+    match (machine, len) {
+        // bkpt #0
+        (EM_ARM, _) => [0x00, 0xbe, 0, 0],
+        // c.ebreak
+        (EM_RISCV, 2) => [0x02, 0x90, 0, 0],
+        // ebreak
+        _ => [0x73, 0x00, 0x10, 0x00],
+    }
+}
+
+/// Sign extend the low `bits` bits of `value`.
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+/// Bits `high` to `low` of `value`, inclusive.
+fn bits(value: u32, high: u32, low: u32) -> u32 {
+    (value >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+/// Reads `len` bytes of process memory as a little endian number.
+type ReadMemory<'r> = &'r dyn Fn(u32, usize) -> Option<u32>;
+
+/// The addresses execution can continue at after the Thumb instruction at
+/// the program counter. Conditional branches have two, since the flags are
+/// not evaluated.
+fn thumb_successors(r: &[u32], read: ReadMemory) -> Option<[Option<u32>; 2]> {
+    let pc = r[15] & !1;
+    // Reading the program counter gives the address of the instruction
+    // plus 4.
+    let reg = |n: u32| {
+        if n == 15 {
+            pc.wrapping_add(4)
+        } else {
+            r[n as usize]
+        }
+    };
+    let relative = |offset: u32| pc.wrapping_add(4).wrapping_add(offset);
+    let only = |target: u32| Some([Some(target & !1), None]);
+    let hw1 = read(pc, 2)?;
+
+    if hw1 >> 11 < 0b11101 {
+        // 16 bit instructions.
+        let both = |target: u32| Some([Some(pc + 2), Some(target)]);
+        if hw1 & 0xF000 == 0xD000 && bits(hw1, 11, 9) != 0b111 {
+            // B<c>
+            both(relative(sign_extend(bits(hw1, 7, 0) << 1, 9)))
+        } else if hw1 & 0xF800 == 0xE000 {
+            // B
+            only(relative(sign_extend(bits(hw1, 10, 0) << 1, 12)))
+        } else if hw1 & 0xF500 == 0xB100 {
+            // CBZ, CBNZ
+            both(relative(bits(hw1, 9, 9) << 6 | bits(hw1, 7, 3) << 1))
+        } else if hw1 & 0xFF00 == 0x4700 {
+            // BX, BLX
+            only(reg(bits(hw1, 6, 3)))
+        } else if hw1 & 0xFF00 == 0xBD00 {
+            // POP with the program counter, which is popped last.
+            let address = r[13].wrapping_add(4 * bits(hw1, 7, 0).count_ones());
+            only(read(address, 4)?)
+        } else if hw1 & 0xFD87 == 0x4487 {
+            // ADD pc, Rm and MOV pc, Rm
+            let rm = reg(bits(hw1, 6, 3));
+            if hw1 & 0x0200 == 0 {
+                only(pc.wrapping_add(4).wrapping_add(rm))
+            } else {
+                only(rm)
+            }
+        } else {
+            Some([Some(pc + 2), None])
+        }
+    } else {
+        // 32 bit instructions.
+        let hw2 = read(pc + 2, 2)?;
+        let both = |target: u32| Some([Some(pc + 4), Some(target)]);
+        let rn = reg(bits(hw1, 3, 0));
+        if hw1 & 0xF800 == 0xF000 && hw2 & 0x8000 != 0 {
+            let s = bits(hw1, 10, 10);
+            let j1 = bits(hw2, 13, 13);
+            let j2 = bits(hw2, 11, 11);
+            match hw2 & 0x5000 {
+                // BL, B.W
+                0x5000 | 0x1000 => {
+                    let i1 = !(j1 ^ s) & 1;
+                    let i2 = !(j2 ^ s) & 1;
+                    let offset = s << 24
+                        | i1 << 23
+                        | i2 << 22
+                        | bits(hw1, 9, 0) << 12
+                        | bits(hw2, 10, 0) << 1;
+                    only(relative(sign_extend(offset, 25)))
+                }
+                // B<c>.W, unless the condition marks another instruction.
+                0x0000 if bits(hw1, 9, 7) != 0b111 => {
+                    let offset = s << 20
+                        | j2 << 19
+                        | j1 << 18
+                        | bits(hw1, 5, 0) << 12
+                        | bits(hw2, 10, 0) << 1;
+                    both(relative(sign_extend(offset, 21)))
+                }
+                _ => Some([Some(pc + 4), None]),
+            }
+        } else if hw1 & 0xFFD0 == 0xE890 && hw2 & 0x8000 != 0 {
+            // LDMIA and POP.W with the program counter, loaded last.
+            only(read(rn.wrapping_add(4 * (hw2.count_ones() - 1)), 4)?)
+        } else if hw1 & 0xFFD0 == 0xE910 && hw2 & 0x8000 != 0 {
+            // LDMDB with the program counter.
+            only(read(rn.wrapping_sub(4), 4)?)
+        } else if hw1 & 0xFFF0 == 0xF8D0 && bits(hw2, 15, 12) == 15 {
+            // LDR.W pc, [Rn, #imm12], aligning the program counter for
+            // literals.
+            only(read((rn & !3).wrapping_add(bits(hw2, 11, 0)), 4)?)
+        } else if hw1 & 0xFFF0 == 0xF850 && hw2 & 0xF800 == 0xF800 {
+            // LDR pc, [Rn, #+/-imm8] with pre or post indexing.
+            let imm8 = bits(hw2, 7, 0);
+            let address = match (hw2 & 0x0400 != 0, hw2 & 0x0200 != 0) {
+                (false, _) => rn,
+                (true, true) => rn.wrapping_add(imm8),
+                (true, false) => rn.wrapping_sub(imm8),
+            };
+            only(read(address, 4)?)
+        } else if hw1 & 0xFFF0 == 0xE8D0 && hw2 & 0xFFE0 == 0xF000 {
+            // TBB, TBH
+            let rm = reg(bits(hw2, 3, 0));
+            let offset = if hw2 & 0x0010 == 0 {
+                read(rn.wrapping_add(rm), 1)?
+            } else {
+                read(rn.wrapping_add(rm << 1), 2)?
+            };
+            only(relative(offset << 1))
+        } else {
+            Some([Some(pc + 4), None])
+        }
+    }
+}
+
+/// The addresses execution can continue at after the RISC-V instruction at
+/// the program counter. `r` has the program counter first, then x1-x31.
+fn riscv_successors(r: &[u32], read: ReadMemory) -> Option<[Option<u32>; 2]> {
+    let pc = r[0];
+    let x = |n: u32| if n == 0 { 0 } else { r[n as usize] };
+    let only = |target: u32| Some([Some(target), None]);
+    let low = read(pc, 2)?;
+
+    if low & 3 != 3 {
+        // Compressed instructions.
+        let both = |target: u32| Some([Some(pc + 2), Some(target)]);
+        match (low & 3, bits(low, 15, 13)) {
+            // C.JAL, C.J
+            (1, 0b001) | (1, 0b101) => {
+                let offset = bits(low, 12, 12) << 11
+                    | bits(low, 11, 11) << 4
+                    | bits(low, 10, 9) << 8
+                    | bits(low, 8, 8) << 10
+                    | bits(low, 7, 7) << 6
+                    | bits(low, 6, 6) << 7
+                    | bits(low, 5, 3) << 1
+                    | bits(low, 2, 2) << 5;
+                only(pc.wrapping_add(sign_extend(offset, 12)))
+            }
+            // C.BEQZ, C.BNEZ
+            (1, 0b110) | (1, 0b111) => {
+                let offset = bits(low, 12, 12) << 8
+                    | bits(low, 11, 10) << 3
+                    | bits(low, 6, 5) << 6
+                    | bits(low, 4, 3) << 1
+                    | bits(low, 2, 2) << 5;
+                both(pc.wrapping_add(sign_extend(offset, 9)))
+            }
+            // C.JR, C.JALR
+            (2, 0b100) if bits(low, 6, 2) == 0 && bits(low, 11, 7) != 0 => {
+                only(x(bits(low, 11, 7)) & !1)
+            }
+            _ => Some([Some(pc + 2), None]),
+        }
+    } else {
+        let inst = read(pc, 4)?;
+        match inst & 0x7F {
+            // JAL
+            0x6F => {
+                let offset = bits(inst, 31, 31) << 20
+                    | bits(inst, 19, 12) << 12
+                    | bits(inst, 20, 20) << 11
+                    | bits(inst, 30, 21) << 1;
+                only(pc.wrapping_add(sign_extend(offset, 21)))
+            }
+            // JALR
+            0x67 => only(x(bits(inst, 19, 15)).wrapping_add(sign_extend(inst >> 20, 12)) & !1),
+            // Branches
+            0x63 => {
+                let offset = bits(inst, 31, 31) << 12
+                    | bits(inst, 7, 7) << 11
+                    | bits(inst, 30, 25) << 5
+                    | bits(inst, 11, 8) << 1;
+                Some([Some(pc + 4), Some(pc.wrapping_add(sign_extend(offset, 13)))])
+            }
+            _ => Some([Some(pc + 4), None]),
+        }
+    }
+}
+
+pub struct GdbStub<'a, C: ProcessManagementCapability> {
+    uart: &'a dyn uart::UartData<'a>,
+    flash: &'a dyn NonvolatileStorage<'static>,
+    kernel: &'static Kernel,
+    capability: C,
+
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_state: Cell<RxState>,
+    packet: TakeCell<'static, [u8]>,
+    packet_len: Cell<usize>,
+    checksum: Cell<u8>,
+
+    /// Output waiting for the transmission in progress to finish.
+    out_buffer: TakeCell<'static, [u8]>,
+    out_len: Cell<usize>,
+    /// Holds the output being transmitted, `None` while transmitting.
+    tx_buffer: TakeCell<'static, [u8]>,
+
+    /// Holds breakpoint instructions and original flash contents, `None`
+    /// while writing them.
+    flash_buffer: TakeCell<'static, [u8]>,
+    breakpoints: [Cell<Option<Breakpoint>>; MAX_BREAKPOINTS],
+    /// Breakpoint being written to flash.
+    writing: OptionalCell<usize>,
+    after_sync: Cell<AfterSync>,
+    flash_error: Cell<bool>,
+
+    /// The process gdb is attached to.
+    process: OptionalCell<ProcessId>,
+    /// ELF machine of the process's registers.
+    machine: Cell<u16>,
+    /// Whether gdb let the process run.
+    running: Cell<bool>,
+    stepping: Cell<bool>,
+    /// Signal the process last stopped with.
+    signal: Cell<u8>,
+}
+
+impl<'a, C: ProcessManagementCapability> GdbStub<'a, C> {
+    pub fn new(
+        uart: &'a dyn uart::UartData<'a>,
+        flash: &'a dyn NonvolatileStorage<'static>,
+        rx_buffer: &'static mut [u8],
+        packet_buffer: &'static mut [u8],
+        out_buffer: &'static mut [u8],
+        tx_buffer: &'static mut [u8],
+        flash_buffer: &'static mut [u8],
+        kernel: &'static Kernel,
+        capability: C,
+    ) -> GdbStub<'a, C> {
+        GdbStub {
+            uart,
+            flash,
+            kernel,
+            capability,
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_state: Cell::new(RxState::Idle),
+            packet: TakeCell::new(packet_buffer),
+            packet_len: Cell::new(0),
+            checksum: Cell::new(0),
+            out_buffer: TakeCell::new(out_buffer),
+            out_len: Cell::new(0),
+            tx_buffer: TakeCell::new(tx_buffer),
+            flash_buffer: TakeCell::new(flash_buffer),
+            breakpoints: Default::default(),
+            writing: OptionalCell::empty(),
+            after_sync: Cell::new(AfterSync::Nothing),
+            flash_error: Cell::new(false),
+            process: OptionalCell::empty(),
+            machine: Cell::new(0),
+            running: Cell::new(false),
+            stepping: Cell::new(false),
+            signal: Cell::new(SIGTRAP),
+        }
+    }
+
+    /// Start listening for gdb.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        self.rx_buffer
+            .take()
+            .map_or(Err(ErrorCode::ALREADY), |buffer| {
+                self.uart.receive_buffer(buffer, 1).map_err(|(e, buffer)| {
+                    self.rx_buffer.replace(buffer);
+                    e
+                })
+            })
+    }
+
+    /// Handle a fault of a process. Faults of the attached process, which
+    /// include hitting breakpoints, stop it and are reported to gdb; then
+    /// `Ok(())` is returned so that the kernel leaves the process alone.
+    /// Other faults return `Err(())` for the kernel to handle.
+    pub fn process_fault_hook(&self, process: &dyn Process) -> Result<(), ()> {
+        if !self.is_attached(process) {
+            return Err(());
+        }
+        process.stop();
+        self.running.set(false);
+
+        let pc = process
+            .get_core_registers()
+            .as_ref()
+            .and_then(program_counter);
+        let at_breakpoint = self.breakpoints.iter().any(|slot| {
+            slot.get()
+                .map_or(false, |bp| bp.inserted && Some(bp.address) == pc)
+        });
+        let signal = if at_breakpoint { SIGTRAP } else { SIGSEGV };
+        self.stopped(signal);
+        Ok(())
+    }
+
+    fn is_attached(&self, process: &dyn Process) -> bool {
+        self.process
+            .map_or(false, |processid| *processid == process.processid())
+    }
+
+    /// Call `f` with the attached process, or return `default` if there is
+    /// none.
+    fn with_process<R, F: FnOnce(&dyn Process) -> R>(&self, default: R, f: F) -> R {
+        let f = Cell::new(Some(f));
+        let result = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if self.is_attached(process) {
+                    result.set(f.take().map(|f| f(process)));
+                }
+            });
+        result.into_inner().unwrap_or(default)
+    }
+
+    fn attach(&self, process: &dyn Process) -> bool {
+        let machine = match process.get_core_registers() {
+            Some(registers) if gdb_register_count(registers.machine) > 0 => registers.machine,
+            _ => return false,
+        };
+        self.process.set(process.processid());
+        self.machine.set(machine);
+        process.stop();
+        self.running.set(false);
+        self.signal.set(SIGTRAP);
+        true
+    }
+
+    /// The process stopped: remove any single step breakpoints, then report
+    /// it to gdb.
+    fn stopped(&self, signal: u8) {
+        self.signal.set(signal);
+        if self.stepping.get() {
+            self.stepping.set(false);
+            for slot in self.breakpoints.iter() {
+                if let Some(mut bp) = slot.get() {
+                    if bp.temporary {
+                        bp.wanted = false;
+                        slot.set(Some(bp));
+                    }
+                }
+            }
+            self.sync_then(AfterSync::Report(signal));
+        } else {
+            self.report_stop(signal);
+        }
+    }
+
+    // Output
+
+    fn flush(&self) {
+        if self.out_len.get() == 0 {
+            return;
+        }
+        self.tx_buffer.take().map(|tx_buffer| {
+            let len = self.out_buffer.map_or(0, |out_buffer| {
+                let len = cmp::min(self.out_len.get(), tx_buffer.len());
+                tx_buffer[..len].copy_from_slice(&out_buffer[..len]);
+                out_buffer.copy_within(len..self.out_len.get(), 0);
+                len
+            });
+            self.out_len.set(self.out_len.get() - len);
+            if let Err((_, tx_buffer)) = self.uart.transmit_buffer(tx_buffer, len) {
+                self.tx_buffer.replace(tx_buffer);
+            }
+        });
+    }
+
+    fn send_raw(&self, byte: u8) {
+        self.out_buffer.map(|out_buffer| {
+            if let Some(slot) = out_buffer.get_mut(self.out_len.get()) {
+                *slot = byte;
+                self.out_len.set(self.out_len.get() + 1);
+            }
+        });
+        self.flush();
+    }
+
+    /// Send a packet built by `build`.
+    fn reply<F: FnOnce(&mut PacketWriter)>(&self, build: F) {
+        self.out_buffer.map(|out_buffer| {
+            let start = self.out_len.get();
+            let mut writer = PacketWriter::new(&mut out_buffer[start..]);
+            build(&mut writer);
+            self.out_len.set(start + writer.finish());
+        });
+        self.flush();
+    }
+
+    fn reply_str(&self, reply: &str) {
+        self.reply(|packet| packet.bytes(reply.as_bytes()));
+    }
+
+    fn reply_error(&self) {
+        self.reply_str("E01");
+    }
+
+    fn report_stop(&self, signal: u8) {
+        self.reply(|packet| {
+            packet.bytes(b"S");
+            packet.hex(&[signal]);
+        });
+    }
+
+    // Breakpoints
+
+    /// Update the breakpoints in flash to match the table, then do `after`.
+    fn sync_then(&self, after: AfterSync) {
+        self.after_sync.set(after);
+        self.flash_error.set(false);
+        self.sync_flash();
+    }
+
+    fn sync_flash(&self) {
+        if self.writing.is_some() {
+            return;
+        }
+        let next = self.breakpoints.iter().enumerate().find_map(|(i, slot)| {
+            slot.get()
+                .filter(|bp| bp.wanted != bp.inserted)
+                .map(|bp| (i, bp))
+        });
+        let (index, bp) = match next {
+            Some(next) => next,
+            None => return self.synced(),
+        };
+        let bytes = if bp.wanted {
+            breakpoint_instruction(self.machine.get(), bp.len)
+        } else {
+            bp.original
+        };
+        let result = self
+            .flash_buffer
+            .take()
+            .map_or(Err(ErrorCode::BUSY), |buffer| {
+                buffer[..bp.len].copy_from_slice(&bytes[..bp.len]);
+                self.flash
+                    .write(buffer, bp.address, bp.len)
+                    .map_err(|(e, buffer)| {
+                        self.flash_buffer.replace(buffer);
+                        e
+                    })
+            });
+        match result {
+            Ok(()) => self.writing.set(index),
+            Err(_) => {
+                // Give up on the breakpoint: forget it if it is not in flash,
+                // keep it if it cannot be removed.
+                self.flash_error.set(true);
+                if bp.inserted {
+                    self.breakpoints[index].set(Some(Breakpoint { wanted: true, ..bp }));
+                } else {
+                    self.breakpoints[index].set(None);
+                }
+                self.sync_flash();
+            }
+        }
+    }
+
+    fn synced(&self) {
+        let after = self.after_sync.replace(AfterSync::Nothing);
+        let error = self.flash_error.get();
+        match after {
+            AfterSync::Nothing => {}
+            AfterSync::ReplyOk if error => self.reply_error(),
+            AfterSync::ReplyOk => self.reply_str("OK"),
+            AfterSync::Step if error => {
+                self.stepping.set(false);
+                self.reply_error();
+            }
+            AfterSync::Step => {
+                self.running.set(true);
+                self.with_process((), |process| process.resume());
+            }
+            AfterSync::Report(signal) => self.report_stop(signal),
+            AfterSync::Detach => {
+                self.with_process((), |process| process.resume());
+                self.process.clear();
+                self.reply_str("OK");
+            }
+            AfterSync::Kill { reply } => {
+                self.with_process((), |process| process.try_restart(0));
+                self.process.clear();
+                if reply {
+                    self.reply_str("OK");
+                }
+            }
+        }
+    }
+
+    /// Read process memory, showing the original contents in place of the
+    /// breakpoints in flash.
+    fn read_memory(
+        &self,
+        process: &dyn Process,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), ErrorCode> {
+        process.read_memory(address, buffer)?;
+        for bp in self.breakpoints.iter().filter_map(|slot| slot.get()) {
+            if !bp.inserted {
+                continue;
+            }
+            for i in 0..bp.len {
+                if let Some(offset) = (bp.address + i).checked_sub(address) {
+                    if let Some(byte) = buffer.get_mut(offset) {
+                        *byte = bp.original[i];
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a breakpoint at `address` to the table. Returns false if it is
+    /// not in the process's flash or the table is full.
+    fn add_breakpoint(
+        &self,
+        process: &dyn Process,
+        address: usize,
+        len: usize,
+        temporary: bool,
+    ) -> bool {
+        if let Some(slot) = self
+            .breakpoints
+            .iter()
+            .find(|slot| slot.get().map_or(false, |bp| bp.address == address))
+        {
+            slot.get().map(|bp| {
+                slot.set(Some(Breakpoint {
+                    wanted: true,
+                    temporary: temporary && bp.temporary,
+                    ..bp
+                }))
+            });
+            return true;
+        }
+        if address < process.flash_start() as usize || address + len > process.flash_end() as usize
+        {
+            return false;
+        }
+        let mut original = [0; 4];
+        if process.read_memory(address, &mut original[..len]).is_err() {
+            return false;
+        }
+        match self.breakpoints.iter().find(|slot| slot.get().is_none()) {
+            Some(slot) => {
+                slot.set(Some(Breakpoint {
+                    address,
+                    len,
+                    original,
+                    temporary,
+                    wanted: true,
+                    inserted: false,
+                }));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Place temporary breakpoints wherever the current instruction can
+    /// continue, and let the process run into one of them.
+    fn step(&self, process: &dyn Process) {
+        let registers = match process.get_core_registers() {
+            Some(registers) => registers,
+            None => return self.reply_error(),
+        };
+        let read = |address: u32, len: usize| {
+            let mut bytes = [0; 4];
+            self.read_memory(process, address as usize, &mut bytes[..len])
+                .ok()
+                .map(|()| u32::from_le_bytes(bytes))
+        };
+        let successors = match registers.machine {
+            EM_ARM => thumb_successors(&registers.registers, &read),
+            EM_RISCV => riscv_successors(&registers.registers, &read),
+            _ => None,
+        };
+        let mut added = false;
+        for address in successors.iter().flatten().flatten() {
+            let len = match registers.machine {
+                // Compressed instructions have their low bits not both set.
+                EM_RISCV if read(*address, 2).map_or(false, |low| low & 3 != 3) => 2,
+                EM_RISCV => 4,
+                _ => 2,
+            };
+            added |= self.add_breakpoint(process, *address as usize, len, true);
+        }
+        if !added {
+            return self.reply_error();
+        }
+        self.stepping.set(true);
+        self.sync_then(AfterSync::Step);
+    }
+
+    // Packets
+
+    fn received_byte(&self, byte: u8) {
+        match self.rx_state.get() {
+            RxState::Idle => match byte {
+                b'$' => {
+                    self.packet_len.set(0);
+                    self.checksum.set(0);
+                    self.rx_state.set(RxState::Data);
+                }
+                // Ctrl-C
+                0x03 => self.interrupt(),
+                // Acknowledgements of our packets, which are not resent.
+                _ => {}
+            },
+            RxState::Data => {
+                if byte == b'#' {
+                    self.rx_state.set(RxState::Checksum);
+                } else {
+                    self.checksum.set(self.checksum.get().wrapping_add(byte));
+                    let len = self.packet_len.get();
+                    self.packet.map(|packet| {
+                        if let Some(slot) = packet.get_mut(len) {
+                            *slot = byte;
+                        }
+                    });
+                    self.packet_len.set(len + 1);
+                }
+            }
+            RxState::Checksum => {
+                self.rx_state
+                    .set(RxState::Checksum2(hex_digit(byte).unwrap_or(0xFF)));
+            }
+            RxState::Checksum2(high) => {
+                self.rx_state.set(RxState::Idle);
+                let valid = hex_digit(byte).map(|low| high << 4 | low) == Some(self.checksum.get())
+                    && self.packet_len.get() <= PACKET_LEN;
+                if !valid {
+                    self.send_raw(b'-');
+                } else if self.after_sync.get() == AfterSync::Nothing {
+                    // Packets arriving while breakpoints are being written
+                    // are not acknowledged, so gdb sends them again.
+                    self.send_raw(b'+');
+                    self.packet.take().map(|packet| {
+                        self.handle_packet(&packet[..self.packet_len.get()]);
+                        self.packet.replace(packet);
+                    });
+                }
+            }
+        }
+    }
+
+    fn interrupt(&self) {
+        if self.running.get() {
+            self.running.set(false);
+            self.with_process((), |process| process.stop());
+            self.stopped(SIGINT);
+        }
+    }
+
+    fn handle_packet(&self, packet: &[u8]) {
+        let (command, args) = match packet.split_first() {
+            Some((&command, args)) => (command, args),
+            None => return self.reply_str(""),
+        };
+        // Packets that work without an attached process.
+        match command {
+            b'q' => return self.handle_query(args),
+            b'v' => return self.handle_v(args),
+            b'!' | b'H' | b'T' => return self.reply_str("OK"),
+            b'?' => {
+                if self.process.is_none() {
+                    self.attach_first();
+                }
+                if self.process.is_none() {
+                    return self.reply_str("W00");
+                }
+                if self.running.get() {
+                    self.running.set(false);
+                    self.with_process((), |process| process.stop());
+                }
+                return self.report_stop(self.signal.get());
+            }
+            _ => {}
+        }
+        let handled = self.with_process(false, |process| {
+            self.handle_process_packet(process, command, args);
+            true
+        });
+        if !handled {
+            self.reply_str("E02");
+        }
+    }
+
+    fn attach_first(&self) {
+        let attached = Cell::new(false);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                let alive = match process.get_state() {
+                    State::Running
+                    | State::Yielded
+                    | State::StoppedRunning
+                    | State::StoppedYielded => true,
+                    State::Faulted | State::Terminated | State::Unstarted => false,
+                };
+                if alive && !attached.get() {
+                    attached.set(self.attach(process));
+                }
+            });
+    }
+
+    fn handle_process_packet(&self, process: &dyn Process, command: u8, args: &[u8]) {
+        match command {
+            b'g' => match process.get_core_registers() {
+                Some(registers) => self.reply(|packet| {
+                    for n in 0..gdb_register_count(registers.machine) {
+                        let value = gdb_register(&registers, n).unwrap_or(0);
+                        packet.hex(&value.to_le_bytes());
+                    }
+                }),
+                None => self.reply_error(),
+            },
+            b'G' => {
+                let result = process.get_core_registers().map_or(false, |mut registers| {
+                    let count = gdb_register_count(registers.machine);
+                    args.len() == 8 * count
+                        && (0..count).all(|n| {
+                            let mut value = [0; 4];
+                            decode_hex(&args[8 * n..8 * n + 8], &mut value)
+                                && set_gdb_register(&mut registers, n, u32::from_le_bytes(value))
+                        })
+                        && process.set_core_registers(&registers).is_ok()
+                });
+                self.reply_str(if result { "OK" } else { "E01" });
+            }
+            b'p' => {
+                let value = parse_hex(args).and_then(|n| {
+                    process
+                        .get_core_registers()
+                        .and_then(|registers| gdb_register(&registers, n))
+                });
+                match value {
+                    Some(value) => self.reply(|packet| packet.hex(&value.to_le_bytes())),
+                    None => self.reply_error(),
+                }
+            }
+            b'P' => {
+                let (n, value) = split(args, b'=');
+                let mut bytes = [0; 4];
+                let result = match (parse_hex(n), decode_hex(value, &mut bytes)) {
+                    (Some(n), true) => {
+                        process.get_core_registers().map_or(false, |mut registers| {
+                            set_gdb_register(&mut registers, n, u32::from_le_bytes(bytes))
+                                && process.set_core_registers(&registers).is_ok()
+                        })
+                    }
+                    _ => false,
+                };
+                self.reply_str(if result { "OK" } else { "E01" });
+            }
+            b'm' => {
+                let (address, len) = split(args, b',');
+                match (parse_hex(address), parse_hex(len)) {
+                    (Some(address), Some(len)) => {
+                        let mut buffer = [0; (PACKET_LEN - 4) / 2];
+                        let len = cmp::min(len, buffer.len());
+                        match self.read_memory(process, address, &mut buffer[..len]) {
+                            Ok(()) => self.reply(|packet| packet.hex(&buffer[..len])),
+                            Err(_) => self.reply_error(),
+                        }
+                    }
+                    _ => self.reply_error(),
+                }
+            }
+            b'M' => {
+                let (header, data) = split(args, b':');
+                let (address, len) = split(header, b',');
+                let mut buffer = [0; PACKET_LEN / 2];
+                let result = match (parse_hex(address), parse_hex(len)) {
+                    (Some(address), Some(len)) if len <= buffer.len() => {
+                        decode_hex(data, &mut buffer[..len])
+                            && process.write_memory(address, &buffer[..len]).is_ok()
+                    }
+                    _ => false,
+                };
+                self.reply_str(if result { "OK" } else { "E01" });
+            }
+            b'c' => {
+                self.running.set(true);
+                process.resume();
+            }
+            b's' => self.step(process),
+            b'Z' | b'z' => {
+                let (kind, rest) = split(args, b',');
+                let (address, len) = split(rest, b',');
+                // Only software breakpoints are supported.
+                if kind != b"0" {
+                    return self.reply_str("");
+                }
+                let (address, len) = match (parse_hex(address), parse_hex(len)) {
+                    (Some(address), Some(len @ 2)) | (Some(address), Some(len @ 4)) => {
+                        (address, len)
+                    }
+                    _ => return self.reply_error(),
+                };
+                if command == b'Z' {
+                    if !self.add_breakpoint(process, address, len, false) {
+                        return self.reply_error();
+                    }
+                } else {
+                    for slot in self.breakpoints.iter() {
+                        if let Some(bp) = slot.get().filter(|bp| bp.address == address) {
+                            slot.set(Some(Breakpoint {
+                                wanted: false,
+                                ..bp
+                            }));
+                        }
+                    }
+                }
+                self.sync_then(AfterSync::ReplyOk);
+            }
+            b'D' => self.remove_all_then(AfterSync::Detach),
+            b'k' => self.remove_all_then(AfterSync::Kill { reply: false }),
+            _ => self.reply_str(""),
+        }
+    }
+
+    fn remove_all_then(&self, after: AfterSync) {
+        self.stepping.set(false);
+        for slot in self.breakpoints.iter() {
+            if let Some(bp) = slot.get() {
+                slot.set(Some(Breakpoint {
+                    wanted: false,
+                    ..bp
+                }));
+            }
+        }
+        self.sync_then(after);
+    }
+
+    fn handle_query(&self, args: &[u8]) {
+        let (name, rest) = split(args, b':');
+        match name {
+            b"Supported" => self.reply(|packet| {
+                let _ = write!(packet, "PacketSize={:x};qXfer:features:read+", PACKET_LEN);
+            }),
+            b"Attached" => self.reply_str("1"),
+            b"C" => self.reply_str("QC1"),
+            b"fThreadInfo" => self.reply_str("m1"),
+            b"sThreadInfo" => self.reply_str("l"),
+            b"Xfer" => self.handle_target_xml(rest),
+            b"Rcmd" => self.handle_monitor(rest),
+            _ => self.reply_str(""),
+        }
+    }
+
+    /// `qXfer:features:read:target.xml:<offset>,<length>`
+    fn handle_target_xml(&self, args: &[u8]) {
+        let xml = match self.machine.get() {
+            EM_RISCV => TARGET_XML_RISCV,
+            _ => TARGET_XML_ARM,
+        };
+        let prefix = b"features:read:target.xml:";
+        let range = match args.strip_prefix(&prefix[..]) {
+            Some(range) => range,
+            None => return self.reply_str(""),
+        };
+        let (offset, len) = split(range, b',');
+        match (parse_hex(offset), parse_hex(len)) {
+            (Some(offset), Some(len)) => {
+                let start = cmp::min(offset, xml.len());
+                let end = cmp::min(start + cmp::min(len, PACKET_LEN), xml.len());
+                self.reply(|packet| {
+                    packet.bytes(if end == xml.len() { b"l" } else { b"m" });
+                    packet.bytes(&xml.as_bytes()[start..end]);
+                });
+            }
+            _ => self.reply_error(),
+        }
+    }
+
+    /// `monitor` commands, sent hex encoded.
+    fn handle_monitor(&self, hex: &[u8]) {
+        let mut buffer = [0; 16];
+        let len = hex.len() / 2;
+        if len > buffer.len() || !decode_hex(hex, &mut buffer[..len]) {
+            return self.reply_error();
+        }
+        if &buffer[..len] != b"info" {
+            return self.reply(|packet| packet.hex(b"Only `monitor info` is supported.\n"));
+        }
+        let printed = self.with_process(false, |process| {
+            // Console output goes in `O` packets, hex encoded.
+            let mut text = HexText { stub: self };
+            let _ = write!(
+                text,
+                "Process {}\nflash {:#010x}-{:#010x}, code at {:#010x}\nram {:#010x}-{:#010x}, break {:#010x}\n",
+                process.get_process_name(),
+                process.flash_start() as usize,
+                process.flash_end() as usize,
+                process.flash_non_protected_start() as usize,
+                process.mem_start() as usize,
+                process.mem_end() as usize,
+                process.app_memory_break() as usize,
+            );
+            true
+        });
+        if printed {
+            self.reply_str("OK");
+        } else {
+            self.reply(|packet| packet.hex(b"Not attached to a process.\n"));
+        }
+    }
+
+    fn handle_v(&self, args: &[u8]) {
+        let (name, rest) = split(args, b';');
+        match name {
+            b"Attach" => {
+                let pid = parse_hex(rest);
+                let attached = Cell::new(false);
+                self.kernel
+                    .process_each_capability(&self.capability, |process| {
+                        if pid == Some(process.processid().id() + 1) && !attached.get() {
+                            attached.set(self.attach(process));
+                        }
+                    });
+                if attached.get() {
+                    self.report_stop(SIGTRAP);
+                } else {
+                    self.reply_error();
+                }
+            }
+            b"Kill" => self.remove_all_then(AfterSync::Kill { reply: true }),
+            _ => self.reply_str(""),
+        }
+    }
+}
+
+/// Writes text as `O` packets.
+struct HexText<'s, 'a, C: ProcessManagementCapability> {
+    stub: &'s GdbStub<'a, C>,
+}
+
+impl<C: ProcessManagementCapability> Write for HexText<'_, '_, C> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.stub.reply(|packet| {
+            packet.bytes(b"O");
+            packet.hex(s.as_bytes());
+        });
+        Ok(())
+    }
+}
+
+impl<C: ProcessManagementCapability> uart::TransmitClient for GdbStub<'_, C> {
+    fn transmitted_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        _tx_len: usize,
+        _rval: Result<(), ErrorCode>,
+    ) {
+        self.tx_buffer.replace(tx_buffer);
+        self.flush();
+    }
+}
+
+impl<C: ProcessManagementCapability> uart::ReceiveClient for GdbStub<'_, C> {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        _rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        if rx_len > 0 {
+            self.received_byte(rx_buffer[0]);
+        }
+        if let Err((_, rx_buffer)) = self.uart.receive_buffer(rx_buffer, 1) {
+            self.rx_buffer.replace(rx_buffer);
+        }
+    }
+}
+
+impl<C: ProcessManagementCapability> NonvolatileStorageClient<'static> for GdbStub<'_, C> {
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.flash_buffer.replace(buffer);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.flash_buffer.replace(buffer);
+        self.writing.take().map(|index| {
+            let slot = &self.breakpoints[index];
+            if let Some(bp) = slot.get() {
+                if bp.wanted {
+                    slot.set(Some(Breakpoint {
+                        inserted: true,
+                        ..bp
+                    }));
+                } else {
+                    slot.set(None);
+                }
+            }
+        });
+        self.sync_flash();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads from `memory`, which starts at `base`.
+    fn reader(base: u32, memory: &[u8]) -> impl Fn(u32, usize) -> Option<u32> + '_ {
+        move |address, len| {
+            let offset = address.checked_sub(base)? as usize;
+            let bytes = memory.get(offset..offset + len)?;
+            Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, &byte| value << 8 | byte as u32),
+            )
+        }
+    }
+
+    fn thumb(pc: u32, code: &[u8]) -> [Option<u32>; 2] {
+        let mut registers = [0; 17];
+        registers[15] = pc | 1;
+        registers[13] = 0x2000_0000;
+        registers[14] = 0x4001;
+        thumb_successors(&registers, &reader(pc, code)).unwrap()
+    }
+
+    fn riscv(pc: u32, code: &[u8]) -> [Option<u32>; 2] {
+        let mut registers = [0; 32];
+        registers[0] = pc;
+        // ra
+        registers[1] = 0x4000;
+        riscv_successors(&registers, &reader(pc, code)).unwrap()
+    }
+
+    #[test]
+    fn thumb_branches() {
+        // movs r0, #1
+        assert_eq!(thumb(0x1000, &[0x01, 0x20]), [Some(0x1002), None]);
+        // beq.n 0x1010
+        assert_eq!(thumb(0x1000, &[0x06, 0xd0]), [Some(0x1002), Some(0x1010)]);
+        // b.n 0x0ffc
+        assert_eq!(thumb(0x1000, &[0xfc, 0xe7]), [Some(0x0ffc), None]);
+        // bx lr
+        assert_eq!(thumb(0x1000, &[0x70, 0x47]), [Some(0x4000), None]);
+        // bl 0x2000
+        assert_eq!(
+            thumb(0x1000, &[0x00, 0xf0, 0xfe, 0xff]),
+            [Some(0x2000), None]
+        );
+        // bne.w 0x0f00
+        assert_eq!(
+            thumb(0x1000, &[0x7f, 0xf4, 0x7e, 0xaf]),
+            [Some(0x1004), Some(0x0f00)]
+        );
+        // cbz r0, 0x1008
+        assert_eq!(thumb(0x1000, &[0x10, 0xb1]), [Some(0x1002), Some(0x1008)]);
+        // add.w r0, r1, r2
+        assert_eq!(
+            thumb(0x1000, &[0x01, 0xeb, 0x02, 0x00]),
+            [Some(0x1004), None]
+        );
+    }
+
+    #[test]
+    fn riscv_branches() {
+        // addi a0, a0, 1
+        assert_eq!(
+            riscv(0x1000, &[0x13, 0x05, 0x15, 0x00]),
+            [Some(0x1004), None]
+        );
+        // jal ra, 0x1100
+        assert_eq!(
+            riscv(0x1000, &[0xef, 0x00, 0x00, 0x10]),
+            [Some(0x1100), None]
+        );
+        // beq a0, a1, 0x0ff0
+        assert_eq!(
+            riscv(0x1000, &[0xe3, 0x08, 0xb5, 0xfe]),
+            [Some(0x1004), Some(0x0ff0)]
+        );
+        // ret
+        assert_eq!(riscv(0x1000, &[0x82, 0x80]), [Some(0x4000), None]);
+        // c.j 0x1010
+        assert_eq!(riscv(0x1000, &[0x01, 0xa8]), [Some(0x1010), None]);
+        // c.beqz a0, 0x0ff8
+        assert_eq!(riscv(0x1000, &[0x65, 0xdd]), [Some(0x1002), Some(0x0ff8)]);
+    }
+
+    #[test]
+    fn packets() {
+        let mut buffer = [0; 16];
+        let mut packet = PacketWriter::new(&mut buffer);
+        packet.bytes(b"OK");
+        let len = packet.finish();
+        assert_eq!(&buffer[..len], b"$OK#9a");
+
+        let mut small = [0; 4];
+        let mut packet = PacketWriter::new(&mut small);
+        packet.bytes(b"OK");
+        assert_eq!(packet.finish(), 0);
+
+        assert_eq!(parse_hex(b"20000400"), Some(0x2000_0400));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"x1"), None);
+        let mut bytes = [0; 2];
+        assert!(decode_hex(b"be00", &mut bytes));
+        assert_eq!(bytes, [0xbe, 0x00]);
+        assert!(!decode_hex(b"be0", &mut bytes));
+    }
+}
@@ -0,0 +1,1000 @@
+//! Kernel firmware update with A/B image staging and rollback.
+//!
+//! The board reserves two flash banks that can each hold a kernel image, and a
+//! flash page for a boot-control record. The hardware starts the kernel in
+//! bank A, which also acts as the bootloader, so this capsule never writes to
+//! bank A. Instead, it lets a privileged userspace process of the kernel in
+//! bank A stage a new kernel image, linked to run from bank B, into bank B:
+//!
+//! 1. The process starts an update with the length of the new image and then
+//!    writes the image one page at a time. Each page of bank B is erased and
+//!    then written.
+//! 2. The process finishes the update by providing a signature of the image.
+//!    The capsule reads the staged image back from flash, hashes it with
+//!    SHA-256 through `hil::digest` and checks the signature of the digest
+//!    with the board's public key through `hil::public_key_crypto`. If the
+//!    signature is valid, the boot-control record is updated to mark bank B
+//!    as pending.
+//! 3. On the next reset the hardware starts the kernel in bank A: the board
+//!    calls `boot()` just before entering the main loop, and the kernel
+//!    applies `BootControlRecord::select_boot_bank()` to the record. This
+//!    decrements the remaining boot attempts of a pending bank, and the
+//!    board's `Chainloader` then starts the selected kernel if it is not the
+//!    running one.
+//! 4. When a kernel booted from the pending bank calls `boot()`, it has
+//!    reached the main loop and the pending bank becomes the active bank.
+//! 5. If the new kernel fails to reach the main loop before the boot attempts
+//!    run out, or the `Chainloader` cannot start it, the kernel in bank A
+//!    boots the previously active bank again and marks the record as rolled
+//!    back.
+//!
+//! Because bank A is never overwritten, a kernel running from bank B cannot
+//! stage another update. It can instead make bank A the active bank again, and
+//! the next update is then staged from the kernel in bank A.
+//!
+//! Only the processes whose package names the board lists may use the driver.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! // First page and number of pages of each bank.
+//! let banks = [(0x0, 0x180), (0x400, 0x180)];
+//! let running_bank = capsules::kernel_update::Bank::containing(
+//!     &banks,
+//!     PAGE_SIZE,
+//!     &_stext as *const u8 as usize,
+//! )
+//! .expect("kernel is not in an update bank");
+//! verifier.set_public_key(&UPDATE_PUBLIC_KEY).unwrap();
+//! let kernel_update = static_init!(
+//!     capsules::kernel_update::KernelUpdate<
+//!         'static,
+//!         capsules::virtual_flash::FlashUser<'static, lowrisc::flash_ctrl::FlashCtrl>,
+//!         capsules::virtual_digest::VirtualMuxDigest<'static, lowrisc::hmac::Hmac, 32>,
+//!         capsules::public_key_crypto::ed25519::Ed25519Software<'static, 32>,
+//!     >,
+//!     capsules::kernel_update::KernelUpdate::new(
+//!         flash_user,
+//!         digest,
+//!         verifier,
+//!         page_buffer,
+//!         &mut capsules::kernel_update::DATA_BUFFER,
+//!         &mut capsules::kernel_update::DIGEST_BUFFER,
+//!         &mut capsules::kernel_update::SIGNATURE_BUFFER,
+//!         banks,
+//!         0x580,        // Page holding the boot-control record
+//!         running_bank, // The bank this kernel runs from
+//!         3,            // Boot attempts before rolling back
+//!         &["updater"], // Processes that may update the kernel
+//!         chainloader,
+//!         board_kernel.create_grant(capsules::kernel_update::DRIVER_NUM, &grant_cap),
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(flash_user, kernel_update);
+//! digest.set_sha_client(kernel_update);
+//! verifier.set_verify_client(kernel_update);
+//!
+//! // Just before entering the main loop:
+//! kernel_update.boot();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil;
+use kernel::hil::digest;
+use kernel::hil::public_key_crypto::{ClientVerify, SignatureVerify};
+use kernel::ReadableProcessBuffer;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, ReadOnlyProcessBuffer};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::KernelUpdate as usize;
+
+pub static mut DATA_BUFFER: [u8; 64] = [0; 64];
+pub static mut DIGEST_BUFFER: [u8; 32] = [0; 32];
+pub static mut SIGNATURE_BUFFER: [u8; SIGNATURE_LEN] = [0; SIGNATURE_LEN];
+
+/// Length of an image signature, such as an Ed25519 signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Marks a valid boot-control record ("TKBC").
+const BOOT_CONTROL_MAGIC: u32 = 0x43424b54;
+
+/// One of the two flash banks a kernel image can be stored in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bank {
+    A = 0,
+    B = 1,
+}
+
+impl Bank {
+    pub fn other(self) -> Bank {
+        match self {
+            Bank::A => Bank::B,
+            Bank::B => Bank::A,
+        }
+    }
+
+    /// Return the bank holding `address`, given the first page and number of
+    /// pages of each bank. Boards pass the address of `_stext` to find the
+    /// bank the running kernel was started from.
+    pub fn containing(
+        banks: &[(usize, usize); 2],
+        page_size: usize,
+        address: usize,
+    ) -> Option<Bank> {
+        let page = address / page_size;
+        [Bank::A, Bank::B].iter().copied().find(|&bank| {
+            let (first_page, pages) = banks[bank as usize];
+            page >= first_page && page - first_page < pages
+        })
+    }
+}
+
+/// Starts the kernel in another bank. Implemented by the board.
+pub trait Chainloader {
+    /// Transfer control to the kernel image in `bank`. Returns only if `bank`
+    /// does not hold a kernel that can run from it, for instance because the
+    /// entry point of the image is outside of the bank.
+    fn chainload(&self, bank: Bank) -> ErrorCode;
+}
+
+/// The state of the image in the inactive bank.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootState {
+    /// The active bank is known to be good, and nothing is pending.
+    Confirmed = 0,
+    /// The inactive bank holds a verified image that should be tried.
+    Pending = 1,
+    /// The image in the inactive bank did not confirm before the boot
+    /// attempts ran out, and the bootloader returned to the active bank.
+    RolledBack = 2,
+}
+
+/// The boot-control record shared between the kernel and the bootloader.
+///
+/// The record is stored at the start of a dedicated flash page, with all
+/// fields little-endian:
+///
+/// ```text
+/// 0         4             8       12              16             20        52
+/// +---------+-------------+-------+---------------+--------------+---...---+
+/// | "TKBC"  | active_bank | state | boot_attempts | image_length | digest  |
+/// +---------+-------------+-------+---------------+--------------+---...---+
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BootControlRecord {
+    /// The bank with the last kernel known to reach the main loop.
+    pub active_bank: Bank,
+    pub state: BootState,
+    /// How many more times the bootloader may try the pending bank.
+    pub boot_attempts: u32,
+    /// Length and SHA-256 digest of the image in the pending bank.
+    pub image_length: u32,
+    pub digest: [u8; 32],
+}
+
+impl BootControlRecord {
+    /// Number of bytes the serialized record uses.
+    pub const LENGTH: usize = 52;
+
+    /// Parse a record from the start of `buf`. Returns `None` if `buf` does
+    /// not contain a valid record, for instance because the page is erased.
+    pub fn parse(buf: &[u8]) -> Option<BootControlRecord> {
+        let word = |index: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(
+                buf.get(index..index + 4)?.try_into().ok()?,
+            ))
+        };
+
+        if word(0)? != BOOT_CONTROL_MAGIC {
+            return None;
+        }
+        let active_bank = match word(4)? {
+            0 => Bank::A,
+            1 => Bank::B,
+            _ => return None,
+        };
+        let state = match word(8)? {
+            0 => BootState::Confirmed,
+            1 => BootState::Pending,
+            2 => BootState::RolledBack,
+            _ => return None,
+        };
+        Some(BootControlRecord {
+            active_bank: active_bank,
+            state: state,
+            boot_attempts: word(12)?,
+            image_length: word(16)?,
+            digest: buf.get(20..52)?.try_into().ok()?,
+        })
+    }
+
+    /// Write the record to the start of `buf`, which must be at least
+    /// `BootControlRecord::LENGTH` bytes long.
+    pub fn serialize(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&BOOT_CONTROL_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&(self.active_bank as u32).to_le_bytes());
+        buf[8..12].copy_from_slice(&(self.state as u32).to_le_bytes());
+        buf[12..16].copy_from_slice(&self.boot_attempts.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.image_length.to_le_bytes());
+        buf[20..52].copy_from_slice(&self.digest);
+    }
+
+    /// Decide which bank the bootloader should boot, updating the record.
+    ///
+    /// A bootloader must write the record back to flash before booting the
+    /// returned bank if this function changed it.
+    pub fn select_boot_bank(&mut self) -> Bank {
+        match self.state {
+            BootState::Confirmed | BootState::RolledBack => self.active_bank,
+            BootState::Pending => {
+                if self.boot_attempts > 0 {
+                    self.boot_attempts -= 1;
+                    self.active_bank.other()
+                } else {
+                    self.state = BootState::RolledBack;
+                    self.active_bank
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Erasing and then writing one page of the image, at the given byte
+    /// offset.
+    EraseImage(usize),
+    WriteImage(usize),
+    /// Reading back the staged image and adding it to the digest.
+    ReadImage,
+    HashImage,
+    /// Checking the signature of the digest of the staged image.
+    VerifyImage,
+    /// Reading the record to select the bank to boot.
+    ReadRecord,
+    EraseRecord,
+    WriteRecord,
+}
+
+#[derive(Default)]
+pub struct App {
+    data: ReadOnlyProcessBuffer,
+    signature: ReadOnlyProcessBuffer,
+}
+
+pub struct KernelUpdate<
+    'a,
+    F: hil::flash::Flash + 'static,
+    D: digest::Digest<'a, 32>,
+    S: SignatureVerify<'a, 32, SIGNATURE_LEN>,
+> {
+    flash: &'a F,
+    digest: &'a D,
+    verifier: &'a S,
+    apps: Grant<App, 1>,
+    // The process performing the current update.
+    owner: OptionalCell<ProcessId>,
+    // Package names of the processes that may use the driver.
+    allowed: &'a [&'a str],
+    chainloader: &'a dyn Chainloader,
+
+    page_buffer: TakeCell<'static, F::Page>,
+    data_buffer: TakeCell<'static, [u8]>,
+    digest_buffer: TakeCell<'static, [u8; 32]>,
+    signature_buffer: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+
+    // First page and number of pages of each bank.
+    banks: [(usize, usize); 2],
+    page_size: usize,
+    record_page: usize,
+    running_bank: Bank,
+    boot_attempts: u32,
+    // The bank to start once the record written by `boot()` is stored.
+    chainload: OptionalCell<Bank>,
+
+    state: Cell<State>,
+    // The state of the record as of the last time it was read or written.
+    boot_state: Cell<BootState>,
+    // Length of the image being staged.
+    image_length: Cell<usize>,
+    // How many bytes of the staged image have been added to the digest.
+    hashed_length: Cell<usize>,
+    // How many bytes the last `add_data()` call passed to the digest.
+    hash_chunk_length: Cell<usize>,
+    // SHA-256 digest of the staged image, once its signature is verified.
+    image_digest: Cell<[u8; 32]>,
+}
+
+impl<
+        'a,
+        F: hil::flash::Flash + 'static,
+        D: digest::Digest<'a, 32> + digest::Sha256,
+        S: SignatureVerify<'a, 32, SIGNATURE_LEN>,
+    > KernelUpdate<'a, F, D, S>
+{
+    pub fn new(
+        flash: &'a F,
+        digest: &'a D,
+        verifier: &'a S,
+        page_buffer: &'static mut F::Page,
+        data_buffer: &'static mut [u8],
+        digest_buffer: &'static mut [u8; 32],
+        signature_buffer: &'static mut [u8; SIGNATURE_LEN],
+        banks: [(usize, usize); 2],
+        record_page: usize,
+        running_bank: Bank,
+        boot_attempts: u32,
+        allowed: &'a [&'a str],
+        chainloader: &'a dyn Chainloader,
+        grant: Grant<App, 1>,
+    ) -> KernelUpdate<'a, F, D, S> {
+        let page_size = page_buffer.as_mut().len();
+        KernelUpdate {
+            flash: flash,
+            digest: digest,
+            verifier: verifier,
+            apps: grant,
+            owner: OptionalCell::empty(),
+            allowed: allowed,
+            chainloader: chainloader,
+            page_buffer: TakeCell::new(page_buffer),
+            data_buffer: TakeCell::new(data_buffer),
+            digest_buffer: TakeCell::new(digest_buffer),
+            signature_buffer: TakeCell::new(signature_buffer),
+            banks: banks,
+            page_size: page_size,
+            record_page: record_page,
+            running_bank: running_bank,
+            boot_attempts: boot_attempts,
+            chainload: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            boot_state: Cell::new(BootState::Confirmed),
+            image_length: Cell::new(0),
+            hashed_length: Cell::new(0),
+            hash_chunk_length: Cell::new(0),
+            image_digest: Cell::new([0; 32]),
+        }
+    }
+
+    /// Select the bank to boot and mark the running kernel as good.
+    ///
+    /// Boards should call this just before entering the kernel main loop. The
+    /// kernel in bank A first applies `select_boot_bank()` to the record and
+    /// starts the selected kernel if it is not the running one. Otherwise, if
+    /// the running kernel was booted from a pending bank, the bank becomes the
+    /// active bank and will no longer be rolled back. The record is updated
+    /// asynchronously once the main loop runs.
+    pub fn boot(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.page_buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |page| {
+                self.state.set(State::ReadRecord);
+                self.flash
+                    .read_page(self.record_page, page)
+                    .map_err(|(e, page)| {
+                        self.page_buffer.replace(page);
+                        self.state.set(State::Idle);
+                        e
+                    })
+            })
+    }
+
+    /// Return the state of the boot-control record as last seen by this
+    /// kernel.
+    pub fn boot_state(&self) -> BootState {
+        self.boot_state.get()
+    }
+
+    fn allowed(&self, appid: ProcessId) -> bool {
+        appid
+            .get_process_name()
+            .map_or(false, |name| self.allowed.contains(&name))
+    }
+
+    fn inactive_bank(&self) -> (usize, usize) {
+        self.banks[self.running_bank.other() as usize]
+    }
+
+    // Start the kernel in `bank`. If that fails, mark `record` as rolled back
+    // to the running kernel so the bank is not tried again.
+    fn chainload(&self, mut record: BootControlRecord, bank: Bank) {
+        let _ = self.chainloader.chainload(bank);
+        record.active_bank = self.running_bank;
+        record.state = BootState::RolledBack;
+        record.boot_attempts = 0;
+        let _ = self.write_record(record);
+    }
+
+    // Erase the given page, keeping the contents of the page buffer for the
+    // write that follows.
+    fn erase(&self, page_number: usize, next: State) -> Result<(), ErrorCode> {
+        self.state.set(next);
+        self.flash.erase_page(page_number).map_err(|e| {
+            self.state.set(State::Idle);
+            e
+        })
+    }
+
+    // Start writing one page of the image from the process's data buffer.
+    fn write_image(&self, appid: ProcessId, offset: usize) -> Result<(), ErrorCode> {
+        let page_size = self.page_size;
+        let (first_page, pages) = self.inactive_bank();
+        if page_size == 0 || offset % page_size != 0 || offset >= self.image_length.get() {
+            return Err(ErrorCode::INVAL);
+        }
+        if offset / page_size >= pages {
+            return Err(ErrorCode::SIZE);
+        }
+
+        self.apps
+            .enter(appid, |app, _| {
+                app.data.enter(|data| {
+                    self.page_buffer.map(|page| {
+                        let page = page.as_mut();
+                        let length = cmp::min(
+                            cmp::min(data.len(), page.len()),
+                            self.image_length.get() - offset,
+                        );
+                        data[..length].copy_to_slice(&mut page[..length]);
+                        // Pad the rest of the page as if it were erased.
+                        for b in page[length..].iter_mut() {
+                            *b = 0xff;
+                        }
+                    });
+                })
+            })
+            .map_err(ErrorCode::from)?
+            .map_err(ErrorCode::from)?;
+
+        self.erase(first_page + offset / page_size, State::EraseImage(offset))
+    }
+
+    // Start verifying the staged image against the signature from the
+    // process.
+    fn verify_image(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        self.signature_buffer
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.signature.enter(|signature| {
+                            if signature.len() != buffer.len() {
+                                return Err(ErrorCode::SIZE);
+                            }
+                            signature.copy_to_slice(buffer);
+                            Ok(())
+                        })
+                    })
+                    .map_err(ErrorCode::from)?
+                    .map_err(ErrorCode::from)?
+            })?;
+
+        self.hashed_length.set(0);
+        self.digest.set_mode_sha256()?;
+        self.read_image_page()
+    }
+
+    // Read the page of the staged image holding the next bytes to hash.
+    fn read_image_page(&self) -> Result<(), ErrorCode> {
+        let page_size = self.page_size;
+        let (first_page, _) = self.inactive_bank();
+        let page_number = first_page + self.hashed_length.get() / page_size;
+        self.page_buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |page| {
+                self.state.set(State::ReadImage);
+                self.flash
+                    .read_page(page_number, page)
+                    .map_err(|(e, page)| {
+                        self.page_buffer.replace(page);
+                        self.state.set(State::Idle);
+                        e
+                    })
+            })
+    }
+
+    // Pass the next chunk of the page buffer to the digest.
+    fn hash_image_chunk(&self) -> Result<(), ErrorCode> {
+        let page_size = self.page_size;
+        let hashed = self.hashed_length.get();
+        let page_offset = hashed % page_size;
+
+        self.data_buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |data| {
+                let length = cmp::min(
+                    cmp::min(data.len(), page_size - page_offset),
+                    self.image_length.get() - hashed,
+                );
+                self.page_buffer.map(|page| {
+                    data[..length]
+                        .copy_from_slice(&page.as_mut()[page_offset..page_offset + length]);
+                });
+                self.hash_chunk_length.set(length);
+                self.state.set(State::HashImage);
+
+                let mut lease = LeasableBuffer::new(data);
+                lease.slice(0..length);
+                self.digest
+                    .add_data(lease)
+                    .map(|_| ())
+                    .map_err(|(e, data)| {
+                        self.data_buffer.replace(data);
+                        self.state.set(State::Idle);
+                        e
+                    })
+            })
+    }
+
+    // Make the running kernel in bank B give way to the kernel in bank A on the
+    // next reset.
+    fn return_to_bank_a(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        self.owner.set(appid);
+        self.image_length.set(0);
+        self.write_record(BootControlRecord {
+            active_bank: Bank::A,
+            state: BootState::Confirmed,
+            boot_attempts: 0,
+            image_length: 0,
+            digest: [0; 32],
+        })
+        .map_err(|e| {
+            self.owner.clear();
+            e
+        })
+    }
+
+    // Mark the staged image as pending in the boot-control record.
+    fn write_pending_record(&self) -> Result<(), ErrorCode> {
+        let record = BootControlRecord {
+            active_bank: self.running_bank,
+            state: BootState::Pending,
+            boot_attempts: self.boot_attempts,
+            image_length: self.image_length.get() as u32,
+            digest: self.image_digest.get(),
+        };
+        self.write_record(record)
+    }
+
+    // Apply the record read by `boot()`.
+    fn boot_with(&self, mut record: BootControlRecord) {
+        let stored = record;
+        self.boot_state.set(record.state);
+
+        if self.running_bank == Bank::A {
+            // The hardware always starts the kernel in bank A, which therefore
+            // acts as the bootloader.
+            let bank = record.select_boot_bank();
+            if bank != self.running_bank {
+                if record == stored {
+                    self.chainload(record, bank);
+                    return;
+                }
+                // Count the boot attempt before starting the other kernel.
+                self.chainload.set(bank);
+                if self.write_record(record).is_err() {
+                    self.chainload.clear();
+                }
+                return;
+            }
+        }
+
+        if record.state == BootState::Pending && record.active_bank != self.running_bank {
+            // This kernel was booted from the pending bank and reached the
+            // main loop, so it becomes the active kernel.
+            record.active_bank = self.running_bank;
+            record.state = BootState::Confirmed;
+            record.boot_attempts = 0;
+        }
+        if record != stored {
+            let _ = self.write_record(record);
+        }
+    }
+
+    fn write_record(&self, record: BootControlRecord) -> Result<(), ErrorCode> {
+        self.page_buffer.map(|page| {
+            let page = page.as_mut();
+            for b in page.iter_mut() {
+                *b = 0xff;
+            }
+            record.serialize(page);
+        });
+        self.boot_state.set(record.state);
+        self.erase(self.record_page, State::EraseRecord)
+    }
+
+    // Finish the current operation of the process performing the update.
+    fn operation_done(&self, result: Result<(), ErrorCode>, value: usize) {
+        self.state.set(State::Idle);
+        self.chainload.clear();
+        self.owner.map(|appid| {
+            let _ = self.apps.enter(*appid, |_, upcalls| {
+                upcalls
+                    .schedule_upcall(0, kernel::into_statuscode(result), value, 0)
+                    .ok();
+            });
+        });
+    }
+}
+
+impl<
+        'a,
+        F: hil::flash::Flash + 'static,
+        D: digest::Digest<'a, 32> + digest::Sha256,
+        S: SignatureVerify<'a, 32, SIGNATURE_LEN>,
+    > hil::flash::Client<F> for KernelUpdate<'a, F, D, S>
+{
+    fn read_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.page_buffer.replace(page);
+
+        match self.state.get() {
+            State::ReadImage => {
+                let res = if error == hil::flash::Error::CommandComplete {
+                    self.hash_image_chunk()
+                } else {
+                    Err(ErrorCode::FAIL)
+                };
+                if let Err(e) = res {
+                    self.operation_done(Err(e), 0);
+                }
+            }
+            State::ReadRecord => {
+                self.state.set(State::Idle);
+                if error != hil::flash::Error::CommandComplete {
+                    return;
+                }
+                let record = self
+                    .page_buffer
+                    .map_or(None, |page| BootControlRecord::parse(page.as_mut()));
+                match record {
+                    Some(record) => self.boot_with(record),
+                    None => {
+                        // No record yet, so create one for the running kernel.
+                        let _ = self.write_record(BootControlRecord {
+                            active_bank: self.running_bank,
+                            state: BootState::Confirmed,
+                            boot_attempts: 0,
+                            image_length: 0,
+                            digest: [0; 32],
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.page_buffer.replace(page);
+        let result = if error == hil::flash::Error::CommandComplete {
+            Ok(())
+        } else {
+            Err(ErrorCode::FAIL)
+        };
+
+        match self.state.get() {
+            State::WriteImage(offset) => self.operation_done(result, offset),
+            State::WriteRecord => {
+                if let Some(bank) = self.chainload.take() {
+                    // The record was written by `boot()`. If it could not be
+                    // stored, keep running this kernel rather than start the
+                    // other one without counting the attempt.
+                    self.state.set(State::Idle);
+                    if result.is_ok() {
+                        let record = self
+                            .page_buffer
+                            .map_or(None, |page| BootControlRecord::parse(page.as_mut()));
+                        record.map(|record| self.chainload(record, bank));
+                    }
+                } else if self.owner.is_some() {
+                    // The record was written for the process performing an
+                    // update.
+                    self.image_length.set(0);
+                    self.operation_done(result, 0);
+                    self.owner.clear();
+                } else {
+                    // The record was written by `boot()`.
+                    self.state.set(State::Idle);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        let next = match self.state.get() {
+            State::EraseImage(offset) => {
+                let (first_page, _) = self.inactive_bank();
+                Some((
+                    first_page + offset / self.page_size,
+                    State::WriteImage(offset),
+                ))
+            }
+            State::EraseRecord => Some((self.record_page, State::WriteRecord)),
+            _ => None,
+        };
+
+        next.map(|(page_number, state)| {
+            let res = if error == hil::flash::Error::CommandComplete {
+                self.page_buffer
+                    .take()
+                    .map_or(Err(ErrorCode::RESERVE), |page| {
+                        self.state.set(state);
+                        self.flash
+                            .write_page(page_number, page)
+                            .map_err(|(e, page)| {
+                                self.page_buffer.replace(page);
+                                e
+                            })
+                    })
+            } else {
+                Err(ErrorCode::FAIL)
+            };
+            if let Err(e) = res {
+                self.operation_done(Err(e), 0);
+            }
+        });
+    }
+}
+
+impl<
+        'a,
+        F: hil::flash::Flash + 'static,
+        D: digest::Digest<'a, 32> + digest::Sha256,
+        S: SignatureVerify<'a, 32, SIGNATURE_LEN>,
+    > digest::Client<'a, 32> for KernelUpdate<'a, F, D, S>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.data_buffer.replace(data);
+        if let Err(e) = result {
+            self.digest.clear_data();
+            self.operation_done(Err(e), 0);
+            return;
+        }
+
+        let hashed = self.hashed_length.get() + self.hash_chunk_length.get();
+        self.hashed_length.set(hashed);
+
+        let res = if hashed >= self.image_length.get() {
+            self.digest_buffer
+                .take()
+                .map_or(Err(ErrorCode::RESERVE), |digest| {
+                    self.digest.run(digest).map_err(|(e, digest)| {
+                        self.digest_buffer.replace(digest);
+                        e
+                    })
+                })
+        } else if hashed % self.page_size == 0 {
+            self.read_image_page()
+        } else {
+            self.hash_image_chunk()
+        };
+        if let Err(e) = res {
+            self.digest.clear_data();
+            self.operation_done(Err(e), 0);
+        }
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; 32]) {
+        let res = match (result, self.signature_buffer.take()) {
+            (Ok(()), Some(signature)) => {
+                self.state.set(State::VerifyImage);
+                self.verifier
+                    .verify(digest, signature)
+                    .map_err(|(e, digest, signature)| {
+                        self.digest_buffer.replace(digest);
+                        self.signature_buffer.replace(signature);
+                        e
+                    })
+            }
+            (result, signature) => {
+                self.digest_buffer.replace(digest);
+                signature.map(|signature| self.signature_buffer.replace(signature));
+                result.and(Err(ErrorCode::RESERVE))
+            }
+        };
+        if let Err(e) = res {
+            self.operation_done(Err(e), 0);
+        }
+    }
+}
+
+impl<
+        'a,
+        F: hil::flash::Flash + 'static,
+        D: digest::Digest<'a, 32> + digest::Sha256,
+        S: SignatureVerify<'a, 32, SIGNATURE_LEN>,
+    > ClientVerify<'a, 32, SIGNATURE_LEN> for KernelUpdate<'a, F, D, S>
+{
+    fn verification_done(
+        &'a self,
+        result: Result<bool, ErrorCode>,
+        hash: &'static mut [u8; 32],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) {
+        self.image_digest.set(*hash);
+        self.digest_buffer.replace(hash);
+        self.signature_buffer.replace(signature);
+
+        let res = match result {
+            Ok(true) => self.write_pending_record(),
+            Ok(false) => Err(ErrorCode::FAIL),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            self.operation_done(Err(e), 0);
+        }
+    }
+}
+
+/// Provide an interface for userland.
+impl<
+        'a,
+        F: hil::flash::Flash + 'static,
+        D: digest::Digest<'a, 32> + digest::Sha256,
+        S: SignatureVerify<'a, 32, SIGNATURE_LEN>,
+    > Driver for KernelUpdate<'a, F, D, S>
+{
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The data to write to the next page of the image.
+    /// - `1`: The signature of the SHA-256 digest of the complete image.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyProcessBuffer,
+    ) -> Result<ReadOnlyProcessBuffer, (ReadOnlyProcessBuffer, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.data);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut slice, &mut app.signature);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    // Setup callbacks.
+    //
+    // ### `subscribe_num`
+    //
+    // - `0`: An operation finished. The first argument is the status, and the
+    //        second is the offset of the page written for a write operation.
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    ///
+    /// The other commands fail with `NOSUPPORT` for processes the board does
+    /// not allow to update the kernel.
+    ///
+    /// - `1`: Start a new update of `data1` bytes. Fails with `BUSY` if
+    ///        another process is performing an update, and with `NOSUPPORT`
+    ///        if the kernel is running from bank B.
+    /// - `2`: Write the allowed data buffer to the page of the image that
+    ///        starts at byte offset `data1`.
+    /// - `3`: Verify the allowed signature of the staged image and, if it is
+    ///        valid, mark the image to be booted on the next reset.
+    /// - `4`: Abandon the current update.
+    /// - `5`: Return the bank the kernel is running from and the state of the
+    ///        boot-control record.
+    /// - `6`: Return the page size and the maximum image length.
+    /// - `7`: Boot the kernel in bank A on the next reset, so that a new update
+    ///        can be staged. Fails with `ALREADY` if the kernel is running from
+    ///        bank A.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        // Only the process that started an update can continue it. If that
+        // process no longer exists, another process can take over.
+        let owner_valid = self
+            .owner
+            .map_or(false, |owner| self.apps.enter(*owner, |_, _| {}).is_ok());
+        let is_owner = self.owner.map_or(false, |owner| *owner == appid);
+        let busy = self.state.get() != State::Idle;
+
+        let page_size = self.page_size;
+        let (_, pages) = self.inactive_bank();
+        let res = match command_num {
+            0 => return CommandReturn::success(),
+
+            _ if !self.allowed(appid) => Err(ErrorCode::NOSUPPORT),
+
+            1 => {
+                if busy || (owner_valid && !is_owner) {
+                    Err(ErrorCode::BUSY)
+                } else if self.running_bank == Bank::B {
+                    // Bank A holds the bootloader.
+                    Err(ErrorCode::NOSUPPORT)
+                } else if data1 == 0 || data1 > pages * page_size {
+                    Err(ErrorCode::SIZE)
+                } else {
+                    self.owner.set(appid);
+                    self.image_length.set(data1);
+                    Ok(())
+                }
+            }
+
+            2 | 3 if !is_owner || self.image_length.get() == 0 => Err(ErrorCode::RESERVE),
+            2 | 3 if busy => Err(ErrorCode::BUSY),
+            2 => self.write_image(appid, data1),
+            3 => self.verify_image(appid),
+
+            4 => {
+                if !is_owner {
+                    Err(ErrorCode::RESERVE)
+                } else if busy {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    self.owner.clear();
+                    self.image_length.set(0);
+                    Ok(())
+                }
+            }
+
+            5 => {
+                return CommandReturn::success_u32_u32(
+                    self.running_bank as u32,
+                    self.boot_state.get() as u32,
+                )
+            }
+
+            6 => {
+                return CommandReturn::success_u32_u32(page_size as u32, (pages * page_size) as u32)
+            }
+
+            7 => {
+                if busy || (owner_valid && !is_owner) {
+                    Err(ErrorCode::BUSY)
+                } else if self.running_bank == Bank::A {
+                    Err(ErrorCode::ALREADY)
+                } else {
+                    self.return_to_bank_a(appid)
+                }
+            }
+
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::procs::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -0,0 +1,1151 @@
+//! Kernel keystore giving processes opaque handles to keys.
+//!
+//! Processes that use `capsules::hmac` or `capsules::aes` hold raw key bytes
+//! in their own memory, where a compromised process can leak them. With the
+//! keystore, keys are generated in the kernel or imported once, persisted in
+//! a `hil::kv_system::KVSystem`, and afterwards only referred to by a 32 bit
+//! handle chosen by the process. The key bytes never leave the kernel: a
+//! process can only ask the keystore to compute or verify an HMAC-SHA256, or
+//! to encrypt or decrypt with AES-128 in CBC or CTR mode.
+//!
+//! Ownership and usage
+//! -------------------
+//!
+//! Keys belong to the process that created them, identified by its package
+//! name so that ownership survives reboots and updates. The storage key of a
+//! record is derived from the owner and the handle, so a process can only
+//! reach its own keys, and the owner is recorded and checked again when the
+//! key is loaded.
+//!
+//! Package names are not authenticated: any process loaded with the same name
+//! owns the same keys. Which processes may use the keystore is therefore left
+//! to the board, through an `OwnerPolicy`. `AllowedNames` only admits the
+//! package names the board lists, and should be used unless every process the
+//! board can load is trusted, in which case `AnyProcess` admits them all. Each
+//! key also carries a set of `usage` flags fixed when it is created; for
+//! instance a key allowed only to `VERIFY` cannot be used to compute MACs. Keys
+//! cannot be exported, and a handle cannot be reused until its key has been
+//! deleted.
+//!
+//! Records are stored in plaintext in the key-value store, so the backing
+//! flash must not be readable by processes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static KEYSTORE_POLICY: capsules::keystore::AllowedNames =
+//!     capsules::keystore::AllowedNames {
+//!         names: &["org.tockos.examples.keystore"],
+//!     };
+//!
+//! let keystore = static_init!(
+//!     capsules::keystore::Keystore<
+//!         'static,
+//!         capsules::tickv::TicKVStore<'static, lowrisc::flash_ctrl::FlashCtrl<'static>>,
+//!         VirtualMuxHmac<'static, lowrisc::hmac::Hmac<'static>, 32>,
+//!         earlgrey::aes::Aes<'static>,
+//!         capsules::rng::Entropy32ToRandom<'static>,
+//!     >,
+//!     capsules::keystore::Keystore::new(
+//!         tickv,
+//!         virtual_hmac,
+//!         &peripherals.aes,
+//!         entropy_to_random,
+//!         &mut capsules::keystore::RECORD,
+//!         &mut capsules::keystore::KEY,
+//!         &mut capsules::keystore::DATA,
+//!         &mut capsules::keystore::CRYPT,
+//!         &mut capsules::keystore::DIGEST,
+//!         &KEYSTORE_POLICY,
+//!         board_kernel.create_grant(capsules::keystore::DRIVER_NUM, &grant_cap),
+//!     )
+//! );
+//! tickv.set_client(keystore);
+//! digest::Digest::set_client(virtual_hmac, keystore);
+//! AES128::set_client(&peripherals.aes, keystore);
+//! entropy_to_random.set_client(keystore);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use crate::constant_time::constant_time_eq;
+use crate::sha256::{Sha256State, SHA256_DIGEST_LEN};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::hil::kv_system::{self, KVSystem};
+use kernel::hil::rng;
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, AES128, AES128CBC, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, ReadOnlyProcessBuffer,
+    ReadWriteProcessBuffer, ReadableProcessBuffer, WriteableProcessBuffer,
+};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Keystore as usize;
+
+/// Longest key that can be stored.
+pub const MAX_KEY_LEN: usize = 64;
+/// Offset of the key in a record.
+const KEY_OFFSET: usize = 12;
+/// Length of a stored record.
+pub const RECORD_LEN: usize = KEY_OFFSET + MAX_KEY_LEN;
+const RECORD_VERSION: u8 = 1;
+
+pub static mut RECORD: [u8; RECORD_LEN] = [0; RECORD_LEN];
+pub static mut KEY: [u8; 8] = [0; 8];
+pub static mut DATA: [u8; 64] = [0; 64];
+pub static mut CRYPT: [u8; 64] = [0; 64];
+pub static mut DIGEST: [u8; 32] = [0; 32];
+
+/// Operations a key may be used for.
+pub mod usage {
+    /// Compute HMACs.
+    pub const SIGN: u8 = 1 << 0;
+    /// Verify HMACs.
+    pub const VERIFY: u8 = 1 << 1;
+    pub const ENCRYPT: u8 = 1 << 2;
+    pub const DECRYPT: u8 = 1 << 3;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyKind {
+    HmacSha256,
+    Aes128,
+}
+
+impl KeyKind {
+    fn from_u8(kind: u8) -> Option<KeyKind> {
+        match kind {
+            0 => Some(KeyKind::HmacSha256),
+            1 => Some(KeyKind::Aes128),
+            _ => None,
+        }
+    }
+
+    /// Length of generated keys.
+    fn generated_len(self) -> usize {
+        match self {
+            KeyKind::HmacSha256 => SHA256_DIGEST_LEN,
+            KeyKind::Aes128 => AES128_KEY_SIZE,
+        }
+    }
+
+    fn valid_len(self, len: usize) -> bool {
+        match self {
+            KeyKind::HmacSha256 => len > 0 && len <= MAX_KEY_LEN,
+            KeyKind::Aes128 => len == AES128_KEY_SIZE,
+        }
+    }
+
+    fn valid_usage(self, flags: u8) -> bool {
+        let allowed = match self {
+            KeyKind::HmacSha256 => usage::SIGN | usage::VERIFY,
+            KeyKind::Aes128 => usage::ENCRYPT | usage::DECRYPT,
+        };
+        flags != 0 && flags & !allowed == 0
+    }
+}
+
+/// The metadata stored before the key in a record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyInfo {
+    pub kind: KeyKind,
+    pub usage: u8,
+    pub len: usize,
+    pub owner: [u8; 8],
+}
+
+impl KeyInfo {
+    /// Write the metadata to the start of `record`; the key follows at
+    /// `KEY_OFFSET`.
+    fn write(&self, record: &mut [u8]) {
+        record[0] = RECORD_VERSION;
+        record[1] = self.kind as u8;
+        record[2] = self.usage;
+        record[3] = self.len as u8;
+        record[4..KEY_OFFSET].copy_from_slice(&self.owner);
+    }
+
+    fn read(record: &[u8]) -> Option<KeyInfo> {
+        if record.len() < RECORD_LEN || record[0] != RECORD_VERSION {
+            return None;
+        }
+        let kind = KeyKind::from_u8(record[1])?;
+        let len = record[3] as usize;
+        if !kind.valid_len(len) || !kind.valid_usage(record[2]) {
+            return None;
+        }
+        let mut owner = [0; 8];
+        owner.copy_from_slice(&record[4..KEY_OFFSET]);
+        Some(KeyInfo {
+            kind,
+            usage: record[2],
+            len,
+            owner,
+        })
+    }
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; SHA256_DIGEST_LEN] {
+    let mut digest = [0; SHA256_DIGEST_LEN];
+    let mut sha = Sha256State::new();
+    for part in parts {
+        sha.update(part);
+    }
+    sha.finish(&mut digest);
+    digest
+}
+
+/// Identifier of the process with package name `name`.
+pub fn owner_id(name: &str) -> [u8; 8] {
+    let mut owner = [0; 8];
+    owner.copy_from_slice(&sha256(&[b"tock keystore owner", name.as_bytes()])[..8]);
+    owner
+}
+
+/// Decides which processes may use the keystore.
+pub trait OwnerPolicy {
+    /// The owner of the keys of `process`, or `None` if it may not use the
+    /// keystore.
+    fn owner(&self, process: ProcessId) -> Option<[u8; 8]>;
+}
+
+/// Admit only the processes with one of the package names in `names`.
+pub struct AllowedNames {
+    pub names: &'static [&'static str],
+}
+
+impl OwnerPolicy for AllowedNames {
+    fn owner(&self, process: ProcessId) -> Option<[u8; 8]> {
+        process
+            .get_process_name()
+            .filter(|name| self.names.contains(name))
+            .map(owner_id)
+    }
+}
+
+/// Admit every process with a package name. Only suitable for boards that
+/// load trusted processes, as a process can take the keys of another by
+/// using its name.
+pub struct AnyProcess {}
+
+impl OwnerPolicy for AnyProcess {
+    fn owner(&self, process: ProcessId) -> Option<[u8; 8]> {
+        process
+            .get_process_name()
+            .filter(|name| !name.is_empty())
+            .map(owner_id)
+    }
+}
+
+/// The key-value store key of the record for `handle` of `owner`.
+pub fn storage_key(owner: &[u8; 8], handle: u32) -> [u8; 8] {
+    let mut key = [0; 8];
+    key.copy_from_slice(&sha256(&[b"tock keystore key", owner, &handle.to_le_bytes()])[..8]);
+    key
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AesMode {
+    Cbc,
+    Ctr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Generate(u32, KeyKind, u8),
+    Import(u32, KeyKind, u8),
+    Delete(u32),
+    Sign(u32),
+    Verify(u32),
+    Crypt(u32, AesMode, bool),
+}
+
+impl Op {
+    fn handle(self) -> u32 {
+        match self {
+            Op::Generate(handle, ..)
+            | Op::Import(handle, ..)
+            | Op::Delete(handle)
+            | Op::Sign(handle)
+            | Op::Verify(handle)
+            | Op::Crypt(handle, ..) => handle,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the record for the handle.
+    Loading,
+    /// Collecting randomness for a new key.
+    Generating,
+    Storing,
+    Deleting,
+    Hashing,
+    Crypting,
+}
+
+#[derive(Default)]
+pub struct App {
+    key_material: ReadOnlyProcessBuffer,
+    param: ReadOnlyProcessBuffer,
+    source: ReadOnlyProcessBuffer,
+    dest: ReadWriteProcessBuffer,
+    /// An operation waiting for the keystore.
+    pending: Cell<Option<Op>>,
+}
+
+pub struct Keystore<
+    'a,
+    K: KVSystem<'a, K = [u8; 8]>,
+    D: digest::Digest<'a, 32> + digest::HMACSha256,
+    A: AES128<'a> + AES128Ctr + AES128CBC,
+    R: rng::Rng<'a>,
+> {
+    kv: &'a K,
+    hmac: &'a D,
+    aes: &'a A,
+    rng: &'a R,
+    policy: &'a dyn OwnerPolicy,
+    apps: Grant<App, 1>,
+
+    current: OptionalCell<ProcessId>,
+    op: Cell<Option<Op>>,
+    state: Cell<State>,
+    owner: Cell<[u8; 8]>,
+    /// Key bytes collected while generating a key.
+    generated: Cell<usize>,
+    /// `(offset, len)` of the source being hashed or encrypted.
+    progress: Cell<(usize, usize)>,
+    /// Bytes of the source in the chunk being processed.
+    chunk: Cell<usize>,
+
+    record: TakeCell<'static, [u8]>,
+    key: TakeCell<'static, [u8; 8]>,
+    data: TakeCell<'static, [u8]>,
+    crypt_buf: TakeCell<'a, [u8]>,
+    digest: TakeCell<'static, [u8; 32]>,
+}
+
+impl<
+        'a,
+        K: KVSystem<'a, K = [u8; 8]>,
+        D: digest::Digest<'a, 32> + digest::HMACSha256,
+        A: AES128<'a> + AES128Ctr + AES128CBC,
+        R: rng::Rng<'a>,
+    > Keystore<'a, K, D, A, R>
+{
+    pub fn new(
+        kv: &'a K,
+        hmac: &'a D,
+        aes: &'a A,
+        rng: &'a R,
+        record: &'static mut [u8; RECORD_LEN],
+        key: &'static mut [u8; 8],
+        data: &'static mut [u8],
+        crypt_buf: &'a mut [u8],
+        digest: &'static mut [u8; 32],
+        policy: &'a dyn OwnerPolicy,
+        grant: Grant<App, 1>,
+    ) -> Keystore<'a, K, D, A, R> {
+        Keystore {
+            kv,
+            hmac,
+            aes,
+            rng,
+            policy,
+            apps: grant,
+            current: OptionalCell::empty(),
+            op: Cell::new(None),
+            state: Cell::new(State::Idle),
+            owner: Cell::new([0; 8]),
+            generated: Cell::new(0),
+            progress: Cell::new((0, 0)),
+            chunk: Cell::new(0),
+            record: TakeCell::new(record),
+            key: TakeCell::new(key),
+            data: TakeCell::new(data),
+            crypt_buf: TakeCell::new(crypt_buf),
+            digest: TakeCell::new(digest),
+        }
+    }
+
+    fn wipe_record(&self) {
+        self.record
+            .map(|record| record.iter_mut().for_each(|b| *b = 0));
+    }
+
+    /// Start `op` for `appid` by reading the record for its handle.
+    fn start(&self, appid: ProcessId, op: Op) -> Result<(), ErrorCode> {
+        let owner = self.policy.owner(appid).ok_or(ErrorCode::NOSUPPORT)?;
+        match (self.key.take(), self.record.take()) {
+            (Some(key), Some(record)) => {
+                *key = storage_key(&owner, op.handle());
+                self.owner.set(owner);
+                self.op.set(Some(op));
+                self.current.set(appid);
+                self.state.set(State::Loading);
+                self.kv
+                    .get_value(key, record)
+                    .map_err(|(key, record, res)| {
+                        self.key.replace(key);
+                        self.record.replace(record);
+                        self.state.set(State::Idle);
+                        self.current.clear();
+                        res.err().unwrap_or(ErrorCode::FAIL)
+                    })
+            }
+            (key, record) => {
+                key.map(|key| self.key.replace(key));
+                record.map(|record| self.record.replace(record));
+                Err(ErrorCode::BUSY)
+            }
+        }
+    }
+
+    /// Start the next queued operation, if any.
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let appid = cntr.processid();
+            let pending = cntr.enter(|app, _| app.pending.take());
+            if let Some(op) = pending {
+                if let Err(e) = self.start(appid, op) {
+                    let _ = self.apps.enter(appid, |_, upcalls| {
+                        upcalls
+                            .schedule_upcall(0, kernel::into_statuscode(Err(e)), 0, 0)
+                            .ok();
+                    });
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Report the end of the current operation, with the number of bytes
+    /// written to the destination and, for `VERIFY`, whether the MAC
+    /// matched.
+    fn finish(&self, result: Result<(usize, bool), ErrorCode>) {
+        self.wipe_record();
+        self.state.set(State::Idle);
+        self.op.set(None);
+        self.current.take().map(|appid| {
+            let _ = self.apps.enter(appid, |_, upcalls| {
+                let (status, len, valid) = match result {
+                    Ok((len, valid)) => (Ok(()), len, valid),
+                    Err(e) => (Err(e), 0, false),
+                };
+                upcalls
+                    .schedule_upcall(0, kernel::into_statuscode(status), len, valid as usize)
+                    .ok();
+            });
+        });
+        self.check_queue();
+    }
+
+    /// Run a process closure on the current process.
+    fn with_app<F: FnOnce(&App) -> Result<T, ErrorCode>, T>(&self, fun: F) -> Result<T, ErrorCode> {
+        self.current.map_or(Err(ErrorCode::FAIL), |appid| {
+            self.apps
+                .enter(*appid, |app, _| fun(app))
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
+    /// The record for the handle has been read, or `found` is false.
+    fn loaded(&self, found: bool) {
+        let op = match self.op.get() {
+            Some(op) => op,
+            None => return self.finish(Err(ErrorCode::FAIL)),
+        };
+        let result = match op {
+            Op::Generate(..) | Op::Import(..) if found => Err(ErrorCode::ALREADY),
+            Op::Generate(_, kind, flags) => self.generate(kind, flags),
+            Op::Import(_, kind, flags) => self.import(kind, flags),
+            _ if !found => Err(ErrorCode::INVAL),
+            Op::Delete(_) => self.check_record(0).and_then(|_| self.delete()),
+            Op::Sign(_) => self
+                .check_record(usage::SIGN)
+                .and_then(|info| self.start_hmac(info, false)),
+            Op::Verify(_) => self
+                .check_record(usage::VERIFY)
+                .and_then(|info| self.start_hmac(info, true)),
+            Op::Crypt(_, mode, encrypting) => {
+                let flag = if encrypting {
+                    usage::ENCRYPT
+                } else {
+                    usage::DECRYPT
+                };
+                self.check_record(flag)
+                    .and_then(|info| self.start_aes(info, mode, encrypting))
+            }
+        };
+        if let Err(e) = result {
+            self.finish(Err(e));
+        }
+    }
+
+    /// Check that the loaded record belongs to the current process and
+    /// allows the operations in `required`.
+    fn check_record(&self, required: u8) -> Result<KeyInfo, ErrorCode> {
+        let info = self
+            .record
+            .map_or(None, |record| KeyInfo::read(record))
+            .ok_or(ErrorCode::FAIL)?;
+        if info.owner != self.owner.get() {
+            return Err(ErrorCode::FAIL);
+        }
+        if info.usage & required != required {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok(info)
+    }
+
+    fn generate(&self, kind: KeyKind, flags: u8) -> Result<(), ErrorCode> {
+        self.record.map_or(Err(ErrorCode::BUSY), |record| {
+            KeyInfo {
+                kind,
+                usage: flags,
+                len: kind.generated_len(),
+                owner: self.owner.get(),
+            }
+            .write(record);
+            Ok(())
+        })?;
+        self.generated.set(0);
+        self.state.set(State::Generating);
+        self.rng.get()
+    }
+
+    fn import(&self, kind: KeyKind, flags: u8) -> Result<(), ErrorCode> {
+        let owner = self.owner.get();
+        self.with_app(|app| {
+            app.key_material
+                .enter(|material| {
+                    if !kind.valid_len(material.len()) {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    self.record.map_or(Err(ErrorCode::BUSY), |record| {
+                        KeyInfo {
+                            kind,
+                            usage: flags,
+                            len: material.len(),
+                            owner,
+                        }
+                        .write(record);
+                        material
+                            .copy_to_slice(&mut record[KEY_OFFSET..KEY_OFFSET + material.len()]);
+                        Ok(())
+                    })
+                })
+                .unwrap_or(Err(ErrorCode::RESERVE))
+        })?;
+        self.store()
+    }
+
+    fn store(&self) -> Result<(), ErrorCode> {
+        match (self.key.take(), self.record.take()) {
+            (Some(key), Some(record)) => {
+                self.state.set(State::Storing);
+                self.kv
+                    .append_key(key, record)
+                    .map_err(|(key, record, res)| {
+                        self.key.replace(key);
+                        self.record.replace(record);
+                        res.err().unwrap_or(ErrorCode::FAIL)
+                    })
+            }
+            (key, record) => {
+                key.map(|key| self.key.replace(key));
+                record.map(|record| self.record.replace(record));
+                Err(ErrorCode::BUSY)
+            }
+        }
+    }
+
+    fn delete(&self) -> Result<(), ErrorCode> {
+        let key = self.key.take().ok_or(ErrorCode::BUSY)?;
+        self.state.set(State::Deleting);
+        self.kv.invalidate_key(key).map_err(|(key, res)| {
+            self.key.replace(key);
+            res.err().unwrap_or(ErrorCode::FAIL)
+        })
+    }
+
+    fn start_hmac(&self, info: KeyInfo, verify: bool) -> Result<(), ErrorCode> {
+        let len = self.with_app(|app| {
+            if verify && app.param.len() != SHA256_DIGEST_LEN {
+                return Err(ErrorCode::INVAL);
+            }
+            if !verify && app.dest.len() < SHA256_DIGEST_LEN {
+                return Err(ErrorCode::SIZE);
+            }
+            Ok(app.source.len())
+        })?;
+
+        let res = self.record.map_or(Err(ErrorCode::BUSY), |record| {
+            self.hmac
+                .set_mode_hmacsha256(&record[KEY_OFFSET..KEY_OFFSET + info.len])
+        });
+        // The engine holds its own copy of the key from here on.
+        self.wipe_record();
+        res?;
+
+        self.progress.set((0, len));
+        self.state.set(State::Hashing);
+        self.hash_chunk()
+    }
+
+    /// Add the next chunk of the source to the HMAC, or compute it once
+    /// the whole source has been added.
+    fn hash_chunk(&self) -> Result<(), ErrorCode> {
+        let (offset, len) = self.progress.get();
+        if offset == len {
+            let digest = self.digest.take().ok_or(ErrorCode::BUSY)?;
+            return self.hmac.run(digest).map_err(|(e, digest)| {
+                self.digest.replace(digest);
+                e
+            });
+        }
+
+        let data = self.data.take().ok_or(ErrorCode::BUSY)?;
+        let n = cmp::min(len - offset, data.len());
+        let copied = self.with_app(|app| {
+            app.source
+                .enter(|source| {
+                    source
+                        .get(offset..offset + n)
+                        .map(|source| source.copy_to_slice(&mut data[..n]))
+                        .ok_or(ErrorCode::SIZE)
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        });
+        if let Err(e) = copied {
+            self.data.replace(data);
+            return Err(e);
+        }
+        self.chunk.set(n);
+        let mut lease = LeasableBuffer::new(data);
+        lease.slice(..n);
+        self.hmac.add_data(lease).map(|_| ()).map_err(|(e, data)| {
+            self.data.replace(data);
+            e
+        })
+    }
+
+    fn start_aes(&self, info: KeyInfo, mode: AesMode, encrypting: bool) -> Result<(), ErrorCode> {
+        let mut iv = [0; AES128_BLOCK_SIZE];
+        let len = self.with_app(|app| {
+            let len = app.source.len();
+            if mode == AesMode::Cbc && len % AES128_BLOCK_SIZE != 0 {
+                return Err(ErrorCode::INVAL);
+            }
+            if len == 0 {
+                return Err(ErrorCode::INVAL);
+            }
+            if app.dest.len() < len {
+                return Err(ErrorCode::SIZE);
+            }
+            app.param
+                .enter(|param| {
+                    if param.len() != AES128_BLOCK_SIZE {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    param.copy_to_slice(&mut iv);
+                    Ok(len)
+                })
+                .unwrap_or(Err(ErrorCode::RESERVE))
+        })?;
+
+        let res = self.record.map_or(Err(ErrorCode::BUSY), |record| {
+            match mode {
+                AesMode::Cbc => self.aes.set_mode_aes128cbc(encrypting)?,
+                AesMode::Ctr => self.aes.set_mode_aes128ctr(encrypting)?,
+            }
+            self.aes
+                .set_key(&record[KEY_OFFSET..KEY_OFFSET + info.len])?;
+            self.aes.set_iv(&iv)
+        });
+        self.wipe_record();
+        res?;
+
+        self.progress.set((0, len));
+        self.state.set(State::Crypting);
+        self.aes.start_message();
+        self.crypt_chunk()
+    }
+
+    /// Copy the next chunk of the source into the kernel buffer and encrypt
+    /// or decrypt it.
+    fn crypt_chunk(&self) -> Result<(), ErrorCode> {
+        let (offset, len) = self.progress.get();
+        let buf = self.crypt_buf.take().ok_or(ErrorCode::BUSY)?;
+        let room = buf.len() / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE;
+        let n = cmp::min(len - offset, room);
+        let padded = (n + AES128_BLOCK_SIZE - 1) / AES128_BLOCK_SIZE * AES128_BLOCK_SIZE;
+        let copied = self.with_app(|app| {
+            app.source
+                .enter(|source| {
+                    source
+                        .get(offset..offset + n)
+                        .map(|source| source.copy_to_slice(&mut buf[..n]))
+                        .ok_or(ErrorCode::SIZE)
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        });
+        if let Err(e) = copied {
+            self.crypt_buf.replace(buf);
+            return Err(e);
+        }
+        buf[n..padded].iter_mut().for_each(|b| *b = 0);
+        self.chunk.set(n);
+
+        match self.aes.crypt(None, buf, 0, padded) {
+            None => Ok(()),
+            Some((res, _, buf)) => {
+                self.crypt_buf.replace(buf);
+                Err(res.err().unwrap_or(ErrorCode::FAIL))
+            }
+        }
+    }
+
+    /// Clear the key from the AES engine.
+    fn clear_aes_key(&self) {
+        let _ = self.aes.set_key(&[0; AES128_KEY_SIZE]);
+    }
+}
+
+impl<
+        'a,
+        K: KVSystem<'a, K = [u8; 8]>,
+        D: digest::Digest<'a, 32> + digest::HMACSha256,
+        A: AES128<'a> + AES128Ctr + AES128CBC,
+        R: rng::Rng<'a>,
+    > kv_system::Client<[u8; 8]> for Keystore<'a, K, D, A, R>
+{
+    fn generate_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _unhashed_key: &'static [u8],
+        _key_buf: &'static [u8; 8],
+    ) {
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut [u8; 8],
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.record.replace(value);
+        if self.state.get() == State::Storing {
+            self.finish(result.map(|()| (0, false)));
+        }
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut [u8; 8],
+        ret_buf: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.record.replace(ret_buf);
+        if self.state.get() == State::Loading {
+            self.loaded(result.is_ok());
+        }
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ErrorCode>, key: &'static mut [u8; 8]) {
+        self.key.replace(key);
+        if self.state.get() == State::Deleting {
+            self.finish(result.map(|()| (0, false)));
+        }
+    }
+
+    fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {}
+}
+
+impl<
+        'a,
+        K: KVSystem<'a, K = [u8; 8]>,
+        D: digest::Digest<'a, 32> + digest::HMACSha256,
+        A: AES128<'a> + AES128Ctr + AES128CBC,
+        R: rng::Rng<'a>,
+    > rng::Client for Keystore<'a, K, D, A, R>
+{
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        if self.state.get() != State::Generating {
+            return rng::Continue::Done;
+        }
+        if let Err(e) = error {
+            self.finish(Err(e));
+            return rng::Continue::Done;
+        }
+
+        let done = self.record.map_or(false, |record| {
+            let len = record[3] as usize;
+            let mut generated = self.generated.get();
+            for word in randomness {
+                for byte in word.to_le_bytes().iter() {
+                    if generated < len {
+                        record[KEY_OFFSET + generated] = *byte;
+                        generated += 1;
+                    }
+                }
+                if generated == len {
+                    break;
+                }
+            }
+            self.generated.set(generated);
+            generated == len
+        });
+        if !done {
+            return rng::Continue::More;
+        }
+        if let Err(e) = self.store() {
+            self.finish(Err(e));
+        }
+        rng::Continue::Done
+    }
+}
+
+impl<
+        'a,
+        K: KVSystem<'a, K = [u8; 8]>,
+        D: digest::Digest<'a, 32> + digest::HMACSha256,
+        A: AES128<'a> + AES128Ctr + AES128CBC,
+        R: rng::Rng<'a>,
+    > digest::Client<'a, 32> for Keystore<'a, K, D, A, R>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.data.replace(data);
+        if self.state.get() != State::Hashing {
+            return;
+        }
+        let (offset, len) = self.progress.get();
+        self.progress.set((offset + self.chunk.get(), len));
+        if let Err(e) = result.and_then(|()| self.hash_chunk()) {
+            self.hmac.clear_data();
+            self.finish(Err(e));
+        }
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; 32]) {
+        self.hmac.clear_data();
+        if self.state.get() != State::Hashing {
+            self.digest.replace(digest);
+            return;
+        }
+
+        let verify = self.op.get().map_or(false, |op| match op {
+            Op::Verify(_) => true,
+            _ => false,
+        });
+        let output = result.and_then(|()| {
+            self.with_app(|app| {
+                if verify {
+                    app.param
+                        .enter(|param| {
+                            if param.len() != SHA256_DIGEST_LEN {
+                                return Err(ErrorCode::INVAL);
+                            }
+                            let mut expected = [0; SHA256_DIGEST_LEN];
+                            param.copy_to_slice(&mut expected);
+                            Ok((0, constant_time_eq(&expected, &digest[..])))
+                        })
+                        .unwrap_or_else(|err| Err(err.into()))
+                } else {
+                    app.dest
+                        .mut_enter(|dest| {
+                            dest.get(0..SHA256_DIGEST_LEN)
+                                .map(|dest| {
+                                    dest.copy_from_slice(&digest[..]);
+                                    (SHA256_DIGEST_LEN, false)
+                                })
+                                .ok_or(ErrorCode::SIZE)
+                        })
+                        .unwrap_or_else(|err| Err(err.into()))
+                }
+            })
+        });
+        digest.iter_mut().for_each(|b| *b = 0);
+        self.digest.replace(digest);
+        self.finish(output);
+    }
+}
+
+impl<
+        'a,
+        K: KVSystem<'a, K = [u8; 8]>,
+        D: digest::Digest<'a, 32> + digest::HMACSha256,
+        A: AES128<'a> + AES128Ctr + AES128CBC,
+        R: rng::Rng<'a>,
+    > kernel::hil::symmetric_encryption::Client<'a> for Keystore<'a, K, D, A, R>
+{
+    fn crypt_done(&'a self, _source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        let (offset, len) = self.progress.get();
+        let n = self.chunk.get();
+        let copied = self.with_app(|app| {
+            app.dest
+                .mut_enter(|data| {
+                    data.get(offset..offset + n)
+                        .map(|data| data.copy_from_slice(&dest[..n]))
+                        .ok_or(ErrorCode::SIZE)
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        });
+        dest.iter_mut().for_each(|b| *b = 0);
+        self.crypt_buf.replace(dest);
+        if self.state.get() != State::Crypting {
+            return;
+        }
+
+        let result = copied.and_then(|()| {
+            self.progress.set((offset + n, len));
+            if offset + n < len {
+                self.crypt_chunk().map(|()| None)
+            } else {
+                Ok(Some(len))
+            }
+        });
+        match result {
+            Ok(None) => {}
+            Ok(Some(len)) => {
+                self.clear_aes_key();
+                self.finish(Ok((len, false)));
+            }
+            Err(e) => {
+                self.clear_aes_key();
+                self.finish(Err(e));
+            }
+        }
+    }
+}
+
+impl<
+        'a,
+        K: KVSystem<'a, K = [u8; 8]>,
+        D: digest::Digest<'a, 32> + digest::HMACSha256,
+        A: AES128<'a> + AES128Ctr + AES128CBC,
+        R: rng::Rng<'a>,
+    > Driver for Keystore<'a, K, D, A, R>
+{
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Key material to import.
+    /// - `1`: The IV for AES, or the expected MAC to verify.
+    /// - `2`: The source to authenticate, encrypt or decrypt.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyProcessBuffer,
+    ) -> Result<ReadOnlyProcessBuffer, (ReadOnlyProcessBuffer, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.key_material);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut slice, &mut app.param);
+                    Ok(())
+                }
+                2 => {
+                    mem::swap(&mut slice, &mut app.source);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Setup shared kernel-writable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The destination for a MAC or the result of AES.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteProcessBuffer,
+    ) -> Result<ReadWriteProcessBuffer, (ReadWriteProcessBuffer, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.dest);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    // Setup callbacks.
+    //
+    // ### `subscribe_num`
+    //
+    // - `0`: An operation finished. The arguments are the status, the number
+    //        of bytes written to the destination, and for command `5` `1` if
+    //        the MAC matched.
+
+    /// Command interface.
+    ///
+    /// All commands except `0` take the key handle in `data1`, and are queued
+    /// if another operation is in progress.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Generate a key. `data2` holds the kind in bits 0-7, `0` for
+    ///        HMAC-SHA256 and `1` for AES-128, and the `usage` flags in bits
+    ///        8-15. Fails with `ALREADY` if the handle is in use.
+    /// - `2`: Import the key material in read-only buffer `0`, with `data2`
+    ///        as for command `1`.
+    /// - `3`: Delete the key.
+    /// - `4`: Write the HMAC-SHA256 of the source to the destination.
+    /// - `5`: Compare the HMAC-SHA256 of the source with read-only buffer
+    ///        `1`.
+    /// - `6`: Encrypt the source into the destination with the IV in
+    ///        read-only buffer `1`. `data2` is `0` for CBC and `1` for CTR.
+    /// - `7`: Decrypt, as for command `6`.
+    ///
+    /// Operations on a missing key, or that its `usage` does not allow, fail
+    /// with `INVAL`. Commands other than `0` fail with `NOSUPPORT` for
+    /// processes that the board's `OwnerPolicy` does not admit. Operations
+    /// fail with `SIZE` if a buffer is replaced by one that is too short
+    /// while they run.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        let handle = data1 as u32;
+        let kind_and_usage = || {
+            let kind = KeyKind::from_u8(data2 as u8).ok_or(ErrorCode::INVAL)?;
+            let flags = (data2 >> 8) as u8;
+            if !kind.valid_usage(flags) {
+                return Err(ErrorCode::INVAL);
+            }
+            Ok((kind, flags))
+        };
+        let aes_mode = || match data2 {
+            0 => Ok(AesMode::Cbc),
+            1 => Ok(AesMode::Ctr),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        let op = match command_num {
+            0 => return CommandReturn::success(),
+            1 => kind_and_usage().map(|(kind, flags)| Op::Generate(handle, kind, flags)),
+            2 => kind_and_usage().map(|(kind, flags)| Op::Import(handle, kind, flags)),
+            3 => Ok(Op::Delete(handle)),
+            4 => Ok(Op::Sign(handle)),
+            5 => Ok(Op::Verify(handle)),
+            6 => aes_mode().map(|mode| Op::Crypt(handle, mode, true)),
+            7 => aes_mode().map(|mode| Op::Crypt(handle, mode, false)),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+        let op = match op {
+            Ok(op) => op,
+            Err(e) => return CommandReturn::failure(e),
+        };
+        if self.policy.owner(appid).is_none() {
+            return CommandReturn::failure(ErrorCode::NOSUPPORT);
+        }
+        if self.current.contains(&appid) {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+
+        let res = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.pending.get().is_some() {
+                    return Err(ErrorCode::BUSY);
+                }
+                app.pending.set(Some(op));
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        // Start the request now if nothing else is running.
+        if res.is_ok() && self.current.is_none() {
+            self.check_queue();
+        }
+        CommandReturn::from(res)
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::procs::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let info = KeyInfo {
+            kind: KeyKind::HmacSha256,
+            usage: usage::VERIFY,
+            len: 20,
+            owner: owner_id("app"),
+        };
+        let mut record = [0; RECORD_LEN];
+        info.write(&mut record);
+        assert_eq!(KeyInfo::read(&record), Some(info));
+
+        // Usage flags that do not apply to the kind of key.
+        record[2] = usage::ENCRYPT;
+        assert_eq!(KeyInfo::read(&record), None);
+        // An AES key of the wrong length.
+        record[1] = KeyKind::Aes128 as u8;
+        assert_eq!(KeyInfo::read(&record), None);
+        record[3] = AES128_KEY_SIZE as u8;
+        assert!(KeyInfo::read(&record).is_some());
+        record[0] = RECORD_VERSION + 1;
+        assert_eq!(KeyInfo::read(&record), None);
+    }
+
+    #[test]
+    fn keys_are_scoped_by_owner() {
+        let app = owner_id("app");
+        let other = owner_id("other");
+        assert_ne!(app, other);
+        assert_ne!(storage_key(&app, 1), storage_key(&other, 1));
+        assert_ne!(storage_key(&app, 1), storage_key(&app, 2));
+        assert_eq!(storage_key(&app, 1), storage_key(&owner_id("app"), 1));
+    }
+}
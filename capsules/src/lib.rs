@@ -9,25 +9,33 @@ pub mod net;
 
 pub mod adc;
 pub mod adc_microphone;
+pub mod aes;
+pub mod aes_gcm;
 pub mod alarm;
 pub mod ambient_light;
 pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod attestation;
 pub mod ble_advertising_driver;
 pub mod bus;
 pub mod button;
 pub mod buzzer_driver;
 pub mod console;
+pub mod constant_time;
+pub mod crash_dump;
 pub mod crc;
 pub mod ctap;
+pub mod ctap2;
 pub mod dac;
 pub mod debug_process_restart;
+pub mod drbg;
 pub mod driver;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700cq;
+pub mod gdb_stub;
 pub mod gpio;
 pub mod gpio_async;
 pub mod hd44780;
@@ -38,6 +46,8 @@ pub mod i2c_master;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod kernel_update;
+pub mod keystore;
 pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
@@ -59,7 +69,9 @@ pub mod nrf51822_serialization;
 pub mod panic_button;
 pub mod pca9544a;
 pub mod process_console;
+pub mod process_core_dump;
 pub mod proximity;
+pub mod public_key_crypto;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
@@ -67,8 +79,11 @@ pub mod screen;
 pub mod sdcard;
 pub mod segger_rtt;
 pub mod sha;
+pub mod sha256;
+pub mod sha512;
 pub mod sht3x;
 pub mod si7021;
+pub mod signature;
 pub mod sound_pressure;
 pub mod spi_controller;
 pub mod spi_peripheral;
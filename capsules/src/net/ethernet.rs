@@ -0,0 +1,100 @@
+//! Interface for Ethernet-framed network devices.
+//!
+//! An `EthernetDevice` sends and receives complete Ethernet II frames
+//! (destination MAC, source MAC, EtherType, payload, no FCS). It is
+//! implemented by link layers that look like Ethernet to their peer, such as
+//! the USB CDC-ECM class, and is used by the IPv6 stack to bridge packets
+//! between such a link and the 6LoWPAN network.
+
+use kernel::ErrorCode;
+
+/// Length of the Ethernet II header (two MAC addresses and the EtherType).
+pub const ETHERNET_HEADER_LEN: usize = 14;
+/// Largest frame (header and payload, without FCS) for a 1500 byte MTU.
+pub const ETHERNET_MAX_FRAME_LEN: usize = ETHERNET_HEADER_LEN + 1500;
+
+/// EtherType for IPv6 payloads.
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// A 48-bit Ethernet MAC address.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct EthernetAddress(pub [u8; 6]);
+
+impl EthernetAddress {
+    pub const BROADCAST: EthernetAddress = EthernetAddress([0xff; 6]);
+
+    /// Whether the group (multicast or broadcast) bit is set.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    /// The Ethernet address IPv6 multicast packets to `ip` are sent to
+    /// (RFC 2464, section 7).
+    pub fn from_ipv6_multicast(ip: &[u8; 16]) -> EthernetAddress {
+        EthernetAddress([0x33, 0x33, ip[12], ip[13], ip[14], ip[15]])
+    }
+}
+
+/// Ethernet II frame header.
+#[derive(Copy, Clone, Debug)]
+pub struct EthernetHeader {
+    pub dst: EthernetAddress,
+    pub src: EthernetAddress,
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    /// Parse the header at the start of `frame`, returning `None` if the frame
+    /// is too short.
+    pub fn decode(frame: &[u8]) -> Option<EthernetHeader> {
+        if frame.len() < ETHERNET_HEADER_LEN {
+            return None;
+        }
+        let mut dst = [0; 6];
+        let mut src = [0; 6];
+        dst.copy_from_slice(&frame[0..6]);
+        src.copy_from_slice(&frame[6..12]);
+        Some(EthernetHeader {
+            dst: EthernetAddress(dst),
+            src: EthernetAddress(src),
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+        })
+    }
+
+    /// Write the header to the start of `frame`, which must be at least
+    /// `ETHERNET_HEADER_LEN` bytes long.
+    pub fn encode(&self, frame: &mut [u8]) -> usize {
+        frame[0..6].copy_from_slice(&self.dst.0);
+        frame[6..12].copy_from_slice(&self.src.0);
+        frame[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+        ETHERNET_HEADER_LEN
+    }
+}
+
+/// A device that transmits and receives Ethernet frames.
+pub trait EthernetDevice<'a> {
+    fn set_client(&self, client: &'a dyn EthernetClient);
+
+    /// The device's own MAC address, used as the source of outgoing frames.
+    fn address(&self) -> EthernetAddress;
+
+    /// Whether the link is up, i.e. whether frames can currently be delivered.
+    fn link_up(&self) -> bool;
+
+    /// Send the first `len` bytes of `frame`. On success, `transmit_done` is
+    /// called with the buffer once the frame has been sent.
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
+
+/// Receives frames and transmit completions from an `EthernetDevice`.
+pub trait EthernetClient {
+    /// A complete frame was received. The slice is only valid for the
+    /// duration of the call.
+    fn frame_received(&self, frame: &[u8]);
+
+    fn transmit_done(&self, frame: &'static mut [u8], result: Result<(), ErrorCode>);
+}
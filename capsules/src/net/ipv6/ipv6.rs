@@ -500,14 +500,23 @@ impl<'a> IP6Packet<'a> {
     /// `transport_header` - The `TransportHeader` to be set as the next header
     /// `payload` - The transport payload to be copied into the `IPPayload`
     /// transport payload
+    ///
+    /// # Return Value
+    ///
+    /// Returns `Err(ErrorCode::SIZE)`, leaving the packet unchanged, if the
+    /// payload does not fit in the `IPPayload` buffer.
     pub fn set_payload(
         &mut self,
         transport_header: TransportHeader,
         payload: &LeasableBuffer<'static, u8>,
-    ) {
+    ) -> Result<(), ErrorCode> {
+        if payload.len() > self.payload.payload.len() {
+            return Err(ErrorCode::SIZE);
+        }
         let (next_header, payload_len) = self.payload.set_payload(transport_header, payload);
         self.header.set_next_header(next_header);
         self.header.set_payload_len(payload_len);
+        Ok(())
     }
 
     // TODO: Do we need a decode equivalent? I don't think so, but we might
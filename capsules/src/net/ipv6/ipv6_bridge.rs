@@ -0,0 +1,512 @@
+//! Bridges IPv6 between the 6LoWPAN network and an Ethernet link, so that a
+//! board can act as a border router for its 802.15.4 network.
+//!
+//! The bridge sits between `sixlowpan_state` and the node's own
+//! `IP6RecvStruct`, and is also the client of an `EthernetDevice` (for
+//! example the USB CDC-ECM class). Packets are routed as follows:
+//!
+//! - From 6LoWPAN: packets for one of the node's own addresses are passed to
+//!   the local receiver. Multicast packets are passed to the local receiver
+//!   and to the Ethernet link. Unicast packets for addresses outside the mesh
+//!   prefix are forwarded to the Ethernet link, addressed to the host's MAC
+//!   address (learned from the frames it sends). Link-local unicast packets
+//!   are never forwarded.
+//! - From Ethernet: Neighbor Solicitations for addresses inside the mesh
+//!   prefix are answered with a Neighbor Advertisement carrying our MAC
+//!   address (we proxy neighbor discovery for the mesh). Packets for the
+//!   node's own addresses or multicast groups are passed to the local
+//!   receiver. Unicast packets for other addresses inside the mesh prefix are
+//!   forwarded over 6LoWPAN using a dedicated `IP6Sender`.
+//!
+//! Forwarded packets have their hop limit decremented and are dropped when it
+//! reaches zero. Only one packet is forwarded in each direction at a time;
+//! packets arriving while a forward is in progress are dropped, as are
+//! packets the 6LoWPAN sender cannot encode (anything other than UDP and
+//! ICMPv6 echo and error messages) or whose payload does not fit in its
+//! packet buffer (`MAX_PAYLOAD_LEN` in the UDP component).
+//!
+//! Usage
+//! -----
+//!
+//! The bridge replaces the local `IP6RecvStruct` as the receive client of
+//! `sixlowpan_state`:
+//!
+//! ```rust
+//! let bridge = static_init!(
+//!     IP6Bridge<'static, CdcEcm<'static, UsbDevice>, IP6SendStruct<'static, Alarm>>,
+//!     IP6Bridge::new(
+//!         ecm,
+//!         forward_send,
+//!         ip_receive,
+//!         &LOCAL_IP_IFACES,
+//!         mesh_prefix,
+//!         64,
+//!         &mut capsules::net::ipv6::ipv6_bridge::ETHERNET_TX_BUF,
+//!         &mut capsules::net::ipv6::ipv6_bridge::FORWARD_BUF,
+//!     )
+//! );
+//! sixlowpan_state.set_rx_client(bridge);
+//! ecm.set_client(bridge);
+//! forward_send.set_client(bridge);
+//! ```
+
+use crate::net::ethernet::{EthernetAddress, EthernetClient, EthernetDevice, EthernetHeader};
+use crate::net::ethernet::{ETHERNET_HEADER_LEN, ETHERNET_MAX_FRAME_LEN, ETHERTYPE_IPV6};
+use crate::net::icmpv6::ICMP6Header;
+use crate::net::ipv6::ip_utils::{compute_sum, ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, TransportHeader};
+use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
+use crate::net::udp::UDPHeader;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ErrorCode;
+
+/// Length of the fixed IPv6 header.
+const IP6_HEADER_LEN: usize = 40;
+
+const ICMP_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMP_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+/// Neighbor Advertisement body: flags, reserved, target address, and a
+/// target link-layer address option.
+const NEIGHBOR_ADVERTISEMENT_LEN: usize = 32;
+
+/// Buffer for frames sent to the Ethernet link.
+pub static mut ETHERNET_TX_BUF: [u8; ETHERNET_MAX_FRAME_LEN] = [0; ETHERNET_MAX_FRAME_LEN];
+/// Buffer for transport payloads forwarded over 6LoWPAN.
+pub static mut FORWARD_BUF: [u8; ETHERNET_MAX_FRAME_LEN] = [0; ETHERNET_MAX_FRAME_LEN];
+
+pub struct IP6Bridge<'a, E: EthernetDevice<'a>, S: IP6Sender<'a>> {
+    ethernet: &'a E,
+    sender: &'a S,
+    /// Receiver for packets addressed to this node.
+    local: &'a dyn SixlowpanRxClient,
+    local_addrs: &'a [IPAddr],
+    mesh_prefix: IPAddr,
+    mesh_prefix_len: u8,
+    /// MAC address of the host, learned from the frames it sends.
+    host_mac: OptionalCell<EthernetAddress>,
+    ethernet_tx_buf: TakeCell<'static, [u8]>,
+    forward_buf: TakeCell<'static, [u8]>,
+    /// Whether a packet is being forwarded over 6LoWPAN.
+    forwarding: Cell<bool>,
+}
+
+impl<'a, E: EthernetDevice<'a>, S: IP6Sender<'a>> IP6Bridge<'a, E, S> {
+    pub fn new(
+        ethernet: &'a E,
+        sender: &'a S,
+        local: &'a dyn SixlowpanRxClient,
+        local_addrs: &'a [IPAddr],
+        mesh_prefix: IPAddr,
+        mesh_prefix_len: u8,
+        ethernet_tx_buf: &'static mut [u8],
+        forward_buf: &'static mut [u8],
+    ) -> IP6Bridge<'a, E, S> {
+        IP6Bridge {
+            ethernet,
+            sender,
+            local,
+            local_addrs,
+            mesh_prefix,
+            mesh_prefix_len,
+            host_mac: OptionalCell::empty(),
+            ethernet_tx_buf: TakeCell::new(ethernet_tx_buf),
+            forward_buf: TakeCell::new(forward_buf),
+            forwarding: Cell::new(false),
+        }
+    }
+
+    fn is_local(&self, addr: &IPAddr) -> bool {
+        self.local_addrs.iter().any(|a| a == addr)
+    }
+
+    fn in_mesh(&self, addr: &IPAddr) -> bool {
+        let full_bytes = (self.mesh_prefix_len / 8) as usize;
+        let remaining = self.mesh_prefix_len % 8;
+        if addr.0[..full_bytes] != self.mesh_prefix.0[..full_bytes] {
+            return false;
+        }
+        if remaining == 0 {
+            return true;
+        }
+        let mask = 0xffu8 << (8 - remaining);
+        addr.0[full_bytes] & mask == self.mesh_prefix.0[full_bytes] & mask
+    }
+
+    /// Send `packet`, a complete IPv6 packet, to the Ethernet link. The hop
+    /// limit is decremented if `forwarded` is set.
+    fn send_to_ethernet(&self, dst: EthernetAddress, packet: &[u8], forwarded: bool) {
+        if !self.ethernet.link_up() {
+            return;
+        }
+        let len = ETHERNET_HEADER_LEN + packet.len();
+        self.ethernet_tx_buf.take().map(|frame| {
+            if len > frame.len() {
+                self.ethernet_tx_buf.replace(frame);
+                return;
+            }
+            EthernetHeader {
+                dst,
+                src: self.ethernet.address(),
+                ethertype: ETHERTYPE_IPV6,
+            }
+            .encode(frame);
+            frame[ETHERNET_HEADER_LEN..len].copy_from_slice(packet);
+            if forwarded {
+                // Hop limit is byte 7 of the IPv6 header.
+                frame[ETHERNET_HEADER_LEN + 7] -= 1;
+            }
+            if let Err((_, frame)) = self.ethernet.transmit(frame, len) {
+                self.ethernet_tx_buf.replace(frame);
+            }
+        });
+    }
+
+    /// Forward an IPv6 packet received from the Ethernet link over 6LoWPAN.
+    fn send_to_mesh(&self, mut header: IP6Header, packet: &[u8]) {
+        if self.forwarding.get() {
+            return;
+        }
+        let transport = &packet[IP6_HEADER_LEN..];
+        let (transport_header, header_len) = match header.get_next_header() {
+            ip6_nh::UDP => match UDPHeader::decode(transport).done() {
+                Some((offset, udp_header)) => (TransportHeader::UDP(udp_header), offset),
+                None => return,
+            },
+            ip6_nh::ICMP => match ICMP6Header::decode(transport).done() {
+                Some((offset, icmp_header)) => (TransportHeader::ICMP(icmp_header), offset),
+                None => return,
+            },
+            // The 6LoWPAN sender cannot encode other transport protocols.
+            _ => return,
+        };
+        let payload = &transport[header_len..];
+        header.set_hop_limit(header.get_hop_limit() - 1);
+
+        self.forward_buf.take().map(|buf| {
+            if payload.len() > buf.len() {
+                self.forward_buf.replace(buf);
+                return;
+            }
+            buf[..payload.len()].copy_from_slice(payload);
+            let mut lease = LeasableBuffer::new(buf);
+            lease.slice(0..payload.len());
+            // The sender copies the payload, so we can take the buffer back
+            // straight away. It refuses payloads larger than its packet
+            // buffer, which drops the frame.
+            let result = self.sender.send_packet(header, transport_header, &lease);
+            self.forward_buf.replace(lease.take());
+            self.forwarding.set(result == Ok(()));
+        });
+    }
+
+    /// Answer a Neighbor Solicitation from the host if its target is inside
+    /// the mesh, so that the host sends packets for the mesh to us.
+    ///
+    /// Returns whether `packet` was a Neighbor Solicitation.
+    fn proxy_neighbor_solicitation(
+        &self,
+        src_mac: EthernetAddress,
+        header: &IP6Header,
+        packet: &[u8],
+    ) -> bool {
+        let icmp = &packet[IP6_HEADER_LEN..];
+        if header.get_next_header() != ip6_nh::ICMP
+            || icmp.len() < 24
+            || icmp[0] != ICMP_NEIGHBOR_SOLICITATION
+        {
+            return false;
+        }
+        let mut target = IPAddr::new();
+        target.0.copy_from_slice(&icmp[8..24]);
+        // Messages that may have been forwarded by a router are invalid.
+        if header.get_hop_limit() != 255 || !(self.in_mesh(&target) || self.is_local(&target)) {
+            return true;
+        }
+
+        let solicitor = header.get_src_addr();
+        let (dst_mac, dst_addr, solicited) = if solicitor.is_unspecified() {
+            // Duplicate address detection: reply to all nodes.
+            let mut all_nodes = IPAddr::new();
+            all_nodes.0[0] = 0xff;
+            all_nodes.0[1] = 0x02;
+            all_nodes.0[15] = 0x01;
+            (
+                EthernetAddress::from_ipv6_multicast(&all_nodes.0),
+                all_nodes,
+                false,
+            )
+        } else {
+            (src_mac, solicitor, true)
+        };
+
+        let mut packet = [0; IP6_HEADER_LEN + NEIGHBOR_ADVERTISEMENT_LEN];
+        let mut reply = IP6Header::new();
+        reply.set_next_header(ip6_nh::ICMP);
+        reply.set_payload_len(NEIGHBOR_ADVERTISEMENT_LEN as u16);
+        reply.src_addr = target;
+        reply.dst_addr = dst_addr;
+        if reply.encode(&mut packet).done().is_none() {
+            return true;
+        }
+
+        let icmp = &mut packet[IP6_HEADER_LEN..];
+        icmp[0] = ICMP_NEIGHBOR_ADVERTISEMENT;
+        // Router flag, and solicited flag when answering a node. As a proxy
+        // we must not set the override flag.
+        icmp[4] = 0x80 | if solicited { 0x40 } else { 0 };
+        icmp[8..24].copy_from_slice(&target.0);
+        // Target link-layer address option.
+        icmp[24] = 2;
+        icmp[25] = 1;
+        icmp[26..32].copy_from_slice(&self.ethernet.address().0);
+        let cksum = icmp_checksum(&target, &dst_addr, icmp);
+        icmp[2..4].copy_from_slice(&cksum.to_be_bytes());
+
+        self.send_to_ethernet(dst_mac, &packet, false);
+        true
+    }
+}
+
+/// Compute the ICMPv6 checksum of `icmp`, whose checksum field is zero.
+fn icmp_checksum(src: &IPAddr, dst: &IPAddr, icmp: &[u8]) -> u16 {
+    let mut sum = compute_sum(&src.0, 16) + compute_sum(&dst.0, 16);
+    sum += icmp.len() as u32 + ip6_nh::ICMP as u32;
+    sum += compute_sum(icmp, icmp.len() as u16);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+impl<'a, E: EthernetDevice<'a>, S: IP6Sender<'a>> SixlowpanRxClient for IP6Bridge<'a, E, S> {
+    fn receive(&self, buf: &[u8], len: usize, result: Result<(), ErrorCode>) {
+        if len > buf.len() || result != Ok(()) {
+            return;
+        }
+        let header = match IP6Header::decode(buf).done() {
+            Some((_, header)) => header,
+            None => return,
+        };
+        let dst = header.get_dst_addr();
+
+        if dst.is_multicast() {
+            self.local.receive(buf, len, result);
+            self.send_to_ethernet(
+                EthernetAddress::from_ipv6_multicast(&dst.0),
+                &buf[..len],
+                false,
+            );
+        } else if self.is_local(&dst) {
+            self.local.receive(buf, len, result);
+        } else if !self.in_mesh(&dst) && !dst.is_unicast_link_local() && header.get_hop_limit() > 1
+        {
+            self.host_mac
+                .map(|host_mac| self.send_to_ethernet(*host_mac, &buf[..len], true));
+        }
+    }
+}
+
+impl<'a, E: EthernetDevice<'a>, S: IP6Sender<'a>> EthernetClient for IP6Bridge<'a, E, S> {
+    fn frame_received(&self, frame: &[u8]) {
+        let eth_header = match EthernetHeader::decode(frame) {
+            Some(eth_header) if eth_header.ethertype == ETHERTYPE_IPV6 => eth_header,
+            _ => return,
+        };
+        if !eth_header.src.is_multicast() {
+            self.host_mac.set(eth_header.src);
+        }
+
+        let packet = &frame[ETHERNET_HEADER_LEN..];
+        let header = match IP6Header::decode(packet).done() {
+            Some((_, header)) => header,
+            None => return,
+        };
+        let len = IP6_HEADER_LEN + header.get_payload_len() as usize;
+        if len > packet.len() {
+            return;
+        }
+        let packet = &packet[..len];
+
+        if self.proxy_neighbor_solicitation(eth_header.src, &header, packet) {
+            return;
+        }
+
+        let dst = header.get_dst_addr();
+        if dst.is_multicast() || self.is_local(&dst) {
+            self.local.receive(packet, len, Ok(()));
+        } else if self.in_mesh(&dst) && header.get_hop_limit() > 1 {
+            self.send_to_mesh(header, packet);
+        }
+    }
+
+    fn transmit_done(&self, frame: &'static mut [u8], _result: Result<(), ErrorCode>) {
+        self.ethernet_tx_buf.replace(frame);
+    }
+}
+
+impl<'a, E: EthernetDevice<'a>, S: IP6Sender<'a>> IP6SendClient for IP6Bridge<'a, E, S> {
+    fn send_done(&self, _result: Result<(), ErrorCode>) {
+        self.forwarding.set(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::net::ieee802154::MacAddress;
+    use crate::net::ipv6::{IP6Packet, IPPayload};
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    struct MockEthernet;
+
+    impl<'a> EthernetDevice<'a> for MockEthernet {
+        fn set_client(&self, _client: &'a dyn EthernetClient) {}
+
+        fn address(&self) -> EthernetAddress {
+            EthernetAddress([0x02, 0, 0, 0, 0, 0x0b])
+        }
+
+        fn link_up(&self) -> bool {
+            true
+        }
+
+        fn transmit(
+            &self,
+            frame: &'static mut [u8],
+            _len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::OFF, frame))
+        }
+    }
+
+    /// Copies packets into an `IP6Packet` with a small payload buffer, as
+    /// `IP6SendStruct` does.
+    struct MockSender {
+        packet: RefCell<IP6Packet<'static>>,
+        sent: Cell<usize>,
+    }
+
+    impl<'a> IP6Sender<'a> for MockSender {
+        fn set_client(&self, _client: &'a dyn IP6SendClient) {}
+
+        fn set_addr(&self, _src_addr: IPAddr) {}
+
+        fn set_gateway(&self, _gateway: MacAddress) {}
+
+        fn set_header(&mut self, _ip6_header: IP6Header) {}
+
+        fn send_to(
+            &self,
+            _dst: IPAddr,
+            _transport_header: TransportHeader,
+            _payload: &LeasableBuffer<'static, u8>,
+            _net_cap: &'static crate::net::network_capabilities::NetworkCapability,
+        ) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn send_packet(
+            &self,
+            ip6_header: IP6Header,
+            transport_header: TransportHeader,
+            payload: &LeasableBuffer<'static, u8>,
+        ) -> Result<(), ErrorCode> {
+            let mut packet = self.packet.borrow_mut();
+            packet.header = ip6_header;
+            packet.set_payload(transport_header, payload)?;
+            self.sent.set(self.sent.get() + 1);
+            Ok(())
+        }
+    }
+
+    struct MockLocal;
+
+    impl SixlowpanRxClient for MockLocal {
+        fn receive<'a>(&self, _buf: &'a [u8], _len: usize, _result: Result<(), ErrorCode>) {}
+    }
+
+    /// Sender payload capacity, as `MAX_PAYLOAD_LEN` in the UDP component.
+    const PAYLOAD_CAPACITY: usize = 200;
+
+    fn bridge() -> &'static IP6Bridge<'static, MockEthernet, MockSender> {
+        let payload: &'static mut [u8] = Box::leak(Box::new([0; PAYLOAD_CAPACITY]));
+        let sender = Box::leak(Box::new(MockSender {
+            packet: RefCell::new(IP6Packet::new(IPPayload::new(
+                TransportHeader::UDP(UDPHeader::new()),
+                payload,
+            ))),
+            sent: Cell::new(0),
+        }));
+        let mut mesh_prefix = IPAddr::new();
+        mesh_prefix.0[..8].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 1]);
+        Box::leak(Box::new(IP6Bridge::new(
+            Box::leak(Box::new(MockEthernet)),
+            sender,
+            Box::leak(Box::new(MockLocal)),
+            &[],
+            mesh_prefix,
+            64,
+            Box::leak(Box::new([0; ETHERNET_MAX_FRAME_LEN])),
+            Box::leak(Box::new([0; ETHERNET_MAX_FRAME_LEN])),
+        )))
+    }
+
+    /// An Ethernet frame holding a UDP packet for a mesh address.
+    fn udp_frame(payload_len: usize) -> Vec<u8> {
+        let udp_len = 8 + payload_len;
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + IP6_HEADER_LEN + udp_len];
+        EthernetHeader {
+            dst: EthernetAddress([0x02, 0, 0, 0, 0, 0x0b]),
+            src: EthernetAddress([0x02, 0, 0, 0, 0, 0x0a]),
+            ethertype: ETHERTYPE_IPV6,
+        }
+        .encode(&mut frame);
+        let mut header = IP6Header::new();
+        header.set_next_header(ip6_nh::UDP);
+        header.set_payload_len(udp_len as u16);
+        header.src_addr.0[..2].copy_from_slice(&[0xfd, 0x02]);
+        header.dst_addr.0[..8].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 1]);
+        header.dst_addr.0[15] = 2;
+        header
+            .encode(&mut frame[ETHERNET_HEADER_LEN..])
+            .done()
+            .unwrap();
+        let mut udp = UDPHeader::new();
+        udp.set_src_port(1000);
+        udp.set_dst_port(2000);
+        udp.set_len(udp_len as u16);
+        udp.encode(&mut frame, ETHERNET_HEADER_LEN + IP6_HEADER_LEN)
+            .done()
+            .unwrap();
+        frame
+    }
+
+    #[test]
+    fn forwards_to_mesh() {
+        let bridge = bridge();
+        bridge.frame_received(&udp_frame(PAYLOAD_CAPACITY));
+        assert_eq!(bridge.sender.sent.get(), 1);
+        assert!(bridge.forwarding.get());
+    }
+
+    #[test]
+    fn drops_oversize_frame() {
+        let bridge = bridge();
+        bridge.frame_received(&udp_frame(PAYLOAD_CAPACITY + 1));
+        bridge.frame_received(&udp_frame(1200));
+        assert_eq!(bridge.sender.sent.get(), 0);
+        assert!(!bridge.forwarding.get());
+
+        // The bridge still forwards packets that fit.
+        bridge.frame_received(&udp_frame(10));
+        assert_eq!(bridge.sender.sent.get(), 1);
+    }
+}
@@ -86,6 +86,25 @@ pub trait IP6Sender<'a> {
         payload: &LeasableBuffer<'static, u8>,
         net_cap: &'static NetworkCapability,
     ) -> Result<(), ErrorCode>;
+
+    /// This method sends the provided transport header and payload with the
+    /// given IPv6 header, rather than one built from the configured source
+    /// address. It is used to forward packets that did not originate at this
+    /// node, so the source address and hop limit of `ip6_header` are kept.
+    ///
+    /// # Arguments
+    /// `ip6_header` - The `IP6Header` for the packet being sent
+    /// `transport_header` - The `TransportHeader` for the packet being sent
+    /// `payload` - The transport payload for the packet being sent
+    ///
+    /// Returns `Err(ErrorCode::SIZE)` if the payload is larger than the
+    /// sender's packet buffer.
+    fn send_packet(
+        &self,
+        ip6_header: IP6Header,
+        transport_header: TransportHeader,
+        payload: &LeasableBuffer<'static, u8>,
+    ) -> Result<(), ErrorCode>;
 }
 
 /// This struct is a specific implementation of the `IP6Sender` trait. This
@@ -141,10 +160,33 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
             self.radio.get_pan(),
             None,
         );
-        self.init_packet(dst, transport_header, payload);
+        self.init_packet(dst, transport_header, payload)?;
         let ret = self.send_next_fragment();
         ret
     }
+
+    fn send_packet(
+        &self,
+        ip6_header: IP6Header,
+        transport_header: TransportHeader,
+        payload: &LeasableBuffer<'static, u8>,
+    ) -> Result<(), ErrorCode> {
+        let _ = self.sixlowpan.init(
+            self.src_mac_addr,
+            self.dst_mac_addr,
+            self.radio.get_pan(),
+            None,
+        );
+        self.ip6_packet
+            .map(|ip6_packet| {
+                ip6_packet.header = ip6_header;
+                ip6_packet.set_payload(transport_header, payload)?;
+                ip6_packet.set_transport_checksum();
+                Ok(())
+            })
+            .unwrap_or(Err(ErrorCode::NOMEM))?;
+        self.send_next_fragment()
+    }
 }
 
 impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
@@ -178,19 +220,21 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
         dst_addr: IPAddr,
         transport_header: TransportHeader,
         payload: &LeasableBuffer<'static, u8>,
-    ) {
+    ) -> Result<(), ErrorCode> {
         self.ip6_packet.map_or_else(
             || {
                 debug!("init packet failed.");
+                Err(ErrorCode::NOMEM)
             },
             |ip6_packet| {
                 ip6_packet.header = IP6Header::default();
                 ip6_packet.header.src_addr = self.src_addr.get();
                 ip6_packet.header.dst_addr = dst_addr;
-                ip6_packet.set_payload(transport_header, payload);
+                ip6_packet.set_payload(transport_header, payload)?;
                 ip6_packet.set_transport_checksum();
+                Ok(())
             },
-        );
+        )
     }
 
     // Returns BUSY if the tx_buf is not there
@@ -1,4 +1,5 @@
 pub mod ip_utils;
+pub mod ipv6_bridge;
 pub mod ipv6_recv;
 pub mod ipv6_send;
 
@@ -1,9 +1,20 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! The memory provided to userland is divided into per-app regions. Each app
+//! can only read and write its own region, and sees that region as starting at
+//! address 0. The region for an app is allocated the first time the app
+//! accesses storage. Its size is the `storage_size` the app requested with the
+//! persistent storage TLV in its TBF header, or, if the app made no request, a
+//! default size chosen by the board.
+//!
+//! Regions are identified by a storage identifier the board grants to each app
+//! by package name. Apps the board does not grant an identifier to cannot use
+//! the userspace interface, and an app whose persistent storage TLV names a
+//! different identifier than the one granted is refused, so an app cannot
+//! pick another app's region. The allocations are recorded in a region table
+//! kept in the first `REGION_TABLE_LENGTH` bytes of the userspace region, so
+//! an app is given the same region after a reboot or after it is updated. The
+//! userspace region must be at least that long.
 //!
 //! However, the kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
@@ -39,6 +50,12 @@
 //! ```rust
 //! # use kernel::static_init;
 //!
+//! static STORAGE_GRANTS: [capsules::nonvolatile_storage_driver::StorageGrant; 1] =
+//!     [capsules::nonvolatile_storage_driver::StorageGrant {
+//!         package_name: "sensor_log",
+//!         storage_id: 1,
+//!     }];
+//!
 //! let nonvolatile_storage = static_init!(
 //!     capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
 //!     capsules::nonvolatile_storage_driver::NonvolatileStorage::new(
@@ -50,12 +67,17 @@
 //!         0,                           // The byte start address of the region
 //!                                      // that is accessible by the kernel.
 //!         3000,                        // The length of the kernel region.
-//!         &mut capsules::nonvolatile_storage_driver::BUFFER));
+//!         256,                         // The region size for apps that do not
+//!                                      // request a size in their TBF header.
+//!         &STORAGE_GRANTS,             // The apps that may use userspace storage.
+//!         &mut capsules::nonvolatile_storage_driver::BUFFER,
+//!         &mut capsules::nonvolatile_storage_driver::REGION_TABLE));
 //! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
 //! ```
 
 use core::cell::Cell;
 use core::cmp;
+use core::convert::TryInto;
 use core::mem;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil;
@@ -71,6 +93,19 @@ pub const DRIVER_NUM: usize = driver::NUM::NvmStorage as usize;
 
 pub static mut BUFFER: [u8; 512] = [0; 512];
 
+/// Number of bytes at the start of the userspace region that hold the table of
+/// per-app region allocations.
+pub const REGION_TABLE_LENGTH: usize = 512;
+
+pub static mut REGION_TABLE: [u8; REGION_TABLE_LENGTH] = [0; REGION_TABLE_LENGTH];
+
+/// Marks a valid region table in storage ("NVRT").
+const REGION_TABLE_MAGIC: u32 = 0x5452564e;
+/// The table starts with the magic value and the number of entries.
+const REGION_TABLE_HEADER_LENGTH: usize = 8;
+/// Each entry holds the storage identifier, offset and length of a region.
+const REGION_ENTRY_LENGTH: usize = 12;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -83,6 +118,23 @@ pub enum NonvolatileCommand {
 pub enum NonvolatileUser {
     App { app_id: ProcessId },
     Kernel,
+    RegionTable,
+}
+
+/// Grants the app with `package_name` the region of userspace storage
+/// identified by `storage_id`.
+#[derive(Clone, Copy)]
+pub struct StorageGrant {
+    pub package_name: &'static str,
+    pub storage_id: u32,
+}
+
+/// The part of the userspace storage allocated to one app. The offset is
+/// relative to the first byte after the region table.
+#[derive(Clone, Copy)]
+pub struct AppRegion {
+    offset: usize,
+    length: usize,
 }
 
 pub struct App {
@@ -90,6 +142,7 @@ pub struct App {
     command: NonvolatileCommand,
     offset: usize,
     length: usize,
+    region: Option<AppRegion>,
     buffer_read: ReadWriteProcessBuffer,
     buffer_write: ReadOnlyProcessBuffer,
 }
@@ -101,6 +154,7 @@ impl Default for App {
             command: NonvolatileCommand::UserspaceRead,
             offset: 0,
             length: 0,
+            region: None,
             buffer_read: ReadWriteProcessBuffer::default(),
             buffer_write: ReadOnlyProcessBuffer::default(),
         }
@@ -127,6 +181,16 @@ pub struct NonvolatileStorage<'a> {
     // How many bytes allocated to kernel.
     kernel_length: usize,
 
+    // How many bytes to allocate to apps that do not request a size.
+    default_region_length: usize,
+    // The storage identifiers granted to apps by the board.
+    storage_grants: &'static [StorageGrant],
+    // Copy of the region table, also used as the buffer to read and write the
+    // table from storage.
+    region_table: TakeCell<'static, [u8]>,
+    // Whether the region table has been read from storage.
+    region_table_loaded: Cell<bool>,
+
     // Optional client for the kernel. Only needed if the kernel intends to use
     // this nonvolatile storage.
     kernel_client:
@@ -143,6 +207,127 @@ pub struct NonvolatileStorage<'a> {
     kernel_readwrite_address: Cell<usize>,
 }
 
+// Helpers for accessing the region table. The table is stored in the same
+// little-endian layout in RAM and in storage.
+
+fn region_table_read_u32(table: &[u8], index: usize) -> u32 {
+    table
+        .get(index..index + 4)
+        .and_then(|b| b.try_into().ok())
+        .map_or(0, u32::from_le_bytes)
+}
+
+fn region_table_write_u32(table: &mut [u8], index: usize, value: u32) {
+    if let Some(b) = table.get_mut(index..index + 4) {
+        b.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn region_table_capacity(table: &[u8]) -> usize {
+    table.len().saturating_sub(REGION_TABLE_HEADER_LENGTH) / REGION_ENTRY_LENGTH
+}
+
+fn region_table_entries(table: &[u8]) -> usize {
+    cmp::min(
+        region_table_read_u32(table, 4) as usize,
+        region_table_capacity(table),
+    )
+}
+
+fn region_table_entry(table: &[u8], entry: usize) -> (u32, AppRegion) {
+    let index = REGION_TABLE_HEADER_LENGTH + entry * REGION_ENTRY_LENGTH;
+    (
+        region_table_read_u32(table, index),
+        AppRegion {
+            offset: region_table_read_u32(table, index + 4) as usize,
+            length: region_table_read_u32(table, index + 8) as usize,
+        },
+    )
+}
+
+// Find the region of the app with `storage_id` in the table, or allocate one
+// of `length` bytes after all existing regions. Regions must lie within the
+// first `available` bytes after the table. The table comes from storage, so
+// its entries are checked against that before they are used.
+//
+// Returns the region, and whether it was newly allocated, in which case the
+// table must be written back to storage.
+fn region_table_allocate(
+    table: &mut [u8],
+    storage_id: u32,
+    length: usize,
+    available: usize,
+) -> Result<(AppRegion, bool), ErrorCode> {
+    let entries = region_table_entries(table);
+    let mut next_free = 0;
+    let mut corrupt = false;
+    for entry in 0..entries {
+        let (id, region) = region_table_entry(table, entry);
+        let end = region
+            .offset
+            .checked_add(region.length)
+            .filter(|&end| end <= available);
+        match end {
+            Some(_) if id == storage_id => return Ok((region, false)),
+            Some(end) => next_free = cmp::max(next_free, end),
+            None if id == storage_id => return Err(ErrorCode::FAIL),
+            // Where the free space starts is unknown, so nothing more can be
+            // allocated. Apps with valid regions can still use them.
+            None => corrupt = true,
+        }
+    }
+    if corrupt {
+        return Err(ErrorCode::FAIL);
+    }
+
+    // This app does not have a region yet, so allocate one after all existing
+    // regions.
+    if length == 0 {
+        return Err(ErrorCode::NOSUPPORT);
+    }
+    let fits = next_free
+        .checked_add(length)
+        .map_or(false, |end| end <= available);
+    if entries >= region_table_capacity(table) || !fits || length > u32::MAX as usize {
+        return Err(ErrorCode::NOMEM);
+    }
+
+    let index = REGION_TABLE_HEADER_LENGTH + entries * REGION_ENTRY_LENGTH;
+    region_table_write_u32(table, index, storage_id);
+    region_table_write_u32(table, index + 4, next_free as u32);
+    region_table_write_u32(table, index + 8, length as u32);
+    region_table_write_u32(table, 4, (entries + 1) as u32);
+    Ok((
+        AppRegion {
+            offset: next_free,
+            length: length,
+        },
+        true,
+    ))
+}
+
+// The storage identifier the board granted to the app with package `name`.
+// If the app names an identifier in its TBF header as well, it must be the
+// granted one.
+fn granted_storage_id(grants: &[StorageGrant], name: &str, requested: Option<u32>) -> Option<u32> {
+    let granted = grants
+        .iter()
+        .find(|grant| grant.package_name == name)?
+        .storage_id;
+    match requested {
+        Some(storage_id) if storage_id != granted => None,
+        _ => Some(granted),
+    }
+}
+
+// The upcall that signals completion of a userspace command.
+fn upcall_number(command: NonvolatileCommand) -> usize {
+    match command {
+        NonvolatileCommand::UserspaceWrite => 1,
+        _ => 0,
+    }
+}
+
 impl<'a> NonvolatileStorage<'a> {
     pub fn new(
         driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
@@ -151,8 +336,14 @@ impl<'a> NonvolatileStorage<'a> {
         userspace_length: usize,
         kernel_start_address: usize,
         kernel_length: usize,
+        default_region_length: usize,
+        storage_grants: &'static [StorageGrant],
         buffer: &'static mut [u8],
+        region_table: &'static mut [u8],
     ) -> NonvolatileStorage<'a> {
+        if userspace_length < REGION_TABLE_LENGTH {
+            panic!("NonvolatileStorage: userspace region is smaller than the region table");
+        }
         NonvolatileStorage {
             driver: driver,
             apps: grant,
@@ -162,6 +353,10 @@ impl<'a> NonvolatileStorage<'a> {
             userspace_length: userspace_length,
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
+            default_region_length: default_region_length,
+            storage_grants: storage_grants,
+            region_table: TakeCell::new(region_table),
+            region_table_loaded: Cell::new(false),
             kernel_client: OptionalCell::empty(),
             kernel_pending_command: Cell::new(false),
             kernel_command: Cell::new(NonvolatileCommand::KernelRead),
@@ -171,6 +366,107 @@ impl<'a> NonvolatileStorage<'a> {
         }
     }
 
+    // The identifier used to find the region of an app in the region table.
+    fn storage_id(&self, appid: ProcessId) -> Option<u32> {
+        granted_storage_id(
+            self.storage_grants,
+            appid.get_process_name()?,
+            appid
+                .get_persistent_storage_request()
+                .map(|(storage_id, _)| storage_id),
+        )
+    }
+
+    // The number of bytes to allocate to an app that does not have a region
+    // yet.
+    fn requested_region_length(&self, appid: ProcessId) -> usize {
+        appid
+            .get_persistent_storage_request()
+            .map_or(self.default_region_length, |(_, storage_size)| {
+                storage_size as usize
+            })
+    }
+
+    // The number of bytes accessible to an app. This is the length of its
+    // region if it has one, or the length its region will have once allocated.
+    fn region_length(&self, app: &App, appid: ProcessId) -> usize {
+        app.region
+            .map_or_else(|| self.requested_region_length(appid), |r| r.length)
+    }
+
+    // Start reading the region table from storage.
+    fn load_region_table(&self) -> Result<(), ErrorCode> {
+        self.region_table
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |table| {
+                self.current_user.set(NonvolatileUser::RegionTable);
+                let length = table.len();
+                self.driver
+                    .read(table, self.userspace_start_address, length)
+                    .map_err(|(e, table)| {
+                        self.region_table.replace(table);
+                        self.current_user.clear();
+                        e
+                    })
+            })
+    }
+
+    // Start writing the region table back to storage.
+    fn store_region_table(&self) -> Result<(), ErrorCode> {
+        self.region_table
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |table| {
+                self.current_user.set(NonvolatileUser::RegionTable);
+                let length = table.len();
+                self.driver
+                    .write(table, self.userspace_start_address, length)
+                    .map_err(|(e, table)| {
+                        self.region_table.replace(table);
+                        self.current_user.clear();
+                        e
+                    })
+            })
+    }
+
+    // Make sure the region for this app is known.
+    //
+    // Returns `Ok(true)` if `app.region` is set. Returns `Ok(false)` if the
+    // region table must first be loaded or updated, in which case that
+    // operation has been started if the storage was idle. Returns an error if
+    // the app cannot be given a region.
+    //
+    // A newly allocated region is only set once the table holding it has been
+    // written to storage, when this is called again from `check_queue()`.
+    fn resolve_region(&self, app: &mut App, appid: ProcessId) -> Result<bool, ErrorCode> {
+        if app.region.is_some() {
+            return Ok(true);
+        }
+        if self.current_user.is_some() {
+            return Ok(false);
+        }
+        if !self.region_table_loaded.get() {
+            return self.load_region_table().map(|()| false);
+        }
+
+        let storage_id = self.storage_id(appid).ok_or(ErrorCode::NOSUPPORT)?;
+        let length = self.requested_region_length(appid);
+        let available = self.userspace_length.saturating_sub(REGION_TABLE_LENGTH);
+        let (region, allocated) = self.region_table.map_or(Err(ErrorCode::RESERVE), |table| {
+            region_table_allocate(table, storage_id, length, available)
+        })?;
+        if allocated {
+            // Persist the new allocation before the app can use the region.
+            self.store_region_table().map(|()| false).map_err(|e| {
+                // Drop the allocation by reading the table back.
+                self.region_table_loaded.set(false);
+                e
+            })
+        } else {
+            app.region = Some(region);
+            Ok(true)
+        }
+    }
+
     // Check so see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending
     // command completes.
@@ -181,38 +477,26 @@ impl<'a> NonvolatileStorage<'a> {
         length: usize,
         app_id: Option<ProcessId>,
     ) -> Result<(), ErrorCode> {
-        // Do bounds check.
-        match command {
-            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
-                {
-                    return Err(ErrorCode::INVAL);
-                }
-            }
-            NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
-                // Because the kernel uses the NonvolatileStorage interface,
-                // its calls are absolute addresses.
-                if offset < self.kernel_start_address
-                    || offset >= self.kernel_start_address + self.kernel_length
-                    || length > self.kernel_length
-                    || offset + length > self.kernel_start_address + self.kernel_length
-                {
-                    return Err(ErrorCode::INVAL);
-                }
-            }
-        }
-
-        // Do very different actions if this is a call from userspace
-        // or from the kernel.
         match command {
             NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
                 app_id.map_or(Err(ErrorCode::FAIL), |appid| {
                     self.apps
                         .enter(appid, |app, _| {
+                            // Userspace sees its region as starting at address
+                            // 0. If the region has not been looked up yet, this
+                            // checks against the size it would be allocated
+                            // with, and the check is repeated before the
+                            // command runs.
+                            let region_length = self.region_length(app, appid);
+                            if offset >= region_length
+                                || length > region_length
+                                || offset
+                                    .checked_add(length)
+                                    .map_or(true, |end| end > region_length)
+                            {
+                                return Err(ErrorCode::INVAL);
+                            }
+
                             // Get the length of the correct allowed buffer.
                             let allow_buf_len = match command {
                                 NonvolatileCommand::UserspaceRead => app.buffer_read.len(),
@@ -229,99 +513,123 @@ impl<'a> NonvolatileStorage<'a> {
                             // put it.
                             let active_len = cmp::min(length, allow_buf_len);
 
-                            // First need to determine if we can execute this or must
-                            // queue it.
-                            if self.current_user.is_none() {
-                                // No app is currently using the underlying storage.
-                                // Mark this app as active, and then execute the command.
-                                self.current_user
-                                    .set(NonvolatileUser::App { app_id: appid });
-
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    let _ = app.buffer_write.enter(|app_buffer| {
-                                        self.buffer.map(|kernel_buffer| {
-                                            // Check that the internal buffer and the buffer that was
-                                            // allowed are long enough.
-                                            let write_len =
-                                                cmp::min(active_len, kernel_buffer.len());
-
-                                            let d = &app_buffer[0..write_len];
-                                            for (i, c) in
-                                                kernel_buffer[0..write_len].iter_mut().enumerate()
-                                            {
-                                                *c = d[i].get();
-                                            }
-                                        });
-                                    });
-                                }
-
-                                self.userspace_call_driver(command, offset, active_len)
+                            // Only one command per app can be outstanding.
+                            if app.pending_command == true {
+                                // No more room in the queue, nowhere to store this
+                                // request.
+                                return Err(ErrorCode::NOMEM);
+                            }
+
+                            // The command runs now if the storage is idle and
+                            // the region of this app is known. Otherwise, it is
+                            // queued and started from `check_queue()`.
+                            if self.resolve_region(app, appid)? && self.current_user.is_none() {
+                                self.userspace_call_driver(app, appid, command, offset, active_len)
                             } else {
-                                // Some app is using the storage, we must wait.
-                                if app.pending_command == true {
-                                    // No more room in the queue, nowhere to store this
-                                    // request.
-                                    Err(ErrorCode::NOMEM)
-                                } else {
-                                    // We can store this, so lets do it.
-                                    app.pending_command = true;
-                                    app.command = command;
-                                    app.offset = offset;
-                                    app.length = active_len;
-                                    Ok(())
-                                }
+                                app.pending_command = true;
+                                app.command = command;
+                                app.offset = offset;
+                                app.length = active_len;
+                                Ok(())
                             }
                         })
                         .unwrap_or_else(|err| Err(err.into()))
                 })
             }
+            // Kernel commands go through `enqueue_kernel_command()`.
             NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
-                self.kernel_buffer
-                    .take()
-                    .map_or(Err(ErrorCode::NOMEM), |kernel_buffer| {
-                        let active_len = cmp::min(length, kernel_buffer.len());
-
-                        // Check if there is something going on.
-                        if self.current_user.is_none() {
-                            // Nothing is using this, lets go!
-                            self.current_user.set(NonvolatileUser::Kernel);
-
-                            match command {
-                                NonvolatileCommand::KernelRead => {
-                                    self.driver.read(kernel_buffer, offset, active_len)
-                                }
-                                NonvolatileCommand::KernelWrite => {
-                                    self.driver.write(kernel_buffer, offset, active_len)
-                                }
-                                _ => Err(ErrorCode::FAIL),
-                            }
-                        } else {
-                            if self.kernel_pending_command.get() == true {
-                                Err(ErrorCode::NOMEM)
-                            } else {
-                                self.kernel_pending_command.set(true);
-                                self.kernel_command.set(command);
-                                self.kernel_readwrite_length.set(active_len);
-                                self.kernel_readwrite_address.set(offset);
-                                self.kernel_buffer.replace(kernel_buffer);
-                                Ok(())
-                            }
-                        }
-                    })
+                Err(ErrorCode::FAIL)
             }
         }
     }
 
+    // Run a command from the kernel now if the storage is idle, or queue it to
+    // be run from `check_queue()`. If the command cannot be started or
+    // queued, the buffer is handed back.
+    fn enqueue_kernel_command(
+        &self,
+        command: NonvolatileCommand,
+        buffer: &'static mut [u8],
+        offset: usize,
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        // Because the kernel uses the NonvolatileStorage interface, its calls
+        // are absolute addresses.
+        let kernel_end = match self.kernel_start_address.checked_add(self.kernel_length) {
+            Some(kernel_end) => kernel_end,
+            None => return Err((ErrorCode::INVAL, buffer)),
+        };
+        if offset < self.kernel_start_address
+            || offset >= kernel_end
+            || length > self.kernel_length
+            || offset
+                .checked_add(length)
+                .map_or(true, |end| end > kernel_end)
+        {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+
+        let active_len = cmp::min(length, buffer.len());
+
+        // Check if there is something going on.
+        if self.current_user.is_none() {
+            // Nothing is using this, lets go!
+            self.kernel_call_driver(command, buffer, offset, active_len)
+        } else if self.kernel_pending_command.get() {
+            Err((ErrorCode::NOMEM, buffer))
+        } else {
+            self.kernel_pending_command.set(true);
+            self.kernel_command.set(command);
+            self.kernel_readwrite_length.set(active_len);
+            self.kernel_readwrite_address.set(offset);
+            self.kernel_buffer.replace(buffer);
+            Ok(())
+        }
+    }
+
+    // Start a kernel command. The storage must be idle.
+    fn kernel_call_driver(
+        &self,
+        command: NonvolatileCommand,
+        buffer: &'static mut [u8],
+        offset: usize,
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.current_user.set(NonvolatileUser::Kernel);
+        let res = match command {
+            NonvolatileCommand::KernelRead => self.driver.read(buffer, offset, length),
+            NonvolatileCommand::KernelWrite => self.driver.write(buffer, offset, length),
+            _ => Err((ErrorCode::FAIL, buffer)),
+        };
+        if res.is_err() {
+            self.current_user.clear();
+        }
+        res
+    }
+
+    // Start a userspace command for an app whose region is known. The storage
+    // must be idle.
     fn userspace_call_driver(
         &self,
+        app: &App,
+        appid: ProcessId,
         command: NonvolatileCommand,
         offset: usize,
         length: usize,
     ) -> Result<(), ErrorCode> {
+        let region = app.region.ok_or(ErrorCode::FAIL)?;
+        if offset >= region.length || length > region.length - offset {
+            return Err(ErrorCode::INVAL);
+        }
+
         // Calculate where we want to actually read from in the physical
-        // storage.
-        let physical_address = offset + self.userspace_start_address;
+        // storage. App regions start after the region table.
+        let physical_address = self
+            .userspace_start_address
+            .checked_add(REGION_TABLE_LENGTH)
+            .and_then(|address| address.checked_add(region.offset))
+            .and_then(|address| address.checked_add(offset))
+            .ok_or(ErrorCode::INVAL)?;
 
         self.buffer
             .take()
@@ -330,61 +638,105 @@ impl<'a> NonvolatileStorage<'a> {
                 // allowed are long enough.
                 let active_len = cmp::min(length, buffer.len());
 
-                // self.current_app.set(Some(appid));
-                match command {
+                self.current_user
+                    .set(NonvolatileUser::App { app_id: appid });
+                let res = match command {
                     NonvolatileCommand::UserspaceRead => {
                         self.driver.read(buffer, physical_address, active_len)
                     }
                     NonvolatileCommand::UserspaceWrite => {
+                        // Need to copy bytes if this is a write!
+                        let _ = app.buffer_write.enter(|app_buffer| {
+                            let write_len = cmp::min(active_len, app_buffer.len());
+                            for (i, c) in buffer[0..write_len].iter_mut().enumerate() {
+                                *c = app_buffer[i].get();
+                            }
+                        });
                         self.driver.write(buffer, physical_address, active_len)
                     }
-                    _ => Err(ErrorCode::FAIL),
-                }
+                    _ => Err((ErrorCode::FAIL, buffer)),
+                };
+                res.map_err(|(e, buffer)| {
+                    self.buffer.replace(buffer);
+                    self.current_user.clear();
+                    e
+                })
             })
     }
 
+    // Fail the queued commands of apps whose region is not known.
+    fn fail_unresolved_commands(&self) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, upcalls| {
+                if app.pending_command && app.region.is_none() {
+                    app.pending_command = false;
+                    // Tell the app its queued command could not be run by
+                    // signaling it with a length of 0.
+                    upcalls
+                        .schedule_upcall(upcall_number(app.command), 0, 0, 0)
+                        .ok();
+                }
+            });
+        }
+    }
+
     fn check_queue(&self) {
         // Check if there are any pending events.
         if self.kernel_pending_command.get() {
             self.kernel_buffer.take().map(|kernel_buffer| {
                 self.kernel_pending_command.set(false);
-                self.current_user.set(NonvolatileUser::Kernel);
-
-                match self.kernel_command.get() {
-                    NonvolatileCommand::KernelRead => self.driver.read(
-                        kernel_buffer,
-                        self.kernel_readwrite_address.get(),
-                        self.kernel_readwrite_length.get(),
-                    ),
-                    NonvolatileCommand::KernelWrite => self.driver.write(
-                        kernel_buffer,
-                        self.kernel_readwrite_address.get(),
-                        self.kernel_readwrite_length.get(),
-                    ),
-                    _ => Err(ErrorCode::FAIL),
+                let command = self.kernel_command.get();
+
+                if let Err((_, buffer)) = self.kernel_call_driver(
+                    command,
+                    kernel_buffer,
+                    self.kernel_readwrite_address.get(),
+                    self.kernel_readwrite_length.get(),
+                ) {
+                    // Tell the kernel its queued command could not be run by
+                    // returning the buffer with a length of 0.
+                    self.kernel_client.map(move |client| match command {
+                        NonvolatileCommand::KernelWrite => client.write_done(buffer, 0),
+                        _ => client.read_done(buffer, 0),
+                    });
                 }
             });
         } else {
             // If the kernel is not requesting anything, check all of the apps.
             for cntr in self.apps.iter() {
                 let appid = cntr.processid();
-                let started_command = cntr.enter(|app, _| {
-                    if app.pending_command {
-                        app.pending_command = false;
-                        self.current_user
-                            .set(NonvolatileUser::App { app_id: appid });
-                        if let Ok(()) =
-                            self.userspace_call_driver(app.command, app.offset, app.length)
-                        {
-                            true
-                        } else {
-                            false
+                cntr.enter(|app, upcalls| {
+                    if !app.pending_command {
+                        return;
+                    }
+                    match self.resolve_region(app, appid) {
+                        Ok(true) => {
+                            let (command, offset, length) = (app.command, app.offset, app.length);
+                            if let Err(_) =
+                                self.userspace_call_driver(app, appid, command, offset, length)
+                            {
+                                // Tell the app its queued command could not be
+                                // run by signaling it with a length of 0.
+                                upcalls
+                                    .schedule_upcall(upcall_number(app.command), 0, 0, 0)
+                                    .ok();
+                            }
+                            app.pending_command = false;
+                        }
+                        // The region table is being loaded or updated, the
+                        // command stays queued until that finishes.
+                        Ok(false) => {}
+                        Err(_) => {
+                            app.pending_command = false;
+                            // Tell the app its queued command could not be
+                            // run by signaling it with a length of 0.
+                            upcalls
+                                .schedule_upcall(upcall_number(app.command), 0, 0, 0)
+                                .ok();
                         }
-                    } else {
-                        false
                     }
                 });
-                if started_command {
+                if self.current_user.is_some() {
                     break;
                 }
             }
@@ -422,6 +774,19 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         upcalls.schedule_upcall(0, length, 0, 0).ok();
                     });
                 }
+                NonvolatileUser::RegionTable => {
+                    // If storage does not contain a valid table yet, start
+                    // with an empty one. It is written to storage the first
+                    // time a region is allocated.
+                    if region_table_read_u32(buffer, 0) != REGION_TABLE_MAGIC {
+                        for b in buffer.iter_mut() {
+                            *b = 0;
+                        }
+                        region_table_write_u32(buffer, 0, REGION_TABLE_MAGIC);
+                    }
+                    self.region_table.replace(buffer);
+                    self.region_table_loaded.set(true);
+                }
             }
         });
 
@@ -446,6 +811,16 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         upcalls.schedule_upcall(1, length, 0, 0).ok();
                     });
                 }
+                NonvolatileUser::RegionTable => {
+                    if length < buffer.len() {
+                        // The new allocation may not have been stored, so
+                        // read the table back and fail the commands waiting
+                        // for it.
+                        self.region_table_loaded.set(false);
+                        self.fail_unresolved_commands();
+                    }
+                    self.region_table.replace(buffer);
+                }
             }
         });
 
@@ -464,9 +839,8 @@ impl hil::nonvolatile_storage::NonvolatileStorage<'static> for NonvolatileStorag
         buffer: &'static mut [u8],
         address: usize,
         length: usize,
-    ) -> Result<(), ErrorCode> {
-        self.kernel_buffer.replace(buffer);
-        self.enqueue_command(NonvolatileCommand::KernelRead, address, length, None)
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.enqueue_kernel_command(NonvolatileCommand::KernelRead, buffer, address, length)
     }
 
     fn write(
@@ -474,9 +848,8 @@ impl hil::nonvolatile_storage::NonvolatileStorage<'static> for NonvolatileStorag
         buffer: &'static mut [u8],
         address: usize,
         length: usize,
-    ) -> Result<(), ErrorCode> {
-        self.kernel_buffer.replace(buffer);
-        self.enqueue_command(NonvolatileCommand::KernelWrite, address, length, None)
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.enqueue_kernel_command(NonvolatileCommand::KernelWrite, buffer, address, length)
     }
 }
 
@@ -553,7 +926,7 @@ impl Driver for NonvolatileStorage<'_> {
     /// ### `command_num`
     ///
     /// - `0`: Return Ok(()) if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
+    /// - `1`: Return the number of bytes available to this app.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
     fn command(
@@ -569,8 +942,20 @@ impl Driver for NonvolatileStorage<'_> {
             }
 
             1 /* How many bytes are accessible from userspace */ => {
-                // TODO: Would break on 64-bit platforms
-                CommandReturn::success_u32(self.userspace_length as u32)
+                let res = self.apps.enter(appid, |app, _| {
+                    if self.storage_id(appid).is_none() {
+                        Err(ErrorCode::NOSUPPORT)
+                    } else {
+                        Ok(self.region_length(app, appid))
+                    }
+                });
+
+                match res {
+                    // TODO: Would break on 64-bit platforms
+                    Ok(Ok(length)) => CommandReturn::success_u32(length as u32),
+                    Ok(Err(e)) => CommandReturn::failure(e),
+                    Err(e) => CommandReturn::failure(e.into()),
+                }
             },
 
             2 /* Issue a read command */ => {
@@ -611,3 +996,170 @@ impl Driver for NonvolatileStorage<'_> {
         self.apps.enter(processid, |_, _| {})
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Userspace bytes after the region table.
+    const AVAILABLE: usize = 4096;
+
+    fn empty_table() -> [u8; REGION_TABLE_LENGTH] {
+        let mut table = [0; REGION_TABLE_LENGTH];
+        region_table_write_u32(&mut table, 0, REGION_TABLE_MAGIC);
+        table
+    }
+
+    fn write_entry(table: &mut [u8], storage_id: u32, offset: u32, length: u32) {
+        let entries = region_table_entries(table);
+        let index = REGION_TABLE_HEADER_LENGTH + entries * REGION_ENTRY_LENGTH;
+        region_table_write_u32(table, index, storage_id);
+        region_table_write_u32(table, index + 4, offset);
+        region_table_write_u32(table, index + 8, length);
+        region_table_write_u32(table, 4, entries as u32 + 1);
+    }
+
+    fn region(result: Result<(AppRegion, bool), ErrorCode>) -> (usize, usize, bool) {
+        let (region, allocated) = result.unwrap();
+        (region.offset, region.length, allocated)
+    }
+
+    #[test]
+    fn storage_ids_come_from_grants() {
+        let grants = [
+            StorageGrant {
+                package_name: "logger",
+                storage_id: 7,
+            },
+            StorageGrant {
+                package_name: "sensor",
+                storage_id: 9,
+            },
+        ];
+        assert_eq!(granted_storage_id(&grants, "logger", None), Some(7));
+        assert_eq!(granted_storage_id(&grants, "sensor", Some(9)), Some(9));
+        // An app cannot name another app's region in its TBF header.
+        assert_eq!(granted_storage_id(&grants, "logger", Some(9)), None);
+        // Apps without a grant get no region.
+        assert_eq!(granted_storage_id(&grants, "other", None), None);
+        assert_eq!(granted_storage_id(&grants, "other", Some(7)), None);
+    }
+
+    #[test]
+    fn allocates_consecutive_regions() {
+        let mut table = empty_table();
+        assert_eq!(
+            region(region_table_allocate(&mut table, 1, 256, AVAILABLE)),
+            (0, 256, true)
+        );
+        assert_eq!(
+            region(region_table_allocate(&mut table, 2, 1024, AVAILABLE)),
+            (256, 1024, true)
+        );
+        assert_eq!(region_table_entries(&table), 2);
+    }
+
+    #[test]
+    fn finds_existing_region() {
+        let mut table = empty_table();
+        region_table_allocate(&mut table, 1, 256, AVAILABLE).unwrap();
+        region_table_allocate(&mut table, 2, 512, AVAILABLE).unwrap();
+
+        // The length requested now does not matter once a region exists.
+        assert_eq!(
+            region(region_table_allocate(&mut table, 2, 100, AVAILABLE)),
+            (256, 512, false)
+        );
+        assert_eq!(region_table_entries(&table), 2);
+    }
+
+    #[test]
+    fn refuses_regions_past_end() {
+        let mut table = empty_table();
+        region_table_allocate(&mut table, 1, AVAILABLE - 16, AVAILABLE).unwrap();
+        assert_eq!(
+            region_table_allocate(&mut table, 2, 17, AVAILABLE).err(),
+            Some(ErrorCode::NOMEM)
+        );
+        assert_eq!(
+            region(region_table_allocate(&mut table, 2, 16, AVAILABLE)),
+            (AVAILABLE - 16, 16, true)
+        );
+    }
+
+    #[test]
+    fn refuses_lengths_that_wrap() {
+        let mut table = empty_table();
+        region_table_allocate(&mut table, 1, 256, AVAILABLE).unwrap();
+        assert_eq!(
+            region_table_allocate(&mut table, 2, usize::MAX, AVAILABLE).err(),
+            Some(ErrorCode::NOMEM)
+        );
+        assert_eq!(
+            region_table_allocate(&mut table, 2, usize::MAX - 128, AVAILABLE).err(),
+            Some(ErrorCode::NOMEM)
+        );
+        assert_eq!(region_table_entries(&table), 1);
+    }
+
+    #[test]
+    fn refuses_zero_length() {
+        let mut table = empty_table();
+        assert_eq!(
+            region_table_allocate(&mut table, 1, 0, AVAILABLE).err(),
+            Some(ErrorCode::NOSUPPORT)
+        );
+    }
+
+    #[test]
+    fn refuses_when_table_full() {
+        let mut table = empty_table();
+        let capacity = region_table_capacity(&table);
+        for id in 0..capacity {
+            region_table_allocate(&mut table, id as u32, 1, AVAILABLE).unwrap();
+        }
+        assert_eq!(
+            region_table_allocate(&mut table, capacity as u32, 1, AVAILABLE).err(),
+            Some(ErrorCode::NOMEM)
+        );
+    }
+
+    #[test]
+    fn rejects_stored_regions_outside_userspace() {
+        let mut table = empty_table();
+        write_entry(&mut table, 1, 0, 256);
+        // Wraps around when the offset and length are added.
+        write_entry(&mut table, 2, u32::MAX, 2);
+        // Ends past the userspace region.
+        write_entry(&mut table, 3, 4000, 200);
+
+        assert_eq!(
+            region(region_table_allocate(&mut table, 1, 256, AVAILABLE)),
+            (0, 256, false)
+        );
+        assert_eq!(
+            region_table_allocate(&mut table, 2, 256, AVAILABLE).err(),
+            Some(ErrorCode::FAIL)
+        );
+        assert_eq!(
+            region_table_allocate(&mut table, 3, 256, AVAILABLE).err(),
+            Some(ErrorCode::FAIL)
+        );
+        // Where the free space starts is not known.
+        assert_eq!(
+            region_table_allocate(&mut table, 4, 256, AVAILABLE).err(),
+            Some(ErrorCode::FAIL)
+        );
+    }
+
+    #[test]
+    fn ignores_excess_entry_count() {
+        let mut table = empty_table();
+        region_table_write_u32(&mut table, 4, u32::MAX);
+        assert_eq!(region_table_entries(&table), region_table_capacity(&table));
+        assert_eq!(
+            region_table_allocate(&mut table, 99, 256, AVAILABLE).err(),
+            Some(ErrorCode::NOMEM)
+        );
+    }
+}
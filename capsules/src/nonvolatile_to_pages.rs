@@ -100,35 +100,39 @@ impl<'a, F: hil::flash::Flash> hil::nonvolatile_storage::NonvolatileStorage<'sta
         buffer: &'static mut [u8],
         address: usize,
         length: usize,
-    ) -> Result<(), ErrorCode> {
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         if self.state.get() != State::Idle {
-            return Err(ErrorCode::BUSY);
+            return Err((ErrorCode::BUSY, buffer));
         }
 
-        self.pagebuffer
-            .take()
-            .map_or(Err(ErrorCode::RESERVE), move |pagebuffer| {
-                let page_size = pagebuffer.as_mut().len();
+        let pagebuffer = match self.pagebuffer.take() {
+            Some(pagebuffer) => pagebuffer,
+            None => return Err((ErrorCode::RESERVE, buffer)),
+        };
+        let page_size = pagebuffer.as_mut().len();
 
-                // Just start reading. We'll worry about how much of the page we
-                // want later.
+        // Just start reading. We'll worry about how much of the page we want
+        // later.
+        match self.driver.read_page(address / page_size, pagebuffer) {
+            Ok(()) => {
                 self.state.set(State::Read);
                 self.buffer.replace(buffer);
                 self.address.set(address);
                 self.length.set(length);
                 self.remaining_length.set(length);
                 self.buffer_index.set(0);
-
-                match self.driver.read_page(address / page_size, pagebuffer) {
-                    Ok(()) => Ok(()),
-                    Err((return_code, pagebuffer)) => {
-                        self.pagebuffer.replace(pagebuffer);
-                        Err(return_code
-                            .try_into()
-                            .expect("Result<(), ErrorCode> success variant in error case"))
-                    }
-                }
-            })
+                Ok(())
+            }
+            Err((return_code, pagebuffer)) => {
+                self.pagebuffer.replace(pagebuffer);
+                Err((
+                    return_code
+                        .try_into()
+                        .expect("Result<(), ErrorCode> success variant in error case"),
+                    buffer,
+                ))
+            }
+        }
     }
 
     fn write(
@@ -136,60 +140,56 @@ impl<'a, F: hil::flash::Flash> hil::nonvolatile_storage::NonvolatileStorage<'sta
         buffer: &'static mut [u8],
         address: usize,
         length: usize,
-    ) -> Result<(), ErrorCode> {
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         if self.state.get() != State::Idle {
-            return Err(ErrorCode::BUSY);
+            return Err((ErrorCode::BUSY, buffer));
         }
 
-        self.pagebuffer
-            .take()
-            .map_or(Err(ErrorCode::RESERVE), move |pagebuffer| {
-                let page_size = pagebuffer.as_mut().len();
+        let pagebuffer = match self.pagebuffer.take() {
+            Some(pagebuffer) => pagebuffer,
+            None => return Err((ErrorCode::RESERVE, buffer)),
+        };
+        let page_size = pagebuffer.as_mut().len();
 
-                self.state.set(State::Write);
-                self.length.set(length);
+        let result = if address % page_size == 0 && length >= page_size {
+            // This write is aligned to a page and we are writing an entire
+            // page or more.
 
-                if address % page_size == 0 && length >= page_size {
-                    // This write is aligned to a page and we are writing an entire
-                    // page or more.
-
-                    // Copy data into page buffer.
-                    for i in 0..page_size {
-                        pagebuffer.as_mut()[i] = buffer[i];
-                    }
+            // Copy data into page buffer.
+            for i in 0..page_size {
+                pagebuffer.as_mut()[i] = buffer[i];
+            }
 
-                    self.buffer.replace(buffer);
-                    self.address.set(address + page_size);
-                    self.remaining_length.set(length - page_size);
-                    self.buffer_index.set(page_size);
+            self.driver
+                .write_page(address / page_size, pagebuffer)
+                .map(|()| (address + page_size, length - page_size, page_size))
+        } else {
+            // Need to do a read first.
+            self.driver
+                .read_page(address / page_size, pagebuffer)
+                .map(|()| (address, length, 0))
+        };
 
-                    match self.driver.write_page(address / page_size, pagebuffer) {
-                        Ok(()) => Ok(()),
-                        Err((return_code, pagebuffer)) => {
-                            self.pagebuffer.replace(pagebuffer);
-                            Err(return_code
-                                .try_into()
-                                .expect("Result<(), ErrorCode> success variant in error case"))
-                        }
-                    }
-                } else {
-                    // Need to do a read first.
-                    self.buffer.replace(buffer);
-                    self.address.set(address);
-                    self.remaining_length.set(length);
-                    self.buffer_index.set(0);
-
-                    match self.driver.read_page(address / page_size, pagebuffer) {
-                        Ok(()) => Ok(()),
-                        Err((return_code, pagebuffer)) => {
-                            self.pagebuffer.replace(pagebuffer);
-                            Err(return_code
-                                .try_into()
-                                .expect("Result<(), ErrorCode> success variant in error case"))
-                        }
-                    }
-                }
-            })
+        match result {
+            Ok((address, remaining_length, buffer_index)) => {
+                self.state.set(State::Write);
+                self.buffer.replace(buffer);
+                self.address.set(address);
+                self.length.set(length);
+                self.remaining_length.set(remaining_length);
+                self.buffer_index.set(buffer_index);
+                Ok(())
+            }
+            Err((return_code, pagebuffer)) => {
+                self.pagebuffer.replace(pagebuffer);
+                Err((
+                    return_code
+                        .try_into()
+                        .expect("Result<(), ErrorCode> success variant in error case"),
+                    buffer,
+                ))
+            }
+        }
     }
 }
 
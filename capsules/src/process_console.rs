@@ -5,14 +5,34 @@
 //! --------
 //!
 //! This module provides a simple text-based console to inspect and control
-//! which processes are running. The console has five commands:
+//! which processes are running. The console has these commands:
 //!  - 'help' prints the available commands and arguments
 //!  - 'status' prints the current system status
 //!  - 'list' lists the current processes with their IDs and running state
 //!  - 'stop n' stops the process with name n
 //!  - 'start n' starts the stopped process with name n
 //!  - 'fault n' forces the process with name n into a fault state
-//!  - 'panic' causes the kernel to run the panic handler
+//!  - 'terminate n' terminates the process with name n
+//!  - 'restart n' terminates and restarts the process with name n
+//!  - 'process n' prints the memory map of the process with name n
+//!  - 'kernel' prints the kernel version and memory map
+//!  - 'memory n a l' prints a hex dump of l bytes (default 64) at hex
+//!    address a of the process with name n. Only its flash and the RAM below
+//!    its app break can be read.
+//!  - 'grants n' lists the grants the process with name n has allocated and
+//!    the drivers they belong to
+//!  - 'drivers' lists the system call drivers in the board's driver table
+//!  - 'highwater' lists how much stack and heap each process has used at most
+//!  - 'baud r' changes the baud rate of the console UART to r
+//!  - 'reboot' resets the chip
+//!
+//! `baud`, `reboot` and `drivers` are only available if the board passed a
+//! UART to configure, a reset function or its platform. Boards can add their
+//! own commands with `ProcessConsole::set_commands()`, see the
+//! `ConsoleCommand` trait.
+//!
+//! The up and down arrow keys step through the last few commands, and the
+//! left and right arrow keys move the cursor within the current command.
 //!
 //! ### `list` Command Fields:
 //!
@@ -26,7 +46,11 @@
 //!   because the queue was full.
 //! - `Restarts`: How many times this process has crashed and been restarted by
 //!   the kernel.
-//! - `State`: The state the process is in.
+//! - `Filtered`: How many system calls of this process the board's system
+//!   call filter has rejected, for instance because they are not listed in
+//!   the permissions of its TBF header.
+//! - `State`: The state the process is in. If the last fault of the process
+//!   was a stack overflow, `stack overflow` follows the row.
 //! - `Grants`: The number of grants that have been initialized for the process
 //!   out of the total number of grants defined by the kernel.
 //!
@@ -58,6 +82,33 @@
 //! pconsole.start();
 //! ```
 //!
+//! Board commands
+//! --------------
+//!
+//! A board adds commands by implementing `ConsoleCommand` and passing a
+//! table of them to the console:
+//!
+//! ```rust
+//! # use core::fmt;
+//! # use capsules::process_console::ConsoleCommand;
+//!
+//! struct Temperature;
+//! impl ConsoleCommand for Temperature {
+//!     fn name(&self) -> &'static str {
+//!         "temp"
+//!     }
+//!     fn help(&self) -> &'static str {
+//!         "prints the die temperature"
+//!     }
+//!     fn execute(&self, _arguments: &str, writer: &mut dyn fmt::Write) {
+//!         let _ = writeln!(writer, "Temperature: {}", read_temperature());
+//!     }
+//! }
+//!
+//! static COMMANDS: [&dyn ConsoleCommand; 1] = [&Temperature];
+//! pconsole.set_commands(&COMMANDS);
+//! ```
+//!
 //! Buffer use and output
 //! ---------------------
 //! `ProcessConsole` does not use its own write buffer for output:
@@ -96,6 +147,18 @@
 //! 01     c_hello      0         8                0         0  Yielded    3/12
 //! ```
 //!
+//! To see how close processes came to running out of stack or heap, use
+//! `highwater`. Sizes are in bytes and `?` marks values the kernel does not
+//! know, for example because the process has not told it where its heap
+//! starts:
+//!
+//! ```text
+//! highwater
+//!  PID    Name                Max Stack   Heap  Max Heap
+//!   00    blink                     328   1024      1024
+//!   01    c_hello                   512      ?         ?
+//! ```
+//!
 //! To get a general view of the system, use the status command:
 //!
 //! ```text
@@ -117,8 +180,9 @@ use core::cmp;
 use core::fmt;
 use core::fmt::write;
 use core::str;
+use enum_primitive::cast::FromPrimitive;
 use kernel::capabilities::ProcessManagementCapability;
-use kernel::common::cells::TakeCell;
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::ProcessId;
 
 use kernel::debug;
@@ -126,6 +190,9 @@ use kernel::hil::uart;
 use kernel::introspection::KernelInfo;
 use kernel::ErrorCode;
 use kernel::Kernel;
+use kernel::Platform;
+
+use crate::driver;
 
 /// Buffer to hold outgoing data that is passed to the UART hardware.
 pub static mut WRITE_BUF: [u8; 500] = [0; 500];
@@ -138,6 +205,64 @@ pub static mut READ_BUF: [u8; 4] = [0; 4];
 /// Commands can be up to 32 bytes long: since commands themselves are 4-5
 /// characters, limiting arguments to 25 bytes or so seems fine for now.
 pub static mut COMMAND_BUF: [u8; 32] = [0; 32];
+/// Holds the last few commands, each taking as much space as the command
+/// buffer.
+pub static mut HISTORY_BUF: [u8; 128] = [0; 128];
+
+/// The built-in commands, as printed by `help`.
+const BUILTIN_COMMANDS: &[u8] = b"help status list stop start fault terminate restart process \
+kernel memory grants drivers highwater baud reboot";
+
+/// Bytes printed on each line of a `memory` dump.
+const MEMORY_LINE_LEN: usize = 16;
+
+/// Longest `memory` dump, in bytes.
+const MEMORY_MAX_LEN: usize = 1024;
+
+/// A command that a board adds to the console.
+pub trait ConsoleCommand {
+    /// The word that runs the command.
+    fn name(&self) -> &'static str;
+
+    /// A short description of the command and its arguments.
+    fn help(&self) -> &'static str;
+
+    /// Run the command. `arguments` is the rest of the command line after the
+    /// name. Output written to `writer` is printed on the console; it must
+    /// fit in `ConsoleWriter`'s buffer and is truncated otherwise.
+    fn execute(&self, arguments: &str, writer: &mut dyn fmt::Write);
+}
+
+/// Looks up system call drivers in the board's driver table, for the
+/// `drivers` command. Every `Platform` implements it.
+pub trait DriverLookup {
+    /// Whether the board has a driver for `driver_num`.
+    fn has_driver(&self, driver_num: usize) -> bool;
+}
+
+impl<P: Platform> DriverLookup for P {
+    fn has_driver(&self, driver_num: usize) -> bool {
+        self.with_driver(driver_num, |driver| driver.is_some())
+    }
+}
+
+/// Where the console is in an escape sequence sent by an arrow key.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum EscapeState {
+    None,
+    /// Received ESC.
+    Escape,
+    /// Received ESC [.
+    Bracket,
+}
+
+/// An action that waits for the console output to be transmitted first.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum PendingAction {
+    None,
+    Reboot,
+    Baud(u32),
+}
 
 /// States used for state machine to allow printing large strings asynchronously
 /// across multiple calls. This reduces the size of the buffer needed to print
@@ -160,6 +285,12 @@ enum WriterState {
     ProcessStackUnused,
     ProcessFlash,
     ProcessProtected,
+    MemoryStart,
+    MemoryLine,
+    GrantsStart,
+    GrantLine,
+    DriversStart,
+    DriverLine,
 }
 
 impl Default for WriterState {
@@ -197,6 +328,25 @@ pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     rx_buffer: TakeCell<'static, [u8]>,
     command_buffer: TakeCell<'static, [u8]>,
     command_index: Cell<usize>,
+    /// Position of the cursor in the command, at most `command_index`.
+    command_cursor: Cell<usize>,
+    escape_state: Cell<EscapeState>,
+
+    /// Length of the command buffer, and of each history slot.
+    command_len: usize,
+    /// Previous commands, in slots as long as the command buffer.
+    history_buffer: TakeCell<'static, [u8]>,
+    /// Slot the next command is saved in.
+    history_next: Cell<usize>,
+    /// Number of saved commands.
+    history_count: Cell<usize>,
+    /// How many commands back the arrow keys have gone, 0 for a new command.
+    history_position: Cell<usize>,
+
+    /// Progress through states that print one line per item: the next item
+    /// (an address for `MemoryLine`) and the end of the items.
+    writer_index: Cell<usize>,
+    writer_end: Cell<usize>,
 
     /// Flag to mark that the process console is active and has called receive
     /// from the underlying UART.
@@ -212,6 +362,20 @@ pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     /// Memory addresses of where the kernel is placed in memory on chip.
     kernel_addresses: KernelAddresses,
 
+    /// Commands added by the board.
+    commands: OptionalCell<&'a [&'a dyn ConsoleCommand]>,
+
+    /// Resets the chip for `reboot`.
+    reset_function: OptionalCell<fn() -> !>,
+
+    /// Changes the baud rate for `baud`.
+    uart_configure: OptionalCell<&'a dyn uart::Configure>,
+
+    /// The board's driver table, for `drivers`.
+    driver_lookup: OptionalCell<&'a dyn DriverLookup>,
+
+    pending_action: Cell<PendingAction>,
+
     /// This capsule needs to use potentially dangerous APIs related to
     /// processes, and requires a capability to access those APIs.
     capability: C,
@@ -231,16 +395,104 @@ impl ConsoleWriter {
     pub fn clear(&mut self) {
         self.size = 0;
     }
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let len = cmp::min(bytes.len(), self.buf.len() - self.size);
+        self.buf[self.size..self.size + len].copy_from_slice(&bytes[..len]);
+        self.size += len;
+    }
 }
 impl fmt::Write for ConsoleWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let curr = (s).as_bytes().len();
-        self.buf[self.size..self.size + curr].copy_from_slice(&(s).as_bytes()[..]);
+        let curr = cmp::min(s.as_bytes().len(), self.buf.len() - self.size);
+        self.buf[self.size..self.size + curr].copy_from_slice(&(s).as_bytes()[..curr]);
         self.size += curr;
         Ok(())
     }
 }
 
+/// A size in bytes, or `?` if it is not known.
+struct Size(Option<usize>);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(size) => fmt::Display::fmt(&size, f),
+            None => f.pad("?"),
+        }
+    }
+}
+
+/// Write the name of driver `driver_num`, if it is one of the drivers in this
+/// crate.
+fn write_driver_name(writer: &mut dyn fmt::Write, driver_num: usize) -> fmt::Result {
+    match driver::NUM::from_usize(driver_num) {
+        Some(num) => write!(writer, "{:?}", num),
+        None => writer.write_str("unknown"),
+    }
+}
+
+/// Format `bytes`, read from `address`, as a hex dump line.
+fn write_memory_line(writer: &mut dyn fmt::Write, address: usize, bytes: &[u8]) -> fmt::Result {
+    write!(writer, "  {:#010x} ", address)?;
+    for i in 0..MEMORY_LINE_LEN {
+        match bytes.get(i) {
+            Some(byte) => write!(writer, " {:02x}", byte)?,
+            None => writer.write_str("   ")?,
+        }
+    }
+    writer.write_str("  |")?;
+    for &byte in bytes {
+        let c = if byte >= 0x20 && byte < 0x7f {
+            byte as char
+        } else {
+            '.'
+        };
+        write!(writer, "{}", c)?;
+    }
+    writer.write_str("|\n")
+}
+
+/// Split a command line into the command word and its arguments.
+fn split_command(command: &str) -> (&str, &str) {
+    let command = command.trim_start();
+    let name = command.split_whitespace().next().unwrap_or("");
+    (name, command[name.len()..].trim())
+}
+
+/// The driver the `drivers` command lists at `index`, if the board has it.
+/// The first indexes are the drivers of this crate, and the rest the grants,
+/// so that drivers from other crates are listed once they create a grant.
+fn listed_driver<F>(index: usize, lookup: &dyn DriverLookup, grant_driver_num: F) -> Option<usize>
+where
+    F: FnOnce(usize) -> Option<usize>,
+{
+    let driver_num = match driver::NUM::ALL.get(index) {
+        Some(&num) => num as usize,
+        None => grant_driver_num(index - driver::NUM::ALL.len())
+            .filter(|&driver_num| driver::NUM::from_usize(driver_num).is_none())?,
+    };
+    if lookup.has_driver(driver_num) {
+        Some(driver_num)
+    } else {
+        None
+    }
+}
+
+/// Move the terminal cursor `count` characters left.
+fn write_cursor_left(writer: &mut dyn fmt::Write, count: usize) -> fmt::Result {
+    if count > 0 {
+        write!(writer, "\x1b[{}D", count)
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse a hex number with an optional `0x` prefix.
+fn parse_hex(s: &str) -> Option<usize> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    usize::from_str_radix(digits, 16).ok()
+}
+
 fn exceeded_check(size: usize, allocated: usize) -> &'static str {
     if size > allocated {
         " EXCEEDED!"
@@ -256,6 +508,7 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
         rx_buffer: &'static mut [u8],
         queue_buffer: &'static mut [u8],
         cmd_buffer: &'static mut [u8],
+        history_buffer: &'static mut [u8],
         kernel: &'static Kernel,
         kernel_addresses: KernelAddresses,
         capability: C,
@@ -270,16 +523,53 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             writer_process: Cell::new(None),
             rx_in_progress: Cell::new(false),
             rx_buffer: TakeCell::new(rx_buffer),
+            command_len: cmd_buffer.len(),
             command_buffer: TakeCell::new(cmd_buffer),
             command_index: Cell::new(0),
+            command_cursor: Cell::new(0),
+            escape_state: Cell::new(EscapeState::None),
+            history_buffer: TakeCell::new(history_buffer),
+            history_next: Cell::new(0),
+            history_count: Cell::new(0),
+            history_position: Cell::new(0),
+            writer_index: Cell::new(0),
+            writer_end: Cell::new(0),
             running: Cell::new(false),
             execute: Cell::new(false),
             kernel: kernel,
             kernel_addresses: kernel_addresses,
+            commands: OptionalCell::empty(),
+            reset_function: OptionalCell::empty(),
+            uart_configure: OptionalCell::empty(),
+            driver_lookup: OptionalCell::empty(),
+            pending_action: Cell::new(PendingAction::None),
             capability: capability,
         }
     }
 
+    /// Add the board's own commands to the console. They are checked before
+    /// the built-in commands, so they can replace them.
+    pub fn set_commands(&self, commands: &'a [&'a dyn ConsoleCommand]) {
+        self.commands.set(commands);
+    }
+
+    /// Enable the `reboot` command, which calls `reset`.
+    pub fn set_reset_function(&self, reset: fn() -> !) {
+        self.reset_function.set(reset);
+    }
+
+    /// Enable the `baud` command, which reconfigures `configure`. This is
+    /// usually the UART mux the console's UART device is on.
+    pub fn set_uart_configure(&self, configure: &'a dyn uart::Configure) {
+        self.uart_configure.set(configure);
+    }
+
+    /// Enable the `drivers` command, which lists the drivers in the driver
+    /// table of `platform`.
+    pub fn set_driver_lookup(&self, platform: &'a dyn DriverLookup) {
+        self.driver_lookup.set(platform);
+    }
+
     pub fn start(&self) -> Result<(), ErrorCode> {
         if self.running.get() == false {
             self.rx_buffer.take().map(|buffer| {
@@ -301,13 +591,23 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
 
             let _ = self.write_bytes(b"Welcome to the process console.\n");
-            let _ = self.write_bytes(
-                b"Valid commands are: help status list stop start fault process kernel\n",
-            );
+            self.write_valid_commands();
         }
         Ok(())
     }
 
+    fn write_valid_commands(&self) {
+        let _ = self.write_bytes(b"Valid commands are: ");
+        let _ = self.write_bytes(BUILTIN_COMMANDS);
+        self.commands.map(|commands| {
+            for command in commands.iter() {
+                let _ = self.write_bytes(b" ");
+                let _ = self.write_bytes(command.name().as_bytes());
+            }
+        });
+        let _ = self.write_bytes(b"\n");
+    }
+
     /// Simple state machine helper function that identifies the next state for
     /// printing log debug messages.
     fn next_state(&self, state: WriterState) -> WriterState {
@@ -327,10 +627,28 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             WriterState::ProcessStackUnused => WriterState::ProcessFlash,
             WriterState::ProcessFlash => WriterState::ProcessProtected,
             WriterState::ProcessProtected => WriterState::Empty,
+            WriterState::MemoryStart | WriterState::MemoryLine => {
+                self.next_line_state(WriterState::MemoryLine)
+            }
+            WriterState::GrantsStart | WriterState::GrantLine => {
+                self.next_line_state(WriterState::GrantLine)
+            }
+            WriterState::DriversStart | WriterState::DriverLine => {
+                self.next_line_state(WriterState::DriverLine)
+            }
             WriterState::Empty => WriterState::Empty,
         }
     }
 
+    /// Stay in `line` while there are items left to print.
+    fn next_line_state(&self, line: WriterState) -> WriterState {
+        if self.writer_index.get() < self.writer_end.get() {
+            line
+        } else {
+            WriterState::Empty
+        }
+    }
+
     // These `print_process_()` functions are split out from the main state
     // machine because of an incompatibility with rustfmt. Rustfmt cannot handle
     // long lines inside of match statements, so we use individual functions to
@@ -769,10 +1087,124 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                         });
                 }
             }
+            WriterState::MemoryLine => {
+                let address = self.writer_index.get();
+                let len = cmp::min(MEMORY_LINE_LEN, self.writer_end.get() - address);
+                let mut bytes = [0; MEMORY_LINE_LEN];
+                let read = self.with_process(process_id, |process| {
+                    process.read_memory(address, &mut bytes[..len])
+                });
+                let mut console_writer = ConsoleWriter::new();
+                match read {
+                    Some(Ok(())) => {
+                        let _ = write_memory_line(&mut console_writer, address, &bytes[..len]);
+                        self.writer_index.set(address + len);
+                    }
+                    _ => {
+                        let _ = write(
+                            &mut console_writer,
+                            format_args!("  {:#010x}  not readable\n", address),
+                        );
+                        self.writer_index.set(self.writer_end.get());
+                    }
+                }
+                let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+            }
+            WriterState::GrantLine => {
+                let grant_num = self.writer_index.get();
+                self.writer_index.set(grant_num + 1);
+                let info: KernelInfo = KernelInfo::new(self.kernel);
+                let allocated = process_id.map_or(false, |process_id| {
+                    info.app_grant_is_allocated(process_id, grant_num, &self.capability)
+                });
+                if allocated {
+                    self.write_driver_line(grant_num);
+                }
+            }
+            WriterState::DriverLine => {
+                let index = self.writer_index.get();
+                self.writer_index.set(index + 1);
+                let info: KernelInfo = KernelInfo::new(self.kernel);
+                let driver_num = self.driver_lookup.and_then(|lookup| {
+                    listed_driver(index, lookup, |grant_num| {
+                        info.grant_driver_num(grant_num, &self.capability)
+                    })
+                });
+                if let Some(driver_num) = driver_num {
+                    let mut console_writer = ConsoleWriter::new();
+                    let _ = write(&mut console_writer, format_args!("  {:#07x}  ", driver_num));
+                    let _ = write_driver_name(&mut console_writer, driver_num);
+                    let _ = write(&mut console_writer, format_args!("\n"));
+                    let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+                }
+            }
             _ => {}
         }
     }
 
+    /// Print grant `grant_num` and the driver it belongs to.
+    fn write_driver_line(&self, grant_num: usize) {
+        let info: KernelInfo = KernelInfo::new(self.kernel);
+        let mut console_writer = ConsoleWriter::new();
+        let _ = write(&mut console_writer, format_args!("  {:5}  ", grant_num));
+        let _ = match info.grant_driver_num(grant_num, &self.capability) {
+            Some(driver_num) => write(&mut console_writer, format_args!("{:#07x}  ", driver_num))
+                .and_then(|()| write_driver_name(&mut console_writer, driver_num)),
+            None => write(&mut console_writer, format_args!("      ?  unknown")),
+        };
+        let _ = write(&mut console_writer, format_args!("\n"));
+        let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+    }
+
+    /// Call `f` with the process `process_id`, if it still exists.
+    fn with_process<F, R>(&self, process_id: Option<ProcessId>, f: F) -> Option<R>
+    where
+        F: FnOnce(&dyn kernel::procs::Process) -> R,
+    {
+        let f = Cell::new(Some(f));
+        let result = Cell::new(None);
+        if let Some(process_id) = process_id {
+            self.kernel
+                .process_each_capability(&self.capability, |process| {
+                    if process.processid() == process_id {
+                        if let Some(f) = f.take() {
+                            result.set(Some(f(process)));
+                        }
+                    }
+                });
+        }
+        result.into_inner()
+    }
+
+    /// Call `f` with the process named `name`, if there is one.
+    fn with_process_named<F>(&self, name: &str, f: F)
+    where
+        F: Fn(&dyn kernel::procs::Process),
+    {
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.get_process_name() == name {
+                    f(process);
+                }
+            });
+    }
+
+    /// Start printing one line per item with `start`, for items `index` up to
+    /// `end`.
+    fn write_lines(
+        &self,
+        start: WriterState,
+        process: Option<ProcessId>,
+        index: usize,
+        end: usize,
+    ) {
+        if self.writer_state.get() == WriterState::Empty {
+            self.writer_index.set(index);
+            self.writer_end.set(end);
+        }
+        self.write_state(start, process);
+    }
+
     // Process the command in the command buffer and clear the buffer.
     fn read_command(&self) {
         self.command_buffer.map(|command| {
@@ -794,13 +1226,26 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                 match cmd_str {
                     Ok(s) => {
                         let clean_str = s.trim();
+                        self.save_history(clean_str);
+                        let (name, _) = split_command(clean_str);
 
-                        if clean_str.starts_with("help") {
+                        if self.run_board_command(clean_str) {
+                            // The board's command printed its own output.
+                        } else if name == "help" {
                             let _ = self.write_bytes(b"Welcome to the process console.\n");
-                            let _ = self.write_bytes(b"Valid commands are: ");
-                            let _ = self
-                                .write_bytes(b"help status list stop start fault process kernel\n");
-                        } else if clean_str.starts_with("start") {
+                            self.write_valid_commands();
+                            self.commands.map(|commands| {
+                                for command in commands.iter() {
+                                    let mut console_writer = ConsoleWriter::new();
+                                    let _ = write(
+                                        &mut console_writer,
+                                        format_args!("  {}: {}\n", command.name(), command.help()),
+                                    );
+                                    let _ = self
+                                        .write_bytes(&(console_writer.buf)[..console_writer.size]);
+                                }
+                            });
+                        } else if name == "start" {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
                                 self.kernel
@@ -820,7 +1265,7 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                         }
                                     });
                             });
-                        } else if clean_str.starts_with("stop") {
+                        } else if name == "stop" {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
                                 self.kernel
@@ -840,7 +1285,7 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                         }
                                     });
                             });
-                        } else if clean_str.starts_with("fault") {
+                        } else if name == "fault" {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
                                 self.kernel
@@ -860,10 +1305,10 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                         }
                                     });
                             });
-                        } else if clean_str.starts_with("list") {
+                        } else if name == "list" {
                             let _ = self.write_bytes(b" PID    Name                Quanta  ");
                             let _ = self.write_bytes(b"Syscalls  Dropped Callbacks  ");
-                            let _ = self.write_bytes(b"Restarts    State  Grants\n");
+                            let _ = self.write_bytes(b"Restarts  Filtered    State  Grants\n");
                             self.kernel
                                 .process_each_capability(&self.capability, |proc| {
                                     let info: KernelInfo = KernelInfo::new(self.kernel);
@@ -876,23 +1321,29 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                     let _ = write(
                                         &mut console_writer,
                                         format_args!(
-                                            "  {:?}\t{:<20}{:6}{:10}{:19}{:10}  {:?}{:5}/{}\n",
+                                            "  {:?}\t{:<20}{:6}{:10}{:19}{:10}{:10}  {:?}{:5}/{}{}\n",
                                             process_id,
                                             pname,
                                             proc.debug_timeslice_expiration_count(),
                                             proc.debug_syscall_count(),
                                             proc.debug_dropped_upcall_count(),
                                             proc.get_restart_count(),
+                                            proc.debug_syscall_filter_violation_count(),
                                             proc.get_state(),
                                             grants_used,
-                                            grants_total
+                                            grants_total,
+                                            if proc.debug_stack_overflowed() {
+                                                "  stack overflow"
+                                            } else {
+                                                ""
+                                            }
                                         ),
                                     );
 
                                     let _ = self
                                         .write_bytes(&(console_writer.buf)[..console_writer.size]);
                                 });
-                        } else if clean_str.starts_with("status") {
+                        } else if name == "status" {
                             let info: KernelInfo = KernelInfo::new(self.kernel);
                             let mut console_writer = ConsoleWriter::new();
                             let _ = write(
@@ -921,7 +1372,7 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                 ),
                             );
                             let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
-                        } else if clean_str.starts_with("process") {
+                        } else if name == "process" {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
                                 self.kernel
@@ -936,7 +1387,7 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                         }
                                     });
                             });
-                        } else if clean_str.starts_with("kernel") {
+                        } else if name == "kernel" {
                             let mut console_writer = ConsoleWriter::new();
                             let _ = write(
                                 &mut console_writer,
@@ -951,10 +1402,127 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                             // Prints kernel memory by moving the writer to the
                             // start state.
                             self.write_state(WriterState::KernelStart, None);
-                        } else {
-                            let _ = self.write_bytes(b"Valid commands are: ");
+                        } else if name == "terminate" {
+                            let argument = clean_str.split_whitespace().nth(1);
+                            argument.map(|name| {
+                                self.with_process_named(name, |proc| {
+                                    proc.terminate(0);
+                                    let mut console_writer = ConsoleWriter::new();
+                                    let _ = write(
+                                        &mut console_writer,
+                                        format_args!("Process {} terminated\n", name),
+                                    );
+                                    let _ = self
+                                        .write_bytes(&(console_writer.buf)[..console_writer.size]);
+                                });
+                            });
+                        } else if name == "restart" {
+                            let argument = clean_str.split_whitespace().nth(1);
+                            argument.map(|name| {
+                                self.with_process_named(name, |proc| {
+                                    proc.try_restart(0);
+                                    let mut console_writer = ConsoleWriter::new();
+                                    let _ = write(
+                                        &mut console_writer,
+                                        format_args!("Process {} restarted\n", name),
+                                    );
+                                    let _ = self
+                                        .write_bytes(&(console_writer.buf)[..console_writer.size]);
+                                });
+                            });
+                        } else if name == "memory" {
+                            self.memory_command(clean_str);
+                        } else if name == "grants" {
+                            let argument = clean_str.split_whitespace().nth(1);
+                            argument.map(|name| {
+                                self.with_process_named(name, |proc| {
+                                    let info: KernelInfo = KernelInfo::new(self.kernel);
+                                    let _ = self.write_bytes(b"  Grant  Driver   Name\n");
+                                    self.write_lines(
+                                        WriterState::GrantsStart,
+                                        Some(proc.processid()),
+                                        0,
+                                        info.number_grants(&self.capability),
+                                    );
+                                });
+                            });
+                        } else if name == "drivers" {
+                            if self.driver_lookup.is_some() {
+                                let info: KernelInfo = KernelInfo::new(self.kernel);
+                                let _ = self.write_bytes(b"  Driver   Name\n");
+                                self.write_lines(
+                                    WriterState::DriversStart,
+                                    None,
+                                    0,
+                                    driver::NUM::ALL.len() + info.number_grants(&self.capability),
+                                );
+                            } else {
+                                let _ = self.write_bytes(b"This board cannot list its drivers\n");
+                            }
+                        } else if name == "highwater" {
                             let _ = self
-                                .write_bytes(b"help status list stop start fault process kernel\n");
+                                .write_bytes(b" PID    Name                Max Stack   Heap  Max Heap\n");
+                            self.kernel
+                                .process_each_capability(&self.capability, |proc| {
+                                    let stack = proc.debug_stack_start().and_then(|start| {
+                                        proc.debug_stack_end()
+                                            .map(|end| (start as usize).saturating_sub(end as usize))
+                                    });
+                                    let heap_start = proc.debug_heap_start().map(|p| p as usize);
+                                    let heap = heap_start.map(|start| {
+                                        (proc.app_memory_break() as usize).saturating_sub(start)
+                                    });
+                                    let heap_max = heap_start.and_then(|start| {
+                                        proc.debug_heap_end()
+                                            .map(|end| (end as usize).saturating_sub(start))
+                                    });
+                                    let mut console_writer = ConsoleWriter::new();
+                                    let _ = write(
+                                        &mut console_writer,
+                                        format_args!(
+                                            "  {:?}\t{:<20}{:>9}{:>7}{:>10}\n",
+                                            proc.processid(),
+                                            proc.get_process_name(),
+                                            Size(stack),
+                                            Size(heap),
+                                            Size(heap_max),
+                                        ),
+                                    );
+                                    let _ = self
+                                        .write_bytes(&(console_writer.buf)[..console_writer.size]);
+                                });
+                        } else if name == "baud" {
+                            let rate = clean_str
+                                .split_whitespace()
+                                .nth(1)
+                                .and_then(|rate| rate.parse::<u32>().ok());
+                            match (rate, self.uart_configure.is_some()) {
+                                (_, false) => {
+                                    let _ = self.write_bytes(b"This board cannot change the baud rate\n");
+                                }
+                                (None, true) | (Some(0), true) => {
+                                    let _ = self.write_bytes(b"Usage: baud <rate>\n");
+                                }
+                                (Some(rate), true) => {
+                                    let mut console_writer = ConsoleWriter::new();
+                                    let _ = write(
+                                        &mut console_writer,
+                                        format_args!("Switching to {} baud\n", rate),
+                                    );
+                                    let _ = self
+                                        .write_bytes(&(console_writer.buf)[..console_writer.size]);
+                                    self.pending_action.set(PendingAction::Baud(rate));
+                                }
+                            }
+                        } else if name == "reboot" {
+                            if self.reset_function.is_some() {
+                                let _ = self.write_bytes(b"Rebooting\n");
+                                self.pending_action.set(PendingAction::Reboot);
+                            } else {
+                                let _ = self.write_bytes(b"This board cannot reboot\n");
+                            }
+                        } else {
+                            self.write_valid_commands();
                         }
                     }
                     Err(_e) => {
@@ -972,6 +1540,206 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             command[0] = 0;
         });
         self.command_index.set(0);
+        self.command_cursor.set(0);
+    }
+
+    /// Run the board's command named by the first word of `command`. Returns
+    /// whether there was such a command.
+    fn run_board_command(&self, command: &str) -> bool {
+        let (name, arguments) = split_command(command);
+        self.commands.map_or(false, |commands| {
+            commands
+                .iter()
+                .find(|board_command| board_command.name() == name)
+                .map_or(false, |board_command| {
+                    let mut console_writer = ConsoleWriter::new();
+                    board_command.execute(arguments, &mut console_writer);
+                    let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+                    true
+                })
+        })
+    }
+
+    /// Run `memory <name> <address> [length]`.
+    fn memory_command(&self, command: &str) {
+        let mut arguments = command.split_whitespace().skip(1);
+        let name = arguments.next();
+        let address = arguments.next().and_then(parse_hex);
+        let len = match arguments.next() {
+            Some(len) => len.parse::<usize>().ok(),
+            None => Some(64),
+        };
+        match (name, address, len) {
+            (Some(name), Some(address), Some(len)) => {
+                let len = cmp::min(len, MEMORY_MAX_LEN);
+                self.with_process_named(name, |proc| {
+                    self.write_lines(
+                        WriterState::MemoryStart,
+                        Some(proc.processid()),
+                        address,
+                        address.saturating_add(len),
+                    );
+                });
+            }
+            _ => {
+                let _ = self.write_bytes(b"Usage: memory <process> <hex address> [length]\n");
+            }
+        }
+    }
+
+    /// Handle a byte typed on the console: edit the command, or execute it
+    /// at the end of the line.
+    fn receive_byte(&self, byte: u8) {
+        match self.escape_state.replace(EscapeState::None) {
+            EscapeState::Escape => {
+                if byte == b'[' {
+                    self.escape_state.set(EscapeState::Bracket);
+                }
+                return;
+            }
+            EscapeState::Bracket => {
+                match byte {
+                    b'A' => self.browse_history(true),
+                    b'B' => self.browse_history(false),
+                    b'C' => self.move_cursor(true),
+                    b'D' => self.move_cursor(false),
+                    _ => {}
+                }
+                return;
+            }
+            EscapeState::None => {}
+        }
+
+        self.command_buffer.map(|command| {
+            let index = self.command_index.get();
+            let cursor = self.command_cursor.get();
+            if byte == b'\n' || byte == b'\r' {
+                self.execute.set(true);
+                let _ = self.write_bytes(&[b'\r', b'\n']);
+            } else if byte == 0x1b {
+                self.escape_state.set(EscapeState::Escape);
+            } else if (byte == b'\x08' || byte == 0x7f) && cursor > 0 {
+                // Backspace, remove the byte before the cursor. Move back,
+                // reprint the rest of the line over it, erase the last
+                // character and move back to the cursor.
+                command.copy_within(cursor..index + 1, cursor - 1);
+                self.command_index.set(index - 1);
+                self.command_cursor.set(cursor - 1);
+                let mut console_writer = ConsoleWriter::new();
+                let _ = write(&mut console_writer, format_args!("\x08"));
+                console_writer.write_bytes(&command[cursor - 1..index - 1]);
+                let _ = write(&mut console_writer, format_args!(" "));
+                let _ = write_cursor_left(&mut console_writer, index - cursor + 1);
+                let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+            } else if index < (command.len() - 1) && byte >= 0x20 && byte < 0x7f {
+                // Insert the byte at the cursor, echo it and the rest of the
+                // line after it, and move back to just after it.
+                command.copy_within(cursor..index + 1, cursor + 1);
+                command[cursor] = byte;
+                self.command_index.set(index + 1);
+                self.command_cursor.set(cursor + 1);
+                if cursor == index {
+                    let _ = self.write_byte(byte);
+                } else {
+                    let mut console_writer = ConsoleWriter::new();
+                    console_writer.write_bytes(&command[cursor..index + 1]);
+                    let _ = write_cursor_left(&mut console_writer, index - cursor);
+                    let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+                }
+            }
+        });
+    }
+
+    /// Move the cursor one character right or left in the command.
+    fn move_cursor(&self, right: bool) {
+        let cursor = self.command_cursor.get();
+        if right && cursor < self.command_index.get() {
+            self.command_cursor.set(cursor + 1);
+            let _ = self.write_bytes(b"\x1b[C");
+        } else if !right && cursor > 0 {
+            self.command_cursor.set(cursor - 1);
+            let _ = self.write_bytes(b"\x1b[D");
+        }
+    }
+
+    /// Number of commands the history buffer holds.
+    fn history_slots(&self) -> usize {
+        self.history_buffer.map_or(0, |history| {
+            history.len().checked_div(self.command_len).unwrap_or(0)
+        })
+    }
+
+    /// Save `command` as the newest command in the history, unless it is
+    /// empty or repeats the newest one.
+    fn save_history(&self, command: &str) {
+        self.history_position.set(0);
+        let slots = self.history_slots();
+        if command.is_empty() || slots == 0 {
+            return;
+        }
+        if self.history_count.get() > 0
+            && self.history_entry(1, |entry| entry == command.as_bytes())
+        {
+            return;
+        }
+        let slot_len = self.command_len;
+        self.history_buffer.map(|history| {
+            let slot = &mut history[self.history_next.get() * slot_len..][..slot_len];
+            let len = cmp::min(command.len(), slot_len - 1);
+            slot[..len].copy_from_slice(&command.as_bytes()[..len]);
+            slot[len] = 0;
+        });
+        self.history_next.set((self.history_next.get() + 1) % slots);
+        self.history_count
+            .set(cmp::min(self.history_count.get() + 1, slots));
+    }
+
+    /// Call `f` with the history entry `back` commands ago, counting from 1.
+    fn history_entry<F: FnOnce(&[u8]) -> bool>(&self, back: usize, f: F) -> bool {
+        let slots = self.history_slots();
+        let slot_len = self.command_len;
+        let slot = (self.history_next.get() + slots - back) % slots;
+        self.history_buffer.map_or(false, |history| {
+            let entry = &history[slot * slot_len..][..slot_len];
+            let len = entry.iter().position(|&b| b == 0).unwrap_or(slot_len);
+            f(&entry[..len])
+        })
+    }
+
+    /// Replace the command with the previous (`older`) or next command in the
+    /// history. Going past the newest command clears the line.
+    fn browse_history(&self, older: bool) {
+        let position = self.history_position.get();
+        let position = if older && position < self.history_count.get() {
+            position + 1
+        } else if !older && position > 0 {
+            position - 1
+        } else {
+            return;
+        };
+        self.history_position.set(position);
+
+        let mut console_writer = ConsoleWriter::new();
+        // Return to the start of the line and clear it.
+        let _ = write(&mut console_writer, format_args!("\r\x1b[K"));
+        self.command_buffer.map(|command| {
+            let len = if position == 0 {
+                0
+            } else {
+                let mut len = 0;
+                self.history_entry(position, |entry| {
+                    len = entry.len();
+                    command[..len].copy_from_slice(entry);
+                    true
+                });
+                len
+            };
+            command[len] = 0;
+            self.command_index.set(len);
+            self.command_cursor.set(len);
+            console_writer.write_bytes(&command[..len]);
+        });
+        let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
     }
 
     fn write_state(&self, state: WriterState, process: Option<ProcessId>) {
@@ -980,7 +1748,9 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             self.writer_process.replace(process);
         }
 
-        if !self.tx_in_progress.get() {
+        // Keep going until a state prints something, as states such as
+        // `GrantLine` may have nothing to print.
+        while !self.tx_in_progress.get() && self.writer_state.get() != WriterState::Empty {
             self.writer_state
                 .replace(self.next_state(self.writer_state.take()));
             self.create_state_buffer(self.writer_state.get(), self.writer_process.get());
@@ -1037,10 +1807,15 @@ impl<'a, C: ProcessManagementCapability> uart::TransmitClient for ProcessConsole
         self.tx_in_progress.set(false);
 
         // If in the middle of an active state, finish the state machine.
-        if self.writer_state.get() != WriterState::Empty
-            && self.writer_state.get() != WriterState::KernelStart
-            && self.writer_state.get() != WriterState::ProcessStart
-        {
+        if !matches!(
+            self.writer_state.get(),
+            WriterState::Empty
+                | WriterState::KernelStart
+                | WriterState::ProcessStart
+                | WriterState::MemoryStart
+                | WriterState::GrantsStart
+                | WriterState::DriversStart
+        ) {
             self.write_state(WriterState::Empty, None);
         }
 
@@ -1065,6 +1840,28 @@ impl<'a, C: ProcessManagementCapability> uart::TransmitClient for ProcessConsole
             self.execute.set(false);
             self.read_command();
         }
+
+        // Once everything has been printed, run the action that would have
+        // cut the output short.
+        if !self.tx_in_progress.get() && self.writer_state.get() == WriterState::Empty {
+            match self.pending_action.replace(PendingAction::None) {
+                PendingAction::None => {}
+                PendingAction::Reboot => {
+                    self.reset_function.map(|reset| reset());
+                }
+                PendingAction::Baud(rate) => {
+                    self.uart_configure.map(|configure| {
+                        let _ = configure.configure(uart::Parameters {
+                            baud_rate: rate,
+                            width: uart::Width::Eight,
+                            stop_bits: uart::StopBits::One,
+                            parity: uart::Parity::None,
+                            hw_flow_control: false,
+                        });
+                    });
+                }
+            }
+        }
     }
 }
 impl<'a, C: ProcessManagementCapability> uart::ReceiveClient for ProcessConsole<'a, C> {
@@ -1078,30 +1875,7 @@ impl<'a, C: ProcessManagementCapability> uart::ReceiveClient for ProcessConsole<
         if error == uart::Error::None {
             match rx_len {
                 0 => debug!("ProcessConsole had read of 0 bytes"),
-                1 => {
-                    self.command_buffer.map(|command| {
-                        let index = self.command_index.get() as usize;
-                        if read_buf[0] == ('\n' as u8) || read_buf[0] == ('\r' as u8) {
-                            self.execute.set(true);
-                            let _ = self.write_bytes(&['\r' as u8, '\n' as u8]);
-                        } else if read_buf[0] == ('\x08' as u8) && index > 0 {
-                            // Backspace, echo and remove last byte
-                            // Note echo is '\b \b' to erase
-                            let _ = self.write_bytes(&['\x08' as u8, ' ' as u8, '\x08' as u8]);
-                            command[index - 1] = '\0' as u8;
-                            self.command_index.set(index - 1);
-                        } else if index < (command.len() - 1) && read_buf[0] < 128 {
-                            // For some reason, sometimes reads return > 127 but no error,
-                            // which causes utf-8 decoding failure, so check byte is < 128. -pal
-
-                            // Echo the byte and store it
-                            let _ = self.write_byte(read_buf[0]);
-                            command[index] = read_buf[0];
-                            self.command_index.set(index + 1);
-                            command[index + 1] = 0;
-                        }
-                    });
-                }
+                1 => self.receive_byte(read_buf[0]),
                 _ => debug!(
                     "ProcessConsole issues reads of 1 byte, but receive_complete was length {}",
                     rx_len
@@ -1112,3 +1886,62 @@ impl<'a, C: ProcessManagementCapability> uart::ReceiveClient for ProcessConsole<
         let _ = self.uart.receive_buffer(read_buf, 1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn memory_line() {
+        let mut writer = ConsoleWriter::new();
+        let _ = write_memory_line(&mut writer, 0x20004000, b"Tock\x00\xff");
+        assert_eq!(
+            str::from_utf8(&writer.buf[..writer.size]).unwrap(),
+            "  0x20004000  54 6f 63 6b 00 ff                                |Tock..|\n"
+        );
+    }
+
+    #[test]
+    fn hex_arguments() {
+        assert_eq!(parse_hex("0x20004000"), Some(0x20004000));
+        assert_eq!(parse_hex("1f"), Some(0x1f));
+        assert_eq!(parse_hex("0xg"), None);
+    }
+
+    #[test]
+    fn commands_match_whole_words() {
+        assert_eq!(split_command("drivers"), ("drivers", ""));
+        assert_eq!(split_command("driversX"), ("driversX", ""));
+        assert_eq!(
+            split_command(" memory  blink 0x20004000 "),
+            ("memory", "blink 0x20004000")
+        );
+        assert_eq!(split_command(""), ("", ""));
+    }
+
+    /// A driver table with the console, the alarm and a driver from another
+    /// crate.
+    struct Drivers;
+    impl DriverLookup for Drivers {
+        fn has_driver(&self, driver_num: usize) -> bool {
+            matches!(driver_num, 0x0 | 0x1 | 0xa0000)
+        }
+    }
+
+    #[test]
+    fn drivers_come_from_the_driver_table() {
+        let listed: Vec<usize> = (0..driver::NUM::ALL.len() + 3)
+            .filter_map(|index| {
+                listed_driver(index, &Drivers, |grant_num| {
+                    // Grants of the console, a driver the board does not have,
+                    // and the other crate's driver.
+                    [0x1, 0xb0000, 0xa0000].get(grant_num).copied()
+                })
+            })
+            .collect();
+        assert_eq!(listed, [0x0, 0x1, 0xa0000]);
+    }
+}
@@ -0,0 +1,297 @@
+//! Save core dumps of faulted processes over a UART or into flash.
+//!
+//! Both capsules are `CoreDumpClient`s for `kernel::procs::CoreDumpFaultPolicy`.
+//! When a process faults they copy its ELF core file into a staging buffer,
+//! since the process's memory may be reused as soon as the fault policy acts,
+//! and then write it out:
+//!
+//! - `CoreDumpConsole` prints the core file as `COREDUMP` hex lines, which
+//!   `tools/crash-dump --core` turns back into the core file:
+//!
+//!   ```text
+//!   ---| Core dump of process blink |---
+//!   COREDUMP 000000 7f454c46010101000000000000000000040028000100000000000000
+//!   ...
+//!   COREDUMP END
+//!   ```
+//!
+//! - `CoreDumpFlash` writes the core file to the start of a region of
+//!   nonvolatile storage, where a debugger can read it back.
+//!
+//! The core file can then be loaded with the app's ELF, for example with
+//! `arm-none-eabi-gdb app.elf blink.core`. Dumps that do not fit in the
+//! staging buffer, or that arrive while the previous one is still being
+//! written, are dropped with a debug message.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! static FAULT_POLICY: kernel::procs::CoreDumpFaultPolicy<kernel::procs::StopFaultPolicy> =
+//!     kernel::procs::CoreDumpFaultPolicy::new(kernel::procs::StopFaultPolicy {});
+//!
+//! let staging = static_init!([u8; 16384], [0; 16384]);
+//! let core_dump = static_init!(
+//!     capsules::process_core_dump::CoreDumpConsole<'static>,
+//!     capsules::process_core_dump::CoreDumpConsole::new(
+//!         uart_device,
+//!         staging,
+//!         &mut capsules::process_core_dump::WRITE_BUF,
+//!     )
+//! );
+//! hil::uart::Transmit::set_transmit_client(uart_device, core_dump);
+//! FAULT_POLICY.set_client(core_dump);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::TakeCell;
+use kernel::debug;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::uart;
+use kernel::procs::{CoreDumpClient, Process, ProcessCoreDump};
+use kernel::ErrorCode;
+
+/// Bytes of the core file on each hex line.
+const BYTES_PER_LINE: usize = 32;
+
+/// Longest hex line: "COREDUMP ", offset, space, hex bytes and line end.
+const LINE_LEN: usize = 18 + 2 * BYTES_PER_LINE;
+
+pub static mut WRITE_BUF: [u8; 128] = [0; 128];
+
+const BANNER_START: &[u8] = b"\r\n---| Core dump of process ";
+const BANNER_END: &[u8] = b" |---\r\n";
+const END: &[u8] = b"COREDUMP END\r\n";
+
+/// Copy `dump` into `staging`. Returns the length of the dump, or `None` if
+/// it does not fit.
+fn stage(process: &dyn Process, dump: &ProcessCoreDump, staging: &mut [u8]) -> Option<usize> {
+    if dump.len() > staging.len() {
+        debug!(
+            "Core dump of {} dropped: {} bytes do not fit in {}",
+            process.get_process_name(),
+            dump.len(),
+            staging.len()
+        );
+        return None;
+    }
+    Some(dump.read(0, staging))
+}
+
+/// Prints core dumps over a UART.
+pub struct CoreDumpConsole<'a> {
+    uart: &'a dyn uart::Transmit<'a>,
+    /// The core file being printed, or the empty buffer.
+    staging: TakeCell<'static, [u8]>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    /// Length of the core file in `staging`, zero if there is none.
+    dump_len: Cell<usize>,
+    name: Cell<&'static str>,
+    /// Index of the piece being printed.
+    piece: Cell<usize>,
+    /// Bytes of that piece already printed.
+    offset: Cell<usize>,
+}
+
+impl<'a> CoreDumpConsole<'a> {
+    pub fn new(
+        uart: &'a dyn uart::Transmit<'a>,
+        staging: &'static mut [u8],
+        tx_buffer: &'static mut [u8],
+    ) -> CoreDumpConsole<'a> {
+        CoreDumpConsole {
+            uart,
+            staging: TakeCell::new(staging),
+            tx_buffer: TakeCell::new(tx_buffer),
+            dump_len: Cell::new(0),
+            name: Cell::new(""),
+            piece: Cell::new(0),
+            offset: Cell::new(0),
+        }
+    }
+
+    /// Copy the `index`th piece of the output into `line` and return its
+    /// length, or `None` after the last piece.
+    fn piece(&self, index: usize, line: &mut [u8; LINE_LEN]) -> Option<usize> {
+        let copy = |line: &mut [u8; LINE_LEN], bytes: &[u8]| {
+            let len = cmp::min(bytes.len(), LINE_LEN);
+            line[..len].copy_from_slice(&bytes[..len]);
+            len
+        };
+        let lines = (self.dump_len.get() + BYTES_PER_LINE - 1) / BYTES_PER_LINE;
+        match index {
+            0 => Some(copy(line, BANNER_START)),
+            1 => Some(copy(line, self.name.get().as_bytes())),
+            2 => Some(copy(line, BANNER_END)),
+            _ if index - 3 < lines => {
+                let start = (index - 3) * BYTES_PER_LINE;
+                let end = cmp::min(start + BYTES_PER_LINE, self.dump_len.get());
+                self.staging
+                    .map(|staging| hex_line(start, &staging[start..end], line))
+            }
+            _ if index - 3 == lines => Some(copy(line, END)),
+            _ => None,
+        }
+    }
+
+    /// Fill `buffer` with the next part of the output and transmit it, or
+    /// release the staging buffer if everything was printed.
+    fn send(&self, buffer: &'static mut [u8]) {
+        let mut len = 0;
+        let mut line = [0; LINE_LEN];
+        while len < buffer.len() {
+            let piece_len = match self.piece(self.piece.get(), &mut line) {
+                Some(piece_len) => piece_len,
+                None => break,
+            };
+            let remaining = &line[self.offset.get()..piece_len];
+            let count = cmp::min(remaining.len(), buffer.len() - len);
+            buffer[len..len + count].copy_from_slice(&remaining[..count]);
+            len += count;
+            if count == remaining.len() {
+                self.piece.set(self.piece.get() + 1);
+                self.offset.set(0);
+            } else {
+                self.offset.set(self.offset.get() + count);
+            }
+        }
+
+        if len == 0 {
+            self.dump_len.set(0);
+            self.tx_buffer.replace(buffer);
+            return;
+        }
+        let _ = self
+            .uart
+            .transmit_buffer(buffer, len)
+            .map_err(|(_, buffer)| {
+                self.dump_len.set(0);
+                self.tx_buffer.replace(buffer);
+            });
+    }
+}
+
+/// Format `bytes`, found at `offset` in the core file, as a hex line.
+fn hex_line(offset: usize, bytes: &[u8], line: &mut [u8; LINE_LEN]) -> usize {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut len = 0;
+    let mut put = |byte: u8| {
+        line[len] = byte;
+        len += 1;
+    };
+    b"COREDUMP ".iter().for_each(|&byte| put(byte));
+    for shift in [20, 16, 12, 8, 4, 0].iter() {
+        put(HEX[(offset >> shift) & 0xF]);
+    }
+    put(b' ');
+    for byte in bytes {
+        put(HEX[(byte >> 4) as usize]);
+        put(HEX[(byte & 0xF) as usize]);
+    }
+    put(b'\r');
+    put(b'\n');
+    len
+}
+
+impl CoreDumpClient for CoreDumpConsole<'_> {
+    fn process_faulted(&self, process: &dyn Process, dump: &ProcessCoreDump) {
+        if self.dump_len.get() != 0 {
+            debug!("Core dump of {} dropped: busy", process.get_process_name());
+            return;
+        }
+        let len = match self
+            .staging
+            .map(|staging| stage(process, dump, staging))
+            .flatten()
+        {
+            Some(len) => len,
+            None => return,
+        };
+        self.dump_len.set(len);
+        self.name.set(process.get_process_name());
+        self.piece.set(0);
+        self.offset.set(0);
+        if let Some(buffer) = self.tx_buffer.take() {
+            self.send(buffer);
+        }
+    }
+}
+
+impl uart::TransmitClient for CoreDumpConsole<'_> {
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        _rval: Result<(), ErrorCode>,
+    ) {
+        self.send(buffer);
+    }
+}
+
+/// Writes core dumps into nonvolatile storage.
+pub struct CoreDumpFlash<'a> {
+    storage: &'a dyn NonvolatileStorage<'static>,
+    /// The core file, or `None` while it is being written.
+    staging: TakeCell<'static, [u8]>,
+    /// Where the core file is written.
+    region_start: usize,
+    region_len: usize,
+}
+
+impl<'a> CoreDumpFlash<'a> {
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'static>,
+        staging: &'static mut [u8],
+        region_start: usize,
+        region_len: usize,
+    ) -> CoreDumpFlash<'a> {
+        CoreDumpFlash {
+            storage,
+            staging: TakeCell::new(staging),
+            region_start,
+            region_len,
+        }
+    }
+}
+
+impl CoreDumpClient for CoreDumpFlash<'_> {
+    fn process_faulted(&self, process: &dyn Process, dump: &ProcessCoreDump) {
+        let staging = match self.staging.take() {
+            Some(staging) => staging,
+            None => {
+                debug!("Core dump of {} dropped: busy", process.get_process_name());
+                return;
+            }
+        };
+        let capacity = cmp::min(staging.len(), self.region_len);
+        let len = match stage(process, dump, &mut staging[..capacity]) {
+            Some(len) => len,
+            None => {
+                self.staging.replace(staging);
+                return;
+            }
+        };
+        if let Err((e, staging)) = self.storage.write(staging, self.region_start, len) {
+            self.staging.replace(staging);
+            debug!(
+                "Core dump of {} not saved: {:?}",
+                process.get_process_name(),
+                e
+            );
+        }
+    }
+}
+
+impl NonvolatileStorageClient<'static> for CoreDumpFlash<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.staging.replace(buffer);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.staging.replace(buffer);
+    }
+}
@@ -0,0 +1,377 @@
+//! 256-bit integers and modular arithmetic for elliptic curve cryptography.
+//!
+//! Arithmetic modulo the curve primes and group orders uses Montgomery
+//! multiplication, which works for any odd modulus below 2^256. Values in the
+//! Montgomery domain are ordinary `U256`s; it is up to the caller to keep
+//! track of which domain a value is in.
+//!
+//! Modular addition, subtraction and multiplication do not branch on their
+//! operands, so they can be used on secret values. Comparisons, `pow` and
+//! `inv` branch on their inputs or exponent, which must be public.
+
+use core::cmp::Ordering;
+
+/// Number of 32-bit limbs in a `U256`.
+const LIMBS: usize = 8;
+
+/// An unsigned 256-bit integer, as little-endian 32-bit limbs.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct U256(pub [u32; LIMBS]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; LIMBS]);
+    pub const ONE: U256 = U256([1, 0, 0, 0, 0, 0, 0, 0]);
+
+    pub const fn from_u32(v: u32) -> U256 {
+        U256([v, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    /// Parse 64 hexadecimal digits, most significant first. Used for curve
+    /// constants.
+    pub const fn from_be_hex(hex: &str) -> U256 {
+        let bytes = hex.as_bytes();
+        let mut limbs = [0u32; LIMBS];
+        let mut i = 0;
+        while i < 64 {
+            let c = bytes[i];
+            let digit = match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'f' => c - b'a' + 10,
+                b'A'..=b'F' => c - b'A' + 10,
+                // Indexing out of bounds fails const evaluation on an invalid
+                // digit.
+                _ => [0u8; 0][c as usize],
+            };
+            let bit = (63 - i) * 4;
+            limbs[bit / 32] |= (digit as u32) << (bit % 32);
+            i += 1;
+        }
+        U256(limbs)
+    }
+
+    pub fn from_be_bytes(bytes: &[u8; 32]) -> U256 {
+        let mut limbs = [0u32; LIMBS];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let j = 32 - 4 * (i + 1);
+            *limb = u32::from_be_bytes([bytes[j], bytes[j + 1], bytes[j + 2], bytes[j + 3]]);
+        }
+        U256(limbs)
+    }
+
+    pub fn from_le_bytes(bytes: &[u8; 32]) -> U256 {
+        let mut limbs = [0u32; LIMBS];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let j = 4 * i;
+            *limb = u32::from_le_bytes([bytes[j], bytes[j + 1], bytes[j + 2], bytes[j + 3]]);
+        }
+        U256(limbs)
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            let j = 32 - 4 * (i + 1);
+            bytes[j..j + 4].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            bytes[4 * i..4 * i + 4].copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|limb| *limb == 0)
+    }
+
+    /// Bit `i`, counting from the least significant bit.
+    pub fn bit(&self, i: usize) -> bool {
+        (self.0[i / 32] >> (i % 32)) & 1 == 1
+    }
+
+    /// `a` if `choice` is 0 and `b` if it is 1, without branching on
+    /// `choice`.
+    pub fn select(a: &U256, b: &U256, choice: u32) -> U256 {
+        let mask = choice.wrapping_neg();
+        let mut result = [0u32; LIMBS];
+        for i in 0..LIMBS {
+            result[i] = a.0[i] ^ (mask & (a.0[i] ^ b.0[i]));
+        }
+        U256(result)
+    }
+
+    /// Swap `a` and `b` if `choice` is 1, without branching on `choice`.
+    pub fn swap(a: &mut U256, b: &mut U256, choice: u32) {
+        let mask = choice.wrapping_neg();
+        for i in 0..LIMBS {
+            let t = mask & (a.0[i] ^ b.0[i]);
+            a.0[i] ^= t;
+            b.0[i] ^= t;
+        }
+    }
+
+    /// `self + other`, and whether the addition overflowed.
+    pub fn add(&self, other: &U256) -> (U256, bool) {
+        let mut result = [0u32; LIMBS];
+        let mut carry = 0u64;
+        for i in 0..LIMBS {
+            let sum = self.0[i] as u64 + other.0[i] as u64 + carry;
+            result[i] = sum as u32;
+            carry = sum >> 32;
+        }
+        (U256(result), carry != 0)
+    }
+
+    /// `self - other`, and whether the subtraction underflowed.
+    pub fn sub(&self, other: &U256) -> (U256, bool) {
+        let mut result = [0u32; LIMBS];
+        let mut borrow = 0i64;
+        for i in 0..LIMBS {
+            let diff = self.0[i] as i64 - other.0[i] as i64 + borrow;
+            result[i] = diff as u32;
+            borrow = diff >> 32;
+        }
+        (U256(result), borrow != 0)
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> Ordering {
+        for i in (0..LIMBS).rev() {
+            match self.0[i].cmp(&other.0[i]) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Arithmetic modulo an odd number `m`.
+///
+/// `add`, `sub` and `neg` work in either domain. `mul`, `square`, `pow` and
+/// `inv` take and return values in the Montgomery domain, where `x` is
+/// represented as `x * 2^256 mod m`. All inputs must be reduced modulo `m`.
+pub struct Modulus {
+    m: U256,
+    /// `-m^-1 mod 2^32`.
+    m_inv: u32,
+    /// `2^512 mod m`, used to convert into the Montgomery domain.
+    r2: U256,
+}
+
+impl Modulus {
+    pub fn new(m: U256) -> Modulus {
+        // Newton's iteration doubles the number of correct low bits each
+        // step, starting from 1 correct bit for odd `m`.
+        let mut inv: u32 = 1;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(m.0[0].wrapping_mul(inv)));
+        }
+
+        let mut modulus = Modulus {
+            m,
+            m_inv: inv.wrapping_neg(),
+            r2: U256::ZERO,
+        };
+
+        let mut r2 = U256::ONE;
+        for _ in 0..512 {
+            r2 = modulus.add(&r2, &r2);
+        }
+        modulus.r2 = r2;
+        modulus
+    }
+
+    pub fn modulus(&self) -> &U256 {
+        &self.m
+    }
+
+    pub fn add(&self, a: &U256, b: &U256) -> U256 {
+        let (sum, carry) = a.add(b);
+        let (reduced, borrow) = sum.sub(&self.m);
+        // `sum` is already reduced if subtracting `m` underflows, unless the
+        // addition itself overflowed.
+        U256::select(&reduced, &sum, (borrow & !carry) as u32)
+    }
+
+    pub fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (diff, borrow) = a.sub(b);
+        U256::select(&diff, &diff.add(&self.m).0, borrow as u32)
+    }
+
+    pub fn neg(&self, a: &U256) -> U256 {
+        self.sub(&U256::ZERO, a)
+    }
+
+    /// Montgomery multiplication: `a * b * 2^-256 mod m`.
+    ///
+    /// Also correct for any `a < 2^256` as long as `b < m`.
+    pub fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let m = &self.m.0;
+        let mut t = [0u32; LIMBS + 2];
+        for i in 0..LIMBS {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let s = t[j] as u64 + a.0[j] as u64 * b.0[i] as u64 + carry;
+                t[j] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[LIMBS] as u64 + carry;
+            t[LIMBS] = s as u32;
+            t[LIMBS + 1] = (s >> 32) as u32;
+
+            let q = t[0].wrapping_mul(self.m_inv);
+            let s = t[0] as u64 + q as u64 * m[0] as u64;
+            let mut carry = s >> 32;
+            for j in 1..LIMBS {
+                let s = t[j] as u64 + q as u64 * m[j] as u64 + carry;
+                t[j - 1] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[LIMBS] as u64 + carry;
+            t[LIMBS - 1] = s as u32;
+            t[LIMBS] = t[LIMBS + 1] + (s >> 32) as u32;
+        }
+
+        let mut result = [0u32; LIMBS];
+        result.copy_from_slice(&t[..LIMBS]);
+        let result = U256(result);
+        let (reduced, borrow) = result.sub(&self.m);
+        U256::select(&reduced, &result, (borrow & (t[LIMBS] == 0)) as u32)
+    }
+
+    pub fn square(&self, a: &U256) -> U256 {
+        self.mul(a, a)
+    }
+
+    /// Convert `a` into the Montgomery domain, reducing it modulo `m`. Any
+    /// `a < 2^256` is accepted.
+    pub fn to_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &self.r2)
+    }
+
+    /// Convert the 512-bit number `hi * 2^256 + lo` into the Montgomery
+    /// domain, reducing it modulo `m`.
+    pub fn to_mont_wide(&self, hi: &U256, lo: &U256) -> U256 {
+        // hi * 2^256 in the Montgomery domain is hi * 2^512, which is the
+        // Montgomery product of hi * 2^256 and 2^512.
+        let hi = self.mul(&self.to_mont(hi), &self.r2);
+        self.add(&hi, &self.to_mont(lo))
+    }
+
+    /// Convert `a` out of the Montgomery domain.
+    pub fn from_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &U256::ONE)
+    }
+
+    /// One in the Montgomery domain.
+    pub fn one(&self) -> U256 {
+        self.to_mont(&U256::ONE)
+    }
+
+    /// `a^e` for `a` in the Montgomery domain and an ordinary exponent `e`,
+    /// which must be public.
+    pub fn pow(&self, a: &U256, e: &U256) -> U256 {
+        let mut result = self.one();
+        for i in (0..256).rev() {
+            result = self.square(&result);
+            if e.bit(i) {
+                result = self.mul(&result, a);
+            }
+        }
+        result
+    }
+
+    /// `a^-1` for `a` in the Montgomery domain. `m` must be prime and `a`
+    /// non-zero.
+    pub fn inv(&self, a: &U256) -> U256 {
+        let exponent = self.m.sub(&U256::from_u32(2)).0;
+        self.pow(a, &exponent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P256: U256 =
+        U256::from_be_hex("ffffffff00000001000000000000000000000000ffffffffffffffffffffffff");
+
+    #[test]
+    fn byte_round_trip() {
+        let mut bytes = [0u8; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        assert_eq!(U256::from_be_bytes(&bytes).to_be_bytes(), bytes);
+        assert_eq!(U256::from_le_bytes(&bytes).to_le_bytes(), bytes);
+        assert_eq!(U256::from_be_bytes(&bytes).0[0], 0x1c1d1e1f);
+        assert_eq!(U256::from_le_bytes(&bytes).0[0], 0x03020100);
+    }
+
+    #[test]
+    fn montgomery_round_trip() {
+        let p = Modulus::new(P256);
+        let x =
+            U256::from_be_hex("6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296");
+        assert_eq!(p.from_mont(&p.to_mont(&x)), x);
+    }
+
+    #[test]
+    fn multiply_and_invert() {
+        let p = Modulus::new(P256);
+        let a = p.to_mont(&U256::from_u32(6));
+        let b = p.to_mont(&U256::from_u32(7));
+        assert_eq!(p.from_mont(&p.mul(&a, &b)), U256::from_u32(42));
+
+        let a_inv = p.inv(&a);
+        assert_eq!(p.mul(&a, &a_inv), p.one());
+    }
+
+    #[test]
+    fn reduce_large_values() {
+        let p = Modulus::new(P256);
+        // (p + 5) mod p = 5
+        let x = P256.add(&U256::from_u32(5)).0;
+        assert_eq!(p.from_mont(&p.to_mont(&x)), U256::from_u32(5));
+        // (1 * 2^256 + 0) mod p = 2^256 - p
+        let wide = p.to_mont_wide(&U256::ONE, &U256::ZERO);
+        assert_eq!(p.from_mont(&wide), U256::ZERO.sub(&P256).0);
+    }
+
+    #[test]
+    fn reduce_at_the_modulus() {
+        let p = Modulus::new(P256);
+        let max = P256.sub(&U256::ONE).0;
+        assert_eq!(p.add(&max, &U256::ONE), U256::ZERO);
+        assert_eq!(p.add(&max, &max), max.sub(&U256::ONE).0);
+        assert_eq!(p.sub(&U256::ZERO, &U256::ONE), max);
+        assert_eq!(p.sub(&max, &max), U256::ZERO);
+        assert_eq!(p.neg(&U256::ZERO), U256::ZERO);
+    }
+
+    #[test]
+    fn select_and_swap() {
+        let a = U256::from_u32(1);
+        let b = P256;
+        assert_eq!(U256::select(&a, &b, 0), a);
+        assert_eq!(U256::select(&a, &b, 1), b);
+
+        let (mut x, mut y) = (a, b);
+        U256::swap(&mut x, &mut y, 0);
+        assert_eq!((x, y), (a, b));
+        U256::swap(&mut x, &mut y, 1);
+        assert_eq!((x, y), (b, a));
+    }
+}
@@ -0,0 +1,578 @@
+//! Ed25519 signatures (RFC 8032) in software.
+//!
+//! `Ed25519Software` implements `SignatureVerify<HL, 64>` and
+//! `SignatureSign<HL, 64>`, where the `HL` byte message is signed directly.
+//! Public keys are the 32 byte compressed encoding of the point `A`, private
+//! keys are the 32 byte seed from which the signing scalar is derived, and
+//! signatures are `R || S`. Operations run to completion in a deferred call.
+//!
+//! Scalar multiplication and the arithmetic on the signing scalar and nonce
+//! do not branch on secret data.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ed25519 = static_init!(
+//!     capsules::public_key_crypto::ed25519::Ed25519Software<'static, 32>,
+//!     capsules::public_key_crypto::ed25519::Ed25519Software::new(dynamic_deferred_caller)
+//! );
+//! ed25519.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ed25519)
+//!         .expect("no deferred call slot available for ed25519"),
+//! );
+//! ed25519.set_public_key(&PUBLIC_KEY).unwrap();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::public_key_crypto::{ClientSign, ClientVerify, SignatureSign, SignatureVerify};
+use kernel::ErrorCode;
+
+use super::bignum::{Modulus, U256};
+use crate::sha512::{Sha512State, SHA512_DIGEST_LEN};
+
+/// Length of public keys, private keys (seeds) and each signature half.
+pub const KEY_LEN: usize = 32;
+/// Length of a signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// The field prime 2^255 - 19.
+const P: U256 = U256([
+    0xffffffed, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0x7fffffff,
+]);
+/// (p - 5) / 8 = 2^252 - 3, the exponent used for square roots.
+const P_MINUS_5_DIV_8: U256 = U256([
+    0xfffffffd, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0x0fffffff,
+]);
+/// (p - 1) / 4 = 2^253 - 5; 2 raised to this is a square root of -1.
+const P_MINUS_1_DIV_4: U256 = U256([
+    0xfffffffb, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0x1fffffff,
+]);
+/// The order of the base point.
+const L: U256 =
+    U256::from_be_hex("1000000000000000000000000000000014def9dea2f79cd65812631a5cf5d3ed");
+
+/// A point in extended twisted Edwards coordinates, `x = X/Z`, `y = Y/Z`,
+/// `x * y = T/Z`. Coordinates are in the Montgomery domain.
+#[derive(Copy, Clone)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+    t: U256,
+}
+
+/// Curve constants, computed once per operation.
+struct Curve {
+    p: Modulus,
+    l: Modulus,
+    /// 2 * d, where d = -121665 / 121666.
+    d2: U256,
+    d: U256,
+    sqrt_m1: U256,
+    base: Point,
+}
+
+fn split(bytes: &[u8; SHA512_DIGEST_LEN]) -> ([u8; 32], [u8; 32]) {
+    let mut lo = [0; 32];
+    let mut hi = [0; 32];
+    lo.copy_from_slice(&bytes[..32]);
+    hi.copy_from_slice(&bytes[32..]);
+    (lo, hi)
+}
+
+impl Curve {
+    fn new() -> Curve {
+        let p = Modulus::new(P);
+        let small = |v| p.to_mont(&U256::from_u32(v));
+
+        let d = p.mul(&p.neg(&small(121665)), &p.inv(&small(121666)));
+        let d2 = p.add(&d, &d);
+        let sqrt_m1 = p.pow(&small(2), &P_MINUS_1_DIV_4);
+        // The base point is the point with y = 4/5 and even x.
+        let base_y = p.mul(&small(4), &p.inv(&small(5)));
+
+        let mut curve = Curve {
+            l: Modulus::new(L),
+            d2,
+            d,
+            sqrt_m1,
+            base: Point {
+                x: U256::ZERO,
+                y: U256::ZERO,
+                z: U256::ZERO,
+                t: U256::ZERO,
+            },
+            p,
+        };
+        curve.base = curve
+            .recover_x(base_y, false)
+            .expect("Ed25519 base point must decompress");
+        curve
+    }
+
+    fn identity(&self) -> Point {
+        Point {
+            x: U256::ZERO,
+            y: self.p.one(),
+            z: self.p.one(),
+            t: U256::ZERO,
+        }
+    }
+
+    /// Find the point with coordinate `y` whose `x` has the given sign bit.
+    fn recover_x(&self, y: U256, sign: bool) -> Option<Point> {
+        let p = &self.p;
+        let y2 = p.square(&y);
+        let u = p.sub(&y2, &p.one());
+        let v = p.add(&p.mul(&self.d, &y2), &p.one());
+
+        // x = u v^3 (u v^7)^((p - 5) / 8)
+        let v3 = p.mul(&p.square(&v), &v);
+        let v7 = p.mul(&p.square(&v3), &v);
+        let mut x = p.mul(&p.mul(&u, &v3), &p.pow(&p.mul(&u, &v7), &P_MINUS_5_DIV_8));
+
+        let vx2 = p.mul(&v, &p.square(&x));
+        if vx2 == u {
+        } else if vx2 == p.neg(&u) {
+            x = p.mul(&x, &self.sqrt_m1);
+        } else {
+            return None;
+        }
+
+        let x_plain = p.from_mont(&x);
+        if x_plain.is_zero() && sign {
+            return None;
+        }
+        if x_plain.bit(0) != sign {
+            x = p.neg(&x);
+        }
+
+        Some(Point {
+            x,
+            y,
+            z: p.one(),
+            t: p.mul(&x, &y),
+        })
+    }
+
+    fn decompress(&self, bytes: &[u8; 32]) -> Option<Point> {
+        let mut y = *bytes;
+        let sign = y[31] & 0x80 != 0;
+        y[31] &= 0x7f;
+        let y = U256::from_le_bytes(&y);
+        if y >= P {
+            return None;
+        }
+        self.recover_x(self.p.to_mont(&y), sign)
+    }
+
+    fn compress(&self, point: &Point) -> [u8; 32] {
+        let p = &self.p;
+        let z_inv = p.inv(&point.z);
+        let x = p.from_mont(&p.mul(&point.x, &z_inv));
+        let y = p.from_mont(&p.mul(&point.y, &z_inv));
+        let mut bytes = y.to_le_bytes();
+        if x.bit(0) {
+            bytes[31] |= 0x80;
+        }
+        bytes
+    }
+
+    /// Point addition (add-2008-hwcd-3), complete for all inputs so it is
+    /// also used for doubling.
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        let p = &self.p;
+        let aa = p.mul(&p.sub(&a.y, &a.x), &p.sub(&b.y, &b.x));
+        let bb = p.mul(&p.add(&a.y, &a.x), &p.add(&b.y, &b.x));
+        let cc = p.mul(&p.mul(&a.t, &self.d2), &b.t);
+        let zz = p.mul(&a.z, &b.z);
+        let dd = p.add(&zz, &zz);
+        let e = p.sub(&bb, &aa);
+        let f = p.sub(&dd, &cc);
+        let g = p.add(&dd, &cc);
+        let h = p.add(&bb, &aa);
+        Point {
+            x: p.mul(&e, &f),
+            y: p.mul(&g, &h),
+            z: p.mul(&f, &g),
+            t: p.mul(&e, &h),
+        }
+    }
+
+    fn neg(&self, a: &Point) -> Point {
+        Point {
+            x: self.p.neg(&a.x),
+            y: a.y,
+            z: a.z,
+            t: self.p.neg(&a.t),
+        }
+    }
+
+    /// Swap `a` and `b` if `choice` is 1, without branching on `choice`.
+    fn swap(a: &mut Point, b: &mut Point, choice: u32) {
+        U256::swap(&mut a.x, &mut b.x, choice);
+        U256::swap(&mut a.y, &mut b.y, choice);
+        U256::swap(&mut a.z, &mut b.z, choice);
+        U256::swap(&mut a.t, &mut b.t, choice);
+    }
+
+    /// `[k]point`, by a Montgomery ladder that runs the same additions for
+    /// every `k`, so the secret scalars used when signing do not leak
+    /// through timing.
+    fn scalar_mul(&self, k: &U256, point: &Point) -> Point {
+        let mut r0 = self.identity();
+        let mut r1 = *point;
+        for i in (0..256).rev() {
+            let bit = k.bit(i) as u32;
+            Curve::swap(&mut r0, &mut r1, bit);
+            r1 = self.add(&r0, &r1);
+            r0 = self.add(&r0, &r0);
+            Curve::swap(&mut r0, &mut r1, bit);
+        }
+        r0
+    }
+
+    /// Reduce a little-endian SHA-512 output modulo `L`.
+    fn reduce(&self, digest: &[u8; SHA512_DIGEST_LEN]) -> U256 {
+        let (lo, hi) = split(digest);
+        let l = &self.l;
+        l.from_mont(&l.to_mont_wide(&U256::from_le_bytes(&hi), &U256::from_le_bytes(&lo)))
+    }
+
+    /// `SHA-512(R || A || M) mod L`.
+    fn challenge(&self, r: &[u8; 32], public_key: &[u8; 32], message: &[u8]) -> U256 {
+        let mut digest = [0; SHA512_DIGEST_LEN];
+        let mut sha = Sha512State::new();
+        sha.update(r);
+        sha.update(public_key);
+        sha.update(message);
+        sha.finish(&mut digest);
+        self.reduce(&digest)
+    }
+}
+
+/// Derive the signing scalar and nonce prefix from a seed.
+fn expand(seed: &[u8; KEY_LEN]) -> (U256, [u8; 32]) {
+    let mut digest = [0; SHA512_DIGEST_LEN];
+    let mut sha = Sha512State::new();
+    sha.update(seed);
+    sha.finish(&mut digest);
+
+    let (mut scalar, prefix) = split(&digest);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    (U256::from_le_bytes(&scalar), prefix)
+}
+
+/// Compute the public key for the private key `seed`.
+pub fn public_key(seed: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let curve = Curve::new();
+    let (a, _) = expand(seed);
+    curve.compress(&curve.scalar_mul(&a, &curve.base))
+}
+
+/// Check that `signature` is a valid signature of `message` for
+/// `public_key`.
+pub fn verify(public_key: &[u8; KEY_LEN], message: &[u8], signature: &[u8; SIGNATURE_LEN]) -> bool {
+    let curve = Curve::new();
+    let a = match curve.decompress(public_key) {
+        Some(a) => a,
+        None => return false,
+    };
+
+    let (r, s) = split(signature);
+    let s = U256::from_le_bytes(&s);
+    if s >= L {
+        return false;
+    }
+    let k = curve.challenge(&r, public_key, message);
+
+    // [S]B - [k]A must equal R.
+    let sb = curve.scalar_mul(&s, &curve.base);
+    let ka = curve.scalar_mul(&k, &curve.neg(&a));
+    curve.compress(&curve.add(&sb, &ka)) == r
+}
+
+/// Sign `message` with the private key `seed`.
+pub fn sign(seed: &[u8; KEY_LEN], message: &[u8], signature: &mut [u8; SIGNATURE_LEN]) {
+    let curve = Curve::new();
+    let l = &curve.l;
+    let (a, prefix) = expand(seed);
+    let public_key = curve.compress(&curve.scalar_mul(&a, &curve.base));
+
+    let mut digest = [0; SHA512_DIGEST_LEN];
+    let mut sha = Sha512State::new();
+    sha.update(&prefix);
+    sha.update(message);
+    sha.finish(&mut digest);
+    let r = curve.reduce(&digest);
+    let big_r = curve.compress(&curve.scalar_mul(&r, &curve.base));
+
+    // S = r + k * a mod L
+    let k = curve.challenge(&big_r, &public_key, message);
+    let s = l.add(&l.to_mont(&r), &l.mul(&l.to_mont(&k), &l.to_mont(&a)));
+    let s = l.from_mont(&s);
+
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(&s.to_le_bytes());
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Verify,
+    Sign,
+}
+
+/// Ed25519 signature engine implemented in software, signing and verifying
+/// `HL` byte messages.
+pub struct Ed25519Software<'a, const HL: usize> {
+    verify_client: OptionalCell<&'a dyn ClientVerify<'a, HL, SIGNATURE_LEN>>,
+    sign_client: OptionalCell<&'a dyn ClientSign<'a, HL, SIGNATURE_LEN>>,
+
+    public_key: OptionalCell<[u8; KEY_LEN]>,
+    private_key: MapCell<[u8; KEY_LEN]>,
+
+    /// The operation in progress, along with its buffers.
+    operation: Cell<Operation>,
+    hash: TakeCell<'static, [u8; HL]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a, const HL: usize> Ed25519Software<'a, HL> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> Ed25519Software<'a, HL> {
+        Ed25519Software {
+            verify_client: OptionalCell::empty(),
+            sign_client: OptionalCell::empty(),
+            public_key: OptionalCell::empty(),
+            private_key: MapCell::empty(),
+            operation: Cell::new(Operation::Verify),
+            hash: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn busy(&self) -> bool {
+        self.hash.is_some()
+    }
+
+    fn start(
+        &self,
+        operation: Operation,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; HL],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.busy() || self.handle.is_none() {
+            return Err((ErrorCode::BUSY, hash, signature));
+        }
+        self.operation.set(operation);
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+}
+
+impl<'a, const HL: usize> SignatureVerify<'a, HL, SIGNATURE_LEN> for Ed25519Software<'a, HL> {
+    fn set_verify_client(&'a self, client: &'a dyn ClientVerify<'a, HL, SIGNATURE_LEN>) {
+        self.verify_client.set(client);
+    }
+
+    fn set_public_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if self.busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        if key.len() != KEY_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut public_key = [0; KEY_LEN];
+        public_key.copy_from_slice(key);
+        Curve::new()
+            .decompress(&public_key)
+            .ok_or(ErrorCode::INVAL)?;
+        self.public_key.set(public_key);
+        Ok(())
+    }
+
+    fn verify(
+        &'a self,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; HL],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.public_key.is_none() {
+            return Err((ErrorCode::RESERVE, hash, signature));
+        }
+        self.start(Operation::Verify, hash, signature)
+    }
+}
+
+impl<'a, const HL: usize> SignatureSign<'a, HL, SIGNATURE_LEN> for Ed25519Software<'a, HL> {
+    fn set_sign_client(&'a self, client: &'a dyn ClientSign<'a, HL, SIGNATURE_LEN>) {
+        self.sign_client.set(client);
+    }
+
+    fn set_private_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if self.busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        if key.len() != KEY_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut seed = [0; KEY_LEN];
+        seed.copy_from_slice(key);
+        self.private_key.replace(seed);
+        Ok(())
+    }
+
+    fn sign(
+        &'a self,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; HL],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.private_key.is_none() {
+            return Err((ErrorCode::RESERVE, hash, signature));
+        }
+        self.start(Operation::Sign, hash, signature)
+    }
+
+    fn clear_private_key(&self) {
+        self.private_key.map(|seed| *seed = [0; KEY_LEN]);
+        self.private_key.take();
+    }
+}
+
+impl<'a, const HL: usize> DynamicDeferredCallClient for Ed25519Software<'a, HL> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let (hash, signature) = match (self.hash.take(), self.signature.take()) {
+            (Some(hash), Some(signature)) => (hash, signature),
+            _ => return,
+        };
+
+        match self.operation.get() {
+            Operation::Verify => {
+                let result = self
+                    .public_key
+                    .map(|key| verify(key, &hash[..], signature))
+                    .ok_or(ErrorCode::RESERVE);
+                self.verify_client
+                    .map(move |client| client.verification_done(result, hash, signature));
+            }
+            Operation::Sign => {
+                let result = self
+                    .private_key
+                    .map(|seed| sign(seed, &hash[..], signature))
+                    .ok_or(ErrorCode::RESERVE);
+                self.sign_client
+                    .map(move |client| client.signing_done(result, hash, signature));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        let mut out = [0; N];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    /// RFC 8032 section 7.1, TEST 1 and TEST 2.
+    const VECTORS: [(&str, &str, &[u8], &str); 2] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            &[],
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            &[0x72],
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+    ];
+
+    #[test]
+    fn rfc8032_public_key() {
+        for (seed, public, _, _) in VECTORS.iter() {
+            assert_eq!(public_key(&hex(seed)), hex::<32>(public));
+        }
+    }
+
+    #[test]
+    fn rfc8032_sign() {
+        for (seed, _, message, expected) in VECTORS.iter() {
+            let mut signature = [0; SIGNATURE_LEN];
+            sign(&hex(seed), message, &mut signature);
+            assert_eq!(signature, hex::<64>(expected));
+        }
+    }
+
+    #[test]
+    fn rfc8032_verify() {
+        for (_, public, message, signature) in VECTORS.iter() {
+            assert!(verify(&hex(public), message, &hex(signature)));
+        }
+    }
+
+    #[test]
+    fn reject_tampered() {
+        let (_, public, message, signature) = VECTORS[1];
+        let public = hex::<32>(public);
+        let signature = hex::<64>(signature);
+
+        assert!(!verify(&public, &[0x73], &signature));
+
+        let mut bad = signature;
+        bad[0] ^= 1;
+        assert!(!verify(&public, message, &bad));
+
+        // S + L is a non-canonical encoding of the same scalar.
+        let mut bad = signature;
+        let s = U256::from_le_bytes(&split(&signature).1);
+        bad[32..].copy_from_slice(&s.add(&L).0.to_le_bytes());
+        assert!(!verify(&public, message, &bad));
+    }
+}
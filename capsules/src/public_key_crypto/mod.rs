@@ -0,0 +1,10 @@
+//! Software implementations of public key signature algorithms.
+//!
+//! These implement `kernel::hil::public_key_crypto` for boards without a
+//! hardware accelerator: `ed25519` provides Ed25519 and `p256` provides ECDSA
+//! P-256, each with verification and signing. Both are built on the 256-bit
+//! modular arithmetic in `bignum`.
+
+pub mod bignum;
+pub mod ed25519;
+pub mod p256;
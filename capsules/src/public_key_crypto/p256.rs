@@ -0,0 +1,807 @@
+//! ECDSA P-256 signatures in software.
+//!
+//! `EcdsaP256Software` implements `SignatureVerify<32, 64>` and
+//! `SignatureSign<32, 64>`. The message is the 32 byte hash being signed or
+//! verified, usually a SHA-256 digest. Public keys are the uncompressed point
+//! `x || y` as two 32 byte big-endian integers, optionally prefixed with the
+//! SEC1 `0x04` tag, private keys are a 32 byte big-endian scalar below the
+//! group order, and signatures are `r || s` in the same format. Operations
+//! run to completion in a deferred call.
+//!
+//! Signing uses deterministic nonces (RFC 6979 with HMAC-SHA256). Scalar
+//! multiplication by the private key and the nonce runs a Montgomery ladder
+//! over complete addition formulas, so it does not branch on secret data.
+//! Verification only handles public values and uses faster formulas that do.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ecdsa = static_init!(
+//!     capsules::public_key_crypto::p256::EcdsaP256Software<'static>,
+//!     capsules::public_key_crypto::p256::EcdsaP256Software::new(dynamic_deferred_caller)
+//! );
+//! ecdsa.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ecdsa)
+//!         .expect("no deferred call slot available for ecdsa"),
+//! );
+//! ecdsa.set_public_key(&PUBLIC_KEY).unwrap();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::public_key_crypto::{ClientSign, ClientVerify, SignatureSign, SignatureVerify};
+use kernel::ErrorCode;
+
+use super::bignum::{Modulus, U256};
+use crate::sha256::{HmacSha256State, SHA256_DIGEST_LEN};
+
+/// Length of the hash that is signed.
+pub const HASH_LEN: usize = 32;
+/// Length of a public key, `x || y`.
+pub const PUBLIC_KEY_LEN: usize = 64;
+/// Length of a private key.
+pub const PRIVATE_KEY_LEN: usize = 32;
+/// Length of a signature, `r || s`.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// The field prime.
+const P: U256 =
+    U256::from_be_hex("ffffffff00000001000000000000000000000000ffffffffffffffffffffffff");
+/// The order of the base point.
+const N: U256 =
+    U256::from_be_hex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551");
+/// The curve is y^2 = x^3 - 3x + b.
+const B: U256 =
+    U256::from_be_hex("5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b");
+const GX: U256 =
+    U256::from_be_hex("6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296");
+const GY: U256 =
+    U256::from_be_hex("4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5");
+
+/// A point in Jacobian coordinates, `x = X/Z^2`, `y = Y/Z^3`, with `Z = 0`
+/// for the point at infinity. Coordinates are in the Montgomery domain.
+#[derive(Copy, Clone)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+impl Point {
+    const INFINITY: Point = Point {
+        x: U256::ZERO,
+        y: U256::ZERO,
+        z: U256::ZERO,
+    };
+
+    fn is_infinity(&self) -> bool {
+        self.z.is_zero()
+    }
+}
+
+/// Curve constants, computed once per operation.
+struct Curve {
+    p: Modulus,
+    n: Modulus,
+    b: U256,
+}
+
+fn split(bytes: &[u8; 64]) -> (U256, U256) {
+    let mut hi = [0; 32];
+    let mut lo = [0; 32];
+    hi.copy_from_slice(&bytes[..32]);
+    lo.copy_from_slice(&bytes[32..]);
+    (U256::from_be_bytes(&hi), U256::from_be_bytes(&lo))
+}
+
+impl Curve {
+    fn new() -> Curve {
+        let p = Modulus::new(P);
+        Curve {
+            b: p.to_mont(&B),
+            n: Modulus::new(N),
+            p,
+        }
+    }
+
+    /// Convert an affine point with coordinates below `p` to Jacobian
+    /// coordinates, checking that it is on the curve.
+    fn from_affine(&self, x: &U256, y: &U256) -> Option<Point> {
+        let p = &self.p;
+        if *x >= P || *y >= P {
+            return None;
+        }
+        let x = p.to_mont(x);
+        let y = p.to_mont(y);
+
+        // y^2 = x^3 - 3x + b
+        let x3 = p.mul(&p.square(&x), &x);
+        let three_x = p.add(&p.add(&x, &x), &x);
+        let rhs = p.add(&p.sub(&x3, &three_x), &self.b);
+        if p.square(&y) != rhs {
+            return None;
+        }
+
+        Some(Point { x, y, z: p.one() })
+    }
+
+    fn generator(&self) -> Point {
+        Point {
+            x: self.p.to_mont(&GX),
+            y: self.p.to_mont(&GY),
+            z: self.p.one(),
+        }
+    }
+
+    /// Point doubling (dbl-2001-b, for a = -3).
+    fn double(&self, a: &Point) -> Point {
+        if a.is_infinity() {
+            return *a;
+        }
+        let p = &self.p;
+        let delta = p.square(&a.z);
+        let gamma = p.square(&a.y);
+        let beta = p.mul(&a.x, &gamma);
+        let t = p.mul(&p.sub(&a.x, &delta), &p.add(&a.x, &delta));
+        let alpha = p.add(&p.add(&t, &t), &t);
+
+        let beta4 = p.add(&p.add(&beta, &beta), &p.add(&beta, &beta));
+        let beta8 = p.add(&beta4, &beta4);
+        let x = p.sub(&p.square(&alpha), &beta8);
+
+        let yz = p.add(&a.y, &a.z);
+        let z = p.sub(&p.sub(&p.square(&yz), &gamma), &delta);
+
+        let gamma2 = p.square(&gamma);
+        let gamma2_4 = p.add(&p.add(&gamma2, &gamma2), &p.add(&gamma2, &gamma2));
+        let gamma2_8 = p.add(&gamma2_4, &gamma2_4);
+        let y = p.sub(&p.mul(&alpha, &p.sub(&beta4, &x)), &gamma2_8);
+
+        Point { x, y, z }
+    }
+
+    /// Point addition (add-2007-bl), handling the point at infinity and
+    /// equal inputs.
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        if a.is_infinity() {
+            return *b;
+        }
+        if b.is_infinity() {
+            return *a;
+        }
+        let p = &self.p;
+        let z1z1 = p.square(&a.z);
+        let z2z2 = p.square(&b.z);
+        let u1 = p.mul(&a.x, &z2z2);
+        let u2 = p.mul(&b.x, &z1z1);
+        let s1 = p.mul(&p.mul(&a.y, &b.z), &z2z2);
+        let s2 = p.mul(&p.mul(&b.y, &a.z), &z1z1);
+
+        let h = p.sub(&u2, &u1);
+        let s = p.sub(&s2, &s1);
+        if h.is_zero() {
+            return if s.is_zero() {
+                self.double(a)
+            } else {
+                Point::INFINITY
+            };
+        }
+
+        let h2 = p.add(&h, &h);
+        let i = p.square(&h2);
+        let j = p.mul(&h, &i);
+        let r = p.add(&s, &s);
+        let v = p.mul(&u1, &i);
+
+        let x = p.sub(&p.sub(&p.square(&r), &j), &p.add(&v, &v));
+        let s1j = p.mul(&s1, &j);
+        let y = p.sub(&p.mul(&r, &p.sub(&v, &x)), &p.add(&s1j, &s1j));
+        let zz = p.add(&a.z, &b.z);
+        let z = p.mul(&p.sub(&p.sub(&p.square(&zz), &z1z1), &z2z2), &h);
+
+        Point { x, y, z }
+    }
+
+    /// `u1 * a + u2 * b`, using Shamir's trick.
+    fn double_scalar_mul(&self, u1: &U256, a: &Point, u2: &U256, b: &Point) -> Point {
+        let ab = self.add(a, b);
+        let mut result = Point::INFINITY;
+        for i in (0..256).rev() {
+            result = self.double(&result);
+            result = match (u1.bit(i), u2.bit(i)) {
+                (true, true) => self.add(&result, &ab),
+                (true, false) => self.add(&result, a),
+                (false, true) => self.add(&result, b),
+                (false, false) => result,
+            };
+        }
+        result
+    }
+
+    /// The affine x coordinate of a point that is not at infinity.
+    fn affine_x(&self, a: &Point) -> U256 {
+        let p = &self.p;
+        let z_inv = p.inv(&a.z);
+        p.from_mont(&p.mul(&a.x, &p.square(&z_inv)))
+    }
+
+    /// Complete point addition in projective coordinates (Renes, Costello
+    /// and Batina 2015, algorithm 4, for a = -3). It is correct for every
+    /// pair of inputs, including equal points and the identity, without
+    /// branching.
+    fn add_complete(&self, a: &Projective, b: &Projective) -> Projective {
+        let p = &self.p;
+        let double = |v: &U256| p.add(v, v);
+        let triple = |v: &U256| p.add(&p.add(v, v), v);
+
+        let xx = p.mul(&a.x, &b.x);
+        let yy = p.mul(&a.y, &b.y);
+        let zz = p.mul(&a.z, &b.z);
+        let xy_pairs = p.sub(
+            &p.mul(&p.add(&a.x, &a.y), &p.add(&b.x, &b.y)),
+            &p.add(&xx, &yy),
+        );
+        let yz_pairs = p.sub(
+            &p.mul(&p.add(&a.y, &a.z), &p.add(&b.y, &b.z)),
+            &p.add(&yy, &zz),
+        );
+        let xz_pairs = p.sub(
+            &p.mul(&p.add(&a.x, &a.z), &p.add(&b.x, &b.z)),
+            &p.add(&xx, &zz),
+        );
+
+        let bzz3 = triple(&p.sub(&xz_pairs, &p.mul(&self.b, &zz)));
+        let yy_m_bzz3 = p.sub(&yy, &bzz3);
+        let yy_p_bzz3 = p.add(&yy, &bzz3);
+
+        let zz3 = triple(&zz);
+        let bxz3 = triple(&p.sub(&p.mul(&self.b, &xz_pairs), &p.add(&zz3, &xx)));
+        let xx3_m_zz3 = p.sub(&p.add(&double(&xx), &xx), &zz3);
+
+        Projective {
+            x: p.sub(&p.mul(&yy_p_bzz3, &xy_pairs), &p.mul(&yz_pairs, &bxz3)),
+            y: p.add(&p.mul(&yy_p_bzz3, &yy_m_bzz3), &p.mul(&xx3_m_zz3, &bxz3)),
+            z: p.add(&p.mul(&yy_m_bzz3, &yz_pairs), &p.mul(&xy_pairs, &xx3_m_zz3)),
+        }
+    }
+
+    /// `[k]G`, by a Montgomery ladder that runs the same additions for every
+    /// `k`, so private keys and nonces do not leak through timing.
+    fn base_mul(&self, k: &U256) -> Projective {
+        let mut r0 = Projective {
+            x: U256::ZERO,
+            y: self.p.one(),
+            z: U256::ZERO,
+        };
+        let mut r1 = Projective {
+            x: self.p.to_mont(&GX),
+            y: self.p.to_mont(&GY),
+            z: self.p.one(),
+        };
+        for i in (0..256).rev() {
+            let bit = k.bit(i) as u32;
+            Projective::swap(&mut r0, &mut r1, bit);
+            r1 = self.add_complete(&r0, &r1);
+            r0 = self.add_complete(&r0, &r0);
+            Projective::swap(&mut r0, &mut r1, bit);
+        }
+        r0
+    }
+
+    /// The affine coordinates of a projective point that is not the
+    /// identity.
+    fn to_affine(&self, a: &Projective) -> (U256, U256) {
+        let p = &self.p;
+        let z_inv = p.inv(&a.z);
+        (
+            p.from_mont(&p.mul(&a.x, &z_inv)),
+            p.from_mont(&p.mul(&a.y, &z_inv)),
+        )
+    }
+}
+
+/// A point in homogeneous projective coordinates, `x = X/Z`, `y = Y/Z`, with
+/// `(0 : 1 : 0)` as the identity. Coordinates are in the Montgomery domain.
+/// Used for the constant time scalar multiplication when signing.
+#[derive(Copy, Clone)]
+struct Projective {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+impl Projective {
+    /// Swap `a` and `b` if `choice` is 1, without branching on `choice`.
+    fn swap(a: &mut Projective, b: &mut Projective, choice: u32) {
+        U256::swap(&mut a.x, &mut b.x, choice);
+        U256::swap(&mut a.y, &mut b.y, choice);
+        U256::swap(&mut a.z, &mut b.z, choice);
+    }
+}
+
+/// Parse a public key in either `x || y` or `0x04 || x || y` form, returning
+/// `x || y` if the point is on the curve.
+fn parse_public_key(key: &[u8]) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    let key = match key.len() {
+        PUBLIC_KEY_LEN => key,
+        65 if key[0] == 0x04 => &key[1..],
+        _ => return None,
+    };
+    let mut point = [0; PUBLIC_KEY_LEN];
+    point.copy_from_slice(key);
+
+    let (x, y) = split(&point);
+    Curve::new().from_affine(&x, &y).map(|_| point)
+}
+
+/// Check that `signature` is a valid signature of `hash` for `public_key`.
+pub fn verify(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    hash: &[u8; HASH_LEN],
+    signature: &[u8; SIGNATURE_LEN],
+) -> bool {
+    let curve = Curve::new();
+    let n = &curve.n;
+
+    let (qx, qy) = split(public_key);
+    let q = match curve.from_affine(&qx, &qy) {
+        Some(q) => q,
+        None => return false,
+    };
+
+    let (r, s) = split(signature);
+    if r.is_zero() || s.is_zero() || r >= N || s >= N {
+        return false;
+    }
+
+    // to_mont() reduces the hash modulo n.
+    let e = n.to_mont(&U256::from_be_bytes(hash));
+    let w = n.inv(&n.to_mont(&s));
+    let u1 = n.from_mont(&n.mul(&e, &w));
+    let u2 = n.from_mont(&n.mul(&n.to_mont(&r), &w));
+
+    let point = curve.double_scalar_mul(&u1, &curve.generator(), &u2, &q);
+    if point.is_infinity() {
+        return false;
+    }
+
+    let x = curve.affine_x(&point);
+    let x = if x >= N { x.sub(&N).0 } else { x };
+    x == r
+}
+
+/// Deterministic nonce generation (RFC 6979 section 3.2) with HMAC-SHA256.
+/// The hash and the group order are both 256 bits, so no bit truncation is
+/// needed.
+struct NonceGenerator {
+    k: [u8; SHA256_DIGEST_LEN],
+    v: [u8; SHA256_DIGEST_LEN],
+    used: bool,
+}
+
+impl NonceGenerator {
+    /// `hash` must already be reduced modulo `n`.
+    fn new(private_key: &[u8; PRIVATE_KEY_LEN], hash: &[u8; HASH_LEN]) -> NonceGenerator {
+        let mut generator = NonceGenerator {
+            k: [0; SHA256_DIGEST_LEN],
+            v: [1; SHA256_DIGEST_LEN],
+            used: false,
+        };
+        generator.reseed(&[&[0x00], private_key, hash]);
+        generator.reseed(&[&[0x01], private_key, hash]);
+        generator
+    }
+
+    /// `K = HMAC_K(V || parts)`, then `V = HMAC_K(V)`.
+    fn reseed(&mut self, parts: &[&[u8]]) {
+        let mut hmac = HmacSha256State::new(&self.k);
+        hmac.update(&self.v);
+        for part in parts {
+            hmac.update(part);
+        }
+        hmac.finish(&mut self.k);
+        self.step();
+    }
+
+    /// `V = HMAC_K(V)`.
+    fn step(&mut self) {
+        let mut hmac = HmacSha256State::new(&self.k);
+        hmac.update(&self.v);
+        hmac.finish(&mut self.v);
+    }
+
+    /// The next nonce candidate in `[1, n)`.
+    fn next(&mut self) -> U256 {
+        if self.used {
+            self.reseed(&[&[0x00]]);
+        }
+        self.used = true;
+        loop {
+            self.step();
+            let k = U256::from_be_bytes(&self.v);
+            if !k.is_zero() && k < N {
+                return k;
+            }
+            self.reseed(&[&[0x00]]);
+        }
+    }
+}
+
+/// Parse a private key, which must be in `[1, n)`.
+fn parse_private_key(key: &[u8]) -> Option<U256> {
+    if key.len() != PRIVATE_KEY_LEN {
+        return None;
+    }
+    let mut bytes = [0; PRIVATE_KEY_LEN];
+    bytes.copy_from_slice(key);
+    let d = U256::from_be_bytes(&bytes);
+    if d.is_zero() || d >= N {
+        return None;
+    }
+    Some(d)
+}
+
+/// Derive a private key from 64 uniformly random bytes, such as the output of
+/// a key derivation function, by reducing them modulo `n`. The bias is
+/// negligible. A zero result, which is just as unlikely, is replaced by one
+/// so that every input gives a valid key.
+pub fn private_key_from_bytes(bytes: &[u8; 64]) -> [u8; PRIVATE_KEY_LEN] {
+    let n = Modulus::new(N);
+    let (hi, lo) = split(bytes);
+    let d = n.from_mont(&n.to_mont_wide(&hi, &lo));
+    U256::select(&d, &U256::ONE, d.is_zero() as u32).to_be_bytes()
+}
+
+/// Compute the public key `x || y` for `private_key`, or `None` if the
+/// private key is not in `[1, n)`.
+pub fn public_key(private_key: &[u8; PRIVATE_KEY_LEN]) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    let d = parse_private_key(private_key)?;
+    let curve = Curve::new();
+    let (x, y) = curve.to_affine(&curve.base_mul(&d));
+
+    let mut key = [0; PUBLIC_KEY_LEN];
+    key[..32].copy_from_slice(&x.to_be_bytes());
+    key[32..].copy_from_slice(&y.to_be_bytes());
+    Some(key)
+}
+
+/// Sign `hash` with `private_key`, writing `r || s` to `signature`. Fails
+/// with `INVAL` if the private key is not in `[1, n)`.
+pub fn sign(
+    private_key: &[u8; PRIVATE_KEY_LEN],
+    hash: &[u8; HASH_LEN],
+    signature: &mut [u8; SIGNATURE_LEN],
+) -> Result<(), ErrorCode> {
+    let d = parse_private_key(private_key).ok_or(ErrorCode::INVAL)?;
+    let curve = Curve::new();
+    let n = &curve.n;
+
+    // to_mont() reduces the hash modulo n.
+    let e = n.to_mont(&U256::from_be_bytes(hash));
+    let d = n.to_mont(&d);
+    let mut nonces = NonceGenerator::new(private_key, &n.from_mont(&e).to_be_bytes());
+    loop {
+        let k = nonces.next();
+        let (x, _) = curve.to_affine(&curve.base_mul(&k));
+        let r = if x >= N { x.sub(&N).0 } else { x };
+        if r.is_zero() {
+            continue;
+        }
+
+        // s = k^-1 (e + r d) mod n
+        let k_inv = n.inv(&n.to_mont(&k));
+        let rd = n.mul(&n.to_mont(&r), &d);
+        let s = n.from_mont(&n.mul(&k_inv, &n.add(&e, &rd)));
+        if s.is_zero() {
+            continue;
+        }
+
+        signature[..32].copy_from_slice(&r.to_be_bytes());
+        signature[32..].copy_from_slice(&s.to_be_bytes());
+        return Ok(());
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Verify,
+    Sign,
+}
+
+/// ECDSA P-256 signature engine implemented in software.
+pub struct EcdsaP256Software<'a> {
+    verify_client: OptionalCell<&'a dyn ClientVerify<'a, HASH_LEN, SIGNATURE_LEN>>,
+    sign_client: OptionalCell<&'a dyn ClientSign<'a, HASH_LEN, SIGNATURE_LEN>>,
+
+    public_key: OptionalCell<[u8; PUBLIC_KEY_LEN]>,
+    private_key: MapCell<[u8; PRIVATE_KEY_LEN]>,
+
+    /// The operation in progress, along with its buffers.
+    operation: Cell<Operation>,
+    hash: TakeCell<'static, [u8; HASH_LEN]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> EcdsaP256Software<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> EcdsaP256Software<'a> {
+        EcdsaP256Software {
+            verify_client: OptionalCell::empty(),
+            sign_client: OptionalCell::empty(),
+            public_key: OptionalCell::empty(),
+            private_key: MapCell::empty(),
+            operation: Cell::new(Operation::Verify),
+            hash: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn busy(&self) -> bool {
+        self.hash.is_some()
+    }
+
+    fn start(
+        &self,
+        operation: Operation,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; HASH_LEN],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.busy() || self.handle.is_none() {
+            return Err((ErrorCode::BUSY, hash, signature));
+        }
+        self.operation.set(operation);
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+}
+
+impl<'a> SignatureVerify<'a, HASH_LEN, SIGNATURE_LEN> for EcdsaP256Software<'a> {
+    fn set_verify_client(&'a self, client: &'a dyn ClientVerify<'a, HASH_LEN, SIGNATURE_LEN>) {
+        self.verify_client.set(client);
+    }
+
+    fn set_public_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if self.busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        let key = parse_public_key(key).ok_or(ErrorCode::INVAL)?;
+        self.public_key.set(key);
+        Ok(())
+    }
+
+    fn verify(
+        &'a self,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; HASH_LEN],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.public_key.is_none() {
+            return Err((ErrorCode::RESERVE, hash, signature));
+        }
+        self.start(Operation::Verify, hash, signature)
+    }
+}
+
+impl<'a> SignatureSign<'a, HASH_LEN, SIGNATURE_LEN> for EcdsaP256Software<'a> {
+    fn set_sign_client(&'a self, client: &'a dyn ClientSign<'a, HASH_LEN, SIGNATURE_LEN>) {
+        self.sign_client.set(client);
+    }
+
+    fn set_private_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if self.busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        parse_private_key(key).ok_or(ErrorCode::INVAL)?;
+        let mut private_key = [0; PRIVATE_KEY_LEN];
+        private_key.copy_from_slice(key);
+        self.private_key.replace(private_key);
+        Ok(())
+    }
+
+    fn sign(
+        &'a self,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; HASH_LEN],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.private_key.is_none() {
+            return Err((ErrorCode::RESERVE, hash, signature));
+        }
+        self.start(Operation::Sign, hash, signature)
+    }
+
+    fn clear_private_key(&self) {
+        self.private_key.map(|key| *key = [0; PRIVATE_KEY_LEN]);
+        self.private_key.take();
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for EcdsaP256Software<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let (hash, signature) = match (self.hash.take(), self.signature.take()) {
+            (Some(hash), Some(signature)) => (hash, signature),
+            _ => return,
+        };
+
+        match self.operation.get() {
+            Operation::Verify => {
+                let result = self
+                    .public_key
+                    .map(|key| verify(key, hash, signature))
+                    .ok_or(ErrorCode::RESERVE);
+                self.verify_client
+                    .map(move |client| client.verification_done(result, hash, signature));
+            }
+            Operation::Sign => {
+                let result = self
+                    .private_key
+                    .map(|key| sign(key, hash, signature))
+                    .unwrap_or(Err(ErrorCode::RESERVE));
+                self.sign_client
+                    .map(move |client| client.signing_done(result, hash, signature));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha256::Sha256State;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        let mut out = [0; N];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    fn sha256(message: &[u8]) -> [u8; HASH_LEN] {
+        let mut digest = [0; HASH_LEN];
+        let mut sha = Sha256State::new();
+        sha.update(message);
+        sha.finish(&mut digest);
+        digest
+    }
+
+    /// RFC 6979 appendix A.2.5.
+    const PRIVATE_KEY: &str = "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721";
+    const PUBLIC_KEY: &str = "60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb67903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299";
+
+    /// RFC 6979 appendix A.2.5, SHA-256 signatures.
+    const VECTORS: [(&[u8], &str); 2] = [
+        (
+            b"sample",
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8",
+        ),
+        (
+            b"test",
+            "f1abb023518351cd71d881567b1ea663ed3efcf6c5132b354f28d3b0b7d38367019f4113742a2b14bd25926b49c649155f267e60d3814b4c0cc84250e46f0083",
+        ),
+    ];
+
+    #[test]
+    fn generator_on_curve() {
+        assert!(Curve::new().from_affine(&GX, &GY).is_some());
+        assert!(Curve::new().from_affine(&GX, &GX).is_none());
+    }
+
+    #[test]
+    fn parse_public_keys() {
+        let key = hex::<64>(PUBLIC_KEY);
+        assert_eq!(parse_public_key(&key), Some(key));
+
+        let mut sec1 = [0x04; 65];
+        sec1[1..].copy_from_slice(&key);
+        assert_eq!(parse_public_key(&sec1), Some(key));
+
+        let mut bad = key;
+        bad[63] ^= 1;
+        assert_eq!(parse_public_key(&bad), None);
+        assert_eq!(parse_public_key(&key[..63]), None);
+    }
+
+    #[test]
+    fn rfc6979_verify() {
+        let key = hex(PUBLIC_KEY);
+        for (message, signature) in VECTORS.iter() {
+            assert!(verify(&key, &sha256(message), &hex(signature)));
+        }
+    }
+
+    #[test]
+    fn rfc6979_public_key() {
+        assert_eq!(public_key(&hex(PRIVATE_KEY)), Some(hex(PUBLIC_KEY)));
+    }
+
+    #[test]
+    fn rfc6979_sign() {
+        let key = hex(PRIVATE_KEY);
+        for (message, expected) in VECTORS.iter() {
+            let mut signature = [0; SIGNATURE_LEN];
+            assert_eq!(sign(&key, &sha256(message), &mut signature), Ok(()));
+            assert_eq!(signature, hex::<64>(expected));
+        }
+    }
+
+    #[test]
+    fn derived_keys_sign_and_verify() {
+        for fill in [0x00, 0x5a, 0xff].iter() {
+            let private_key = private_key_from_bytes(&[*fill; 64]);
+            let public_key = public_key(&private_key).unwrap();
+            let hash = sha256(&[*fill]);
+            let mut signature = [0; SIGNATURE_LEN];
+            assert_eq!(sign(&private_key, &hash, &mut signature), Ok(()));
+            assert!(verify(&public_key, &hash, &signature));
+        }
+        // All-zero input is mapped to a valid key.
+        assert_eq!(private_key_from_bytes(&[0; 64]), U256::ONE.to_be_bytes());
+    }
+
+    #[test]
+    fn reject_invalid_private_keys() {
+        let mut signature = [0; SIGNATURE_LEN];
+        for key in [U256::ZERO, N].iter() {
+            let key = key.to_be_bytes();
+            assert_eq!(public_key(&key), None);
+            assert_eq!(
+                sign(&key, &[0; HASH_LEN], &mut signature),
+                Err(ErrorCode::INVAL)
+            );
+        }
+    }
+
+    #[test]
+    fn reject_tampered() {
+        let key = hex(PUBLIC_KEY);
+        let (message, signature) = VECTORS[0];
+        let signature = hex::<64>(signature);
+
+        assert!(!verify(&key, &sha256(b"samplf"), &signature));
+
+        let mut bad = signature;
+        bad[40] ^= 0x80;
+        assert!(!verify(&key, &sha256(message), &bad));
+
+        // r = 0 is never valid.
+        let mut bad = signature;
+        bad[..32].copy_from_slice(&[0; 32]);
+        assert!(!verify(&key, &sha256(message), &bad));
+    }
+}
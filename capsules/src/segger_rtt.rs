@@ -22,34 +22,46 @@
 //! $ JLinkRTTClient
 //! ```
 //!
+//! Channels
+//! --------
+//!
+//! The RTT memory has `NUM_CHANNELS` up (chip to host) and down (host to
+//! chip) channels, each with its own buffers:
+//!
+//! - `DEBUG_CHANNEL` ("Terminal") is the channel RTT viewers show by default
+//!   and is meant for the kernel debug output, and the panic handler.
+//! - `CONSOLE_CHANNEL` ("Console") is meant for a console that also takes
+//!   input, such as the process console.
+//! - `TRACE_CHANNEL` ("Trace") is meant for high volume output such as traces.
+//!
+//! Each channel is a separate `SeggerRtt` that implements the `hil::uart`
+//! traits, so any of them can be the underlying UART of a
+//! `virtual_uart::MuxUart`. A board without a spare UART can run its consoles
+//! over RTT this way.
+//!
 //! Notes
 //! -----
 //!
-//! This capsule requires a timer, but the timer is only there to defer the
-//! `transmit_complete` callback until the next scheduler loop. In the future,
-//! if there is support for software interrupts or deferred calls in capsules,
-//! this timer should be removed.
+//! RTT has no interrupts: the host reads and writes the buffers in the chip's
+//! memory over JTAG. This capsule uses an alarm to issue the
+//! `transmitted_buffer` callback after copying the data into the up buffer,
+//! and to poll the down buffer for input while a receive is pending.
 //!
-//! Todo
-//! ----
-//!
-//! - Implement receive functionality.
+//! Up channels do not block by default: data that does not fit in the free
+//! space of the up buffer is dropped, so the kernel does not stall when no
+//! host is attached. If the host sets the channel to the
+//! `BLOCK_IF_FIFO_FULL` mode, transmissions instead wait until the host has
+//! read enough of the buffer.
 //!
 //! Usage
 //! -----
 //!
-//! ```rust
-//! pub struct Platform {
-//!     // Other fields omitted for clarity
-//!     console: &'static capsules::console::Console<'static>,
-//! }
-//! ```
-//!
 //! In `main()`:
 //!
 //! ```rust
 //! # use kernel::static_init;
 //! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//! # use capsules::segger_rtt::{SeggerRtt, SeggerRttMemory, CONSOLE_CHANNEL};
 //!
 //! let virtual_alarm_rtt = static_init!(
 //!     VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
@@ -57,36 +69,36 @@
 //! );
 //!
 //! let rtt_memory = static_init!(
-//!     capsules::segger_rtt::SeggerRttMemory,
-//!     capsules::segger_rtt::SeggerRttMemory::new(b"Terminal\0",
-//!         &mut capsules::segger_rtt::UP_BUFFER,
-//!         b"Terminal\0",
-//!         &mut capsules::segger_rtt::DOWN_BUFFER)
+//!     SeggerRttMemory,
+//!     SeggerRttMemory::new_raw(
+//!         [
+//!             (b"Terminal\0", debug_up.as_ptr(), debug_up.len()),
+//!             (b"Console\0", console_up.as_ptr(), console_up.len()),
+//!             (b"Trace\0", trace_up.as_ptr(), trace_up.len()),
+//!         ],
+//!         [
+//!             (b"Terminal\0", debug_down.as_ptr(), debug_down.len()),
+//!             (b"Console\0", console_down.as_ptr(), console_down.len()),
+//!             (b"Trace\0", trace_down.as_ptr(), trace_down.len()),
+//!         ],
+//!     )
 //! );
 //!
+//! // The console channel, shared by the console and process console.
 //! let rtt = static_init!(
-//!     capsules::segger_rtt::SeggerRtt<VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>>,
-//!     capsules::segger_rtt::SeggerRtt::new(virtual_alarm_rtt, rtt_memory,
-//!         &mut capsules::segger_rtt::UP_BUFFER,
-//!         &mut capsules::segger_rtt::DOWN_BUFFER)
+//!     SeggerRtt<VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>>,
+//!     SeggerRtt::new(virtual_alarm_rtt, rtt_memory, CONSOLE_CHANNEL, console_up, console_down)
 //! );
-//! virtual_alarm_rtt.set_client(rtt);
+//! virtual_alarm_rtt.set_alarm_client(rtt);
 //!
-//! let console = static_init!(
-//!     capsules::console::Console<'static>,
-//!     capsules::console::Console::new(
-//!         rtt,
-//!         &mut capsules::console::WRITE_BUF,
-//!         &mut capsules::console::READ_BUF,
-//!         board_kernel.create_grant(&grant_cap)
-//!     )
-//! );
-//! kernel::hil::uart::UART::set_client(rtt, console);
-//! console.initialize();
+//! let uart_mux = components::console::UartMuxComponent::new(rtt, 115200, dynamic_deferred_caller)
+//!     .finalize(());
 //! ```
 
 use core::cell::Cell;
+use core::cmp;
 use core::marker::PhantomData;
+use core::ptr;
 use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
 use kernel::hil;
 use kernel::hil::uart;
@@ -98,6 +110,41 @@ pub const DEFAULT_UP_BUFFER_LENGTH: usize = 1024;
 /// Suggested length for the down buffer to pass to the Segger RTT capsule.
 pub const DEFAULT_DOWN_BUFFER_LENGTH: usize = 32;
 
+/// Number of up and of down channels in the RTT memory.
+pub const NUM_CHANNELS: usize = 3;
+
+/// Channel for kernel debug output and panics.
+pub const DEBUG_CHANNEL: usize = 0;
+
+/// Channel for an interactive console.
+pub const CONSOLE_CHANNEL: usize = 1;
+
+/// Channel for traces.
+pub const TRACE_CHANNEL: usize = 2;
+
+/// Mask of the mode in the flags of an up buffer.
+const MODE_MASK: u32 = 0b11;
+
+/// Mode in which data that does not fit in the up buffer is dropped.
+const MODE_NO_BLOCK_TRIM: u32 = 1;
+
+/// Mode in which the chip waits for the host to read the up buffer when it
+/// is full.
+const MODE_BLOCK_IF_FIFO_FULL: u32 = 2;
+
+/// Delay before the `transmitted_buffer` callback, and between attempts to
+/// write to a full up buffer in the blocking mode.
+///
+/// This heuristic interval was tested with the console capsule on a nRF52840-DK
+/// board, passing buffers up to 1500 bytes from userspace. 100 micro-seconds
+/// was too short, even for buffers as small as 128 bytes. 1 milli-second seems to
+/// be reliable.
+const TRANSMIT_DELAY_US: u32 = 1000;
+
+/// How often the down buffer is checked for input while a receive is
+/// pending.
+const RECEIVE_POLL_INTERVAL_US: u32 = 10_000;
+
 /// This structure is defined by the segger RTT protocol. It must exist in
 /// memory in exactly this form so that the segger JTAG tool can find it in the
 /// chip's memory and read and write messages to the appropriate buffers.
@@ -106,8 +153,8 @@ pub struct SeggerRttMemory<'a> {
     id: VolatileCell<[u8; 16]>,
     number_up_buffers: VolatileCell<u32>,
     number_down_buffers: VolatileCell<u32>,
-    up_buffer: SeggerRttBuffer<'a>,
-    down_buffer: SeggerRttBuffer<'a>,
+    up_buffers: [SeggerRttBuffer<'a>; NUM_CHANNELS],
+    down_buffers: [SeggerRttBuffer<'a>; NUM_CHANNELS],
 }
 
 #[repr(C)]
@@ -122,15 +169,47 @@ pub struct SeggerRttBuffer<'a> {
     _lifetime: PhantomData<&'a [u8]>,
 }
 
+impl<'a> SeggerRttBuffer<'a> {
+    fn new(name: &'a [u8], buffer_ptr: *const u8, buffer_len: usize, flags: u32) -> Self {
+        SeggerRttBuffer {
+            name: VolatileCell::new(name.as_ptr()),
+            buffer: VolatileCell::new(buffer_ptr),
+            length: VolatileCell::new(buffer_len as u32),
+            write_position: VolatileCell::new(0),
+            read_position: VolatileCell::new(0),
+            flags: VolatileCell::new(flags),
+            _lifetime: PhantomData,
+        }
+    }
+}
+
 impl<'a> SeggerRttMemory<'a> {
+    /// Create the RTT memory. Each channel is given as the name of the
+    /// channel (a nul-terminated string) and the address and length of its
+    /// buffer.
     pub fn new_raw(
-        up_buffer_name: &'a [u8],
-        up_buffer_ptr: *const u8,
-        up_buffer_len: usize,
-        down_buffer_name: &'a [u8],
-        down_buffer_ptr: *const u8,
-        down_buffer_len: usize,
+        up_buffers: [(&'a [u8], *const u8, usize); NUM_CHANNELS],
+        down_buffers: [(&'a [u8], *const u8, usize); NUM_CHANNELS],
     ) -> SeggerRttMemory<'a> {
+        // Every channel is filled in below, this only gives the arrays a
+        // starting value.
+        const UNUSED: SeggerRttBuffer<'static> = SeggerRttBuffer {
+            name: VolatileCell::new(ptr::null()),
+            buffer: VolatileCell::new(ptr::null()),
+            length: VolatileCell::new(0),
+            write_position: VolatileCell::new(0),
+            read_position: VolatileCell::new(0),
+            flags: VolatileCell::new(0),
+            _lifetime: PhantomData,
+        };
+        let mut up: [SeggerRttBuffer<'a>; NUM_CHANNELS] = [UNUSED; NUM_CHANNELS];
+        let mut down: [SeggerRttBuffer<'a>; NUM_CHANNELS] = [UNUSED; NUM_CHANNELS];
+        for channel in 0..NUM_CHANNELS {
+            let (name, ptr, len) = up_buffers[channel];
+            up[channel] = SeggerRttBuffer::new(name, ptr, len, MODE_NO_BLOCK_TRIM);
+            let (name, ptr, len) = down_buffers[channel];
+            down[channel] = SeggerRttBuffer::new(name, ptr, len, 0);
+        }
         SeggerRttMemory {
             // This field is a magic value that must be set to "SEGGER RTT" for the debugger to
             // recognize it when scanning the memory.
@@ -140,69 +219,137 @@ impl<'a> SeggerRttMemory<'a> {
             // known problem so far. If needed, this ID could be scrambled here, with the real magic
             // value being written only when this object is fully initialized.
             id: VolatileCell::new(*b"SEGGER RTT\0\0\0\0\0\0"),
-            number_up_buffers: VolatileCell::new(1),
-            number_down_buffers: VolatileCell::new(1),
-            up_buffer: SeggerRttBuffer {
-                name: VolatileCell::new(up_buffer_name.as_ptr()),
-                buffer: VolatileCell::new(up_buffer_ptr),
-                length: VolatileCell::new(up_buffer_len as u32),
-                write_position: VolatileCell::new(0),
-                read_position: VolatileCell::new(0),
-                flags: VolatileCell::new(0),
-                _lifetime: PhantomData,
-            },
-            down_buffer: SeggerRttBuffer {
-                name: VolatileCell::new(down_buffer_name.as_ptr()),
-                buffer: VolatileCell::new(down_buffer_ptr),
-                length: VolatileCell::new(down_buffer_len as u32),
-                write_position: VolatileCell::new(0),
-                read_position: VolatileCell::new(0),
-                flags: VolatileCell::new(0),
-                _lifetime: PhantomData,
-            },
+            number_up_buffers: VolatileCell::new(NUM_CHANNELS as u32),
+            number_down_buffers: VolatileCell::new(NUM_CHANNELS as u32),
+            up_buffers: up,
+            down_buffers: down,
         }
     }
 
-    /// This getter allows access to the underlying buffer in the panic handler.
+    /// This getter allows access to the underlying buffer of the debug
+    /// channel in the panic handler.
     /// The result is a pointer so that only `unsafe` code can actually dereference it - this is to
     /// restrict this priviledged access to the panic handler.
     pub fn get_up_buffer_ptr(&self) -> *const SeggerRttBuffer<'a> {
-        &self.up_buffer
+        &self.up_buffers[DEBUG_CHANNEL]
     }
 }
 
+/// One up and down channel of the RTT memory, used as a UART.
 pub struct SeggerRtt<'a, A: hil::time::Alarm<'a>> {
-    alarm: &'a A, // Dummy alarm so we can get a callback.
-    config: TakeCell<'a, SeggerRttMemory<'a>>,
+    alarm: &'a A,
+    config: &'a SeggerRttMemory<'a>,
+    channel: usize,
     up_buffer: TakeCell<'a, [u8]>,
-    _down_buffer: TakeCell<'a, [u8]>,
-    client: OptionalCell<&'a dyn uart::TransmitClient>,
-    client_buffer: TakeCell<'static, [u8]>,
+    down_buffer: TakeCell<'a, [u8]>,
+    tx_client: OptionalCell<&'a dyn uart::TransmitClient>,
+    tx_buffer: TakeCell<'static, [u8]>,
     tx_len: Cell<usize>,
+    /// Bytes of `tx_buffer` already copied into the up buffer.
+    tx_position: Cell<usize>,
+    rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    /// Bytes of `rx_buffer` already received.
+    rx_position: Cell<usize>,
+    rx_abort: Cell<bool>,
 }
 
 impl<'a, A: hil::time::Alarm<'a>> SeggerRtt<'a, A> {
+    /// Use `channel` of `config`, whose up and down buffers are `up_buffer`
+    /// and `down_buffer`.
     pub fn new(
         alarm: &'a A,
-        config: &'a mut SeggerRttMemory<'a>,
+        config: &'a SeggerRttMemory<'a>,
+        channel: usize,
         up_buffer: &'a mut [u8],
         down_buffer: &'a mut [u8],
     ) -> SeggerRtt<'a, A> {
         SeggerRtt {
             alarm: alarm,
-            config: TakeCell::new(config),
+            config: config,
+            channel: channel,
             up_buffer: TakeCell::new(up_buffer),
-            _down_buffer: TakeCell::new(down_buffer),
-            client: OptionalCell::empty(),
-            client_buffer: TakeCell::empty(),
+            down_buffer: TakeCell::new(down_buffer),
+            tx_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
             tx_len: Cell::new(0),
+            tx_position: Cell::new(0),
+            rx_client: OptionalCell::empty(),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_position: Cell::new(0),
+            rx_abort: Cell::new(false),
         }
     }
+
+    /// Copy as much of the pending transmission into the up buffer as fits.
+    /// Unless the host asked for the blocking mode, the rest is dropped.
+    fn write_up(&self) {
+        let up = &self.config.up_buffers[self.channel];
+        self.tx_buffer.map(|tx_data| {
+            self.up_buffer.map(|buffer| {
+                let buffer_len = buffer.len();
+                let read = up.read_position.get() as usize % buffer_len;
+                let mut write = up.write_position.get() as usize % buffer_len;
+                // One byte stays empty so a full buffer differs from an empty
+                // one.
+                let free = (read + buffer_len - write - 1) % buffer_len;
+                let position = self.tx_position.get();
+                let count = cmp::min(free, self.tx_len.get() - position);
+                for &byte in &tx_data[position..position + count] {
+                    buffer[write] = byte;
+                    write = (write + 1) % buffer_len;
+                }
+                // Once we update the `write_position` the RTT listener will go
+                // ahead and read the data from us.
+                up.write_position.set(write as u32);
+                self.tx_position.set(position + count);
+            });
+            if up.flags.get() & MODE_MASK != MODE_BLOCK_IF_FIFO_FULL {
+                self.tx_position.set(self.tx_len.get());
+            }
+        });
+    }
+
+    /// Copy input from the down buffer into the pending receive.
+    fn read_down(&self) {
+        let down = &self.config.down_buffers[self.channel];
+        self.rx_buffer.map(|rx_data| {
+            self.down_buffer.map(|buffer| {
+                let buffer_len = buffer.len();
+                let mut read = down.read_position.get() as usize % buffer_len;
+                let write = down.write_position.get() as usize % buffer_len;
+                let available = (write + buffer_len - read) % buffer_len;
+                let position = self.rx_position.get();
+                let count = cmp::min(available, self.rx_len.get() - position);
+                for byte in &mut rx_data[position..position + count] {
+                    *byte = buffer[read];
+                    read = (read + 1) % buffer_len;
+                }
+                down.read_position.set(read as u32);
+                self.rx_position.set(position + count);
+            });
+        });
+    }
+
+    /// Set the alarm if a transmission or receive is pending.
+    fn arm(&self) {
+        let delay = if self.tx_buffer.is_some() {
+            TRANSMIT_DELAY_US
+        } else if self.rx_buffer.is_some() {
+            RECEIVE_POLL_INTERVAL_US
+        } else {
+            return;
+        };
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_us(delay));
+    }
 }
 
 impl<'a, A: hil::time::Alarm<'a>> uart::Transmit<'a> for SeggerRtt<'a, A> {
     fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
-        self.client.set(client);
+        self.tx_client.set(client);
     }
 
     fn transmit_buffer(
@@ -210,39 +357,18 @@ impl<'a, A: hil::time::Alarm<'a>> uart::Transmit<'a> for SeggerRtt<'a, A> {
         tx_data: &'static mut [u8],
         tx_len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        if self.up_buffer.is_some() && self.config.is_some() {
-            self.up_buffer.map(|buffer| {
-                self.config.map(move |config| {
-                    // Copy the incoming data into the buffer. Once we increment
-                    // the `write_position` the RTT listener will go ahead and read
-                    // the message from us.
-                    let mut index = config.up_buffer.write_position.get() as usize;
-                    let buffer_len = config.up_buffer.length.get() as usize;
-
-                    for i in 0..tx_len {
-                        buffer[(i + index) % buffer_len] = tx_data[i];
-                    }
-
-                    index = (index + tx_len) % buffer_len;
-                    config.up_buffer.write_position.set(index as u32);
-                    self.tx_len.set(tx_len);
-                    // Save the client buffer so we can pass it back with the callback.
-                    self.client_buffer.replace(tx_data);
-
-                    // Start a short timer so that we get a callback and can issue the callback to
-                    // the client.
-                    //
-                    // This heuristic interval was tested with the console capsule on a nRF52840-DK
-                    // board, passing buffers up to 1500 bytes from userspace. 100 micro-seconds
-                    // was too short, even for buffers as small as 128 bytes. 1 milli-second seems to
-                    // be reliable.
-                    let delay = A::ticks_from_us(1000);
-                    self.alarm.set_alarm(self.alarm.now(), delay);
-                })
-            });
-            Ok(())
-        } else {
+        if self.tx_buffer.is_some() || self.up_buffer.map_or(true, |buffer| buffer.is_empty()) {
             Err((ErrorCode::BUSY, tx_data))
+        } else if tx_len > tx_data.len() {
+            Err((ErrorCode::SIZE, tx_data))
+        } else {
+            self.tx_len.set(tx_len);
+            self.tx_position.set(0);
+            // Save the client buffer so we can pass it back with the callback.
+            self.tx_buffer.replace(tx_data);
+            self.write_up();
+            self.arm();
+            Ok(())
         }
     }
 
@@ -257,33 +383,77 @@ impl<'a, A: hil::time::Alarm<'a>> uart::Transmit<'a> for SeggerRtt<'a, A> {
 
 impl<'a, A: hil::time::Alarm<'a>> hil::time::AlarmClient for SeggerRtt<'a, A> {
     fn alarm(&self) {
-        self.client.map(|client| {
-            self.client_buffer.take().map(|buffer| {
-                client.transmitted_buffer(buffer, self.tx_len.get(), Ok(()));
-            });
-        });
+        if self.tx_buffer.is_some() {
+            self.write_up();
+            if self.tx_position.get() == self.tx_len.get() {
+                self.tx_buffer.take().map(|buffer| {
+                    self.tx_client.map(move |client| {
+                        client.transmitted_buffer(buffer, self.tx_len.get(), Ok(()));
+                    });
+                });
+            }
+        }
+
+        if self.rx_buffer.is_some() {
+            self.read_down();
+            let abort = self.rx_abort.replace(false);
+            if abort || self.rx_position.get() == self.rx_len.get() {
+                self.rx_buffer.take().map(|buffer| {
+                    self.rx_client.map(move |client| {
+                        if abort {
+                            client.received_buffer(
+                                buffer,
+                                self.rx_position.get(),
+                                Err(ErrorCode::CANCEL),
+                                uart::Error::Aborted,
+                            );
+                        } else {
+                            client.received_buffer(
+                                buffer,
+                                self.rx_position.get(),
+                                Ok(()),
+                                uart::Error::None,
+                            );
+                        }
+                    });
+                });
+            }
+        }
+
+        self.arm();
     }
 }
 
-// Dummy implementation so this can act as the underlying UART for a
-// virtualized UART MUX. -pal 1/10/19
+// RTT has no baud rate or framing, so any configuration is accepted. This lets
+// the channel act as the underlying UART for a virtualized UART MUX.
 impl<'a, A: hil::time::Alarm<'a>> uart::Configure for SeggerRtt<'a, A> {
     fn configure(&self, _parameters: uart::Parameters) -> Result<(), ErrorCode> {
-        Err(ErrorCode::FAIL)
+        Ok(())
     }
 }
 
-// Dummy implementation so this can act as the underlying UART for a
-// virtualized UART MUX.  -pal 1/10/19
 impl<'a, A: hil::time::Alarm<'a>> uart::Receive<'a> for SeggerRtt<'a, A> {
-    fn set_receive_client(&self, _client: &'a dyn uart::ReceiveClient) {}
+    fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
 
     fn receive_buffer(
         &self,
         buffer: &'static mut [u8],
-        _len: usize,
+        len: usize,
     ) -> Result<(), (ErrorCode, &'static mut [u8])> {
-        Err((ErrorCode::FAIL, buffer))
+        if self.rx_buffer.is_some() || self.down_buffer.map_or(true, |buffer| buffer.is_empty()) {
+            Err((ErrorCode::BUSY, buffer))
+        } else if len > buffer.len() {
+            Err((ErrorCode::SIZE, buffer))
+        } else {
+            self.rx_len.set(len);
+            self.rx_position.set(0);
+            self.rx_abort.set(false);
+            self.rx_buffer.replace(buffer);
+            self.arm();
+            Ok(())
+        }
     }
 
     fn receive_word(&self) -> Result<(), ErrorCode> {
@@ -291,6 +461,15 @@ impl<'a, A: hil::time::Alarm<'a>> uart::Receive<'a> for SeggerRtt<'a, A> {
     }
 
     fn receive_abort(&self) -> Result<(), ErrorCode> {
-        Ok(())
+        if self.rx_buffer.is_some() {
+            // The buffer is returned from the next alarm, with whatever was
+            // received until then.
+            self.rx_abort.set(true);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_us(TRANSMIT_DELAY_US));
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(())
+        }
     }
 }
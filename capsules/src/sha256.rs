@@ -0,0 +1,469 @@
+//! Software implementation of SHA-256 and HMAC-SHA256.
+//!
+//! `Sha256Software` implements `hil::digest::Digest<32>` with the `Sha256`
+//! and `HMACSha256` modes entirely in software, for chips without a hashing
+//! peripheral. Data is hashed when it is added, and completion callbacks are
+//! delivered through a deferred call, as they would be by a hardware block.
+//! It can be used directly or shared through `virtual_digest`.
+//!
+//! The SHA-384/512 modes are not supported by a 32 byte digest; use
+//! `capsules::sha512::Sha512Software` for those.
+//!
+//! `Sha256State` and `HmacSha256State` are the synchronous primitives the
+//! capsule is built on, and can be used by other capsules that need a hash
+//! without going through the asynchronous interface.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let sha = static_init!(
+//!     capsules::sha256::Sha256Software<'static>,
+//!     capsules::sha256::Sha256Software::new(dynamic_deferred_caller)
+//! );
+//! sha.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(sha)
+//!         .expect("no deferred call slot available for sha256"),
+//! );
+//!
+//! let mux_digest = components::digest::DigestMuxComponent::new(sha).finalize(
+//!     components::digest_mux_component_helper!(capsules::sha256::Sha256Software, 32),
+//! );
+//! ```
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::ErrorCode;
+
+/// Size of a SHA-256 message block, which is also the HMAC key block size.
+pub const SHA256_BLOCK_LEN: usize = 64;
+/// Size of a SHA-256 digest.
+pub const SHA256_DIGEST_LEN: usize = 32;
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_HASH: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// An in-progress SHA-256 computation.
+#[derive(Clone)]
+pub struct Sha256State {
+    hash: [u32; 8],
+    /// Bytes of the current block that have not been compressed yet.
+    block: [u8; SHA256_BLOCK_LEN],
+    block_len: usize,
+    /// Total message length in bytes.
+    length: u64,
+}
+
+impl Sha256State {
+    pub const fn new() -> Sha256State {
+        Sha256State {
+            hash: INITIAL_HASH,
+            block: [0; SHA256_BLOCK_LEN],
+            block_len: 0,
+            length: 0,
+        }
+    }
+
+    /// Add `data` to the message.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+
+        while !data.is_empty() {
+            let n = core::cmp::min(SHA256_BLOCK_LEN - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+
+            if self.block_len == SHA256_BLOCK_LEN {
+                compress(&mut self.hash, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Pad the message and write its digest to `digest`. The state must be
+    /// reset before it is used again.
+    pub fn finish(&mut self, digest: &mut [u8; SHA256_DIGEST_LEN]) {
+        let bit_length = self.length.wrapping_mul(8);
+
+        self.block[self.block_len] = 0x80;
+        self.block_len += 1;
+        if self.block_len > SHA256_BLOCK_LEN - 8 {
+            self.block[self.block_len..].iter_mut().for_each(|b| *b = 0);
+            compress(&mut self.hash, &self.block);
+            self.block_len = 0;
+        }
+        self.block[self.block_len..SHA256_BLOCK_LEN - 8]
+            .iter_mut()
+            .for_each(|b| *b = 0);
+        self.block[SHA256_BLOCK_LEN - 8..].copy_from_slice(&bit_length.to_be_bytes());
+        compress(&mut self.hash, &self.block);
+
+        for (chunk, word) in digest.chunks_mut(4).zip(self.hash.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    /// Start a new message, discarding any data added so far.
+    pub fn reset(&mut self) {
+        *self = Sha256State::new();
+    }
+}
+
+fn compress(hash: &mut [u32; 8], block: &[u8; SHA256_BLOCK_LEN]) {
+    let mut w = [0u32; 64];
+    for (i, chunk) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *hash;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(ROUND_CONSTANTS[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (word, v) in hash.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+        *word = word.wrapping_add(*v);
+    }
+}
+
+/// An in-progress HMAC-SHA256 computation (RFC 2104).
+#[derive(Clone)]
+pub struct HmacSha256State {
+    inner: Sha256State,
+    /// Hash state after absorbing the outer padded key.
+    outer: Sha256State,
+}
+
+impl HmacSha256State {
+    pub fn new(key: &[u8]) -> HmacSha256State {
+        // Keys longer than a block are hashed first.
+        let mut key_block = [0u8; SHA256_BLOCK_LEN];
+        if key.len() > SHA256_BLOCK_LEN {
+            let mut hashed = [0u8; SHA256_DIGEST_LEN];
+            let mut sha = Sha256State::new();
+            sha.update(key);
+            sha.finish(&mut hashed);
+            key_block[..SHA256_DIGEST_LEN].copy_from_slice(&hashed);
+        } else {
+            key_block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut pad = [0u8; SHA256_BLOCK_LEN];
+        let mut inner = Sha256State::new();
+        for (p, k) in pad.iter_mut().zip(key_block.iter()) {
+            *p = k ^ 0x36;
+        }
+        inner.update(&pad);
+        let mut outer = Sha256State::new();
+        for (p, k) in pad.iter_mut().zip(key_block.iter()) {
+            *p = k ^ 0x5c;
+        }
+        outer.update(&pad);
+
+        HmacSha256State { inner, outer }
+    }
+
+    /// Add `data` to the message.
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Write the MAC of the message to `mac`.
+    pub fn finish(&mut self, mac: &mut [u8; SHA256_DIGEST_LEN]) {
+        let mut inner_hash = [0u8; SHA256_DIGEST_LEN];
+        self.inner.finish(&mut inner_hash);
+        self.outer.update(&inner_hash);
+        self.outer.finish(mac);
+    }
+}
+
+enum Engine {
+    Sha256(Sha256State),
+    HmacSha256(HmacSha256State),
+}
+
+/// SHA-256 and HMAC-SHA256 digest engine implemented in software.
+pub struct Sha256Software<'a> {
+    client: OptionalCell<&'a dyn digest::Client<'a, 32>>,
+    /// The current computation, `None` if no mode has been set.
+    engine: MapCell<Engine>,
+
+    /// Buffers waiting to be returned from the deferred call.
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; 32]>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> Sha256Software<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> Sha256Software<'a> {
+        Sha256Software {
+            client: OptionalCell::empty(),
+            engine: MapCell::empty(),
+            data: TakeCell::empty(),
+            digest: TakeCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn busy(&self) -> bool {
+        self.data.is_some() || self.digest.is_some()
+    }
+}
+
+impl<'a> digest::Digest<'a, 32> for Sha256Software<'a> {
+    fn set_client(&'a self, client: &'a dyn digest::Client<'a, 32>) {
+        self.client.set(client);
+    }
+
+    /// Hash the data immediately; `add_data_done()` is called from a
+    /// deferred call.
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ErrorCode, &'static mut [u8])> {
+        if self.busy() || self.handle.is_none() {
+            return Err((ErrorCode::BUSY, data.take()));
+        }
+
+        if self.engine.is_none() {
+            self.engine.put(Engine::Sha256(Sha256State::new()));
+        }
+        self.engine.map(|engine| match engine {
+            Engine::Sha256(sha) => sha.update(&data[..]),
+            Engine::HmacSha256(hmac) => hmac.update(&data[..]),
+        });
+
+        let len = data.len();
+        self.data.replace(data.take());
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(len)
+    }
+
+    fn run(
+        &'a self,
+        digest: &'static mut [u8; 32],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 32])> {
+        if self.busy() || self.handle.is_none() {
+            return Err((ErrorCode::BUSY, digest));
+        }
+
+        // Without a mode set this is the SHA-256 of an empty message.
+        match self.engine.take() {
+            Some(Engine::Sha256(mut sha)) => sha.finish(digest),
+            Some(Engine::HmacSha256(mut hmac)) => hmac.finish(digest),
+            None => Sha256State::new().finish(digest),
+        }
+
+        self.digest.replace(digest);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        // Dropping the engine discards the HMAC key and any buffered data.
+        self.engine.take();
+    }
+}
+
+impl digest::Sha256 for Sha256Software<'_> {
+    fn set_mode_sha256(&self) -> Result<(), ErrorCode> {
+        self.engine.replace(Engine::Sha256(Sha256State::new()));
+        Ok(())
+    }
+}
+
+impl digest::Sha384 for Sha256Software<'_> {
+    fn set_mode_sha384(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+impl digest::Sha512 for Sha256Software<'_> {
+    fn set_mode_sha512(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+impl digest::HMACSha256 for Sha256Software<'_> {
+    fn set_mode_hmacsha256(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        self.engine
+            .replace(Engine::HmacSha256(HmacSha256State::new(key)));
+        Ok(())
+    }
+}
+
+impl digest::HMACSha384 for Sha256Software<'_> {
+    fn set_mode_hmacsha384(&self, _key: &[u8]) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+impl digest::HMACSha512 for Sha256Software<'_> {
+    fn set_mode_hmacsha512(&self, _key: &[u8]) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for Sha256Software<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.data.take().map(|data| {
+            self.client
+                .map(move |client| client.add_data_done(Ok(()), data));
+        });
+        self.digest.take().map(|digest| {
+            self.client
+                .map(move |client| client.hash_done(Ok(()), digest));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        let mut digest = [0; 32];
+        let mut sha = Sha256State::new();
+        sha.update(data);
+        sha.finish(&mut digest);
+        digest
+    }
+
+    fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut mac = [0; 32];
+        let mut hmac = HmacSha256State::new(key);
+        hmac.update(data);
+        hmac.finish(&mut mac);
+        mac
+    }
+
+    fn hex(s: &str) -> [u8; 32] {
+        let mut out = [0; 32];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    // FIPS 180-4 examples.
+
+    #[test]
+    fn sha256_empty() {
+        assert_eq!(
+            sha256(b""),
+            hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+    }
+
+    #[test]
+    fn sha256_abc() {
+        assert_eq!(
+            sha256(b"abc"),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+    }
+
+    #[test]
+    fn sha256_two_blocks() {
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+    }
+
+    #[test]
+    fn sha256_million_a() {
+        let mut digest = [0; 32];
+        let mut sha = Sha256State::new();
+        // Uneven chunks exercise partial block buffering.
+        let chunk = [b'a'; 1000];
+        for _ in 0..1000 {
+            sha.update(&chunk[..1]);
+            sha.update(&chunk[1..]);
+        }
+        sha.finish(&mut digest);
+        assert_eq!(
+            digest,
+            hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")
+        );
+    }
+
+    // RFC 4231 test cases.
+
+    #[test]
+    fn hmac_sha256_case_1() {
+        assert_eq!(
+            hmac_sha256(&[0x0b; 20], b"Hi There"),
+            hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7")
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_case_2() {
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_case_6_long_key() {
+        assert_eq!(
+            hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+        );
+    }
+}
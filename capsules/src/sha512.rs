@@ -0,0 +1,627 @@
+//! Software implementation of SHA-384, SHA-512 and their HMACs.
+//!
+//! `Sha512Software` implements `hil::digest::Digest<64>` with the `Sha384`,
+//! `Sha512`, `HMACSha384` and `HMACSha512` modes entirely in software. It
+//! works like `capsules::sha256::Sha256Software`: data is hashed when it is
+//! added and callbacks are delivered through a deferred call. SHA-384 digests
+//! are written to the first 48 bytes of the digest buffer and the rest is
+//! zeroed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let sha = static_init!(
+//!     capsules::sha512::Sha512Software<'static>,
+//!     capsules::sha512::Sha512Software::new(dynamic_deferred_caller)
+//! );
+//! sha.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(sha)
+//!         .expect("no deferred call slot available for sha512"),
+//! );
+//!
+//! let mux_digest = components::digest::DigestMuxComponent::new(sha).finalize(
+//!     components::digest_mux_component_helper!(capsules::sha512::Sha512Software, 64),
+//! );
+//! ```
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::ErrorCode;
+
+/// Size of a SHA-384/512 message block, which is also the HMAC key block
+/// size.
+pub const SHA512_BLOCK_LEN: usize = 128;
+/// Size of a SHA-512 digest.
+pub const SHA512_DIGEST_LEN: usize = 64;
+/// Size of a SHA-384 digest.
+pub const SHA384_DIGEST_LEN: usize = 48;
+
+const ROUND_CONSTANTS: [u64; 80] = [
+    0x428a2f98d728ae22,
+    0x7137449123ef65cd,
+    0xb5c0fbcfec4d3b2f,
+    0xe9b5dba58189dbbc,
+    0x3956c25bf348b538,
+    0x59f111f1b605d019,
+    0x923f82a4af194f9b,
+    0xab1c5ed5da6d8118,
+    0xd807aa98a3030242,
+    0x12835b0145706fbe,
+    0x243185be4ee4b28c,
+    0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f,
+    0x80deb1fe3b1696b1,
+    0x9bdc06a725c71235,
+    0xc19bf174cf692694,
+    0xe49b69c19ef14ad2,
+    0xefbe4786384f25e3,
+    0x0fc19dc68b8cd5b5,
+    0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275,
+    0x4a7484aa6ea6e483,
+    0x5cb0a9dcbd41fbd4,
+    0x76f988da831153b5,
+    0x983e5152ee66dfab,
+    0xa831c66d2db43210,
+    0xb00327c898fb213f,
+    0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2,
+    0xd5a79147930aa725,
+    0x06ca6351e003826f,
+    0x142929670a0e6e70,
+    0x27b70a8546d22ffc,
+    0x2e1b21385c26c926,
+    0x4d2c6dfc5ac42aed,
+    0x53380d139d95b3df,
+    0x650a73548baf63de,
+    0x766a0abb3c77b2a8,
+    0x81c2c92e47edaee6,
+    0x92722c851482353b,
+    0xa2bfe8a14cf10364,
+    0xa81a664bbc423001,
+    0xc24b8b70d0f89791,
+    0xc76c51a30654be30,
+    0xd192e819d6ef5218,
+    0xd69906245565a910,
+    0xf40e35855771202a,
+    0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8,
+    0x1e376c085141ab53,
+    0x2748774cdf8eeb99,
+    0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63,
+    0x4ed8aa4ae3418acb,
+    0x5b9cca4f7763e373,
+    0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc,
+    0x78a5636f43172f60,
+    0x84c87814a1f0ab72,
+    0x8cc702081a6439ec,
+    0x90befffa23631e28,
+    0xa4506cebde82bde9,
+    0xbef9a3f7b2c67915,
+    0xc67178f2e372532b,
+    0xca273eceea26619c,
+    0xd186b8c721c0c207,
+    0xeada7dd6cde0eb1e,
+    0xf57d4f7fee6ed178,
+    0x06f067aa72176fba,
+    0x0a637dc5a2c898a6,
+    0x113f9804bef90dae,
+    0x1b710b35131c471b,
+    0x28db77f523047d84,
+    0x32caab7b40c72493,
+    0x3c9ebe0a15c9bebc,
+    0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6,
+    0x597f299cfc657e2a,
+    0x5fcb6fab3ad6faec,
+    0x6c44198c4a475817,
+];
+
+const SHA512_INITIAL_HASH: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SHA384_INITIAL_HASH: [u64; 8] = [
+    0xcbbb9d5dc1059ed8,
+    0x629a292a367cd507,
+    0x9159015a3070dd17,
+    0x152fecd8f70e5939,
+    0x67332667ffc00b31,
+    0x8eb44a8768581511,
+    0xdb0c2e0d64f98fa7,
+    0x47b5481dbefa4fa4,
+];
+
+/// An in-progress SHA-512 or SHA-384 computation.
+#[derive(Clone)]
+pub struct Sha512State {
+    hash: [u64; 8],
+    /// Bytes of the current block that have not been compressed yet.
+    block: [u8; SHA512_BLOCK_LEN],
+    block_len: usize,
+    /// Total message length in bytes.
+    length: u64,
+    /// Length of the digest: `SHA512_DIGEST_LEN` or `SHA384_DIGEST_LEN`.
+    digest_len: usize,
+}
+
+impl Sha512State {
+    /// Start a SHA-512 computation.
+    pub const fn new() -> Sha512State {
+        Sha512State {
+            hash: SHA512_INITIAL_HASH,
+            block: [0; SHA512_BLOCK_LEN],
+            block_len: 0,
+            length: 0,
+            digest_len: SHA512_DIGEST_LEN,
+        }
+    }
+
+    /// Start a SHA-384 computation.
+    pub const fn new_sha384() -> Sha512State {
+        Sha512State {
+            hash: SHA384_INITIAL_HASH,
+            block: [0; SHA512_BLOCK_LEN],
+            block_len: 0,
+            length: 0,
+            digest_len: SHA384_DIGEST_LEN,
+        }
+    }
+
+    /// Length of the digest this computation produces.
+    pub fn digest_len(&self) -> usize {
+        self.digest_len
+    }
+
+    /// Add `data` to the message.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+
+        while !data.is_empty() {
+            let n = core::cmp::min(SHA512_BLOCK_LEN - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+
+            if self.block_len == SHA512_BLOCK_LEN {
+                compress(&mut self.hash, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Pad the message and write its digest to the start of `digest`,
+    /// zeroing any remaining bytes. The state must be reset before it is used
+    /// again.
+    pub fn finish(&mut self, digest: &mut [u8; SHA512_DIGEST_LEN]) {
+        let bit_length = (self.length as u128).wrapping_mul(8);
+
+        self.block[self.block_len] = 0x80;
+        self.block_len += 1;
+        if self.block_len > SHA512_BLOCK_LEN - 16 {
+            self.block[self.block_len..].iter_mut().for_each(|b| *b = 0);
+            compress(&mut self.hash, &self.block);
+            self.block_len = 0;
+        }
+        self.block[self.block_len..SHA512_BLOCK_LEN - 16]
+            .iter_mut()
+            .for_each(|b| *b = 0);
+        self.block[SHA512_BLOCK_LEN - 16..].copy_from_slice(&bit_length.to_be_bytes());
+        compress(&mut self.hash, &self.block);
+
+        for (chunk, word) in digest.chunks_mut(8).zip(self.hash.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest[self.digest_len..].iter_mut().for_each(|b| *b = 0);
+    }
+
+    /// Start a new message of the same kind, discarding any data added so
+    /// far.
+    pub fn reset(&mut self) {
+        *self = if self.digest_len == SHA384_DIGEST_LEN {
+            Sha512State::new_sha384()
+        } else {
+            Sha512State::new()
+        };
+    }
+}
+
+fn compress(hash: &mut [u64; 8], block: &[u8; SHA512_BLOCK_LEN]) {
+    let mut w = [0u64; 80];
+    for (i, chunk) in block.chunks(8).enumerate() {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(chunk);
+        w[i] = u64::from_be_bytes(bytes);
+    }
+    for i in 16..80 {
+        let s0 = w[i - 15].rotate_right(1) ^ w[i - 15].rotate_right(8) ^ (w[i - 15] >> 7);
+        let s1 = w[i - 2].rotate_right(19) ^ w[i - 2].rotate_right(61) ^ (w[i - 2] >> 6);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *hash;
+    for i in 0..80 {
+        let s1 = e.rotate_right(14) ^ e.rotate_right(18) ^ e.rotate_right(41);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(ROUND_CONSTANTS[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (word, v) in hash.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+        *word = word.wrapping_add(*v);
+    }
+}
+
+/// An in-progress HMAC-SHA512 or HMAC-SHA384 computation (RFC 2104).
+#[derive(Clone)]
+pub struct HmacSha512State {
+    inner: Sha512State,
+    /// Hash state after absorbing the outer padded key.
+    outer: Sha512State,
+}
+
+impl HmacSha512State {
+    /// Start an HMAC-SHA512 computation with `key`.
+    pub fn new(key: &[u8]) -> HmacSha512State {
+        HmacSha512State::with_hash(key, Sha512State::new())
+    }
+
+    /// Start an HMAC-SHA384 computation with `key`.
+    pub fn new_sha384(key: &[u8]) -> HmacSha512State {
+        HmacSha512State::with_hash(key, Sha512State::new_sha384())
+    }
+
+    fn with_hash(key: &[u8], hash: Sha512State) -> HmacSha512State {
+        // Keys longer than a block are hashed first.
+        let mut key_block = [0u8; SHA512_BLOCK_LEN];
+        if key.len() > SHA512_BLOCK_LEN {
+            let mut hashed = [0u8; SHA512_DIGEST_LEN];
+            let mut sha = hash.clone();
+            sha.update(key);
+            sha.finish(&mut hashed);
+            key_block[..SHA512_DIGEST_LEN].copy_from_slice(&hashed);
+        } else {
+            key_block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut pad = [0u8; SHA512_BLOCK_LEN];
+        let mut inner = hash.clone();
+        for (p, k) in pad.iter_mut().zip(key_block.iter()) {
+            *p = k ^ 0x36;
+        }
+        inner.update(&pad);
+        let mut outer = hash;
+        for (p, k) in pad.iter_mut().zip(key_block.iter()) {
+            *p = k ^ 0x5c;
+        }
+        outer.update(&pad);
+
+        HmacSha512State { inner, outer }
+    }
+
+    /// Add `data` to the message.
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Write the MAC of the message to the start of `mac`, zeroing any
+    /// remaining bytes.
+    pub fn finish(&mut self, mac: &mut [u8; SHA512_DIGEST_LEN]) {
+        let mut inner_hash = [0u8; SHA512_DIGEST_LEN];
+        self.inner.finish(&mut inner_hash);
+        self.outer.update(&inner_hash[..self.inner.digest_len()]);
+        self.outer.finish(mac);
+    }
+}
+
+enum Engine {
+    Sha(Sha512State),
+    Hmac(HmacSha512State),
+}
+
+/// SHA-384/512 and HMAC-SHA384/512 digest engine implemented in software.
+pub struct Sha512Software<'a> {
+    client: OptionalCell<&'a dyn digest::Client<'a, 64>>,
+    /// The current computation, `None` if no mode has been set.
+    engine: MapCell<Engine>,
+
+    /// Buffers waiting to be returned from the deferred call.
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; 64]>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> Sha512Software<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> Sha512Software<'a> {
+        Sha512Software {
+            client: OptionalCell::empty(),
+            engine: MapCell::empty(),
+            data: TakeCell::empty(),
+            digest: TakeCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn busy(&self) -> bool {
+        self.data.is_some() || self.digest.is_some()
+    }
+}
+
+impl<'a> digest::Digest<'a, 64> for Sha512Software<'a> {
+    fn set_client(&'a self, client: &'a dyn digest::Client<'a, 64>) {
+        self.client.set(client);
+    }
+
+    /// Hash the data immediately; `add_data_done()` is called from a
+    /// deferred call.
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ErrorCode, &'static mut [u8])> {
+        if self.busy() || self.handle.is_none() {
+            return Err((ErrorCode::BUSY, data.take()));
+        }
+
+        if self.engine.is_none() {
+            self.engine.put(Engine::Sha(Sha512State::new()));
+        }
+        self.engine.map(|engine| match engine {
+            Engine::Sha(sha) => sha.update(&data[..]),
+            Engine::Hmac(hmac) => hmac.update(&data[..]),
+        });
+
+        let len = data.len();
+        self.data.replace(data.take());
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(len)
+    }
+
+    fn run(
+        &'a self,
+        digest: &'static mut [u8; 64],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 64])> {
+        if self.busy() || self.handle.is_none() {
+            return Err((ErrorCode::BUSY, digest));
+        }
+
+        // Without a mode set this is the SHA-512 of an empty message.
+        match self.engine.take() {
+            Some(Engine::Sha(mut sha)) => sha.finish(digest),
+            Some(Engine::Hmac(mut hmac)) => hmac.finish(digest),
+            None => Sha512State::new().finish(digest),
+        }
+
+        self.digest.replace(digest);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        // Dropping the engine discards the HMAC key and any buffered data.
+        self.engine.take();
+    }
+}
+
+impl digest::Sha256 for Sha512Software<'_> {
+    fn set_mode_sha256(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+impl digest::Sha384 for Sha512Software<'_> {
+    fn set_mode_sha384(&self) -> Result<(), ErrorCode> {
+        self.engine.replace(Engine::Sha(Sha512State::new_sha384()));
+        Ok(())
+    }
+}
+
+impl digest::Sha512 for Sha512Software<'_> {
+    fn set_mode_sha512(&self) -> Result<(), ErrorCode> {
+        self.engine.replace(Engine::Sha(Sha512State::new()));
+        Ok(())
+    }
+}
+
+impl digest::HMACSha256 for Sha512Software<'_> {
+    fn set_mode_hmacsha256(&self, _key: &[u8]) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+impl digest::HMACSha384 for Sha512Software<'_> {
+    fn set_mode_hmacsha384(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        self.engine
+            .replace(Engine::Hmac(HmacSha512State::new_sha384(key)));
+        Ok(())
+    }
+}
+
+impl digest::HMACSha512 for Sha512Software<'_> {
+    fn set_mode_hmacsha512(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        self.engine.replace(Engine::Hmac(HmacSha512State::new(key)));
+        Ok(())
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for Sha512Software<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.data.take().map(|data| {
+            self.client
+                .map(move |client| client.add_data_done(Ok(()), data));
+        });
+        self.digest.take().map(|digest| {
+            self.client
+                .map(move |client| client.hash_done(Ok(()), digest));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha(mut state: Sha512State, data: &[u8]) -> [u8; 64] {
+        let mut digest = [0; 64];
+        state.update(data);
+        state.finish(&mut digest);
+        digest
+    }
+
+    fn hmac(mut state: HmacSha512State, data: &[u8]) -> [u8; 64] {
+        let mut mac = [0; 64];
+        state.update(data);
+        state.finish(&mut mac);
+        mac
+    }
+
+    /// Decode `s` into a 64 byte digest, zero padded.
+    fn hex(s: &str) -> [u8; 64] {
+        let mut out = [0; 64];
+        for i in 0..s.len() / 2 {
+            out[i] = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    // FIPS 180-4 examples.
+
+    #[test]
+    fn sha512_empty() {
+        assert_eq!(
+            sha(Sha512State::new(), b"")[..],
+            hex(
+                "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+                 47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"
+            )[..]
+        );
+    }
+
+    #[test]
+    fn sha512_abc() {
+        assert_eq!(
+            sha(Sha512State::new(), b"abc")[..],
+            hex(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            )[..]
+        );
+    }
+
+    #[test]
+    fn sha512_two_blocks() {
+        assert_eq!(
+            sha(
+                Sha512State::new(),
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                  hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"
+            )[..],
+            hex(
+                "8e959b75dae313da8cf4f72814fc143f8f7779c6eb9f7fa17299aeadb6889018\
+                 501d289e4900f7e4331b99dec4b5433ac7d329eeb6dd26545e96e55b874be909"
+            )[..]
+        );
+    }
+
+    #[test]
+    fn sha384_abc() {
+        assert_eq!(
+            sha(Sha512State::new_sha384(), b"abc")[..],
+            hex(
+                "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed\
+                 8086072ba1e7cc2358baeca134c825a7"
+            )[..]
+        );
+    }
+
+    // RFC 4231 test cases.
+
+    #[test]
+    fn hmac_sha512_case_1() {
+        assert_eq!(
+            hmac(HmacSha512State::new(&[0x0b; 20]), b"Hi There")[..],
+            hex(
+                "87aa7cdea5ef619d4ff0b4241a1d6cb02379f4e2ce4ec2787ad0b30545e17cde\
+                 daa833b7d6b8a702038b274eaea3f4e4be9d914eeb61f1702e696c203a126854"
+            )[..]
+        );
+    }
+
+    #[test]
+    fn hmac_sha512_case_2() {
+        assert_eq!(
+            hmac(
+                HmacSha512State::new(b"Jefe"),
+                b"what do ya want for nothing?"
+            )[..],
+            hex(
+                "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea250554\
+                 9758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737"
+            )[..]
+        );
+    }
+
+    #[test]
+    fn hmac_sha512_case_6_long_key() {
+        assert_eq!(
+            hmac(
+                HmacSha512State::new(&[0xaa; 131]),
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )[..],
+            hex(
+                "80b24263c7c1a3ebb71493c1dd7be8b49b46d1f41b4aeec1121b013783f8f352\
+                 6b56d037e05f2598bd0fd2215d6a1e5295e64f73f63f0aec8b915a985d786598"
+            )[..]
+        );
+    }
+
+    #[test]
+    fn hmac_sha384_case_1() {
+        assert_eq!(
+            hmac(HmacSha512State::new_sha384(&[0x0b; 20]), b"Hi There")[..],
+            hex(
+                "afd03944d84895626b0825f4ab46907f15f9dadbe4101ec682aa034c7cebc59c\
+                 faea9ea9076ede7f4af152e8b2fa9cb6"
+            )[..]
+        );
+    }
+}
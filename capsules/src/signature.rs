@@ -0,0 +1,285 @@
+//! Provides userspace with access to signature verification.
+//!
+//! Userspace shares the hash of a payload, for instance a firmware image it
+//! has received, and a signature over it. The capsule checks the signature
+//! against the public key the board configured on the underlying
+//! `SignatureVerify` implementation, so processes can check that a payload
+//! was signed by a key they trust without being able to change that key.
+//!
+//! Verifications from several processes are queued and run one at a time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ecdsa = static_init!(
+//!     capsules::public_key_crypto::p256::EcdsaP256Software<'static>,
+//!     capsules::public_key_crypto::p256::EcdsaP256Software::new(dynamic_deferred_caller)
+//! );
+//! ecdsa.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ecdsa)
+//!         .expect("no deferred call slot available for ecdsa"),
+//! );
+//! ecdsa.set_public_key(&FIRMWARE_SIGNING_KEY).unwrap();
+//!
+//! let signature = static_init!(
+//!     capsules::signature::SignatureDriver<
+//!         'static,
+//!         capsules::public_key_crypto::p256::EcdsaP256Software<'static>,
+//!         32,
+//!         64,
+//!     >,
+//!     capsules::signature::SignatureDriver::new(
+//!         ecdsa,
+//!         &mut capsules::signature::HASH_BUFFER,
+//!         &mut capsules::signature::SIGNATURE_BUFFER,
+//!         board_kernel.create_grant(capsules::signature::DRIVER_NUM, &grant_cap),
+//!     )
+//! );
+//! ecdsa.set_verify_client(signature);
+//! ```
+
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::public_key_crypto::{ClientVerify, SignatureVerify};
+use kernel::ReadableProcessBuffer;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, ProcessId, ReadOnlyProcessBuffer};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Signature as usize;
+
+pub static mut HASH_BUFFER: [u8; 32] = [0; 32];
+pub static mut SIGNATURE_BUFFER: [u8; 64] = [0; 64];
+
+#[derive(Default)]
+pub struct App {
+    hash: ReadOnlyProcessBuffer,
+    signature: ReadOnlyProcessBuffer,
+    /// The process asked for a verification that has not started yet.
+    pending: Cell<bool>,
+}
+
+pub struct SignatureDriver<'a, V: SignatureVerify<'a, HL, SL>, const HL: usize, const SL: usize> {
+    verifier: &'a V,
+    apps: Grant<App, 1>,
+    /// The process whose verification is in progress.
+    current: OptionalCell<ProcessId>,
+
+    hash: TakeCell<'static, [u8; HL]>,
+    signature: TakeCell<'static, [u8; SL]>,
+}
+
+impl<'a, V: SignatureVerify<'a, HL, SL>, const HL: usize, const SL: usize>
+    SignatureDriver<'a, V, HL, SL>
+{
+    pub fn new(
+        verifier: &'a V,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SL],
+        grant: Grant<App, 1>,
+    ) -> SignatureDriver<'a, V, HL, SL> {
+        SignatureDriver {
+            verifier,
+            apps: grant,
+            current: OptionalCell::empty(),
+            hash: TakeCell::new(hash),
+            signature: TakeCell::new(signature),
+        }
+    }
+
+    /// Copy the process's hash and signature and start verifying them.
+    fn start(&self, appid: ProcessId) -> Result<(), ErrorCode> {
+        let (hash, signature) = match (self.hash.take(), self.signature.take()) {
+            (Some(hash), Some(signature)) => (hash, signature),
+            (hash, signature) => {
+                hash.map(|buf| self.hash.replace(buf));
+                signature.map(|buf| self.signature.replace(buf));
+                return Err(ErrorCode::BUSY);
+            }
+        };
+
+        let copied = self
+            .apps
+            .enter(appid, |app, _| {
+                let hash_copied = app.hash.enter(|data| {
+                    if data.len() != HL {
+                        return false;
+                    }
+                    data.copy_to_slice(&mut hash[..]);
+                    true
+                });
+                let signature_copied = app.signature.enter(|data| {
+                    if data.len() != SL {
+                        return false;
+                    }
+                    data.copy_to_slice(&mut signature[..]);
+                    true
+                });
+                hash_copied.unwrap_or(false) && signature_copied.unwrap_or(false)
+            })
+            .unwrap_or(false);
+
+        if !copied {
+            self.hash.replace(hash);
+            self.signature.replace(signature);
+            return Err(ErrorCode::INVAL);
+        }
+
+        match self.verifier.verify(hash, signature) {
+            Ok(()) => {
+                self.current.set(appid);
+                Ok(())
+            }
+            Err((e, hash, signature)) => {
+                self.hash.replace(hash);
+                self.signature.replace(signature);
+                Err(e)
+            }
+        }
+    }
+
+    /// Start the next queued verification, if any.
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let appid = cntr.processid();
+            let pending = cntr.enter(|app, _| app.pending.replace(false));
+            if pending {
+                if let Err(e) = self.start(appid) {
+                    let _ = self.apps.enter(appid, |_, upcalls| {
+                        upcalls
+                            .schedule_upcall(0, kernel::into_statuscode(Err(e)), 0, 0)
+                            .ok();
+                    });
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, V: SignatureVerify<'a, HL, SL>, const HL: usize, const SL: usize> ClientVerify<'a, HL, SL>
+    for SignatureDriver<'a, V, HL, SL>
+{
+    fn verification_done(
+        &'a self,
+        result: Result<bool, ErrorCode>,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SL],
+    ) {
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+
+        self.current.take().map(|appid| {
+            let _ = self.apps.enter(appid, |_, upcalls| {
+                let (status, valid) = match result {
+                    Ok(valid) => (Ok(()), valid as usize),
+                    Err(e) => (Err(e), 0),
+                };
+                upcalls
+                    .schedule_upcall(0, kernel::into_statuscode(status), valid, 0)
+                    .ok();
+            });
+        });
+
+        self.check_queue();
+    }
+}
+
+impl<'a, V: SignatureVerify<'a, HL, SL>, const HL: usize, const SL: usize> Driver
+    for SignatureDriver<'a, V, HL, SL>
+{
+    /// Setup shared kernel-readable buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The hash to verify. Must be exactly as long as the hashes the
+    ///        verifier accepts (32 bytes for SHA-256).
+    /// - `1`: The signature. Must be exactly as long as the signatures the
+    ///        verifier accepts (64 bytes for ECDSA P-256 and Ed25519).
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyProcessBuffer,
+    ) -> Result<ReadOnlyProcessBuffer, (ReadOnlyProcessBuffer, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    mem::swap(&mut slice, &mut app.hash);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut slice, &mut app.signature);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    // Setup callbacks.
+    //
+    // ### `subscribe_num`
+    //
+    // - `0`: A verification finished. The first argument is the status, and
+    //        the second is `1` if the signature is valid and `0` if not.
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Verify the allowed signature over the allowed hash. If another
+    ///        verification is in progress the request is queued. Fails with
+    ///        `BUSY` if this process already has a verification pending.
+    fn command(
+        &self,
+        command_num: usize,
+        _data1: usize,
+        _data2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => {
+                if self.current.contains(&appid) {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                let ret = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        if app.pending.get() {
+                            CommandReturn::failure(ErrorCode::BUSY)
+                        } else {
+                            app.pending.set(true);
+                            CommandReturn::success()
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into());
+
+                // Start the request now if nothing else is running.
+                if self.current.is_none() {
+                    self.check_queue();
+                }
+                ret
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::procs::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
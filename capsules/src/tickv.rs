@@ -97,7 +97,7 @@ impl<'a, F: Flash> tickv::flash_controller::FlashController<512> for TickFSFlast
 
         if self
             .flash
-            .write_page((0x20040000 + address) / 512, data_buf)
+            .write_page(self.region_offset + address / 512, data_buf)
             .is_err()
         {
             return Err(tickv::error_codes::ErrorCode::WriteFail);
@@ -115,12 +115,27 @@ impl<'a, F: Flash> tickv::flash_controller::FlashController<512> for TickFSFlast
 
 pub type TicKVKeyType = [u8; 8];
 
+/// Map a TicKV error that ended an operation to the `ErrorCode` documented by
+/// `hil::kv_system`.
+fn kv_error(e: tickv::error_codes::ErrorCode) -> ErrorCode {
+    match e {
+        tickv::error_codes::ErrorCode::KeyAlreadyExists => ErrorCode::ALREADY,
+        tickv::error_codes::ErrorCode::KeyNotFound => ErrorCode::INVAL,
+        tickv::error_codes::ErrorCode::RegionFull | tickv::error_codes::ErrorCode::FlashFull => {
+            ErrorCode::NOMEM
+        }
+        tickv::error_codes::ErrorCode::ObjectTooLarge
+        | tickv::error_codes::ErrorCode::BufferTooSmall(_) => ErrorCode::SIZE,
+        _ => ErrorCode::FAIL,
+    }
+}
+
 pub struct TicKVStore<'a, F: Flash + 'static> {
     tickv: AsyncTicKV<'a, TickFSFlastCtrl<'a, F>, 512>,
     operation: Cell<Operation>,
     next_operation: Cell<Operation>,
 
-    value_buffer: Cell<Option<&'static [u8]>>,
+    value_buffer: TakeCell<'static, [u8]>,
     key_buffer: TakeCell<'static, [u8; 8]>,
     ret_buffer: TakeCell<'static, [u8]>,
 
@@ -145,7 +160,7 @@ impl<'a, F: Flash> TicKVStore<'a, F> {
             tickv,
             operation: Cell::new(Operation::None),
             next_operation: Cell::new(Operation::None),
-            value_buffer: Cell::new(None),
+            value_buffer: TakeCell::empty(),
             key_buffer: TakeCell::empty(),
             ret_buffer: TakeCell::empty(),
             client: OptionalCell::empty(),
@@ -244,12 +259,14 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
                         );
                     });
                 }
-                Err(tickv::error_codes::ErrorCode::EraseNotReady(_)) | Ok(_) => {}
-                _ => {
+                Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::EraseNotReady(_))
+                | Ok(_) => {}
+                Err(e) => {
                     self.operation.set(Operation::None);
                     self.client.map(|cb| {
                         cb.get_value_complete(
-                            Err(ErrorCode::FAIL),
+                            Err(kv_error(e)),
                             self.key_buffer.take().unwrap(),
                             self.ret_buffer.take().unwrap(),
                         );
@@ -261,14 +278,41 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
                     self.operation.set(Operation::None);
                 }
-                _ => {}
+                Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::WriteNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::EraseNotReady(_))
+                | Ok(_) => {}
+                Err(e) => {
+                    // The key could not be added, for example because it
+                    // already exists.
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.append_key_complete(
+                            Err(kv_error(e)),
+                            self.key_buffer.take().unwrap(),
+                            self.tickv.get_stored_value_buffer().unwrap(),
+                        );
+                    });
+                }
             },
             Operation::InvalidateKey => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
                     self.operation.set(Operation::None);
                 }
-                _ => {}
+                Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::WriteNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::EraseNotReady(_))
+                | Ok(_) => {}
+                Err(e) => {
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.invalidate_key_complete(
+                            Err(kv_error(e)),
+                            self.key_buffer.take().unwrap(),
+                        );
+                    });
+                }
             },
             Operation::GarbageCollect => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
@@ -370,8 +414,15 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
     fn append_key(
         &self,
         key: &'static mut Self::K,
-        value: &'static [u8],
-    ) -> Result<(), (&'static mut Self::K, &'static [u8], Result<(), ErrorCode>)> {
+        value: &'static mut [u8],
+    ) -> Result<
+        (),
+        (
+            &'static mut Self::K,
+            &'static mut [u8],
+            Result<(), ErrorCode>,
+        ),
+    > {
         match self.operation.get() {
             Operation::None => {
                 self.operation.set(Operation::AppendKey);
@@ -387,7 +438,11 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                             self.key_buffer.replace(key);
                             Ok(())
                         }
-                        _ => Err((key, value, Err(ErrorCode::FAIL))),
+                        e => {
+                            self.operation.set(Operation::None);
+                            let value = self.tickv.get_stored_value_buffer().unwrap();
+                            Err((key, value, Err(kv_error(e))))
+                        }
                     },
                 }
             }
@@ -396,7 +451,7 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                 // We can save this request and start it after init
                 self.next_operation.set(Operation::AppendKey);
                 self.key_buffer.replace(key);
-                self.value_buffer.replace(Some(value));
+                self.value_buffer.replace(value);
                 Ok(())
             }
             _ => {
@@ -433,7 +488,10 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                             self.key_buffer.replace(key);
                             Ok(())
                         }
-                        _ => Err((key, buf.unwrap(), Err(ErrorCode::FAIL))),
+                        e => {
+                            self.operation.set(Operation::None);
+                            Err((key, buf.unwrap(), Err(kv_error(e))))
+                        }
                     },
                 }
             }
@@ -471,7 +529,10 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                             self.key_buffer.replace(key);
                             Ok(())
                         }
-                        _ => Err((key, Err(ErrorCode::FAIL))),
+                        e => {
+                            self.operation.set(Operation::None);
+                            Err((key, Err(kv_error(e))))
+                        }
                     },
                 }
             }
@@ -499,7 +560,10 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                     Err(e) => match e {
                         tickv::error_codes::ErrorCode::ReadNotReady(_)
                         | tickv::error_codes::ErrorCode::WriteNotReady(_) => Ok(0),
-                        _ => Err(Err(ErrorCode::FAIL)),
+                        e => {
+                            self.operation.set(Operation::None);
+                            Err(Err(kv_error(e)))
+                        }
                     },
                 }
             }
@@ -6,12 +6,15 @@ use core::cell::Cell;
 use core::cmp;
 use kernel::ErrorCode;
 
+use super::composite::UsbFunction;
 use super::descriptors;
 use super::descriptors::Buffer64;
 use super::descriptors::CdcInterfaceDescriptor;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
+use super::descriptors::FunctionDescriptors;
 use super::descriptors::InterfaceDescriptor;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
@@ -26,12 +29,15 @@ use kernel::hil::time::{Alarm, AlarmClient};
 use kernel::hil::uart;
 use kernel::hil::usb::TransferType;
 
-/// Identifying number for the endpoint when transferring data from us to the
-/// host.
-const ENDPOINT_IN_NUM: usize = 2;
-/// Identifying number for the endpoint when transferring data from the host to
-/// us.
-const ENDPOINT_OUT_NUM: usize = 3;
+/// Endpoint for transferring data from us to the host, relative to the first
+/// endpoint of the function.
+const ENDPOINT_IN: usize = 0;
+/// Endpoint for transferring data from the host to us.
+const ENDPOINT_OUT: usize = 1;
+/// Interrupt endpoint for notifications to the host.
+const ENDPOINT_NOTIFY: usize = 2;
+/// First endpoint number used when the CDC device is used on its own.
+const FIRST_ENDPOINT_NUM: usize = 2;
 
 static LANGUAGES: &'static [u16; 1] = &[
     0x0409, // English (United States)
@@ -109,6 +115,11 @@ pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
     /// 64 byte buffers for each endpoint.
     buffers: [Buffer64; N_ENDPOINTS],
 
+    /// Interface and endpoint numbers the function starts at. These are
+    /// reassigned when the CDC device is part of a composite device.
+    first_interface: Cell<u8>,
+    first_endpoint: Cell<usize>,
+
     /// Current state of the CDC driver. This helps us track if a CDC client is
     /// connected and listening or not.
     state: Cell<State>,
@@ -184,16 +195,80 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
         deferred_caller: &'a DynamicDeferredCall,
         host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
     ) -> Self {
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            Self::with_descriptors(0, FIRST_ENDPOINT_NUM, |function| {
+                descriptors::create_function_descriptor_buffers(
+                    descriptors::DeviceDescriptor {
+                        vendor_id: vendor_id,
+                        product_id: product_id,
+                        manufacturer_string: 1,
+                        product_string: 2,
+                        serial_number_string: 3,
+                        class: 0x2, // Class: CDC
+                        max_packet_size_ep0: max_ctrl_packet_size,
+                        ..descriptors::DeviceDescriptor::default()
+                    },
+                    descriptors::ConfigurationDescriptor {
+                        ..descriptors::ConfigurationDescriptor::default()
+                    },
+                    function,
+                )
+            });
+
+        Self {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [
+                Buffer64::default(),
+                Buffer64::default(),
+                Buffer64::default(),
+            ],
+            first_interface: Cell::new(0),
+            first_endpoint: Cell::new(FIRST_ENDPOINT_NUM),
+            state: Cell::new(State::Disabled),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_client: OptionalCell::empty(),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_offset: Cell::new(0),
+            rx_client: OptionalCell::empty(),
+            timeout_alarm,
+            boot_period: Cell::new(true),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+            deferred_call_pending_droptx: Cell::new(false),
+            deferred_call_pending_abortrx: Cell::new(false),
+            host_initiated_function,
+        }
+    }
+
+    /// Call `f` with the descriptors of the CDC-ACM function, with interface
+    /// and endpoint numbers starting at the given ones.
+    fn with_descriptors<R, F: FnOnce(&mut FunctionDescriptors) -> R>(
+        first_interface: u8,
+        first_endpoint: usize,
+        f: F,
+    ) -> R {
         let interfaces: &mut [InterfaceDescriptor] = &mut [
             InterfaceDescriptor {
-                interface_number: 0,
+                interface_number: first_interface,
                 interface_class: 0x02,    // CDC communication
                 interface_subclass: 0x02, // abstract control model (ACM)
                 interface_protocol: 0x01, // V.25ter (AT commands)
                 ..InterfaceDescriptor::default()
             },
             InterfaceDescriptor {
-                interface_number: 1,
+                interface_number: first_interface + 1,
                 interface_class: 0x0a,    // CDC data
                 interface_subclass: 0x00, // none
                 interface_protocol: 0x00, // none
@@ -201,7 +276,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             },
         ];
 
-        let cdc_descriptors: &mut [CdcInterfaceDescriptor] = &mut [
+        let cdc_descriptors: &[CdcInterfaceDescriptor] = &[
             CdcInterfaceDescriptor {
                 subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
                 field1: 0x10, // CDC
@@ -209,8 +284,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             },
             CdcInterfaceDescriptor {
                 subtype: descriptors::CdcInterfaceDescriptorSubType::CallManagement,
-                field1: 0x00, // Capabilities
-                field2: 0x01, // Data interface 1
+                field1: 0x00,                // Capabilities
+                field2: first_interface + 1, // Data interface
             },
             CdcInterfaceDescriptor {
                 subtype: descriptors::CdcInterfaceDescriptorSubType::AbstractControlManagement,
@@ -219,22 +294,25 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             },
             CdcInterfaceDescriptor {
                 subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
-                field1: 0x00, // Interface 0
-                field2: 0x01, // Interface 1
+                field1: first_interface,     // Communication interface
+                field2: first_interface + 1, // Data interface
             },
         ];
 
         let endpoints: &[&[EndpointDescriptor]] = &[
             &[EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(4, TransferDirection::DeviceToHost),
+                endpoint_address: EndpointAddress::new(
+                    first_endpoint + ENDPOINT_NOTIFY,
+                    TransferDirection::DeviceToHost,
+                ),
                 transfer_type: TransferType::Interrupt,
                 max_packet_size: 8,
                 interval: 16,
             }],
             &[
                 EndpointDescriptor {
-                    endpoint_address: EndpointAddress::new_const(
-                        2,
+                    endpoint_address: EndpointAddress::new(
+                        first_endpoint + ENDPOINT_IN,
                         TransferDirection::DeviceToHost,
                     ),
                     transfer_type: TransferType::Bulk,
@@ -242,8 +320,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
                     interval: 0,
                 },
                 EndpointDescriptor {
-                    endpoint_address: EndpointAddress::new_const(
-                        3,
+                    endpoint_address: EndpointAddress::new(
+                        first_endpoint + ENDPOINT_OUT,
                         TransferDirection::HostToDevice,
                     ),
                     transfer_type: TransferType::Bulk,
@@ -253,60 +331,13 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             ],
         ];
 
-        let (device_descriptor_buffer, other_descriptor_buffer) =
-            descriptors::create_descriptor_buffers(
-                descriptors::DeviceDescriptor {
-                    vendor_id: vendor_id,
-                    product_id: product_id,
-                    manufacturer_string: 1,
-                    product_string: 2,
-                    serial_number_string: 3,
-                    class: 0x2, // Class: CDC
-                    max_packet_size_ep0: max_ctrl_packet_size,
-                    ..descriptors::DeviceDescriptor::default()
-                },
-                descriptors::ConfigurationDescriptor {
-                    ..descriptors::ConfigurationDescriptor::default()
-                },
-                interfaces,
-                endpoints,
-                None, // No HID descriptor
-                Some(cdc_descriptors),
-            );
-
-        Self {
-            client_ctrl: ClientCtrl::new(
-                controller,
-                device_descriptor_buffer,
-                other_descriptor_buffer,
-                None, // No HID descriptor
-                None, // No report descriptor
-                LANGUAGES,
-                strings,
-            ),
-            buffers: [
-                Buffer64::default(),
-                Buffer64::default(),
-                Buffer64::default(),
-            ],
-            state: Cell::new(State::Disabled),
-            ctrl_state: Cell::new(CtrlState::Idle),
-            tx_buffer: TakeCell::empty(),
-            tx_len: Cell::new(0),
-            tx_offset: Cell::new(0),
-            tx_client: OptionalCell::empty(),
-            rx_buffer: TakeCell::empty(),
-            rx_len: Cell::new(0),
-            rx_offset: Cell::new(0),
-            rx_client: OptionalCell::empty(),
-            timeout_alarm,
-            boot_period: Cell::new(true),
-            deferred_caller,
-            handle: OptionalCell::empty(),
-            deferred_call_pending_droptx: Cell::new(false),
-            deferred_call_pending_abortrx: Cell::new(false),
-            host_initiated_function,
-        }
+        f(&mut FunctionDescriptors {
+            interfaces,
+            endpoints,
+            hid: None,
+            cdc: Some(cdc_descriptors),
+            cdc_ethernet: None,
+        })
     }
 
     pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
@@ -318,9 +349,15 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
         self.client_ctrl.controller()
     }
 
+    /// Endpoint number of the endpoint at `offset` within the function.
     #[inline]
-    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
-        &self.buffers[i - 1].buf
+    fn endpoint(&self, offset: usize) -> usize {
+        self.first_endpoint.get() + offset
+    }
+
+    #[inline]
+    fn buffer(&'a self, endpoint: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[endpoint - self.first_endpoint.get()].buf
     }
 
     /// This is a helper function used to indicate successful uart transmission to
@@ -345,23 +382,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
         // Set up the default control endpoint
         self.client_ctrl.enable();
 
-        // Setup buffers for IN and OUT data transfer.
-        self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
-        self.controller()
-            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
-
-        self.controller()
-            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
-        self.controller()
-            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
-
-        self.state.set(State::Enabled);
-
-        self.timeout_alarm.set_alarm(
-            self.timeout_alarm.now(),
-            A::ticks_from_ms(CDC_BUFFER_TIMEOUT_MS),
-        );
+        self.enable_endpoints();
     }
 
     fn attach(&'a self) {
@@ -379,30 +400,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     /// CDC uses special values here, and we can use these to know when a CDC
     /// client is connected or not.
     fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
-        descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).map(|setup_data| {
-            let b_request = setup_data.request_code;
-
-            match CDCCntrlMessage::from(b_request) {
-                CDCCntrlMessage::SetLineCoding => {
-                    self.ctrl_state.set(CtrlState::SetLineCoding);
-                }
-                CDCCntrlMessage::SetControlLineState => {
-                    // Bit 0 and 1 of the value (setup_data.value) can be set
-                    // D0: Indicates to DCE if DTE is present or not.
-                    //     - 0 -> Not present
-                    //     - 1 -> Present
-                    // D1: Carrier control for half duplex modems.
-                    //     - 0 -> Deactivate carrier
-                    //     - 1 -> Activate carrier
-                    // Currently we don't care about the value
-                }
-                CDCCntrlMessage::SendBreak => {
-                    // On Mac, we seem to get the SEND_BREAK to signal that a
-                    // client disconnects.
-                    self.state.set(State::Enumerated)
-                }
-                _ => {}
-            }
+        SetupData::get(&self.client_ctrl.ctrl_buffer.buf).map(|setup_data| {
+            self.function_ctrl_setup(&setup_data, &self.client_ctrl.ctrl_buffer.buf)
         });
 
         self.client_ctrl.ctrl_setup(endpoint)
@@ -415,31 +414,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
     /// Handle a Control Out transaction
     fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
-        // Check what state our Ctrl endpoint is in.
-        if self.ctrl_state.get() == CtrlState::SetLineCoding {
-            // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
-            // We can parse the data we got.
-            descriptors::CdcAcmSetLineCodingData::get(&self.client_ctrl.ctrl_buffer.buf).map(
-                |line_coding| {
-                    // Check if we should switch our main state machine to
-                    // connecting meaning that the host is connecting to the virtual
-                    // serial port. We decide this based on if the host is
-                    // configuring the baud rate to what we expect.
-                    if self.state.get() == State::Enumerated && line_coding.baud_rate == 115200 {
-                        self.state.set(State::Connecting);
-                    }
-
-                    // Check if the baud rate we got matches the special flag
-                    // value (1200 baud). If so, we run an optional function
-                    // provided when the CDC stack was configured.
-                    if line_coding.baud_rate == 1200 {
-                        self.host_initiated_function.map(|f| {
-                            f();
-                        });
-                    }
-                },
-            );
-        }
+        self.function_ctrl_out(&self.client_ctrl.ctrl_buffer.buf, packet_bytes);
 
         self.client_ctrl.ctrl_out(endpoint, packet_bytes)
     }
@@ -450,16 +425,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&'a self, endpoint: usize) {
-        self.ctrl_state.set(CtrlState::Idle);
-
-        // Here we check to see if we just got connected to a CDC client. If so,
-        // we can begin transmitting if needed.
-        if self.state.get() == State::Connecting {
-            self.state.set(State::Connected);
-            if self.tx_buffer.is_some() {
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
-            }
-        }
+        self.function_ctrl_status_complete();
 
         self.client_ctrl.ctrl_status_complete(endpoint)
     }
@@ -591,7 +557,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
             if remaining > 0 {
                 // We do, so ask to send again.
                 self.tx_buffer.replace(tx_buf);
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                self.controller()
+                    .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
             } else {
                 // We don't have anything to send, so that means we are
                 // ok to signal the callback.
@@ -605,6 +572,123 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     }
 }
 
+impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> UsbFunction<'a> for CdcAcm<'a, U, A> {
+    fn num_interfaces(&self) -> u8 {
+        2
+    }
+
+    fn num_endpoints(&self) -> usize {
+        N_ENDPOINTS
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.first_interface.set(first_interface);
+        self.first_endpoint.set(first_endpoint);
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&mut FunctionDescriptors)) {
+        Self::with_descriptors(self.first_interface.get(), self.first_endpoint.get(), f)
+    }
+
+    fn enable_endpoints(&'a self) {
+        // Setup buffers for IN and OUT data transfer.
+        self.controller().endpoint_set_in_buffer(
+            self.endpoint(ENDPOINT_IN),
+            self.buffer(self.endpoint(ENDPOINT_IN)),
+        );
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, self.endpoint(ENDPOINT_IN));
+
+        self.controller().endpoint_set_out_buffer(
+            self.endpoint(ENDPOINT_OUT),
+            self.buffer(self.endpoint(ENDPOINT_OUT)),
+        );
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, self.endpoint(ENDPOINT_OUT));
+
+        self.state.set(State::Enabled);
+
+        self.timeout_alarm.set_alarm(
+            self.timeout_alarm.now(),
+            A::ticks_from_ms(CDC_BUFFER_TIMEOUT_MS),
+        );
+    }
+
+    /// CDC uses special values here, and we can use these to know when a CDC
+    /// client is connected or not.
+    fn function_ctrl_setup(
+        &'a self,
+        setup_data: &SetupData,
+        _ctrl_buffer: &'a [VolatileCell<u8>; 64],
+    ) -> Option<hil::usb::CtrlSetupResult> {
+        let b_request = setup_data.request_code;
+
+        match CDCCntrlMessage::from(b_request) {
+            CDCCntrlMessage::SetLineCoding => {
+                self.ctrl_state.set(CtrlState::SetLineCoding);
+            }
+            CDCCntrlMessage::SetControlLineState => {
+                // Bit 0 and 1 of the value (setup_data.value) can be set
+                // D0: Indicates to DCE if DTE is present or not.
+                //     - 0 -> Not present
+                //     - 1 -> Present
+                // D1: Carrier control for half duplex modems.
+                //     - 0 -> Deactivate carrier
+                //     - 1 -> Activate carrier
+                // Currently we don't care about the value
+            }
+            CDCCntrlMessage::SendBreak => {
+                // On Mac, we seem to get the SEND_BREAK to signal that a
+                // client disconnects.
+                self.state.set(State::Enumerated)
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    fn function_ctrl_out(&'a self, ctrl_buffer: &'a [VolatileCell<u8>; 64], _packet_bytes: u32) {
+        // Check what state our Ctrl endpoint is in.
+        if self.ctrl_state.get() == CtrlState::SetLineCoding {
+            // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
+            // We can parse the data we got.
+            descriptors::CdcAcmSetLineCodingData::get(ctrl_buffer).map(|line_coding| {
+                // Check if we should switch our main state machine to
+                // connecting meaning that the host is connecting to the virtual
+                // serial port. We decide this based on if the host is
+                // configuring the baud rate to what we expect.
+                if self.state.get() == State::Enumerated && line_coding.baud_rate == 115200 {
+                    self.state.set(State::Connecting);
+                }
+
+                // Check if the baud rate we got matches the special flag
+                // value (1200 baud). If so, we run an optional function
+                // provided when the CDC stack was configured.
+                if line_coding.baud_rate == 1200 {
+                    self.host_initiated_function.map(|f| {
+                        f();
+                    });
+                }
+            });
+        }
+    }
+
+    fn function_ctrl_status_complete(&'a self) {
+        self.ctrl_state.set(CtrlState::Idle);
+
+        // Here we check to see if we just got connected to a CDC client. If so,
+        // we can begin transmitting if needed.
+        if self.state.get() == State::Connecting {
+            self.state.set(State::Connected);
+            if self.tx_buffer.is_some() {
+                self.controller()
+                    .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+            }
+        }
+    }
+}
+
 impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Configure for CdcAcm<'a, U, A> {
     fn configure(&self, _parameters: uart::Parameters) -> Result<(), ErrorCode> {
         // Since this is not a real UART, we don't need to consider these
@@ -643,7 +727,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Transmit<'a>
             if self.state.get() == State::Connected {
                 // Then signal to the lower layer that we are ready to do a TX
                 // by putting data in the IN endpoint.
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                self.controller()
+                    .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
                 Ok(())
             } else if self.boot_period.get() {
                 // indicate success because we will try to send it once a host connects
@@ -0,0 +1,770 @@
+//! Communications Class Device, Ethernet Control Model, for USB
+//!
+//! This capsule presents a USB Ethernet interface (CDC-ECM) to the host and
+//! exposes it to the kernel as an `EthernetDevice`, so that the network stack
+//! can exchange Ethernet frames with the host.
+//!
+//! The device has two interfaces:
+//!
+//! - Interface 0 (communications) with an interrupt IN endpoint (1) used to
+//!   tell the host the link is connected.
+//! - Interface 1 (data) with a bulk IN (2) and bulk OUT (3) endpoint carrying
+//!   whole Ethernet frames, each terminated by a short (or zero length)
+//!   packet. As CDC-ECM requires, the endpoints belong to alternate setting
+//!   1; the default setting 0 has none. Frames only flow while the host has
+//!   selected setting 1.
+//!
+//! The fourth entry of `strings` is the MAC address the host uses for its end
+//! of the link, written as 12 hexadecimal digits (e.g. `"02000000000a"`). It
+//! must be different from the `mac_address` given for the device's own end.
+//! The link is reported up once the host configures the Ethernet packet
+//! filter, which hosts do when bringing the interface up.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ethernet::EthernetAddress;
+//!
+//! let ecm = static_init!(
+//!     capsules::usb::cdc_ecm::CdcEcm<'static, UsbDevice>,
+//!     capsules::usb::cdc_ecm::CdcEcm::new(
+//!         usb_device,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x6668,
+//!         0xabce,
+//!         &["Tock", "Border router", "0001", "02000000000a"],
+//!         EthernetAddress([0x02, 0, 0, 0, 0, 0x0b]),
+//!         &mut capsules::usb::cdc_ecm::RX_BUFFER,
+//!     )
+//! );
+//! usb_device.set_client(ecm);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::ErrorCode;
+
+use super::composite::UsbFunction;
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::CdcEthernetNetworkingDescriptor;
+use super::descriptors::CdcInterfaceDescriptor;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::FunctionDescriptors;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+use crate::net::ethernet::ETHERNET_MAX_FRAME_LEN;
+use crate::net::ethernet::{EthernetAddress, EthernetClient, EthernetDevice};
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+
+/// Interrupt endpoint used for notifications to the host, relative to the
+/// first endpoint of the function.
+const ENDPOINT_NOTIFY: usize = 0;
+/// Bulk endpoint for frames from us to the host.
+const ENDPOINT_IN: usize = 1;
+/// Bulk endpoint for frames from the host to us.
+const ENDPOINT_OUT: usize = 2;
+/// First endpoint number used when the CDC-ECM device is used on its own.
+const FIRST_ENDPOINT_NUM: usize = 1;
+
+const N_ENDPOINTS: usize = 3;
+
+/// Size of a full speed bulk packet.
+const BULK_PACKET_SIZE: usize = 64;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Buffer received frames are assembled in.
+pub static mut RX_BUFFER: [u8; ETHERNET_MAX_FRAME_LEN] = [0; ETHERNET_MAX_FRAME_LEN];
+
+/// Class specific request setting which frames the host wants to receive.
+const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+
+/// Length of a NETWORK_CONNECTION notification.
+const NOTIFICATION_LEN: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    Disabled,
+    Enabled,
+    Attached,
+    Enumerated,
+    /// The host has set the packet filter. We move to `Connected` once the
+    /// control transfer completes.
+    Connecting,
+    /// The host has brought the interface up and we have told it the link is
+    /// connected.
+    Connected,
+}
+
+pub struct CdcEcm<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    /// Interface and endpoint numbers the function starts at. These are
+    /// reassigned when the CDC-ECM device is part of a composite device.
+    first_interface: Cell<u8>,
+    first_endpoint: Cell<usize>,
+
+    state: Cell<State>,
+
+    /// MAC address of our end of the link.
+    mac_address: EthernetAddress,
+
+    /// Whether a NETWORK_CONNECTION notification is waiting to be sent.
+    notification_pending: Cell<bool>,
+    /// Whether the host selected the alternate setting of the data interface
+    /// that has the bulk endpoints.
+    data_enabled: Cell<bool>,
+
+    /// Frame being transmitted to the host.
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    /// Whether the frame still needs a zero length packet to terminate it,
+    /// which is the case when its length is a multiple of the packet size.
+    tx_zlp_pending: Cell<bool>,
+
+    /// Buffer frames from the host are assembled in.
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    /// Set when the frame being received does not fit in `rx_buffer`. The
+    /// rest of it is discarded.
+    rx_overflow: Cell<bool>,
+
+    client: OptionalCell<&'a dyn EthernetClient>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CdcEcm<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 4],
+        mac_address: EthernetAddress,
+        rx_buffer: &'static mut [u8],
+    ) -> Self {
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            Self::with_descriptors(0, FIRST_ENDPOINT_NUM, |function| {
+                descriptors::create_function_descriptor_buffers(
+                    descriptors::DeviceDescriptor {
+                        vendor_id: vendor_id,
+                        product_id: product_id,
+                        manufacturer_string: 1,
+                        product_string: 2,
+                        serial_number_string: 3,
+                        class: 0x2, // Class: CDC
+                        max_packet_size_ep0: max_ctrl_packet_size,
+                        ..descriptors::DeviceDescriptor::default()
+                    },
+                    descriptors::ConfigurationDescriptor {
+                        ..descriptors::ConfigurationDescriptor::default()
+                    },
+                    function,
+                )
+            });
+
+        Self {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [
+                Buffer64::default(),
+                Buffer64::default(),
+                Buffer64::default(),
+            ],
+            first_interface: Cell::new(0),
+            first_endpoint: Cell::new(FIRST_ENDPOINT_NUM),
+            state: Cell::new(State::Disabled),
+            mac_address,
+            notification_pending: Cell::new(false),
+            data_enabled: Cell::new(false),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_zlp_pending: Cell::new(false),
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_len: Cell::new(0),
+            rx_overflow: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    /// Call `f` with the descriptors of the CDC-ECM function, with interface
+    /// and endpoint numbers starting at the given ones.
+    fn with_descriptors<R, F: FnOnce(&mut FunctionDescriptors) -> R>(
+        first_interface: u8,
+        first_endpoint: usize,
+        f: F,
+    ) -> R {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [
+            InterfaceDescriptor {
+                interface_number: first_interface,
+                interface_class: 0x02,    // CDC communication
+                interface_subclass: 0x06, // Ethernet control model (ECM)
+                interface_protocol: 0x00, // none
+                ..InterfaceDescriptor::default()
+            },
+            InterfaceDescriptor {
+                interface_number: first_interface + 1,
+                interface_class: 0x0a,    // CDC data
+                interface_subclass: 0x00, // none
+                interface_protocol: 0x00, // none
+                ..InterfaceDescriptor::default()
+            },
+            InterfaceDescriptor {
+                interface_number: first_interface + 1,
+                alternate_setting: 1,
+                interface_class: 0x0a,    // CDC data
+                interface_subclass: 0x00, // none
+                interface_protocol: 0x00, // none
+                ..InterfaceDescriptor::default()
+            },
+        ];
+
+        let cdc_descriptors: &[CdcInterfaceDescriptor] = &[
+            CdcInterfaceDescriptor {
+                subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
+                field1: 0x10, // CDC 1.10
+                field2: 0x01, // CDC 1.10
+            },
+            CdcInterfaceDescriptor {
+                subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
+                field1: first_interface,     // Communication interface
+                field2: first_interface + 1, // Data interface
+            },
+        ];
+
+        let ethernet_descriptor = CdcEthernetNetworkingDescriptor {
+            mac_address_string: 4,
+            ethernet_statistics: 0, // No statistics
+            max_segment_size: ETHERNET_MAX_FRAME_LEN as u16,
+            num_multicast_filters: 0, // No perfect multicast filtering
+            num_power_filters: 0,
+        };
+
+        let endpoints: &[&[EndpointDescriptor]] = &[
+            &[EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    first_endpoint + ENDPOINT_NOTIFY,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 16,
+                interval: 32,
+            }],
+            // The default setting of the data interface has no endpoints.
+            &[],
+            &[
+                EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        first_endpoint + ENDPOINT_IN,
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: BULK_PACKET_SIZE as u16,
+                    interval: 0,
+                },
+                EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        first_endpoint + ENDPOINT_OUT,
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: BULK_PACKET_SIZE as u16,
+                    interval: 0,
+                },
+            ],
+        ];
+
+        f(&mut FunctionDescriptors {
+            interfaces,
+            endpoints,
+            hid: None,
+            cdc: Some(cdc_descriptors),
+            cdc_ethernet: Some(&ethernet_descriptor),
+        })
+    }
+
+    /// Endpoint number of the endpoint at `offset` within the function.
+    #[inline]
+    fn endpoint(&self, offset: usize) -> usize {
+        self.first_endpoint.get() + offset
+    }
+
+    #[inline]
+    fn buffer(&'a self, endpoint: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[endpoint - self.first_endpoint.get()].buf
+    }
+
+    /// Stop moving frames, as the host reset the device or deselected the
+    /// data endpoints. Partly received frames are dropped.
+    fn disable_data(&self) {
+        self.data_enabled.set(false);
+        self.rx_len.set(0);
+        self.rx_overflow.set(false);
+        self.abort_transmit();
+    }
+
+    /// Drop any frame in flight to the host and return it to the client.
+    fn abort_transmit(&self) {
+        self.tx_buffer.take().map(|tx_buf| {
+            self.client
+                .map(move |client| client.transmit_done(tx_buf, Err(ErrorCode::OFF)));
+        });
+    }
+
+    fn transmit_packet(&'a self) -> hil::usb::InResult {
+        self.tx_buffer.map_or(hil::usb::InResult::Delay, |tx_buf| {
+            let offset = self.tx_offset.get();
+            let remaining = self.tx_len.get() - offset;
+            if remaining > 0 {
+                let packet = self.buffer(self.endpoint(ENDPOINT_IN));
+                let to_send = cmp::min(packet.len(), remaining);
+                for i in 0..to_send {
+                    packet[i].set(tx_buf[offset + i]);
+                }
+                self.tx_offset.set(offset + to_send);
+                hil::usb::InResult::Packet(to_send)
+            } else if self.tx_zlp_pending.replace(false) {
+                hil::usb::InResult::Packet(0)
+            } else {
+                hil::usb::InResult::Delay
+            }
+        })
+    }
+
+    fn receive_packet(&'a self, packet_bytes: usize) {
+        let packet = self.buffer(self.endpoint(ENDPOINT_OUT));
+        self.rx_buffer.map(|rx_buf| {
+            let offset = self.rx_len.get();
+            if offset + packet_bytes > rx_buf.len() {
+                self.rx_overflow.set(true);
+            } else if !self.rx_overflow.get() {
+                for i in 0..packet_bytes {
+                    rx_buf[offset + i] = packet[i].get();
+                }
+                self.rx_len.set(offset + packet_bytes);
+            }
+
+            // A short packet ends the frame.
+            if packet_bytes < BULK_PACKET_SIZE {
+                let len = self.rx_len.replace(0);
+                if !self.rx_overflow.replace(false) && len > 0 {
+                    self.client
+                        .map(|client| client.frame_received(&rx_buf[..len]));
+                }
+            }
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CdcEcm<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        self.enable_endpoints();
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+        self.state.set(State::Attached);
+    }
+
+    fn bus_reset(&'a self) {
+        // The host has to bring the interface up again after a reset.
+        self.state.set(State::Enumerated);
+        self.notification_pending.set(false);
+        self.disable_data();
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        SetupData::get(&self.client_ctrl.ctrl_buffer.buf).map(|setup_data| {
+            self.function_ctrl_setup(&setup_data, &self.client_ctrl.ctrl_buffer.buf)
+        });
+
+        self.client_ctrl.ctrl_setup(endpoint)
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.function_ctrl_status_complete();
+
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk/Interrupt IN transaction.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                if endpoint == self.endpoint(ENDPOINT_NOTIFY) && self.notification_pending.get() {
+                    // NETWORK_CONNECTION notification, value 1 (connected),
+                    // for the communication interface.
+                    let notification: [u8; NOTIFICATION_LEN] =
+                        [0xa1, 0x00, 1, 0, self.first_interface.get(), 0, 0, 0];
+                    let packet = self.buffer(endpoint);
+                    for (i, b) in notification.iter().enumerate() {
+                        packet[i].set(*b);
+                    }
+                    hil::usb::InResult::Packet(NOTIFICATION_LEN)
+                } else {
+                    hil::usb::InResult::Delay
+                }
+            }
+            TransferType::Bulk => self.transmit_packet(),
+            TransferType::Control | TransferType::Isochronous => hil::usb::InResult::Delay,
+        }
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                self.receive_packet(packet_bytes as usize);
+                hil::usb::OutResult::Ok
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::OutResult::Ok
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        if endpoint == self.endpoint(ENDPOINT_NOTIFY) {
+            self.notification_pending.set(false);
+            return;
+        }
+
+        let frame_done = self.tx_len.get() == self.tx_offset.get() && !self.tx_zlp_pending.get();
+        if frame_done {
+            self.tx_buffer.take().map(|tx_buf| {
+                self.client
+                    .map(move |client| client.transmit_done(tx_buf, Ok(())));
+            });
+        } else {
+            self.controller()
+                .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for CdcEcm<'a, U> {
+    fn num_interfaces(&self) -> u8 {
+        2
+    }
+
+    fn num_endpoints(&self) -> usize {
+        N_ENDPOINTS
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.first_interface.set(first_interface);
+        self.first_endpoint.set(first_endpoint);
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&mut FunctionDescriptors)) {
+        Self::with_descriptors(self.first_interface.get(), self.first_endpoint.get(), f)
+    }
+
+    fn enable_endpoints(&'a self) {
+        self.controller().endpoint_set_in_buffer(
+            self.endpoint(ENDPOINT_NOTIFY),
+            self.buffer(self.endpoint(ENDPOINT_NOTIFY)),
+        );
+        self.controller()
+            .endpoint_in_enable(TransferType::Interrupt, self.endpoint(ENDPOINT_NOTIFY));
+
+        self.controller().endpoint_set_in_buffer(
+            self.endpoint(ENDPOINT_IN),
+            self.buffer(self.endpoint(ENDPOINT_IN)),
+        );
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, self.endpoint(ENDPOINT_IN));
+
+        self.controller().endpoint_set_out_buffer(
+            self.endpoint(ENDPOINT_OUT),
+            self.buffer(self.endpoint(ENDPOINT_OUT)),
+        );
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, self.endpoint(ENDPOINT_OUT));
+
+        self.state.set(State::Enabled);
+    }
+
+    /// The host sets the Ethernet packet filter when it brings the interface
+    /// up, which we take as the signal to report the link as connected. It
+    /// also selects the alternate setting of the data interface with the
+    /// endpoints, which we track to know when frames can flow. The requests
+    /// themselves are answered by `ClientCtrl`.
+    fn function_ctrl_setup(
+        &'a self,
+        setup_data: &SetupData,
+        _ctrl_buffer: &'a [VolatileCell<u8>; 64],
+    ) -> Option<hil::usb::CtrlSetupResult> {
+        if let Some(StandardRequest::SetInterface {
+            alternate_setting,
+            interface,
+        }) = setup_data.get_standard_request()
+        {
+            if interface == (self.first_interface.get() + 1) as u16 {
+                match alternate_setting {
+                    0 => self.disable_data(),
+                    1 => self.data_enabled.set(true),
+                    // Rejected by `ClientCtrl`.
+                    _ => {}
+                }
+            }
+        } else if matches!(setup_data.request_type.request_type(), RequestType::Class)
+            && setup_data.request_code == SET_ETHERNET_PACKET_FILTER
+        {
+            if setup_data.value != 0 && self.state.get() == State::Enumerated {
+                self.state.set(State::Connecting);
+            }
+        }
+
+        None
+    }
+
+    fn function_ctrl_status_complete(&'a self) {
+        if self.state.get() == State::Connecting {
+            self.state.set(State::Connected);
+            self.notification_pending.set(true);
+            self.controller()
+                .endpoint_resume_in(self.endpoint(ENDPOINT_NOTIFY));
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> EthernetDevice<'a> for CdcEcm<'a, U> {
+    fn set_client(&self, client: &'a dyn EthernetClient) {
+        self.client.set(client);
+    }
+
+    fn address(&self) -> EthernetAddress {
+        self.mac_address
+    }
+
+    fn link_up(&self) -> bool {
+        self.state.get() == State::Connected && self.data_enabled.get()
+    }
+
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.link_up() {
+            Err((ErrorCode::OFF, frame))
+        } else if self.tx_buffer.is_some() {
+            Err((ErrorCode::BUSY, frame))
+        } else if len > frame.len() || len > ETHERNET_MAX_FRAME_LEN {
+            Err((ErrorCode::SIZE, frame))
+        } else {
+            self.tx_len.set(len);
+            self.tx_offset.set(0);
+            self.tx_zlp_pending.set(len % BULK_PACKET_SIZE == 0);
+            self.tx_buffer.replace(frame);
+            self.controller()
+                .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::usb::descriptors::DescriptorType;
+    use hil_mock::usb::MockUsbController;
+    use kernel::hil::usb::{Client, UsbController};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    type Ecm = CdcEcm<'static, MockUsbController<'static>>;
+
+    const DATA_INTERFACE: u8 = 1;
+
+    fn setup() -> &'static Ecm {
+        let usb: &'static MockUsbController = Box::leak(Box::new(MockUsbController::new()));
+        let ecm: &'static Ecm = Box::leak(Box::new(CdcEcm::new(
+            usb,
+            64,
+            0x6668,
+            0xabce,
+            &["Tock", "Border router", "0001", "02000000000a"],
+            EthernetAddress([0x02, 0, 0, 0, 0, 0x0b]),
+            Box::leak(Box::new([0; ETHERNET_MAX_FRAME_LEN])),
+        )));
+        usb.set_client(ecm);
+        ecm.enable();
+        ecm.attach();
+        ecm.bus_reset();
+        ecm
+    }
+
+    /// Run the setup stage of a control request.
+    fn request(ecm: &'static Ecm, setup: [u8; 8]) -> hil::usb::CtrlSetupResult {
+        for (cell, &byte) in ecm.client_ctrl.ctrl_buffer.buf.iter().zip(setup.iter()) {
+            cell.set(byte);
+        }
+        ecm.ctrl_setup(0)
+    }
+
+    /// Run a control request without a data stage to completion, returning
+    /// whether the device accepted it.
+    fn control(ecm: &'static Ecm, setup: [u8; 8]) -> bool {
+        let accepted = matches!(request(ecm, setup), hil::usb::CtrlSetupResult::Ok);
+        if accepted {
+            ecm.ctrl_status_complete(0);
+        }
+        accepted
+    }
+
+    fn set_interface(ecm: &'static Ecm, interface: u8, alternate_setting: u8) -> bool {
+        control(ecm, [0x01, 11, alternate_setting, 0, interface, 0, 0, 0])
+    }
+
+    fn set_packet_filter(ecm: &'static Ecm) -> bool {
+        control(ecm, [0x21, SET_ETHERNET_PACKET_FILTER, 0x0e, 0, 0, 0, 0, 0])
+    }
+
+    /// The configuration descriptor and everything after it.
+    fn configuration(ecm: &'static Ecm) -> Vec<u8> {
+        assert!(matches!(
+            request(ecm, [0x80, 6, 0, 2, 0, 0, 0xff, 0]),
+            hil::usb::CtrlSetupResult::Ok
+        ));
+        let mut descriptors = Vec::new();
+        loop {
+            match ecm.ctrl_in(0) {
+                hil::usb::CtrlInResult::Packet(length, done) => {
+                    descriptors.extend(
+                        ecm.client_ctrl.ctrl_buffer.buf[..length]
+                            .iter()
+                            .map(|cell| cell.get()),
+                    );
+                    if done {
+                        break;
+                    }
+                }
+                _ => panic!("configuration descriptor not sent"),
+            }
+        }
+        ecm.ctrl_status_complete(0);
+        descriptors
+    }
+
+    #[test]
+    fn data_interface_alternate_settings() {
+        let ecm = setup();
+        let descriptors = configuration(ecm);
+        // Two interfaces, even though the data interface has two settings.
+        assert_eq!(descriptors[4], 2);
+
+        // (interface, alternate setting, number of endpoints)
+        let mut interfaces = Vec::new();
+        let mut offset = 0;
+        while offset < descriptors.len() {
+            if descriptors[offset + 1] == DescriptorType::Interface as u8 {
+                interfaces.push((
+                    descriptors[offset + 2],
+                    descriptors[offset + 3],
+                    descriptors[offset + 4],
+                ));
+            }
+            offset += descriptors[offset] as usize;
+        }
+        assert_eq!(
+            interfaces,
+            [(0, 0, 1), (DATA_INTERFACE, 0, 0), (DATA_INTERFACE, 1, 2)]
+        );
+    }
+
+    #[test]
+    fn set_interface_checks_the_setting() {
+        let ecm = setup();
+        assert!(set_interface(ecm, 0, 0));
+        assert!(set_interface(ecm, DATA_INTERFACE, 0));
+        assert!(set_interface(ecm, DATA_INTERFACE, 1));
+        assert!(!set_interface(ecm, 0, 1));
+        assert!(!set_interface(ecm, DATA_INTERFACE, 2));
+        assert!(!set_interface(ecm, 2, 0));
+    }
+
+    #[test]
+    fn link_needs_data_endpoints() {
+        let ecm = setup();
+        assert!(set_packet_filter(ecm));
+        // The host has not selected the data endpoints yet.
+        assert!(!ecm.link_up());
+        let frame = Box::leak(Box::new([0; 64]));
+        let frame = match ecm.transmit(frame, 64) {
+            Err((ErrorCode::OFF, frame)) => frame,
+            _ => panic!("transmitted without the data endpoints"),
+        };
+
+        assert!(set_interface(ecm, DATA_INTERFACE, 1));
+        assert!(ecm.link_up());
+        assert!(ecm.transmit(frame, 64).is_ok());
+
+        // Going back to the setting without endpoints takes the link down.
+        assert!(set_interface(ecm, DATA_INTERFACE, 0));
+        assert!(!ecm.link_up());
+        // An invalid setting leaves the link as it is.
+        assert!(set_interface(ecm, DATA_INTERFACE, 1));
+        assert!(!set_interface(ecm, DATA_INTERFACE, 2));
+        assert!(ecm.link_up());
+    }
+}
@@ -0,0 +1,359 @@
+//! Composite USB devices
+//!
+//! A composite device exposes several USB classes at once, for example a
+//! CDC-ACM console, a CTAP HID authenticator and a mass storage disk. Each
+//! class is a *function* of the device, implemented by a type that
+//! implements `UsbFunction`. `CompositeDevice` is the single
+//! `hil::usb::Client` of the USB controller. It owns the control endpoint,
+//! builds the configuration descriptor out of the functions' descriptors, and
+//! dispatches requests and endpoint events to the function they belong to.
+//!
+//! Interface and endpoint numbers are allocated to the functions in the order
+//! they are given, starting from interface 0 and endpoint 1. Functions with
+//! more than one interface are grouped with an interface association
+//! descriptor, so the device uses the "multi-interface function" device class
+//! (0xEF/0x02/0x01). The controller must support as many endpoints as the
+//! functions use in total.
+//!
+//! The strings of the composite device replace those given to the individual
+//! functions. A CDC-ECM function refers to string 4 for the host MAC address,
+//! so that string must be present when one is included.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let cdc = static_init!(CdcAcm<'static, nrf52::usbd::Usbd, VirtualMuxAlarm<..>>, CdcAcm::new(..));
+//! let ctap = static_init!(CtapHid<'static, nrf52::usbd::Usbd>, CtapHid::new(..));
+//! let functions = static_init!(
+//!     [&'static dyn UsbFunction<'static>; 2],
+//!     [cdc, ctap]
+//! );
+//! let composite = static_init!(
+//!     CompositeDevice<'static, nrf52::usbd::Usbd>,
+//!     CompositeDevice::new(
+//!         &nrf52::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x503a,
+//!         &["Tock", "Console and security key", "0001"],
+//!         functions,
+//!     )
+//! );
+//! nrf52::usbd::USBD.set_client(composite);
+//! composite.enable();
+//! composite.attach();
+//! ```
+
+use core::cell::Cell;
+
+use super::descriptors;
+use super::descriptors::FunctionDescriptors;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::ReportDescriptor;
+use super::descriptors::SetupData;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// One class implementation within a composite device.
+///
+/// Functions still implement `hil::usb::Client`, which the composite device
+/// uses for bus resets and for transfers on the function's endpoints. Control
+/// requests addressed to one of the function's interfaces are offered to the
+/// `function_ctrl_*` methods, which receive the shared control endpoint
+/// buffer.
+pub trait UsbFunction<'a>: hil::usb::Client<'a> {
+    /// Number of interfaces the function uses.
+    fn num_interfaces(&self) -> u8;
+
+    /// Number of endpoint numbers the function uses.
+    fn num_endpoints(&self) -> usize;
+
+    /// Assign the function its first interface and endpoint numbers. The
+    /// function uses consecutive numbers from these. Must be called before
+    /// the device is enabled.
+    fn assign(&self, first_interface: u8, first_endpoint: usize);
+
+    /// Call `f` with the function's descriptors, using the assigned
+    /// interface and endpoint numbers.
+    fn descriptors(&self, f: &mut dyn FnMut(&mut FunctionDescriptors));
+
+    /// HID and report descriptors the host may request from the function's
+    /// interfaces, if it is a HID function.
+    fn hid_descriptors(
+        &self,
+    ) -> (
+        Option<&'static HIDDescriptor<'static>>,
+        Option<&'static ReportDescriptor<'static>>,
+    ) {
+        (None, None)
+    }
+
+    /// Set up the function's endpoints. The control endpoint is managed by
+    /// the composite device.
+    fn enable_endpoints(&'a self);
+
+    /// Handle the setup stage of a control request addressed to one of the
+    /// function's interfaces. Returning `None` lets the composite device
+    /// handle the request (accepting class requests without data); returning
+    /// a result claims the request, and its data stage is then passed to
+    /// `function_ctrl_in`.
+    fn function_ctrl_setup(
+        &'a self,
+        setup: &SetupData,
+        ctrl_buffer: &'a [VolatileCell<u8>; 64],
+    ) -> Option<hil::usb::CtrlSetupResult>;
+
+    /// Provide data for a control IN request the function claimed.
+    fn function_ctrl_in(
+        &'a self,
+        _ctrl_buffer: &'a [VolatileCell<u8>; 64],
+    ) -> hil::usb::CtrlInResult {
+        hil::usb::CtrlInResult::Error
+    }
+
+    /// Data received for a control OUT request addressed to the function.
+    fn function_ctrl_out(&'a self, _ctrl_buffer: &'a [VolatileCell<u8>; 64], _packet_bytes: u32) {}
+
+    /// A control request addressed to the function completed.
+    fn function_ctrl_status_complete(&'a self) {}
+}
+
+pub struct CompositeDevice<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    functions: &'a [&'a dyn UsbFunction<'a>],
+
+    /// Index of the function the current control request is addressed to.
+    ctrl_function: OptionalCell<usize>,
+    /// Whether that function claimed the request.
+    ctrl_claimed: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CompositeDevice<'a, U> {
+    /// Panics if the descriptors of `functions` do not fit in a
+    /// `descriptors::DescriptorBuffer`.
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        functions: &'a [&'a dyn UsbFunction<'a>],
+    ) -> Self {
+        let mut first_interface = 0;
+        let mut first_endpoint = 1;
+        for function in functions {
+            function.assign(first_interface, first_endpoint);
+            first_interface += function.num_interfaces();
+            first_endpoint += function.num_endpoints();
+        }
+
+        let mut related_length = 0;
+        for function in functions {
+            function.descriptors(&mut |d| related_length += d.size(true));
+        }
+
+        let device_descriptor_buffer =
+            descriptors::create_device_buffer(descriptors::DeviceDescriptor {
+                vendor_id: vendor_id,
+                product_id: product_id,
+                manufacturer_string: 1,
+                product_string: 2,
+                serial_number_string: 3,
+                class: 0xef,    // Class: Miscellaneous
+                subclass: 0x02, // Common class
+                protocol: 0x01, // Interface association descriptors
+                max_packet_size_ep0: max_ctrl_packet_size,
+                ..descriptors::DeviceDescriptor::default()
+            });
+        let other_descriptor_buffer = descriptors::create_configuration_buffer(
+            descriptors::ConfigurationDescriptor {
+                ..descriptors::ConfigurationDescriptor::default()
+            },
+            first_interface,
+            related_length,
+            |buf| {
+                let mut len = 0;
+                for function in functions {
+                    function.descriptors(&mut |d| len += d.write_to(&buf[len..], true));
+                }
+                len
+            },
+        );
+
+        CompositeDevice {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // HID descriptors are set per request
+                None,
+                LANGUAGES,
+                strings,
+            ),
+            functions,
+            ctrl_function: OptionalCell::empty(),
+            ctrl_claimed: Cell::new(false),
+        }
+    }
+
+    /// Index of the function that owns `interface`.
+    fn function_for_interface(&self, interface: u8) -> Option<usize> {
+        let mut first = 0;
+        for (i, function) in self.functions.iter().enumerate() {
+            let count = function.num_interfaces();
+            if interface >= first && interface < first + count {
+                return Some(i);
+            }
+            first += count;
+        }
+        None
+    }
+
+    /// The function that owns `endpoint`.
+    fn function_for_endpoint(&self, endpoint: usize) -> Option<&'a dyn UsbFunction<'a>> {
+        let mut first = 1;
+        for function in self.functions.iter() {
+            let count = function.num_endpoints();
+            if endpoint >= first && endpoint < first + count {
+                return Some(*function);
+            }
+            first += count;
+        }
+        None
+    }
+
+    /// The function that owns the current control request.
+    fn ctrl_function(&self) -> Option<&'a dyn UsbFunction<'a>> {
+        self.ctrl_function.map(|i| self.functions[*i])
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CompositeDevice<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        for function in self.functions {
+            function.enable_endpoints();
+        }
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        for function in self.functions {
+            function.bus_reset();
+        }
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// Requests addressed to an interface are offered to the function owning
+    /// it first.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.ctrl_function.clear();
+        self.ctrl_claimed.set(false);
+
+        let ctrl_buffer = &self.client_ctrl.ctrl_buffer.buf;
+        let claimed = SetupData::get(ctrl_buffer).and_then(|setup_data| {
+            if !matches!(setup_data.request_type.recipient(), Recipient::Interface) {
+                return None;
+            }
+            let index = self.function_for_interface(setup_data.index as u8)?;
+            let function = self.functions[index];
+            self.ctrl_function.set(index);
+
+            let (hid, report) = function.hid_descriptors();
+            self.client_ctrl.set_hid_descriptors(hid, report);
+
+            function.function_ctrl_setup(&setup_data, ctrl_buffer)
+        });
+
+        match claimed {
+            Some(result) => {
+                self.ctrl_claimed.set(true);
+                result
+            }
+            None => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        if self.ctrl_claimed.get() {
+            self.ctrl_function()
+                .map_or(hil::usb::CtrlInResult::Error, |function| {
+                    function.function_ctrl_in(&self.client_ctrl.ctrl_buffer.buf)
+                })
+        } else {
+            self.client_ctrl.ctrl_in(endpoint)
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.ctrl_function().map(|function| {
+            function.function_ctrl_out(&self.client_ctrl.ctrl_buffer.buf, packet_bytes)
+        });
+
+        if self.ctrl_claimed.get() {
+            hil::usb::CtrlOutResult::Ok
+        } else {
+            self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+        }
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.ctrl_function()
+            .map(|function| function.function_ctrl_status_complete());
+        self.ctrl_function.clear();
+        self.ctrl_claimed.set(false);
+
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk/Interrupt IN transaction
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        self.function_for_endpoint(endpoint)
+            .map_or(hil::usb::InResult::Error, |function| {
+                function.packet_in(transfer_type, endpoint)
+            })
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        self.function_for_endpoint(endpoint)
+            .map_or(hil::usb::OutResult::Error, |function| {
+                function.packet_out(transfer_type, endpoint, packet_bytes)
+            })
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.function_for_endpoint(endpoint)
+            .map(|function| function.packet_transmitted(endpoint));
+    }
+}
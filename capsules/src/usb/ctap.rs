@@ -5,26 +5,31 @@
 use core::cell::Cell;
 use core::cmp;
 
+use super::composite::UsbFunction;
 use super::descriptors;
 use super::descriptors::Buffer64;
 use super::descriptors::DescriptorType;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
+use super::descriptors::FunctionDescriptors;
 use super::descriptors::HIDCountryCode;
 use super::descriptors::HIDDescriptor;
 use super::descriptors::HIDSubordinateDescriptor;
 use super::descriptors::InterfaceDescriptor;
 use super::descriptors::ReportDescriptor;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
 use kernel::hil;
 use kernel::hil::usb::TransferType;
 use kernel::ErrorCode;
 
-/// Use 1 Interrupt transfer IN/OUT endpoint
+/// Use 1 Interrupt transfer IN/OUT endpoint. This is the endpoint number when
+/// the CTAP device is used on its own.
 const ENDPOINT_NUM: usize = 1;
 
 const OUT_BUFFER: usize = 0;
@@ -85,6 +90,11 @@ pub struct CtapHid<'a, U: 'a> {
     /// 64 byte buffers for each endpoint.
     buffers: [Buffer64; N_ENDPOINTS],
 
+    /// Interface and endpoint numbers the function uses. These are
+    /// reassigned when the CTAP device is part of a composite device.
+    interface: Cell<u8>,
+    endpoint: Cell<usize>,
+
     client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; 64]>>,
 
     /// A buffer to hold the data we want to send
@@ -108,55 +118,25 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
         product_id: u16,
         strings: &'static [&'static str; 3],
     ) -> Self {
-        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
-            interface_number: 0,
-            interface_class: 0x03,    // HID
-            interface_subclass: 0x00, // No subcall
-            interface_protocol: 0x00, // No protocol
-            ..InterfaceDescriptor::default()
-        }];
-
-        let endpoints: &[&[EndpointDescriptor]] = &[&[
-            EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(
-                    ENDPOINT_NUM,
-                    TransferDirection::DeviceToHost,
-                ),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 64,
-                interval: 5,
-            },
-            EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(
-                    ENDPOINT_NUM,
-                    TransferDirection::HostToDevice,
-                ),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 64,
-                interval: 5,
-            },
-        ]];
-
         let (device_descriptor_buffer, other_descriptor_buffer) =
-            descriptors::create_descriptor_buffers(
-                descriptors::DeviceDescriptor {
-                    vendor_id: vendor_id,
-                    product_id: product_id,
-                    manufacturer_string: 1,
-                    product_string: 2,
-                    serial_number_string: 3,
-                    class: 0x03, // Class: HID
-                    max_packet_size_ep0: MAX_CTRL_PACKET_SIZE,
-                    ..descriptors::DeviceDescriptor::default()
-                },
-                descriptors::ConfigurationDescriptor {
-                    ..descriptors::ConfigurationDescriptor::default()
-                },
-                interfaces,
-                endpoints,
-                Some(&HID_DESCRIPTOR),
-                None,
-            );
+            Self::with_descriptors(0, ENDPOINT_NUM, |function| {
+                descriptors::create_function_descriptor_buffers(
+                    descriptors::DeviceDescriptor {
+                        vendor_id: vendor_id,
+                        product_id: product_id,
+                        manufacturer_string: 1,
+                        product_string: 2,
+                        serial_number_string: 3,
+                        class: 0x03, // Class: HID
+                        max_packet_size_ep0: MAX_CTRL_PACKET_SIZE,
+                        ..descriptors::DeviceDescriptor::default()
+                    },
+                    descriptors::ConfigurationDescriptor {
+                        ..descriptors::ConfigurationDescriptor::default()
+                    },
+                    function,
+                )
+            });
 
         CtapHid {
             client_ctrl: ClientCtrl::new(
@@ -169,6 +149,8 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
                 strings,
             ),
             buffers: [Buffer64::default(), Buffer64::default()],
+            interface: Cell::new(0),
+            endpoint: Cell::new(ENDPOINT_NUM),
             client: OptionalCell::empty(),
             send_buffer: TakeCell::empty(),
             recv_buffer: TakeCell::empty(),
@@ -178,6 +160,45 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
         }
     }
 
+    /// Call `f` with the descriptors of the CTAP HID function, using the
+    /// given interface and endpoint numbers.
+    fn with_descriptors<R, F: FnOnce(&mut FunctionDescriptors) -> R>(
+        interface: u8,
+        endpoint: usize,
+        f: F,
+    ) -> R {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: interface,
+            interface_class: 0x03,    // HID
+            interface_subclass: 0x00, // No subcall
+            interface_protocol: 0x00, // No protocol
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(endpoint, TransferDirection::DeviceToHost),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 64,
+                interval: 5,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(endpoint, TransferDirection::HostToDevice),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 64,
+                interval: 5,
+            },
+        ]];
+
+        f(&mut FunctionDescriptors {
+            interfaces,
+            endpoints,
+            hid: Some(&HID_DESCRIPTOR),
+            cdc: None,
+            cdc_ethernet: None,
+        })
+    }
+
     #[inline]
     fn controller(&self) -> &'a U {
         self.client_ctrl.controller()
@@ -202,7 +223,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for
         let len = send.len();
 
         self.send_buffer.replace(send);
-        self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        self.controller().endpoint_resume_in(self.endpoint.get());
 
         Ok(len)
     }
@@ -233,7 +254,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for
             }
         } else {
             // If we have nothing to process, accept more data
-            self.controller().endpoint_resume_out(ENDPOINT_NUM);
+            self.controller().endpoint_resume_out(self.endpoint.get());
         }
 
         Ok(())
@@ -253,13 +274,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         // Set up the default control endpoint
         self.client_ctrl.enable();
 
-        // Setup buffers for IN and OUT data transfer.
-        self.controller()
-            .endpoint_set_out_buffer(ENDPOINT_NUM, &self.buffers[OUT_BUFFER].buf);
-        self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffers[IN_BUFFER].buf);
-        self.controller()
-            .endpoint_in_out_enable(TransferType::Interrupt, ENDPOINT_NUM);
+        self.enable_endpoints();
     }
 
     fn attach(&'a self) {
@@ -289,9 +304,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
 
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&'a self, endpoint: usize) {
-        if self.send_buffer.is_some() {
-            self.controller().endpoint_resume_in(ENDPOINT_NUM);
-        }
+        self.function_ctrl_status_complete();
 
         self.client_ctrl.ctrl_status_complete(endpoint)
     }
@@ -404,3 +417,57 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         });
     }
 }
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for CtapHid<'a, U> {
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn num_endpoints(&self) -> usize {
+        1
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.interface.set(first_interface);
+        self.endpoint.set(first_endpoint);
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&mut FunctionDescriptors)) {
+        Self::with_descriptors(self.interface.get(), self.endpoint.get(), f)
+    }
+
+    fn hid_descriptors(
+        &self,
+    ) -> (
+        Option<&'static HIDDescriptor<'static>>,
+        Option<&'static ReportDescriptor<'static>>,
+    ) {
+        (Some(&HID_DESCRIPTOR), Some(&REPORT))
+    }
+
+    fn enable_endpoints(&'a self) {
+        // Setup buffers for IN and OUT data transfer.
+        let endpoint = self.endpoint.get();
+        self.controller()
+            .endpoint_set_out_buffer(endpoint, &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_set_in_buffer(endpoint, &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_out_enable(TransferType::Interrupt, endpoint);
+    }
+
+    fn function_ctrl_setup(
+        &'a self,
+        _setup_data: &SetupData,
+        _ctrl_buffer: &'a [VolatileCell<u8>; 64],
+    ) -> Option<hil::usb::CtrlSetupResult> {
+        // HID class requests are handled by the control endpoint helper.
+        None
+    }
+
+    fn function_ctrl_status_complete(&'a self) {
+        if self.send_buffer.is_some() {
+            self.controller().endpoint_resume_in(self.endpoint.get());
+        }
+    }
+}
@@ -3,7 +3,6 @@
 //! Mostly data types for USB descriptors.
 
 use core::cell::Cell;
-use core::cmp::min;
 use core::convert::From;
 use core::fmt;
 use kernel::common::cells::VolatileCell;
@@ -97,7 +96,10 @@ impl SetupData {
                 10 => Some(StandardRequest::GetInterface {
                     interface: self.index,
                 }),
-                11 => Some(StandardRequest::SetInterface),
+                11 => Some(StandardRequest::SetInterface {
+                    alternate_setting: (self.value & 0xff) as u8,
+                    interface: self.index,
+                }),
                 12 => Some(StandardRequest::SynchFrame),
                 _ => None,
             },
@@ -142,7 +144,10 @@ pub enum StandardRequest {
     GetInterface {
         interface: u16,
     },
-    SetInterface,
+    SetInterface {
+        alternate_setting: u8,
+        interface: u16,
+    },
     SynchFrame,
 }
 
@@ -156,6 +161,7 @@ pub enum DescriptorType {
     DeviceQualifier,
     OtherSpeedConfiguration,
     InterfacePower,
+    InterfaceAssociation = 0x0b,
     HID = 0x21,
     Report = 0x22,
     CdcInterface = 0x24,
@@ -392,38 +398,182 @@ impl DeviceBuffer {
     }
 }
 
+/// Size of `DescriptorBuffer`, which bounds the total length of the
+/// configuration descriptor and everything that follows it.
+pub const DESCRIPTOR_BUFFER_LEN: usize = 256;
+
 /// Buffer for holding the configuration, interface(s), and endpoint(s)
 /// descriptors. Also includes class-specific functional descriptors.
 pub struct DescriptorBuffer {
-    pub buf: [Cell<u8>; 128],
+    pub buf: [Cell<u8>; DESCRIPTOR_BUFFER_LEN],
     pub len: usize,
 }
 
 impl DescriptorBuffer {
+    pub fn new() -> Self {
+        // Cell is not Copy, so the array is built from a constant.
+        const EMPTY: Cell<u8> = Cell::new(0);
+        DescriptorBuffer {
+            buf: [EMPTY; DESCRIPTOR_BUFFER_LEN],
+            len: 0,
+        }
+    }
+
     pub fn write_to(&self, buf: &[Cell<u8>]) -> usize {
         for i in 0..self.len {
             buf[i].set(self.buf[i].get());
         }
         self.len
     }
+
+    /// Whether the configuration has an interface descriptor for `interface`
+    /// with the given alternate setting.
+    pub fn has_interface(&self, interface: u8, alternate_setting: u8) -> bool {
+        let mut offset = 0;
+        while offset + 4 <= self.len {
+            let length = self.buf[offset].get() as usize;
+            if length == 0 {
+                break;
+            }
+            if self.buf[offset + 1].get() == DescriptorType::Interface as u8
+                && self.buf[offset + 2].get() == interface
+                && self.buf[offset + 3].get() == alternate_setting
+            {
+                return true;
+            }
+            offset += length;
+        }
+        false
+    }
 }
 
-/// Transform descriptor structs into descriptor buffers that can be
-/// passed into the control endpoint handler. Each endpoint descriptor list
-/// corresponds to the matching index in the interface descriptor list. For
-/// example, if the interface descriptor list contains `[ID1, ID2, ID3]`,
-/// and the endpoint descriptors list is `[[ED1, ED2], [ED3, ED4, ED5],
-/// [ED6]]`, then the third interface descriptor (`ID3`) has one
-/// corresponding endpoint descriptor (`ED6`).
-pub fn create_descriptor_buffers(
-    device_descriptor: DeviceDescriptor,
-    mut configuration_descriptor: ConfigurationDescriptor,
-    interface_descriptor: &mut [InterfaceDescriptor],
-    endpoint_descriptors: &[&[EndpointDescriptor]],
-    hid_descriptor: Option<&HIDDescriptor>,
-    cdc_descriptor: Option<&[CdcInterfaceDescriptor]>,
-) -> (DeviceBuffer, DescriptorBuffer) {
-    // Create device descriptor buffer and fill.
+/// The descriptors for one function of a device: a group of interfaces that
+/// together implement one class (for example the communications and data
+/// interfaces of CDC-ACM), with their endpoints and any class-specific
+/// descriptors.
+///
+/// Each endpoint descriptor list corresponds to the matching index in the
+/// interface descriptor list. For example, if the interface descriptor list
+/// contains `[ID1, ID2, ID3]`, and the endpoint descriptors list is `[[ED1,
+/// ED2], [ED3, ED4, ED5], [ED6]]`, then the third interface descriptor
+/// (`ID3`) has one corresponding endpoint descriptor (`ED6`). Class-specific
+/// descriptors are placed after the first interface descriptor.
+///
+/// Alternate settings of an interface are listed as separate interface
+/// descriptors with the same interface number, after its default setting.
+pub struct FunctionDescriptors<'a> {
+    pub interfaces: &'a mut [InterfaceDescriptor],
+    pub endpoints: &'a [&'a [EndpointDescriptor]],
+    pub hid: Option<&'a HIDDescriptor<'a>>,
+    pub cdc: Option<&'a [CdcInterfaceDescriptor]>,
+    pub cdc_ethernet: Option<&'a CdcEthernetNetworkingDescriptor>,
+}
+
+impl<'a> FunctionDescriptors<'a> {
+    /// Number of interfaces of the function, not counting alternate settings.
+    pub fn num_interfaces(&self) -> u8 {
+        self.interfaces
+            .iter()
+            .filter(|d| d.alternate_setting == 0)
+            .count() as u8
+    }
+
+    /// The interface association descriptor grouping this function's
+    /// interfaces, if it has more than one.
+    fn association(&self) -> Option<InterfaceAssociationDescriptor> {
+        let num_interfaces = self.num_interfaces();
+        if num_interfaces > 1 {
+            let first = &self.interfaces[0];
+            Some(InterfaceAssociationDescriptor {
+                first_interface: first.interface_number,
+                interface_count: num_interfaces,
+                function_class: first.interface_class,
+                function_subclass: first.interface_subclass,
+                function_protocol: first.interface_protocol,
+                string_index: first.string_index,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Total length of the function's descriptors. `with_association`
+    /// selects whether an interface association descriptor is included, as
+    /// is needed when the function is part of a composite device.
+    pub fn size(&self, with_association: bool) -> usize {
+        let association = if with_association {
+            self.association().map_or(0, |d| d.size())
+        } else {
+            0
+        };
+        association
+            + self.interfaces.iter().map(|d| d.size()).sum::<usize>()
+            + self
+                .endpoints
+                .iter()
+                .map(|descs| descs.iter().map(|d| d.size()).sum::<usize>())
+                .sum::<usize>()
+            + self.hid.map_or(0, |d| d.size())
+            + self
+                .cdc
+                .map_or(0, |ds| ds.iter().map(|d| d.size()).sum::<usize>())
+            + self.cdc_ethernet.map_or(0, |d| d.size())
+    }
+
+    /// Write the function's descriptors to `buf`, returning the number of
+    /// bytes written.
+    pub fn write_to(&mut self, buf: &[Cell<u8>], with_association: bool) -> usize {
+        // Set the number of endpoints for each interface descriptor.
+        for (i, d) in self.interfaces.iter_mut().enumerate() {
+            d.num_endpoints = self.endpoints[i].len() as u8;
+        }
+
+        let mut len = 0;
+        if with_association {
+            if let Some(da) = self.association() {
+                len += da.write_to(&buf[len..]);
+            }
+        }
+
+        // Fill in the interface descriptor and its associated endpoints.
+        for (i, d) in self.interfaces.iter().enumerate() {
+            // Add the interface descriptor.
+            len += d.write_to(&buf[len..]);
+
+            // If there is a HID descriptor, we include
+            // it with the first interface descriptor.
+            if i == 0 {
+                // HID descriptor, if any.
+                if let Some(dh) = self.hid {
+                    len += dh.write_to(&buf[len..]);
+                }
+            }
+
+            // If there is a CDC descriptor array, we include
+            // it with the first interface descriptor.
+            if i == 0 {
+                // CDC descriptor, if any.
+                if let Some(dcdc) = self.cdc {
+                    for dcs in dcdc {
+                        len += dcs.write_to(&buf[len..]);
+                    }
+                }
+                if let Some(dce) = self.cdc_ethernet {
+                    len += dce.write_to(&buf[len..]);
+                }
+            }
+
+            // Endpoints for each interface.
+            for de in self.endpoints[i] {
+                len += de.write_to(&buf[len..]);
+            }
+        }
+        len
+    }
+}
+
+/// Create the device descriptor buffer for `device_descriptor`.
+pub fn create_device_buffer(device_descriptor: DeviceDescriptor) -> DeviceBuffer {
     // Cell doesn't implement Copy, so here we are.
     let mut dev_buf = DeviceBuffer {
         buf: [
@@ -450,106 +600,84 @@ pub fn create_descriptor_buffers(
         len: 0,
     };
     dev_buf.len = device_descriptor.write_to(&dev_buf.buf);
+    dev_buf
+}
 
-    // Create other descriptors buffer.
-    // For the moment, the Default trait is not implemented for arrays
-    // of length > 32, and the Cell type is not Copy, so we have to
-    // initialize each element manually.
-    let mut other_buf = DescriptorBuffer {
-        #[rustfmt::skip]
-        buf: [
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(),
-        ],
-        len: 0,
-    };
+/// Transform descriptor structs into descriptor buffers that can be
+/// passed into the control endpoint handler. See `FunctionDescriptors` for
+/// how interface and endpoint descriptors correspond.
+pub fn create_descriptor_buffers(
+    device_descriptor: DeviceDescriptor,
+    configuration_descriptor: ConfigurationDescriptor,
+    interface_descriptor: &mut [InterfaceDescriptor],
+    endpoint_descriptors: &[&[EndpointDescriptor]],
+    hid_descriptor: Option<&HIDDescriptor>,
+    cdc_descriptor: Option<&[CdcInterfaceDescriptor]>,
+    cdc_ethernet_descriptor: Option<&CdcEthernetNetworkingDescriptor>,
+) -> (DeviceBuffer, DescriptorBuffer) {
+    create_function_descriptor_buffers(
+        device_descriptor,
+        configuration_descriptor,
+        &mut FunctionDescriptors {
+            interfaces: interface_descriptor,
+            endpoints: endpoint_descriptors,
+            hid: hid_descriptor,
+            cdc: cdc_descriptor,
+            cdc_ethernet: cdc_ethernet_descriptor,
+        },
+    )
+}
+
+/// Create the descriptor buffers for a device made of a single function.
+pub fn create_function_descriptor_buffers(
+    device_descriptor: DeviceDescriptor,
+    configuration_descriptor: ConfigurationDescriptor,
+    function: &mut FunctionDescriptors,
+) -> (DeviceBuffer, DescriptorBuffer) {
+    let num_interfaces = function.num_interfaces();
+    let len = function.size(false);
+    (
+        create_device_buffer(device_descriptor),
+        create_configuration_buffer(configuration_descriptor, num_interfaces, len, |buf| {
+            function.write_to(buf, false)
+        }),
+    )
+}
 
-    // Setup certain descriptor fields since now we know the tree of
-    // descriptors.
+/// Create the configuration descriptor buffer for a configuration with
+/// `num_interfaces` interfaces, whose descriptors (`related_length` bytes in
+/// total) are written by `write_related`.
+///
+/// Panics if the configuration does not fit in a `DescriptorBuffer`, as the
+/// host would otherwise read a truncated configuration.
+pub fn create_configuration_buffer<F: FnOnce(&[Cell<u8>]) -> usize>(
+    mut configuration_descriptor: ConfigurationDescriptor,
+    num_interfaces: u8,
+    related_length: usize,
+    write_related: F,
+) -> DescriptorBuffer {
+    let mut other_buf = DescriptorBuffer::new();
 
     // Configuration Descriptor. We assume there is only one configuration
     // descriptor, since this is very common for most USB devices.
-    configuration_descriptor.num_interfaces = interface_descriptor.len() as u8;
-
-    // Calculate the length of all dependent descriptors.
-    // TODO should we be erroring here if len > 128? Otherwise we'll probably
-    // buffer overrun and panic.
-    configuration_descriptor.related_descriptor_length =
-        interface_descriptor.iter().map(|d| d.size()).sum::<usize>()
-            + endpoint_descriptors
-                .iter()
-                .map(|descs| descs.iter().map(|d| d.size()).sum::<usize>())
-                .sum::<usize>()
-            + hid_descriptor.map_or(0, |d| d.size())
-            + cdc_descriptor.map_or(0, |ds| ds.iter().map(|d| d.size()).sum::<usize>());
-
-    // Set the number of endpoints for each interface descriptor.
-    for (i, d) in interface_descriptor.iter_mut().enumerate() {
-        d.num_endpoints = endpoint_descriptors[i].len() as u8;
-    }
+    configuration_descriptor.num_interfaces = num_interfaces;
+    configuration_descriptor.related_descriptor_length = related_length;
+
+    // `wTotalLength` covers every descriptor of the configuration, so all of
+    // them must fit in the buffer.
+    let total_length = configuration_descriptor.size() + related_length;
+    assert!(
+        total_length <= other_buf.buf.len(),
+        "USB configuration of {} bytes exceeds DESCRIPTOR_BUFFER_LEN",
+        total_length
+    );
 
     // Fill a single configuration into the buffer and track length.
     let mut len = 0;
     len += configuration_descriptor.write_to(&other_buf.buf[len..]);
-
-    // Fill in the interface descriptor and its associated endpoints.
-    for (i, d) in interface_descriptor.iter().enumerate() {
-        // Add the interface descriptor.
-        len += d.write_to(&other_buf.buf[len..]);
-
-        // If there is a HID descriptor, we include
-        // it with the first interface descriptor.
-        if i == 0 {
-            // HID descriptor, if any.
-            if let Some(dh) = hid_descriptor {
-                len += dh.write_to(&other_buf.buf[len..]);
-            }
-        }
-
-        // If there is a CDC descriptor array, we include
-        // it with the first interface descriptor.
-        if i == 0 {
-            // CDC descriptor, if any.
-            if let Some(dcdc) = cdc_descriptor {
-                for dcs in dcdc {
-                    len += dcs.write_to(&other_buf.buf[len..]);
-                }
-            }
-        }
-
-        // Endpoints for each interface.
-        for de in endpoint_descriptors[i] {
-            len += de.write_to(&other_buf.buf[len..]);
-        }
-    }
-    other_buf.len = min(len, other_buf.buf.len());
-
-    // return the two buffers
-    (dev_buf, other_buf)
+    len += write_related(&other_buf.buf[len..]);
+    other_buf.len = len;
+    other_buf
 }
 
 pub struct ConfigurationDescriptor {
@@ -859,6 +987,67 @@ impl Descriptor for CdcInterfaceDescriptor {
     }
 }
 
+/// Groups the interfaces of one function of a composite device, so that the
+/// host binds them to a single driver.
+pub struct InterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_index: u8,
+}
+
+impl Descriptor for InterfaceAssociationDescriptor {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(8);
+        buf[1].set(DescriptorType::InterfaceAssociation as u8);
+        buf[2].set(self.first_interface);
+        buf[3].set(self.interface_count);
+        buf[4].set(self.function_class);
+        buf[5].set(self.function_subclass);
+        buf[6].set(self.function_protocol);
+        buf[7].set(self.string_index);
+        8
+    }
+}
+
+/// The CDC Ethernet Networking functional descriptor, which is longer than
+/// the other CDC functional descriptors.
+pub struct CdcEthernetNetworkingDescriptor {
+    /// Index of the string descriptor holding the host's MAC address as 12
+    /// hexadecimal digits.
+    pub mac_address_string: u8,
+    pub ethernet_statistics: u32,
+    pub max_segment_size: u16,
+    pub num_multicast_filters: u16,
+    pub num_power_filters: u8,
+}
+
+impl Descriptor for CdcEthernetNetworkingDescriptor {
+    fn size(&self) -> usize {
+        13
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(13);
+        buf[1].set(DescriptorType::CdcInterface as u8);
+        buf[2].set(CdcInterfaceDescriptorSubType::EthernetNetworking as u8);
+        buf[3].set(self.mac_address_string);
+        for (i, byte) in self.ethernet_statistics.to_le_bytes().iter().enumerate() {
+            buf[4 + i].set(*byte);
+        }
+        put_u16(&buf[8..10], self.max_segment_size);
+        put_u16(&buf[10..12], self.num_multicast_filters);
+        buf[12].set(self.num_power_filters);
+        13
+    }
+}
+
 /// The data structure sent in a CDC-ACM Set Line Coding message.
 #[derive(Debug, Copy, Clone)]
 pub struct CdcAcmSetLineCodingData {
@@ -948,3 +1137,85 @@ fn put_u16<'a>(buf: &'a [Cell<u8>], n: u16) {
     buf[0].set((n & 0xff) as u8);
     buf[1].set((n >> 8) as u8);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configuration(related_length: usize) -> DescriptorBuffer {
+        create_configuration_buffer(
+            ConfigurationDescriptor::default(),
+            1,
+            related_length,
+            |buf| {
+                for b in buf[..related_length].iter() {
+                    b.set(0xa5);
+                }
+                related_length
+            },
+        )
+    }
+
+    #[test]
+    fn configuration_total_length() {
+        let buffer = configuration(DESCRIPTOR_BUFFER_LEN - 9);
+        assert_eq!(buffer.len, DESCRIPTOR_BUFFER_LEN);
+        assert_eq!(
+            buffer.buf[2].get() as usize | (buffer.buf[3].get() as usize) << 8,
+            buffer.len
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds DESCRIPTOR_BUFFER_LEN")]
+    fn configuration_too_large() {
+        configuration(DESCRIPTOR_BUFFER_LEN - 8);
+    }
+
+    #[test]
+    fn alternate_settings() {
+        let endpoint = EndpointDescriptor {
+            endpoint_address: EndpointAddress::new(1, TransferDirection::DeviceToHost),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        let mut function = FunctionDescriptors {
+            interfaces: &mut [
+                InterfaceDescriptor {
+                    interface_number: 0,
+                    ..InterfaceDescriptor::default()
+                },
+                InterfaceDescriptor {
+                    interface_number: 1,
+                    ..InterfaceDescriptor::default()
+                },
+                InterfaceDescriptor {
+                    interface_number: 1,
+                    alternate_setting: 1,
+                    ..InterfaceDescriptor::default()
+                },
+            ],
+            endpoints: &[&[], &[], &[endpoint]],
+            hid: None,
+            cdc: None,
+            cdc_ethernet: None,
+        };
+        let (_, buffer) = create_function_descriptor_buffers(
+            DeviceDescriptor::default(),
+            ConfigurationDescriptor::default(),
+            &mut function,
+        );
+
+        // The configuration counts interfaces, not alternate settings.
+        assert_eq!(buffer.buf[4].get(), 2);
+        assert!(buffer.has_interface(0, 0));
+        assert!(buffer.has_interface(1, 0));
+        assert!(buffer.has_interface(1, 1));
+        assert!(!buffer.has_interface(0, 1));
+        assert!(!buffer.has_interface(2, 0));
+        // The endpoint belongs to the alternate setting.
+        assert_eq!(buffer.buf[9 + 9 + 4].get(), 0);
+        assert_eq!(buffer.buf[9 + 9 + 9 + 4].get(), 1);
+    }
+}
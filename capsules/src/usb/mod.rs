@@ -1,6 +1,9 @@
 pub mod cdc;
+pub mod cdc_ecm;
+pub mod composite;
 pub mod ctap;
 pub mod descriptors;
+pub mod msc;
 pub mod usb_user;
 pub mod usbc_client;
 pub mod usbc_client_ctrl;
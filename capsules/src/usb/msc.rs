@@ -0,0 +1,1095 @@
+//! Mass Storage Class device for USB
+//!
+//! This capsule presents a region of nonvolatile storage to a USB host as a
+//! disk, using the Bulk-Only Transport (BOT) and the SCSI transparent command
+//! set. Any storage that implements `hil::nonvolatile_storage` can be exposed,
+//! for example a flash region through `NonvolatileToPages`.
+//!
+//! The disk is made of 512 byte blocks starting at `start_address` in the
+//! underlying storage. The number of blocks can be changed at runtime with
+//! `set_num_blocks()`, which allows media that is only sized after it
+//! initializes (like an SD card) to be presented as "no medium" until then.
+//! If the disk is read-only, the host sees it as write-protected.
+//!
+//! Supported SCSI commands are TEST UNIT READY, REQUEST SENSE, INQUIRY,
+//! MODE SENSE(6), START STOP UNIT, PREVENT ALLOW MEDIUM REMOVAL, READ FORMAT
+//! CAPACITIES, READ CAPACITY(10), READ(10), WRITE(10) and VERIFY(10).
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let msc = static_init!(
+//!     capsules::usb::msc::MassStorage<'static, nrf52840::usbd::Usbd, NonvolatileToPages>,
+//!     capsules::usb::msc::MassStorage::new(
+//!         &nrf52840_peripherals.usbd,
+//!         capsules::usb::msc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x503a,
+//!         strings,
+//!         nv_to_page,
+//!         &mut capsules::usb::msc::BUFFER,
+//!         0x60000,     // Start address of the disk in storage
+//!         0x20000 / 512, // Number of blocks
+//!         true,        // Read-only
+//!     )
+//! );
+//! nv_to_page.set_client(msc);
+//! nrf52840_peripherals.usbd.set_client(msc);
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+
+use super::composite::UsbFunction;
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::FunctionDescriptors;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::usb::TransferType;
+
+/// Endpoint for transferring data from us to the host, relative to the first
+/// endpoint of the function.
+const ENDPOINT_IN: usize = 0;
+/// Endpoint for transferring data from the host to us.
+const ENDPOINT_OUT: usize = 1;
+/// First endpoint number used when the mass storage device is used on its own.
+const FIRST_ENDPOINT_NUM: usize = 1;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+/// Platform-specific packet length for the `SAM4L` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_SAM4L: u8 = 8;
+/// Platform-specific packet length for the `nRF52` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_NRF52840: u8 = 64;
+/// Platform-specific packet length for the `earlgrey` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
+
+const N_ENDPOINTS: usize = 2;
+
+/// Size of a disk block. The buffer passed to `MassStorage::new()` must hold
+/// at least one block.
+pub const BLOCK_SIZE: usize = 512;
+
+pub static mut BUFFER: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+/// Command Block Wrapper signature ("USBC").
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LENGTH: usize = 31;
+/// Command Status Wrapper signature ("USBS").
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_LENGTH: usize = 13;
+
+/// Class-specific control requests.
+const BOT_GET_MAX_LUN: u8 = 0xfe;
+const BOT_RESET: u8 = 0xff;
+
+/// CSW status values.
+const STATUS_PASSED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+const STATUS_PHASE_ERROR: u8 = 2;
+
+/// SCSI operation codes.
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_MODE_SENSE_6: u8 = 0x1a;
+const SCSI_START_STOP_UNIT: u8 = 0x1b;
+const SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const SCSI_READ_FORMAT_CAPACITIES: u8 = 0x23;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2a;
+const SCSI_VERIFY_10: u8 = 0x2f;
+
+/// SCSI sense data as (sense key, additional sense code).
+#[derive(Clone, Copy, PartialEq)]
+enum Sense {
+    NoSense = 0x0000,
+    MediumNotPresent = 0x023a,
+    ReadError = 0x0311,
+    WriteError = 0x030c,
+    InvalidCommand = 0x0520,
+    LbaOutOfRange = 0x0521,
+    InvalidField = 0x0524,
+    WriteProtected = 0x0727,
+}
+
+/// Where we are in the Bulk-Only Transport protocol.
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    /// Waiting for the host to send a Command Block Wrapper.
+    Command,
+    /// Sending data to the host. Once the buffer is exhausted, any further
+    /// data the host expects is padded with zeros.
+    DataIn,
+    /// Receiving data from the host. Data that is not part of a block write
+    /// is discarded.
+    DataOut,
+    /// Waiting for the storage to finish reading or writing a block.
+    Storage,
+    /// Sending the Command Status Wrapper.
+    Status,
+    /// The host sent an invalid Command Block Wrapper. Both bulk endpoints
+    /// stall until the host resets the device (BOT section 6.6.1).
+    Stalled,
+}
+
+/// The block operation in progress, if any.
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    None,
+    Read,
+    Write,
+}
+
+/// Implementation of the Mass Storage Class (MSC) Bulk-Only Transport over
+/// USB.
+pub struct MassStorage<'a, U: 'a, S: NonvolatileStorage<'a>> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    /// Interface and endpoint numbers the function starts at. These are
+    /// reassigned when the mass storage device is part of a composite device.
+    interface: Cell<u8>,
+    first_endpoint: Cell<usize>,
+
+    /// The storage holding the disk contents.
+    storage: &'a S,
+    /// Holds a block of the disk, or the response to a SCSI command.
+    buffer: TakeCell<'a, [u8]>,
+    /// Address of the first block in `storage`.
+    start_address: usize,
+    num_blocks: Cell<u32>,
+    read_only: bool,
+
+    phase: Cell<Phase>,
+    /// The host is waiting for a reply to GET MAX LUN.
+    ctrl_max_lun: Cell<bool>,
+
+    /// Tag of the current command, echoed in the status.
+    tag: Cell<u32>,
+    /// How many bytes the host expects to transfer in the data phase.
+    expected_length: Cell<usize>,
+    /// Whether the data phase of the current command goes to the host.
+    direction_in: Cell<bool>,
+    /// How many bytes have been transferred in the data phase, including
+    /// padding and discarded data.
+    transferred: Cell<usize>,
+    /// How many of the transferred bytes were meaningful, used to compute the
+    /// residue reported in the status.
+    processed: Cell<usize>,
+    status: Cell<u8>,
+    sense: Cell<Sense>,
+
+    /// Where we are in `buffer` while sending or receiving data.
+    buffer_offset: Cell<usize>,
+    buffer_length: Cell<usize>,
+
+    operation: Cell<Operation>,
+    /// Next block to read or write, and how many blocks are left.
+    lba: Cell<u32>,
+    blocks_remaining: Cell<u32>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: NonvolatileStorage<'a>> MassStorage<'a, U, S> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'a S,
+        buffer: &'a mut [u8],
+        start_address: usize,
+        num_blocks: u32,
+        read_only: bool,
+    ) -> Self {
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            Self::with_descriptors(0, FIRST_ENDPOINT_NUM, |function| {
+                descriptors::create_function_descriptor_buffers(
+                    descriptors::DeviceDescriptor {
+                        vendor_id: vendor_id,
+                        product_id: product_id,
+                        manufacturer_string: 1,
+                        product_string: 2,
+                        serial_number_string: 3,
+                        class: 0x00, // Class is defined by the interface
+                        max_packet_size_ep0: max_ctrl_packet_size,
+                        ..descriptors::DeviceDescriptor::default()
+                    },
+                    descriptors::ConfigurationDescriptor {
+                        ..descriptors::ConfigurationDescriptor::default()
+                    },
+                    function,
+                )
+            });
+
+        MassStorage {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            interface: Cell::new(0),
+            first_endpoint: Cell::new(FIRST_ENDPOINT_NUM),
+            storage: storage,
+            buffer: TakeCell::new(buffer),
+            start_address: start_address,
+            num_blocks: Cell::new(num_blocks),
+            read_only: read_only,
+            phase: Cell::new(Phase::Command),
+            ctrl_max_lun: Cell::new(false),
+            tag: Cell::new(0),
+            expected_length: Cell::new(0),
+            direction_in: Cell::new(false),
+            transferred: Cell::new(0),
+            processed: Cell::new(0),
+            status: Cell::new(STATUS_PASSED),
+            sense: Cell::new(Sense::NoSense),
+            buffer_offset: Cell::new(0),
+            buffer_length: Cell::new(0),
+            operation: Cell::new(Operation::None),
+            lba: Cell::new(0),
+            blocks_remaining: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    /// Call `f` with the descriptors of the mass storage function, with
+    /// interface and endpoint numbers starting at the given ones.
+    fn with_descriptors<R, F: FnOnce(&mut FunctionDescriptors) -> R>(
+        interface: u8,
+        first_endpoint: usize,
+        f: F,
+    ) -> R {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: interface,
+            interface_class: 0x08,    // Mass storage
+            interface_subclass: 0x06, // SCSI transparent command set
+            interface_protocol: 0x50, // Bulk-only transport
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    first_endpoint + ENDPOINT_IN,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    first_endpoint + ENDPOINT_OUT,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+        ]];
+
+        f(&mut FunctionDescriptors {
+            interfaces,
+            endpoints,
+            hid: None,
+            cdc: None,
+            cdc_ethernet: None,
+        })
+    }
+
+    /// Endpoint number of the endpoint at `offset` within the function.
+    #[inline]
+    fn endpoint(&self, offset: usize) -> usize {
+        self.first_endpoint.get() + offset
+    }
+
+    #[inline]
+    fn buffer(&'a self, endpoint: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[endpoint - self.first_endpoint.get()].buf
+    }
+
+    /// Change the size of the disk. A size of zero reports that no medium is
+    /// present.
+    pub fn set_num_blocks(&self, num_blocks: u32) {
+        self.num_blocks.set(num_blocks);
+    }
+
+    /// Forget any command in progress and wait for the next command.
+    fn reset(&self) {
+        self.phase.set(Phase::Command);
+        self.operation.set(Operation::None);
+        self.buffer_offset.set(0);
+        self.buffer_length.set(0);
+    }
+
+    /// Parse a Command Block Wrapper and start executing the command in it.
+    fn command(&self, packet: &[VolatileCell<u8>], packet_bytes: usize) {
+        let mut cbw = [0; CBW_LENGTH];
+        for (byte, cell) in cbw.iter_mut().zip(packet.iter()) {
+            *byte = cell.get();
+        }
+        let word = |index: usize| u32::from_le_bytes(cbw[index..index + 4].try_into().unwrap());
+
+        if packet_bytes != CBW_LENGTH || word(0) != CBW_SIGNATURE {
+            // Not a valid CBW. The OUT endpoint stalls when we return, and the
+            // IN endpoint on the next IN token.
+            self.phase.set(Phase::Stalled);
+            self.controller()
+                .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+            return;
+        }
+
+        self.tag.set(word(4));
+        self.expected_length.set(word(8) as usize);
+        self.direction_in.set(cbw[12] & 0x80 != 0);
+        self.transferred.set(0);
+        self.processed.set(0);
+        self.status.set(STATUS_PASSED);
+        self.operation.set(Operation::None);
+        self.buffer_offset.set(0);
+        self.buffer_length.set(0);
+
+        let cb_length = cmp::min(cbw[14] as usize, 16);
+        self.execute(&cbw[15..15 + cb_length]);
+    }
+
+    /// Execute a SCSI command block.
+    fn execute(&self, cb: &[u8]) {
+        if cb.is_empty() {
+            return self.fail(Sense::InvalidCommand);
+        }
+        let num_blocks = self.num_blocks.get();
+        let medium_present = num_blocks > 0;
+
+        match cb[0] {
+            SCSI_TEST_UNIT_READY | SCSI_START_STOP_UNIT | SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                if medium_present {
+                    self.complete()
+                } else {
+                    self.fail(Sense::MediumNotPresent)
+                }
+            }
+            SCSI_REQUEST_SENSE => {
+                let sense = self.sense.replace(Sense::NoSense) as u16;
+                let mut response = [0; 18];
+                response[0] = 0x70; // Current error, fixed format
+                response[2] = (sense >> 8) as u8;
+                response[7] = 10; // Additional sense length
+                response[12] = sense as u8;
+                self.respond(&response);
+            }
+            SCSI_INQUIRY => {
+                let mut response = [0; 36];
+                response[1] = 0x80; // Removable
+                response[2] = 0x04; // SPC-2
+                response[3] = 0x02; // Response data format
+                response[4] = 31; // Additional length
+                response[8..16].copy_from_slice(b"Tock    ");
+                response[16..32].copy_from_slice(b"Mass Storage    ");
+                response[32..36].copy_from_slice(b"1.0 ");
+                self.respond(&response);
+            }
+            SCSI_MODE_SENSE_6 => {
+                let mut response = [0; 4];
+                response[0] = 3; // Mode data length
+                response[2] = if self.read_only { 0x80 } else { 0 };
+                self.respond(&response);
+            }
+            SCSI_READ_FORMAT_CAPACITIES => {
+                if !medium_present {
+                    return self.fail(Sense::MediumNotPresent);
+                }
+                let mut response = [0; 12];
+                response[3] = 8; // Capacity list length
+                response[4..8].copy_from_slice(&num_blocks.to_be_bytes());
+                response[8] = 0x02; // Formatted media
+                response[9..12].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..4]);
+                self.respond(&response);
+            }
+            SCSI_READ_CAPACITY_10 => {
+                if !medium_present {
+                    return self.fail(Sense::MediumNotPresent);
+                }
+                let mut response = [0; 8];
+                response[0..4].copy_from_slice(&(num_blocks - 1).to_be_bytes());
+                response[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.respond(&response);
+            }
+            SCSI_READ_10 | SCSI_WRITE_10 | SCSI_VERIFY_10 => {
+                if cb.len() < 10 {
+                    return self.fail(Sense::InvalidField);
+                }
+                if !medium_present {
+                    return self.fail(Sense::MediumNotPresent);
+                }
+                let lba = u32::from_be_bytes(cb[2..6].try_into().unwrap());
+                let count = u16::from_be_bytes(cb[7..9].try_into().unwrap()) as u32;
+                if lba as u64 + count as u64 > num_blocks as u64 {
+                    return self.fail(Sense::LbaOutOfRange);
+                }
+
+                let direction_in = cb[0] == SCSI_READ_10;
+                if cb[0] != SCSI_VERIFY_10
+                    && (self.direction_in.get() != direction_in
+                        || count as usize * BLOCK_SIZE > self.expected_length.get())
+                {
+                    // The host and the command disagree on the data phase.
+                    return self.phase_error();
+                }
+
+                match cb[0] {
+                    SCSI_READ_10 => {
+                        self.operation.set(Operation::Read);
+                        self.lba.set(lba);
+                        self.blocks_remaining.set(count);
+                        if count > 0 {
+                            self.read_block();
+                        } else {
+                            self.complete();
+                        }
+                    }
+                    SCSI_WRITE_10 => {
+                        if self.read_only {
+                            return self.fail(Sense::WriteProtected);
+                        }
+                        self.operation.set(Operation::Write);
+                        self.lba.set(lba);
+                        self.blocks_remaining.set(count);
+                        self.complete();
+                    }
+                    _ => self.complete(),
+                }
+            }
+            _ => self.fail(Sense::InvalidCommand),
+        }
+    }
+
+    /// Send `response` as the data for the current command.
+    fn respond(&self, response: &[u8]) {
+        if !self.direction_in.get() && self.expected_length.get() > 0 {
+            return self.phase_error();
+        }
+        let length = cmp::min(response.len(), self.expected_length.get());
+        let copied = self.buffer.map_or(false, |buffer| {
+            buffer[..length].copy_from_slice(&response[..length]);
+            true
+        });
+        if copied {
+            self.buffer_length.set(length);
+            self.complete();
+        } else {
+            self.fail(Sense::InvalidCommand);
+        }
+    }
+
+    /// End the current command with a failure.
+    fn fail(&self, sense: Sense) {
+        self.sense.set(sense);
+        self.status.set(STATUS_FAILED);
+        self.operation.set(Operation::None);
+        self.complete();
+    }
+
+    /// End the current command because the host and device disagree on the
+    /// data phase. The host has to reset the device to recover.
+    fn phase_error(&self) {
+        self.status.set(STATUS_PHASE_ERROR);
+        self.operation.set(Operation::None);
+        self.expected_length.set(0);
+        self.complete();
+    }
+
+    /// Move on to the data phase if the host still expects data, otherwise
+    /// send the status.
+    fn complete(&self) {
+        if self.transferred.get() < self.expected_length.get() {
+            if self.direction_in.get() {
+                self.phase.set(Phase::DataIn);
+                self.controller()
+                    .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+            } else {
+                self.phase.set(Phase::DataOut);
+                self.controller()
+                    .endpoint_resume_out(self.endpoint(ENDPOINT_OUT));
+            }
+        } else {
+            self.phase.set(Phase::Status);
+            self.controller()
+                .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+        }
+    }
+
+    fn block_address(&self) -> usize {
+        self.start_address + self.lba.get() as usize * BLOCK_SIZE
+    }
+
+    fn read_block(&self) {
+        self.phase.set(Phase::Storage);
+        let result = self
+            .buffer
+            .take()
+            .map_or(Err(kernel::ErrorCode::NOMEM), |buffer| {
+                self.storage
+                    .read(buffer, self.block_address(), BLOCK_SIZE)
+                    .map_err(|(e, buffer)| {
+                        // Keep the buffer so later commands can still run.
+                        self.buffer.replace(buffer);
+                        e
+                    })
+            });
+        if result.is_err() {
+            self.fail(Sense::ReadError);
+        }
+    }
+
+    fn write_block(&self) {
+        self.phase.set(Phase::Storage);
+        let result = self
+            .buffer
+            .take()
+            .map_or(Err(kernel::ErrorCode::NOMEM), |buffer| {
+                self.storage
+                    .write(buffer, self.block_address(), BLOCK_SIZE)
+                    .map_err(|(e, buffer)| {
+                        // Keep the buffer so later commands can still run.
+                        self.buffer.replace(buffer);
+                        e
+                    })
+            });
+        if result.is_err() {
+            self.fail(Sense::WriteError);
+        }
+    }
+
+    /// Write the Command Status Wrapper into the IN endpoint buffer.
+    fn write_status(&self, packet: &[VolatileCell<u8>]) -> usize {
+        let residue = self.expected_length.get() - self.processed.get();
+        let mut csw = [0; CSW_LENGTH];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.get().to_le_bytes());
+        csw[8..12].copy_from_slice(&(residue as u32).to_le_bytes());
+        csw[12] = self.status.get();
+        for (cell, &byte) in packet.iter().zip(csw.iter()) {
+            cell.set(byte);
+        }
+        CSW_LENGTH
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: NonvolatileStorage<'a>> hil::usb::Client<'a>
+    for MassStorage<'a, U, S>
+{
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        self.enable_endpoints();
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        SetupData::get(&self.client_ctrl.ctrl_buffer.buf)
+            .and_then(|setup_data| {
+                self.function_ctrl_setup(&setup_data, &self.client_ctrl.ctrl_buffer.buf)
+            })
+            .unwrap_or_else(|| self.client_ctrl.ctrl_setup(endpoint))
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        if self.ctrl_max_lun.get() {
+            self.function_ctrl_in(&self.client_ctrl.ctrl_buffer.buf)
+        } else {
+            self.client_ctrl.ctrl_in(endpoint)
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk/Interrupt IN transaction.
+    ///
+    /// This is called when we can send data to the host, either data for the
+    /// current command or its status.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                let packet = self.buffer(endpoint);
+                match self.phase.get() {
+                    Phase::DataIn => {
+                        let offset = self.buffer_offset.get();
+                        let length = self.buffer_length.get();
+                        let wanted = self.expected_length.get() - self.transferred.get();
+
+                        if offset < length {
+                            // Send data from the buffer.
+                            let to_send = cmp::min(cmp::min(packet.len(), length - offset), wanted);
+                            self.buffer.map(|buffer| {
+                                for i in 0..to_send {
+                                    packet[i].set(buffer[offset + i]);
+                                }
+                            });
+                            self.buffer_offset.set(offset + to_send);
+                            self.transferred.set(self.transferred.get() + to_send);
+                            self.processed.set(self.processed.get() + to_send);
+                            hil::usb::InResult::Packet(to_send)
+                        } else if self.operation.get() == Operation::Read
+                            && self.blocks_remaining.get() > 0
+                        {
+                            // Fetch the next block from storage.
+                            self.read_block();
+                            hil::usb::InResult::Delay
+                        } else if wanted > 0 {
+                            // Pad the rest of the data the host expects.
+                            let to_send = cmp::min(packet.len(), wanted);
+                            for i in 0..to_send {
+                                packet[i].set(0);
+                            }
+                            self.transferred.set(self.transferred.get() + to_send);
+                            hil::usb::InResult::Packet(to_send)
+                        } else {
+                            self.phase.set(Phase::Command);
+                            hil::usb::InResult::Packet(self.write_status(packet))
+                        }
+                    }
+                    Phase::Status => {
+                        self.phase.set(Phase::Command);
+                        hil::usb::InResult::Packet(self.write_status(packet))
+                    }
+                    Phase::Stalled => hil::usb::InResult::Error,
+                    Phase::Command | Phase::DataOut | Phase::Storage => hil::usb::InResult::Delay,
+                }
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                // Nothing to do for mass storage.
+                hil::usb::InResult::Delay
+            }
+        }
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                let packet = self.buffer(endpoint);
+                let packet_bytes = cmp::min(packet_bytes as usize, packet.len());
+                match self.phase.get() {
+                    Phase::Command => {
+                        self.command(packet, packet_bytes);
+                        if self.phase.get() == Phase::Stalled {
+                            hil::usb::OutResult::Error
+                        } else {
+                            hil::usb::OutResult::Ok
+                        }
+                    }
+                    Phase::DataOut => {
+                        self.transferred.set(self.transferred.get() + packet_bytes);
+
+                        if self.operation.get() == Operation::Write
+                            && self.blocks_remaining.get() > 0
+                        {
+                            let offset = self.buffer_offset.get();
+                            let to_copy = cmp::min(packet_bytes, BLOCK_SIZE - offset);
+                            self.buffer.map(|buffer| {
+                                for i in 0..to_copy {
+                                    buffer[offset + i] = packet[i].get();
+                                }
+                            });
+                            self.buffer_offset.set(offset + to_copy);
+
+                            if offset + to_copy == BLOCK_SIZE {
+                                // Hold off the host until the block is written.
+                                self.write_block();
+                                return hil::usb::OutResult::Delay;
+                            }
+                        } else if self.transferred.get() >= self.expected_length.get() {
+                            // All the data we had to discard has arrived.
+                            self.phase.set(Phase::Status);
+                            self.controller()
+                                .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+                        }
+                        hil::usb::OutResult::Ok
+                    }
+                    Phase::DataIn | Phase::Storage | Phase::Status => {
+                        // The host should not be sending anything now.
+                        hil::usb::OutResult::Delay
+                    }
+                    Phase::Stalled => hil::usb::OutResult::Error,
+                }
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                // Nothing to do for mass storage.
+                hil::usb::OutResult::Ok
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        // Keep sending while there is data or a status to send.
+        match self.phase.get() {
+            Phase::DataIn | Phase::Status => {
+                self.controller()
+                    .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+            }
+            Phase::Command | Phase::DataOut | Phase::Storage | Phase::Stalled => {}
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: NonvolatileStorage<'a>> UsbFunction<'a>
+    for MassStorage<'a, U, S>
+{
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn num_endpoints(&self) -> usize {
+        N_ENDPOINTS
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.interface.set(first_interface);
+        self.first_endpoint.set(first_endpoint);
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&mut FunctionDescriptors)) {
+        Self::with_descriptors(self.interface.get(), self.first_endpoint.get(), f)
+    }
+
+    fn enable_endpoints(&'a self) {
+        // Setup buffers for IN and OUT data transfer.
+        self.controller().endpoint_set_in_buffer(
+            self.endpoint(ENDPOINT_IN),
+            self.buffer(self.endpoint(ENDPOINT_IN)),
+        );
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, self.endpoint(ENDPOINT_IN));
+
+        self.controller().endpoint_set_out_buffer(
+            self.endpoint(ENDPOINT_OUT),
+            self.buffer(self.endpoint(ENDPOINT_OUT)),
+        );
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, self.endpoint(ENDPOINT_OUT));
+    }
+
+    /// Bulk-Only Transport defines two class requests: GET MAX LUN and the
+    /// mass storage reset.
+    fn function_ctrl_setup(
+        &'a self,
+        setup_data: &SetupData,
+        _ctrl_buffer: &'a [VolatileCell<u8>; 64],
+    ) -> Option<hil::usb::CtrlSetupResult> {
+        if !matches!(setup_data.request_type.request_type(), RequestType::Class) {
+            return None;
+        }
+
+        match setup_data.request_code {
+            BOT_GET_MAX_LUN => {
+                self.ctrl_max_lun.set(true);
+                Some(hil::usb::CtrlSetupResult::Ok)
+            }
+            BOT_RESET => {
+                self.reset();
+                Some(hil::usb::CtrlSetupResult::Ok)
+            }
+            _ => None,
+        }
+    }
+
+    fn function_ctrl_in(
+        &'a self,
+        ctrl_buffer: &'a [VolatileCell<u8>; 64],
+    ) -> hil::usb::CtrlInResult {
+        if self.ctrl_max_lun.replace(false) {
+            // We only have a single logical unit.
+            ctrl_buffer[0].set(0);
+            hil::usb::CtrlInResult::Packet(1, true)
+        } else {
+            hil::usb::CtrlInResult::Error
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: NonvolatileStorage<'a>> NonvolatileStorageClient<'a>
+    for MassStorage<'a, U, S>
+{
+    fn read_done(&self, buffer: &'a mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+        if self.phase.get() != Phase::Storage {
+            // The host reset us while the storage was busy.
+            return;
+        }
+        self.lba.set(self.lba.get() + 1);
+        self.blocks_remaining.set(self.blocks_remaining.get() - 1);
+
+        self.buffer_offset.set(0);
+        self.buffer_length.set(BLOCK_SIZE);
+        self.phase.set(Phase::DataIn);
+        self.controller()
+            .endpoint_resume_in(self.endpoint(ENDPOINT_IN));
+    }
+
+    fn write_done(&self, buffer: &'a mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+        if self.phase.get() != Phase::Storage {
+            // The host reset us while the storage was busy.
+            return;
+        }
+        self.lba.set(self.lba.get() + 1);
+        self.blocks_remaining.set(self.blocks_remaining.get() - 1);
+        self.processed.set(self.processed.get() + BLOCK_SIZE);
+
+        self.buffer_offset.set(0);
+        if self.blocks_remaining.get() == 0 {
+            self.operation.set(Operation::None);
+        }
+        self.complete();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use hil_mock::usb::MockUsbController;
+    use kernel::common::cells::OptionalCell;
+    use kernel::hil::usb::Client;
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// Storage that refuses every read and write.
+    struct FailingStorage {
+        client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+    }
+
+    impl NonvolatileStorage<'static> for FailingStorage {
+        fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+            self.client.set(client);
+        }
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            _address: usize,
+            _length: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::FAIL, buffer))
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            _address: usize,
+            _length: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::FAIL, buffer))
+        }
+    }
+
+    type Msc = MassStorage<'static, MockUsbController<'static>, FailingStorage>;
+
+    const OUT: usize = FIRST_ENDPOINT_NUM + ENDPOINT_OUT;
+    const IN: usize = FIRST_ENDPOINT_NUM + ENDPOINT_IN;
+
+    fn setup() -> (&'static Msc, &'static MockUsbController<'static>) {
+        let usb = Box::leak(Box::new(MockUsbController::new()));
+        let storage = Box::leak(Box::new(FailingStorage {
+            client: OptionalCell::empty(),
+        }));
+        let msc: &'static Msc = Box::leak(Box::new(MassStorage::new(
+            usb,
+            MAX_CTRL_PACKET_SIZE_NRF52840,
+            0x6667,
+            0xabcd,
+            &["Tock", "Disk", "0"],
+            storage,
+            Box::leak(Box::new([0; BLOCK_SIZE])),
+            0,
+            16,
+            false,
+        )));
+        storage.set_client(msc);
+        (msc, usb)
+    }
+
+    fn cbw(tag: u32, length: u32, direction_in: bool, cb: &[u8]) -> [u8; CBW_LENGTH] {
+        let mut cbw = [0; CBW_LENGTH];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&length.to_le_bytes());
+        cbw[12] = if direction_in { 0x80 } else { 0 };
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        cbw
+    }
+
+    fn send(msc: &'static Msc, data: &[u8]) -> hil::usb::OutResult {
+        for (cell, &byte) in msc.buffer(OUT).iter().zip(data.iter()) {
+            cell.set(byte);
+        }
+        msc.packet_out(TransferType::Bulk, OUT, data.len() as u32)
+    }
+
+    /// Take the next IN packet, or `None` if the endpoint is stalled.
+    fn receive(msc: &'static Msc) -> Option<Vec<u8>> {
+        match msc.packet_in(TransferType::Bulk, IN) {
+            hil::usb::InResult::Packet(length) => Some(
+                msc.buffer(IN)[..length]
+                    .iter()
+                    .map(|cell| cell.get())
+                    .collect(),
+            ),
+            hil::usb::InResult::Delay => Some(Vec::new()),
+            hil::usb::InResult::Error => None,
+        }
+    }
+
+    /// Parse a Command Status Wrapper into (tag, residue, status).
+    fn csw(packet: &[u8]) -> (u32, u32, u8) {
+        assert_eq!(packet.len(), CSW_LENGTH);
+        let word = |index: usize| u32::from_le_bytes(packet[index..index + 4].try_into().unwrap());
+        assert_eq!(word(0), CSW_SIGNATURE);
+        (word(4), word(8), packet[12])
+    }
+
+    #[test]
+    fn command_without_data() {
+        let (msc, _) = setup();
+        let result = send(msc, &cbw(0x12345678, 0, false, &[SCSI_TEST_UNIT_READY; 6]));
+        assert!(matches!(result, hil::usb::OutResult::Ok));
+        assert_eq!(csw(&receive(msc).unwrap()), (0x12345678, 0, STATUS_PASSED));
+    }
+
+    #[test]
+    fn short_response_reports_residue() {
+        let (msc, _) = setup();
+        let mut inquiry = [0; 6];
+        inquiry[0] = SCSI_INQUIRY;
+        send(msc, &cbw(7, 64, true, &inquiry));
+
+        let data = receive(msc).unwrap();
+        assert_eq!(data.len(), 36);
+        assert_eq!(&data[8..16], b"Tock    ");
+        // The host asked for more than INQUIRY returns, the rest is padding.
+        assert_eq!(receive(msc).unwrap(), [0; 28]);
+        assert_eq!(csw(&receive(msc).unwrap()), (7, 28, STATUS_PASSED));
+    }
+
+    #[test]
+    fn invalid_cbw_stalls_until_reset() {
+        let (msc, usb) = setup();
+        let valid = cbw(1, 0, false, &[SCSI_TEST_UNIT_READY; 6]);
+
+        let mut bad_signature = valid;
+        bad_signature[0] ^= 1;
+        for invalid in [&bad_signature[..], &valid[..CBW_LENGTH - 1]].iter() {
+            let resumes = usb.in_resumes(IN);
+            assert!(matches!(send(msc, invalid), hil::usb::OutResult::Error));
+            // The IN endpoint is resumed so that it stalls too.
+            assert_eq!(usb.in_resumes(IN), resumes + 1);
+            assert_eq!(receive(msc), None);
+            // A valid CBW is not accepted until the host resets the device.
+            assert!(matches!(send(msc, &valid), hil::usb::OutResult::Error));
+            assert_eq!(receive(msc), None);
+
+            // Bulk-Only Mass Storage Reset.
+            let setup = [0x21, BOT_RESET, 0, 0, 0, 0, 0, 0];
+            for (cell, &byte) in msc.client_ctrl.ctrl_buffer.buf.iter().zip(setup.iter()) {
+                cell.set(byte);
+            }
+            assert!(matches!(msc.ctrl_setup(0), hil::usb::CtrlSetupResult::Ok));
+
+            assert!(matches!(send(msc, &valid), hil::usb::OutResult::Ok));
+            assert_eq!(csw(&receive(msc).unwrap()), (1, 0, STATUS_PASSED));
+        }
+    }
+
+    #[test]
+    fn storage_error_fails_command() {
+        let (msc, _) = setup();
+        let mut read = [0; 10];
+        read[0] = SCSI_READ_10;
+        read[8] = 1; // One block
+        send(msc, &cbw(2, BLOCK_SIZE as u32, true, &read));
+
+        // The host still gets the data it asked for, then a failed status.
+        let mut padding = 0;
+        let status = loop {
+            let packet = receive(msc).unwrap();
+            if padding == BLOCK_SIZE {
+                break packet;
+            }
+            assert!(packet.iter().all(|&byte| byte == 0));
+            padding += packet.len();
+        };
+        assert_eq!(csw(&status), (2, BLOCK_SIZE as u32, STATUS_FAILED));
+
+        // The buffer survived the error, so the sense data can be returned.
+        let mut request_sense = [0; 6];
+        request_sense[0] = SCSI_REQUEST_SENSE;
+        send(msc, &cbw(3, 18, true, &request_sense));
+        let sense = receive(msc).unwrap();
+        assert_eq!((sense[2], sense[12]), (0x03, 0x11));
+        assert_eq!(csw(&receive(msc).unwrap()), (3, 0, STATUS_PASSED));
+    }
+}
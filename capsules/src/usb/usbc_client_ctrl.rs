@@ -29,12 +29,14 @@ use super::descriptors::SetupData;
 use super::descriptors::StandardRequest;
 use super::descriptors::StringDescriptor;
 use super::descriptors::TransferDirection;
+use super::descriptors::DESCRIPTOR_BUFFER_LEN;
 use core::cell::Cell;
 use core::cmp::min;
+use kernel::common::cells::OptionalCell;
 use kernel::hil;
 use kernel::hil::usb::TransferType;
 
-const DESCRIPTOR_BUFLEN: usize = 128;
+const DESCRIPTOR_BUFLEN: usize = DESCRIPTOR_BUFFER_LEN;
 
 const N_ENDPOINTS: usize = 3;
 
@@ -64,12 +66,12 @@ pub struct ClientCtrl<'a, 'b, U: 'a> {
 
     /// An optional HID descriptor for the configuration. This can be requested
     /// separately. It must also be included in `other_descriptor_buffer` if it exists.
-    hid_descriptor: Option<&'b HIDDescriptor<'b>>,
+    hid_descriptor: OptionalCell<&'b HIDDescriptor<'b>>,
 
     /// An optional report descriptor for the configuration. This can be
     /// requested separately. It must also be included in
     /// `other_descriptor_buffer` if it exists.
-    report_descriptor: Option<&'b ReportDescriptor<'b>>,
+    report_descriptor: OptionalCell<&'b ReportDescriptor<'b>>,
 
     /// Supported language (only one for now).
     language: &'b [u16; 1],
@@ -109,57 +111,38 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
         language: &'b [u16; 1],
         strings: &'b [&'b str],
     ) -> Self {
+        // Cell is not Copy, so the array is built from a constant.
+        const EMPTY: Cell<u8> = Cell::new(0);
+        let hid_descriptor_cell = OptionalCell::empty();
+        hid_descriptor_cell.insert(hid_descriptor);
+        let report_descriptor_cell = OptionalCell::empty();
+        report_descriptor_cell.insert(report_descriptor);
         ClientCtrl {
             controller: controller,
             state: Default::default(),
-            // For the moment, the Default trait is not implemented for arrays
-            // of length > 32, and the Cell type is not Copy, so we have to
-            // initialize each element manually.
-            #[rustfmt::skip]
-            descriptor_storage: [
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-                Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            ],
+            descriptor_storage: [EMPTY; DESCRIPTOR_BUFLEN],
             ctrl_buffer: Buffer64::default(),
             device_descriptor_buffer,
             other_descriptor_buffer,
-            hid_descriptor,
-            report_descriptor,
+            hid_descriptor: hid_descriptor_cell,
+            report_descriptor: report_descriptor_cell,
             language,
             strings,
         }
     }
 
+    /// Set the HID and report descriptors returned for interface
+    /// `GetDescriptor` requests. A composite device sets these to those of
+    /// the function owning the interface before handling such a request.
+    pub fn set_hid_descriptors(
+        &self,
+        hid_descriptor: Option<&'b HIDDescriptor<'b>>,
+        report_descriptor: Option<&'b ReportDescriptor<'b>>,
+    ) {
+        self.hid_descriptor.insert(hid_descriptor);
+        self.report_descriptor.insert(report_descriptor);
+    }
+
     #[inline]
     pub fn controller(&self) -> &'a U {
         self.controller
@@ -336,29 +319,43 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
                 requested_length,
             } => match descriptor_type {
                 DescriptorType::HID => {
-                    if let Some(desc) = self.hid_descriptor {
-                        let buf = self.descriptor_buf();
-                        let len = desc.write_to(buf);
-                        let end = min(len, requested_length as usize);
-                        self.state[endpoint].set(State::CtrlIn(0, end));
-                        hil::usb::CtrlSetupResult::Ok
-                    } else {
-                        hil::usb::CtrlSetupResult::ErrGeneric
-                    }
+                    self.hid_descriptor
+                        .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |desc| {
+                            let buf = self.descriptor_buf();
+                            let len = desc.write_to(buf);
+                            let end = min(len, requested_length as usize);
+                            self.state[endpoint].set(State::CtrlIn(0, end));
+                            hil::usb::CtrlSetupResult::Ok
+                        })
                 }
                 DescriptorType::Report => {
-                    if let Some(desc) = self.report_descriptor {
-                        let buf = self.descriptor_buf();
-                        let len = desc.write_to(buf);
-                        let end = min(len, requested_length as usize);
-                        self.state[endpoint].set(State::CtrlIn(0, end));
-                        hil::usb::CtrlSetupResult::Ok
-                    } else {
-                        hil::usb::CtrlSetupResult::ErrGeneric
-                    }
+                    self.report_descriptor
+                        .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |desc| {
+                            let buf = self.descriptor_buf();
+                            let len = desc.write_to(buf);
+                            let end = min(len, requested_length as usize);
+                            self.state[endpoint].set(State::CtrlIn(0, end));
+                            hil::usb::CtrlSetupResult::Ok
+                        })
                 }
                 _ => hil::usb::CtrlSetupResult::ErrGeneric,
             },
+            StandardRequest::SetInterface {
+                alternate_setting,
+                interface,
+            } => {
+                // Functions that care which setting is active (like CDC-ECM)
+                // track it themselves, we only check that it exists.
+                if interface <= 0xff
+                    && self
+                        .other_descriptor_buffer
+                        .has_interface(interface as u8, alternate_setting)
+                {
+                    hil::usb::CtrlSetupResult::Ok
+                } else {
+                    hil::usb::CtrlSetupResult::ErrGeneric
+                }
+            }
             _ => hil::usb::CtrlSetupResult::ErrGeneric,
         }
     }
@@ -36,6 +36,13 @@
 //! combine saved_tag and the unencrypted tag to form the encrypted tag and
 //! verify its correctness.
 //!
+//! Each `VirtualAES128CCM` also implements `AES128` and the CTR, CBC and ECB
+//! mode traits, so that clients that need plain AES can share the hardware
+//! with the CCM* users. The key, mode and IV are kept per virtual device and
+//! programmed into the hardware before each `crypt()`, and the CBC/CTR
+//! chaining value is carried across calls until the next `start_message()`.
+//! The key is shared between the CCM* and the plain AES interfaces.
+//!
 //! Usage
 //! -----
 //!
@@ -65,7 +72,7 @@
 //! ccm_client1.setup();
 //! let data1 = static_init!([u8; 4 * AES128_BLOCK_SIZE], [0x00; 4 * AES128_BLOCK_SIZE]);
 //! let t1 = static_init!(Test<'static, AESCCMCLIENT>, Test::new(ccm_client1, data1));
-//! ccm_client1.set_client(t1);
+//! AES128CCM::set_client(ccm_client1, t1);
 //! let crypt_buf2 = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
 //! let ccm_client2 = static_init!(
 //!     AESCCMCLIENT,
@@ -74,7 +81,7 @@
 //! ccm_client2.setup();
 //! let data2 = static_init!([u8; 4 * AES128_BLOCK_SIZE], [0x00; 4 * AES128_BLOCK_SIZE]);
 //! let t2 = static_init!(Test<'static, AESCCMCLIENT>, Test::new(ccm_client2, data2));
-//! ccm_client2.set_client(t2);
+//! AES128CCM::set_client(ccm_client2, t2);
 //! t1.run();
 //! t2.run();
 //!
@@ -91,7 +98,7 @@ use kernel::common::{List, ListLink, ListNode};
 use kernel::debug;
 use kernel::hil::symmetric_encryption;
 use kernel::hil::symmetric_encryption::{
-    AES128Ctr, AES128, AES128CBC, AES128_BLOCK_SIZE, AES128_KEY_SIZE, CCM_NONCE_LENGTH,
+    AES128Ctr, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE, CCM_NONCE_LENGTH,
 };
 use kernel::ErrorCode;
 
@@ -100,6 +107,23 @@ enum CCMState {
     Idle,
     Auth,
     Encrypt,
+    /// A plain AES128 operation on behalf of the `AES128` client.
+    Aes,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum AesMode {
+    Ecb,
+    Cbc,
+    Ctr,
+}
+
+// to cache up the function parameters of AES128::crypt()
+struct AesParameters<'a> {
+    source: Option<&'a mut [u8]>,
+    dest: &'a mut [u8],
+    start_index: usize,
+    stop_index: usize,
 }
 
 // to cache up the function parameters of the crypt() function
@@ -135,7 +159,7 @@ impl CryptFunctionParameters {
     }
 }
 
-pub struct MuxAES128CCM<'a, A: AES128<'a> + AES128Ctr + AES128CBC> {
+pub struct MuxAES128CCM<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> {
     aes: &'a A,
     clients: List<'a, VirtualAES128CCM<'a, A>>,
     inflight: OptionalCell<&'a VirtualAES128CCM<'a, A>>,
@@ -143,7 +167,7 @@ pub struct MuxAES128CCM<'a, A: AES128<'a> + AES128Ctr + AES128CBC> {
     handle: OptionalCell<DeferredCallHandle>,
 }
 
-impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> MuxAES128CCM<'a, A> {
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> MuxAES128CCM<'a, A> {
     pub fn new(aes: &'a A, deferred_caller: &'a DynamicDeferredCall) -> MuxAES128CCM<'a, A> {
         aes.enable(); // enable the hardware, in case it's forgotten elsewhere
         MuxAES128CCM {
@@ -187,9 +211,28 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> MuxAES128CCM<'a, A> {
 
     fn do_next_op(&self) {
         if self.inflight.is_none() {
-            let mnode = self.clients.iter().find(|node| node.queued_up.is_some());
+            let mnode = self
+                .clients
+                .iter()
+                .find(|node| node.queued_up.is_some() || node.aes_queued.is_some());
             mnode.map(|node| {
                 self.inflight.set(node);
+                if let Some(parameters) = node.aes_queued.take() {
+                    if let Err((ecode, source, dest)) = node.start_aes(parameters) {
+                        // `crypt_done` has no way to report the error, so the
+                        // client gets its buffers back unchanged
+                        debug!(
+                            "virtual_aes_ccm: failed to start AES operation: {:?}",
+                            ecode
+                        );
+                        node.remove_from_queue();
+                        node.aes_client.map(move |client| {
+                            client.crypt_done(source, dest);
+                        });
+                        self.do_next_op();
+                    }
+                    return;
+                }
                 let parameters: CryptFunctionParameters = node.queued_up.take().unwrap();
                 // now, eat the parameters
                 let _ = node.crypt_r(parameters).map_err(|(ecode, _)| {
@@ -219,13 +262,15 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> MuxAES128CCM<'a, A> {
     }
 }
 
-impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> DynamicDeferredCallClient for MuxAES128CCM<'a, A> {
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> DynamicDeferredCallClient
+    for MuxAES128CCM<'a, A>
+{
     fn call(&self, _handle: DeferredCallHandle) {
         self.do_next_op();
     }
 }
 
-impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::Client<'a>
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption::Client<'a>
     for MuxAES128CCM<'a, A>
 {
     fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
@@ -243,7 +288,7 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::Client<'a>
     }
 }
 
-pub struct VirtualAES128CCM<'a, A: AES128<'a> + AES128Ctr + AES128CBC> {
+pub struct VirtualAES128CCM<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> {
     mux: &'a MuxAES128CCM<'a, A>,
     aes: &'a A,
     next: ListLink<'a, VirtualAES128CCM<'a, A>>,
@@ -263,9 +308,19 @@ pub struct VirtualAES128CCM<'a, A: AES128<'a> + AES128Ctr + AES128CBC> {
     nonce: Cell<[u8; CCM_NONCE_LENGTH]>,
     saved_tag: Cell<[u8; AES128_BLOCK_SIZE]>,
     queued_up: OptionalCell<CryptFunctionParameters>,
+
+    aes_client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+    aes_mode: Cell<(AesMode, bool)>,
+    aes_iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+    /// The IV for the next `crypt()` of the current message.
+    aes_chain: Cell<[u8; AES128_BLOCK_SIZE]>,
+    /// The last ciphertext block of a CBC decryption in progress.
+    aes_saved: Cell<[u8; AES128_BLOCK_SIZE]>,
+    aes_range: Cell<(usize, usize)>,
+    aes_queued: OptionalCell<AesParameters<'a>>,
 }
 
-impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> VirtualAES128CCM<'a, A> {
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128CCM<'a, A> {
     pub fn new(
         mux: &'a MuxAES128CCM<'a, A>,
         crypt_buf: &'static mut [u8],
@@ -287,6 +342,13 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> VirtualAES128CCM<'a, A> {
             nonce: Cell::new(Default::default()),
             saved_tag: Cell::new(Default::default()),
             queued_up: OptionalCell::empty(),
+            aes_client: OptionalCell::empty(),
+            aes_mode: Cell::new((AesMode::Ctr, true)),
+            aes_iv: Cell::new(Default::default()),
+            aes_chain: Cell::new(Default::default()),
+            aes_saved: Cell::new(Default::default()),
+            aes_range: Cell::new((0, 0)),
+            aes_queued: OptionalCell::empty(),
         }
     }
 
@@ -660,11 +722,90 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> VirtualAES128CCM<'a, A> {
 
     fn remove_from_queue(&self) {
         self.queued_up.clear();
+        self.aes_queued.clear();
         self.mux.inflight.clear();
     }
+
+    /// Program this client's key, mode and chaining value into the hardware
+    /// and start a queued `AES128::crypt()`.
+    fn start_aes(
+        &self,
+        parameters: AesParameters<'a>,
+    ) -> Result<(), (ErrorCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        let AesParameters {
+            source,
+            dest,
+            start_index,
+            stop_index,
+        } = parameters;
+
+        let (mode, encrypting) = self.aes_mode.get();
+        let res = self
+            .aes
+            .set_key(&self.key.get())
+            .and_then(|()| match mode {
+                AesMode::Ecb => self.aes.set_mode_aes128ecb(encrypting),
+                AesMode::Cbc => self.aes.set_mode_aes128cbc(encrypting),
+                AesMode::Ctr => self.aes.set_mode_aes128ctr(encrypting),
+            })
+            .and_then(|()| self.aes.set_iv(&self.aes_chain.get()));
+        if let Err(e) = res {
+            return Err((e, source, dest));
+        }
+
+        // A CBC decryption chains from its last input block, which is
+        // overwritten when decrypting in place.
+        let input = source
+            .as_ref()
+            .map_or(&dest[start_index..stop_index], |source| {
+                &source[..stop_index - start_index]
+            });
+        if input.len() >= AES128_BLOCK_SIZE {
+            let mut last = [0u8; AES128_BLOCK_SIZE];
+            last.copy_from_slice(&input[input.len() - AES128_BLOCK_SIZE..]);
+            self.aes_saved.set(last);
+        }
+        self.aes_range.set((start_index, stop_index));
+
+        self.aes.start_message();
+        match self.aes.crypt(source, dest, start_index, stop_index) {
+            None => {
+                self.state.set(CCMState::Aes);
+                Ok(())
+            }
+            Some((res, source, dest)) => Err((res.err().unwrap_or(ErrorCode::FAIL), source, dest)),
+        }
+    }
+
+    /// Advance the chaining value past the blocks just processed.
+    fn end_aes(&self, dest: &[u8]) {
+        let (mode, encrypting) = self.aes_mode.get();
+        let (start_index, stop_index) = self.aes_range.get();
+        let blocks = (stop_index - start_index) / AES128_BLOCK_SIZE;
+        if blocks == 0 {
+            return;
+        }
+        match mode {
+            AesMode::Ecb => {}
+            AesMode::Cbc => {
+                if encrypting {
+                    let mut last = [0u8; AES128_BLOCK_SIZE];
+                    last.copy_from_slice(&dest[stop_index - AES128_BLOCK_SIZE..stop_index]);
+                    self.aes_chain.set(last);
+                } else {
+                    self.aes_chain.set(self.aes_saved.get());
+                }
+            }
+            AesMode::Ctr => {
+                let counter = u128::from_be_bytes(self.aes_chain.get());
+                self.aes_chain
+                    .set(counter.wrapping_add(blocks as u128).to_be_bytes());
+            }
+        }
+    }
 }
 
-impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::AES128CCM<'a>
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption::AES128CCM<'a>
     for VirtualAES128CCM<'a, A>
 {
     fn set_client(&self, client: &'a dyn symmetric_encryption::CCMClient) {
@@ -727,13 +868,23 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::AES128CCM<
     }
 }
 
-impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::Client<'a>
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption::Client<'a>
     for VirtualAES128CCM<'a, A>
 {
-    fn crypt_done(&self, _: Option<&'a mut [u8]>, crypt_buf: &'a mut [u8]) {
+    fn crypt_done(&self, source: Option<&'a mut [u8]>, crypt_buf: &'a mut [u8]) {
+        if self.state.get() == CCMState::Aes {
+            self.end_aes(crypt_buf);
+            self.state.set(CCMState::Idle);
+            self.remove_from_queue();
+            self.mux.do_next_op();
+            self.aes_client.map(move |client| {
+                client.crypt_done(source, crypt_buf);
+            });
+            return;
+        }
         self.crypt_buf.replace(crypt_buf);
         match self.state.get() {
-            CCMState::Idle => {}
+            CCMState::Idle | CCMState::Aes => {}
             CCMState::Auth => {
                 if !self.reversed() {
                     if self.confidential.get() {
@@ -816,8 +967,103 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::Client<'a>
     }
 }
 
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128<'a> for VirtualAES128CCM<'a, A> {
+    fn enable(&self) {
+        self.mux.enable();
+    }
+
+    fn disable(&self) {
+        // The hardware is shared, so it stays enabled for the other clients.
+    }
+
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+        self.aes_client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if key.len() != AES128_KEY_SIZE {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut new_key = [0u8; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        Ok(())
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+        if iv.len() != AES128_BLOCK_SIZE {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut new_iv = [0u8; AES128_BLOCK_SIZE];
+        new_iv.copy_from_slice(iv);
+        self.aes_iv.set(new_iv);
+        Ok(())
+    }
+
+    fn start_message(&self) {
+        if self.aes_queued.is_none() && self.state.get() != CCMState::Aes {
+            self.aes_chain.set(self.aes_iv.get());
+        }
+    }
+
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(Result<(), ErrorCode>, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.aes_queued.is_some()
+            || self.queued_up.is_some()
+            || self.state.get() != CCMState::Idle
+        {
+            return Some((Err(ErrorCode::BUSY), source, dest));
+        }
+        let len_ok = source
+            .as_ref()
+            .map_or(true, |source| source.len() == stop_index - start_index);
+        if stop_index < start_index
+            || stop_index > dest.len()
+            || (stop_index - start_index) % AES128_BLOCK_SIZE != 0
+            || !len_ok
+        {
+            return Some((Err(ErrorCode::INVAL), source, dest));
+        }
+
+        self.aes_queued.set(AesParameters {
+            source,
+            dest,
+            start_index,
+            stop_index,
+        });
+        self.mux.do_next_op_async();
+        None
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128Ctr for VirtualAES128CCM<'a, A> {
+    fn set_mode_aes128ctr(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.aes_mode.set((AesMode::Ctr, encrypting));
+        Ok(())
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128CBC for VirtualAES128CCM<'a, A> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.aes_mode.set((AesMode::Cbc, encrypting));
+        Ok(())
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128ECB for VirtualAES128CCM<'a, A> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.aes_mode.set((AesMode::Ecb, encrypting));
+        Ok(())
+    }
+}
+
 // Fit in the linked list
-impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> ListNode<'a, VirtualAES128CCM<'a, A>>
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> ListNode<'a, VirtualAES128CCM<'a, A>>
     for VirtualAES128CCM<'a, A>
 {
     fn next(&'a self) -> &'a ListLink<'a, VirtualAES128CCM<'a, A>> {
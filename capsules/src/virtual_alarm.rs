@@ -230,3 +230,88 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use hil_mock::alarm::MockAlarm;
+    use std::boxed::Box;
+
+    /// Records the time at which its alarm fired.
+    struct Client<'a> {
+        alarm: &'a VirtualMuxAlarm<'a, MockAlarm<'a>>,
+        fired_at: Cell<Option<u32>>,
+    }
+
+    impl time::AlarmClient for Client<'_> {
+        fn alarm(&self) {
+            self.fired_at.set(Some(self.alarm.now().into_u32()));
+        }
+    }
+
+    fn virtual_alarm(
+        mux: &'static MuxAlarm<'static, MockAlarm<'static>>,
+    ) -> (
+        &'static VirtualMuxAlarm<'static, MockAlarm<'static>>,
+        &'static Client<'static>,
+    ) {
+        let alarm = Box::leak(Box::new(VirtualMuxAlarm::new(mux)));
+        let client = Box::leak(Box::new(Client {
+            alarm,
+            fired_at: Cell::new(None),
+        }));
+        alarm.set_alarm_client(client);
+        (alarm, client)
+    }
+
+    fn mux() -> (
+        &'static MockAlarm<'static>,
+        &'static MuxAlarm<'static, MockAlarm<'static>>,
+    ) {
+        let alarm = Box::leak(Box::new(MockAlarm::new()));
+        let mux = Box::leak(Box::new(MuxAlarm::new(alarm)));
+        alarm.set_alarm_client(mux);
+        (alarm, mux)
+    }
+
+    #[test]
+    fn alarms_fire_in_expiration_order() {
+        let (alarm, mux) = mux();
+        let (late, late_client) = virtual_alarm(mux);
+        let (early, early_client) = virtual_alarm(mux);
+
+        late.set_alarm(late.now(), 100.into());
+        early.set_alarm(early.now(), 30.into());
+        assert_eq!(alarm.get_alarm().into_u32(), 30);
+
+        assert_eq!(alarm.advance(50), 1);
+        assert_eq!(early_client.fired_at.get(), Some(30));
+        assert_eq!(late_client.fired_at.get(), None);
+        assert_eq!(alarm.get_alarm().into_u32(), 100);
+
+        assert_eq!(alarm.advance(100), 1);
+        assert_eq!(late_client.fired_at.get(), Some(100));
+        assert!(!alarm.is_armed());
+    }
+
+    #[test]
+    fn disarming_the_last_alarm_disarms_the_hardware() {
+        let (alarm, mux) = mux();
+        let (first, _) = virtual_alarm(mux);
+        let (second, second_client) = virtual_alarm(mux);
+
+        first.set_alarm(first.now(), 10.into());
+        second.set_alarm(second.now(), 20.into());
+        assert_eq!(first.disarm(), Ok(()));
+        // The hardware alarm still fires for the disarmed alarm, but only the
+        // armed one is delivered.
+        assert_eq!(alarm.advance(20), 2);
+        assert_eq!(second_client.fired_at.get(), Some(20));
+
+        second.set_alarm(second.now(), 20.into());
+        assert_eq!(second.disarm(), Ok(()));
+        assert!(!alarm.is_armed());
+    }
+}
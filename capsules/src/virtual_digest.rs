@@ -404,3 +404,36 @@ impl<
         });
     }
 }
+
+/// The `MuxDigest` can be set as the client of the underlying hardware, in
+/// which case callbacks are routed to whichever `VirtualMuxDigest` is
+/// currently running. This allows more than one `VirtualMuxDigest` to share
+/// the hardware.
+impl<
+        'a,
+        A: digest::Digest<'a, L>
+            + digest::HMACSha256
+            + digest::HMACSha384
+            + digest::HMACSha512
+            + digest::Sha256
+            + digest::Sha384
+            + digest::Sha512,
+        const L: usize,
+    > digest::Client<'a, L> for MuxDigest<'a, A, L>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        let running_id = self.running_id.get();
+        self.users
+            .iter()
+            .find(|node| node.id == running_id)
+            .map(move |node| node.add_data_done(result, data));
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; L]) {
+        let running_id = self.running_id.get();
+        self.users
+            .iter()
+            .find(|node| node.id == running_id)
+            .map(move |node| node.hash_done(result, digest));
+    }
+}
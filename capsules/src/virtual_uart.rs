@@ -57,7 +57,7 @@ pub static mut RX_BUF: [u8; RX_BUF_LEN] = [0; RX_BUF_LEN];
 
 pub struct MuxUart<'a> {
     uart: &'a dyn uart::Uart<'a>,
-    speed: u32,
+    speed: Cell<u32>,
     devices: List<'a, UartDevice<'a>>,
     inflight: OptionalCell<&'a UartDevice<'a>>,
     buffer: TakeCell<'static, [u8]>,
@@ -198,7 +198,7 @@ impl<'a> MuxUart<'a> {
     ) -> MuxUart<'a> {
         MuxUart {
             uart: uart,
-            speed: speed,
+            speed: Cell::new(speed),
             devices: List::new(),
             inflight: OptionalCell::empty(),
             buffer: TakeCell::new(buffer),
@@ -210,7 +210,7 @@ impl<'a> MuxUart<'a> {
 
     pub fn initialize(&self) {
         let _ = self.uart.configure(uart::Parameters {
-            baud_rate: self.speed,
+            baud_rate: self.speed.get(),
             width: uart::Width::Eight,
             stop_bits: uart::StopBits::One,
             parity: uart::Parity::None,
@@ -304,6 +304,16 @@ impl<'a> MuxUart<'a> {
     }
 }
 
+/// Reconfigures the shared UART, for example to change its baud rate. The new
+/// configuration applies to every `UartDevice` on the mux.
+impl<'a> uart::Configure for MuxUart<'a> {
+    fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
+        self.uart.configure(params)?;
+        self.speed.set(params.baud_rate);
+        Ok(())
+    }
+}
+
 impl<'a> DynamicDeferredCallClient for MuxUart<'a> {
     fn call(&self, _handle: DeferredCallHandle) {
         self.do_next_op();
@@ -479,3 +489,124 @@ impl<'a> uart::Receive<'a> for UartDevice<'a> {
         Err(ErrorCode::FAIL)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use hil_mock::uart::MockUart;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use kernel::hil::uart::{Receive, Transmit};
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct Client {
+        transmitted: Cell<usize>,
+        received: RefCell<Vec<u8>>,
+    }
+
+    impl uart::TransmitClient for Client {
+        fn transmitted_buffer(&self, _: &'static mut [u8], _: usize, _: Result<(), ErrorCode>) {
+            self.transmitted.set(self.transmitted.get() + 1);
+        }
+    }
+
+    impl uart::ReceiveClient for Client {
+        fn received_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            rx_len: usize,
+            _rcode: Result<(), ErrorCode>,
+            _error: uart::Error,
+        ) {
+            self.received
+                .borrow_mut()
+                .extend_from_slice(&rx_buffer[..rx_len]);
+        }
+    }
+
+    struct Setup {
+        uart: &'static MockUart<'static>,
+        mux: &'static MuxUart<'static>,
+        handle: DeferredCallHandle,
+    }
+
+    fn setup() -> Setup {
+        let uart = Box::leak(Box::new(MockUart::new()));
+        let clients: &'static [DynamicDeferredCallClientState; 1] =
+            Box::leak(Box::new(Default::default()));
+        let deferred_caller = Box::leak(Box::new(DynamicDeferredCall::new(clients)));
+        let mux = Box::leak(Box::new(MuxUart::new(
+            uart,
+            Box::leak(Box::new([0; RX_BUF_LEN])),
+            115200,
+            deferred_caller,
+        )));
+        let handle = deferred_caller.register(mux).unwrap();
+        mux.initialize_callback_handle(handle);
+        mux.initialize();
+        uart.set_transmit_client(mux);
+        uart.set_receive_client(mux);
+        Setup { uart, mux, handle }
+    }
+
+    fn device(mux: &'static MuxUart<'static>) -> (&'static UartDevice<'static>, &'static Client) {
+        let device = Box::leak(Box::new(UartDevice::new(mux, true)));
+        device.setup();
+        let client = Box::leak(Box::new(Client::default()));
+        device.set_transmit_client(client);
+        device.set_receive_client(client);
+        (device, client)
+    }
+
+    #[test]
+    fn transmissions_are_serialized() {
+        let Setup { uart, mux, handle } = setup();
+        let (first, first_client) = device(mux);
+        let (second, second_client) = device(mux);
+        assert_eq!(uart.parameters().map(|p| p.baud_rate), Some(115200));
+
+        assert!(first
+            .transmit_buffer(Box::leak(Box::new(*b"one")), 3)
+            .is_ok());
+        assert!(second
+            .transmit_buffer(Box::leak(Box::new(*b"two")), 3)
+            .is_ok());
+        // Nothing starts until the deferred call runs.
+        assert!(!uart.is_transmitting());
+        mux.call(handle);
+        assert_eq!(uart.take_transmitted().len(), 3);
+
+        assert!(uart.complete_transmit());
+        assert!(uart.complete_transmit());
+        assert!(!uart.complete_transmit());
+        assert_eq!(first_client.transmitted.get(), 1);
+        assert_eq!(second_client.transmitted.get(), 1);
+        assert_eq!(uart.take_transmitted().len(), 3);
+    }
+
+    #[test]
+    fn received_bytes_reach_every_receiver() {
+        let Setup { uart, mux, .. } = setup();
+        let (first, first_client) = device(mux);
+        let (second, second_client) = device(mux);
+
+        assert!(first.receive_buffer(Box::leak(Box::new([0; 2])), 2).is_ok());
+        assert!(second
+            .receive_buffer(Box::leak(Box::new([0; 4])), 4)
+            .is_ok());
+        // The mux reads the shortest outstanding length, then restarts the
+        // underlying receive for the rest of the longer read.
+        uart.receive(b"ab");
+        assert_eq!(*first_client.received.borrow(), b"ab");
+        assert!(second_client.received.borrow().is_empty());
+        assert!(uart.is_receiving());
+        uart.receive(b"cdef");
+        assert_eq!(*first_client.received.borrow(), b"ab");
+        assert_eq!(*second_client.received.borrow(), b"abcd");
+        assert!(!uart.is_receiving());
+    }
+}
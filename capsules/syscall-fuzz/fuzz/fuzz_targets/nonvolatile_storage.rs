@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use syscall_fuzz::targets::nonvolatile_storage::NonvolatileStorageTarget;
+
+fuzz_target!(|data: &[u8]| {
+    syscall_fuzz::run::<NonvolatileStorageTarget>(data);
+});
@@ -0,0 +1,57 @@
+//! Per-input storage for objects the kernel needs as `&'static`.
+//!
+//! Boards create their kernel objects once with `static_init!` and never free
+//! them. The harness builds a fresh kernel, processes and capsule for every
+//! fuzzer input, so it allocates them from an [`Arena`] instead and frees
+//! them all once the input has run.
+
+use std::cell::RefCell;
+
+pub struct Arena {
+    allocations: RefCell<Vec<(*mut u8, unsafe fn(*mut u8))>>,
+}
+
+unsafe fn drop_box<T>(ptr: *mut u8) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena {
+            allocations: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Move `value` into the arena.
+    ///
+    /// # Safety
+    ///
+    /// The returned reference is only valid until the arena is dropped. The
+    /// caller must make sure nothing uses it after that.
+    pub unsafe fn alloc<T: 'static>(&self, value: T) -> &'static mut T {
+        let ptr = Box::into_raw(Box::new(value));
+        self.allocations
+            .borrow_mut()
+            .push((ptr as *mut u8, drop_box::<T>));
+        &mut *ptr
+    }
+
+    /// Allocate a zeroed byte buffer of `len` bytes.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Arena::alloc`].
+    pub unsafe fn buffer(&self, len: usize) -> &'static mut [u8] {
+        self.alloc(vec![0; len]).as_mut_slice()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        // Free in reverse order of allocation, so objects go before anything
+        // they were built from.
+        for (ptr, drop) in self.allocations.get_mut().drain(..).rev() {
+            unsafe { drop(ptr) };
+        }
+    }
+}
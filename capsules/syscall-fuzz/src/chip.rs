@@ -0,0 +1,138 @@
+//! A chip on which processes never run.
+//!
+//! The harness makes system calls on behalf of processes directly, so the
+//! chip only has to hold on to the value each system call returns until the
+//! harness collects it.
+
+use core::cell::Cell;
+use core::fmt::Write;
+
+use kernel::procs::FunctionCall;
+use kernel::syscall::{ContextSwitchReason, SyscallReturn, UserspaceKernelBoundary};
+use kernel::Chip;
+
+/// Bytes of process memory below the initial app break. The harness places
+/// the buffers it allows to drivers in this area.
+pub const BUFFER_AREA_SIZE: usize = 1024;
+
+pub struct Boundary {
+    last_return: Cell<Option<SyscallReturn>>,
+}
+
+impl Boundary {
+    /// The value returned by the last system call, if it has not been taken
+    /// yet.
+    pub fn take_return(&self) -> Option<SyscallReturn> {
+        self.last_return.take()
+    }
+}
+
+impl UserspaceKernelBoundary for Boundary {
+    type StoredState = ();
+
+    fn initial_process_app_brk_size(&self) -> usize {
+        BUFFER_AREA_SIZE
+    }
+
+    unsafe fn initialize_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut Self::StoredState,
+    ) -> Result<(), ()> {
+        Ok(())
+    }
+
+    unsafe fn set_syscall_return_value(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut Self::StoredState,
+        return_value: SyscallReturn,
+    ) -> Result<(), ()> {
+        self.last_return.set(Some(return_value));
+        Ok(())
+    }
+
+    unsafe fn set_process_function(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut Self::StoredState,
+        _upcall: FunctionCall,
+    ) -> Result<(), ()> {
+        Ok(())
+    }
+
+    unsafe fn switch_to_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut Self::StoredState,
+    ) -> (ContextSwitchReason, Option<*const u8>) {
+        unreachable!("fuzzed processes never run")
+    }
+
+    unsafe fn print_context(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &Self::StoredState,
+        _writer: &mut dyn Write,
+    ) {
+    }
+}
+
+pub struct FuzzChip {
+    boundary: Boundary,
+}
+
+impl FuzzChip {
+    pub fn new() -> FuzzChip {
+        FuzzChip {
+            boundary: Boundary {
+                last_return: Cell::new(None),
+            },
+        }
+    }
+}
+
+impl Chip for FuzzChip {
+    type MPU = ();
+    type UserspaceKernelBoundary = Boundary;
+    type SchedulerTimer = ();
+    type WatchDog = ();
+
+    fn service_pending_interrupts(&self) {}
+
+    fn has_pending_interrupts(&self) -> bool {
+        false
+    }
+
+    fn mpu(&self) -> &() {
+        &()
+    }
+
+    fn scheduler_timer(&self) -> &() {
+        &()
+    }
+
+    fn watchdog(&self) -> &() {
+        &()
+    }
+
+    fn userspace_kernel_boundary(&self) -> &Boundary {
+        &self.boundary
+    }
+
+    fn sleep(&self) {}
+
+    unsafe fn atomic<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        f()
+    }
+
+    unsafe fn print_state(&self, _writer: &mut dyn Write) {}
+}
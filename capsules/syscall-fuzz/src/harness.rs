@@ -0,0 +1,538 @@
+//! The kernel, processes and driver that each fuzzer input runs against.
+
+use core::marker::PhantomData;
+use core::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+use hil_mock::uart::MockUart;
+use kernel::capabilities;
+use kernel::common::RingBuffer;
+use kernel::create_capability;
+use kernel::debug::{DebugWriter, DebugWriterWrapper};
+use kernel::procs::{FunctionCallSource, PanicFaultPolicy, Process, Task};
+use kernel::syscall::{Syscall, SyscallReturn};
+use kernel::{Chip, Driver, Kernel, Platform};
+
+use crate::arena::Arena;
+use crate::chip::{FuzzChip, BUFFER_AREA_SIZE};
+use crate::input::{self, Buffer, Op, UpcallFn};
+use crate::tbf;
+
+/// Number of processes loaded for each input.
+pub const NUM_PROCS: usize = 3;
+
+/// Processes the fuzzer input acts on. The remaining process is kept for the
+/// liveness probe, so the probe starts from a process with clean state.
+pub const FUZZED_PROCS: usize = 2;
+
+/// The process the liveness probe runs from.
+pub const PROBE_PROCESS: usize = 2;
+
+const MIN_RAM_SIZE: u32 = 4096;
+const PROCESS_NAMES: [&str; NUM_PROCS] = ["fuzz0", "fuzz1", "fuzz2"];
+const APP_MEMORY_SIZE: usize = NUM_PROCS * 16 * 1024;
+
+/// Hardware operations `settle` completes before it decides the driver is
+/// livelocked.
+const MAX_SETTLE_STEPS: usize = 1000;
+
+/// A driver under test, together with the mock hardware it runs on.
+pub trait Target: Sized + 'static {
+    /// The driver number processes use to reach the driver.
+    const DRIVER_NUM: usize;
+
+    /// Create the driver and its mock hardware. This runs before processes
+    /// are loaded, so it is where the driver's grant is created.
+    ///
+    /// # Safety
+    ///
+    /// Everything the target refers to must be allocated from `arena`, and
+    /// must not be used once the arena is dropped.
+    unsafe fn create(kernel: &'static Kernel, arena: &Arena) -> &'static Self;
+
+    fn driver(&self) -> &dyn Driver;
+
+    /// Bring the hardware up once processes are loaded.
+    fn start(&self) {}
+
+    /// Complete one outstanding hardware operation. Returns `false` if the
+    /// hardware is idle.
+    fn step(&self) -> bool;
+
+    /// Trigger a hardware event selected by `event`, such as receiving data.
+    fn event(&self, _event: u8) {}
+
+    /// Check that the driver still serves a process after the fuzzer input
+    /// has run. Panics if it does not.
+    fn probe(harness: &Harness<Self>);
+}
+
+/// The platform seen by the kernel: the target's driver and nothing else.
+struct Board<T: Target>(&'static T);
+
+impl<T: Target> Platform for Board<T> {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+    where
+        F: FnOnce(Option<&dyn Driver>) -> R,
+    {
+        if driver_num == T::DRIVER_NUM {
+            f(Some(self.0.driver()))
+        } else {
+            f(None)
+        }
+    }
+}
+
+pub struct Harness<'a, T: Target> {
+    kernel: &'static Kernel,
+    chip: &'static FuzzChip,
+    board: Board<T>,
+    processes: &'static [Option<&'static dyn Process>],
+    _arena: PhantomData<&'a Arena>,
+}
+
+/// Returns `true` for any of the success variants of `SyscallReturn`.
+pub fn succeeded(rval: &SyscallReturn) -> bool {
+    matches!(
+        rval,
+        SyscallReturn::Success
+            | SyscallReturn::SuccessU32(_)
+            | SyscallReturn::SuccessU32U32(_, _)
+            | SyscallReturn::SuccessU32U32U32(_, _, _)
+            | SyscallReturn::SuccessU64(_)
+            | SyscallReturn::SuccessU64U32(_, _)
+            | SyscallReturn::AllowReadWriteSuccess(_, _)
+            | SyscallReturn::AllowReadOnlySuccess(_, _)
+            | SyscallReturn::SubscribeSuccess(_, _)
+    )
+}
+
+impl<'a, T: Target> Harness<'a, T> {
+    /// Create a kernel with the target's driver and load the processes.
+    pub fn new(arena: &'a Arena) -> Harness<'a, T> {
+        set_debug_writer();
+        let process_cap = create_capability!(capabilities::ProcessManagementCapability);
+
+        // The harness keeps only the shared reference to the process table
+        // once loading, the only writer, is done.
+        unsafe {
+            let processes: *mut [Option<&'static dyn Process>; NUM_PROCS] =
+                arena.alloc([None; NUM_PROCS]);
+            let kernel = arena.alloc(Kernel::new(&*processes));
+            let target = T::create(kernel, arena);
+            let chip = arena.alloc(FuzzChip::new());
+
+            let mut flash = Vec::new();
+            for name in PROCESS_NAMES.iter() {
+                flash.extend(tbf::app_image(name, MIN_RAM_SIZE));
+            }
+            let flash: &'static [u8] = arena.alloc(flash).as_slice();
+            let memory = arena.alloc(vec![0u64; APP_MEMORY_SIZE / 8]);
+            kernel::procs::load_processes(
+                kernel,
+                chip,
+                flash,
+                core::slice::from_raw_parts_mut(memory.as_mut_ptr() as *mut u8, APP_MEMORY_SIZE),
+                &mut *processes,
+                arena.alloc(PanicFaultPolicy {}),
+                &process_cap,
+            )
+            .expect("failed to load the fuzzed processes");
+
+            target.start();
+            Harness {
+                kernel,
+                chip,
+                board: Board(target),
+                processes: &*processes,
+                _arena: PhantomData,
+            }
+        }
+    }
+
+    pub fn target(&self) -> &'static T {
+        self.board.0
+    }
+
+    fn process(&self, process: usize) -> &'static dyn Process {
+        self.processes[process].expect("process was not loaded")
+    }
+
+    /// Make `syscall` on behalf of `process` and return its result.
+    pub fn syscall(&self, process: usize, syscall: Syscall) -> SyscallReturn {
+        let process_cap = create_capability!(capabilities::ProcessManagementCapability);
+        self.kernel.handle_syscall_external(
+            &self.board,
+            self.process(process),
+            syscall,
+            &process_cap,
+        );
+        self.chip
+            .userspace_kernel_boundary()
+            .take_return()
+            .expect("system call did not return a value")
+    }
+
+    pub fn command(
+        &self,
+        process: usize,
+        command_num: usize,
+        arg0: usize,
+        arg1: usize,
+    ) -> SyscallReturn {
+        self.syscall(
+            process,
+            Syscall::Command {
+                driver_number: T::DRIVER_NUM,
+                subdriver_number: command_num,
+                arg0,
+                arg1,
+            },
+        )
+    }
+
+    fn buffer_address(&self, process: usize, buffer: Buffer) -> (*mut u8, usize) {
+        match buffer {
+            Buffer::Null => (ptr::null_mut(), 0),
+            Buffer::Memory { offset, len } => (
+                (self.process(process).mem_start() as *mut u8).wrapping_add(offset),
+                len,
+            ),
+        }
+    }
+
+    pub fn allow_readwrite(
+        &self,
+        process: usize,
+        allow_num: usize,
+        buffer: Buffer,
+    ) -> SyscallReturn {
+        let (allow_address, allow_size) = self.buffer_address(process, buffer);
+        self.syscall(
+            process,
+            Syscall::ReadWriteAllow {
+                driver_number: T::DRIVER_NUM,
+                subdriver_number: allow_num,
+                allow_address,
+                allow_size,
+            },
+        )
+    }
+
+    pub fn allow_readonly(
+        &self,
+        process: usize,
+        allow_num: usize,
+        buffer: Buffer,
+    ) -> SyscallReturn {
+        let (allow_address, allow_size) = self.buffer_address(process, buffer);
+        self.syscall(
+            process,
+            Syscall::ReadOnlyAllow {
+                driver_number: T::DRIVER_NUM,
+                subdriver_number: allow_num,
+                allow_address,
+                allow_size,
+            },
+        )
+    }
+
+    pub fn subscribe(
+        &self,
+        process: usize,
+        subscribe_num: usize,
+        upcall: UpcallFn,
+    ) -> SyscallReturn {
+        let upcall_ptr = match upcall {
+            UpcallFn::Null => ptr::null_mut(),
+            UpcallFn::Valid(index) => {
+                self.process(process)
+                    .flash_non_protected_start()
+                    .wrapping_add(index * 4 % tbf::CODE_SIZE) as *mut ()
+            }
+            UpcallFn::Raw(address) => address as *mut (),
+        };
+        self.syscall(
+            process,
+            Syscall::Subscribe {
+                driver_number: T::DRIVER_NUM,
+                subdriver_number: subscribe_num,
+                upcall_ptr,
+                appdata: 0,
+            },
+        )
+    }
+
+    /// Write `bytes` to the buffer area of `process`, starting at `offset`.
+    /// Whatever does not fit in the area is dropped.
+    pub fn write_memory(&self, process: usize, offset: usize, bytes: &[u8]) {
+        let start = offset.min(BUFFER_AREA_SIZE);
+        let len = bytes.len().min(BUFFER_AREA_SIZE - start);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                (self.process(process).mem_start() as *mut u8).add(start),
+                len,
+            );
+        }
+    }
+
+    /// Remove the upcalls queued for `process`, returning the subscribe
+    /// number and arguments of those from the target's driver.
+    pub fn take_upcalls(&self, process: usize) -> Vec<(usize, [usize; 3])> {
+        let mut upcalls = Vec::new();
+        while let Some(task) = self.process(process).dequeue_task() {
+            if let Task::FunctionCall(call) = task {
+                if let FunctionCallSource::Driver(id) = call.source {
+                    if id.driver_num == T::DRIVER_NUM {
+                        upcalls.push((
+                            id.subscribe_num,
+                            [call.argument0, call.argument1, call.argument2],
+                        ));
+                    }
+                }
+            }
+        }
+        upcalls
+    }
+
+    /// Complete outstanding hardware operations until the hardware is idle.
+    /// Panics if it is still busy after `MAX_SETTLE_STEPS` operations.
+    pub fn settle(&self) {
+        for _ in 0..MAX_SETTLE_STEPS {
+            if !self.target().step() {
+                return;
+            }
+        }
+        panic!(
+            "livelock: hardware still busy after {} operations",
+            MAX_SETTLE_STEPS
+        );
+    }
+
+    pub fn apply(&self, op: &Op) {
+        match *op {
+            Op::Command {
+                process,
+                command_num,
+                arg0,
+                arg1,
+            } => {
+                self.command(process, command_num, arg0, arg1);
+            }
+            Op::AllowReadWrite {
+                process,
+                allow_num,
+                buffer,
+            } => {
+                self.allow_readwrite(process, allow_num, buffer);
+            }
+            Op::AllowReadOnly {
+                process,
+                allow_num,
+                buffer,
+            } => {
+                self.allow_readonly(process, allow_num, buffer);
+            }
+            Op::Subscribe {
+                process,
+                subscribe_num,
+                upcall,
+            } => {
+                self.subscribe(process, subscribe_num, upcall);
+            }
+            Op::WriteMemory {
+                process,
+                offset,
+                ref bytes,
+            } => self.write_memory(process, offset, bytes),
+            Op::Step => {
+                self.target().step();
+            }
+            Op::Event(event) => self.target().event(event),
+            Op::DeliverUpcalls { process } => {
+                self.take_upcalls(process);
+            }
+        }
+    }
+}
+
+/// Set once the first time a harness is created. Capsules may print with
+/// `debug!`, which panics without a debug writer. The writer is never
+/// drained, so once its buffer fills, output is dropped.
+fn set_debug_writer() {
+    static DEBUG_WRITER: Once = Once::new();
+    DEBUG_WRITER.call_once(|| unsafe {
+        let uart: &'static MockUart<'static> = Box::leak(Box::new(MockUart::new()));
+        let output: &'static mut [u8] = Box::leak(vec![0; 64].into_boxed_slice());
+        let internal: &'static mut [u8] = Box::leak(vec![0; 1024].into_boxed_slice());
+        let ring_buffer = Box::leak(Box::new(RingBuffer::new(internal)));
+        let writer: &'static DebugWriter =
+            Box::leak(Box::new(DebugWriter::new(uart, output, ring_buffer)));
+        kernel::hil::uart::Transmit::set_transmit_client(uart, writer);
+        kernel::debug::set_debug_writer_wrapper(Box::leak(Box::new(DebugWriterWrapper::new(
+            writer,
+        ))));
+    });
+}
+
+/// Kernel state such as the debug writer is global, so inputs run one at a
+/// time even when tests run on several threads.
+struct RunLock;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+impl RunLock {
+    fn acquire() -> RunLock {
+        while RUNNING
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::thread::yield_now();
+        }
+        RunLock
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+/// Run the fuzzer input `data` against a fresh instance of `T`, then check
+/// the driver is still live. Panics on any bug found.
+pub fn run<T: Target>(data: &[u8]) {
+    let _lock = RunLock::acquire();
+    let arena = Arena::new();
+    let harness = Harness::<T>::new(&arena);
+    for op in input::parse(data, FUZZED_PROCS) {
+        harness.apply(&op);
+    }
+    harness.settle();
+    T::probe(&harness);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::targets::console::ConsoleTarget;
+    use crate::targets::nonvolatile_storage::NonvolatileStorageTarget;
+    use crate::targets::screen::ScreenTarget;
+    use crate::targets::udp::UdpTarget;
+    use kernel::procs::{FixedQuotaPolicy, ProcessQuotaCounts, ProcessQuotas};
+    use kernel::ErrorCode;
+
+    /// Deterministic pseudo-random inputs (xorshift), so failures reproduce.
+    fn inputs(count: usize) -> impl Iterator<Item = Vec<u8>> {
+        let mut state: u32 = 0x2545_f491;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        (0..count).map(move |_| {
+            let len = next() as usize % 512;
+            (0..len).map(|_| next() as u8).collect()
+        })
+    }
+
+    fn fuzz<T: Target>() {
+        run::<T>(&[]);
+        for input in inputs(200) {
+            run::<T>(&input);
+        }
+    }
+
+    #[test]
+    fn console() {
+        fuzz::<ConsoleTarget>();
+    }
+
+    /// A write of zero bytes used to leave the console waiting forever for a
+    /// transmission the UART had refused.
+    #[test]
+    fn console_zero_length_write() {
+        run::<ConsoleTarget>(&[0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn nonvolatile_storage() {
+        fuzz::<NonvolatileStorageTarget>();
+    }
+
+    #[test]
+    fn screen() {
+        fuzz::<ScreenTarget>();
+    }
+
+    #[test]
+    fn udp() {
+        fuzz::<UdpTarget>();
+    }
+
+    /// Apply `quotas` to every process of `harness`.
+    fn set_quotas<T: Target>(harness: &Harness<T>, quotas: ProcessQuotas) {
+        let process_cap = create_capability!(capabilities::ProcessManagementCapability);
+        let policy: &'static FixedQuotaPolicy = Box::leak(Box::new(FixedQuotaPolicy::new(quotas)));
+        harness.kernel.set_quota_policy(policy, &process_cap);
+    }
+
+    fn quota_violations<T: Target>(harness: &Harness<T>, process: usize) -> ProcessQuotaCounts {
+        harness.process(process).debug_quota_exceeded_counts()
+    }
+
+    #[test]
+    fn grant_quota() {
+        let _lock = RunLock::acquire();
+        let arena = Arena::new();
+        let harness = Harness::<ConsoleTarget>::new(&arena);
+        set_quotas(
+            &harness,
+            ProcessQuotas {
+                grant_bytes_per_driver: Some(1),
+                ..ProcessQuotas::unlimited()
+            },
+        );
+
+        // The console's grant does not fit in one byte.
+        assert!(matches!(
+            harness.subscribe(0, 1, UpcallFn::Valid(0)),
+            SyscallReturn::SubscribeFailure(ErrorCode::NOMEM, _, _)
+        ));
+        assert_eq!(quota_violations(&harness, 0).grant_bytes_per_driver, 1);
+
+        set_quotas(&harness, ProcessQuotas::unlimited());
+        assert!(succeeded(&harness.subscribe(0, 1, UpcallFn::Valid(0))));
+    }
+
+    #[test]
+    fn pending_task_quota() {
+        let _lock = RunLock::acquire();
+        let arena = Arena::new();
+        let harness = Harness::<ConsoleTarget>::new(&arena);
+        set_quotas(
+            &harness,
+            ProcessQuotas {
+                pending_tasks: Some(1),
+                ..ProcessQuotas::unlimited()
+            },
+        );
+        // Drop the task that starts the process.
+        harness.take_upcalls(0);
+
+        let buffer = Buffer::Memory { offset: 0, len: 4 };
+        assert!(succeeded(&harness.allow_readonly(0, 1, buffer)));
+        assert!(succeeded(&harness.subscribe(0, 1, UpcallFn::Valid(0))));
+        for _ in 0..2 {
+            assert!(succeeded(&harness.command(0, 1, 4, 0)));
+            harness.settle();
+        }
+
+        // Only the first write callback fits in the queue.
+        assert_eq!(harness.take_upcalls(0), vec![(1, [4, 0, 0])]);
+        assert_eq!(quota_violations(&harness, 0).pending_tasks, 1);
+    }
+}
@@ -0,0 +1,222 @@
+//! Decoding fuzzer input into system calls and hardware events.
+//!
+//! The input is a sequence of operations, each an opcode byte followed by its
+//! arguments. Input that runs out part way through an operation ends the
+//! sequence, so every byte string is a valid input.
+
+/// Most operations decoded from one input.
+pub const MAX_OPS: usize = 256;
+
+/// Buffer offset that stands for a null pointer.
+const NULL_OFFSET: u16 = 0xFFFF;
+
+/// A buffer passed to allow, relative to the start of the process's buffer
+/// area. Buffers that run past the area are passed as is, so the kernel's
+/// checks are exercised too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Buffer {
+    Null,
+    Memory { offset: usize, len: usize },
+}
+
+/// The function pointer passed to subscribe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpcallFn {
+    Null,
+    /// An address in the process's flash, which the kernel accepts.
+    Valid(usize),
+    /// An arbitrary address, which the kernel almost always rejects.
+    Raw(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Command {
+        process: usize,
+        command_num: usize,
+        arg0: usize,
+        arg1: usize,
+    },
+    AllowReadWrite {
+        process: usize,
+        allow_num: usize,
+        buffer: Buffer,
+    },
+    AllowReadOnly {
+        process: usize,
+        allow_num: usize,
+        buffer: Buffer,
+    },
+    Subscribe {
+        process: usize,
+        subscribe_num: usize,
+        upcall: UpcallFn,
+    },
+    /// The process changes its own memory, including allowed buffers.
+    WriteMemory {
+        process: usize,
+        offset: usize,
+        bytes: Vec<u8>,
+    },
+    /// Complete one outstanding hardware operation.
+    Step,
+    /// A target-specific hardware event, such as a byte arriving.
+    Event(u8),
+    /// The process handles its queued upcalls.
+    DeliverUpcalls { process: usize },
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.data.split_first()?;
+        self.data = rest;
+        Some(byte)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes([
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+        ]))
+    }
+
+    /// A system call argument. Small values are the common case, so they
+    /// take one byte; a byte of 0xF0 or more is followed by a full word.
+    fn arg(&mut self) -> Option<usize> {
+        match self.u8()? {
+            small @ 0..=0xEF => Some(small as usize),
+            _ => Some(self.u32()? as usize),
+        }
+    }
+
+    /// A command, allow or subscribe number. Drivers mostly use the first
+    /// few, so half of the byte values select one of those.
+    fn num(&mut self) -> Option<usize> {
+        match self.u8()? {
+            small @ 0..=0x7F => Some(small as usize % 8),
+            _ => self.arg(),
+        }
+    }
+
+    fn process(&mut self, processes: usize) -> Option<usize> {
+        Some(self.u8()? as usize % processes)
+    }
+
+    fn buffer(&mut self) -> Option<Buffer> {
+        let offset = self.u16()?;
+        let len = self.u16()?;
+        Some(match offset {
+            NULL_OFFSET => Buffer::Null,
+            _ => Buffer::Memory {
+                offset: offset as usize,
+                len: len as usize,
+            },
+        })
+    }
+
+    fn upcall(&mut self) -> Option<UpcallFn> {
+        Some(match self.u8()? % 3 {
+            0 => UpcallFn::Null,
+            1 => UpcallFn::Valid(self.u8()? as usize),
+            _ => UpcallFn::Raw(self.u32()? as usize),
+        })
+    }
+
+    fn op(&mut self, processes: usize) -> Option<Op> {
+        Some(match self.u8()? % 8 {
+            0 => Op::Command {
+                process: self.process(processes)?,
+                command_num: self.num()?,
+                arg0: self.arg()?,
+                arg1: self.arg()?,
+            },
+            1 => Op::AllowReadWrite {
+                process: self.process(processes)?,
+                allow_num: self.num()?,
+                buffer: self.buffer()?,
+            },
+            2 => Op::AllowReadOnly {
+                process: self.process(processes)?,
+                allow_num: self.num()?,
+                buffer: self.buffer()?,
+            },
+            3 => Op::Subscribe {
+                process: self.process(processes)?,
+                subscribe_num: self.num()?,
+                upcall: self.upcall()?,
+            },
+            4 => {
+                let process = self.process(processes)?;
+                let offset = self.u16()? as usize;
+                let len = self.u8()? as usize;
+                let bytes = self.data.get(..len)?.to_vec();
+                self.data = &self.data[len..];
+                Op::WriteMemory {
+                    process,
+                    offset,
+                    bytes,
+                }
+            }
+            5 => Op::Step,
+            6 => Op::Event(self.u8()?),
+            _ => Op::DeliverUpcalls {
+                process: self.process(processes)?,
+            },
+        })
+    }
+}
+
+/// Decode `data` into operations on the first `processes` processes.
+pub fn parse(data: &[u8], processes: usize) -> Vec<Op> {
+    let mut reader = Reader { data };
+    let mut ops = Vec::new();
+    while ops.len() < MAX_OPS {
+        match reader.op(processes) {
+            Some(op) => ops.push(op),
+            None => break,
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_operation_ends_the_input() {
+        let data = [
+            0, 3, 0x81, 0xF0, 0x78, 0x56, 0x34, 0x12, 2, 9, // command
+            5, // step
+            1, 0, 9, 0xFF, 0xFF, 0, 0, // allow null
+            4, 1, 0, 0, 10, 1, 2, // write, cut short
+        ];
+        assert_eq!(
+            parse(&data, 2),
+            vec![
+                Op::Command {
+                    process: 1,
+                    command_num: 0x12345678,
+                    arg0: 2,
+                    arg1: 9,
+                },
+                Op::Step,
+                Op::AllowReadWrite {
+                    process: 0,
+                    allow_num: 1,
+                    buffer: Buffer::Null,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! Host-side fuzzing harness for capsule system call drivers.
+//!
+//! Each fuzzer input is run against a fresh kernel with three processes and
+//! one driver, built over the mock hardware from `hil-mock`. The input is
+//! decoded into a sequence of system calls made on behalf of two of the
+//! processes, interleaved with hardware events such as operations completing
+//! or data arriving (see [`input`]). The processes never run: system calls go
+//! straight to `Kernel::handle_syscall_external`, so they pass through the
+//! same dispatch and buffer validation as on hardware.
+//!
+//! Besides panics in the driver, the harness looks for two kinds of bugs
+//! once the input has run:
+//!
+//! - Livelock: completing the outstanding hardware operations must leave the
+//!   hardware idle within a bounded number of steps.
+//! - Deadlock: the third process, which the input never touches, must still
+//!   be able to use the driver and get its upcall (see [`Target::probe`]).
+//!
+//! The libFuzzer entry points live in the `fuzz` directory and call
+//! [`run`] with one of the [`targets`].
+
+pub mod arena;
+pub mod chip;
+pub mod harness;
+pub mod input;
+pub mod targets;
+pub mod tbf;
+
+pub use harness::{run, Harness, Target};
@@ -0,0 +1,66 @@
+//! `capsules::console` over a mock UART.
+//!
+//! Events deliver one received byte each.
+
+use capsules::console::{Console, DRIVER_NUM};
+use hil_mock::uart::MockUart;
+use kernel::capabilities;
+use kernel::create_capability;
+use kernel::hil::uart::{Receive, Transmit};
+use kernel::{Driver, Kernel};
+
+use crate::arena::Arena;
+use crate::harness::{succeeded, Harness, Target, PROBE_PROCESS};
+use crate::input::{Buffer, UpcallFn};
+
+pub struct ConsoleTarget {
+    uart: &'static MockUart<'static>,
+    console: &'static Console<'static>,
+}
+
+impl Target for ConsoleTarget {
+    const DRIVER_NUM: usize = DRIVER_NUM;
+
+    unsafe fn create(kernel: &'static Kernel, arena: &Arena) -> &'static Self {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let uart = arena.alloc(MockUart::new());
+        let console = arena.alloc(Console::new(
+            uart,
+            arena.buffer(64),
+            arena.buffer(64),
+            kernel.create_grant(DRIVER_NUM, &grant_cap),
+        ));
+        uart.set_transmit_client(console);
+        uart.set_receive_client(console);
+        arena.alloc(ConsoleTarget { uart, console })
+    }
+
+    fn driver(&self) -> &dyn Driver {
+        self.console
+    }
+
+    fn step(&self) -> bool {
+        self.uart.complete_transmit() || self.uart.complete_receive_abort()
+    }
+
+    fn event(&self, event: u8) {
+        self.uart.receive(&[event]);
+    }
+
+    /// Write four bytes and wait for the write callback.
+    fn probe(harness: &Harness<Self>) {
+        let p = PROBE_PROCESS;
+        harness.write_memory(p, 0, b"ping");
+        let buffer = Buffer::Memory { offset: 0, len: 4 };
+        assert!(succeeded(&harness.allow_readonly(p, 1, buffer)));
+        assert!(succeeded(&harness.subscribe(p, 1, UpcallFn::Valid(0))));
+        assert!(succeeded(&harness.command(p, 1, 4, 0)));
+        harness.settle();
+        let upcalls = harness.take_upcalls(p);
+        assert!(
+            upcalls.iter().any(|&(num, args)| num == 1 && args[0] == 4),
+            "deadlock: console write never completed, upcalls {:?}",
+            upcalls
+        );
+    }
+}
@@ -0,0 +1,6 @@
+//! The drivers the harness can fuzz.
+
+pub mod console;
+pub mod nonvolatile_storage;
+pub mod screen;
+pub mod udp;
@@ -0,0 +1,114 @@
+//! `capsules::nonvolatile_storage_driver` over a mock flash.
+//!
+//! Events make the next flash operation fail.
+
+use capsules::nonvolatile_storage_driver::{
+    NonvolatileStorage, StorageGrant, DRIVER_NUM, REGION_TABLE_LENGTH,
+};
+use capsules::nonvolatile_to_pages::NonvolatileToPages;
+use hil_mock::flash::{MockFlash, MockPage, PAGE_SIZE};
+use kernel::capabilities;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::{Driver, Kernel};
+
+use crate::arena::Arena;
+use crate::harness::{succeeded, Harness, Target, PROBE_PROCESS};
+use crate::input::{Buffer, UpcallFn};
+
+const FLASH_PAGES: usize = 16;
+const USERSPACE_LENGTH: usize = 12 * PAGE_SIZE;
+const KERNEL_LENGTH: usize = FLASH_PAGES * PAGE_SIZE - USERSPACE_LENGTH;
+
+/// Storage for the probe process and one other, so inputs reach both
+/// granted and ungranted processes.
+const STORAGE_GRANTS: &[StorageGrant] = &[
+    StorageGrant {
+        package_name: "fuzz0",
+        storage_id: 1,
+    },
+    StorageGrant {
+        package_name: "fuzz2",
+        storage_id: 2,
+    },
+];
+
+pub struct NonvolatileStorageTarget {
+    flash: &'static MockFlash<'static>,
+    storage: &'static NonvolatileStorage<'static>,
+}
+
+impl Target for NonvolatileStorageTarget {
+    const DRIVER_NUM: usize = DRIVER_NUM;
+
+    unsafe fn create(kernel: &'static Kernel, arena: &Arena) -> &'static Self {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let flash = arena.alloc(MockFlash::new(FLASH_PAGES));
+        let pages = arena.alloc(NonvolatileToPages::new(
+            flash,
+            arena.alloc(MockPage::default()),
+        ));
+        hil::flash::HasClient::set_client(flash, pages);
+        let storage = arena.alloc(NonvolatileStorage::new(
+            pages,
+            kernel.create_grant(DRIVER_NUM, &grant_cap),
+            0,
+            USERSPACE_LENGTH,
+            USERSPACE_LENGTH,
+            KERNEL_LENGTH,
+            PAGE_SIZE,
+            STORAGE_GRANTS,
+            arena.buffer(512),
+            arena.buffer(REGION_TABLE_LENGTH),
+        ));
+        hil::nonvolatile_storage::NonvolatileStorage::set_client(pages, storage);
+        arena.alloc(NonvolatileStorageTarget { flash, storage })
+    }
+
+    fn driver(&self) -> &dyn Driver {
+        self.storage
+    }
+
+    fn step(&self) -> bool {
+        self.flash.complete()
+    }
+
+    fn event(&self, _event: u8) {
+        self.flash.fail_next();
+    }
+
+    /// Write a few bytes to the start of the probe's region, then read them
+    /// back. The data is not compared, as the input may have left a failure
+    /// injected for the next flash operation.
+    fn probe(harness: &Harness<Self>) {
+        let p = PROBE_PROCESS;
+        let buffer = Buffer::Memory { offset: 0, len: 8 };
+        harness.write_memory(p, 0, b"nvprobe!");
+        assert!(succeeded(&harness.allow_readonly(p, 0, buffer)));
+        assert!(succeeded(&harness.allow_readwrite(
+            p,
+            0,
+            Buffer::Memory { offset: 8, len: 8 }
+        )));
+        assert!(succeeded(&harness.subscribe(p, 0, UpcallFn::Valid(0))));
+        assert!(succeeded(&harness.subscribe(p, 1, UpcallFn::Valid(1))));
+
+        assert!(succeeded(&harness.command(p, 3, 0, 8)));
+        harness.settle();
+        let upcalls = harness.take_upcalls(p);
+        assert!(
+            upcalls.iter().any(|&(num, _)| num == 1),
+            "deadlock: nonvolatile storage write never completed, upcalls {:?}",
+            upcalls
+        );
+
+        assert!(succeeded(&harness.command(p, 2, 0, 8)));
+        harness.settle();
+        let upcalls = harness.take_upcalls(p);
+        assert!(
+            upcalls.iter().any(|&(num, _)| num == 0),
+            "deadlock: nonvolatile storage read never completed, upcalls {:?}",
+            upcalls
+        );
+    }
+}
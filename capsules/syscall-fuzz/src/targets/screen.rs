@@ -0,0 +1,67 @@
+//! `capsules::screen` over a mock screen with setup support.
+//!
+//! Events complete the outstanding screen operation with an error.
+
+use capsules::screen::{Screen, DRIVER_NUM};
+use hil_mock::screen::MockScreen;
+use kernel::capabilities;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::{Driver, ErrorCode, Kernel};
+
+use crate::arena::Arena;
+use crate::harness::{succeeded, Harness, Target, PROBE_PROCESS};
+use crate::input::UpcallFn;
+
+pub struct ScreenTarget {
+    mock: &'static MockScreen,
+    screen: &'static Screen<'static>,
+}
+
+impl Target for ScreenTarget {
+    const DRIVER_NUM: usize = DRIVER_NUM;
+
+    unsafe fn create(kernel: &'static Kernel, arena: &Arena) -> &'static Self {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let mock = arena.alloc(MockScreen::new((32, 16)));
+        let screen = arena.alloc(Screen::new(
+            mock,
+            Some(mock),
+            arena.buffer(64),
+            kernel.create_grant(DRIVER_NUM, &grant_cap),
+        ));
+        hil::screen::Screen::set_client(mock, Some(screen));
+        hil::screen::ScreenSetup::set_client(mock, Some(screen));
+        arena.alloc(ScreenTarget { mock, screen })
+    }
+
+    fn driver(&self) -> &dyn Driver {
+        self.screen
+    }
+
+    fn start(&self) {
+        self.mock.ready();
+    }
+
+    fn step(&self) -> bool {
+        self.mock.complete(Ok(()))
+    }
+
+    fn event(&self, _event: u8) {
+        self.mock.complete(Err(ErrorCode::FAIL));
+    }
+
+    /// Set the brightness and wait for the command to complete.
+    fn probe(harness: &Harness<Self>) {
+        let p = PROBE_PROCESS;
+        assert!(succeeded(&harness.subscribe(p, 0, UpcallFn::Valid(0))));
+        assert!(succeeded(&harness.command(p, 3, 10, 0)));
+        harness.settle();
+        let upcalls = harness.take_upcalls(p);
+        assert!(
+            upcalls.iter().any(|&(num, _)| num == 0),
+            "deadlock: screen command never completed, upcalls {:?}",
+            upcalls
+        );
+    }
+}
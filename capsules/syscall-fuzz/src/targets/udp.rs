@@ -0,0 +1,232 @@
+//! `capsules::net::udp::UDPDriver` over a mock UDP sender.
+//!
+//! The sender accepts one datagram at a time and completes it when stepped.
+//! Events deliver a datagram to one of a few local ports.
+
+use core::cell::Cell;
+
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::udp::udp_port_table::{UdpPortBindingTx, UdpPortManager, MAX_NUM_BOUND_PORTS};
+use capsules::net::udp::udp_recv::UDPRecvClient;
+use capsules::net::udp::udp_send::{UDPSendClient, UDPSender};
+use capsules::net::udp::{UDPDriver, UDPHeader, DRIVER_NUM};
+use kernel::capabilities::{self, UdpDriverCapability};
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::create_capability;
+use kernel::syscall::SyscallReturn;
+use kernel::{Driver, ErrorCode, Kernel};
+
+use crate::arena::Arena;
+use crate::harness::{succeeded, Harness, Target, PROBE_PROCESS};
+use crate::input::{Buffer, UpcallFn};
+
+const MAX_PAYLOAD_LEN: usize = 64;
+const INTERFACE: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+const REMOTE: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+const FIRST_PORT: u16 = 9000;
+
+/// Length of a serialized `UDPEndpoint`: an address and a port.
+const ENDPOINT_LEN: usize = 18;
+
+struct DriverCap;
+unsafe impl UdpDriverCapability for DriverCap {}
+static DRIVER_CAP: DriverCap = DriverCap;
+
+/// A UDP layer that only ever sends on behalf of the userspace driver.
+struct MockSender {
+    client: OptionalCell<&'static dyn UDPSendClient>,
+    datagram: MapCell<LeasableBuffer<'static, u8>>,
+    sent: Cell<usize>,
+}
+
+impl MockSender {
+    fn complete(&self) -> bool {
+        self.datagram
+            .take()
+            .map(|datagram| {
+                self.sent.set(self.sent.get() + 1);
+                self.client
+                    .map(move |client| client.send_done(Ok(()), datagram));
+            })
+            .is_some()
+    }
+}
+
+impl UDPSender<'static> for MockSender {
+    fn set_client(&self, client: &'static dyn UDPSendClient) {
+        self.client.set(client);
+    }
+
+    fn send_to(
+        &'static self,
+        _dest: IPAddr,
+        _dst_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    fn driver_send_to(
+        &'static self,
+        _dest: IPAddr,
+        _dst_port: u16,
+        _src_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _driver_send_cap: &dyn UdpDriverCapability,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        if self.datagram.is_some() {
+            return Err(buf);
+        }
+        self.datagram.replace(buf);
+        Ok(())
+    }
+
+    fn send(
+        &'static self,
+        _dest: IPAddr,
+        _udp_header: UDPHeader,
+        buf: LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    fn get_binding(&self) -> Option<UdpPortBindingTx> {
+        None
+    }
+
+    fn is_bound(&self) -> bool {
+        false
+    }
+
+    fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx> {
+        Some(binding)
+    }
+}
+
+pub struct UdpTarget {
+    sender: &'static MockSender,
+    driver: &'static UDPDriver<'static>,
+}
+
+fn endpoint(addr: IPAddr, port: u16) -> [u8; ENDPOINT_LEN] {
+    let mut endpoint = [0; ENDPOINT_LEN];
+    endpoint[..16].copy_from_slice(&addr.0);
+    endpoint[16..].copy_from_slice(&port.to_le_bytes());
+    endpoint
+}
+
+impl Target for UdpTarget {
+    const DRIVER_NUM: usize = DRIVER_NUM;
+
+    unsafe fn create(kernel: &'static Kernel, arena: &Arena) -> &'static Self {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let table_cap = create_capability!(capabilities::CreatePortTableCapability);
+
+        let udp_vis = arena.alloc(UdpVisibilityCapability::new(&create_cap));
+        let port_table = arena.alloc(UdpPortManager::new(
+            &table_cap,
+            arena.alloc([None; MAX_NUM_BOUND_PORTS]),
+            udp_vis,
+        ));
+        let net_cap = arena.alloc(NetworkCapability::new(
+            AddrRange::Any,
+            PortRange::Any,
+            PortRange::Any,
+            &create_cap,
+        ));
+        let sender = arena.alloc(MockSender {
+            client: OptionalCell::empty(),
+            datagram: MapCell::empty(),
+            sent: Cell::new(0),
+        });
+        let driver = arena.alloc(UDPDriver::new(
+            sender,
+            kernel.create_grant(DRIVER_NUM, &grant_cap),
+            arena.alloc([INTERFACE]),
+            MAX_PAYLOAD_LEN,
+            port_table,
+            LeasableBuffer::new(arena.buffer(MAX_PAYLOAD_LEN)),
+            &DRIVER_CAP,
+            net_cap,
+        ));
+        sender.set_client(driver);
+        port_table.set_user_ports(driver, &DRIVER_CAP);
+        arena.alloc(UdpTarget { sender, driver })
+    }
+
+    fn driver(&self) -> &dyn Driver {
+        self.driver
+    }
+
+    fn step(&self) -> bool {
+        self.sender.complete()
+    }
+
+    fn event(&self, event: u8) {
+        let payload = [event; 16];
+        self.driver.receive(
+            REMOTE,
+            INTERFACE,
+            FIRST_PORT,
+            FIRST_PORT + (event % 4) as u16,
+            &payload[..(event as usize % payload.len())],
+        );
+    }
+
+    /// Bind to a free port and send a datagram from it.
+    fn probe(harness: &Harness<Self>) {
+        let p = PROBE_PROCESS;
+        let (payload, rx_cfg, tx_cfg) = (0, 64, 128);
+
+        // The fuzzed processes may hold some of the ports already.
+        let rx_buffer = Buffer::Memory {
+            offset: rx_cfg,
+            len: 2 * ENDPOINT_LEN,
+        };
+        assert!(succeeded(&harness.allow_readwrite(p, 2, rx_buffer)));
+        let port = (FIRST_PORT..FIRST_PORT + 16)
+            .find(|&port| {
+                harness.write_memory(p, rx_cfg + ENDPOINT_LEN, &endpoint(INTERFACE, port));
+                match harness.command(p, 3, 0, 0) {
+                    SyscallReturn::Failure(ErrorCode::BUSY) => false,
+                    rval => {
+                        assert!(succeeded(&rval), "bind failed: {:?}", rval);
+                        true
+                    }
+                }
+            })
+            .expect("no free port to bind");
+
+        harness.write_memory(p, tx_cfg, &endpoint(INTERFACE, port));
+        harness.write_memory(p, tx_cfg + ENDPOINT_LEN, &endpoint(REMOTE, FIRST_PORT));
+        harness.write_memory(p, payload, b"ping");
+        let tx_buffer = Buffer::Memory {
+            offset: tx_cfg,
+            len: 2 * ENDPOINT_LEN,
+        };
+        assert!(succeeded(&harness.allow_readwrite(p, 1, tx_buffer)));
+        let payload_buffer = Buffer::Memory {
+            offset: payload,
+            len: 4,
+        };
+        assert!(succeeded(&harness.allow_readonly(p, 0, payload_buffer)));
+        assert!(succeeded(&harness.subscribe(p, 1, UpcallFn::Valid(0))));
+        let rval = harness.command(p, 2, 0, 0);
+        assert!(succeeded(&rval), "send failed: {:?}", rval);
+        harness.settle();
+        let upcalls = harness.take_upcalls(p);
+        assert!(
+            upcalls.iter().any(|&(num, _)| num == 1),
+            "deadlock: UDP send never completed, upcalls {:?}",
+            upcalls
+        );
+    }
+}
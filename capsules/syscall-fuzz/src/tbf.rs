@@ -0,0 +1,26 @@
+//! Minimal TBF images for the fuzzed processes.
+//!
+//! The processes never run, so their images carry only a TBF header followed
+//! by [`CODE_SIZE`] bytes of "code" that upcall function pointers can point
+//! into.
+
+use tock_tbf::serialize;
+use tock_tbf::types::{TbfHeaderV2, TbfHeaderV2Main};
+
+/// Bytes of flash after the TBF header of each process.
+pub const CODE_SIZE: usize = 256;
+
+/// Build the TBF image for a process named `name` that needs
+/// `minimum_ram_size` bytes of RAM.
+pub fn app_image(name: &'static str, minimum_ram_size: u32) -> Vec<u8> {
+    let mut header = TbfHeaderV2::new(0, true);
+    header.set_main(Some(TbfHeaderV2Main::new(0, 0, minimum_ram_size)));
+    header.set_package_name(Some(name));
+
+    let total_size = serialize::tbf_header_size(&header).unwrap() + CODE_SIZE;
+    header.set_total_size(total_size as u32);
+
+    let mut image = vec![0; total_size];
+    serialize::serialize_tbf_header(&header, &mut image).unwrap();
+    image
+}
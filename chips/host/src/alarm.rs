@@ -0,0 +1,94 @@
+//! Alarm driven by the host's monotonic clock.
+//!
+//! Time is measured in microseconds since the alarm was created and wraps
+//! every 2^32 microseconds, like a free-running 32-bit hardware counter.
+
+use core::cell::Cell;
+use std::time::{Duration, Instant};
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm, Ticks, Ticks32, Time};
+use kernel::ErrorCode;
+
+pub struct HostAlarm<'a> {
+    epoch: Instant,
+    client: OptionalCell<&'a dyn time::AlarmClient>,
+    reference: Cell<u32>,
+    dt: Cell<u32>,
+    armed: Cell<bool>,
+}
+
+impl<'a> HostAlarm<'a> {
+    pub fn new() -> HostAlarm<'a> {
+        HostAlarm {
+            epoch: Instant::now(),
+            client: OptionalCell::empty(),
+            reference: Cell::new(0),
+            dt: Cell::new(0),
+            armed: Cell::new(false),
+        }
+    }
+
+    /// Ticks elapsed since the alarm's reference point.
+    fn elapsed(&self) -> u32 {
+        self.now().into_u32().wrapping_sub(self.reference.get())
+    }
+
+    pub(crate) fn is_pending(&self) -> bool {
+        self.armed.get() && self.elapsed() >= self.dt.get()
+    }
+
+    pub(crate) fn handle_interrupt(&self) {
+        self.armed.set(false);
+        self.client.map(|client| client.alarm());
+    }
+
+    /// How long the chip may sleep before the alarm fires, or `None` if the
+    /// alarm is not armed.
+    pub(crate) fn time_until_expiry(&self) -> Option<Duration> {
+        if self.armed.get() {
+            let remaining = self.dt.get().saturating_sub(self.elapsed());
+            Some(Duration::from_micros(remaining as u64))
+        } else {
+            None
+        }
+    }
+}
+
+impl Time for HostAlarm<'_> {
+    type Frequency = time::Freq1MHz;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32::from(self.epoch.elapsed().as_micros() as u32)
+    }
+}
+
+impl<'a> Alarm<'a> for HostAlarm<'a> {
+    fn set_alarm_client(&'a self, client: &'a dyn time::AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) {
+        self.reference.set(reference.into_u32());
+        self.dt.set(dt.into_u32());
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> Self::Ticks {
+        Ticks32::from(self.reference.get().wrapping_add(self.dt.get()))
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.armed.set(false);
+        Ok(())
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    fn minimum_dt(&self) -> Self::Ticks {
+        Ticks32::from(1)
+    }
+}
@@ -0,0 +1,176 @@
+//! High-level setup and "interrupt" handling for the host emulation chip.
+//!
+//! The emulated peripherals do not raise interrupts asynchronously. Instead
+//! each one reports whether it has an event pending, and
+//! `service_pending_interrupts()` runs the bottom halves of all pending
+//! peripherals on the kernel thread. The emulated CPU polls for pending
+//! events while a process runs, and host threads that produce events (such
+//! as the UART receive thread) use a [`Wakeup`] to interrupt `sleep()`.
+
+use core::fmt::Write;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use kernel::hil::time::Alarm;
+use kernel::Chip;
+
+use crate::alarm::HostAlarm;
+use crate::flash::HostFlash;
+use crate::mpu::HostMpu;
+use crate::syscall::SysCall;
+use crate::uart::HostUart;
+
+/// Wakes the kernel thread from `HostChip::sleep()`, the way a hardware
+/// interrupt wakes a sleeping MCU.
+#[derive(Clone, Default)]
+pub struct Wakeup(Arc<(Mutex<bool>, Condvar)>);
+
+impl Wakeup {
+    pub fn new() -> Wakeup {
+        Wakeup::default()
+    }
+
+    /// Signal the kernel thread. May be called from any host thread.
+    pub fn notify(&self) {
+        let (lock, condvar) = &*self.0;
+        *lock.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+
+    /// Block until `notify()` is called or `timeout` elapses. Without a
+    /// timeout this blocks until the next notification.
+    fn wait(&self, timeout: Option<Duration>) {
+        let (lock, condvar) = &*self.0;
+        let mut notified = lock.lock().unwrap();
+        match timeout {
+            Some(timeout) => {
+                if !*notified {
+                    notified = condvar.wait_timeout(notified, timeout).unwrap().0;
+                }
+            }
+            None => {
+                while !*notified {
+                    notified = condvar.wait(notified).unwrap();
+                }
+            }
+        }
+        *notified = false;
+    }
+}
+
+pub struct HostDefaultPeripherals<'a> {
+    pub uart: HostUart<'a>,
+    pub alarm: HostAlarm<'a>,
+    pub flash: HostFlash,
+    /// Checked by the emulated CPU on every access a process makes.
+    pub mpu: HostMpu,
+}
+
+impl<'a> HostDefaultPeripherals<'a> {
+    pub fn new(uart: HostUart<'a>, flash: HostFlash) -> Self {
+        Self {
+            uart,
+            alarm: HostAlarm::new(),
+            flash,
+            mpu: HostMpu::new(),
+        }
+    }
+
+    pub(crate) fn has_pending_interrupts(&self) -> bool {
+        self.uart.is_pending() || self.alarm.is_pending() || self.flash.is_pending()
+    }
+
+    fn service_pending_interrupts(&self) {
+        if self.uart.is_pending() {
+            self.uart.handle_interrupt();
+        }
+        if self.alarm.is_pending() {
+            self.alarm.handle_interrupt();
+        }
+        if self.flash.is_pending() {
+            self.flash.handle_interrupt();
+        }
+    }
+}
+
+pub struct HostChip<'a, A: 'static + Alarm<'static>> {
+    userspace_kernel_boundary: SysCall<'a>,
+    peripherals: &'a HostDefaultPeripherals<'a>,
+    scheduler_timer: kernel::VirtualSchedulerTimer<A>,
+    wakeup: Wakeup,
+}
+
+impl<'a, A: 'static + Alarm<'static>> HostChip<'a, A> {
+    /// Create the chip. `virtual_alarm` must be an alarm on the peripherals'
+    /// `HostAlarm`, and `wakeup` the same `Wakeup` the peripherals were
+    /// created with.
+    pub fn new(
+        peripherals: &'a HostDefaultPeripherals<'a>,
+        virtual_alarm: &'static A,
+        wakeup: Wakeup,
+    ) -> Self {
+        Self {
+            userspace_kernel_boundary: SysCall::new(peripherals),
+            peripherals,
+            scheduler_timer: kernel::VirtualSchedulerTimer::new(virtual_alarm),
+            wakeup,
+        }
+    }
+}
+
+impl<'a, A: 'static + Alarm<'static>> Chip for HostChip<'a, A> {
+    type MPU = HostMpu;
+    type UserspaceKernelBoundary = SysCall<'a>;
+    type SchedulerTimer = kernel::VirtualSchedulerTimer<A>;
+    type WatchDog = ();
+
+    fn service_pending_interrupts(&self) {
+        while self.peripherals.has_pending_interrupts() {
+            self.peripherals.service_pending_interrupts();
+        }
+    }
+
+    fn has_pending_interrupts(&self) -> bool {
+        self.peripherals.has_pending_interrupts()
+    }
+
+    fn mpu(&self) -> &HostMpu {
+        &self.peripherals.mpu
+    }
+
+    fn scheduler_timer(&self) -> &Self::SchedulerTimer {
+        &self.scheduler_timer
+    }
+
+    fn watchdog(&self) -> &() {
+        &()
+    }
+
+    fn userspace_kernel_boundary(&self) -> &SysCall<'a> {
+        &self.userspace_kernel_boundary
+    }
+
+    fn sleep(&self) {
+        // Sleep until a host thread has an event for us or the alarm is due.
+        self.wakeup.wait(self.peripherals.alarm.time_until_expiry());
+    }
+
+    unsafe fn atomic<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        // Events from other host threads are only ever delivered on the
+        // kernel thread, so there is nothing to mask.
+        f()
+    }
+
+    unsafe fn print_state(&self, writer: &mut dyn Write) {
+        let _ = writer.write_fmt(format_args!(
+            "\r\n---| Host Emulation State |---\r\n\
+             Host process ID: {}\r\n\
+             Alarm armed:     {}\r\n",
+            std::process::id(),
+            self.peripherals.alarm.is_armed(),
+        ));
+    }
+}
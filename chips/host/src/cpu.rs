@@ -0,0 +1,618 @@
+//! Interpreter for the RV32IMAC instruction set, which runs process code.
+//!
+//! Processes are RISC-V binaries, as on the OpenTitan boards, and run in user
+//! mode: the base integer instructions and the M, A and C extensions are
+//! implemented, and the only CSRs are the read-only `cycle`, `time` and
+//! `instret` counters, which all count retired instructions.
+//!
+//! The CPU shares the host's memory. A process address is used as a host
+//! address once the MPU (see [`crate::mpu`]) has allowed the access, so
+//! process flash and memory must be mapped below 4 GiB (see
+//! [`crate::memory`]). As on hardware, an exception leaves the program counter
+//! at the instruction that raised it.
+
+use crate::mpu::{Access, HostMpu};
+
+/// Exceptions a process can raise, with their RISC-V cause codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    InstructionFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadMisaligned = 4,
+    LoadFault = 5,
+    StoreMisaligned = 6,
+    StoreFault = 7,
+    UserEnvCall = 8,
+}
+
+impl Exception {
+    pub fn from_cause(cause: u32) -> Option<Exception> {
+        Some(match cause {
+            1 => Exception::InstructionFault,
+            2 => Exception::IllegalInstruction,
+            3 => Exception::Breakpoint,
+            4 => Exception::LoadMisaligned,
+            5 => Exception::LoadFault,
+            6 => Exception::StoreMisaligned,
+            7 => Exception::StoreFault,
+            8 => Exception::UserEnvCall,
+            _ => return None,
+        })
+    }
+}
+
+/// An exception and the value of `mtval` for it: the faulting address for
+/// access faults, the instruction for illegal instructions, and 0 otherwise.
+pub type Trap = (Exception, u32);
+
+/// Architectural state of the CPU while it runs a process.
+#[derive(Clone, Default)]
+pub struct Hart {
+    /// `x0` to `x31`. `x0` is always 0.
+    pub x: [u32; 32],
+    pub pc: u32,
+    pub instret: u64,
+    /// Address reserved by the last `lr.w`.
+    reservation: Option<u32>,
+}
+
+fn bits(value: u32, high: u32, low: u32) -> u32 {
+    (value >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+/// Sign-extend the low `width` bits of `value`.
+fn sign_extend(value: u32, width: u32) -> u32 {
+    let shift = 32 - width;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+// Encoders for the 32-bit instructions compressed instructions expand to.
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    imm << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    bits(imm, 11, 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | bits(imm, 4, 0) << 7 | 0x23
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    bits(imm, 12, 12) << 31
+        | bits(imm, 10, 5) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | bits(imm, 4, 1) << 8
+        | bits(imm, 11, 11) << 7
+        | 0x63
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    bits(imm, 20, 20) << 31
+        | bits(imm, 10, 1) << 21
+        | bits(imm, 11, 11) << 20
+        | bits(imm, 19, 12) << 12
+        | rd << 7
+        | 0x6f
+}
+
+/// Expand a compressed instruction into the 32-bit instruction it stands
+/// for, or `None` if it is not a valid RV32C instruction.
+fn expand(c: u32) -> Option<u32> {
+    const ADDI: u32 = 0;
+    const SLLI: u32 = 1;
+    const SRLI: u32 = 5;
+    const ANDI: u32 = 7;
+    const LW: u32 = 2;
+    const OP_IMM: u32 = 0x13;
+    const OP: u32 = 0x33;
+
+    // Registers x8 to x15, encoded in three bits.
+    let rd_short = 8 + bits(c, 4, 2);
+    let rs1_short = 8 + bits(c, 9, 7);
+    let rd = bits(c, 11, 7);
+    let rs2 = bits(c, 6, 2);
+    let imm6 = sign_extend(bits(c, 12, 12) << 5 | bits(c, 6, 2), 6);
+    let j_offset = sign_extend(
+        bits(c, 12, 12) << 11
+            | bits(c, 11, 11) << 4
+            | bits(c, 10, 9) << 8
+            | bits(c, 8, 8) << 10
+            | bits(c, 7, 7) << 6
+            | bits(c, 6, 6) << 7
+            | bits(c, 5, 3) << 1
+            | bits(c, 2, 2) << 5,
+        12,
+    );
+    let b_offset = sign_extend(
+        bits(c, 12, 12) << 8
+            | bits(c, 11, 10) << 3
+            | bits(c, 6, 5) << 6
+            | bits(c, 4, 3) << 1
+            | bits(c, 2, 2) << 5,
+        9,
+    );
+    let lw_offset = bits(c, 12, 10) << 3 | bits(c, 6, 6) << 2 | bits(c, 5, 5) << 6;
+
+    Some(match (c & 3, bits(c, 15, 13)) {
+        // c.addi4spn
+        (0, 0b000) => {
+            let imm = bits(c, 12, 11) << 4
+                | bits(c, 10, 7) << 6
+                | bits(c, 6, 6) << 2
+                | bits(c, 5, 5) << 3;
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm, 2, ADDI, rd_short, OP_IMM)
+        }
+        // c.lw
+        (0, 0b010) => i_type(lw_offset, rs1_short, LW, rd_short, 0x03),
+        // c.sw
+        (0, 0b110) => s_type(lw_offset, rd_short, rs1_short, LW),
+        // c.addi, c.nop
+        (1, 0b000) => i_type(imm6 & 0xfff, rd, ADDI, rd, OP_IMM),
+        // c.jal
+        (1, 0b001) => j_type(j_offset, 1),
+        // c.li
+        (1, 0b010) => i_type(imm6 & 0xfff, 0, ADDI, rd, OP_IMM),
+        // c.addi16sp
+        (1, 0b011) if rd == 2 => {
+            let imm = sign_extend(
+                bits(c, 12, 12) << 9
+                    | bits(c, 6, 6) << 4
+                    | bits(c, 5, 5) << 6
+                    | bits(c, 4, 3) << 7
+                    | bits(c, 2, 2) << 5,
+                10,
+            );
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm & 0xfff, 2, ADDI, 2, OP_IMM)
+        }
+        // c.lui
+        (1, 0b011) => {
+            if imm6 == 0 {
+                return None;
+            }
+            imm6 << 12 | rd << 7 | 0x37
+        }
+        (1, 0b100) => match bits(c, 11, 10) {
+            // c.srli, c.srai; shift amounts of 32 and more are RV64 only.
+            0b00 | 0b01 if bits(c, 12, 12) == 0 => {
+                let funct7 = bits(c, 10, 10) << 5;
+                i_type(funct7 << 5 | rs2, rs1_short, SRLI, rs1_short, OP_IMM)
+            }
+            // c.andi
+            0b10 => i_type(imm6 & 0xfff, rs1_short, ANDI, rs1_short, OP_IMM),
+            // c.sub, c.xor, c.or, c.and
+            0b11 if bits(c, 12, 12) == 0 => {
+                let (funct7, funct3) = match bits(c, 6, 5) {
+                    0b00 => (0x20, 0),
+                    0b01 => (0, 4),
+                    0b10 => (0, 6),
+                    _ => (0, 7),
+                };
+                r_type(funct7, rd_short, rs1_short, funct3, rs1_short, OP)
+            }
+            _ => return None,
+        },
+        // c.j
+        (1, 0b101) => j_type(j_offset, 0),
+        // c.beqz, c.bnez
+        (1, 0b110) => b_type(b_offset, 0, rs1_short, 0),
+        (1, 0b111) => b_type(b_offset, 0, rs1_short, 1),
+        // c.slli
+        (2, 0b000) if bits(c, 12, 12) == 0 => i_type(rs2, rd, SLLI, rd, OP_IMM),
+        // c.lwsp
+        (2, 0b010) if rd != 0 => {
+            let imm = bits(c, 12, 12) << 5 | bits(c, 6, 4) << 2 | bits(c, 3, 2) << 6;
+            i_type(imm, 2, LW, rd, 0x03)
+        }
+        (2, 0b100) => match (bits(c, 12, 12), rd, rs2) {
+            // c.jr
+            (0, 1..=31, 0) => i_type(0, rd, 0, 0, 0x67),
+            // c.mv
+            (0, _, _) if rs2 != 0 => r_type(0, rs2, 0, 0, rd, OP),
+            // c.ebreak
+            (1, 0, 0) => 0x0010_0073,
+            // c.jalr
+            (1, _, 0) => i_type(0, rd, 0, 1, 0x67),
+            // c.add
+            (1, _, _) => r_type(0, rs2, rd, 0, rd, OP),
+            _ => return None,
+        },
+        // c.swsp
+        (2, 0b110) => {
+            let imm = bits(c, 12, 9) << 2 | bits(c, 8, 7) << 6;
+            s_type(imm, rs2, 2, LW)
+        }
+        _ => return None,
+    })
+}
+
+impl Hart {
+    fn set(&mut self, rd: u32, value: u32) {
+        if rd != 0 {
+            self.x[rd as usize] = value;
+        }
+    }
+
+    fn load(&self, mpu: &HostMpu, address: u32, len: u32, access: Access) -> Result<u32, Trap> {
+        let exception = match access {
+            Access::Execute => Exception::InstructionFault,
+            _ => Exception::LoadFault,
+        };
+        if !mpu.allows(address, len, access) {
+            return Err((exception, address));
+        }
+        let pointer = address as usize as *const u8;
+        // Safety: the MPU only allows accesses to flash and memory the kernel
+        // gave the process, which the board has mapped.
+        Ok(unsafe {
+            match len {
+                1 => pointer.read() as u32,
+                2 => u16::from_le((pointer as *const u16).read_unaligned()) as u32,
+                _ => u32::from_le((pointer as *const u32).read_unaligned()),
+            }
+        })
+    }
+
+    fn store(&mut self, mpu: &HostMpu, address: u32, len: u32, value: u32) -> Result<(), Trap> {
+        if !mpu.allows(address, len, Access::Write) {
+            return Err((Exception::StoreFault, address));
+        }
+        // A store by this hart breaks its own reservation too, which is
+        // allowed, and is the only way a reservation can be broken as there
+        // is a single hart.
+        if self.reservation == Some(address & !3) {
+            self.reservation = None;
+        }
+        let pointer = address as usize as *mut u8;
+        // Safety: as for `load()`.
+        unsafe {
+            match len {
+                1 => pointer.write(value as u8),
+                2 => (pointer as *mut u16).write_unaligned((value as u16).to_le()),
+                _ => (pointer as *mut u32).write_unaligned(value.to_le()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch the instruction at `pc`, expanding compressed instructions.
+    /// Returns the instruction and its length.
+    fn fetch(&self, mpu: &HostMpu) -> Result<(u32, u32), Trap> {
+        let low = self.load(mpu, self.pc, 2, Access::Execute)?;
+        if low & 3 != 3 {
+            let instruction = expand(low).ok_or((Exception::IllegalInstruction, low))?;
+            return Ok((instruction, 2));
+        }
+        let high = self.load(mpu, self.pc.wrapping_add(2), 2, Access::Execute)?;
+        Ok((high << 16 | low, 4))
+    }
+
+    /// Execute one instruction.
+    pub fn step(&mut self, mpu: &HostMpu) -> Result<(), Trap> {
+        let (instruction, len) = self.fetch(mpu)?;
+        let illegal = (Exception::IllegalInstruction, instruction);
+
+        let opcode = bits(instruction, 6, 0);
+        let rd = bits(instruction, 11, 7);
+        let funct3 = bits(instruction, 14, 12);
+        let rs1 = bits(instruction, 19, 15);
+        let rs2 = bits(instruction, 24, 20);
+        let funct7 = bits(instruction, 31, 25);
+        let a = self.x[rs1 as usize];
+        let b = self.x[rs2 as usize];
+        let imm_i = sign_extend(instruction >> 20, 12);
+        let imm_s = sign_extend(funct7 << 5 | rd, 12);
+        let imm_b = sign_extend(
+            bits(instruction, 31, 31) << 12
+                | bits(instruction, 7, 7) << 11
+                | bits(instruction, 30, 25) << 5
+                | bits(instruction, 11, 8) << 1,
+            13,
+        );
+        let imm_j = sign_extend(
+            bits(instruction, 31, 31) << 20
+                | bits(instruction, 19, 12) << 12
+                | bits(instruction, 20, 20) << 11
+                | bits(instruction, 30, 21) << 1,
+            21,
+        );
+
+        let mut next_pc = self.pc.wrapping_add(len);
+        match opcode {
+            // lui
+            0x37 => self.set(rd, instruction & 0xffff_f000),
+            // auipc
+            0x17 => self.set(rd, self.pc.wrapping_add(instruction & 0xffff_f000)),
+            // jal
+            0x6f => {
+                self.set(rd, next_pc);
+                next_pc = self.pc.wrapping_add(imm_j);
+            }
+            // jalr
+            0x67 if funct3 == 0 => {
+                self.set(rd, next_pc);
+                next_pc = a.wrapping_add(imm_i) & !1;
+            }
+            // Branches
+            0x63 => {
+                let taken = match funct3 {
+                    0 => a == b,
+                    1 => a != b,
+                    4 => (a as i32) < (b as i32),
+                    5 => (a as i32) >= (b as i32),
+                    6 => a < b,
+                    7 => a >= b,
+                    _ => return Err(illegal),
+                };
+                if taken {
+                    next_pc = self.pc.wrapping_add(imm_b);
+                }
+            }
+            // Loads
+            0x03 => {
+                let address = a.wrapping_add(imm_i);
+                let value = match funct3 {
+                    0 => sign_extend(self.load(mpu, address, 1, Access::Read)?, 8),
+                    1 => sign_extend(self.load(mpu, address, 2, Access::Read)?, 16),
+                    2 => self.load(mpu, address, 4, Access::Read)?,
+                    4 => self.load(mpu, address, 1, Access::Read)?,
+                    5 => self.load(mpu, address, 2, Access::Read)?,
+                    _ => return Err(illegal),
+                };
+                self.set(rd, value);
+            }
+            // Stores
+            0x23 => {
+                let address = a.wrapping_add(imm_s);
+                match funct3 {
+                    0 => self.store(mpu, address, 1, b)?,
+                    1 => self.store(mpu, address, 2, b)?,
+                    2 => self.store(mpu, address, 4, b)?,
+                    _ => return Err(illegal),
+                }
+            }
+            // Register-immediate operations
+            0x13 => {
+                let shamt = rs2;
+                let value = match (funct3, funct7) {
+                    (0, _) => a.wrapping_add(imm_i),
+                    (2, _) => ((a as i32) < (imm_i as i32)) as u32,
+                    (3, _) => (a < imm_i) as u32,
+                    (4, _) => a ^ imm_i,
+                    (6, _) => a | imm_i,
+                    (7, _) => a & imm_i,
+                    (1, 0x00) => a << shamt,
+                    (5, 0x00) => a >> shamt,
+                    (5, 0x20) => ((a as i32) >> shamt) as u32,
+                    _ => return Err(illegal),
+                };
+                self.set(rd, value);
+            }
+            // Register-register operations
+            0x33 => {
+                let value = match (funct7, funct3) {
+                    (0x00, 0) => a.wrapping_add(b),
+                    (0x20, 0) => a.wrapping_sub(b),
+                    (0x00, 1) => a << (b & 31),
+                    (0x00, 2) => ((a as i32) < (b as i32)) as u32,
+                    (0x00, 3) => (a < b) as u32,
+                    (0x00, 4) => a ^ b,
+                    (0x00, 5) => a >> (b & 31),
+                    (0x20, 5) => ((a as i32) >> (b & 31)) as u32,
+                    (0x00, 6) => a | b,
+                    (0x00, 7) => a & b,
+                    (0x01, funct3) => multiply(funct3, a, b),
+                    _ => return Err(illegal),
+                };
+                self.set(rd, value);
+            }
+            // fence, fence.i: there is a single hart and no cache.
+            0x0f if funct3 <= 1 => {}
+            // Atomics
+            0x2f if funct3 == 2 => self.atomic(mpu, instruction, a, b)?,
+            0x73 => match (instruction, funct3) {
+                (0x0000_0073, _) => return Err((Exception::UserEnvCall, 0)),
+                (0x0010_0073, _) => return Err((Exception::Breakpoint, 0)),
+                // csrrs and csrrc (or their immediate forms) of the counters
+                // with no bits to change are reads.
+                (_, 2) | (_, 3) | (_, 6) | (_, 7) if rs1 == 0 => {
+                    let value = match instruction >> 20 {
+                        0xc00 | 0xc01 | 0xc02 => self.instret as u32,
+                        0xc80 | 0xc81 | 0xc82 => (self.instret >> 32) as u32,
+                        _ => return Err(illegal),
+                    };
+                    self.set(rd, value);
+                }
+                _ => return Err(illegal),
+            },
+            _ => return Err(illegal),
+        }
+
+        self.pc = next_pc;
+        self.instret += 1;
+        Ok(())
+    }
+
+    fn atomic(
+        &mut self,
+        mpu: &HostMpu,
+        instruction: u32,
+        address: u32,
+        b: u32,
+    ) -> Result<(), Trap> {
+        let rd = bits(instruction, 11, 7);
+        let funct5 = bits(instruction, 31, 27);
+        match funct5 {
+            // lr.w
+            0x02 => {
+                if address & 3 != 0 {
+                    return Err((Exception::LoadMisaligned, address));
+                }
+                let value = self.load(mpu, address, 4, Access::Read)?;
+                self.reservation = Some(address);
+                self.set(rd, value);
+            }
+            // sc.w
+            0x03 => {
+                if address & 3 != 0 {
+                    return Err((Exception::StoreMisaligned, address));
+                }
+                if self.reservation == Some(address) {
+                    self.store(mpu, address, 4, b)?;
+                    self.set(rd, 0);
+                } else {
+                    self.set(rd, 1);
+                }
+                self.reservation = None;
+            }
+            _ => {
+                if address & 3 != 0 {
+                    return Err((Exception::StoreMisaligned, address));
+                }
+                if !mpu.allows(address, 4, Access::Read) {
+                    return Err((Exception::StoreFault, address));
+                }
+                let old = self.load(mpu, address, 4, Access::Read)?;
+                let new = match funct5 {
+                    0x01 => b,
+                    0x00 => old.wrapping_add(b),
+                    0x04 => old ^ b,
+                    0x0c => old & b,
+                    0x08 => old | b,
+                    0x10 => (old as i32).min(b as i32) as u32,
+                    0x14 => (old as i32).max(b as i32) as u32,
+                    0x18 => old.min(b),
+                    0x1c => old.max(b),
+                    _ => return Err((Exception::IllegalInstruction, instruction)),
+                };
+                self.store(mpu, address, 4, new)?;
+                self.set(rd, old);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The M extension operation with `funct3`.
+fn multiply(funct3: u32, a: u32, b: u32) -> u32 {
+    let (sa, sb) = (a as i32, b as i32);
+    match funct3 {
+        0 => a.wrapping_mul(b),
+        1 => ((sa as i64 * sb as i64) >> 32) as u32,
+        2 => ((sa as i64 * b as i64) >> 32) as u32,
+        3 => ((a as u64 * b as u64) >> 32) as u32,
+        4 => match sb {
+            0 => u32::MAX,
+            _ => sa.wrapping_div(sb) as u32,
+        },
+        5 => match b {
+            0 => u32::MAX,
+            _ => a / b,
+        },
+        6 => match sb {
+            0 => a,
+            _ => sa.wrapping_rem(sb) as u32,
+        },
+        _ => match b {
+            0 => a,
+            _ => a % b,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpu::HostMpuConfig;
+    use kernel::mpu::{Permissions, MPU};
+
+    #[test]
+    fn compressed_instructions_expand() {
+        // c.mv s0, a0
+        assert_eq!(expand(0x842a), Some(r_type(0, 10, 0, 0, 8, 0x33)));
+        // c.li a0, 5
+        assert_eq!(expand(0x4515), Some(i_type(5, 0, 0, 10, 0x13)));
+        // c.jr ra
+        assert_eq!(expand(0x8082), Some(i_type(0, 1, 0, 0, 0x67)));
+        // c.lui t0, 1
+        assert_eq!(expand(0x6285), Some(1 << 12 | 5 << 7 | 0x37));
+        // All zeros is defined to be illegal.
+        assert_eq!(expand(0x0000), None);
+    }
+
+    #[test]
+    fn division_follows_the_spec() {
+        assert_eq!(multiply(4, 7, 0), u32::MAX);
+        assert_eq!(multiply(5, 7, 0), u32::MAX);
+        assert_eq!(multiply(6, 7, 0), 7);
+        assert_eq!(multiply(7, 7, 0), 7);
+        let min = i32::MIN as u32;
+        assert_eq!(multiply(4, min, -1i32 as u32), min);
+        assert_eq!(multiply(6, min, -1i32 as u32), 0);
+        assert_eq!(multiply(4, -7i32 as u32, 2), -3i32 as u32);
+        assert_eq!(multiply(1, -1i32 as u32, -1i32 as u32), 0);
+        assert_eq!(multiply(3, u32::MAX, u32::MAX), u32::MAX - 1);
+    }
+
+    #[test]
+    fn program_runs_within_its_mpu_regions() {
+        const BASE: u32 = 0x3f00_0000;
+        let memory = crate::memory::map_fixed(BASE as usize, 0x1000).unwrap();
+        let program: &[u32] = &[
+            BASE | 5 << 7 | 0x37,          // lui t0, BASE
+            i_type(7, 0, 0, 10, 0x13),     // li a0, 7
+            i_type(5, 10, 0, 11, 0x13),    // addi a1, a0, 5
+            s_type(0x100, 11, 5, 2),       // sw a1, 0x100(t0)
+            i_type(0x100, 5, 2, 12, 0x03), // lw a2, 0x100(t0)
+            0x73,                          // ecall
+            s_type(0x400, 11, 5, 2),       // sw a1, 0x400(t0)
+        ];
+        for (word, instruction) in memory.chunks_exact_mut(4).zip(program) {
+            word.copy_from_slice(&instruction.to_le_bytes());
+        }
+
+        let mpu = HostMpu::new();
+        let mut config = HostMpuConfig::default();
+        mpu.allocate_region(
+            BASE as *const u8,
+            0x1000,
+            0x400,
+            Permissions::ReadWriteExecute,
+            &mut config,
+        )
+        .unwrap();
+        let mpu = HostMpu::with_config(config);
+
+        let mut hart = Hart {
+            pc: BASE,
+            ..Hart::default()
+        };
+        let trap = loop {
+            if let Err(trap) = hart.step(&mpu) {
+                break trap;
+            }
+        };
+        assert_eq!(trap, (Exception::UserEnvCall, 0));
+        assert_eq!(hart.pc, BASE + 20);
+        assert_eq!(hart.x[12], 12);
+        assert_eq!(hart.instret, 5);
+
+        // The store is past the end of the region, so it faults without
+        // changing memory, and the pc stays on it.
+        hart.pc += 4;
+        assert_eq!(hart.step(&mpu), Err((Exception::StoreFault, BASE + 0x400)));
+        assert_eq!(hart.pc, BASE + 24);
+        assert_eq!(memory[0x400], 0);
+    }
+}
@@ -0,0 +1,242 @@
+//! Flash emulated with a file (or host memory).
+//!
+//! Operations are carried out immediately on the backing store; the
+//! completion callback is delivered from the next interrupt service pass, as
+//! it would be by a flash controller. Erased flash reads as `0xFF`, and a
+//! file that is shorter than the flash is extended with erased pages.
+//!
+//! ```rust,ignore
+//! let flash = host::flash::HostFlash::open("flash.bin", 64)?;
+//! let pagebuffer = static_init!(host::flash::HostPage, host::flash::HostPage::default());
+//! ```
+
+use core::cell::{Cell, RefCell};
+use core::ops::{Index, IndexMut};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ErrorCode;
+
+pub const PAGE_SIZE: usize = 512;
+
+/// Value of an erased flash byte.
+const ERASED: u8 = 0xFF;
+
+pub struct HostPage(pub [u8; PAGE_SIZE]);
+
+impl Default for HostPage {
+    fn default() -> Self {
+        Self { 0: [0; PAGE_SIZE] }
+    }
+}
+
+impl Index<usize> for HostPage {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        &self.0[idx]
+    }
+}
+
+impl IndexMut<usize> for HostPage {
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        &mut self.0[idx]
+    }
+}
+
+impl AsMut<[u8]> for HostPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+enum Backing {
+    File(File),
+    Memory(Vec<u8>),
+}
+
+impl Backing {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            Backing::File(file) => {
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.read_exact(buf)
+            }
+            Backing::Memory(memory) => {
+                buf.copy_from_slice(&memory[offset..offset + buf.len()]);
+                Ok(())
+            }
+        }
+    }
+
+    fn write(&mut self, offset: usize, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Backing::File(file) => {
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.write_all(buf)?;
+                file.flush()
+            }
+            Backing::Memory(memory) => {
+                memory[offset..offset + buf.len()].copy_from_slice(buf);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The operation whose completion callback is pending.
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Read,
+    Write,
+    Erase,
+}
+
+pub struct HostFlash {
+    backing: RefCell<Backing>,
+    num_pages: usize,
+    client: OptionalCell<&'static dyn hil::flash::Client<HostFlash>>,
+    buffer: TakeCell<'static, HostPage>,
+    pending: Cell<Option<(Operation, hil::flash::Error)>>,
+}
+
+impl HostFlash {
+    /// Emulate `num_pages` pages of flash backed by the file at `path`, so
+    /// that its contents persist across runs.
+    pub fn open<P: AsRef<Path>>(path: P, num_pages: usize) -> io::Result<HostFlash> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        let size = num_pages * PAGE_SIZE;
+        let current = file.metadata()?.len() as usize;
+        if current < size {
+            file.seek(SeekFrom::Start(current as u64))?;
+            file.write_all(&vec![ERASED; size - current])?;
+        }
+        Ok(HostFlash::with_backing(Backing::File(file), num_pages))
+    }
+
+    /// Emulate `num_pages` pages of erased flash held in host memory.
+    pub fn in_memory(num_pages: usize) -> HostFlash {
+        HostFlash::with_backing(
+            Backing::Memory(vec![ERASED; num_pages * PAGE_SIZE]),
+            num_pages,
+        )
+    }
+
+    fn with_backing(backing: Backing, num_pages: usize) -> HostFlash {
+        HostFlash {
+            backing: RefCell::new(backing),
+            num_pages,
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            pending: Cell::new(None),
+        }
+    }
+
+    /// Size of the emulated flash in bytes.
+    pub fn size(&self) -> usize {
+        self.num_pages * PAGE_SIZE
+    }
+
+    fn status(result: io::Result<()>) -> hil::flash::Error {
+        match result {
+            Ok(()) => hil::flash::Error::CommandComplete,
+            Err(_) => hil::flash::Error::FlashError,
+        }
+    }
+
+    fn check_request(&self, page_number: usize) -> Result<(), ErrorCode> {
+        if self.pending.get().is_some() {
+            Err(ErrorCode::BUSY)
+        } else if page_number >= self.num_pages {
+            Err(ErrorCode::INVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn is_pending(&self) -> bool {
+        self.pending.get().is_some()
+    }
+
+    pub(crate) fn handle_interrupt(&self) {
+        if let Some((operation, status)) = self.pending.take() {
+            self.client.map(|client| match operation {
+                Operation::Read => {
+                    self.buffer
+                        .take()
+                        .map(|buffer| client.read_complete(buffer, status));
+                }
+                Operation::Write => {
+                    self.buffer
+                        .take()
+                        .map(|buffer| client.write_complete(buffer, status));
+                }
+                Operation::Erase => client.erase_complete(status),
+            });
+        }
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for HostFlash {
+    fn set_client(&self, client: &'static C) {
+        self.client.set(client);
+    }
+}
+
+impl hil::flash::Flash for HostFlash {
+    type Page = HostPage;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if let Err(e) = self.check_request(page_number) {
+            return Err((e, buf));
+        }
+        let result = self
+            .backing
+            .borrow_mut()
+            .read(page_number * PAGE_SIZE, &mut buf.0);
+        self.buffer.replace(buf);
+        self.pending
+            .set(Some((Operation::Read, HostFlash::status(result))));
+        Ok(())
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if let Err(e) = self.check_request(page_number) {
+            return Err((e, buf));
+        }
+        let result = self
+            .backing
+            .borrow_mut()
+            .write(page_number * PAGE_SIZE, &buf.0);
+        self.buffer.replace(buf);
+        self.pending
+            .set(Some((Operation::Write, HostFlash::status(result))));
+        Ok(())
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        self.check_request(page_number)?;
+        let result = self
+            .backing
+            .borrow_mut()
+            .write(page_number * PAGE_SIZE, &[ERASED; PAGE_SIZE]);
+        self.pending
+            .set(Some((Operation::Erase, HostFlash::status(result))));
+        Ok(())
+    }
+}
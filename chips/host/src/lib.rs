@@ -0,0 +1,18 @@
+//! Chip support for running the Tock kernel as a Linux process.
+//!
+//! Unlike the other chip crates this one uses `std`: every peripheral is
+//! emulated with host facilities (stdio or a pty for the UART, the host clock
+//! for the alarm, a file for flash), and processes, which are RISC-V
+//! binaries, run on an emulated RV32IMAC CPU with an MPU. See `README.md`.
+
+#![crate_name = "host"]
+#![crate_type = "rlib"]
+
+pub mod alarm;
+pub mod chip;
+pub mod cpu;
+pub mod flash;
+pub mod memory;
+pub mod mpu;
+pub mod syscall;
+pub mod uart;
@@ -0,0 +1,61 @@
+//! Host memory at fixed addresses, for process flash and memory.
+//!
+//! The emulated CPU uses process addresses as host addresses, and the kernel
+//! hands processes host addresses, so both must fit in 32 bits. Mapping app
+//! flash and memory at the addresses a RISC-V board uses also means processes
+//! linked for that board run unchanged.
+
+use std::io;
+use std::os::raw::{c_int, c_long, c_void};
+
+const PROT_READ: c_int = 0x1;
+const PROT_WRITE: c_int = 0x2;
+const MAP_PRIVATE: c_int = 0x02;
+const MAP_ANONYMOUS: c_int = 0x20;
+const MAP_FIXED_NOREPLACE: c_int = 0x10_0000;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: c_long,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+/// Map `len` bytes of zeroed memory at `address`, which must be page aligned.
+/// Fails if anything is already mapped there.
+pub fn map_fixed(address: usize, len: usize) -> io::Result<&'static mut [u8]> {
+    let requested = address as *mut c_void;
+    // Safety: MAP_FIXED_NOREPLACE never replaces an existing mapping, so
+    // this cannot invalidate any memory in use.
+    let mapped = unsafe {
+        mmap(
+            requested,
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED_NOREPLACE,
+            -1,
+            0,
+        )
+    };
+    if mapped == MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    if mapped != requested {
+        // Kernels before Linux 4.17 ignore MAP_FIXED_NOREPLACE and treat the
+        // address as a hint.
+        unsafe { munmap(mapped, len) };
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("cannot map memory at {:#x}", address),
+        ));
+    }
+    // Safety: the mapping is new, never unmapped, and nothing else refers to
+    // it.
+    Ok(unsafe { std::slice::from_raw_parts_mut(mapped as *mut u8, len) })
+}
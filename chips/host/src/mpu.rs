@@ -0,0 +1,389 @@
+//! Memory protection for processes run by the emulated CPU.
+//!
+//! The emulated MPU has [`NUM_REGIONS`] regions of any size and alignment,
+//! plus a guard region that takes precedence over them. The CPU (see
+//! [`crate::cpu`]) checks every instruction fetch, load and store of a process
+//! against the configuration the kernel last set with `configure_mpu()`, and
+//! raises an access fault for anything no region allows. Accesses by the
+//! kernel are never checked.
+
+use core::cell::RefCell;
+use core::fmt;
+
+use kernel::mpu::{self, Permissions, Region};
+use kernel::ProcessId;
+
+/// Number of regions a configuration can hold, not counting the guard.
+pub const NUM_REGIONS: usize = 8;
+
+/// The kinds of access a process can make.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy)]
+struct HostRegion {
+    start: usize,
+    size: usize,
+    permissions: Permissions,
+}
+
+impl HostRegion {
+    fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        matches!(
+            (self.permissions, access),
+            (Permissions::ReadWriteExecute, _)
+                | (Permissions::ReadWriteOnly, Access::Read)
+                | (Permissions::ReadWriteOnly, Access::Write)
+                | (Permissions::ReadExecuteOnly, Access::Read)
+                | (Permissions::ReadExecuteOnly, Access::Execute)
+                | (Permissions::ReadOnly, Access::Read)
+                | (Permissions::ExecuteOnly, Access::Execute)
+        )
+    }
+}
+
+impl fmt::Display for HostRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (r, w, x) = (
+            self.allows(Access::Read),
+            self.allows(Access::Write),
+            self.allows(Access::Execute),
+        );
+        write!(
+            f,
+            "addr={:#010X}, size={:#010X} ({}{}{})",
+            self.start,
+            self.size,
+            if r { "r" } else { "-" },
+            if w { "w" } else { "-" },
+            if x { "x" } else { "-" },
+        )
+    }
+}
+
+/// Regions of one process.
+#[derive(Clone, Copy, Default)]
+pub struct HostMpuConfig {
+    regions: [Option<HostRegion>; NUM_REGIONS],
+    /// Index of the region covering app-owned memory, and the end of the
+    /// block that region may grow into.
+    app_memory_region: Option<(usize, usize)>,
+    /// Start and end of the guard region.
+    guard: Option<(usize, usize)>,
+}
+
+impl HostMpuConfig {
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        let app_memory_block = self.app_memory_region.and_then(|(index, block_end)| {
+            self.regions[index].map(|region| (region.start, block_end))
+        });
+        self.regions
+            .iter()
+            .flatten()
+            .map(|region| (region.start, region.end()))
+            .chain(app_memory_block)
+            .any(|(region_start, region_end)| start < region_end && region_start < end)
+    }
+
+    /// Add a region, returning its index.
+    fn add(&mut self, start: usize, size: usize, permissions: Permissions) -> Option<usize> {
+        if self.overlaps(start, start + size) {
+            return None;
+        }
+        let index = self.regions.iter().position(Option::is_none)?;
+        self.regions[index] = Some(HostRegion {
+            start,
+            size,
+            permissions,
+        });
+        Some(index)
+    }
+
+    fn allows(&self, address: usize, len: usize, access: Access) -> bool {
+        let end = match address.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        if let Some((guard_start, guard_end)) = self.guard {
+            if address < guard_end && guard_start < end {
+                return false;
+            }
+        }
+        self.regions
+            .iter()
+            .flatten()
+            .any(|region| region.start <= address && end <= region.end() && region.allows(access))
+    }
+}
+
+impl fmt::Display for HostMpuConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\r\n Host MPU")?;
+        for (i, region) in self.regions.iter().enumerate() {
+            match region {
+                Some(region) => write!(f, "\r\n  Region {}: {}", i, region)?,
+                None => write!(f, "\r\n  Region {}: <unset>", i)?,
+            }
+        }
+        if let Some((start, end)) = self.guard {
+            write!(
+                f,
+                "\r\n  Guard:    addr={:#010X}, size={:#010X}",
+                start,
+                end - start
+            )?;
+        }
+        write!(f, "\r\n")
+    }
+}
+
+pub struct HostMpu {
+    active: RefCell<HostMpuConfig>,
+}
+
+impl HostMpu {
+    pub fn new() -> HostMpu {
+        HostMpu {
+            active: RefCell::new(HostMpuConfig::default()),
+        }
+    }
+
+    /// Whether the running process may make an access of `len` bytes at
+    /// `address`.
+    pub(crate) fn allows(&self, address: u32, len: u32, access: Access) -> bool {
+        self.active
+            .borrow()
+            .allows(address as usize, len as usize, access)
+    }
+}
+
+impl mpu::MPU for HostMpu {
+    type MpuConfig = HostMpuConfig;
+
+    fn clear_mpu(&self) {
+        *self.active.borrow_mut() = HostMpuConfig::default();
+    }
+
+    fn number_total_regions(&self) -> usize {
+        NUM_REGIONS
+    }
+
+    fn allocate_region(
+        &self,
+        unallocated_memory_start: *const u8,
+        unallocated_memory_size: usize,
+        min_region_size: usize,
+        permissions: Permissions,
+        config: &mut HostMpuConfig,
+    ) -> Option<Region> {
+        if min_region_size > unallocated_memory_size {
+            return None;
+        }
+        let start = unallocated_memory_start as usize;
+        config.add(start, min_region_size, permissions)?;
+        Some(Region::new(unallocated_memory_start, min_region_size))
+    }
+
+    fn allocate_guard_region(
+        &self,
+        memory_start: *const u8,
+        region_end: *const u8,
+        min_region_size: usize,
+        config: &mut HostMpuConfig,
+    ) -> Option<Region> {
+        let end = region_end as usize;
+        let start = end.checked_sub(min_region_size)?;
+        if start < memory_start as usize {
+            return None;
+        }
+        config.guard = Some((start, end));
+        Some(Region::new(start as *const u8, min_region_size))
+    }
+
+    fn allocate_app_memory_region(
+        &self,
+        unallocated_memory_start: *const u8,
+        unallocated_memory_size: usize,
+        min_memory_size: usize,
+        initial_app_memory_size: usize,
+        initial_kernel_memory_size: usize,
+        permissions: Permissions,
+        config: &mut HostMpuConfig,
+    ) -> Option<(*const u8, usize)> {
+        if config.app_memory_region.is_some() {
+            return None;
+        }
+        let memory_size = core::cmp::max(
+            min_memory_size,
+            initial_app_memory_size + initial_kernel_memory_size,
+        );
+        if memory_size > unallocated_memory_size {
+            return None;
+        }
+        // The region may grow up to the kernel-owned memory, so keep the rest
+        // of the block free of other regions.
+        let start = unallocated_memory_start as usize;
+        if config.overlaps(start, start + memory_size) {
+            return None;
+        }
+        let index = config.add(start, initial_app_memory_size, permissions)?;
+        config.app_memory_region = Some((index, start + memory_size));
+        Some((unallocated_memory_start, memory_size))
+    }
+
+    fn update_app_memory_region(
+        &self,
+        app_memory_break: *const u8,
+        kernel_memory_break: *const u8,
+        permissions: Permissions,
+        config: &mut HostMpuConfig,
+    ) -> Result<(), ()> {
+        if (app_memory_break as usize) > (kernel_memory_break as usize) {
+            return Err(());
+        }
+        let (index, _) = config.app_memory_region.ok_or(())?;
+        let region = config.regions[index].as_mut().ok_or(())?;
+        let size = (app_memory_break as usize)
+            .checked_sub(region.start)
+            .ok_or(())?;
+        region.size = size;
+        region.permissions = permissions;
+        Ok(())
+    }
+
+    fn configure_mpu(&self, config: &HostMpuConfig, _app_id: &ProcessId) {
+        *self.active.borrow_mut() = *config;
+    }
+}
+
+#[cfg(test)]
+impl HostMpu {
+    /// An MPU with `config` active, as after `configure_mpu()`.
+    pub(crate) fn with_config(config: HostMpuConfig) -> HostMpu {
+        HostMpu {
+            active: RefCell::new(config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::mpu::MPU as _;
+
+    const MEMORY_START: usize = 0x1000_5000;
+
+    /// A config with app memory allocated at `MEMORY_START`.
+    fn app_config(mpu: &HostMpu) -> HostMpuConfig {
+        let mut config = HostMpuConfig::default();
+        let (start, size) = mpu
+            .allocate_app_memory_region(
+                MEMORY_START as *const u8,
+                0x10000,
+                0x1000,
+                0x800,
+                0x400,
+                Permissions::ReadWriteOnly,
+                &mut config,
+            )
+            .unwrap();
+        assert_eq!((start as usize, size), (MEMORY_START, 0x1000));
+        config
+    }
+
+    #[test]
+    fn app_memory_grows_with_break() {
+        let mpu = HostMpu::new();
+        let mut config = app_config(&mpu);
+        assert!(config.allows(MEMORY_START + 0x7fc, 4, Access::Write));
+        assert!(!config.allows(MEMORY_START + 0x7fe, 4, Access::Read));
+        assert!(!config.allows(MEMORY_START, 4, Access::Execute));
+
+        mpu.update_app_memory_region(
+            (MEMORY_START + 0xa00) as *const u8,
+            (MEMORY_START + 0xc00) as *const u8,
+            Permissions::ReadWriteOnly,
+            &mut config,
+        )
+        .unwrap();
+        assert!(config.allows(MEMORY_START + 0x9fc, 4, Access::Read));
+        assert!(!config.allows(MEMORY_START + 0xa00, 1, Access::Read));
+
+        // The break cannot pass kernel-owned memory.
+        assert!(mpu
+            .update_app_memory_region(
+                (MEMORY_START + 0xe00) as *const u8,
+                (MEMORY_START + 0xc00) as *const u8,
+                Permissions::ReadWriteOnly,
+                &mut config,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn regions_do_not_overlap_app_memory() {
+        let mpu = HostMpu::new();
+        let mut config = app_config(&mpu);
+        // The unused part of the app memory block is reserved for growth.
+        assert!(mpu
+            .allocate_region(
+                (MEMORY_START + 0xc00) as *const u8,
+                0x400,
+                0x100,
+                Permissions::ReadOnly,
+                &mut config,
+            )
+            .is_none());
+        let region = mpu
+            .allocate_region(
+                (MEMORY_START + 0x1000) as *const u8,
+                0x400,
+                0x100,
+                Permissions::ReadOnly,
+                &mut config,
+            )
+            .unwrap();
+        assert_eq!(region.size(), 0x100);
+        assert!(config.allows(MEMORY_START + 0x1000, 0x100, Access::Read));
+        assert!(!config.allows(MEMORY_START + 0x1000, 4, Access::Write));
+        // An access must fit in a single region.
+        assert!(!config.allows(MEMORY_START + 0x10fe, 4, Access::Read));
+    }
+
+    #[test]
+    fn guard_region_takes_precedence() {
+        let mpu = HostMpu::new();
+        let mut config = app_config(&mpu);
+        let guard = mpu
+            .allocate_guard_region(
+                MEMORY_START as *const u8,
+                (MEMORY_START + 0x200) as *const u8,
+                32,
+                &mut config,
+            )
+            .unwrap();
+        assert_eq!(guard.start_address() as usize, MEMORY_START + 0x1e0);
+        assert!(config.allows(MEMORY_START + 0x1dc, 4, Access::Write));
+        assert!(!config.allows(MEMORY_START + 0x1de, 4, Access::Write));
+        assert!(!config.allows(MEMORY_START + 0x1fc, 4, Access::Read));
+        assert!(config.allows(MEMORY_START + 0x200, 4, Access::Read));
+
+        // The guard must lie within process memory.
+        assert!(mpu
+            .allocate_guard_region(
+                MEMORY_START as *const u8,
+                (MEMORY_START + 16) as *const u8,
+                32,
+                &mut config,
+            )
+            .is_none());
+    }
+}
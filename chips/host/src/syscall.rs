@@ -0,0 +1,232 @@
+//! Kernel-userland system call interface for processes on the emulated CPU.
+//!
+//! Processes are RISC-V binaries and use the system call ABI of the RISC-V
+//! boards (see `rv32i::syscall`): `ecall` with the system call class in `a4`
+//! and the arguments in `a0` to `a3`, which also hold the return values.
+//!
+//! `switch_to_process()` runs the process on the emulated CPU (see
+//! [`crate::cpu`]) until it makes a system call or raises an exception. Every
+//! [`INTERRUPT_CHECK_INTERVAL`] instructions it also checks whether any
+//! peripheral has an interrupt pending, and if so returns to the kernel as a
+//! hardware interrupt would. The scheduler timer runs on the alarm, so this is
+//! also how a process that never makes a system call is preempted.
+
+use core::fmt::Write;
+
+use kernel::procs::FunctionCall;
+use kernel::syscall::{ContextSwitchReason, CoreRegisters, Syscall, SyscallReturn};
+use kernel::ErrorCode;
+
+use crate::chip::HostDefaultPeripherals;
+use crate::cpu::{Exception, Hart};
+
+/// How many instructions a process runs between checks for interrupts.
+pub const INTERRUPT_CHECK_INTERVAL: usize = 1024;
+
+const R_RA: usize = 1;
+const R_SP: usize = 2;
+const R_A0: usize = 10;
+const R_A4: usize = 14;
+
+/// ELF machine number for RISC-V, for core registers.
+const EM_RISCV: u16 = 243;
+
+/// The state of a process while it is not running.
+#[derive(Default)]
+pub struct HostStoredState {
+    hart: Hart,
+    /// Cause and value of the last exception, as in the `mcause` and `mtval`
+    /// CSRs.
+    mcause: u32,
+    mtval: u32,
+}
+
+pub struct SysCall<'a> {
+    peripherals: &'a HostDefaultPeripherals<'a>,
+}
+
+impl<'a> SysCall<'a> {
+    pub(crate) fn new(peripherals: &'a HostDefaultPeripherals<'a>) -> SysCall<'a> {
+        SysCall { peripherals }
+    }
+}
+
+fn exception_name(mcause: u32) -> &'static str {
+    match Exception::from_cause(mcause) {
+        Some(Exception::InstructionFault) => "Instruction access fault",
+        Some(Exception::IllegalInstruction) => "Illegal instruction",
+        Some(Exception::Breakpoint) => "Breakpoint",
+        Some(Exception::LoadMisaligned) => "Load address misaligned",
+        Some(Exception::LoadFault) => "Load access fault",
+        Some(Exception::StoreMisaligned) => "Store/AMO address misaligned",
+        Some(Exception::StoreFault) => "Store/AMO access fault",
+        Some(Exception::UserEnvCall) => "Environment call from U-mode",
+        None => "No exception",
+    }
+}
+
+impl<'a> kernel::syscall::UserspaceKernelBoundary for SysCall<'a> {
+    type StoredState = HostStoredState;
+
+    fn initial_process_app_brk_size(&self) -> usize {
+        // No context switch state lives in process memory.
+        0
+    }
+
+    unsafe fn initialize_process(
+        &self,
+        accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut Self::StoredState,
+    ) -> Result<(), ()> {
+        *state = HostStoredState::default();
+        // As on RISC-V hardware, no stack is pre-allocated; the process sets
+        // up its own.
+        state.hart.x[R_SP] = accessible_memory_start as u32;
+        Ok(())
+    }
+
+    unsafe fn set_syscall_return_value(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut Self::StoredState,
+        return_value: SyscallReturn,
+    ) -> Result<(), ()> {
+        let mut registers = [0; 4];
+        let [a0, a1, a2, a3] = &mut registers;
+        return_value.encode_syscall_return(a0, a1, a2, a3);
+        state.hart.x[R_A0..R_A0 + 4].copy_from_slice(&registers);
+        Ok(())
+    }
+
+    unsafe fn set_process_function(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut Self::StoredState,
+        upcall: FunctionCall,
+    ) -> Result<(), ()> {
+        let hart = &mut state.hart;
+        hart.x[R_A0] = upcall.argument0 as u32;
+        hart.x[R_A0 + 1] = upcall.argument1 as u32;
+        hart.x[R_A0 + 2] = upcall.argument2 as u32;
+        hart.x[R_A0 + 3] = upcall.argument3 as u32;
+        // The function returns to where the process yielded. When the process
+        // starts there is nothing to return to, and it must not return.
+        hart.x[R_RA] = hart.pc;
+        hart.pc = upcall.pc as u32;
+        Ok(())
+    }
+
+    unsafe fn switch_to_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut Self::StoredState,
+    ) -> (ContextSwitchReason, Option<*const u8>) {
+        let mpu = &self.peripherals.mpu;
+        let trap = 'run: loop {
+            for _ in 0..INTERRUPT_CHECK_INTERVAL {
+                if let Err(trap) = state.hart.step(mpu) {
+                    break 'run Some(trap);
+                }
+            }
+            if self.peripherals.has_pending_interrupts() {
+                break None;
+            }
+        };
+
+        let reason = match trap {
+            None => ContextSwitchReason::Interrupted,
+            Some((exception, mtval)) => {
+                state.mcause = exception as u32;
+                state.mtval = mtval;
+                match exception {
+                    Exception::UserEnvCall => {
+                        // Resume after the `ecall`.
+                        state.hart.pc = state.hart.pc.wrapping_add(4);
+                        let x = &state.hart.x;
+                        match Syscall::from_register_arguments(
+                            x[R_A4] as u8,
+                            x[R_A0] as usize,
+                            x[R_A0 + 1] as usize,
+                            x[R_A0 + 2] as usize,
+                            x[R_A0 + 3] as usize,
+                        ) {
+                            Some(syscall) => ContextSwitchReason::SyscallFired { syscall },
+                            None => ContextSwitchReason::Fault,
+                        }
+                    }
+                    _ => ContextSwitchReason::Fault,
+                }
+            }
+        };
+        (reason, Some(state.hart.x[R_SP] as *const u8))
+    }
+
+    unsafe fn print_context(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &HostStoredState,
+        writer: &mut dyn Write,
+    ) {
+        let x = &state.hart.x;
+        for i in 0..16 {
+            let _ = writer.write_fmt(format_args!(
+                "\r\n R{:<2}: {:#010X}    R{:<2}: {:#010X}",
+                i,
+                x[i],
+                i + 16,
+                x[i + 16]
+            ));
+        }
+        let _ = writer.write_fmt(format_args!(
+            "\
+             \r\n PC : {:#010X}\
+             \r\n\
+             \r\n mcause: {:#010X} ({})\
+             \r\n mtval:  {:#010X}\
+             \r\n instret: {}\
+             \r\n\r\n",
+            state.hart.pc,
+            state.mcause,
+            exception_name(state.mcause),
+            state.mtval,
+            state.hart.instret,
+        ));
+    }
+
+    unsafe fn core_registers(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &HostStoredState,
+    ) -> Option<CoreRegisters> {
+        // RISC-V `elf_gregset_t`: pc, then x1-x31.
+        let mut registers = [0; kernel::syscall::MAX_CORE_REGISTERS];
+        registers[0] = state.hart.pc;
+        registers[1..].copy_from_slice(&state.hart.x[1..]);
+        Some(CoreRegisters {
+            machine: EM_RISCV,
+            registers,
+            count: 32,
+        })
+    }
+
+    unsafe fn set_core_registers(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        state: &mut HostStoredState,
+        registers: &CoreRegisters,
+    ) -> Result<(), ErrorCode> {
+        if registers.machine != EM_RISCV || registers.count < 32 {
+            return Err(ErrorCode::INVAL);
+        }
+        state.hart.pc = registers.registers[0];
+        state.hart.x[1..].copy_from_slice(&registers.registers[1..32]);
+        Ok(())
+    }
+}
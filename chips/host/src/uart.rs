@@ -0,0 +1,231 @@
+//! UART emulated with the host's stdio or a terminal device.
+//!
+//! Transmitted bytes are written to the output immediately. Received bytes are
+//! read by a dedicated host thread and queued until the kernel services the
+//! UART. To attach a terminal other than the one the emulator was started
+//! from, create a pty pair (for example with
+//! `socat -d -d pty,raw,echo=0 pty,raw,echo=0`) and pass one end to
+//! [`HostUart::open`].
+
+use core::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ErrorCode;
+
+use crate::chip::Wakeup;
+
+pub struct HostUart<'a> {
+    output: RefCell<Box<dyn Write>>,
+    input: Arc<Mutex<VecDeque<u8>>>,
+    tx_client: OptionalCell<&'a dyn hil::uart::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn hil::uart::ReceiveClient>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_pending: Cell<bool>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+    rx_aborted: Cell<bool>,
+}
+
+impl<'a> HostUart<'a> {
+    /// Emulate the UART with the emulator's standard input and output.
+    pub fn stdio(wakeup: Wakeup) -> HostUart<'a> {
+        HostUart::new(Box::new(io::stdin()), Box::new(io::stdout()), wakeup)
+    }
+
+    /// Emulate the UART with a terminal device such as a pty.
+    pub fn open<P: AsRef<Path>>(path: P, wakeup: Wakeup) -> io::Result<HostUart<'a>> {
+        let device = OpenOptions::new().read(true).write(true).open(path)?;
+        let output = device.try_clone()?;
+        Ok(HostUart::new(Box::new(device), Box::new(output), wakeup))
+    }
+
+    /// Emulate the UART with arbitrary streams. `input` is read on its own
+    /// host thread until it reaches end-of-file.
+    pub fn new(
+        mut input: Box<dyn Read + Send>,
+        output: Box<dyn Write>,
+        wakeup: Wakeup,
+    ) -> HostUart<'a> {
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let rx_queue = queue.clone();
+        thread::Builder::new()
+            .name(String::from("uart-rx"))
+            .spawn(move || {
+                let mut buf = [0; 64];
+                loop {
+                    match input.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            rx_queue.lock().unwrap().extend(&buf[..n]);
+                            wakeup.notify();
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn UART receive thread");
+
+        HostUart {
+            output: RefCell::new(output),
+            input: queue,
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_pending: Cell::new(false),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            rx_aborted: Cell::new(false),
+        }
+    }
+
+    pub(crate) fn is_pending(&self) -> bool {
+        self.tx_pending.get()
+            || (self.rx_buffer.is_some()
+                && (self.rx_aborted.get() || !self.input.lock().unwrap().is_empty()))
+    }
+
+    pub(crate) fn handle_interrupt(&self) {
+        if self.tx_pending.get() {
+            self.tx_pending.set(false);
+            self.tx_buffer.take().map(|buffer| {
+                self.tx_client.map(move |client| {
+                    client.transmitted_buffer(buffer, self.tx_len.get(), Ok(()))
+                });
+            });
+        }
+
+        self.rx_buffer.take().map(|buffer| {
+            // Move as many queued bytes as the receive needs.
+            let mut index = self.rx_index.get();
+            {
+                let mut input = self.input.lock().unwrap();
+                while index < self.rx_len.get() {
+                    match input.pop_front() {
+                        Some(byte) => {
+                            buffer[index] = byte;
+                            index += 1;
+                        }
+                        None => break,
+                    }
+                }
+            }
+            self.rx_index.set(index);
+
+            if self.rx_aborted.get() {
+                self.rx_aborted.set(false);
+                self.rx_client.map(move |client| {
+                    client.received_buffer(
+                        buffer,
+                        index,
+                        Err(ErrorCode::CANCEL),
+                        hil::uart::Error::Aborted,
+                    )
+                });
+            } else if index == self.rx_len.get() {
+                self.rx_client.map(move |client| {
+                    client.received_buffer(buffer, index, Ok(()), hil::uart::Error::None)
+                });
+            } else {
+                self.rx_buffer.replace(buffer);
+            }
+        });
+    }
+}
+
+impl hil::uart::Configure for HostUart<'_> {
+    fn configure(&self, _params: hil::uart::Parameters) -> Result<(), ErrorCode> {
+        // Line settings have no meaning for host streams.
+        Ok(())
+    }
+}
+
+impl<'a> hil::uart::Transmit<'a> for HostUart<'a> {
+    fn set_transmit_client(&self, client: &'a dyn hil::uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_data: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if tx_len == 0 || tx_len > tx_data.len() {
+            return Err((ErrorCode::SIZE, tx_data));
+        }
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx_data));
+        }
+
+        let written = {
+            let mut output = self.output.borrow_mut();
+            output
+                .write_all(&tx_data[..tx_len])
+                .and_then(|()| output.flush())
+        };
+        if written.is_err() {
+            return Err((ErrorCode::FAIL, tx_data));
+        }
+
+        self.tx_buffer.replace(tx_data);
+        self.tx_len.set(tx_len);
+        self.tx_pending.set(true);
+        Ok(())
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        // Transmissions complete as soon as they start.
+        Err(ErrorCode::FAIL)
+    }
+}
+
+impl<'a> hil::uart::Receive<'a> for HostUart<'a> {
+    fn set_receive_client(&self, client: &'a dyn hil::uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, rx_buffer));
+        }
+        if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+
+        self.rx_buffer.replace(rx_buffer);
+        self.rx_len.set(rx_len);
+        self.rx_index.set(0);
+        self.rx_aborted.set(false);
+        Ok(())
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        if self.rx_buffer.is_some() {
+            self.rx_aborted.set(true);
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(())
+        }
+    }
+}
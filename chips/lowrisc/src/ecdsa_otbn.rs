@@ -0,0 +1,223 @@
+//! ECDSA P-256 signature verification on OTBN.
+//!
+//! OTBN has no fixed function; the board supplies the binary of an OTBN
+//! application implementing P-256 verification, such as OpenTitan's
+//! `p256_ecdsa`, together with an `OtbnApp` describing where the application
+//! expects its inputs in data memory. For each verification the binary is
+//! loaded, the inputs are written to data memory as little-endian 256-bit
+//! words, and the application is run. The application leaves the x
+//! coordinate of the recovered point, `x_r`, in data memory, and the
+//! signature is valid if it equals `r`.
+//!
+//! The binary is reloaded for every verification so that OTBN can be shared
+//! with other users of the `MuxAccel`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ecdsa_otbn = static_init!(
+//!     lowrisc::virtual_otbn::VirtualMuxAccel<'static, 1024>,
+//!     lowrisc::virtual_otbn::VirtualMuxAccel::new(mux_otbn)
+//! );
+//! let ecdsa = static_init!(
+//!     lowrisc::ecdsa_otbn::OtbnEcdsaP256<'static>,
+//!     lowrisc::ecdsa_otbn::OtbnEcdsaP256::new(
+//!         ecdsa_otbn,
+//!         &mut P256_ECDSA_BINARY,
+//!         P256_ECDSA_APP,
+//!         &mut OTBN_OUTPUT,
+//!     )
+//! );
+//! ecdsa_otbn.set_client(ecdsa);
+//! ecdsa.set_public_key(&FIRMWARE_SIGNING_KEY).unwrap();
+//! ```
+
+use crate::otbn::Client;
+use crate::virtual_otbn::VirtualMuxAccel;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::public_key_crypto::{ClientVerify, SignatureVerify};
+use kernel::ErrorCode;
+
+/// Length of the hash that is signed.
+pub const HASH_LEN: usize = 32;
+/// Length of a public key, `x || y` as big-endian integers.
+pub const PUBLIC_KEY_LEN: usize = 64;
+/// Length of a signature, `r || s` as big-endian integers.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// How to drive the OTBN application. Addresses are byte offsets into data
+/// memory and must be multiples of four; `x_r` must be within the first 1024
+/// bytes, which are returned when the application finishes.
+#[derive(Clone, Copy)]
+pub struct OtbnApp {
+    /// Address of the first instruction to run.
+    pub start_address: usize,
+    /// Where the operation mode is written, and the value selecting
+    /// verification.
+    pub mode: usize,
+    pub mode_verify: u32,
+    /// Where the hash, signature and public key are written.
+    pub msg: usize,
+    pub r: usize,
+    pub s: usize,
+    pub x: usize,
+    pub y: usize,
+    /// Where the application leaves its result.
+    pub x_r: usize,
+}
+
+/// Convert a big-endian integer to the little-endian layout OTBN uses.
+fn reversed(be: &[u8]) -> [u8; 32] {
+    let mut le = [0; 32];
+    for (i, byte) in be.iter().rev().enumerate() {
+        le[i] = *byte;
+    }
+    le
+}
+
+pub struct OtbnEcdsaP256<'a> {
+    otbn: &'a VirtualMuxAccel<'a, 1024>,
+    app: OtbnApp,
+    client: OptionalCell<&'a dyn ClientVerify<'a, HASH_LEN, SIGNATURE_LEN>>,
+    public_key: OptionalCell<[u8; PUBLIC_KEY_LEN]>,
+
+    binary: TakeCell<'static, [u8]>,
+    output: TakeCell<'static, [u8; 1024]>,
+
+    /// Buffers of the verification in progress.
+    hash: TakeCell<'static, [u8; HASH_LEN]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+}
+
+impl<'a> OtbnEcdsaP256<'a> {
+    pub fn new(
+        otbn: &'a VirtualMuxAccel<'a, 1024>,
+        binary: &'static mut [u8],
+        app: OtbnApp,
+        output: &'static mut [u8; 1024],
+    ) -> OtbnEcdsaP256<'a> {
+        OtbnEcdsaP256 {
+            otbn,
+            app,
+            client: OptionalCell::empty(),
+            public_key: OptionalCell::empty(),
+            binary: TakeCell::new(binary),
+            output: TakeCell::new(output),
+            hash: TakeCell::empty(),
+            signature: TakeCell::empty(),
+        }
+    }
+
+    /// Write the inputs of the verification to data memory and start it.
+    fn start(&'a self) -> Result<(), ErrorCode> {
+        let app = &self.app;
+        let key = self.public_key.extract().ok_or(ErrorCode::RESERVE)?;
+
+        self.otbn
+            .load_data(app.mode, &app.mode_verify.to_le_bytes())?;
+        self.hash.map_or(Err(ErrorCode::FAIL), |hash| {
+            self.otbn.load_data(app.msg, &reversed(&hash[..]))
+        })?;
+        self.signature.map_or(Err(ErrorCode::FAIL), |signature| {
+            self.otbn.load_data(app.r, &reversed(&signature[..32]))?;
+            self.otbn.load_data(app.s, &reversed(&signature[32..]))
+        })?;
+        self.otbn.load_data(app.x, &reversed(&key[..32]))?;
+        self.otbn.load_data(app.y, &reversed(&key[32..]))?;
+        self.otbn.set_property(0, app.start_address)?;
+
+        let output = self.output.take().ok_or(ErrorCode::BUSY)?;
+        self.otbn.run(output).map_err(|(e, output)| {
+            self.output.replace(output);
+            e
+        })
+    }
+
+    fn finish(&'a self, result: Result<bool, ErrorCode>) {
+        self.otbn.clear_data();
+        if let (Some(hash), Some(signature)) = (self.hash.take(), self.signature.take()) {
+            self.client
+                .map(move |client| client.verification_done(result, hash, signature));
+        }
+    }
+}
+
+impl<'a> SignatureVerify<'a, HASH_LEN, SIGNATURE_LEN> for OtbnEcdsaP256<'a> {
+    fn set_verify_client(&'a self, client: &'a dyn ClientVerify<'a, HASH_LEN, SIGNATURE_LEN>) {
+        self.client.set(client);
+    }
+
+    /// The key is `x || y`, optionally prefixed with the SEC1 `0x04` tag. The
+    /// OTBN application checks that the point is on the curve when verifying.
+    fn set_public_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        if self.hash.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let key = match key.len() {
+            PUBLIC_KEY_LEN => key,
+            65 if key[0] == 0x04 => &key[1..],
+            _ => return Err(ErrorCode::INVAL),
+        };
+        let mut public_key = [0; PUBLIC_KEY_LEN];
+        public_key.copy_from_slice(key);
+        self.public_key.set(public_key);
+        Ok(())
+    }
+
+    fn verify(
+        &'a self,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ErrorCode,
+            &'static mut [u8; HASH_LEN],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.hash.is_some() {
+            return Err((ErrorCode::BUSY, hash, signature));
+        }
+        if self.public_key.is_none() {
+            return Err((ErrorCode::RESERVE, hash, signature));
+        }
+        let binary = match self.binary.take() {
+            Some(binary) => binary,
+            None => return Err((ErrorCode::BUSY, hash, signature)),
+        };
+
+        if let Err((e, binary)) = self.otbn.load_binary(LeasableBuffer::new(binary)) {
+            self.binary.replace(binary);
+            self.otbn.clear_data();
+            return Err((e, hash, signature));
+        }
+
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+        Ok(())
+    }
+}
+
+impl<'a> Client<'a, 1024> for OtbnEcdsaP256<'a> {
+    fn binary_load_done(&'a self, result: Result<(), ErrorCode>, input: &'static mut [u8]) {
+        self.binary.replace(input);
+        if let Err(e) = result.and_then(|()| self.start()) {
+            self.finish(Err(e));
+        }
+    }
+
+    fn op_done(&'a self, result: Result<(), ErrorCode>, output: &'static mut [u8; 1024]) {
+        let x_r = self.app.x_r;
+        let result = result.and_then(|()| {
+            let x_r = output.get(x_r..x_r + 32).ok_or(ErrorCode::FAIL)?;
+            Ok(self
+                .signature
+                .map_or(false, |signature| reversed(x_r)[..] == signature[..32]))
+        });
+        self.output.replace(output);
+        self.finish(result);
+    }
+}
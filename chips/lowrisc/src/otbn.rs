@@ -121,8 +121,15 @@ impl<'a> Otbn<'a> {
         }
 
         if !self.registers.status.is_set(STATUS::BUSY) {
-            self.client.map(|client| {
-                client.op_done(Ok(()), self.out_buffer.take().unwrap());
+            self.out_buffer.take().map(|buf| {
+                // Return the start of the data memory, which is where OTBN
+                // applications leave their results.
+                for (i, word) in buf.chunks_mut(4).enumerate() {
+                    word.copy_from_slice(&self.registers.dmem[i].get().to_le_bytes());
+                }
+                self.client.map(move |client| {
+                    client.op_done(Ok(()), buf);
+                });
             });
         }
 
@@ -179,6 +186,33 @@ impl<'a> Otbn<'a> {
         Ok(())
     }
 
+    /// Write `data` to the data memory, starting at byte offset `address`.
+    ///
+    /// This is used to pass the inputs of an operation to the application
+    /// loaded with `load_binary()`. The write is complete when this returns.
+    /// Returns `INVAL` if `address` or the length of `data` is not a multiple
+    /// of four bytes, or if the data does not fit in the data memory.
+    pub fn load_data(&self, address: usize, data: &[u8]) -> Result<(), ErrorCode> {
+        if self.registers.status.is_set(STATUS::BUSY) {
+            // OTBN is performing an operation, we can't make any changes
+            return Err(ErrorCode::BUSY);
+        }
+
+        if address % 4 != 0
+            || data.len() % 4 != 0
+            || address + data.len() > self.registers.dmem.len() * 4
+        {
+            return Err(ErrorCode::INVAL);
+        }
+
+        for (i, word) in data.chunks(4).enumerate() {
+            self.registers.dmem[address / 4 + i]
+                .set(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        }
+
+        Ok(())
+    }
+
     /// Set the OTBN properties
     /// key values:
     ///  `0` -> Start Address, set the start address
@@ -199,7 +233,9 @@ impl<'a> Otbn<'a> {
 
     /// Run the acceleration operation.
     /// This doesn't return any data, instead the client needs to have
-    /// set a `op_done` handler to determine when this is complete.
+    /// set a `op_done` handler to determine when this is complete. When the
+    /// operation succeeds `output` is filled with the start of the data
+    /// memory.
     /// On error the return value will contain a return code and the original data
     /// If there is data from the `load_binary()` command asyncrously waiting to
     /// be written it will be written before the operation starts.
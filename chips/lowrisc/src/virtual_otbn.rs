@@ -5,7 +5,7 @@ use crate::otbn::{Client, Otbn};
 use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::leasable_buffer::LeasableBuffer;
-use kernel::common::{ListLink, ListNode};
+use kernel::common::{List, ListLink, ListNode};
 use kernel::ErrorCode;
 
 pub struct VirtualMuxAccel<'a, const T: usize> {
@@ -35,6 +35,10 @@ impl<'a, const T: usize> VirtualMuxAccel<'a, T> {
     }
 
     pub fn set_client(&'a self, client: &'a dyn Client<'a, T>) {
+        let node = self.mux.users.iter().find(|node| node.id == self.id);
+        if node.is_none() {
+            self.mux.users.push_head(self);
+        }
         self.client.set(client);
     }
 
@@ -54,6 +58,19 @@ impl<'a, const T: usize> VirtualMuxAccel<'a, T> {
         }
     }
 
+    pub fn load_data(&self, address: usize, data: &[u8]) -> Result<(), ErrorCode> {
+        // Check if any mux is enabled. If it isn't we enable it for us.
+        if self.mux.running.get() == false {
+            self.mux.running.set(true);
+            self.mux.running_id.set(self.id);
+            self.mux.accel.load_data(address, data)
+        } else if self.mux.running_id.get() == self.id {
+            self.mux.accel.load_data(address, data)
+        } else {
+            Err(ErrorCode::BUSY)
+        }
+    }
+
     pub fn set_property(&self, key: usize, value: usize) -> Result<(), ErrorCode> {
         // Check if any mux is enabled. If it isn't we enable it for us.
         if self.mux.running.get() == false {
@@ -114,6 +131,7 @@ pub struct MuxAccel<'a, const T: usize> {
     running: Cell<bool>,
     running_id: Cell<u32>,
     next_id: Cell<u32>,
+    users: List<'a, VirtualMuxAccel<'a, T>>,
 }
 
 impl<'a, const T: usize> MuxAccel<'a, T> {
@@ -123,6 +141,27 @@ impl<'a, const T: usize> MuxAccel<'a, T> {
             running: Cell::new(false),
             running_id: Cell::new(0),
             next_id: Cell::new(0),
+            users: List::new(),
         }
     }
+
+    fn running_user(&self) -> Option<&'a VirtualMuxAccel<'a, T>> {
+        let id = self.running_id.get();
+        self.users.iter().find(|node| node.id == id)
+    }
+}
+
+/// The `MuxAccel` can be set as the client of the underlying `Otbn`, in which
+/// case callbacks are routed to whichever `VirtualMuxAccel` is currently
+/// running. This allows more than one `VirtualMuxAccel` to share OTBN.
+impl<'a, const T: usize> Client<'a, T> for MuxAccel<'a, T> {
+    fn binary_load_done(&'a self, result: Result<(), ErrorCode>, input: &'static mut [u8]) {
+        self.running_user()
+            .map(move |node| node.binary_load_done(result, input));
+    }
+
+    fn op_done(&'a self, result: Result<(), ErrorCode>, output: &'static mut [u8; T]) {
+        self.running_user()
+            .map(move |node| node.op_done(result, output));
+    }
 }
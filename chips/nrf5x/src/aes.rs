@@ -336,6 +336,13 @@ impl kernel::hil::symmetric_encryption::AES128CBC for AesECB<'_> {
         Ok(())
     }
 }
+
+impl kernel::hil::symmetric_encryption::AES128ECB for AesECB<'_> {
+    // `crypt` always runs the ECB engine as a CTR keystream generator
+    fn set_mode_aes128ecb(&self, _encrypting: bool) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
 //TODO: replace this placeholder with a proper implementation of the AES system
 impl<'a> kernel::hil::symmetric_encryption::AES128CCM<'a> for AesECB<'a> {
     /// Set the client instance which will receive `crypt_done()` callbacks
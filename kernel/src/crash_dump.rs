@@ -0,0 +1,561 @@
+//! Crash dumps of kernel panics that survive a reset.
+//!
+//! When a board reserves a crash dump area with `set_area()`, `debug::panic`
+//! and `debug::panic_print` serialize the panic into it before printing the
+//! usual diagnostics: the panic message, the kernel version, a snippet of the
+//! kernel stack, the state of every process and the chip's CPU state. The area
+//! should be RAM the startup code leaves alone, such as the `.crash_dump`
+//! section of the generic linker script, so that the dump is still there after
+//! the board resets. On the next boot `previous()` returns the dump so that a
+//! capsule can report it, and `clear()` discards it. A new panic overwrites
+//! any dump that was not reported yet.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! #[link_section = ".crash_dump"]
+//! static mut CRASH_DUMP_AREA: [u8; 2048] = [0; 2048];
+//!
+//! kernel::crash_dump::set_area(&mut CRASH_DUMP_AREA, &_estack as *const u8);
+//! ```
+//!
+//! Format
+//! ------
+//!
+//! All integers are little endian. The dump starts with a 16 byte header:
+//!
+//! ```text
+//! 0       4         6       8        12         16
+//! | magic | version | flags | length | checksum |
+//! ```
+//!
+//! `length` is the number of bytes of records following the header and
+//! `checksum` is the CRC-32 of those bytes. Every record is a 16 bit tag, a
+//! 16 bit payload length and the payload, padded to a multiple of four bytes.
+//! Readers skip records with tags they do not know.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use crate::process::{Process, State};
+use crate::Chip;
+
+/// "TKCD", marks a valid dump.
+pub const MAGIC: u32 = 0x4443_4B54;
+/// Version of the format described above.
+pub const VERSION: u16 = 1;
+/// Size of the dump header.
+pub const HEADER_LEN: usize = 16;
+/// Set in the header flags if records did not fit in the area.
+pub const FLAG_TRUNCATED: u16 = 1 << 0;
+
+/// Maximum number of stack words kept in the stack record.
+const MAX_STACK_WORDS: usize = 128;
+
+/// Size of the fixed part of a process record, before the name.
+const PROCESS_RECORD_LEN: usize = 32;
+
+/// Kinds of records in a dump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordKind {
+    /// The formatted `PanicInfo`, including where the panic happened.
+    PanicMessage = 1,
+    /// `TOCK_KERNEL_VERSION` the kernel was built with.
+    KernelVersion = 2,
+    /// Words of the kernel stack, see `StackRecord`.
+    Stack = 3,
+    /// State of one process, see `ProcessRecord`.
+    Process = 4,
+    /// Text printed by `Chip::print_state()`, such as fault status registers.
+    CpuState = 5,
+}
+
+impl RecordKind {
+    pub fn from_tag(tag: u16) -> Option<RecordKind> {
+        match tag {
+            1 => Some(RecordKind::PanicMessage),
+            2 => Some(RecordKind::KernelVersion),
+            3 => Some(RecordKind::Stack),
+            4 => Some(RecordKind::Process),
+            5 => Some(RecordKind::CpuState),
+            _ => None,
+        }
+    }
+}
+
+struct Area {
+    buffer: &'static mut [u8],
+    stack_end: usize,
+}
+
+static mut AREA: Option<Area> = None;
+
+/// Reserve `area` for crash dumps.
+///
+/// `stack_end` is the top of the kernel stack (`_estack` in the generic linker
+/// script); the stack record holds the words between the stack pointer at the
+/// time of the panic and this address. The contents of `area` are kept, so a
+/// dump written before the reset is available through `previous()`.
+pub unsafe fn set_area(area: &'static mut [u8], stack_end: *const u8) {
+    AREA = Some(Area {
+        buffer: area,
+        stack_end: stack_end as usize,
+    });
+}
+
+/// The dump left in the crash dump area by a panic before the last reset, if
+/// the area holds a valid one.
+pub fn previous() -> Option<CrashDump<'static>> {
+    unsafe { AREA.as_ref().and_then(|area| CrashDump::parse(area.buffer)) }
+}
+
+/// Discard the dump in the crash dump area.
+///
+/// This invalidates the header, so dumps returned by `previous()` should no
+/// longer be used after this call.
+pub fn clear() {
+    unsafe {
+        if let Some(area) = AREA.as_mut() {
+            for byte in area.buffer.iter_mut().take(HEADER_LEN) {
+                *byte = 0;
+            }
+        }
+    }
+}
+
+/// Serialize a panic into the crash dump area, if the board reserved one.
+pub(crate) unsafe fn record_panic<C: Chip>(
+    panic_info: &PanicInfo,
+    processes: &'static [Option<&'static dyn Process>],
+    chip: &'static Option<&'static C>,
+) {
+    // The address of a local is as close to the stack pointer as portable code
+    // gets.
+    let marker = 0usize;
+    let stack_pointer = &marker as *const usize as usize;
+
+    let area = match AREA.as_mut() {
+        Some(area) => area,
+        None => return,
+    };
+    let mut dump = Serializer::new(area.buffer);
+
+    dump.record(RecordKind::PanicMessage, |dump| {
+        let _ = write!(dump, "{}", panic_info);
+    });
+    dump.record(RecordKind::KernelVersion, |dump| {
+        dump.push(
+            option_env!("TOCK_KERNEL_VERSION")
+                .unwrap_or("unknown")
+                .as_bytes(),
+        );
+    });
+
+    let stack_start = (stack_pointer + 3) & !3;
+    if stack_start < area.stack_end {
+        let words = core::cmp::min((area.stack_end - stack_start) / 4, MAX_STACK_WORDS);
+        dump.record(RecordKind::Stack, |dump| {
+            dump.push(&(stack_start as u32).to_le_bytes());
+            for i in 0..words {
+                let word = core::ptr::read_volatile((stack_start as *const u32).add(i));
+                dump.push(&word.to_le_bytes());
+            }
+        });
+    }
+
+    for (index, process) in processes.iter().enumerate() {
+        if let Some(process) = process {
+            dump.record(RecordKind::Process, |dump| {
+                dump.push(&process_record(index, *process));
+                dump.push(process.get_process_name().as_bytes());
+            });
+        }
+    }
+
+    chip.map(|chip| {
+        dump.record(RecordKind::CpuState, |dump| chip.print_state(dump));
+    });
+
+    dump.finish();
+}
+
+/// The fixed part of the process record of `process`.
+fn process_record(index: usize, process: &dyn Process) -> [u8; PROCESS_RECORD_LEN] {
+    let mut record = [0; PROCESS_RECORD_LEN];
+    record[0] = index as u8;
+    record[1] = state_code(process.get_state());
+    record[2] = if process.debug_stack_overflowed() {
+        1
+    } else {
+        0
+    };
+    let words = [
+        process.get_restart_count() as u32,
+        process.debug_syscall_count() as u32,
+        process.debug_dropped_upcall_count() as u32,
+        process.flash_start() as u32,
+        process.flash_end() as u32,
+        process.mem_start() as u32,
+        process.mem_end() as u32,
+    ];
+    for (i, word) in words.iter().enumerate() {
+        record[4 + 4 * i..8 + 4 * i].copy_from_slice(&word.to_le_bytes());
+    }
+    record
+}
+
+fn state_code(state: State) -> u8 {
+    match state {
+        State::Running => 0,
+        State::Yielded => 1,
+        State::StoppedRunning => 2,
+        State::StoppedYielded => 3,
+        State::Faulted => 4,
+        State::Terminated => 5,
+        State::Unstarted => 6,
+    }
+}
+
+fn state_from_code(code: u8) -> Option<State> {
+    match code {
+        0 => Some(State::Running),
+        1 => Some(State::Yielded),
+        2 => Some(State::StoppedRunning),
+        3 => Some(State::StoppedYielded),
+        4 => Some(State::Faulted),
+        5 => Some(State::Terminated),
+        6 => Some(State::Unstarted),
+        _ => None,
+    }
+}
+
+/// Writes records into a crash dump area, dropping what does not fit.
+struct Serializer<'a> {
+    buffer: &'a mut [u8],
+    /// Where the next byte goes.
+    position: usize,
+    /// Offset of the current record's tag, if a record is open.
+    record_start: Option<usize>,
+    truncated: bool,
+}
+
+impl<'a> Serializer<'a> {
+    fn new(buffer: &'a mut [u8]) -> Serializer<'a> {
+        Serializer {
+            buffer,
+            position: HEADER_LEN,
+            record_start: None,
+            truncated: false,
+        }
+    }
+
+    /// Add a record of the given kind whose payload is pushed by `payload`.
+    fn record<F: FnOnce(&mut Self)>(&mut self, kind: RecordKind, payload: F) {
+        if self.position + 4 > self.buffer.len() {
+            self.truncated = true;
+            return;
+        }
+        let tag = kind as u16;
+        self.buffer[self.position..self.position + 2].copy_from_slice(&tag.to_le_bytes());
+        self.record_start = Some(self.position);
+        self.position += 4;
+
+        payload(self);
+
+        let start = self.record_start.take().unwrap_or(0);
+        let len = (self.position - start - 4) as u16;
+        self.buffer[start + 2..start + 4].copy_from_slice(&len.to_le_bytes());
+        while self.position % 4 != 0 && self.position < self.buffer.len() {
+            self.buffer[self.position] = 0;
+            self.position += 1;
+        }
+    }
+
+    /// Append `bytes` to the payload of the open record.
+    fn push(&mut self, bytes: &[u8]) {
+        let start = match self.record_start {
+            Some(start) => start,
+            None => return,
+        };
+        let room = core::cmp::min(
+            self.buffer.len() - self.position,
+            start + 4 + u16::MAX as usize - self.position,
+        );
+        let len = core::cmp::min(room, bytes.len());
+        if len < bytes.len() {
+            self.truncated = true;
+        }
+        self.buffer[self.position..self.position + len].copy_from_slice(&bytes[..len]);
+        self.position += len;
+    }
+
+    /// Write the header, which makes the dump valid.
+    fn finish(self) {
+        if self.buffer.len() < HEADER_LEN {
+            return;
+        }
+        let records = &self.buffer[HEADER_LEN..self.position];
+        let checksum = crc32(records);
+        let length = records.len() as u32;
+        let flags = if self.truncated { FLAG_TRUNCATED } else { 0 };
+        self.buffer[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        self.buffer[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        self.buffer[6..8].copy_from_slice(&flags.to_le_bytes());
+        self.buffer[8..12].copy_from_slice(&length.to_le_bytes());
+        self.buffer[12..16].copy_from_slice(&checksum.to_le_bytes());
+    }
+}
+
+impl Write for Serializer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise to keep the panic path small.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// A validated crash dump.
+#[derive(Clone, Copy)]
+pub struct CrashDump<'a> {
+    data: &'a [u8],
+    flags: u16,
+}
+
+impl<'a> CrashDump<'a> {
+    /// Check the header and checksum of the dump at the start of `data`.
+    pub fn parse(data: &'a [u8]) -> Option<CrashDump<'a>> {
+        if data.len() < HEADER_LEN || read_u32(data, 0) != MAGIC || read_u16(data, 4) != VERSION {
+            return None;
+        }
+        let length = read_u32(data, 8) as usize;
+        let records = data.get(HEADER_LEN..HEADER_LEN.checked_add(length)?)?;
+        if crc32(records) != read_u32(data, 12) {
+            return None;
+        }
+        Some(CrashDump {
+            data: &data[..HEADER_LEN + length],
+            flags: read_u16(data, 6),
+        })
+    }
+
+    /// The whole dump, header included.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Whether records were dropped or cut short because the area was full.
+    pub fn truncated(&self) -> bool {
+        self.flags & FLAG_TRUNCATED != 0
+    }
+
+    pub fn records(&self) -> Records<'a> {
+        Records {
+            data: &self.data[HEADER_LEN..],
+        }
+    }
+
+    /// Payload of the first record of the given kind.
+    pub fn find(&self, kind: RecordKind) -> Option<&'a [u8]> {
+        self.records()
+            .find(|record| record.kind() == Some(kind))
+            .map(|record| record.payload)
+    }
+}
+
+/// One record of a crash dump.
+#[derive(Clone, Copy)]
+pub struct Record<'a> {
+    pub tag: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    pub fn kind(&self) -> Option<RecordKind> {
+        RecordKind::from_tag(self.tag)
+    }
+}
+
+/// Iterator over the records of a crash dump.
+pub struct Records<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Record<'a>> {
+        if self.data.len() < 4 {
+            return None;
+        }
+        let tag = read_u16(self.data, 0);
+        let len = read_u16(self.data, 2) as usize;
+        let payload = self.data.get(4..4 + len)?;
+        let padded = core::cmp::min((4 + len + 3) & !3, self.data.len());
+        self.data = &self.data[padded..];
+        Some(Record { tag, payload })
+    }
+}
+
+/// Payload of a `RecordKind::Stack` record.
+#[derive(Clone, Copy)]
+pub struct StackRecord<'a> {
+    /// Address of the first word.
+    pub start: u32,
+    words: &'a [u8],
+}
+
+impl<'a> StackRecord<'a> {
+    pub fn parse(payload: &'a [u8]) -> Option<StackRecord<'a>> {
+        if payload.len() < 4 {
+            return None;
+        }
+        Some(StackRecord {
+            start: read_u32(payload, 0),
+            words: &payload[4..],
+        })
+    }
+
+    /// The words of the stack, from the lowest address up.
+    pub fn words(&self) -> impl Iterator<Item = u32> + 'a {
+        self.words.chunks_exact(4).map(|word| read_u32(word, 0))
+    }
+}
+
+/// Payload of a `RecordKind::Process` record.
+#[derive(Clone, Copy)]
+pub struct ProcessRecord<'a> {
+    /// Index of the process in the processes array.
+    pub index: u8,
+    /// `None` if the state was recorded by a newer kernel.
+    pub state: Option<State>,
+    pub stack_overflowed: bool,
+    pub restart_count: u32,
+    pub syscall_count: u32,
+    pub dropped_upcall_count: u32,
+    pub flash_start: u32,
+    pub flash_end: u32,
+    pub mem_start: u32,
+    pub mem_end: u32,
+    /// The name, possibly cut short if the dump was truncated.
+    pub name: &'a [u8],
+}
+
+impl<'a> ProcessRecord<'a> {
+    pub fn parse(payload: &'a [u8]) -> Option<ProcessRecord<'a>> {
+        if payload.len() < PROCESS_RECORD_LEN {
+            return None;
+        }
+        Some(ProcessRecord {
+            index: payload[0],
+            state: state_from_code(payload[1]),
+            stack_overflowed: payload[2] & 1 != 0,
+            restart_count: read_u32(payload, 4),
+            syscall_count: read_u32(payload, 8),
+            dropped_upcall_count: read_u32(payload, 12),
+            flash_start: read_u32(payload, 16),
+            flash_end: read_u32(payload, 20),
+            mem_start: read_u32(payload, 24),
+            mem_end: read_u32(payload, 28),
+            name: &payload[PROCESS_RECORD_LEN..],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialized_dump_parses() {
+        let mut area = [0xAA; 256];
+        let mut dump = Serializer::new(&mut area);
+        let line = 42;
+        dump.record(RecordKind::PanicMessage, |dump| {
+            let _ = write!(dump, "panicked at 'oops', foo.rs:{}", line);
+        });
+        dump.record(RecordKind::Stack, |dump| {
+            dump.push(&0x2000_0100u32.to_le_bytes());
+            dump.push(&0x0000_4321u32.to_le_bytes());
+            dump.push(&0xDEAD_BEEFu32.to_le_bytes());
+        });
+        dump.finish();
+
+        let dump = CrashDump::parse(&area).unwrap();
+        assert!(!dump.truncated());
+        let mut records = dump.records();
+        let message = records.next().unwrap();
+        assert_eq!(message.kind(), Some(RecordKind::PanicMessage));
+        assert_eq!(message.payload, b"panicked at 'oops', foo.rs:42");
+        let stack = StackRecord::parse(records.next().unwrap().payload).unwrap();
+        assert_eq!(stack.start, 0x2000_0100);
+        assert!(stack.words().eq([0x4321, 0xDEAD_BEEF].iter().copied()));
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn overflow_truncates() {
+        let mut area = [0; 32];
+        let mut dump = Serializer::new(&mut area);
+        dump.record(RecordKind::CpuState, |dump| {
+            dump.push(&[b'x'; 64]);
+        });
+        dump.record(RecordKind::KernelVersion, |dump| dump.push(b"v1"));
+        dump.finish();
+
+        let dump = CrashDump::parse(&area).unwrap();
+        assert!(dump.truncated());
+        let records: [Option<Record>; 2] = {
+            let mut records = dump.records();
+            [records.next(), records.next()]
+        };
+        assert_eq!(records[0].unwrap().payload, &[b'x'; 12][..]);
+        assert!(records[1].is_none());
+    }
+
+    #[test]
+    fn corruption_is_rejected() {
+        let mut area = [0; 64];
+        let mut dump = Serializer::new(&mut area);
+        dump.record(RecordKind::KernelVersion, |dump| dump.push(b"2.0"));
+        dump.finish();
+        assert!(CrashDump::parse(&area).is_some());
+
+        area[HEADER_LEN + 4] ^= 1;
+        assert!(CrashDump::parse(&area).is_none());
+        assert!(CrashDump::parse(&[0; 64]).is_none());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -98,6 +98,9 @@ pub trait IoWrite {
 /// well-defined state. Care must be taken on how one interacts with
 /// the system once this function returns.
 ///
+/// If the board reserved a crash dump area, the panic is also saved
+/// there (see `kernel::crash_dump`).
+///
 /// **NOTE:** The supplied `writer` must be synchronous.
 pub unsafe fn panic_print<W: Write + IoWrite, C: Chip>(
     writer: &mut W,
@@ -107,6 +110,8 @@ pub unsafe fn panic_print<W: Write + IoWrite, C: Chip>(
     chip: &'static Option<&'static C>,
 ) {
     panic_begin(nop);
+    // Save the panic before printing it, in case printing fails
+    crate::crash_dump::record_panic(panic_info, processes, chip);
     panic_banner(writer, panic_info);
     // Flush debug buffer if needed
     flush(writer);
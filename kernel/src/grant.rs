@@ -481,6 +481,8 @@ impl<'a, T: Default, const NUM_UPCALLS: usize> ProcessGrant<'a, T, NUM_UPCALLS>
                     // Now we can calculate the entire size of the grant.
                     let alloc_size = upcalls_size + upcalls_padding + grant_t_size;
 
+                    // This fails if the grant region is full, or if the grant
+                    // is larger than the process's quota allows.
                     let (ptr_upcall_count, optional_ptr_first_upcall, raw_ptr_grant_nn) = process
                         .allocate_grant(grant_num, driver_num, alloc_size, alloc_align)
                         .map_or(Err(Error::OutOfMemory), |buf| {
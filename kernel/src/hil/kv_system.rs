@@ -88,7 +88,7 @@ pub trait Client<K: KeyType> {
         &self,
         result: Result<(), ErrorCode>,
         key: &'static mut K,
-        value: &'static [u8],
+        value: &'static mut [u8],
     );
 
     /// This callback is called when the get_value operation completes
@@ -155,13 +155,21 @@ pub trait KVSystem<'a> {
     ///    `BUSY`: An operation is already in progress
     ///    `INVAL`: An invalid parameter was passed
     ///    `NODEVICE`: No KV store was setup
-    ///    `ENOSUPPORT`: The key could not be added due to a collision.
+    ///    `ALREADY`: The key could not be added because it already exists.
     ///    `NOMEM`: The key could not be added due to no more space.
+    ///    `SIZE`: The value is too large to be stored.
     fn append_key(
         &self,
         key: &'static mut Self::K,
-        value: &'static [u8],
-    ) -> Result<(), (&'static mut Self::K, &'static [u8], Result<(), ErrorCode>)>;
+        value: &'static mut [u8],
+    ) -> Result<
+        (),
+        (
+            &'static mut Self::K,
+            &'static mut [u8],
+            Result<(), ErrorCode>,
+        ),
+    >;
 
     /// Retrieves the value from a specified key.
     ///
@@ -173,9 +181,9 @@ pub trait KVSystem<'a> {
     ///
     /// The possible `Result<(), ErrorCode>`s are:
     ///    `BUSY`: An operation is already in progress
-    ///    `INVAL`: An invalid parameter was passed
+    ///    `INVAL`: An invalid parameter was passed, or the key could not be
+    ///             found
     ///    `NODEVICE`: No KV store was setup
-    ///    `ENOSUPPORT`: The key could not be found.
     fn get_value(
         &self,
         key: &'static mut Self::K,
@@ -198,9 +206,9 @@ pub trait KVSystem<'a> {
     ///
     /// The possible `Result<(), ErrorCode>`s are:
     ///    `BUSY`: An operation is already in progress
-    ///    `INVAL`: An invalid parameter was passed
+    ///    `INVAL`: An invalid parameter was passed, or the key could not be
+    ///             found
     ///    `NODEVICE`: No KV store was setup
-    ///    `ENOSUPPORT`: The key could not be found.
     fn invalidate_key(
         &self,
         key: &'static mut Self::K,
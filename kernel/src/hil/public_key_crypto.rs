@@ -0,0 +1,92 @@
+//! Interface for public key signatures.
+//!
+//! `SignatureVerify` checks a signature over a fixed-length message, usually
+//! the digest of a larger payload such as a firmware image, against a public
+//! key. `SignatureSign` produces such signatures with a private key.
+//!
+//! `HL` is the length of the message (hash) in bytes and `SL` the length of
+//! the signature. Key and signature encodings are algorithm specific and are
+//! documented by the implementations; for ECDSA P-256, for example, the
+//! signature is `r || s` as two 32 byte big-endian integers.
+
+use crate::ErrorCode;
+
+/// Implement this trait and use `set_verify_client()` in order to receive
+/// verification results.
+pub trait ClientVerify<'a, const HL: usize, const SL: usize> {
+    /// Called when a verification started with `verify()` completes.
+    ///
+    /// `result` is `Ok(true)` if the signature is valid for the message and
+    /// the current public key, `Ok(false)` if it is not, and an error if the
+    /// verification could not be performed. The buffers passed to `verify()`
+    /// are returned.
+    fn verification_done(
+        &'a self,
+        result: Result<bool, ErrorCode>,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SL],
+    );
+}
+
+/// Verifies signatures with a public key.
+pub trait SignatureVerify<'a, const HL: usize, const SL: usize> {
+    /// Set the client instance which will receive `verification_done()`
+    /// callbacks.
+    fn set_verify_client(&'a self, client: &'a dyn ClientVerify<'a, HL, SL>);
+
+    /// Set the public key signatures are checked against.
+    ///
+    /// Returns `INVAL` if the key is not a valid encoding of a public key for
+    /// the algorithm, and `BUSY` if a verification is in progress.
+    fn set_public_key(&self, key: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Check that `signature` is a valid signature of `hash`.
+    ///
+    /// The result is delivered through `verification_done()`. On error the
+    /// buffers are returned immediately: `BUSY` if an operation is already in
+    /// progress, `RESERVE` if no public key has been set.
+    fn verify(
+        &'a self,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SL],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; HL], &'static mut [u8; SL])>;
+}
+
+/// Implement this trait and use `set_sign_client()` in order to receive
+/// signatures.
+pub trait ClientSign<'a, const HL: usize, const SL: usize> {
+    /// Called when a signature started with `sign()` is complete. On success
+    /// `signature` holds the signature of `hash`.
+    fn signing_done(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SL],
+    );
+}
+
+/// Produces signatures with a private key.
+pub trait SignatureSign<'a, const HL: usize, const SL: usize> {
+    /// Set the client instance which will receive `signing_done()` callbacks.
+    fn set_sign_client(&'a self, client: &'a dyn ClientSign<'a, HL, SL>);
+
+    /// Set the private key used to sign.
+    ///
+    /// Returns `INVAL` if the key is not a valid encoding of a private key for
+    /// the algorithm, and `BUSY` if an operation is in progress.
+    fn set_private_key(&self, key: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Sign `hash`, writing the signature to `signature`.
+    ///
+    /// The result is delivered through `signing_done()`. On error the
+    /// buffers are returned immediately: `BUSY` if an operation is already in
+    /// progress, `RESERVE` if no private key has been set.
+    fn sign(
+        &'a self,
+        hash: &'static mut [u8; HL],
+        signature: &'static mut [u8; SL],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; HL], &'static mut [u8; SL])>;
+
+    /// Clear the private key.
+    fn clear_private_key(&self);
+}
@@ -135,3 +135,44 @@ pub trait AES128CCM<'a> {
         encrypting: bool,
     ) -> Result<(), (ErrorCode, &'static mut [u8])>;
 }
+
+pub trait GCMClient {
+    /// `res` is Ok(()) if the encryption/decryption process succeeded. This
+    /// does not mean that the message has been verified in the case of
+    /// decryption.
+    /// If we are encrypting: `tag_is_valid` is `true` iff `res` is Ok(()).
+    /// If we are decrypting: `tag_is_valid` is `true` iff `res` is Ok(()) and the
+    /// message authentication tag is valid.
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool);
+}
+
+pub const GCM_IV_LENGTH: usize = 12;
+pub const GCM_TAG_LENGTH: usize = 16;
+
+pub trait AES128GCM<'a> {
+    /// Set the client instance which will receive `crypt_done()` callbacks
+    fn set_client(&'a self, client: &'a dyn GCMClient);
+
+    /// Set the key to be used for GCM encryption
+    /// Returns `INVAL` if length is not `AES128_KEY_SIZE`
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Set the IV (length GCM_IV_LENGTH) to be used for GCM encryption
+    fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Try to begin the encryption/decryption process
+    ///
+    /// The additional authenticated data is `buf[aad_offset..message_offset]`
+    /// and the message is the `message_len` bytes at `message_offset`, which
+    /// are encrypted or decrypted in place. The `GCM_TAG_LENGTH` byte tag
+    /// follows the message: it is written there when encrypting, and
+    /// compared against it when decrypting.
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        aad_offset: usize,
+        message_offset: usize,
+        message_len: usize,
+        encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
@@ -16,6 +16,7 @@ use crate::capabilities::ProcessManagementCapability;
 use crate::common::cells::NumericCellExt;
 use crate::process;
 use crate::process::ProcessId;
+use crate::process_policies::ProcessQuotaCounts;
 use crate::sched::Kernel;
 
 /// This struct provides the inspection functions.
@@ -103,6 +104,18 @@ impl KernelInfo {
             .process_map_or(0, app, |process| process.debug_dropped_upcall_count())
     }
 
+    /// Returns how many times the app has hit each of its resource quotas.
+    pub fn number_app_quota_violations(
+        &self,
+        app: ProcessId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> ProcessQuotaCounts {
+        self.kernel
+            .process_map_or(ProcessQuotaCounts::default(), app, |process| {
+                process.debug_quota_exceeded_counts()
+            })
+    }
+
     /// Returns the number of time this app has been restarted.
     pub fn number_app_restarts(
         &self,
@@ -143,6 +156,32 @@ impl KernelInfo {
         (used, number_of_grants)
     }
 
+    /// Returns the number of grants that exist in the system.
+    pub fn number_grants(&self, _capability: &dyn ProcessManagementCapability) -> usize {
+        self.kernel.get_grant_count()
+    }
+
+    /// Returns the driver number grant `grant_num` was created for, if known.
+    pub fn grant_driver_num(
+        &self,
+        grant_num: usize,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> Option<usize> {
+        self.kernel.grant_driver_num(grant_num)
+    }
+
+    /// Returns whether `app` has allocated grant `grant_num`.
+    pub fn app_grant_is_allocated(
+        &self,
+        app: ProcessId,
+        grant_num: usize,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> bool {
+        self.kernel.process_map_or(false, app, |process| {
+            process.grant_is_allocated(grant_num).unwrap_or(false)
+        })
+    }
+
     /// Returns the total number of times all processes have exceeded
     /// their timeslices.
     pub fn timeslice_expirations(&self, _capability: &dyn ProcessManagementCapability) -> usize {
@@ -92,6 +92,7 @@
 pub mod capabilities;
 pub mod common;
 pub mod component;
+pub mod crash_dump;
 pub mod debug;
 pub mod hil;
 pub mod introspection;
@@ -102,10 +103,12 @@ mod config;
 mod driver;
 mod errorcode;
 mod grant;
+mod measurement;
 mod mem;
 mod memop;
 mod platform;
 mod process;
+mod process_core_dump;
 mod process_policies;
 mod process_standard;
 mod process_utilities;
@@ -121,6 +124,7 @@ pub use crate::mem::{
     ReadableProcessSlice, WriteableProcessBuffer, WriteableProcessSlice,
 };
 pub use crate::platform::scheduler_timer::{SchedulerTimer, VirtualSchedulerTimer};
+pub use crate::platform::syscall_filter;
 pub use crate::platform::watchdog;
 pub use crate::platform::{mpu, Chip, InterruptService, Platform};
 pub use crate::platform::{ClockInterface, NoClockControl, NO_CLOCK_CONTROL};
@@ -138,14 +142,19 @@ pub use crate::upcall::UpcallError;
 // processes.
 /// Publicly available process-related objects.
 pub mod procs {
+    pub use crate::measurement::{MeasuredImage, MeasurementLog, MAX_MEASURED_IMAGES};
     pub use crate::process::{
-        Error, FaultAction, FunctionCall, FunctionCallSource, Process, State, Task,
+        AllowSlot, AllowedBuffers, Error, FaultAction, FunctionCall, FunctionCallSource, Process,
+        State, Task, MAX_ALLOWED_BUFFERS,
     };
+    pub use crate::process_core_dump::{CoreDumpClient, CoreDumpFaultPolicy, ProcessCoreDump};
     pub use crate::process_policies::{
-        PanicFaultPolicy, ProcessFaultPolicy, RestartFaultPolicy, StopFaultPolicy,
+        FixedQuotaPolicy, PanicFaultPolicy, ProcessFaultPolicy, ProcessQuotaCounts,
+        ProcessQuotaPolicy, ProcessQuotas, RestartFaultPolicy, StopFaultPolicy,
         StopWithDebugFaultPolicy, ThresholdRestartFaultPolicy,
         ThresholdRestartThenPanicFaultPolicy,
     };
     pub use crate::process_standard::ProcessStandard;
-    pub use crate::process_utilities::{load_processes, ProcessLoadError};
+    pub use crate::process_utilities::{load_processes, load_processes_measured, ProcessLoadError};
+    pub use tock_tbf::types::CommandPermissions;
 }
@@ -0,0 +1,70 @@
+//! Log of the images measured at boot.
+//!
+//! The kernel records the flash region of each image it loads, starting with
+//! the kernel itself (recorded by the board) followed by the TBF of every
+//! process `load_processes_measured()` creates. The log only holds references
+//! to the images: hashing them is left to a capsule using `hil::digest`, since
+//! digest engines are asynchronous and not available while processes load.
+
+use core::cell::Cell;
+
+/// Maximum number of images the log can hold. Images recorded once the log
+/// is full are dropped and the log is marked as truncated.
+pub const MAX_MEASURED_IMAGES: usize = 16;
+
+/// One measured image: a name and the flash region it occupies.
+#[derive(Clone, Copy)]
+pub struct MeasuredImage {
+    pub name: &'static str,
+    pub image: &'static [u8],
+}
+
+const EMPTY: Cell<Option<MeasuredImage>> = Cell::new(None);
+
+/// Ordered list of the images loaded at boot.
+pub struct MeasurementLog {
+    entries: [Cell<Option<MeasuredImage>>; MAX_MEASURED_IMAGES],
+    len: Cell<usize>,
+    truncated: Cell<bool>,
+}
+
+impl MeasurementLog {
+    pub const fn new() -> MeasurementLog {
+        MeasurementLog {
+            entries: [EMPTY; MAX_MEASURED_IMAGES],
+            len: Cell::new(0),
+            truncated: Cell::new(false),
+        }
+    }
+
+    /// Append an image to the log.
+    pub fn record(&self, name: &'static str, image: &'static [u8]) {
+        let len = self.len.get();
+        match self.entries.get(len) {
+            Some(entry) => {
+                entry.set(Some(MeasuredImage { name, image }));
+                self.len.set(len + 1);
+            }
+            None => self.truncated.set(true),
+        }
+    }
+
+    /// Number of images in the log.
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len.get() == 0
+    }
+
+    /// The `index`th image recorded, if any.
+    pub fn get(&self, index: usize) -> Option<MeasuredImage> {
+        self.entries.get(index).and_then(|entry| entry.get())
+    }
+
+    /// Whether images were dropped because the log was full.
+    pub fn truncated(&self) -> bool {
+        self.truncated.get()
+    }
+}
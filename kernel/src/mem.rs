@@ -647,6 +647,18 @@ impl ReadableProcessSlice {
         self.slice.len()
     }
 
+    /// The subslice in `range`, or `None` if `range` is out of bounds.
+    ///
+    /// Processes can replace a buffer with a shorter one at any time, so
+    /// this should be used instead of indexing whenever the range was not
+    /// checked against this slice.
+    pub fn get(&self, range: Range<usize>) -> Option<&ReadableProcessSlice> {
+        if range.start > range.end || range.end > self.len() {
+            return None;
+        }
+        Some(&self[range])
+    }
+
     pub fn iter(&self) -> core::slice::Iter<'_, ReadableProcessByte> {
         self.slice.iter()
     }
@@ -804,6 +816,18 @@ impl WriteableProcessSlice {
         self.slice.len()
     }
 
+    /// The subslice in `range`, or `None` if `range` is out of bounds.
+    ///
+    /// Processes can replace a buffer with a shorter one at any time, so
+    /// this should be used instead of indexing whenever the range was not
+    /// checked against this slice.
+    pub fn get(&self, range: Range<usize>) -> Option<&WriteableProcessSlice> {
+        if range.start > range.end || range.end > self.len() {
+            return None;
+        }
+        Some(&self[range])
+    }
+
     pub fn iter(&self) -> core::slice::Iter<'_, Cell<u8>> {
         self.slice.iter()
     }
@@ -37,6 +37,11 @@ use crate::ErrorCode;
 ///   where the app has put the start of its heap. This is not strictly
 ///   necessary for correct operation, but allows for better debugging if the
 ///   app crashes.
+/// - `12`: Specify the lowest address of the app stack. The kernel places a
+///   guard region the app cannot access right below it, so that a stack
+///   overflow faults and is reported as such instead of silently corrupting
+///   memory. Apps with the stack at the start of their memory, as in Tock's
+///   standard layout, are guarded without it.
 pub(crate) fn memop(process: &dyn Process, op_type: usize, r1: usize) -> SyscallReturn {
     match op_type {
         // Op Type 0: BRK
@@ -107,6 +112,14 @@ pub(crate) fn memop(process: &dyn Process, op_type: usize, r1: usize) -> Syscall
             SyscallReturn::Success
         }
 
+        // Op Type 12: Specify the lowest address of the app stack.
+        12 => {
+            match process.setup_stack_guard(r1 as *const u8) {
+                Ok(()) => SyscallReturn::Success,
+                Err(e) => SyscallReturn::Failure(e),
+            }
+        }
+
         _ => SyscallReturn::Failure(ErrorCode::NOSUPPORT),
     }
 }
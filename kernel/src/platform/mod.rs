@@ -8,6 +8,7 @@ use core::fmt::Write;
 
 pub mod mpu;
 pub(crate) mod scheduler_timer;
+pub mod syscall_filter;
 pub mod watchdog;
 
 /// Interface for individual boards.
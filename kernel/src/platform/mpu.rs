@@ -158,6 +158,39 @@ pub trait MPU {
         }
     }
 
+    /// Allocates a guard region that user mode cannot access.
+    ///
+    /// Guard regions catch accesses that run off the end of a buffer inside
+    /// app-owned memory, such as a process stack overflowing into its data.
+    /// An implementation must allocate a region of at least `min_region_size`
+    /// bytes that ends exactly at `region_end` and starts no lower than
+    /// `memory_start`, and store it in `config`. Unlike other regions, the
+    /// guard region may overlap the app memory region, and must take
+    /// precedence over it.
+    ///
+    /// # Arguments
+    ///
+    /// - `memory_start`:    lowest address the region may cover
+    /// - `region_end`:      address just past the end of the region
+    /// - `min_region_size`: minimum size of the region
+    /// - `config`:          MPU region configuration
+    ///
+    /// # Return Value
+    ///
+    /// Returns the start and size of the allocated region. If it is infeasible
+    /// to allocate the region, for instance because `region_end` is not
+    /// aligned as the MPU requires, returns None and makes no changes.
+    #[allow(unused_variables)]
+    fn allocate_guard_region(
+        &self,
+        memory_start: *const u8,
+        region_end: *const u8,
+        min_region_size: usize,
+        config: &mut Self::MpuConfig,
+    ) -> Option<Region> {
+        None
+    }
+
     /// Chooses the location for a process's memory, and allocates an MPU region
     /// covering the app-owned part.
     ///
@@ -0,0 +1,141 @@
+//! System call filters for `Platform::filter_syscall()`.
+//!
+//! `TbfHeaderFilterDefaultAllow` enforces the permissions TLV of each
+//! process's TBF header, so boards do not need to write their own filtering
+//! logic. A board uses it by forwarding its `filter_syscall()`:
+//!
+//! ```ignore
+//! impl Platform for Board {
+//!     fn filter_syscall(
+//!         &self,
+//!         process: &dyn kernel::procs::Process,
+//!         syscall: &kernel::syscall::Syscall,
+//!     ) -> Result<(), kernel::ErrorCode> {
+//!         self.syscall_filter.filter_syscall(process, syscall)
+//!     }
+//! }
+//! ```
+//!
+//! Rejected system calls are counted per process; the count is shown by the
+//! process console.
+
+use crate::errorcode::ErrorCode;
+use crate::process::Process;
+use crate::syscall::Syscall;
+use tock_tbf::types::CommandPermissions;
+
+/// Number of commands covered by one `allowed_commands` bitmask.
+const COMMANDS_PER_MASK: usize = 64;
+
+/// Filter system calls with the permissions TLV of the process's TBF header.
+///
+/// Processes without a permissions TLV may make any system call. Processes
+/// with one may only call the commands it lists, and may only subscribe and
+/// allow buffers to drivers it lists. Other system calls fail with
+/// `NODEVICE`, as if the driver did not exist.
+pub struct TbfHeaderFilterDefaultAllow {}
+
+impl TbfHeaderFilterDefaultAllow {
+    pub const fn new() -> TbfHeaderFilterDefaultAllow {
+        TbfHeaderFilterDefaultAllow {}
+    }
+
+    pub fn filter_syscall(
+        &self,
+        process: &dyn Process,
+        syscall: &Syscall,
+    ) -> Result<(), ErrorCode> {
+        check_permissions(syscall, |driver_num, offset| {
+            process.get_command_permissions(driver_num, offset)
+        })
+    }
+}
+
+/// Check `syscall` against the permissions returned by `permissions`.
+fn check_permissions<F: Fn(usize, usize) -> CommandPermissions>(
+    syscall: &Syscall,
+    permissions: F,
+) -> Result<(), ErrorCode> {
+    match *syscall {
+        Syscall::Command {
+            driver_number,
+            subdriver_number,
+            ..
+        } => match permissions(driver_number, subdriver_number / COMMANDS_PER_MASK) {
+            CommandPermissions::NoPermsAtAll => Ok(()),
+            CommandPermissions::NoPermsThisDriver => Err(ErrorCode::NODEVICE),
+            CommandPermissions::Mask(allowed) => {
+                if allowed & (1 << (subdriver_number % COMMANDS_PER_MASK)) != 0 {
+                    Ok(())
+                } else {
+                    Err(ErrorCode::NODEVICE)
+                }
+            }
+        },
+        Syscall::Subscribe { driver_number, .. }
+        | Syscall::ReadWriteAllow { driver_number, .. }
+        | Syscall::ReadOnlyAllow { driver_number, .. } => match permissions(driver_number, 0) {
+            CommandPermissions::NoPermsThisDriver => Err(ErrorCode::NODEVICE),
+            _ => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(driver_number: usize, subdriver_number: usize) -> Syscall {
+        Syscall::Command {
+            driver_number,
+            subdriver_number,
+            arg0: 0,
+            arg1: 0,
+        }
+    }
+
+    #[test]
+    fn enforces_listed_commands() {
+        // Driver 1 may call commands 0, 1 and 65.
+        let perms = |driver_num, offset| match (driver_num, offset) {
+            (1, 0) => CommandPermissions::Mask(0b11),
+            (1, 1) => CommandPermissions::Mask(0b10),
+            (1, _) => CommandPermissions::Mask(0),
+            _ => CommandPermissions::NoPermsThisDriver,
+        };
+
+        assert_eq!(check_permissions(&command(1, 0), perms), Ok(()));
+        assert_eq!(check_permissions(&command(1, 1), perms), Ok(()));
+        assert_eq!(check_permissions(&command(1, 65), perms), Ok(()));
+        assert_eq!(
+            check_permissions(&command(1, 2), perms),
+            Err(ErrorCode::NODEVICE)
+        );
+        assert_eq!(
+            check_permissions(&command(1, 200), perms),
+            Err(ErrorCode::NODEVICE)
+        );
+        assert_eq!(
+            check_permissions(&command(2, 0), perms),
+            Err(ErrorCode::NODEVICE)
+        );
+
+        let subscribe = |driver_number| Syscall::Subscribe {
+            driver_number,
+            subdriver_number: 0,
+            upcall_ptr: core::ptr::null_mut(),
+            appdata: 0,
+        };
+        assert_eq!(check_permissions(&subscribe(1), perms), Ok(()));
+        assert_eq!(
+            check_permissions(&subscribe(2), perms),
+            Err(ErrorCode::NODEVICE)
+        );
+
+        // Without a permissions TLV everything is allowed.
+        let unrestricted = |_, _| CommandPermissions::NoPermsAtAll;
+        assert_eq!(check_permissions(&command(2, 7), unrestricted), Ok(()));
+        assert_eq!(check_permissions(&subscribe(2), unrestricted), Ok(()));
+    }
+}
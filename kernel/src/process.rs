@@ -11,10 +11,13 @@ use crate::errorcode::ErrorCode;
 use crate::ipc;
 use crate::mem::{ReadOnlyProcessBuffer, ReadWriteProcessBuffer};
 use crate::platform::mpu::{self};
+use crate::process_policies::ProcessQuotaCounts;
 use crate::sched::Kernel;
 use crate::syscall::{self, Syscall, SyscallReturn};
 use crate::upcall::UpcallId;
 
+use tock_tbf::types::CommandPermissions;
+
 /// Userspace process identifier.
 ///
 /// This should be treated as an opaque type that can be used to represent a
@@ -158,6 +161,25 @@ impl ProcessId {
             (start, end)
         })
     }
+
+    /// Returns the package name of the app this `ProcessId` refers to, or
+    /// `None` if the app no longer exists.
+    ///
+    /// Unlike `id()`, the package name is stable across reboots and can be
+    /// used to identify an app in persistent state kept by a capsule.
+    pub fn get_process_name(&self) -> Option<&'static str> {
+        self.kernel
+            .process_map_or(None, *self, |process| Some(process.get_process_name()))
+    }
+
+    /// Returns the persistent storage identifier and the number of bytes of
+    /// persistent storage the app requested in its TBF header. Returns `None`
+    /// if the app did not make such a request or no longer exists.
+    pub fn get_persistent_storage_request(&self) -> Option<(u32, u32)> {
+        self.kernel.process_map_or(None, *self, |process| {
+            process.get_persistent_storage_request()
+        })
+    }
 }
 
 /// This trait represents a generic process that the Tock scheduler can
@@ -303,6 +325,16 @@ pub trait Process {
     /// writeable flash region.
     fn get_writeable_flash_region(&self, region_index: usize) -> (u32, u32);
 
+    /// Get the persistent storage identifier and the number of bytes of
+    /// persistent storage requested in the TBF header for this process, if
+    /// the header contains such a request.
+    fn get_persistent_storage_request(&self) -> Option<(u32, u32)>;
+
+    /// Get the commands of driver `driver_num` this process may call, in the
+    /// block of 64 commands starting at `offset * 64`, as listed in the
+    /// permissions TLV of its TBF header.
+    fn get_command_permissions(&self, driver_num: usize, offset: usize) -> CommandPermissions;
+
     /// Debug function to update the kernel on where the stack starts for this
     /// process. Processes are not required to call this through the memop
     /// system call, but it aids in debugging the process.
@@ -312,6 +344,23 @@ pub trait Process {
     /// Also optional.
     fn update_heap_start_pointer(&self, heap_pointer: *const u8);
 
+    /// Place a guard region that the process cannot access right below
+    /// `stack_bottom`, the lowest address of its stack, so that a stack
+    /// overflow faults instead of corrupting the memory below the stack.
+    /// Faults with the stack pointer below `stack_bottom` are then reported
+    /// as stack overflows.
+    ///
+    /// Until a process calls this its stack bottom is the start of process
+    /// memory, where Tock's app layout puts the stack. No guard is needed
+    /// there, as the memory below is not accessible to the process, so every
+    /// process that follows the layout is guarded without calling this.
+    ///
+    /// Returns `ALREADY` if the stack was already set up, `INVAL` if
+    /// `stack_bottom` is not in the process's memory, and `NOSUPPORT` if the
+    /// MPU cannot place the guard region, for instance because `stack_bottom`
+    /// is not aligned as the MPU requires.
+    fn setup_stack_guard(&self, stack_bottom: *const u8) -> Result<(), ErrorCode>;
+
     // additional memop like functions
 
     /// Return the highest address the process has access to, or the current
@@ -362,6 +411,19 @@ pub trait Process {
         size: usize,
     ) -> Result<ReadOnlyProcessBuffer, ErrorCode>;
 
+    /// Checks if the process may allow `size` bytes in `slot` without
+    /// exceeding its quota of non-empty allowed buffers. A violation is
+    /// counted if not.
+    ///
+    /// The kernel asks this before passing the buffer to the capsule, and
+    /// fails the allow with `ErrorCode::NOMEM` without calling the capsule if
+    /// this returns false.
+    fn allow_quota_available(&self, slot: AllowSlot, size: usize) -> bool;
+
+    /// Record that a capsule accepted an allow of `size` bytes in `slot`, so
+    /// that the slot counts against the quota while the buffer is non-empty.
+    fn record_allowed_buffer(&self, slot: AllowSlot, size: usize);
+
     /// Set a single byte within the process address space at
     /// `addr` to `value`. Return true if `addr` is within the RAM
     /// bounds currently exposed to the process (thereby writable
@@ -536,6 +598,26 @@ pub trait Process {
     /// context, and the state of the memory protection unit (MPU).
     fn print_full_process(&self, writer: &mut dyn Write);
 
+    /// Return the registers of the process as they were when it last stopped
+    /// running, in the layout of an ELF core file. Returns `None` if the
+    /// architecture does not support core dumps.
+    fn get_core_registers(&self) -> Option<syscall::CoreRegisters>;
+
+    /// Replace the registers of the process, given in the layout
+    /// `get_core_registers()` returns. This is for debuggers, and only makes
+    /// sense while the process is stopped.
+    fn set_core_registers(&self, registers: &syscall::CoreRegisters) -> Result<(), ErrorCode>;
+
+    /// Copy the memory of the process starting at `address` into `buffer`.
+    /// The whole range must be in the process's flash, or in its RAM below
+    /// the application break, otherwise `Err(ErrorCode::INVAL)` is returned.
+    fn read_memory(&self, address: usize, buffer: &mut [u8]) -> Result<(), ErrorCode>;
+
+    /// Write `data` into the RAM of the process starting at `address`. The
+    /// whole range must be below the application break, otherwise
+    /// `Err(ErrorCode::INVAL)` is returned. Flash cannot be written this way.
+    fn write_memory(&self, address: usize, data: &[u8]) -> Result<(), ErrorCode>;
+
     // debug
 
     /// Returns how many syscalls this app has called.
@@ -550,6 +632,22 @@ pub trait Process {
     /// Increment the number of times the process has exceeded its timeslice.
     fn debug_timeslice_expired(&self);
 
+    /// Returns how many syscalls of this process the platform's syscall
+    /// filter has rejected.
+    fn debug_syscall_filter_violation_count(&self) -> usize;
+
+    /// Increment the number of syscalls rejected by the syscall filter.
+    fn debug_syscall_filtered(&self);
+
+    /// Returns how many times this process has hit each of its resource
+    /// quotas.
+    fn debug_quota_exceeded_counts(&self) -> ProcessQuotaCounts;
+
+    /// Returns whether the last fault of this process was a stack overflow
+    /// below the start of its memory, or below the stack set up with
+    /// `setup_stack_guard()`.
+    fn debug_stack_overflowed(&self) -> bool;
+
     /// Increment the number of times the process called a syscall and record
     /// the last syscall that was called.
     fn debug_syscall_called(&self, last_syscall: Syscall);
@@ -562,6 +660,9 @@ pub trait Process {
 
     /// Return the lowest recorded address of the process stack, if known.
     fn debug_stack_end(&self) -> Option<*const u8>;
+
+    /// Return the highest recorded app break of the process, if known.
+    fn debug_heap_end(&self) -> Option<*const u8>;
 }
 
 /// Opaque identifier for custom grants allocated dynamically from a process's
@@ -764,3 +865,130 @@ pub struct FunctionCall {
     pub argument3: usize,
     pub pc: usize,
 }
+
+/// Identifies one of a process's allow slots: the buffer shared with driver
+/// `driver_number` under `subdriver_number`, read-only and read-write buffers
+/// having separate slots.
+///
+/// This is public for external implementations of `Process`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AllowSlot {
+    pub driver_number: usize,
+    pub subdriver_number: usize,
+    pub read_only: bool,
+}
+
+/// Most allow slots whose buffers are tracked for the allow quota. A quota
+/// larger than this is treated as this.
+pub const MAX_ALLOWED_BUFFERS: usize = 16;
+
+/// The allow slots of a process that hold a non-empty buffer.
+///
+/// The kernel cannot ask a capsule what it holds without handing it the new
+/// buffer, so it keeps its own record of which slots are in use. This lets it
+/// check the allow quota before any capsule sees a buffer: replacing or
+/// unallowing a buffer in a slot already in use never grows the count.
+///
+/// This is public for external implementations of `Process`.
+#[derive(Default)]
+pub struct AllowedBuffers {
+    slots: [Cell<Option<AllowSlot>>; MAX_ALLOWED_BUFFERS],
+}
+
+impl AllowedBuffers {
+    /// Number of slots that hold a non-empty buffer.
+    pub fn count(&self) -> usize {
+        self.slots.iter().filter(|s| s.get().is_some()).count()
+    }
+
+    /// Whether allowing `size` bytes in `slot` keeps the process within
+    /// `limit` non-empty buffers.
+    pub fn fits(&self, slot: AllowSlot, size: usize, limit: usize) -> bool {
+        size == 0 || self.contains(slot) || self.count() < limit.min(MAX_ALLOWED_BUFFERS)
+    }
+
+    /// Record that a capsule accepted a buffer of `size` bytes in `slot`.
+    ///
+    /// A slot that does not fit is not recorded; that only happens for
+    /// processes without an allow quota, whose count is never checked.
+    pub fn record(&self, slot: AllowSlot, size: usize) {
+        if size == 0 {
+            self.slots
+                .iter()
+                .filter(|s| s.get() == Some(slot))
+                .for_each(|s| s.set(None));
+        } else if !self.contains(slot) {
+            if let Some(empty) = self.slots.iter().find(|s| s.get().is_none()) {
+                empty.set(Some(slot));
+            }
+        }
+    }
+
+    /// Forget every slot, as when the process restarts.
+    pub fn clear(&self) {
+        self.slots.iter().for_each(|s| s.set(None));
+    }
+
+    fn contains(&self, slot: AllowSlot) -> bool {
+        self.slots.iter().any(|s| s.get() == Some(slot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllowSlot, AllowedBuffers, MAX_ALLOWED_BUFFERS};
+
+    fn slot(subdriver_number: usize, read_only: bool) -> AllowSlot {
+        AllowSlot {
+            driver_number: 1,
+            subdriver_number,
+            read_only,
+        }
+    }
+
+    #[test]
+    fn allow_quota_counts_new_slots_only() {
+        let allowed = AllowedBuffers::default();
+
+        assert!(allowed.fits(slot(0, true), 4, 1));
+        allowed.record(slot(0, true), 4);
+        assert_eq!(allowed.count(), 1);
+
+        // Replacing the buffer in the same slot does not need room.
+        assert!(allowed.fits(slot(0, true), 8, 1));
+        allowed.record(slot(0, true), 8);
+        assert_eq!(allowed.count(), 1);
+
+        // A read-write buffer is another slot, and over the quota.
+        assert!(!allowed.fits(slot(0, false), 4, 1));
+
+        // Unallowing always fits, even with no room left.
+        assert!(allowed.fits(slot(0, false), 0, 1));
+    }
+
+    #[test]
+    fn allow_quota_unallow_frees_room() {
+        let allowed = AllowedBuffers::default();
+
+        allowed.record(slot(0, true), 4);
+        assert!(!allowed.fits(slot(1, true), 4, 1));
+
+        allowed.record(slot(0, true), 0);
+        assert_eq!(allowed.count(), 0);
+        assert!(allowed.fits(slot(1, true), 4, 1));
+    }
+
+    #[test]
+    fn allow_quota_capped_by_table() {
+        let allowed = AllowedBuffers::default();
+
+        for i in 0..MAX_ALLOWED_BUFFERS {
+            assert!(allowed.fits(slot(i, false), 4, usize::MAX));
+            allowed.record(slot(i, false), 4);
+        }
+        assert!(!allowed.fits(slot(MAX_ALLOWED_BUFFERS, false), 4, usize::MAX));
+
+        allowed.clear();
+        assert_eq!(allowed.count(), 0);
+    }
+}
@@ -0,0 +1,372 @@
+//! Core dumps of faulted processes in ELF core format.
+//!
+//! A `ProcessCoreDump` presents the state of a process as an ELF core file
+//! that `gdb` can load next to the app's ELF: a `NT_PRSTATUS` note with the
+//! registers the process had when it stopped running, a loadable segment with
+//! the RAM the process can access, and a segment without contents covering
+//! its flash, whose contents are in the app's ELF. The grant region and the
+//! rest of the kernel-owned memory at the top of the process's RAM are left
+//! out.
+//!
+//! The dump is not copied anywhere: reading it reads the process's memory, so
+//! it is only valid while that memory is untouched. `CoreDumpFaultPolicy`
+//! hands the dump of a faulted process to a `CoreDumpClient` before the fault
+//! is acted on, which is the last point where the memory is known to be
+//! intact.
+//!
+//! ```rust,ignore
+//! static FAULT_POLICY: CoreDumpFaultPolicy<StopFaultPolicy> =
+//!     CoreDumpFaultPolicy::new(StopFaultPolicy {});
+//! FAULT_POLICY.set_client(core_dump_capsule);
+//! ```
+
+use core::cmp;
+
+use crate::common::cells::OptionalCell;
+use crate::process::{self, Process};
+use crate::process_policies::ProcessFaultPolicy;
+use crate::syscall::{CoreRegisters, MAX_CORE_REGISTERS};
+
+const ELF_HEADER_LEN: usize = 52;
+const PROGRAM_HEADER_LEN: usize = 32;
+const PROGRAM_HEADERS: usize = 3;
+const NOTE_HEADER_LEN: usize = 12;
+/// "CORE", NUL terminated and padded to four bytes.
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+/// Bytes of `elf_prstatus` before `pr_reg`.
+const PRSTATUS_PREFIX_LEN: usize = 72;
+
+/// Largest size of everything before the process memory.
+const MAX_HEADERS_LEN: usize = ELF_HEADER_LEN
+    + PROGRAM_HEADERS * PROGRAM_HEADER_LEN
+    + NOTE_HEADER_LEN
+    + NOTE_NAME.len()
+    + PRSTATUS_PREFIX_LEN
+    + 4 * MAX_CORE_REGISTERS
+    + 4;
+
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const SIGSEGV: u16 = 11;
+
+/// Appends little endian values to a byte array.
+struct Encoder<'a> {
+    buffer: &'a mut [u8],
+    position: usize,
+}
+
+impl Encoder<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn program_header(
+        &mut self,
+        kind: u32,
+        offset: usize,
+        address: u32,
+        file_len: usize,
+        memory_len: usize,
+        flags: u32,
+    ) {
+        self.u32(kind);
+        self.u32(offset as u32);
+        self.u32(address);
+        self.u32(address);
+        self.u32(file_len as u32);
+        self.u32(memory_len as u32);
+        self.u32(flags);
+        self.u32(if kind == PT_LOAD { 4 } else { 0 });
+    }
+}
+
+/// The ELF core file of a process.
+pub struct ProcessCoreDump<'a> {
+    headers: [u8; MAX_HEADERS_LEN],
+    headers_len: usize,
+    memory: &'a [u8],
+}
+
+impl<'a> ProcessCoreDump<'a> {
+    /// Build the core dump of `process`. Returns `None` if the architecture
+    /// does not support core dumps.
+    pub fn new(process: &'a dyn Process) -> Option<ProcessCoreDump<'a>> {
+        let registers = process.get_core_registers()?;
+        let memory_start = process.mem_start();
+        let memory_len = process.app_memory_break() as usize - memory_start as usize;
+        // The memory between the start of the process's RAM and its break is
+        // allocated to the process and stays valid as long as `process` is
+        // borrowed.
+        let memory = unsafe { core::slice::from_raw_parts(memory_start, memory_len) };
+        let flash_start = process.flash_start() as u32;
+        let flash_len = process.flash_end() as usize - process.flash_start() as usize;
+
+        let mut dump = ProcessCoreDump {
+            headers: [0; MAX_HEADERS_LEN],
+            headers_len: 0,
+            memory,
+        };
+        dump.headers_len = Self::encode_headers(
+            &mut dump.headers,
+            &registers,
+            process.processid().id() as u32,
+            memory_start as u32,
+            memory_len,
+            flash_start,
+            flash_len,
+        );
+        Some(dump)
+    }
+
+    fn encode_headers(
+        buffer: &mut [u8],
+        registers: &CoreRegisters,
+        pid: u32,
+        memory_start: u32,
+        memory_len: usize,
+        flash_start: u32,
+        flash_len: usize,
+    ) -> usize {
+        let count = cmp::min(registers.count, MAX_CORE_REGISTERS);
+        let prstatus_len = PRSTATUS_PREFIX_LEN + 4 * count + 4;
+        let note_offset = ELF_HEADER_LEN + PROGRAM_HEADERS * PROGRAM_HEADER_LEN;
+        let note_len = NOTE_HEADER_LEN + NOTE_NAME.len() + prstatus_len;
+        let memory_offset = note_offset + note_len;
+
+        let mut elf = Encoder {
+            buffer,
+            position: 0,
+        };
+        // ELF header: 32 bit, little endian, version 1.
+        elf.bytes(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        elf.u16(ET_CORE);
+        elf.u16(registers.machine);
+        elf.u32(1);
+        elf.u32(0); // entry
+        elf.u32(ELF_HEADER_LEN as u32); // program headers
+        elf.u32(0); // section headers
+        elf.u32(0); // flags
+        elf.u16(ELF_HEADER_LEN as u16);
+        elf.u16(PROGRAM_HEADER_LEN as u16);
+        elf.u16(PROGRAM_HEADERS as u16);
+        elf.u16(0);
+        elf.u16(0);
+        elf.u16(0);
+
+        elf.program_header(PT_NOTE, note_offset, 0, note_len, 0, 0);
+        elf.program_header(
+            PT_LOAD,
+            memory_offset,
+            memory_start,
+            memory_len,
+            memory_len,
+            PF_R | PF_W,
+        );
+        elf.program_header(
+            PT_LOAD,
+            memory_offset + memory_len,
+            flash_start,
+            0,
+            flash_len,
+            PF_R | PF_X,
+        );
+
+        elf.u32(5); // "CORE" and its NUL
+        elf.u32(prstatus_len as u32);
+        elf.u32(NT_PRSTATUS);
+        elf.bytes(NOTE_NAME);
+
+        // elf_prstatus: the signal, the pid and the registers; the signal
+        // masks, the other ids and the times are left at zero.
+        let prstatus = elf.position;
+        elf.bytes(&[0; PRSTATUS_PREFIX_LEN]);
+        elf.buffer[prstatus + 12..prstatus + 14].copy_from_slice(&SIGSEGV.to_le_bytes());
+        elf.buffer[prstatus + 24..prstatus + 28].copy_from_slice(&pid.to_le_bytes());
+        for register in &registers.registers[..count] {
+            elf.u32(*register);
+        }
+        elf.u32(0); // pr_fpvalid
+
+        elf.position
+    }
+
+    /// Size of the core file in bytes.
+    pub fn len(&self) -> usize {
+        self.headers_len + self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy the bytes of the core file starting at `offset` into `buffer`.
+    /// Returns how many bytes were copied, which is only less than the length
+    /// of `buffer` at the end of the file.
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) -> usize {
+        let mut copied = 0;
+        let headers = &self.headers[..self.headers_len];
+        for (start, part) in [(0, headers), (self.headers_len, self.memory)].iter() {
+            let position = offset + copied;
+            if position >= start + part.len() || copied == buffer.len() {
+                continue;
+            }
+            let from = &part[position - start..];
+            let len = cmp::min(from.len(), buffer.len() - copied);
+            buffer[copied..copied + len].copy_from_slice(&from[..len]);
+            copied += len;
+        }
+        copied
+    }
+}
+
+/// Receives the core dumps taken by `CoreDumpFaultPolicy`.
+pub trait CoreDumpClient {
+    /// Called with the core dump of `process`, which just faulted, before the
+    /// fault policy acts on the fault. The dump reads the process's memory, so
+    /// it must be copied out before returning if it is needed later.
+    fn process_faulted(&self, process: &dyn Process, dump: &ProcessCoreDump);
+}
+
+/// Fault policy that takes a core dump of the faulted process and then
+/// responds to the fault as `policy` decides.
+pub struct CoreDumpFaultPolicy<P: ProcessFaultPolicy> {
+    policy: P,
+    client: OptionalCell<&'static dyn CoreDumpClient>,
+}
+
+impl<P: ProcessFaultPolicy> CoreDumpFaultPolicy<P> {
+    pub const fn new(policy: P) -> CoreDumpFaultPolicy<P> {
+        CoreDumpFaultPolicy {
+            policy,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static dyn CoreDumpClient) {
+        self.client.set(client);
+    }
+}
+
+impl<P: ProcessFaultPolicy> ProcessFaultPolicy for CoreDumpFaultPolicy<P> {
+    fn action(&self, process: &dyn Process) -> process::FaultAction {
+        self.client.map(|client| {
+            if let Some(dump) = ProcessCoreDump::new(process) {
+                client.process_faulted(process, &dump);
+            }
+        });
+        self.policy.action(process)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(memory: &[u8]) -> ProcessCoreDump {
+        let mut registers = CoreRegisters {
+            machine: 40,
+            registers: [0; MAX_CORE_REGISTERS],
+            count: 18,
+        };
+        registers.registers[15] = 0x0004_0123; // pc
+        let mut dump = ProcessCoreDump {
+            headers: [0; MAX_HEADERS_LEN],
+            headers_len: 0,
+            memory,
+        };
+        dump.headers_len = ProcessCoreDump::encode_headers(
+            &mut dump.headers,
+            &registers,
+            3,
+            0x2000_4000,
+            memory.len(),
+            0x0004_0000,
+            0x1000,
+        );
+        dump
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    }
+
+    #[test]
+    fn core_file_layout() {
+        let memory = [0x5A; 64];
+        let dump = dump(&memory);
+        let mut file = [0; 512];
+        let len = dump.read(0, &mut file);
+        assert_eq!(len, dump.len());
+        let file = &file[..len];
+
+        assert_eq!(&file[..4], b"\x7fELF");
+        assert_eq!(u16::from_le_bytes([file[16], file[17]]), ET_CORE);
+        assert_eq!(u16::from_le_bytes([file[18], file[19]]), 40);
+
+        // The note holds the ARM prstatus, 148 bytes, with pc at r15.
+        let note = ELF_HEADER_LEN;
+        assert_eq!(u32_at(file, note), PT_NOTE);
+        let note_offset = u32_at(file, note + 4) as usize;
+        assert_eq!(u32_at(file, note_offset + 4), 148);
+        let registers = note_offset + 20 + PRSTATUS_PREFIX_LEN;
+        assert_eq!(u32_at(file, registers + 15 * 4), 0x0004_0123);
+
+        // The RAM segment points at the process memory.
+        let ram = ELF_HEADER_LEN + PROGRAM_HEADER_LEN;
+        assert_eq!(u32_at(file, ram), PT_LOAD);
+        let ram_offset = u32_at(file, ram + 4) as usize;
+        assert_eq!(u32_at(file, ram + 8), 0x2000_4000);
+        assert_eq!(u32_at(file, ram + 16), 64);
+        assert_eq!(&file[ram_offset..ram_offset + 64], &memory[..]);
+
+        // The flash segment has no contents.
+        let flash = ram + PROGRAM_HEADER_LEN;
+        assert_eq!(u32_at(file, flash + 16), 0);
+        assert_eq!(u32_at(file, flash + 20), 0x1000);
+    }
+
+    #[test]
+    fn read_in_chunks() {
+        let mut memory = [0; 100];
+        for (i, byte) in memory.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let dump = dump(&memory);
+        let mut whole = [0; 512];
+        let len = dump.read(0, &mut whole);
+
+        let mut chunked = [0; 512];
+        let mut offset = 0;
+        loop {
+            let mut chunk = [0; 7];
+            let read = dump.read(offset, &mut chunk);
+            chunked[offset..offset + read].copy_from_slice(&chunk[..read]);
+            offset += read;
+            if read < chunk.len() {
+                break;
+            }
+        }
+        assert_eq!(offset, len);
+        assert_eq!(&chunked[..len], &whole[..len]);
+        assert_eq!(dump.read(len, &mut [0; 4]), 0);
+    }
+}
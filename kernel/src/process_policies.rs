@@ -42,10 +42,17 @@ pub struct StopWithDebugFaultPolicy {}
 
 impl ProcessFaultPolicy for StopWithDebugFaultPolicy {
     fn action(&self, process: &dyn Process) -> process::FaultAction {
-        crate::debug!(
-            "Process {} faulted and was stopped.",
-            process.get_process_name()
-        );
+        if process.debug_stack_overflowed() {
+            crate::debug!(
+                "Process {} overflowed its stack and was stopped.",
+                process.get_process_name()
+            );
+        } else {
+            crate::debug!(
+                "Process {} faulted and was stopped.",
+                process.get_process_name()
+            );
+        }
         process::FaultAction::Stop
     }
 }
@@ -105,3 +112,69 @@ impl ProcessFaultPolicy for ThresholdRestartThenPanicFaultPolicy {
         }
     }
 }
+
+/// Limits on the kernel resources a single process may consume.
+///
+/// A limit of `None` leaves that resource bounded only by what the process
+/// has available: its grant region, and the size of its task queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessQuotas {
+    /// Largest grant, in bytes, a driver may allocate for the process. This
+    /// covers the grant itself and its upcalls, not custom grants allocated
+    /// with a `GrantRegionAllocator`.
+    pub grant_bytes_per_driver: Option<usize>,
+    /// Most upcalls and IPC tasks that may be queued for the process.
+    pub pending_tasks: Option<usize>,
+    /// Most non-empty buffers the process may have allowed at once, read-only
+    /// and read-write combined. At most `MAX_ALLOWED_BUFFERS` are tracked, so
+    /// larger limits act as that.
+    pub allow_buffers: Option<usize>,
+}
+
+impl ProcessQuotas {
+    /// No limits.
+    pub const fn unlimited() -> ProcessQuotas {
+        ProcessQuotas {
+            grant_bytes_per_driver: None,
+            pending_tasks: None,
+            allow_buffers: None,
+        }
+    }
+}
+
+/// How many times a process hit each of its quotas.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessQuotaCounts {
+    pub grant_bytes_per_driver: usize,
+    pub pending_tasks: usize,
+    pub allow_buffers: usize,
+}
+
+/// Generic trait for implementing a policy on how many kernel resources each
+/// process may use.
+///
+/// The kernel asks the policy whenever a process allocates a grant, has a
+/// task queued or allows a buffer, and fails the operation with
+/// `ErrorCode::NOMEM` if it would exceed the quota. The policy is installed
+/// with `Kernel::set_quota_policy()`; without one processes are unlimited.
+pub trait ProcessQuotaPolicy {
+    /// The quotas that apply to `process`.
+    fn quotas(&self, process: &dyn Process) -> ProcessQuotas;
+}
+
+/// Apply the same quotas to every process.
+pub struct FixedQuotaPolicy {
+    quotas: ProcessQuotas,
+}
+
+impl FixedQuotaPolicy {
+    pub const fn new(quotas: ProcessQuotas) -> FixedQuotaPolicy {
+        FixedQuotaPolicy { quotas }
+    }
+}
+
+impl ProcessQuotaPolicy for FixedQuotaPolicy {
+    fn quotas(&self, _: &dyn Process) -> ProcessQuotas {
+        self.quotas
+    }
+}
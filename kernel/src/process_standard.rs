@@ -17,9 +17,10 @@ use crate::errorcode::ErrorCode;
 use crate::mem::{ReadOnlyProcessBuffer, ReadWriteProcessBuffer};
 use crate::platform::mpu::{self, MPU};
 use crate::platform::Chip;
-use crate::process::{Error, FunctionCall, FunctionCallSource, Process, State, Task};
+use crate::process::{AllowSlot, AllowedBuffers, Error, FunctionCall, FunctionCallSource};
 use crate::process::{FaultAction, ProcessCustomGrantIdentifer, ProcessId, ProcessStateCell};
-use crate::process_policies::ProcessFaultPolicy;
+use crate::process::{Process, State, Task};
+use crate::process_policies::{ProcessFaultPolicy, ProcessQuotaCounts};
 use crate::process_utilities::ProcessLoadError;
 use crate::sched::Kernel;
 use crate::syscall::{self, Syscall, SyscallReturn, UserspaceKernelBoundary};
@@ -28,6 +29,9 @@ use crate::upcall::UpcallId;
 // The completion code for a process if it faulted.
 const COMPLETION_FAULT: u32 = 0xffffffff;
 
+// Minimum size of the guard region placed below a process stack.
+const STACK_GUARD_SIZE: usize = 32;
+
 /// State for helping with debugging apps.
 ///
 /// These pointers and counters are not strictly required for kernel operation,
@@ -54,6 +58,9 @@ struct ProcessStandardDebug {
     /// How low have we ever seen the stack pointer.
     app_stack_min_pointer: Option<*const u8>,
 
+    /// How high have we ever seen the app break.
+    app_break_max_pointer: Option<*const u8>,
+
     /// How many syscalls have occurred since the process started.
     syscall_count: usize,
 
@@ -67,6 +74,12 @@ struct ProcessStandardDebug {
     /// How many times this process has been paused because it exceeded its
     /// timeslice.
     timeslice_expiration_count: usize,
+
+    /// How many syscalls were rejected by the platform's syscall filter.
+    syscall_filter_violation_count: usize,
+
+    /// How many times the process hit each of its resource quotas.
+    quota_exceeded: ProcessQuotaCounts,
 }
 
 /// Entry that is stored in the grant pointer table at the top of process
@@ -160,6 +173,21 @@ pub struct ProcessStandard<'a, C: 'static + Chip> {
     /// Pointer to high water mark for process buffers shared through `allow`
     allow_high_water_mark: Cell<*const u8>,
 
+    /// Allow slots currently holding a non-empty buffer, counted against the
+    /// process's quota.
+    allowed_buffers: AllowedBuffers,
+
+    /// Lowest address of the process stack, if the process specified it.
+    /// Otherwise the stack is taken to start at the start of process memory.
+    stack_bottom: Cell<Option<*const u8>>,
+
+    /// Region below the stack the process cannot access, if one is needed.
+    stack_guard: Cell<Option<mpu::Region>>,
+
+    /// Whether the last fault was a stack overflow. This is kept across
+    /// restarts so that the cause of a fault can still be inspected.
+    stack_overflowed: Cell<bool>,
+
     /// Process flash segment. This is the region of nonvolatile flash that
     /// the process occupies.
     flash: &'static [u8],
@@ -221,6 +249,15 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         }
 
         let ret = self.tasks.map_or(Err(ErrorCode::FAIL), |tasks| {
+            if let Some(limit) = self.kernel.process_quotas(self).pending_tasks {
+                if tasks.len() >= limit {
+                    // The task would exceed the process's quota.
+                    self.debug.map(|debug| {
+                        debug.quota_exceeded.pending_tasks += 1;
+                    });
+                    return Err(ErrorCode::NOMEM);
+                }
+            }
             match tasks.enqueue(task) {
                 true => {
                     // The task has been successfully enqueued.
@@ -319,6 +356,9 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             FaultAction::Panic => {
                 // process faulted. Panic and print status
                 self.state.update(State::Faulted);
+                if self.stack_overflowed.get() {
+                    panic!("Process {} overflowed its stack", self.process_name);
+                }
                 panic!("Process {} had a fault", self.process_name);
             }
             FaultAction::Restart => {
@@ -420,6 +460,18 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         self.header.get_writeable_flash_region(region_index)
     }
 
+    fn get_persistent_storage_request(&self) -> Option<(u32, u32)> {
+        self.header.get_persistent_storage_request()
+    }
+
+    fn get_command_permissions(
+        &self,
+        driver_num: usize,
+        offset: usize,
+    ) -> tock_tbf::types::CommandPermissions {
+        self.header.get_command_permissions(driver_num, offset)
+    }
+
     fn update_stack_start_pointer(&self, stack_pointer: *const u8) {
         if stack_pointer >= self.mem_start() && stack_pointer < self.mem_end() {
             self.debug.map(|debug| {
@@ -440,6 +492,41 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         }
     }
 
+    fn setup_stack_guard(&self, stack_bottom: *const u8) -> Result<(), ErrorCode> {
+        // Do not modify an inactive process.
+        if !self.is_active() {
+            return Err(ErrorCode::FAIL);
+        }
+
+        if self.stack_bottom.get().is_some() {
+            return Err(ErrorCode::ALREADY);
+        }
+
+        if stack_bottom < self.mem_start() || stack_bottom >= self.app_break.get() {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Memory below the start of process memory is already inaccessible
+        // to the process, so only a stack above it needs a guard.
+        if stack_bottom > self.mem_start() {
+            let guard = self
+                .mpu_config
+                .map_or(None, |config| {
+                    self.chip.mpu().allocate_guard_region(
+                        self.mem_start(),
+                        stack_bottom,
+                        STACK_GUARD_SIZE,
+                        config,
+                    )
+                })
+                .ok_or(ErrorCode::NOSUPPORT)?;
+            self.stack_guard.set(Some(guard));
+        }
+
+        self.stack_bottom.set(Some(stack_bottom));
+        Ok(())
+    }
+
     fn app_memory_break(&self) -> *const u8 {
         self.app_break.get()
     }
@@ -513,6 +600,14 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
                 } else {
                     let old_break = self.app_break.get();
                     self.app_break.set(new_break);
+                    self.debug.map(|debug| {
+                        if debug
+                            .app_break_max_pointer
+                            .map_or(true, |max| new_break > max)
+                        {
+                            debug.app_break_max_pointer = Some(new_break);
+                        }
+                    });
                     self.chip.mpu().configure_mpu(&config, &self.processid());
                     Ok(old_break)
                 }
@@ -660,6 +755,22 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         }
     }
 
+    fn allow_quota_available(&self, slot: AllowSlot, size: usize) -> bool {
+        match self.kernel.process_quotas(self).allow_buffers {
+            Some(limit) if !self.allowed_buffers.fits(slot, size, limit) => {
+                self.debug.map(|debug| {
+                    debug.quota_exceeded.allow_buffers += 1;
+                });
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn record_allowed_buffer(&self, slot: AllowSlot, size: usize) {
+        self.allowed_buffers.record(slot, size);
+    }
+
     unsafe fn set_byte(&self, addr: *mut u8, value: u8) -> bool {
         if self.in_app_owned_memory(addr, 1) {
             // We verify that this will only write process-accessible memory,
@@ -729,6 +840,16 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             return None;
         }
 
+        // Verify that the grant fits in the process's quota.
+        if let Some(limit) = self.kernel.process_quotas(self).grant_bytes_per_driver {
+            if size > limit {
+                self.debug.map(|debug| {
+                    debug.quota_exceeded.grant_bytes_per_driver += 1;
+                });
+                return None;
+            }
+        }
+
         // Use the shared grant allocator function to actually allocate memory.
         // Returns `None` if the allocation cannot be created.
         if let Some(grant_ptr) = self.allocate_in_grant_region_internal(size, align) {
@@ -1006,6 +1127,14 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
                 }
             });
 
+        // Classify faults with the stack pointer below the stack as stack
+        // overflows.
+        if let Some(syscall::ContextSwitchReason::Fault) = switch_reason {
+            let stack_bottom = self.stack_bottom.get().unwrap_or(self.mem_start());
+            let overflowed = stack_pointer.map_or(false, |sp| sp < stack_bottom);
+            self.stack_overflowed.set(overflowed);
+        }
+
         // If the UKB implementation passed us a stack pointer, update our
         // debugging state. This is completely optional.
         stack_pointer.map(|sp| {
@@ -1043,6 +1172,25 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             .map(|debug| debug.timeslice_expiration_count += 1);
     }
 
+    fn debug_syscall_filter_violation_count(&self) -> usize {
+        self.debug
+            .map_or(0, |debug| debug.syscall_filter_violation_count)
+    }
+
+    fn debug_syscall_filtered(&self) {
+        self.debug
+            .map(|debug| debug.syscall_filter_violation_count += 1);
+    }
+
+    fn debug_quota_exceeded_counts(&self) -> ProcessQuotaCounts {
+        self.debug
+            .map_or(ProcessQuotaCounts::default(), |debug| debug.quota_exceeded)
+    }
+
+    fn debug_stack_overflowed(&self) -> bool {
+        self.stack_overflowed.get()
+    }
+
     fn debug_syscall_called(&self, last_syscall: Syscall) {
         self.debug.map(|debug| {
             debug.syscall_count += 1;
@@ -1065,6 +1213,17 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             .map_or(None, |debug| debug.app_stack_min_pointer.map(|p| p))
     }
 
+    fn debug_heap_end(&self) -> Option<*const u8> {
+        let app_break = self.app_break.get();
+        self.debug.map_or(None, |debug| {
+            Some(
+                debug
+                    .app_break_max_pointer
+                    .map_or(app_break, |max| cmp::max(max, app_break)),
+            )
+        })
+    }
+
     fn print_memory_map(&self, writer: &mut dyn Write) {
         // Flash
         let flash_end = self.flash.as_ptr().wrapping_add(self.flash.len()) as usize;
@@ -1097,19 +1256,29 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         let syscall_count = self.debug.map_or(0, |debug| debug.syscall_count);
         let last_syscall = self.debug.map(|debug| debug.last_syscall);
         let dropped_upcall_count = self.debug.map_or(0, |debug| debug.dropped_upcall_count);
+        let filtered_count = self
+            .debug
+            .map_or(0, |debug| debug.syscall_filter_violation_count);
+        let quota_exceeded = self.debug_quota_exceeded_counts();
         let restart_count = self.restart_count.get();
 
         let _ = writer.write_fmt(format_args!(
             "\
              𝐀𝐩𝐩: {}   -   [{:?}]\
              \r\n Events Queued: {}   Syscall Count: {}   Dropped Upcall Count: {}\
-             \r\n Restart Count: {}\r\n",
+             \r\n Restart Count: {}   Filtered Syscall Count: {}\
+             \r\n Quota Exceeded: Grant: {}   Tasks: {}   Allow: {}   Allowed Buffers: {}\r\n",
             self.process_name,
             self.state.get(),
             events_queued,
             syscall_count,
             dropped_upcall_count,
             restart_count,
+            filtered_count,
+            quota_exceeded.grant_bytes_per_driver,
+            quota_exceeded.pending_tasks,
+            quota_exceeded.allow_buffers,
+            self.allowed_buffers.count(),
         ));
 
         let _ = match last_syscall {
@@ -1117,6 +1286,18 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             None => writer.write_str(" Last Syscall: None\r\n"),
         };
 
+        if let Some(guard) = self.stack_guard.get() {
+            let guard_start = guard.start_address() as usize;
+            let _ = writer.write_fmt(format_args!(
+                " Stack Guard: {:#010X}-{:#010X}\r\n",
+                guard_start,
+                guard_start + guard.size(),
+            ));
+        }
+        if self.stack_overflowed.get() {
+            let _ = writer.write_str(" Last Fault: Stack overflow\r\n");
+        }
+
         let _ = writer.write_fmt(format_args!(
             "\
              \r\n\
@@ -1224,6 +1405,61 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         ));
     }
 
+    fn get_core_registers(&self) -> Option<syscall::CoreRegisters> {
+        self.stored_state.map_or(None, |stored_state| {
+            // We guarantee the memory bounds pointers provided to the UKB are
+            // correct.
+            unsafe {
+                self.chip.userspace_kernel_boundary().core_registers(
+                    self.mem_start(),
+                    self.app_break.get(),
+                    stored_state,
+                )
+            }
+        })
+    }
+
+    fn set_core_registers(&self, registers: &syscall::CoreRegisters) -> Result<(), ErrorCode> {
+        self.stored_state
+            .map_or(Err(ErrorCode::FAIL), |stored_state| {
+                // We guarantee the memory bounds pointers provided to the UKB
+                // are correct.
+                unsafe {
+                    self.chip.userspace_kernel_boundary().set_core_registers(
+                        self.mem_start(),
+                        self.app_break.get(),
+                        stored_state,
+                        registers,
+                    )
+                }
+            })
+    }
+
+    fn read_memory(&self, address: usize, buffer: &mut [u8]) -> Result<(), ErrorCode> {
+        let end = address.checked_add(buffer.len()).ok_or(ErrorCode::INVAL)?;
+        let in_flash = address >= self.flash_start() as usize && end <= self.flash_end() as usize;
+        let in_ram = address >= self.mem_start() as usize && end <= self.app_break.get() as usize;
+        if !in_flash && !in_ram {
+            return Err(ErrorCode::INVAL);
+        }
+        // The range was checked to be in the process's flash or in RAM that
+        // is allocated to the process.
+        let memory = unsafe { slice::from_raw_parts(address as *const u8, buffer.len()) };
+        buffer.copy_from_slice(memory);
+        Ok(())
+    }
+
+    fn write_memory(&self, address: usize, data: &[u8]) -> Result<(), ErrorCode> {
+        let end = address.checked_add(data.len()).ok_or(ErrorCode::INVAL)?;
+        if address < self.mem_start() as usize || end > self.app_break.get() as usize {
+            return Err(ErrorCode::INVAL);
+        }
+        // The range was checked to be in RAM that is allocated to the process.
+        let memory = unsafe { slice::from_raw_parts_mut(address as *mut u8, data.len()) };
+        memory.copy_from_slice(data);
+        Ok(())
+    }
+
     fn print_full_process(&self, writer: &mut dyn Write) {
         self.print_memory_map(writer);
 
@@ -1645,6 +1881,10 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         process.kernel = kernel;
         process.chip = chip;
         process.allow_high_water_mark = Cell::new(initial_allow_high_water_mark);
+        process.allowed_buffers = AllowedBuffers::default();
+        process.stack_bottom = Cell::new(None);
+        process.stack_guard = Cell::new(None);
+        process.stack_overflowed = Cell::new(false);
         process.memory_start = app_memory.as_ptr();
         process.memory_len = app_memory.len();
         process.header = tbf_header;
@@ -1678,10 +1918,13 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
             app_heap_start_pointer: None,
             app_stack_start_pointer: None,
             app_stack_min_pointer: None,
+            app_break_max_pointer: None,
             syscall_count: 0,
             last_syscall: None,
             dropped_upcall_count: 0,
             timeslice_expiration_count: 0,
+            syscall_filter_violation_count: 0,
+            quota_exceeded: ProcessQuotaCounts::default(),
         });
 
         let flash_protected_size = process.header.get_protected_size() as usize;
@@ -1750,10 +1993,13 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
 
         // Reset debug information that is per-execution and not per-process.
         self.debug.map(|debug| {
+            debug.app_break_max_pointer = None;
             debug.syscall_count = 0;
             debug.last_syscall = None;
             debug.dropped_upcall_count = 0;
             debug.timeslice_expiration_count = 0;
+            debug.syscall_filter_violation_count = 0;
+            debug.quota_exceeded = ProcessQuotaCounts::default();
         });
 
         // FLASH
@@ -1839,6 +2085,12 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         // High water mark for `allow`ed memory is reset to the start of the
         // process's memory region.
         self.allow_high_water_mark.set(app_mpu_mem_start);
+        // The grants holding allowed buffers have been cleared.
+        self.allowed_buffers.clear();
+        // The stack guard was part of the old MPU configuration. The process
+        // sets it up again when it starts.
+        self.stack_bottom.set(None);
+        self.stack_guard.set(None);
 
         // Drop the old config and use the clean one
         self.mpu_config.replace(mpu_config);
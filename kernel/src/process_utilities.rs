@@ -6,6 +6,7 @@ use core::fmt;
 use crate::capabilities::ProcessManagementCapability;
 use crate::config;
 use crate::debug;
+use crate::measurement::MeasurementLog;
 use crate::platform::Chip;
 use crate::process::Process;
 use crate::process_policies::ProcessFaultPolicy;
@@ -132,6 +133,56 @@ pub fn load_processes<C: Chip>(
     app_memory: &mut [u8], // not static, so that process.rs cannot hold on to slice w/o unsafe
     procs: &'static mut [Option<&'static dyn Process>],
     fault_policy: &'static dyn ProcessFaultPolicy,
+    capability: &dyn ProcessManagementCapability,
+) -> Result<(), ProcessLoadError> {
+    load_processes_inner(
+        kernel,
+        chip,
+        app_flash,
+        app_memory,
+        procs,
+        fault_policy,
+        None,
+        capability,
+    )
+}
+
+/// Load processes like `load_processes()`, additionally recording the TBF of
+/// every process created in `log`.
+///
+/// Each entry covers the whole TBF (header and binary) in flash and is named
+/// after the process. Boards that want the kernel to appear in the
+/// measurement chain should record it in `log` before calling this function.
+pub fn load_processes_measured<C: Chip>(
+    kernel: &'static Kernel,
+    chip: &'static C,
+    app_flash: &'static [u8],
+    app_memory: &mut [u8],
+    procs: &'static mut [Option<&'static dyn Process>],
+    fault_policy: &'static dyn ProcessFaultPolicy,
+    log: &MeasurementLog,
+    capability: &dyn ProcessManagementCapability,
+) -> Result<(), ProcessLoadError> {
+    load_processes_inner(
+        kernel,
+        chip,
+        app_flash,
+        app_memory,
+        procs,
+        fault_policy,
+        Some(log),
+        capability,
+    )
+}
+
+fn load_processes_inner<C: Chip>(
+    kernel: &'static Kernel,
+    chip: &'static C,
+    app_flash: &'static [u8],
+    app_memory: &mut [u8],
+    procs: &'static mut [Option<&'static dyn Process>],
+    fault_policy: &'static dyn ProcessFaultPolicy,
+    log: Option<&MeasurementLog>,
     _capability: &dyn ProcessManagementCapability,
 ) -> Result<(), ProcessLoadError> {
     if config::CONFIG.debug_load_processes {
@@ -234,6 +285,10 @@ pub fn load_processes<C: Chip>(
                     );
                 }
 
+                if let Some(log) = log {
+                    log.record(process.get_process_name(), entry_flash);
+                }
+
                 // Save the reference to this process in the processes array.
                 procs[i] = Some(process);
             });
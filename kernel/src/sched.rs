@@ -14,7 +14,7 @@ use core::cell::Cell;
 use core::ptr::NonNull;
 
 use crate::capabilities;
-use crate::common::cells::NumericCellExt;
+use crate::common::cells::{NumericCellExt, OptionalCell};
 use crate::common::dynamic_deferred_call::DynamicDeferredCall;
 use crate::config;
 use crate::debug;
@@ -28,7 +28,8 @@ use crate::platform::scheduler_timer::SchedulerTimer;
 use crate::platform::watchdog::WatchDog;
 use crate::platform::{Chip, Platform};
 use crate::process::ProcessId;
-use crate::process::{self, Task};
+use crate::process::{self, AllowSlot, Task};
+use crate::process_policies::{ProcessQuotaPolicy, ProcessQuotas};
 use crate::syscall::{ContextSwitchReason, SyscallReturn};
 use crate::syscall::{Syscall, YieldCall};
 use crate::upcall::{Upcall, UpcallId};
@@ -38,6 +39,10 @@ use crate::upcall::{Upcall, UpcallId};
 /// is less than this threshold.
 pub(crate) const MIN_QUANTA_THRESHOLD_US: u32 = 500;
 
+/// How many grants the kernel remembers the driver number of, for debugging
+/// tools such as the process console.
+const GRANT_DRIVER_NUMS: usize = 32;
+
 /// Trait which any scheduler must implement.
 pub trait Scheduler<C: Chip> {
     /// Decide which process to run next.
@@ -146,6 +151,13 @@ pub struct Kernel {
     /// created and the data structures for grants have already been
     /// established.
     grants_finalized: Cell<bool>,
+
+    /// The driver number each grant was created for, indexed by grant number.
+    /// Only the first `GRANT_DRIVER_NUMS` grants are recorded.
+    grant_driver_nums: [Cell<usize>; GRANT_DRIVER_NUMS],
+
+    /// Limits on the resources each process may use, if the board set any.
+    quota_policy: OptionalCell<&'static dyn ProcessQuotaPolicy>,
 }
 
 /// Enum used to inform scheduler why a process stopped executing (aka why
@@ -181,9 +193,29 @@ impl Kernel {
             process_identifier_max: Cell::new(0),
             grant_counter: Cell::new(0),
             grants_finalized: Cell::new(false),
+            grant_driver_nums: Default::default(),
+            quota_policy: OptionalCell::empty(),
         }
     }
 
+    /// Limit the kernel resources each process may use with `policy`.
+    ///
+    /// Without a policy processes are only limited by the size of their grant
+    /// region and task queue.
+    pub fn set_quota_policy(
+        &self,
+        policy: &'static dyn ProcessQuotaPolicy,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.quota_policy.set(policy);
+    }
+
+    /// The quotas that apply to `process`.
+    pub(crate) fn process_quotas(&self, process: &dyn process::Process) -> ProcessQuotas {
+        self.quota_policy
+            .map_or(ProcessQuotas::unlimited(), |policy| policy.quotas(process))
+    }
+
     /// Something was scheduled for a process, so there is more work to do.
     ///
     /// This is only exposed in the core kernel crate.
@@ -397,9 +429,31 @@ impl Kernel {
         // Create and return a new grant.
         let grant_index = self.grant_counter.get();
         self.grant_counter.increment();
+        if let Some(entry) = self.grant_driver_nums.get(grant_index) {
+            entry.set(driver_num);
+        }
         Grant::new(self, driver_num, grant_index)
     }
 
+    /// Returns the driver number grant `grant_num` was created for, or `None`
+    /// if that grant does not exist or its driver number was not recorded.
+    pub(crate) fn grant_driver_num(&self, grant_num: usize) -> Option<usize> {
+        if grant_num < self.grant_counter.get() {
+            self.grant_driver_nums
+                .get(grant_num)
+                .map(|entry| entry.get())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of grants that have been setup in the system so
+    /// far, without finalizing them. Use this to report on grants, rather than
+    /// to size data structures.
+    pub(crate) fn get_grant_count(&self) -> usize {
+        self.grant_counter.get()
+    }
+
     /// Returns the number of grants that have been setup in the system and
     /// marks the grants as "finalized". This means that no more grants can
     /// be created because data structures have been setup based on the number
@@ -807,6 +861,26 @@ impl Kernel {
         (return_reason, time_executed_us)
     }
 
+    /// Invoke a system call on behalf of a process, outside of the kernel
+    /// loop, exactly as if the process had just made it.
+    ///
+    /// This lets host-side test harnesses drive capsules through the same
+    /// filtering, dispatch and buffer validation as processes running on
+    /// hardware. The result is passed to the process with
+    /// `set_syscall_return_value()`.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn handle_syscall_external<P: Platform>(
+        &self,
+        platform: &P,
+        process: &dyn process::Process,
+        syscall: Syscall,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.handle_syscall(platform, process, syscall);
+    }
+
     /// Method to invoke a system call on a particular process.
     /// Applies the kernel system call filtering policy (if any).
     /// Handles `Yield` and `Exit`, dispatches `Memop` to `memop::memop`,
@@ -852,6 +926,7 @@ impl Kernel {
             _ => {
                 // Check all other syscalls for filtering
                 if let Err(response) = platform.filter_syscall(process, &syscall) {
+                    process.debug_syscall_filtered();
                     process.set_syscall_return_value(SyscallReturn::Failure(response));
 
                     return;
@@ -1111,7 +1186,22 @@ impl Kernel {
                 allow_address,
                 allow_size,
             } => {
+                let slot = AllowSlot {
+                    driver_number,
+                    subdriver_number,
+                    read_only: false,
+                };
                 let res = platform.with_driver(driver_number, |driver| match driver {
+                    Some(_) if !process.allow_quota_available(slot, allow_size) => {
+                        // Sharing the buffer would take the process over its
+                        // quota of allowed buffers. Fail the allow before the
+                        // capsule sees it.
+                        SyscallReturn::AllowReadWriteFailure(
+                            ErrorCode::NOMEM,
+                            allow_address,
+                            allow_size,
+                        )
+                    }
                     Some(d) => {
                         // Try to create an appropriate [`ReadWriteProcessBuffer`].
                         // This method will ensure that the memory in question
@@ -1130,6 +1220,7 @@ impl Kernel {
                                         // operation. Pass the previous buffer
                                         // information back to the process.
                                         let (ptr, len) = returned_pbuf.consume();
+                                        process.record_allowed_buffer(slot, allow_size);
                                         SyscallReturn::AllowReadWriteSuccess(ptr, len)
                                     }
                                     Err((rejected_pbuf, err)) => {
@@ -1178,7 +1269,22 @@ impl Kernel {
                 allow_address,
                 allow_size,
             } => {
+                let slot = AllowSlot {
+                    driver_number,
+                    subdriver_number,
+                    read_only: true,
+                };
                 let res = platform.with_driver(driver_number, |driver| match driver {
+                    Some(_) if !process.allow_quota_available(slot, allow_size) => {
+                        // Sharing the buffer would take the process over its
+                        // quota of allowed buffers. Fail the allow before the
+                        // capsule sees it.
+                        SyscallReturn::AllowReadOnlyFailure(
+                            ErrorCode::NOMEM,
+                            allow_address,
+                            allow_size,
+                        )
+                    }
                     Some(d) => {
                         // Try to create an appropriate [`ReadOnlyProcessBuffer`].
                         // This method will ensure that the memory in question
@@ -1197,6 +1303,7 @@ impl Kernel {
                                         // operation. Pass the previous buffer
                                         // information back to the process.
                                         let (ptr, len) = returned_pbuf.consume();
+                                        process.record_allowed_buffer(slot, allow_size);
                                         SyscallReturn::AllowReadOnlySuccess(ptr, len)
                                     }
                                     Err((rejected_pbuf, err)) => {
@@ -430,6 +430,21 @@ pub enum ContextSwitchReason {
     Interrupted,
 }
 
+/// Most registers an architecture stores in an ELF core file.
+pub const MAX_CORE_REGISTERS: usize = 32;
+
+/// Registers of a process, laid out as in the `NT_PRSTATUS` note of an ELF
+/// core file for the architecture.
+#[derive(Copy, Clone, Debug)]
+pub struct CoreRegisters {
+    /// ELF `e_machine` value of the architecture.
+    pub machine: u16,
+    /// The registers, in the order of the architecture's `elf_gregset_t`.
+    /// Only the first `count` are used.
+    pub registers: [u32; MAX_CORE_REGISTERS],
+    pub count: usize,
+}
+
 /// The `UserspaceKernelBoundary` trait is implemented by the
 /// architectural component of the chip implementation of Tock. This
 /// trait allows the kernel to switch to and from processes
@@ -608,4 +623,49 @@ pub trait UserspaceKernelBoundary {
         state: &Self::StoredState,
         writer: &mut dyn Write,
     );
+
+    /// Return the registers of a process identified by the stored state for
+    /// that process, in the layout ELF core files use for the architecture.
+    /// This is used for core dumps of processes that faulted.
+    ///
+    /// The default implementation returns `None`, for architectures that do
+    /// not support core dumps.
+    ///
+    /// ### Safety
+    ///
+    /// This function guarantees that it if needs to change process memory, it
+    /// will only change memory starting at `accessible_memory_start` and before
+    /// `app_brk`. The caller is responsible for guaranteeing that those
+    /// pointers are valid for the process.
+    unsafe fn core_registers(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &Self::StoredState,
+    ) -> Option<CoreRegisters> {
+        None
+    }
+
+    /// Replace the registers of a process identified by the stored state for
+    /// that process, given in the layout `core_registers()` returns. This is
+    /// used by debuggers attached to a stopped process.
+    ///
+    /// The default implementation returns `Err(ErrorCode::NOSUPPORT)`, for
+    /// architectures that do not support debugging processes.
+    ///
+    /// ### Safety
+    ///
+    /// This function guarantees that it if needs to change process memory, it
+    /// will only change memory starting at `accessible_memory_start` and before
+    /// `app_brk`. The caller is responsible for guaranteeing that those
+    /// pointers are valid for the process.
+    unsafe fn set_core_registers(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut Self::StoredState,
+        _registers: &CoreRegisters,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
 }
@@ -100,8 +100,8 @@
 //! // when appending a key:
 //!
 //! // Add a key
-//! static VALUE: [u8; 32] = [0x23; 32];
-//! let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &VALUE) };
+//! static mut VALUE: [u8; 32] = [0x23; 32];
+//! let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut VALUE) };
 //!
 //! match ret {
 //!     Err(ErrorCode::ReadNotReady(reg)) => {
@@ -142,7 +142,7 @@ pub struct AsyncTicKV<'a, C: FlashController<S>, const S: usize> {
     /// The main TicKV struct
     pub tickv: TicKV<'a, C, S>,
     key: Cell<Option<u64>>,
-    value: Cell<Option<&'static [u8]>>,
+    value: Cell<Option<&'static mut [u8]>>,
     buf: Cell<Option<&'static mut [u8]>>,
 }
 
@@ -185,15 +185,20 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
     ///
     /// On success nothing will be returned.
     /// On error a `ErrorCode` will be returned.
-    pub fn append_key(&self, hash: u64, value: &'static [u8]) -> Result<SuccessCode, ErrorCode> {
-        match self.tickv.append_key(hash, value) {
-            Ok(code) => Ok(code),
-            Err(e) => {
-                self.key.replace(Some(hash));
-                self.value.replace(Some(value));
-                Err(e)
-            }
-        }
+    ///
+    /// In either case the `value` buffer is kept and can be retrieved with
+    /// `get_stored_value_buffer()` once the operation has finished.
+    pub fn append_key(
+        &self,
+        hash: u64,
+        value: &'static mut [u8],
+    ) -> Result<SuccessCode, ErrorCode> {
+        let ret = self.tickv.append_key(hash, value);
+        self.value.replace(Some(value));
+        if ret.is_err() {
+            self.key.replace(Some(hash));
+        }
+        ret
     }
 
     /// Retrieves the value from flash storage.
@@ -266,7 +271,7 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
 
     /// Get the `value` buffer that was passed in by previous
     /// commands.
-    pub fn get_stored_value_buffer(&self) -> Option<&'static [u8]> {
+    pub fn get_stored_value_buffer(&self) -> Option<&'static mut [u8]> {
         self.value.take()
     }
 
@@ -294,9 +299,12 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
     pub fn continue_operation(&self) -> ContinueReturn {
         let ret = match self.tickv.state.get() {
             State::Init(_) => self.tickv.initalise(self.key.get().unwrap()),
-            State::AppendKey(_) => self
-                .tickv
-                .append_key(self.key.get().unwrap(), self.value.get().unwrap()),
+            State::AppendKey(_) => {
+                let value = self.value.take().unwrap();
+                let ret = self.tickv.append_key(self.key.get().unwrap(), value);
+                self.value.replace(Some(value));
+                ret
+            }
             State::GetKey(_) => {
                 let buf = self.buf.take().unwrap();
                 let ret = self.tickv.get_key(self.key.get().unwrap(), buf);
@@ -339,6 +347,7 @@ mod store_flast_ctrl {
     use crate::flash_controller::FlashController;
     use crate::tickv::{HASH_OFFSET, LEN_OFFSET, MAIN_KEY, VERSION, VERSION_OFFSET};
     use core::hash::{Hash, Hasher};
+    use std::boxed::Box;
     use std::cell::Cell;
     use std::cell::RefCell;
     use std::collections::hash_map::DefaultHasher;
@@ -428,6 +437,11 @@ mod store_flast_ctrl {
         assert_eq!(buf[46], 0xba);
     }
 
+    /// A value buffer for `append_key()`, which keeps the buffer it is given.
+    fn new_value() -> &'static mut [u8] {
+        Box::leak(Box::new([0x23; 32]))
+    }
+
     fn get_hashed_key(unhashed_key: &[u8]) -> u64 {
         let mut hash_function = DefaultHasher::new();
         unhashed_key.hash(&mut hash_function);
@@ -542,9 +556,7 @@ mod store_flast_ctrl {
             ret = r;
         }
 
-        static VALUE: [u8; 32] = [0x23; 32];
-
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        let ret = tickv.append_key(get_hashed_key(b"ONE"), new_value());
         match ret {
             Err(ErrorCode::ReadNotReady(reg)) => {
                 // There is no actual delay in the test, just continue now
@@ -555,7 +567,7 @@ mod store_flast_ctrl {
             _ => unreachable!(),
         }
 
-        let ret = tickv.append_key(get_hashed_key(b"TWO"), &VALUE);
+        let ret = tickv.append_key(get_hashed_key(b"TWO"), new_value());
         match ret {
             Err(ErrorCode::ReadNotReady(reg)) => {
                 // There is no actual delay in the test, just continue now
@@ -582,11 +594,10 @@ mod store_flast_ctrl {
             ret = r;
         }
 
-        static VALUE: [u8; 32] = [0x23; 32];
         static mut BUF: [u8; 32] = [0; 32];
 
         println!("Add key ONE");
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        let ret = tickv.append_key(get_hashed_key(b"ONE"), new_value());
         match ret {
             Err(ErrorCode::ReadNotReady(reg)) => {
                 // There is no actual delay in the test, just continue now
@@ -617,7 +628,7 @@ mod store_flast_ctrl {
         }
 
         println!("Add key ONE again");
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        let ret = tickv.append_key(get_hashed_key(b"ONE"), new_value());
         match ret {
             Err(ErrorCode::ReadNotReady(reg)) => {
                 // There is no actual delay in the test, just continue now
@@ -632,7 +643,7 @@ mod store_flast_ctrl {
         }
 
         println!("Add key TWO");
-        let ret = tickv.append_key(get_hashed_key(b"TWO"), &VALUE);
+        let ret = tickv.append_key(get_hashed_key(b"TWO"), new_value());
         match ret {
             Err(ErrorCode::ReadNotReady(reg)) => {
                 // There is no actual delay in the test, just continue now
@@ -707,11 +718,10 @@ mod store_flast_ctrl {
             ret = r;
         }
 
-        static VALUE: [u8; 32] = [0x23; 32];
         static mut BUF: [u8; 32] = [0; 32];
 
         println!("Add key ONE");
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        let ret = tickv.append_key(get_hashed_key(b"ONE"), new_value());
         match ret {
             Err(ErrorCode::ReadNotReady(reg)) => {
                 // There is no actual delay in the test, just continue now
@@ -764,7 +774,6 @@ mod store_flast_ctrl {
             ret = r;
         }
 
-        static VALUE: [u8; 32] = [0x23; 32];
         static mut BUF: [u8; 32] = [0; 32];
 
         println!("Garbage collect empty flash");
@@ -778,7 +787,7 @@ mod store_flast_ctrl {
         }
 
         println!("Add key ONE");
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        let ret = tickv.append_key(get_hashed_key(b"ONE"), new_value());
         match ret {
             Err(ErrorCode::ReadNotReady(reg)) => {
                 // There is no actual delay in the test, just continue now
@@ -860,6 +869,8 @@ mod store_flast_ctrl {
         }
 
         println!("Add Key ONE");
-        tickv.append_key(get_hashed_key(b"ONE"), &VALUE).unwrap();
+        tickv
+            .append_key(get_hashed_key(b"ONE"), new_value())
+            .unwrap();
     }
 }
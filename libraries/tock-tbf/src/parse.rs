@@ -130,6 +130,9 @@ pub fn parse_tbf_header(
                     Default::default();
                 let mut app_name_str = "";
                 let mut fixed_address_pointer: Option<types::TbfHeaderV2FixedAddresses> = None;
+                let mut persistent_storage_pointer: Option<types::TbfHeaderV2PersistentStorage> =
+                    None;
+                let mut permissions_pointer: Option<types::TbfHeaderV2Permissions> = None;
 
                 // Iterate the remainder of the header looking for TLV entries.
                 while remaining.len() > 0 {
@@ -219,6 +222,26 @@ pub fn parse_tbf_header(
                             }
                         }
 
+                        types::TbfHeaderTypes::TbfHeaderPermissions => {
+                            // The entries must fit in the TLV; they are
+                            // decoded from flash when they are used.
+                            let permissions_buf = remaining
+                                .get(0..tlv_header.length as usize)
+                                .ok_or(types::TbfParseError::NotEnoughFlash)?;
+                            permissions_pointer = Some(permissions_buf.try_into()?);
+                        }
+
+                        types::TbfHeaderTypes::TbfHeaderPersistentStorage => {
+                            let entry_len = 8;
+                            if tlv_header.length as usize == entry_len {
+                                persistent_storage_pointer = Some(remaining.try_into()?);
+                            } else {
+                                return Err(types::TbfParseError::BadTlvEntry(
+                                    tlv_header.tipe as usize,
+                                ));
+                            }
+                        }
+
                         _ => {}
                     }
 
@@ -236,6 +259,8 @@ pub fn parse_tbf_header(
                     package_name: Some(app_name_str),
                     writeable_regions: Some(wfr_pointer),
                     fixed_addresses: fixed_address_pointer,
+                    permissions: permissions_pointer,
+                    persistent_storage: persistent_storage_pointer,
                 };
 
                 Ok(types::TbfHeader::TbfHeaderV2(tbf_header))
@@ -0,0 +1,296 @@
+//! Tock Binary Format serialization code.
+//!
+//! This is the inverse of `parse`: it writes a `TbfHeaderV2` back out in the
+//! format `parse_tbf_header()` reads, computing the header size and checksum.
+//! TLV entries are written in order of their type, and only for the fields
+//! that are present.
+
+use core::mem;
+
+use crate::types::{self, TbfHeaderTypes, TbfSerializeError};
+
+/// Size of the base header (version, sizes, flags and checksum).
+const BASE_HEADER_SIZE: usize = 16;
+
+/// Takes a value and rounds it up to be aligned % 4
+macro_rules! align4 {
+    ($e:expr $(,)?) => {
+        ($e) + ((4 - (($e) % 4)) % 4)
+    };
+}
+
+/// Writes a header into a buffer, or only counts its length if there is no
+/// buffer.
+struct Writer<'a> {
+    buf: Option<&'a mut [u8]>,
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        let range = self.len..self.len + bytes.len();
+        self.len = range.end;
+        if let Some(dest) = self.buf.as_mut().and_then(|buf| buf.get_mut(range)) {
+            dest.copy_from_slice(bytes);
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Write a TLV entry with a body of `length` bytes, written by `body`,
+    /// then pad it to a multiple of four bytes.
+    fn tlv<F: FnOnce(&mut Self)>(
+        &mut self,
+        tipe: TbfHeaderTypes,
+        length: usize,
+        body: F,
+    ) -> Result<(), TbfSerializeError> {
+        if length > u16::MAX as usize {
+            return Err(TbfSerializeError::TooLong(tipe as usize));
+        }
+        self.bytes(&(tipe as u16).to_le_bytes());
+        self.bytes(&(length as u16).to_le_bytes());
+        body(self);
+        self.bytes(&[0; 3][..align4!(length) - length]);
+        Ok(())
+    }
+}
+
+fn write_tlvs(header: &types::TbfHeaderV2, w: &mut Writer) -> Result<(), TbfSerializeError> {
+    let tbf_header = types::TbfHeader::TbfHeaderV2(*header);
+
+    if let Some(main) = header.main {
+        w.tlv(
+            TbfHeaderTypes::TbfHeaderMain,
+            mem::size_of::<types::TbfHeaderV2Main>(),
+            |w| {
+                w.u32(main.init_fn_offset());
+                w.u32(main.protected_size());
+                w.u32(main.minimum_ram_size());
+            },
+        )?;
+    }
+
+    let regions = header.writeable_regions.unwrap_or_default();
+    let number_regions = regions.iter().flatten().count();
+    if number_regions > 0 {
+        w.tlv(
+            TbfHeaderTypes::TbfHeaderWriteableFlashRegions,
+            number_regions * mem::size_of::<types::TbfHeaderV2WriteableFlashRegion>(),
+            |w| {
+                for (i, _) in regions.iter().enumerate().filter(|(_, r)| r.is_some()) {
+                    let (offset, size) = tbf_header.get_writeable_flash_region(i);
+                    w.u32(offset);
+                    w.u32(size);
+                }
+            },
+        )?;
+    }
+
+    if let Some(name) = header.package_name.filter(|name| !name.is_empty()) {
+        w.tlv(TbfHeaderTypes::TbfHeaderPackageName, name.len(), |w| {
+            w.bytes(name.as_bytes())
+        })?;
+    }
+
+    if header.fixed_addresses.is_some() {
+        w.tlv(TbfHeaderTypes::TbfHeaderFixedAddresses, 8, |w| {
+            w.u32(tbf_header.get_fixed_address_ram().unwrap_or(0xFFFFFFFF));
+            w.u32(tbf_header.get_fixed_address_flash().unwrap_or(0xFFFFFFFF));
+        })?;
+    }
+
+    if let Some(permissions) = header.permissions {
+        let entries = permissions.entries();
+        let number_perms = entries.len() / types::TbfHeaderV2Permissions::ENTRY_LEN;
+        if number_perms > u16::MAX as usize {
+            return Err(TbfSerializeError::TooLong(
+                TbfHeaderTypes::TbfHeaderPermissions as usize,
+            ));
+        }
+        w.tlv(
+            TbfHeaderTypes::TbfHeaderPermissions,
+            2 + entries.len(),
+            |w| {
+                w.bytes(&(number_perms as u16).to_le_bytes());
+                w.bytes(entries);
+            },
+        )?;
+    }
+
+    if let Some((storage_id, storage_size)) = tbf_header.get_persistent_storage_request() {
+        w.tlv(TbfHeaderTypes::TbfHeaderPersistentStorage, 8, |w| {
+            w.u32(storage_id);
+            w.u32(storage_size);
+        })?;
+    }
+    Ok(())
+}
+
+/// The number of bytes `serialize_tbf_header()` will write for `header`.
+pub fn tbf_header_size(header: &types::TbfHeaderV2) -> Result<usize, TbfSerializeError> {
+    let mut w = Writer {
+        buf: None,
+        len: BASE_HEADER_SIZE,
+    };
+    write_tlvs(header, &mut w)?;
+    Ok(w.len)
+}
+
+/// Write the base header with its checksum to the start of `buf`, which
+/// holds the rest of a header of `header_size` bytes.
+fn finish_header(buf: &mut [u8], header_size: usize, total_size: u32, flags: u32) {
+    buf[0..2].copy_from_slice(&2u16.to_le_bytes());
+    buf[2..4].copy_from_slice(&(header_size as u16).to_le_bytes());
+    buf[4..8].copy_from_slice(&total_size.to_le_bytes());
+    buf[8..12].copy_from_slice(&flags.to_le_bytes());
+    buf[12..16].copy_from_slice(&0u32.to_le_bytes());
+
+    // The checksum is the XOR of each 4 byte word in the header, with the
+    // checksum field itself as zero.
+    let checksum = buf[..header_size]
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .fold(0, |checksum, word| checksum ^ word);
+    buf[12..16].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// Serialize `header` to the start of `buf`.
+///
+/// The header size and checksum in `header` are ignored and computed from
+/// the TLV entries that are written. The total size is written as is.
+///
+/// ## Return
+///
+/// The number of bytes written, which is the header size.
+pub fn serialize_tbf_header(
+    header: &types::TbfHeaderV2,
+    buf: &mut [u8],
+) -> Result<usize, TbfSerializeError> {
+    let header_size = tbf_header_size(header)?;
+    if header_size > u16::MAX as usize {
+        return Err(TbfSerializeError::TooLong(0));
+    }
+    let buf = buf
+        .get_mut(..header_size)
+        .ok_or(TbfSerializeError::BufferTooSmall(header_size))?;
+    write_tlvs(
+        header,
+        &mut Writer {
+            buf: Some(&mut buf[..]),
+            len: BASE_HEADER_SIZE,
+        },
+    )?;
+    finish_header(buf, header_size, header.base.total_size, header.base.flags);
+    Ok(header_size)
+}
+
+/// Serialize the header of a padding "app" of `total_size` bytes to the
+/// start of `buf`. Padding fills the space between apps, for example to
+/// align the next app.
+///
+/// ## Return
+///
+/// The number of bytes written, which is the header size.
+pub fn serialize_tbf_padding(total_size: u32, buf: &mut [u8]) -> Result<usize, TbfSerializeError> {
+    let buf = buf
+        .get_mut(..BASE_HEADER_SIZE)
+        .ok_or(TbfSerializeError::BufferTooSmall(BASE_HEADER_SIZE))?;
+    finish_header(buf, BASE_HEADER_SIZE, total_size, 0);
+    Ok(BASE_HEADER_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use core::convert::TryInto;
+    use std::boxed::Box;
+    use std::vec;
+
+    use super::*;
+    use crate::parse::{parse_tbf_header, parse_tbf_header_lengths};
+    use crate::types::{TbfHeader, TbfHeaderDriverPermission, TbfHeaderV2};
+
+    fn round_trip(header: &TbfHeaderV2) -> TbfHeader {
+        let mut buf = vec![0; tbf_header_size(header).unwrap()];
+        let len = serialize_tbf_header(header, &mut buf).unwrap();
+        assert_eq!(len, buf.len());
+        let buf: &'static [u8] = Box::leak(buf.into_boxed_slice());
+
+        let (version, header_size, total_size) =
+            parse_tbf_header_lengths(buf[0..8].try_into().unwrap())
+                .ok()
+                .unwrap();
+        assert_eq!(header_size as usize, len);
+        assert_eq!(total_size, header.total_size());
+        parse_tbf_header(buf, version).unwrap()
+    }
+
+    #[test]
+    fn round_trip_all_tlvs() {
+        let permission = TbfHeaderDriverPermission {
+            driver_number: 0x1,
+            offset: 0,
+            allowed_commands: 0b1011,
+        };
+        let mut header = TbfHeaderV2::new(0x1000, true);
+        header.set_main(Some(types::TbfHeaderV2Main::new(0x40, 0x20, 0x800)));
+        header.set_package_name(Some("blink"));
+        header.set_writeable_flash_regions(&[
+            types::TbfHeaderV2WriteableFlashRegion::new(0x200, 0x100),
+            types::TbfHeaderV2WriteableFlashRegion::new(0x400, 0x80),
+        ]);
+        header.set_fixed_addresses(None, Some(0x40030000));
+        header.set_permissions(Some(types::TbfHeaderV2Permissions::new(Box::leak(
+            Box::new(permission.encode()),
+        ))));
+        header.set_persistent_storage(Some(types::TbfHeaderV2PersistentStorage::new(7, 512)));
+
+        let parsed = round_trip(&header);
+        assert!(parsed.enabled());
+        assert_eq!(parsed.get_package_name(), Some("blink"));
+        assert_eq!(parsed.get_minimum_app_ram_size(), 0x800);
+        assert_eq!(parsed.number_writeable_flash_regions(), 2);
+        assert_eq!(parsed.get_writeable_flash_region(1), (0x400, 0x80));
+        assert_eq!(parsed.get_fixed_address_ram(), None);
+        assert_eq!(parsed.get_fixed_address_flash(), Some(0x40030000));
+        assert_eq!(parsed.get_persistent_storage_request(), Some((7, 512)));
+        assert_eq!(
+            parsed.get_permissions().unwrap().iter().next(),
+            Some(permission)
+        );
+
+        // Serializing the parsed header again gives the same header size.
+        match parsed {
+            TbfHeader::TbfHeaderV2(hd) => {
+                assert_eq!(tbf_header_size(&hd).unwrap(), hd.header_size() as usize)
+            }
+            TbfHeader::Padding(_) => panic!("parsed an app as padding"),
+        }
+    }
+
+    #[test]
+    fn padding_and_short_buffer() {
+        let mut buf = [0; BASE_HEADER_SIZE];
+        assert!(matches!(
+            serialize_tbf_padding(0x200, &mut buf),
+            Ok(BASE_HEADER_SIZE)
+        ));
+        let buf: &'static [u8] = Box::leak(Box::new(buf));
+        let (version, _, total_size) = parse_tbf_header_lengths(buf[0..8].try_into().unwrap())
+            .ok()
+            .unwrap();
+        assert_eq!(total_size, 0x200);
+        assert!(!parse_tbf_header(buf, version).unwrap().is_app());
+
+        let mut header = TbfHeaderV2::new(0x200, false);
+        header.set_package_name(Some("a"));
+        let mut short = [0; BASE_HEADER_SIZE];
+        assert!(matches!(
+            serialize_tbf_header(&header, &mut short),
+            Err(TbfSerializeError::BufferTooSmall(24))
+        ));
+    }
+}
@@ -86,6 +86,30 @@ impl fmt::Debug for TbfParseError {
     }
 }
 
+/// Error when serializing a TBF header.
+pub enum TbfSerializeError {
+    /// The buffer is too small for the header. The value is the number of
+    /// bytes the header needs.
+    BufferTooSmall(usize),
+
+    /// The header, or one of its TLV entries, is longer than its length
+    /// field can express. The `usize` is the value of the "tipe" field, or 0
+    /// for the header as a whole.
+    TooLong(usize),
+}
+
+impl fmt::Debug for TbfSerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TbfSerializeError::BufferTooSmall(needed) => {
+                write!(f, "Buffer too short for TBF header of {} bytes", needed)
+            }
+            TbfSerializeError::TooLong(0) => write!(f, "TBF header too long"),
+            TbfSerializeError::TooLong(tipe) => write!(f, "TLV entry type {} too long", tipe),
+        }
+    }
+}
+
 // TBF structure
 
 /// TBF fields that must be present in all v2 headers.
@@ -105,6 +129,8 @@ pub enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     TbfHeaderFixedAddresses = 5,
+    TbfHeaderPermissions = 6,
+    TbfHeaderPersistentStorage = 7,
 
     /// Some field in the header that we do not understand. Since the TLV format
     /// specifies the length of each section, if we get a field we do not
@@ -130,6 +156,30 @@ pub struct TbfHeaderV2Main {
     minimum_ram_size: u32,
 }
 
+impl TbfHeaderV2Main {
+    pub fn new(init_fn_offset: u32, protected_size: u32, minimum_ram_size: u32) -> Self {
+        TbfHeaderV2Main {
+            init_fn_offset,
+            protected_size,
+            minimum_ram_size,
+        }
+    }
+
+    /// Offset of the init function from the end of the TBF header.
+    pub fn init_fn_offset(&self) -> u32 {
+        self.init_fn_offset
+    }
+
+    /// Bytes of protected flash after the TBF header.
+    pub fn protected_size(&self) -> u32 {
+        self.protected_size
+    }
+
+    pub fn minimum_ram_size(&self) -> u32 {
+        self.minimum_ram_size
+    }
+}
+
 /// Writeable flash regions only need an offset and size.
 ///
 /// There can be multiple (or zero) flash regions defined, so this is its own
@@ -140,6 +190,15 @@ pub struct TbfHeaderV2WriteableFlashRegion {
     writeable_flash_region_size: u32,
 }
 
+impl TbfHeaderV2WriteableFlashRegion {
+    pub fn new(offset: u32, size: u32) -> Self {
+        TbfHeaderV2WriteableFlashRegion {
+            writeable_flash_region_offset: offset,
+            writeable_flash_region_size: size,
+        }
+    }
+}
+
 /// Optional fixed addresses for flash and RAM for this process.
 ///
 /// If a process is compiled for a specific address this header entry lets the
@@ -164,6 +223,63 @@ pub struct TbfHeaderV2FixedAddresses {
     start_process_flash: u32,
 }
 
+impl TbfHeaderV2FixedAddresses {
+    /// Either address may be `0xFFFFFFFF` if it is not fixed.
+    pub fn new(start_process_ram: u32, start_process_flash: u32) -> Self {
+        TbfHeaderV2FixedAddresses {
+            start_process_ram,
+            start_process_flash,
+        }
+    }
+}
+
+/// Permission for a process to use commands of a driver.
+///
+/// `allowed_commands` is a bitmask of the commands `offset * 64` to
+/// `offset * 64 + 63` of driver `driver_number`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TbfHeaderDriverPermission {
+    pub driver_number: u32,
+    pub offset: u32,
+    pub allowed_commands: u64,
+}
+
+/// Optional list of the drivers and commands this process may use.
+///
+/// The entries are kept in the header in flash and decoded when they are
+/// looked up, so there is no limit on their number.
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2Permissions {
+    /// The encoded `TbfHeaderDriverPermission` entries.
+    perms: &'static [u8],
+}
+
+/// The commands of a driver a process may call, as listed in its
+/// permissions TLV.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandPermissions {
+    /// The process has no permissions TLV, so it is not restricted.
+    NoPermsAtAll,
+    /// The permissions TLV does not list the driver at all.
+    NoPermsThisDriver,
+    /// The bitmask of allowed commands for the requested offset. It is `0`
+    /// if the driver is listed, but not for this offset.
+    Mask(u64),
+}
+
+/// Optional request for a region of persistent nonvolatile storage.
+///
+/// The `storage_id` identifies the owner of the region across reboots and app
+/// updates, so a new version of an app with the same `storage_id` is given
+/// the same region as the version it replaces. The `storage_size` is the
+/// number of bytes the app would like to have allocated the first time it
+/// uses persistent storage.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TbfHeaderV2PersistentStorage {
+    storage_id: u32,
+    storage_size: u32,
+}
+
 // Conversion functions from slices to the various TBF fields.
 
 impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Base {
@@ -209,6 +325,8 @@ impl core::convert::TryFrom<u16> for TbfHeaderTypes {
             2 => Ok(TbfHeaderTypes::TbfHeaderWriteableFlashRegions),
             3 => Ok(TbfHeaderTypes::TbfHeaderPackageName),
             5 => Ok(TbfHeaderTypes::TbfHeaderFixedAddresses),
+            6 => Ok(TbfHeaderTypes::TbfHeaderPermissions),
+            7 => Ok(TbfHeaderTypes::TbfHeaderPersistentStorage),
             _ => Ok(TbfHeaderTypes::Unknown),
         }
     }
@@ -296,6 +414,134 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2FixedAddresses {
     }
 }
 
+impl core::convert::TryFrom<&[u8]> for TbfHeaderDriverPermission {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderDriverPermission, Self::Error> {
+        Ok(TbfHeaderDriverPermission {
+            driver_number: u32::from_le_bytes(
+                b.get(0..4)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            offset: u32::from_le_bytes(
+                b.get(4..8)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            allowed_commands: u64::from_le_bytes(
+                b.get(8..16)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+        })
+    }
+}
+
+impl TbfHeaderDriverPermission {
+    /// Encode the permission the way it is stored in a permissions TLV.
+    pub fn encode(&self) -> [u8; TbfHeaderV2Permissions::ENTRY_LEN] {
+        let mut entry = [0; TbfHeaderV2Permissions::ENTRY_LEN];
+        entry[0..4].copy_from_slice(&self.driver_number.to_le_bytes());
+        entry[4..8].copy_from_slice(&self.offset.to_le_bytes());
+        entry[8..16].copy_from_slice(&self.allowed_commands.to_le_bytes());
+        entry
+    }
+}
+
+impl core::convert::TryFrom<&'static [u8]> for TbfHeaderV2Permissions {
+    type Error = TbfParseError;
+
+    /// Parse the body of a permissions TLV: the number of entries as a `u16`
+    /// followed by the entries.
+    fn try_from(b: &'static [u8]) -> Result<TbfHeaderV2Permissions, Self::Error> {
+        let number_perms = u16::from_le_bytes(
+            b.get(0..2)
+                .ok_or(TbfParseError::NotEnoughFlash)?
+                .try_into()?,
+        ) as usize;
+        let perms = b
+            .get(2..2 + number_perms * TbfHeaderV2Permissions::ENTRY_LEN)
+            .ok_or(TbfParseError::BadTlvEntry(
+                TbfHeaderTypes::TbfHeaderPermissions as usize,
+            ))?;
+        Ok(TbfHeaderV2Permissions { perms })
+    }
+}
+
+impl TbfHeaderV2Permissions {
+    /// Size of an encoded `TbfHeaderDriverPermission`.
+    pub const ENTRY_LEN: usize = 16;
+
+    /// Permissions from `entries`, each a `TbfHeaderDriverPermission`
+    /// encoded with [`TbfHeaderDriverPermission::encode`]. A trailing partial
+    /// entry is ignored.
+    pub fn new(entries: &'static [u8]) -> Self {
+        TbfHeaderV2Permissions {
+            perms: &entries[..entries.len() - entries.len() % Self::ENTRY_LEN],
+        }
+    }
+
+    /// The encoded entries.
+    pub fn entries(&self) -> &'static [u8] {
+        self.perms
+    }
+
+    /// Iterate over the permission entries.
+    pub fn iter(&self) -> impl Iterator<Item = TbfHeaderDriverPermission> {
+        self.perms
+            .chunks_exact(Self::ENTRY_LEN)
+            .map(|entry| entry.try_into().unwrap_or_default())
+    }
+
+    /// Get the commands of `driver_num` at `offset` the process may call.
+    pub fn get_command_permissions(&self, driver_num: usize, offset: usize) -> CommandPermissions {
+        let mut listed = false;
+        let mut mask = 0;
+        for perm in self.iter() {
+            if perm.driver_number as usize == driver_num {
+                listed = true;
+                if perm.offset as usize == offset {
+                    mask |= perm.allowed_commands;
+                }
+            }
+        }
+        if listed {
+            CommandPermissions::Mask(mask)
+        } else {
+            CommandPermissions::NoPermsThisDriver
+        }
+    }
+}
+
+impl TbfHeaderV2PersistentStorage {
+    pub fn new(storage_id: u32, storage_size: u32) -> Self {
+        TbfHeaderV2PersistentStorage {
+            storage_id,
+            storage_size,
+        }
+    }
+}
+
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2PersistentStorage {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2PersistentStorage, Self::Error> {
+        Ok(TbfHeaderV2PersistentStorage {
+            storage_id: u32::from_le_bytes(
+                b.get(0..4)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+            storage_size: u32::from_le_bytes(
+                b.get(4..8)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+        })
+    }
+}
+
 /// Single header that can contain all parts of a v2 header.
 ///
 /// Note, this struct limits the number of writeable regions an app can have to
@@ -308,6 +554,95 @@ pub struct TbfHeaderV2 {
     pub(crate) package_name: Option<&'static str>,
     pub(crate) writeable_regions: Option<[Option<TbfHeaderV2WriteableFlashRegion>; 4]>,
     pub(crate) fixed_addresses: Option<TbfHeaderV2FixedAddresses>,
+    pub(crate) permissions: Option<TbfHeaderV2Permissions>,
+    pub(crate) persistent_storage: Option<TbfHeaderV2PersistentStorage>,
+}
+
+impl TbfHeaderV2 {
+    /// A header with no TLV entries for an app of `total_size` bytes,
+    /// including the header. The header size and checksum are filled in when
+    /// the header is serialized.
+    pub fn new(total_size: u32, enabled: bool) -> Self {
+        TbfHeaderV2 {
+            base: TbfHeaderV2Base {
+                version: 2,
+                header_size: 0,
+                total_size,
+                flags: enabled as u32,
+                checksum: 0,
+            },
+            main: None,
+            package_name: None,
+            writeable_regions: None,
+            fixed_addresses: None,
+            permissions: None,
+            persistent_storage: None,
+        }
+    }
+
+    /// The header size recorded in the base header. For a header that was
+    /// not parsed, this is only correct once it has been serialized.
+    pub fn header_size(&self) -> u16 {
+        self.base.header_size
+    }
+
+    pub fn total_size(&self) -> u32 {
+        self.base.total_size
+    }
+
+    pub fn set_total_size(&mut self, total_size: u32) {
+        self.base.total_size = total_size;
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.base.flags
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.base.flags = (self.base.flags & !1) | enabled as u32;
+    }
+
+    pub fn main(&self) -> Option<TbfHeaderV2Main> {
+        self.main
+    }
+
+    pub fn set_main(&mut self, main: Option<TbfHeaderV2Main>) {
+        self.main = main;
+    }
+
+    /// An empty name is treated as no name.
+    pub fn set_package_name(&mut self, name: Option<&'static str>) {
+        self.package_name = name;
+    }
+
+    /// Replace the writeable flash regions. At most four are kept.
+    pub fn set_writeable_flash_regions(&mut self, regions: &[TbfHeaderV2WriteableFlashRegion]) {
+        let mut wfrs: [Option<TbfHeaderV2WriteableFlashRegion>; 4] = Default::default();
+        for (wfr, region) in wfrs.iter_mut().zip(regions) {
+            *wfr = Some(*region);
+        }
+        self.writeable_regions = Some(wfrs);
+    }
+
+    /// Set either address to `None` to leave it unfixed. If both are `None`
+    /// the fixed addresses TLV is removed.
+    pub fn set_fixed_addresses(&mut self, ram: Option<u32>, flash: Option<u32>) {
+        self.fixed_addresses = match (ram, flash) {
+            (None, None) => None,
+            _ => Some(TbfHeaderV2FixedAddresses::new(
+                ram.unwrap_or(0xFFFFFFFF),
+                flash.unwrap_or(0xFFFFFFFF),
+            )),
+        };
+    }
+
+    pub fn set_permissions(&mut self, permissions: Option<TbfHeaderV2Permissions>) {
+        self.permissions = permissions;
+    }
+
+    pub fn set_persistent_storage(&mut self, storage: Option<TbfHeaderV2PersistentStorage>) {
+        self.persistent_storage = storage;
+    }
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -434,4 +769,33 @@ impl TbfHeader {
             start => Some(start),
         }
     }
+
+    /// Get the persistent storage identifier and the number of bytes of
+    /// persistent storage this process requested. If the process did not
+    /// include a persistent storage request, return `None`.
+    pub fn get_persistent_storage_request(&self) -> Option<(u32, u32)> {
+        match self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .persistent_storage
+                .map(|ps| (ps.storage_id, ps.storage_size)),
+            _ => None,
+        }
+    }
+
+    /// Get the permissions TLV of this process, if it has one.
+    pub fn get_permissions(&self) -> Option<TbfHeaderV2Permissions> {
+        match self {
+            TbfHeader::TbfHeaderV2(hd) => hd.permissions,
+            _ => None,
+        }
+    }
+
+    /// Get the commands of driver `driver_num` this process may call, in the
+    /// block of 64 commands starting at `offset * 64`.
+    pub fn get_command_permissions(&self, driver_num: usize, offset: usize) -> CommandPermissions {
+        match self.get_permissions() {
+            Some(permissions) => permissions.get_command_permissions(driver_num, offset),
+            None => CommandPermissions::NoPermsAtAll,
+        }
+    }
 }
@@ -0,0 +1,254 @@
+//! Just enough of ELF to map kernel addresses to function names.
+//!
+//! Handles little endian 32 and 64 bit files, which covers the ARM and RISC-V
+//! kernels Tock builds.
+
+use std::convert::TryInto;
+
+use crate::Result;
+
+const EM_ARM: u16 = 40;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+/// A function symbol.
+pub struct Function {
+    pub address: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+/// The functions of an ELF file, sorted by address.
+pub struct Symbols {
+    functions: Vec<Function>,
+    /// Whether addresses have the Thumb bit set.
+    thumb: bool,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+}
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8]> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| "truncated ELF file".to_string())
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        Ok(u16::from_le_bytes(
+            self.bytes(offset, 2)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(
+            self.bytes(offset, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64> {
+        Ok(u64::from_le_bytes(
+            self.bytes(offset, 8)?.try_into().unwrap(),
+        ))
+    }
+
+    /// A word: 32 bits in 32 bit files, 64 bits in 64 bit files.
+    fn word(&self, offset: usize) -> Result<u64> {
+        if self.is_64 {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+
+    fn string(&self, offset: usize) -> Result<String> {
+        let rest = self.data.get(offset..).ok_or("truncated ELF file")?;
+        let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+}
+
+impl Symbols {
+    pub fn parse(data: &[u8]) -> Result<Symbols> {
+        if data.get(0..4) != Some(b"\x7fELF") {
+            return Err("not an ELF file".to_string());
+        }
+        let is_64 = match data.get(4) {
+            Some(1) => false,
+            Some(2) => true,
+            _ => return Err("unknown ELF class".to_string()),
+        };
+        if data.get(5) != Some(&1) {
+            return Err("big endian ELF files are not supported".to_string());
+        }
+        let elf = Reader { data, is_64 };
+        let thumb = elf.u16(18)? == EM_ARM;
+
+        let (shoff, shentsize, shnum) = if is_64 {
+            (elf.u64(40)? as usize, elf.u16(58)?, elf.u16(60)?)
+        } else {
+            (elf.u32(32)? as usize, elf.u16(46)?, elf.u16(48)?)
+        };
+        let section = |index: usize| shoff + index * shentsize as usize;
+        // Offset, size and linked section of a section header.
+        let header = |index: usize| -> Result<(usize, usize, u32)> {
+            let base = section(index);
+            if is_64 {
+                Ok((
+                    elf.u64(base + 24)? as usize,
+                    elf.u64(base + 32)? as usize,
+                    elf.u32(base + 40)?,
+                ))
+            } else {
+                Ok((
+                    elf.u32(base + 16)? as usize,
+                    elf.u32(base + 20)? as usize,
+                    elf.u32(base + 24)?,
+                ))
+            }
+        };
+
+        let mut functions = Vec::new();
+        for index in 0..shnum as usize {
+            if elf.u32(section(index) + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let (offset, size, link) = header(index)?;
+            let (strtab, _, _) = header(link as usize)?;
+            let entsize = if is_64 { 24 } else { 16 };
+            for symbol in (offset..offset + size).step_by(entsize) {
+                let (info, value, size) = if is_64 {
+                    (
+                        elf.u8(symbol + 4)?,
+                        elf.u64(symbol + 8)?,
+                        elf.u64(symbol + 16)?,
+                    )
+                } else {
+                    (
+                        elf.u8(symbol + 12)?,
+                        elf.word(symbol + 4)?,
+                        elf.word(symbol + 8)?,
+                    )
+                };
+                if info & 0xF != STT_FUNC {
+                    continue;
+                }
+                let name = elf.string(strtab + elf.u32(symbol)? as usize)?;
+                functions.push(Function {
+                    address: if thumb { value & !1 } else { value },
+                    size,
+                    name: demangle(&name),
+                });
+            }
+        }
+        if functions.is_empty() {
+            return Err("the ELF file has no function symbols".to_string());
+        }
+        functions.sort_by_key(|function| function.address);
+        Ok(Symbols { functions, thumb })
+    }
+
+    /// The function containing `address`, and the offset into it.
+    pub fn lookup(&self, address: u64) -> Option<(&Function, u64)> {
+        let address = if self.thumb { address & !1 } else { address };
+        let index = match self
+            .functions
+            .binary_search_by_key(&address, |function| function.address)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let function = &self.functions[index];
+        let offset = address - function.address;
+        if offset < function.size.max(1) {
+            Some((function, offset))
+        } else {
+            None
+        }
+    }
+
+    /// Whether `word` can be a return address left on the stack: inside a
+    /// function, and with the Thumb bit set on ARM.
+    pub fn is_return_address(&self, word: u64) -> bool {
+        (!self.thumb || word & 1 == 1) && self.lookup(word).is_some()
+    }
+
+    /// `address` as `name+offset`, if it is inside a function.
+    pub fn describe(&self, address: u64) -> Option<String> {
+        self.lookup(address)
+            .map(|(function, offset)| format!("{}+{:#x}", function.name, offset))
+    }
+}
+
+/// Demangle a legacy Rust symbol (`_ZN...E`), dropping the hash. Other names
+/// are returned unchanged.
+pub fn demangle(name: &str) -> String {
+    let mut rest = match name.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return name.to_string(),
+    };
+    let mut path: Vec<String> = Vec::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()) {
+        if digits == 0 {
+            break;
+        }
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) => len,
+            Err(_) => return name.to_string(),
+        };
+        let segment = match rest.get(digits..digits + len) {
+            Some(segment) => segment,
+            None => return name.to_string(),
+        };
+        path.push(unescape(segment));
+        rest = &rest[digits + len..];
+    }
+    if !rest.starts_with('E') || path.is_empty() {
+        return name.to_string();
+    }
+    let is_hash = |segment: &String| {
+        segment.len() == 17
+            && segment.starts_with('h')
+            && segment[1..].chars().all(|c| c.is_ascii_hexdigit())
+    };
+    if path.last().map_or(false, is_hash) {
+        path.pop();
+    }
+    path.join("::")
+}
+
+fn unescape(segment: &str) -> String {
+    const ESCAPES: &[(&str, &str)] = &[
+        ("$SP$", "@"),
+        ("$BP$", "*"),
+        ("$RF$", "&"),
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+    ];
+    let mut segment = segment
+        .strip_prefix("_$")
+        .map_or(segment.to_string(), |s| format!("${}", s));
+    for (escape, replacement) in ESCAPES {
+        segment = segment.replace(escape, replacement);
+    }
+    segment.replace("..", "::")
+}
@@ -0,0 +1,498 @@
+//! Decode a crash dump saved by a kernel panic and symbolicate it against the
+//! kernel ELF.
+//!
+//! The dump is either the raw crash dump area, read with a debugger, or a
+//! console log with the `CRASHDUMP` lines printed by the crash dump reporter
+//! on the next boot.
+//!
+//! With `--core`, it instead extracts the ELF core file of a faulted process
+//! from the `COREDUMP` lines of a console log, for loading into gdb.
+
+mod elf;
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+
+use kernel::crash_dump::{CrashDump, ProcessRecord, RecordKind, StackRecord, MAGIC};
+
+use elf::Symbols;
+
+const USAGE: &str = "\
+usage: crash-dump <dump> [<kernel.elf>]
+       crash-dump --core <log> <output.core>
+
+<dump> is a raw crash dump area or a console log with CRASHDUMP lines. With
+the kernel ELF, code addresses are shown with the function they are in.
+
+With --core, the process core dump in the COREDUMP lines of <log> is written
+to <output.core>.";
+
+type Result<T> = std::result::Result<T, String>;
+
+/// Extract the dump from the `<tag> <offset> <hex>` lines of a console log.
+/// Lines may have a prefix, such as a timestamp. If the log holds several
+/// dumps, the last one is used.
+fn from_log(log: &str, tag: &str) -> Result<Vec<u8>> {
+    let mut dump = Vec::new();
+    let mut found = false;
+    for line in log.lines() {
+        let rest = match line.find(tag) {
+            Some(start) if line[start + tag.len()..].starts_with(' ') => {
+                &line[start + tag.len() + 1..]
+            }
+            _ => continue,
+        };
+        let mut fields = rest.split_whitespace();
+        let (offset, hex) = match (fields.next(), fields.next()) {
+            (Some("END"), _) | (None, _) | (_, None) => continue,
+            (Some(offset), Some(hex)) => (offset, hex),
+        };
+        let offset = usize::from_str_radix(offset, 16)
+            .map_err(|_| format!("invalid {} line: {}", tag, line))?;
+        let bytes = (0..hex.len() / 2)
+            .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| format!("invalid {} line: {}", tag, line))?;
+        if offset == 0 {
+            dump.clear();
+        }
+        if dump.len() < offset + bytes.len() {
+            dump.resize(offset + bytes.len(), 0);
+        }
+        dump[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        found = true;
+    }
+    if found {
+        Ok(dump)
+    } else {
+        Err(format!("no {} lines found", tag))
+    }
+}
+
+fn load_dump(path: &str) -> Result<Vec<u8>> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if data.starts_with(&MAGIC.to_le_bytes()) {
+        Ok(data)
+    } else {
+        from_log(&String::from_utf8_lossy(&data), "CRASHDUMP")
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+/// Append the function of every code address in `line`.
+fn annotate(line: &str, symbols: Option<&Symbols>) -> String {
+    let symbols = match symbols {
+        Some(symbols) => symbols,
+        None => return line.to_string(),
+    };
+    let mut functions = Vec::new();
+    for word in line.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let hex = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+            Some(hex) => hex,
+            None => continue,
+        };
+        if let Some(function) = u64::from_str_radix(hex, 16)
+            .ok()
+            .and_then(|address| symbols.describe(address))
+        {
+            functions.push(function);
+        }
+    }
+    if functions.is_empty() {
+        line.to_string()
+    } else {
+        format!("{}  <{}>", line, functions.join(", "))
+    }
+}
+
+fn print_text(out: &mut dyn Write, payload: &[u8], symbols: Option<&Symbols>) -> io::Result<()> {
+    for line in String::from_utf8_lossy(payload).lines() {
+        let line = line.trim();
+        if !line.is_empty() {
+            writeln!(out, "  {}", annotate(line, symbols))?;
+        }
+    }
+    Ok(())
+}
+
+fn print_stack(
+    out: &mut dyn Write,
+    stack: &StackRecord,
+    symbols: Option<&Symbols>,
+) -> io::Result<()> {
+    writeln!(out, "Stack from {:#010x}:", stack.start)?;
+    let mut any = false;
+    for (i, word) in stack.words().enumerate() {
+        let address = stack.start as u64 + 4 * i as u64;
+        match symbols {
+            // Only show what looks like return addresses, which make up
+            // the call stack.
+            Some(symbols) => {
+                if symbols.is_return_address(word as u64) {
+                    let function = symbols.describe(word as u64).unwrap_or_default();
+                    writeln!(out, "  {:#010x}: {:#010x}  {}", address, word, function)?;
+                    any = true;
+                }
+            }
+            None => {
+                writeln!(out, "  {:#010x}: {:#010x}", address, word)?;
+                any = true;
+            }
+        }
+    }
+    if !any {
+        writeln!(out, "  (no code addresses)")?;
+    }
+    Ok(())
+}
+
+fn print_process(out: &mut dyn Write, process: &ProcessRecord) -> io::Result<()> {
+    let state = match process.state {
+        Some(state) => format!("{:?}", state),
+        None => "Unknown".to_string(),
+    };
+    writeln!(
+        out,
+        "  [{}] {:<16} {:<14} restarts {}, syscalls {}, dropped upcalls {}{}",
+        process.index,
+        String::from_utf8_lossy(process.name),
+        state,
+        process.restart_count,
+        process.syscall_count,
+        process.dropped_upcall_count,
+        if process.stack_overflowed {
+            ", stack overflow"
+        } else {
+            ""
+        },
+    )?;
+    writeln!(
+        out,
+        "      flash {:#010x}-{:#010x}  ram {:#010x}-{:#010x}",
+        process.flash_start, process.flash_end, process.mem_start, process.mem_end
+    )
+}
+
+fn print_dump(out: &mut dyn Write, dump: &CrashDump, symbols: Option<&Symbols>) -> io::Result<()> {
+    if dump.truncated() {
+        writeln!(
+            out,
+            "The dump did not fit in the crash dump area and is truncated.\n"
+        )?;
+    }
+
+    let mut processes = Vec::new();
+    for record in dump.records() {
+        match record.kind() {
+            Some(RecordKind::PanicMessage) => {
+                writeln!(out, "Panic:")?;
+                print_text(out, record.payload, symbols)?;
+            }
+            Some(RecordKind::KernelVersion) => {
+                writeln!(
+                    out,
+                    "Kernel version: {}",
+                    String::from_utf8_lossy(record.payload)
+                )?;
+            }
+            Some(RecordKind::Stack) => match StackRecord::parse(record.payload) {
+                Some(stack) => print_stack(out, &stack, symbols)?,
+                None => writeln!(out, "Stack: (invalid record)")?,
+            },
+            Some(RecordKind::Process) => processes.push(record.payload),
+            Some(RecordKind::CpuState) => {
+                writeln!(out, "CPU state:")?;
+                print_text(out, record.payload, symbols)?;
+            }
+            None => writeln!(
+                out,
+                "Unknown record {} of {} bytes",
+                record.tag,
+                record.payload.len()
+            )?,
+        }
+    }
+
+    if !processes.is_empty() {
+        writeln!(out, "Processes:")?;
+        for payload in processes {
+            match ProcessRecord::parse(payload) {
+                Some(process) => print_process(out, &process)?,
+                None => writeln!(out, "  (invalid record)")?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the decoded dump in `data` to `out`.
+fn decode(out: &mut dyn Write, data: &[u8], symbols: Option<&Symbols>) -> Result<()> {
+    let dump = CrashDump::parse(data).ok_or("invalid crash dump: bad header or checksum")?;
+    print_dump(out, &dump, symbols).map_err(|e| e.to_string())
+}
+
+/// Write the process core dump found in the log at `path` to `output`.
+fn extract_core(path: &str, output: &str) -> Result<()> {
+    let log = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let core = from_log(&String::from_utf8_lossy(&log), "COREDUMP")
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if !core.starts_with(b"\x7fELF") {
+        return Err(format!("{}: the core dump is not an ELF file", path));
+    }
+    fs::write(output, &core).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Wrote {} bytes to {}", core.len(), output);
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let (dump, elf) = match args {
+        [flag, log, output] if flag == "--core" => return extract_core(log, output),
+        [dump] => (dump, None),
+        [dump, elf] => (dump, Some(elf)),
+        _ => return Err(USAGE.to_string()),
+    };
+    let data = load_dump(dump)?;
+    let symbols = match elf {
+        Some(path) => {
+            let elf = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            Some(Symbols::parse(&elf).map_err(|e| format!("{}: {}", path, e))?)
+        }
+        None => None,
+    };
+    decode(&mut io::stdout(), &data, symbols.as_ref())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt;
+    use std::panic;
+
+    use kernel::debug::IoWrite;
+    use kernel::procs::FunctionCall;
+    use kernel::syscall::{ContextSwitchReason, SyscallReturn, UserspaceKernelBoundary};
+    use kernel::Chip;
+
+    const EM_ARM: u16 = 40;
+
+    /// A 32 bit ARM ELF file with only a symbol table of `functions`, each an
+    /// address (with the Thumb bit), size and name.
+    fn elf(functions: &[(u32, u32, &str)]) -> Vec<u8> {
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 16];
+        for (address, size, name) in functions {
+            symtab.extend(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend(&address.to_le_bytes());
+            symtab.extend(&size.to_le_bytes());
+            symtab.extend(&[2, 0, 1, 0]); // STT_FUNC, in section 1
+            strtab.extend(name.as_bytes());
+            strtab.push(0);
+        }
+
+        let symtab_offset = 52;
+        let strtab_offset = symtab_offset + symtab.len();
+        let shoff = strtab_offset + strtab.len();
+        let mut data = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        data.resize(16, 0);
+        data.extend(&2u16.to_le_bytes()); // e_type: executable
+        data.extend(&EM_ARM.to_le_bytes());
+        data.extend(&1u32.to_le_bytes()); // e_version
+        data.extend(&[0; 4]); // e_entry
+        data.extend(&[0; 4]); // e_phoff
+        data.extend(&(shoff as u32).to_le_bytes());
+        data.extend(&[0; 4]); // e_flags
+        data.extend(&52u16.to_le_bytes()); // e_ehsize
+        data.extend(&[0; 4]); // e_phentsize, e_phnum
+        data.extend(&40u16.to_le_bytes()); // e_shentsize
+        data.extend(&3u16.to_le_bytes()); // e_shnum
+        data.extend(&[0; 2]); // e_shstrndx
+        data.extend(&symtab);
+        data.extend(&strtab);
+
+        // Section headers: null, .symtab linked to .strtab, .strtab.
+        let section = |tipe: u32, offset: usize, size: usize, link: u32| {
+            let mut header = vec![0; 4];
+            header.extend(&tipe.to_le_bytes());
+            header.extend(&[0; 8]);
+            header.extend(&(offset as u32).to_le_bytes());
+            header.extend(&(size as u32).to_le_bytes());
+            header.extend(&link.to_le_bytes());
+            header.extend(&[0; 12]);
+            header
+        };
+        data.extend(vec![0; 40]);
+        data.extend(section(2, symtab_offset, symtab.len(), 2));
+        data.extend(section(3, strtab_offset, strtab.len(), 0));
+        data
+    }
+
+    struct Boundary;
+
+    impl UserspaceKernelBoundary for Boundary {
+        type StoredState = ();
+
+        fn initial_process_app_brk_size(&self) -> usize {
+            0
+        }
+
+        unsafe fn initialize_process(
+            &self,
+            _accessible_memory_start: *const u8,
+            _app_brk: *const u8,
+            _state: &mut (),
+        ) -> std::result::Result<(), ()> {
+            Err(())
+        }
+
+        unsafe fn set_syscall_return_value(
+            &self,
+            _accessible_memory_start: *const u8,
+            _app_brk: *const u8,
+            _state: &mut (),
+            _return_value: SyscallReturn,
+        ) -> std::result::Result<(), ()> {
+            Err(())
+        }
+
+        unsafe fn set_process_function(
+            &self,
+            _accessible_memory_start: *const u8,
+            _app_brk: *const u8,
+            _state: &mut (),
+            _upcall: FunctionCall,
+        ) -> std::result::Result<(), ()> {
+            Err(())
+        }
+
+        unsafe fn switch_to_process(
+            &self,
+            _accessible_memory_start: *const u8,
+            _app_brk: *const u8,
+            _state: &mut (),
+        ) -> (ContextSwitchReason, Option<*const u8>) {
+            unreachable!("the test chip has no processes")
+        }
+
+        unsafe fn print_context(
+            &self,
+            _accessible_memory_start: *const u8,
+            _app_brk: *const u8,
+            _state: &(),
+            _writer: &mut dyn fmt::Write,
+        ) {
+        }
+    }
+
+    /// A chip whose CPU state has code addresses in it.
+    struct TestChip;
+
+    impl Chip for TestChip {
+        type MPU = ();
+        type UserspaceKernelBoundary = Boundary;
+        type SchedulerTimer = ();
+        type WatchDog = ();
+
+        fn service_pending_interrupts(&self) {}
+
+        fn has_pending_interrupts(&self) -> bool {
+            false
+        }
+
+        fn mpu(&self) -> &() {
+            &()
+        }
+
+        fn scheduler_timer(&self) -> &() {
+            &()
+        }
+
+        fn watchdog(&self) -> &() {
+            &()
+        }
+
+        fn userspace_kernel_boundary(&self) -> &Boundary {
+            &Boundary
+        }
+
+        fn sleep(&self) {}
+
+        unsafe fn atomic<F, R>(&self, f: F) -> R
+        where
+            F: FnOnce() -> R,
+        {
+            f()
+        }
+
+        unsafe fn print_state(&self, writer: &mut dyn fmt::Write) {
+            let _ = write!(writer, "\r\n  pc: 0x08041013\r\n  lr: 0x08041105\r\n");
+        }
+    }
+
+    static TEST_CHIP: TestChip = TestChip;
+    static CHIP: Option<&'static TestChip> = Some(&TEST_CHIP);
+
+    /// Discards the panic output, which is not what is under test.
+    struct Discard;
+
+    impl fmt::Write for Discard {
+        fn write_str(&mut self, _s: &str) -> fmt::Result {
+            Ok(())
+        }
+    }
+
+    impl IoWrite for Discard {
+        fn write(&mut self, _buf: &[u8]) {}
+    }
+
+    #[test]
+    fn symbolicates_a_kernel_panic() {
+        // Panic through the kernel's panic routine, which saves the dump in
+        // the crash dump area.
+        let area: &'static mut [u8] = Box::leak(vec![0; 2048].into_boxed_slice());
+        let stack_end = 0u32;
+        unsafe {
+            kernel::crash_dump::set_area(area, &stack_end as *const u32 as *const u8);
+        }
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(|info| unsafe {
+            kernel::debug::panic_print(&mut Discard, info, &|| {}, &[], &CHIP);
+        }));
+        let result = panic::catch_unwind(|| panic!("bad pointer from 0x08041021"));
+        panic::set_hook(default_hook);
+        assert!(result.is_err());
+        let dump = kernel::crash_dump::previous().expect("no crash dump was saved");
+
+        let symbols = Symbols::parse(&elf(&[
+            (
+                0x0804_1001,
+                0x40,
+                "_ZN6kernel5debug11panic_print17h0123456789abcdefE",
+            ),
+            (0x0804_1101, 0x20, "main"),
+        ]))
+        .unwrap();
+        let mut out = Vec::new();
+        decode(&mut out, dump.as_bytes(), Some(&symbols)).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let panic = out
+            .lines()
+            .find(|line| line.contains("bad pointer"))
+            .expect("no panic message");
+        assert!(panic.ends_with("<kernel::debug::panic_print+0x20>"));
+        assert!(out.contains("Kernel version: "));
+        assert!(out.contains("Stack from "));
+        assert!(out.contains("  pc: 0x08041013  <kernel::debug::panic_print+0x12>\n"));
+        assert!(out.contains("  lr: 0x08041105  <main+0x4>\n"));
+    }
+}
@@ -0,0 +1,47 @@
+//! Link against the system libfido2.
+//!
+//! Distributions only ship the unversioned `libfido2.so` that `-lfido2`
+//! needs with their development package. Without it, link against the
+//! runtime library through a symlink in `OUT_DIR`. `LIBFIDO2_DIR` overrides
+//! where to look.
+
+use std::env;
+use std::path::PathBuf;
+
+const SEARCH_PATH: &[&str] = &[
+    "/usr/local/lib",
+    "/usr/lib",
+    "/usr/lib64",
+    "/usr/lib/x86_64-linux-gnu",
+    "/lib/x86_64-linux-gnu",
+    "/usr/lib/aarch64-linux-gnu",
+    "/lib/aarch64-linux-gnu",
+];
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=LIBFIDO2_DIR");
+    println!("cargo:rustc-link-lib=dylib=fido2");
+
+    let dirs: Vec<PathBuf> = match env::var_os("LIBFIDO2_DIR") {
+        Some(dir) => vec![dir.into()],
+        None => SEARCH_PATH.iter().map(PathBuf::from).collect(),
+    };
+    for dir in &dirs {
+        if dir.join("libfido2.so").exists() {
+            println!("cargo:rustc-link-search=native={}", dir.display());
+            return;
+        }
+    }
+    for dir in &dirs {
+        let runtime = dir.join("libfido2.so.1");
+        if runtime.exists() {
+            let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+            let link = out_dir.join("libfido2.so");
+            let _ = std::fs::remove_file(&link);
+            std::os::unix::fs::symlink(&runtime, &link).unwrap();
+            println!("cargo:rustc-link-search=native={}", out_dir.display());
+            return;
+        }
+    }
+    // Otherwise leave it to the linker's default search path.
+}
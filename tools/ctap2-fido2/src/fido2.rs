@@ -0,0 +1,435 @@
+//! Bindings to the parts of libfido2 the tests use.
+//!
+//! `ffi` declares the C API from `fido.h`, `fido/es256.h` and
+//! `fido/eddsa.h`. The wrappers own the libfido2 objects and turn error codes
+//! into `Error`, whose values are the CTAP2 status codes the authenticator
+//! returned where there is one.
+
+use crate::gadget::Gadget;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::Once;
+
+#[allow(non_camel_case_types)]
+pub mod ffi {
+    use std::os::raw::{c_char, c_int, c_uchar, c_void};
+
+    #[repr(C)]
+    pub struct fido_dev_t {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct fido_cred_t {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct fido_assert_t {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct es256_pk_t {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct eddsa_pk_t {
+        _private: [u8; 0],
+    }
+
+    /// The functions libfido2 uses to talk to a HID device. `read` returns
+    /// one input report and `write` is given an output report preceded by
+    /// its report ID.
+    #[repr(C)]
+    pub struct fido_dev_io_t {
+        pub open: Option<unsafe extern "C" fn(path: *const c_char) -> *mut c_void>,
+        pub close: Option<unsafe extern "C" fn(handle: *mut c_void)>,
+        pub read: Option<
+            unsafe extern "C" fn(
+                handle: *mut c_void,
+                buf: *mut c_uchar,
+                len: usize,
+                ms: c_int,
+            ) -> c_int,
+        >,
+        pub write: Option<
+            unsafe extern "C" fn(handle: *mut c_void, buf: *const c_uchar, len: usize) -> c_int,
+        >,
+    }
+
+    pub type fido_opt_t = c_int;
+    pub const FIDO_OPT_OMIT: fido_opt_t = 0;
+    pub const FIDO_OPT_FALSE: fido_opt_t = 1;
+    pub const FIDO_OPT_TRUE: fido_opt_t = 2;
+
+    pub const FIDO_OK: c_int = 0;
+
+    pub const COSE_ES256: c_int = -7;
+    pub const COSE_EDDSA: c_int = -8;
+
+    #[link(name = "fido2")]
+    extern "C" {
+        pub fn fido_init(flags: c_int);
+        pub fn fido_strerr(code: c_int) -> *const c_char;
+
+        pub fn fido_dev_new() -> *mut fido_dev_t;
+        pub fn fido_dev_free(dev: *mut *mut fido_dev_t);
+        pub fn fido_dev_set_io_functions(dev: *mut fido_dev_t, io: *const fido_dev_io_t) -> c_int;
+        pub fn fido_dev_open(dev: *mut fido_dev_t, path: *const c_char) -> c_int;
+        pub fn fido_dev_close(dev: *mut fido_dev_t) -> c_int;
+        pub fn fido_dev_is_fido2(dev: *const fido_dev_t) -> bool;
+        pub fn fido_dev_make_cred(
+            dev: *mut fido_dev_t,
+            cred: *mut fido_cred_t,
+            pin: *const c_char,
+        ) -> c_int;
+        pub fn fido_dev_get_assert(
+            dev: *mut fido_dev_t,
+            assert: *mut fido_assert_t,
+            pin: *const c_char,
+        ) -> c_int;
+        pub fn fido_dev_reset(dev: *mut fido_dev_t) -> c_int;
+
+        pub fn fido_cred_new() -> *mut fido_cred_t;
+        pub fn fido_cred_free(cred: *mut *mut fido_cred_t);
+        pub fn fido_cred_set_type(cred: *mut fido_cred_t, cose_alg: c_int) -> c_int;
+        pub fn fido_cred_set_clientdata_hash(
+            cred: *mut fido_cred_t,
+            hash: *const c_uchar,
+            len: usize,
+        ) -> c_int;
+        pub fn fido_cred_set_rp(
+            cred: *mut fido_cred_t,
+            id: *const c_char,
+            name: *const c_char,
+        ) -> c_int;
+        pub fn fido_cred_set_user(
+            cred: *mut fido_cred_t,
+            user_id: *const c_uchar,
+            user_id_len: usize,
+            name: *const c_char,
+            display_name: *const c_char,
+            icon: *const c_char,
+        ) -> c_int;
+        pub fn fido_cred_set_rk(cred: *mut fido_cred_t, rk: fido_opt_t) -> c_int;
+        pub fn fido_cred_exclude(cred: *mut fido_cred_t, id: *const c_uchar, len: usize) -> c_int;
+        pub fn fido_cred_verify_self(cred: *const fido_cred_t) -> c_int;
+        pub fn fido_cred_fmt(cred: *const fido_cred_t) -> *const c_char;
+        pub fn fido_cred_type(cred: *const fido_cred_t) -> c_int;
+        pub fn fido_cred_id_ptr(cred: *const fido_cred_t) -> *const c_uchar;
+        pub fn fido_cred_id_len(cred: *const fido_cred_t) -> usize;
+        pub fn fido_cred_pubkey_ptr(cred: *const fido_cred_t) -> *const c_uchar;
+        pub fn fido_cred_pubkey_len(cred: *const fido_cred_t) -> usize;
+        pub fn fido_cred_sigcount(cred: *const fido_cred_t) -> u32;
+
+        pub fn fido_assert_new() -> *mut fido_assert_t;
+        pub fn fido_assert_free(assert: *mut *mut fido_assert_t);
+        pub fn fido_assert_set_rp(assert: *mut fido_assert_t, id: *const c_char) -> c_int;
+        pub fn fido_assert_set_clientdata_hash(
+            assert: *mut fido_assert_t,
+            hash: *const c_uchar,
+            len: usize,
+        ) -> c_int;
+        pub fn fido_assert_set_up(assert: *mut fido_assert_t, up: fido_opt_t) -> c_int;
+        pub fn fido_assert_allow_cred(
+            assert: *mut fido_assert_t,
+            id: *const c_uchar,
+            len: usize,
+        ) -> c_int;
+        pub fn fido_assert_count(assert: *const fido_assert_t) -> usize;
+        pub fn fido_assert_id_ptr(assert: *const fido_assert_t, idx: usize) -> *const c_uchar;
+        pub fn fido_assert_id_len(assert: *const fido_assert_t, idx: usize) -> usize;
+        pub fn fido_assert_user_id_ptr(assert: *const fido_assert_t, idx: usize) -> *const c_uchar;
+        pub fn fido_assert_user_id_len(assert: *const fido_assert_t, idx: usize) -> usize;
+        pub fn fido_assert_sigcount(assert: *const fido_assert_t, idx: usize) -> u32;
+        pub fn fido_assert_verify(
+            assert: *const fido_assert_t,
+            idx: usize,
+            cose_alg: c_int,
+            pk: *const c_void,
+        ) -> c_int;
+
+        pub fn es256_pk_new() -> *mut es256_pk_t;
+        pub fn es256_pk_free(pk: *mut *mut es256_pk_t);
+        pub fn es256_pk_from_ptr(pk: *mut es256_pk_t, ptr: *const c_void, len: usize) -> c_int;
+        pub fn eddsa_pk_new() -> *mut eddsa_pk_t;
+        pub fn eddsa_pk_free(pk: *mut *mut eddsa_pk_t);
+        pub fn eddsa_pk_from_ptr(pk: *mut eddsa_pk_t, ptr: *const c_void, len: usize) -> c_int;
+    }
+}
+
+/// A libfido2 error code.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Error(pub c_int);
+
+impl Error {
+    pub const CREDENTIAL_EXCLUDED: Error = Error(0x19);
+    pub const UNSUPPORTED_ALGORITHM: Error = Error(0x26);
+    pub const KEY_STORE_FULL: Error = Error(0x28);
+    pub const NO_CREDENTIALS: Error = Error(0x2e);
+    pub const NOT_ALLOWED: Error = Error(0x30);
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = unsafe { CStr::from_ptr(ffi::fido_strerr(self.0)) };
+        write!(f, "{} ({})", self.0, message.to_string_lossy())
+    }
+}
+
+fn check(code: c_int) -> Result<(), Error> {
+    match code {
+        ffi::FIDO_OK => Ok(()),
+        code => Err(Error(code)),
+    }
+}
+
+/// Copy `len` bytes from `ptr`, which libfido2 leaves null when empty.
+unsafe fn bytes(ptr: *const u8, len: usize) -> Vec<u8> {
+    if ptr.is_null() {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(ptr, len).to_vec()
+    }
+}
+
+/// A libfido2 device connected to a gadget.
+pub struct Device(*mut ffi::fido_dev_t);
+
+impl Device {
+    /// Open `gadget` as libfido2 would open a HID authenticator: allocate a
+    /// channel and ask for its capabilities.
+    pub fn open(gadget: &'static Gadget) -> Result<Device, Error> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| unsafe { ffi::fido_init(0) });
+
+        let io = Gadget::io_functions();
+        let path = gadget.path();
+        unsafe {
+            let device = Device(ffi::fido_dev_new());
+            assert!(!device.0.is_null());
+            check(ffi::fido_dev_set_io_functions(device.0, &io))?;
+            check(ffi::fido_dev_open(device.0, path.as_ptr()))?;
+            Ok(device)
+        }
+    }
+
+    pub fn is_fido2(&self) -> bool {
+        unsafe { ffi::fido_dev_is_fido2(self.0) }
+    }
+
+    pub fn make_credential(&self, credential: &mut Credential) -> Result<(), Error> {
+        check(unsafe { ffi::fido_dev_make_cred(self.0, credential.0, ptr::null()) })
+    }
+
+    /// Request assertions, including any that libfido2 fetches with
+    /// `authenticatorGetNextAssertion`.
+    pub fn get_assertion(&self, assertion: &mut Assertion) -> Result<(), Error> {
+        check(unsafe { ffi::fido_dev_get_assert(self.0, assertion.0, ptr::null()) })
+    }
+
+    pub fn reset(&self) -> Result<(), Error> {
+        check(unsafe { ffi::fido_dev_reset(self.0) })
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::fido_dev_close(self.0);
+            ffi::fido_dev_free(&mut self.0);
+        }
+    }
+}
+
+/// A credential public key, as returned by libfido2.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublicKey {
+    pub alg: c_int,
+    pub bytes: Vec<u8>,
+}
+
+/// A request to create a credential, and then the credential.
+pub struct Credential(*mut ffi::fido_cred_t);
+
+impl Credential {
+    pub fn new(
+        alg: c_int,
+        rp: &str,
+        user_id: &[u8],
+        client_data_hash: &[u8; 32],
+        resident: bool,
+    ) -> Credential {
+        let rp = CString::new(rp).unwrap();
+        let name = CString::new("Tock user").unwrap();
+        let rk = if resident {
+            ffi::FIDO_OPT_TRUE
+        } else {
+            ffi::FIDO_OPT_OMIT
+        };
+        unsafe {
+            let credential = Credential(ffi::fido_cred_new());
+            assert!(!credential.0.is_null());
+            check(ffi::fido_cred_set_type(credential.0, alg)).unwrap();
+            check(ffi::fido_cred_set_clientdata_hash(
+                credential.0,
+                client_data_hash.as_ptr(),
+                client_data_hash.len(),
+            ))
+            .unwrap();
+            check(ffi::fido_cred_set_rp(
+                credential.0,
+                rp.as_ptr(),
+                ptr::null(),
+            ))
+            .unwrap();
+            check(ffi::fido_cred_set_user(
+                credential.0,
+                user_id.as_ptr(),
+                user_id.len(),
+                name.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+            ))
+            .unwrap();
+            check(ffi::fido_cred_set_rk(credential.0, rk)).unwrap();
+            credential
+        }
+    }
+
+    /// Refuse to create the credential if `id` is already registered.
+    pub fn exclude(&mut self, id: &[u8]) {
+        check(unsafe { ffi::fido_cred_exclude(self.0, id.as_ptr(), id.len()) }).unwrap();
+    }
+
+    /// Check the self attestation against the credential's own key.
+    pub fn verify_self(&self) -> Result<(), Error> {
+        check(unsafe { ffi::fido_cred_verify_self(self.0) })
+    }
+
+    pub fn format(&self) -> String {
+        unsafe {
+            let format = ffi::fido_cred_fmt(self.0);
+            assert!(!format.is_null());
+            CStr::from_ptr(format).to_string_lossy().into_owned()
+        }
+    }
+
+    pub fn id(&self) -> Vec<u8> {
+        unsafe { bytes(ffi::fido_cred_id_ptr(self.0), ffi::fido_cred_id_len(self.0)) }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        unsafe {
+            PublicKey {
+                alg: ffi::fido_cred_type(self.0),
+                bytes: bytes(
+                    ffi::fido_cred_pubkey_ptr(self.0),
+                    ffi::fido_cred_pubkey_len(self.0),
+                ),
+            }
+        }
+    }
+
+    pub fn sign_count(&self) -> u32 {
+        unsafe { ffi::fido_cred_sigcount(self.0) }
+    }
+}
+
+impl Drop for Credential {
+    fn drop(&mut self) {
+        unsafe { ffi::fido_cred_free(&mut self.0) }
+    }
+}
+
+/// A request for assertions, and then the assertions.
+pub struct Assertion(*mut ffi::fido_assert_t);
+
+impl Assertion {
+    /// Request assertions with the user's presence.
+    pub fn new(rp: &str, client_data_hash: &[u8; 32]) -> Assertion {
+        let rp = CString::new(rp).unwrap();
+        unsafe {
+            let assertion = Assertion(ffi::fido_assert_new());
+            assert!(!assertion.0.is_null());
+            check(ffi::fido_assert_set_rp(assertion.0, rp.as_ptr())).unwrap();
+            check(ffi::fido_assert_set_clientdata_hash(
+                assertion.0,
+                client_data_hash.as_ptr(),
+                client_data_hash.len(),
+            ))
+            .unwrap();
+            check(ffi::fido_assert_set_up(assertion.0, ffi::FIDO_OPT_TRUE)).unwrap();
+            assertion
+        }
+    }
+
+    /// Add `id` to the allow list.
+    pub fn allow(&mut self, id: &[u8]) {
+        check(unsafe { ffi::fido_assert_allow_cred(self.0, id.as_ptr(), id.len()) }).unwrap();
+    }
+
+    pub fn count(&self) -> usize {
+        unsafe { ffi::fido_assert_count(self.0) }
+    }
+
+    pub fn credential_id(&self, index: usize) -> Vec<u8> {
+        unsafe {
+            bytes(
+                ffi::fido_assert_id_ptr(self.0, index),
+                ffi::fido_assert_id_len(self.0, index),
+            )
+        }
+    }
+
+    pub fn user_id(&self, index: usize) -> Vec<u8> {
+        unsafe {
+            bytes(
+                ffi::fido_assert_user_id_ptr(self.0, index),
+                ffi::fido_assert_user_id_len(self.0, index),
+            )
+        }
+    }
+
+    pub fn sign_count(&self, index: usize) -> u32 {
+        unsafe { ffi::fido_assert_sigcount(self.0, index) }
+    }
+
+    /// Check assertion `index` was signed by `key`.
+    pub fn verify(&self, index: usize, key: &PublicKey) -> Result<(), Error> {
+        let ptr = key.bytes.as_ptr() as *const c_void;
+        let len = key.bytes.len();
+        unsafe {
+            match key.alg {
+                ffi::COSE_ES256 => {
+                    let mut pk = ffi::es256_pk_new();
+                    let result = check(ffi::es256_pk_from_ptr(pk, ptr, len)).and_then(|()| {
+                        check(ffi::fido_assert_verify(self.0, index, key.alg, pk as _))
+                    });
+                    ffi::es256_pk_free(&mut pk);
+                    result
+                }
+                ffi::COSE_EDDSA => {
+                    let mut pk = ffi::eddsa_pk_new();
+                    let result = check(ffi::eddsa_pk_from_ptr(pk, ptr, len)).and_then(|()| {
+                        check(ffi::fido_assert_verify(self.0, index, key.alg, pk as _))
+                    });
+                    ffi::eddsa_pk_free(&mut pk);
+                    result
+                }
+                alg => panic!("unexpected algorithm {}", alg),
+            }
+        }
+    }
+}
+
+impl Drop for Assertion {
+    fn drop(&mut self) {
+        unsafe { ffi::fido_assert_free(&mut self.0) }
+    }
+}
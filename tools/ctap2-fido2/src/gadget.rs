@@ -0,0 +1,350 @@
+//! The authenticator as a USB device, for libfido2 to talk to in-process.
+//!
+//! `Controller` stands in for the USB device controller. It keeps the
+//! endpoint buffers `capsules::usb::ctap::CtapHid` hands it, and moves HID
+//! reports between them and libfido2 the way the hardware would on an
+//! interrupt transfer: an OUT report is only accepted after the function
+//! resumed the endpoint, and an IN report is only there once it has data to
+//! send.
+//!
+//! `Gadget` builds the same stack as a board: `CtapHid` on the controller,
+//! `TicKVStore` on flash, a button and an alarm, with
+//! `capsules::ctap2::hid::CtapHidAuthenticator` on top. The flash, button and
+//! alarm are the `hil-mock` mocks. Whenever libfido2 waits for a report the
+//! gadget runs the device: it completes flash operations, and presses the
+//! button if the device is waiting for the user.
+
+use capsules::ctap2::authenticator::{self, Authenticator};
+use capsules::ctap2::ctaphid;
+use capsules::ctap2::hid::CtapHidAuthenticator;
+use capsules::tickv::TicKVStore;
+use capsules::usb::ctap::CtapHid;
+use hil_mock::alarm::MockAlarm;
+use hil_mock::flash::{MockFlash, MockPage, PAGE_SIZE};
+use hil_mock::gpio::MockPin;
+use kernel::common::cells::{OptionalCell, VolatileCell};
+use kernel::hil;
+use kernel::hil::kv_system::KVSystem;
+use kernel::hil::time::Alarm;
+use kernel::hil::usb::{self, TransferType, UsbController};
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uchar, c_void};
+use std::slice;
+
+use crate::fido2::ffi;
+
+/// Number of endpoints the controller keeps buffers for.
+const N_ENDPOINTS: usize = 4;
+/// The interrupt endpoint `CtapHid` uses.
+const ENDPOINT: usize = 1;
+
+pub const FLASH_PAGES: usize = 32;
+
+const SECRET: [u8; 32] = [0x7c; 32];
+const AAGUID: [u8; 16] = [0x70, 0x63, 0x6b, 0x21, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+pub struct Controller<'a> {
+    client: OptionalCell<&'a dyn usb::Client<'a>>,
+    in_buffers: [OptionalCell<&'a [VolatileCell<u8>]>; N_ENDPOINTS],
+    out_buffers: [OptionalCell<&'a [VolatileCell<u8>]>; N_ENDPOINTS],
+    /// Whether the function has data to send on each endpoint.
+    in_resumed: [Cell<bool>; N_ENDPOINTS],
+    /// Whether the function accepts data on each endpoint.
+    out_resumed: [Cell<bool>; N_ENDPOINTS],
+    attached: Cell<bool>,
+}
+
+impl<'a> Default for Controller<'a> {
+    fn default() -> Controller<'a> {
+        Controller {
+            client: OptionalCell::empty(),
+            in_buffers: Default::default(),
+            out_buffers: Default::default(),
+            in_resumed: Default::default(),
+            out_resumed: Default::default(),
+            attached: Cell::new(false),
+        }
+    }
+}
+
+impl<'a> Controller<'a> {
+    /// Deliver an OUT report from the host on `endpoint`. Returns `false`
+    /// if the function is not ready for it, in which case the hardware would
+    /// NAK it.
+    pub fn host_out(&self, endpoint: usize, report: &[u8]) -> bool {
+        if !self.attached.get() || !self.out_resumed[endpoint].replace(false) {
+            return false;
+        }
+        self.out_buffers[endpoint].map(|buffer| {
+            for (cell, byte) in buffer.iter().zip(report) {
+                cell.set(*byte);
+            }
+        });
+        let result = self.client.map(|client| {
+            client.packet_out(TransferType::Interrupt, endpoint, report.len() as u32)
+        });
+        match result {
+            Some(usb::OutResult::Ok) => self.out_resumed[endpoint].set(true),
+            // The function resumes the endpoint when it is ready, possibly
+            // already from `packet_out`.
+            Some(usb::OutResult::Delay) => {}
+            _ => return false,
+        }
+        true
+    }
+
+    /// Poll `endpoint` for an IN report into `report`, returning its length.
+    pub fn host_in(&self, endpoint: usize, report: &mut [u8]) -> Option<usize> {
+        if !self.attached.get() || !self.in_resumed[endpoint].get() {
+            return None;
+        }
+        let result = self
+            .client
+            .map(|client| client.packet_in(TransferType::Interrupt, endpoint));
+        match result {
+            Some(usb::InResult::Packet(len)) => {
+                self.in_buffers[endpoint].map(|buffer| {
+                    for (byte, cell) in report.iter_mut().zip(&buffer[..len]) {
+                        *byte = cell.get();
+                    }
+                });
+                self.in_resumed[endpoint].set(false);
+                self.client
+                    .map(|client| client.packet_transmitted(endpoint));
+                Some(len)
+            }
+            _ => {
+                self.in_resumed[endpoint].set(false);
+                None
+            }
+        }
+    }
+}
+
+impl<'a> UsbController<'a> for Controller<'a> {
+    fn set_client(&self, client: &'a dyn usb::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, _buf: &'a [VolatileCell<u8>]) {}
+
+    fn endpoint_set_in_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.in_buffers[endpoint].set(buf);
+    }
+
+    fn endpoint_set_out_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.out_buffers[endpoint].set(buf);
+    }
+
+    fn enable_as_device(&self, _speed: usb::DeviceSpeed) {}
+
+    fn attach(&self) {
+        self.attached.set(true);
+    }
+
+    fn detach(&self) {
+        self.attached.set(false);
+    }
+
+    fn set_address(&self, _addr: u16) {}
+
+    fn enable_address(&self) {}
+
+    fn endpoint_in_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+
+    fn endpoint_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+
+    fn endpoint_in_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+
+    fn endpoint_resume_in(&self, endpoint: usize) {
+        self.in_resumed[endpoint].set(true);
+    }
+
+    fn endpoint_resume_out(&self, endpoint: usize) {
+        self.out_resumed[endpoint].set(true);
+    }
+}
+
+type Hid = CtapHid<'static, Controller<'static>>;
+type Store = TicKVStore<'static, MockFlash<'static>>;
+pub type Device = CtapHidAuthenticator<'static, Hid, Store, MockPin<'static>, MockAlarm<'static>>;
+
+pub struct Gadget {
+    pub controller: &'static Controller<'static>,
+    pub flash: &'static MockFlash<'static>,
+    pub button: &'static MockPin<'static>,
+    pub alarm: &'static MockAlarm<'static>,
+    pub device: &'static Device,
+    /// Whether the user presses the button when the device asks.
+    pub user_present: Cell<bool>,
+}
+
+fn leak<T>(value: T) -> &'static mut T {
+    Box::leak(Box::new(value))
+}
+
+impl Gadget {
+    /// Plug in a new authenticator with erased flash.
+    pub fn new() -> &'static Gadget {
+        Gadget::with_flash(&vec![0xff; FLASH_PAGES * PAGE_SIZE])
+    }
+
+    /// Plug in a new authenticator whose flash holds `contents`, for example
+    /// that of another gadget, as if it had been restarted.
+    pub fn with_flash(contents: &[u8]) -> &'static Gadget {
+        let controller = leak(Controller::default());
+        let hid: &'static Hid = leak(CtapHid::new(
+            controller,
+            0x1209,
+            0x0001,
+            &["Tock", "CTAP2 gadget", "0"],
+        ));
+
+        let flash = leak(MockFlash::new(FLASH_PAGES));
+        flash.set_contents(0, contents);
+        let store: &'static Store = leak(TicKVStore::new(
+            flash,
+            leak([0; 512]),
+            leak(MockPage::default()),
+            0,
+            FLASH_PAGES * PAGE_SIZE,
+        ));
+        hil::flash::HasClient::set_client(&*flash, store);
+
+        let button = leak(MockPin::new());
+        let alarm = leak(MockAlarm::new());
+
+        let device: &'static Device = leak(CtapHidAuthenticator::new(
+            hid,
+            store,
+            button,
+            hil::gpio::ActivationMode::ActiveHigh,
+            hil::gpio::FloatingState::PullDown,
+            alarm,
+            Authenticator::new(SECRET, AAGUID),
+            leak([0; 1024]),
+            leak([0; authenticator::RECORD_LEN]),
+            leak([0; 8]),
+            leak([0; authenticator::COUNTER_LEN]),
+            leak([0; ctaphid::PACKET_SIZE]),
+            leak([0; ctaphid::PACKET_SIZE]),
+        ));
+        hid.set_client(device);
+        store.set_client(device);
+        hil::gpio::Interrupt::set_client(&*button, device);
+        alarm.set_alarm_client(device);
+
+        controller.set_client(hid);
+        usb::Client::enable(hid);
+        usb::Client::attach(hid);
+
+        store.initalise();
+        while flash.complete() {}
+        device.start().unwrap();
+
+        leak(Gadget {
+            controller,
+            flash,
+            button,
+            alarm,
+            device,
+            user_present: Cell::new(true),
+        })
+    }
+
+    /// Let `ms` milliseconds pass.
+    pub fn advance(&self, ms: u32) {
+        self.alarm.advance(ms);
+    }
+
+    /// Run the device until it has something to send, returning `false` if
+    /// it has nothing left to do.
+    fn run(&self) -> bool {
+        if self.flash.complete() {
+            return true;
+        }
+        if self.user_present.get() && self.button.interrupts_enabled() {
+            self.button.set_input(true);
+            self.button.set_input(false);
+            return true;
+        }
+        false
+    }
+
+    /// Read an IN report into `report`, running the device until it sends
+    /// one.
+    fn read(&self, report: &mut [u8]) -> Option<usize> {
+        loop {
+            if let Some(len) = self.controller.host_in(ENDPOINT, report) {
+                return Some(len);
+            }
+            if !self.run() {
+                return None;
+            }
+        }
+    }
+
+    /// Write an OUT report, running the device until it accepts it.
+    fn write(&self, report: &[u8]) -> bool {
+        loop {
+            if self.controller.host_out(ENDPOINT, report) {
+                return true;
+            }
+            if !self.run() {
+                return false;
+            }
+        }
+    }
+
+    /// The path libfido2 opens the gadget by.
+    pub fn path(&'static self) -> CString {
+        CString::new(format!("tock-ctap2:{:p}", self as *const Gadget)).unwrap()
+    }
+
+    /// The functions libfido2 uses to talk to gadgets.
+    pub fn io_functions() -> ffi::fido_dev_io_t {
+        ffi::fido_dev_io_t {
+            open: Some(io_open),
+            close: Some(io_close),
+            read: Some(io_read),
+            write: Some(io_write),
+        }
+    }
+}
+
+unsafe extern "C" fn io_open(path: *const c_char) -> *mut c_void {
+    let path = CStr::from_ptr(path).to_string_lossy();
+    path.strip_prefix("tock-ctap2:0x")
+        .and_then(|address| usize::from_str_radix(address, 16).ok())
+        .map_or(std::ptr::null_mut(), |address| address as *mut c_void)
+}
+
+unsafe extern "C" fn io_close(_handle: *mut c_void) {}
+
+unsafe extern "C" fn io_read(
+    handle: *mut c_void,
+    buf: *mut c_uchar,
+    len: usize,
+    _ms: c_int,
+) -> c_int {
+    let gadget = &*(handle as *const Gadget);
+    let report = slice::from_raw_parts_mut(buf, len);
+    match gadget.read(report) {
+        Some(len) => len as c_int,
+        // A real device would time out.
+        None => -1,
+    }
+}
+
+unsafe extern "C" fn io_write(handle: *mut c_void, buf: *const c_uchar, len: usize) -> c_int {
+    let gadget = &*(handle as *const Gadget);
+    // Skip the report ID: CTAPHID only has one report.
+    let report = slice::from_raw_parts(buf, len);
+    match report.split_first() {
+        Some((_, report)) if report.len() == ctaphid::PACKET_SIZE && gadget.write(report) => {
+            len as c_int
+        }
+        _ => -1,
+    }
+}
@@ -0,0 +1,11 @@
+//! Tests the kernel's CTAP2 authenticator with libfido2.
+//!
+//! The authenticator in `capsules::ctap2` runs in-process behind a stand-in
+//! for the USB device controller (see `gadget`), and libfido2 talks to it
+//! through custom I/O functions instead of the kernel's hidraw devices.
+//! Everything from the `CtapHid` USB function up is the code a board runs,
+//! and libfido2 is the library `fido2-token` and OpenSSH use. The tests are
+//! in `tests/`.
+
+pub mod fido2;
+pub mod gadget;
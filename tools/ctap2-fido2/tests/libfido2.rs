@@ -0,0 +1,154 @@
+//! libfido2 registers and authenticates with the authenticator, as a browser
+//! would.
+
+use capsules::ctap2::authenticator::MAX_RESIDENT_CREDENTIALS;
+use capsules::ctap2::hid::{NEXT_ASSERTION_TIMEOUT_MS, RESET_WINDOW_MS};
+use ctap2_fido2::fido2::{ffi, Assertion, Credential, Device, Error, PublicKey};
+use ctap2_fido2::gadget::Gadget;
+
+const RP: &str = "tockos.org";
+const CLIENT_DATA_HASH: [u8; 32] = [0xcd; 32];
+
+/// Register a credential, checking its self attestation.
+fn register(device: &Device, alg: i32, user: &[u8], resident: bool) -> Credential {
+    let mut credential = Credential::new(alg, RP, user, &CLIENT_DATA_HASH, resident);
+    device.make_credential(&mut credential).unwrap();
+    assert_eq!(credential.format(), "packed");
+    assert_eq!(credential.public_key().alg, alg);
+    credential.verify_self().unwrap();
+    credential
+}
+
+/// Authenticate with `allow`, or with resident credentials if it is empty.
+fn authenticate(device: &Device, allow: &[&Credential]) -> Result<Assertion, Error> {
+    let mut assertion = Assertion::new(RP, &CLIENT_DATA_HASH);
+    for credential in allow {
+        assertion.allow(&credential.id());
+    }
+    device.get_assertion(&mut assertion).map(|()| assertion)
+}
+
+#[test]
+fn register_and_authenticate() {
+    let gadget = Gadget::new();
+    let device = Device::open(gadget).unwrap();
+    assert!(device.is_fido2());
+
+    for &alg in &[ffi::COSE_ES256, ffi::COSE_EDDSA] {
+        let credential = register(&device, alg, b"user", false);
+        let assertion = authenticate(&device, &[&credential]).unwrap();
+        assert_eq!(assertion.count(), 1);
+        assert_eq!(assertion.credential_id(0), credential.id());
+        assertion.verify(0, &credential.public_key()).unwrap();
+        assert!(assertion.sign_count(0) > credential.sign_count());
+
+        // A signature does not verify with another key.
+        let other = register(&device, alg, b"other user", false);
+        assert!(assertion.verify(0, &other.public_key()).is_err());
+
+        // Registering it again is refused.
+        let mut again = Credential::new(alg, RP, b"user", &CLIENT_DATA_HASH, false);
+        again.exclude(&credential.id());
+        assert_eq!(
+            device.make_credential(&mut again),
+            Err(Error::CREDENTIAL_EXCLUDED)
+        );
+    }
+
+    let mut unsupported = Credential::new(-257, RP, b"user", &CLIENT_DATA_HASH, false);
+    assert_eq!(
+        device.make_credential(&mut unsupported),
+        Err(Error::UNSUPPORTED_ALGORITHM)
+    );
+}
+
+#[test]
+fn resident_credentials() {
+    let gadget = Gadget::new();
+    let device = Device::open(gadget).unwrap();
+    assert_eq!(
+        authenticate(&device, &[]).err(),
+        Some(Error::NO_CREDENTIALS)
+    );
+
+    // Each user gets their own credential, and registering a user again
+    // replaces theirs.
+    register(&device, ffi::COSE_ES256, b"alice", true);
+    let bob = register(&device, ffi::COSE_EDDSA, b"bob", true);
+    let alice = register(&device, ffi::COSE_ES256, b"alice", true);
+
+    // libfido2 fetches the second with authenticatorGetNextAssertion.
+    let assertion = authenticate(&device, &[]).unwrap();
+    assert_eq!(assertion.count(), 2);
+    let keys: Vec<(Vec<u8>, Vec<u8>, PublicKey)> = [&alice, &bob]
+        .iter()
+        .zip(&[&b"alice"[..], &b"bob"[..]])
+        .map(|(credential, user)| (user.to_vec(), credential.id(), credential.public_key()))
+        .collect();
+    for (index, (user, id, key)) in keys.iter().enumerate() {
+        assert_eq!(&assertion.user_id(index), user);
+        assert_eq!(&assertion.credential_id(index), id);
+        assertion.verify(index, key).unwrap();
+    }
+
+    // The slots fill up with other users.
+    for user in 2..MAX_RESIDENT_CREDENTIALS {
+        register(
+            &device,
+            ffi::COSE_EDDSA,
+            format!("user {}", user).as_bytes(),
+            true,
+        );
+    }
+    let mut full = Credential::new(ffi::COSE_ES256, RP, b"mallory", &CLIENT_DATA_HASH, true);
+    assert_eq!(
+        device.make_credential(&mut full),
+        Err(Error::KEY_STORE_FULL)
+    );
+    let assertion = authenticate(&device, &[]).unwrap();
+    assert_eq!(assertion.count(), MAX_RESIDENT_CREDENTIALS);
+
+    // The credentials outlast a restart, and so does the signature counter.
+    drop(device);
+    gadget.advance(NEXT_ASSERTION_TIMEOUT_MS);
+    let last = assertion.sign_count(MAX_RESIDENT_CREDENTIALS - 1);
+    let gadget = Gadget::with_flash(&gadget.flash.contents());
+    let device = Device::open(gadget).unwrap();
+    let assertion = authenticate(&device, &[&bob]).unwrap();
+    assertion.verify(0, &bob.public_key()).unwrap();
+    assert!(assertion.sign_count(0) > last);
+}
+
+#[test]
+fn reset() {
+    let gadget = Gadget::new();
+    let device = Device::open(gadget).unwrap();
+    let resident = register(&device, ffi::COSE_ES256, b"alice", true);
+    let credential = register(&device, ffi::COSE_EDDSA, b"bob", false);
+
+    // Soon after plugging in, a reset forgets every credential.
+    device.reset().unwrap();
+    assert_eq!(
+        authenticate(&device, &[]).err(),
+        Some(Error::NO_CREDENTIALS)
+    );
+    assert_eq!(
+        authenticate(&device, &[&resident, &credential]).err(),
+        Some(Error::NO_CREDENTIALS)
+    );
+
+    // They stay forgotten after a restart.
+    let gadget = Gadget::with_flash(&gadget.flash.contents());
+    let device = Device::open(gadget).unwrap();
+    assert_eq!(
+        authenticate(&device, &[&resident, &credential]).err(),
+        Some(Error::NO_CREDENTIALS)
+    );
+    let credential = register(&device, ffi::COSE_EDDSA, b"bob", false);
+    authenticate(&device, &[&credential]).unwrap();
+
+    // Later, resets are refused.
+    gadget.advance(RESET_WINDOW_MS);
+    assert_eq!(device.reset(), Err(Error::NOT_ALLOWED));
+    authenticate(&device, &[&credential]).unwrap();
+}
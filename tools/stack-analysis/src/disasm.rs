@@ -0,0 +1,371 @@
+//! Decode the few instructions that matter for stack analysis: calls,
+//! jumps, and the instructions that lower the stack pointer in a prologue.
+//! Everything else is skipped over.
+
+use std::convert::TryInto;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// A call to a known address.
+    Call(u64),
+    /// A jump to a known address. Jumps out of the function are tail calls.
+    Jump(u64),
+    /// A call or jump through a register.
+    Indirect,
+    /// Lowers the stack pointer by this many bytes.
+    StackGrow(u64),
+}
+
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as i64
+}
+
+fn offset(address: u64, offset: i64) -> u64 {
+    (address as i64).wrapping_add(offset) as u64
+}
+
+/// Decode Thumb-2 code, as run by Cortex-M cores.
+pub fn thumb(code: &[u8], address: u64) -> Vec<(u64, Op)> {
+    let halfword = |i: usize| {
+        code.get(i..i + 2)
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()) as u32)
+    };
+    let mut ops = Vec::new();
+    let mut i = 0;
+    while let Some(h1) = halfword(i) {
+        let pc = address + i as u64;
+        if h1 >> 11 < 0b11101 {
+            i += 2;
+            let op = if h1 & 0xFF87 == 0x4780 || (h1 & 0xFF87 == 0x4700 && (h1 >> 3) & 0xF != 14) {
+                // blx rm, and bx rm other than a return through lr.
+                Op::Indirect
+            } else if h1 & 0xF800 == 0xE000 {
+                // b
+                Op::Jump(offset(pc + 4, sign_extend(h1 & 0x7FF, 11) << 1))
+            } else if h1 & 0xF000 == 0xD000 && (h1 >> 8) & 0xF < 0xE {
+                // b<c>
+                Op::Jump(offset(pc + 4, sign_extend(h1 & 0xFF, 8) << 1))
+            } else if h1 & 0xFE00 == 0xB400 {
+                // push {registers}
+                let registers = (h1 & 0xFF).count_ones() + (h1 >> 8 & 1);
+                Op::StackGrow(4 * registers as u64)
+            } else if h1 & 0xFF80 == 0xB080 {
+                // sub sp, #imm
+                Op::StackGrow(4 * (h1 & 0x7F) as u64)
+            } else {
+                continue;
+            };
+            ops.push((pc, op));
+            continue;
+        }
+
+        let h2 = match halfword(i + 2) {
+            Some(h2) => h2,
+            None => break,
+        };
+        i += 4;
+        let op = if h1 & 0xF800 == 0xF000 && h2 & 0x8000 == 0x8000 {
+            let s = (h1 >> 10) & 1;
+            let j1 = (h2 >> 13) & 1;
+            let j2 = (h2 >> 11) & 1;
+            if h2 & 0x5000 == 0x0000 {
+                // b<c>.w
+                if (h1 >> 6) & 0xF >= 0xE {
+                    continue;
+                }
+                let imm = s << 20 | j2 << 19 | j1 << 18 | (h1 & 0x3F) << 12 | (h2 & 0x7FF) << 1;
+                Op::Jump(offset(pc + 4, sign_extend(imm, 21)))
+            } else {
+                let i1 = !(j1 ^ s) & 1;
+                let i2 = !(j2 ^ s) & 1;
+                let imm = s << 24 | i1 << 23 | i2 << 22 | (h1 & 0x3FF) << 12 | (h2 & 0x7FF) << 1;
+                let target = offset(pc + 4, sign_extend(imm, 25));
+                match h2 & 0x5000 {
+                    // bl
+                    0x5000 => Op::Call(target),
+                    // b.w
+                    0x1000 => Op::Jump(target),
+                    // blx to ARM code, which Cortex-M cores cannot run.
+                    _ => continue,
+                }
+            }
+        } else if h1 == 0xE92D {
+            // push.w {registers}
+            Op::StackGrow(4 * (h2 & 0xFFFF).count_ones() as u64)
+        } else if h1 == 0xF84D && h2 & 0xFFF == 0xD04 {
+            // str.w rt, [sp, #-4]!
+            Op::StackGrow(4)
+        } else if h1 & 0xFFBF == 0xED2D && h2 & 0x0E00 == 0x0A00 {
+            // vpush {registers}
+            Op::StackGrow(4 * (h2 & 0xFF) as u64)
+        } else if h1 & 0xFBEF == 0xF1AD && h2 & 0x8F00 == 0x0D00 {
+            // sub.w sp, sp, #imm
+            let imm = (h1 >> 10 & 1) << 11 | (h2 >> 12 & 0x7) << 8 | (h2 & 0xFF);
+            Op::StackGrow(thumb_expand_imm(imm) as u64)
+        } else if h1 & 0xFBFF == 0xF2AD && h2 & 0x8F00 == 0x0D00 {
+            // subw sp, sp, #imm
+            let imm = (h1 >> 10 & 1) << 11 | (h2 >> 12 & 0x7) << 8 | (h2 & 0xFF);
+            Op::StackGrow(imm as u64)
+        } else {
+            continue;
+        };
+        ops.push((pc, op));
+    }
+    ops
+}
+
+/// The ThumbExpandImm() function of the ARMv7-M Architecture Reference
+/// Manual, for instructions that do not set the carry flag.
+fn thumb_expand_imm(imm: u32) -> u32 {
+    let byte = imm & 0xFF;
+    if imm >> 10 == 0 {
+        match imm >> 8 & 0x3 {
+            0 => byte,
+            1 => byte << 16 | byte,
+            2 => byte << 24 | byte << 8,
+            _ => byte << 24 | byte << 16 | byte << 8 | byte,
+        }
+    } else {
+        (0x80 | (imm & 0x7F)).rotate_right(imm >> 7)
+    }
+}
+
+const RA: u32 = 1;
+const SP: u32 = 2;
+const T0: u32 = 5;
+
+/// Decode RISC-V code with the compressed extension. `is_64` selects RV64,
+/// where some compressed encodings differ from RV32.
+pub fn riscv(code: &[u8], address: u64, is_64: bool) -> Vec<(u64, Op)> {
+    let mut ops = Vec::new();
+    // The register and value set by the previous instruction, if it was an
+    // `auipc`, for the `auipc` and `jalr` pairs of `call` and `tail`.
+    let mut auipc: Option<(u32, u64)> = None;
+    let mut i = 0;
+    while let Some(bytes) = code.get(i..i + 2) {
+        let pc = address + i as u64;
+        let h = u16::from_le_bytes(bytes.try_into().unwrap()) as u32;
+        let previous = auipc.take();
+
+        if h & 0x3 != 0x3 {
+            i += 2;
+            let quadrant = h & 0x3;
+            let funct3 = h >> 13;
+            let rd = (h >> 7) & 0x1F;
+            let rs2 = (h >> 2) & 0x1F;
+            let op = match (quadrant, funct3) {
+                // c.jal, on RV32 only, and c.j
+                (0b01, 0b001) if !is_64 => Op::Call(offset(pc, compressed_jump_offset(h))),
+                (0b01, 0b101) => Op::Jump(offset(pc, compressed_jump_offset(h))),
+                // c.addi16sp
+                (0b01, 0b011) if rd == SP => {
+                    let imm = (h >> 12 & 1) << 9
+                        | (h >> 6 & 1) << 4
+                        | (h >> 5 & 1) << 6
+                        | (h >> 3 & 0x3) << 7
+                        | (h >> 2 & 1) << 5;
+                    match sign_extend(imm, 10) {
+                        imm if imm < 0 => Op::StackGrow(-imm as u64),
+                        _ => continue,
+                    }
+                }
+                // c.jr and c.jalr
+                (0b10, 0b100) if rd != 0 && rs2 == 0 => {
+                    if h >> 12 & 1 == 0 && (rd == RA || rd == T0) {
+                        // A return.
+                        continue;
+                    }
+                    Op::Indirect
+                }
+                _ => continue,
+            };
+            ops.push((pc, op));
+            continue;
+        }
+
+        let word = match code.get(i..i + 4) {
+            Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()),
+            None => break,
+        };
+        i += 4;
+        let rd = (word >> 7) & 0x1F;
+        let rs1 = (word >> 15) & 0x1F;
+        let op = match word & 0x7F {
+            // jal
+            0x6F => {
+                let imm = (word >> 31) << 20
+                    | (word >> 21 & 0x3FF) << 1
+                    | (word >> 20 & 1) << 11
+                    | (word >> 12 & 0xFF) << 12;
+                let target = offset(pc, sign_extend(imm, 21));
+                if rd == 0 {
+                    Op::Jump(target)
+                } else {
+                    Op::Call(target)
+                }
+            }
+            // jalr
+            0x67 => {
+                let imm = sign_extend(word >> 20, 12);
+                match previous {
+                    Some((register, base)) if register == rs1 && rd == 0 => {
+                        Op::Jump(offset(base, imm))
+                    }
+                    Some((register, base)) if register == rs1 => Op::Call(offset(base, imm)),
+                    _ if rd == 0 && (rs1 == RA || rs1 == T0) && imm == 0 => continue,
+                    _ => Op::Indirect,
+                }
+            }
+            // auipc
+            0x17 => {
+                auipc = Some((rd, offset(pc, sign_extend(word & 0xFFFF_F000, 32))));
+                continue;
+            }
+            // addi sp, sp, -imm
+            0x13 if word >> 12 & 0x7 == 0 && rd == SP && rs1 == SP => {
+                match sign_extend(word >> 20, 12) {
+                    imm if imm < 0 => Op::StackGrow(-imm as u64),
+                    _ => continue,
+                }
+            }
+            _ => continue,
+        };
+        ops.push((pc, op));
+    }
+    ops
+}
+
+/// The offset of a `c.j` or `c.jal` instruction.
+fn compressed_jump_offset(h: u32) -> i64 {
+    let imm = (h >> 12 & 1) << 11
+        | (h >> 11 & 1) << 4
+        | (h >> 9 & 0x3) << 8
+        | (h >> 8 & 1) << 10
+        | (h >> 7 & 1) << 6
+        | (h >> 6 & 1) << 7
+        | (h >> 3 & 0x7) << 1
+        | (h >> 2 & 1) << 5;
+    sign_extend(imm, 12)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn halfwords(code: &[u16]) -> Vec<u8> {
+        code.iter().flat_map(|h| h.to_le_bytes().to_vec()).collect()
+    }
+
+    /// Instructions with the low bits `0b11` are 32 bits, the rest are
+    /// compressed.
+    fn instructions(code: &[u32]) -> Vec<u8> {
+        code.iter()
+            .flat_map(|&word| match word & 0x3 {
+                0x3 => word.to_le_bytes().to_vec(),
+                _ => (word as u16).to_le_bytes().to_vec(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn thumb_narrow() {
+        let code = halfwords(&[
+            0xB590, // push {r4, r7, lr}
+            0xB084, // sub sp, #16
+            0x4798, // blx r3
+            0xE7FE, // b .
+            0xD0FD, // beq . - 2
+            0x4770, // bx lr
+            0xBF00, // nop
+        ]);
+        assert_eq!(
+            thumb(&code, 0x1000),
+            [
+                (0x1000, Op::StackGrow(12)),
+                (0x1002, Op::StackGrow(16)),
+                (0x1004, Op::Indirect),
+                (0x1006, Op::Jump(0x1006)),
+                (0x1008, Op::Jump(0x1006)),
+            ]
+        );
+    }
+
+    #[test]
+    fn thumb_wide() {
+        let code = halfwords(&[
+            0xE92D, 0x4FF0, // push.w {r4-r11, lr}
+            0xED2D, 0x8B04, // vpush {d8, d9}
+            0xF5AD, 0x7D80, // sub.w sp, sp, #256
+            0xF2AD, 0x4D04, // subw sp, sp, #1028
+            0xF000, 0xFFFE, // bl . + 4 + 0xffc
+            0xF7FF, 0xFFFE, // bl .
+            0xF000, 0xB800, // b.w . + 4
+            0xF47F, 0xAFFE, // bne.w .
+        ]);
+        assert_eq!(
+            thumb(&code, 0x2000),
+            [
+                (0x2000, Op::StackGrow(36)),
+                (0x2004, Op::StackGrow(16)),
+                (0x2008, Op::StackGrow(256)),
+                (0x200C, Op::StackGrow(1028)),
+                (0x2010, Op::Call(0x3010)),
+                (0x2014, Op::Call(0x2014)),
+                (0x2018, Op::Jump(0x201C)),
+                (0x201C, Op::Jump(0x201C)),
+            ]
+        );
+    }
+
+    #[test]
+    fn thumb_truncated() {
+        // The first half of a bl.
+        assert_eq!(
+            thumb(&halfwords(&[0xB580, 0xF000]), 0),
+            [(0, Op::StackGrow(8))]
+        );
+    }
+
+    #[test]
+    fn riscv_rv32() {
+        let code = instructions(&[
+            0x7139,     // c.addi16sp sp, -64
+            0xFE010113, // addi sp, sp, -32
+            0x00000097, // auipc ra, 0
+            0x010080E7, // jalr ra, 16(ra)
+            0x100000EF, // jal ra, . + 0x100
+            0x000780E7, // jalr ra, 0(a5)
+            0x8782,     // c.jr a5
+            0x00000317, // auipc t1, 0
+            0x02030067, // jalr zero, 32(t1)
+            0x2001,     // c.jal .
+            0xBFFD,     // c.j . - 2
+            0x00008067, // ret
+            0x8082,     // c.ret
+            0x02010113, // addi sp, sp, 32
+        ]);
+        assert_eq!(
+            riscv(&code, 0x8000_0000, false),
+            [
+                (0x8000_0000, Op::StackGrow(64)),
+                (0x8000_0002, Op::StackGrow(32)),
+                (0x8000_000A, Op::Call(0x8000_0016)),
+                (0x8000_000E, Op::Call(0x8000_010E)),
+                (0x8000_0012, Op::Indirect),
+                (0x8000_0016, Op::Indirect),
+                (0x8000_001C, Op::Jump(0x8000_0038)),
+                (0x8000_0020, Op::Call(0x8000_0020)),
+                (0x8000_0022, Op::Jump(0x8000_0020)),
+            ]
+        );
+    }
+
+    #[test]
+    fn riscv_rv64() {
+        // c.addiw a0, 1 on RV64, where RV32 has c.jal.
+        let code = instructions(&[0x2505, 0x7139]);
+        assert_eq!(riscv(&code, 0, true), [(2, Op::StackGrow(64))]);
+        assert_eq!(riscv(&code, 0, false)[0], (0, Op::Call(0x620)));
+    }
+}
@@ -0,0 +1,311 @@
+//! Just enough of ELF to find a kernel's functions, code and stack sizes.
+//!
+//! Handles little endian 32 and 64 bit files, which covers the ARM and RISC-V
+//! kernels Tock builds.
+
+use std::convert::TryInto;
+
+use crate::Result;
+
+const EM_ARM: u16 = 40;
+const EM_RISCV: u16 = 243;
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 0x2;
+const STT_FUNC: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Machine {
+    Arm,
+    RiscV,
+}
+
+pub struct Section {
+    pub name: String,
+    pub address: u64,
+    offset: usize,
+    size: usize,
+    kind: u32,
+    flags: u64,
+}
+
+pub struct Symbol {
+    /// The address, without the Thumb bit on ARM.
+    pub address: u64,
+    pub size: u64,
+    pub name: String,
+    pub is_function: bool,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub is_64: bool,
+    pub machine: Machine,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+}
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8]> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| "truncated ELF file".to_string())
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        Ok(u16::from_le_bytes(
+            self.bytes(offset, 2)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(
+            self.bytes(offset, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64> {
+        Ok(u64::from_le_bytes(
+            self.bytes(offset, 8)?.try_into().unwrap(),
+        ))
+    }
+
+    /// A word: 32 bits in 32 bit files, 64 bits in 64 bit files.
+    fn word(&self, offset: usize) -> Result<u64> {
+        if self.is_64 {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+
+    fn string(&self, offset: usize) -> Result<String> {
+        let rest = self.data.get(offset..).ok_or("truncated ELF file")?;
+        let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>> {
+        if data.get(0..4) != Some(b"\x7fELF") {
+            return Err("not an ELF file".to_string());
+        }
+        let is_64 = match data.get(4) {
+            Some(1) => false,
+            Some(2) => true,
+            _ => return Err("unknown ELF class".to_string()),
+        };
+        if data.get(5) != Some(&1) {
+            return Err("big endian ELF files are not supported".to_string());
+        }
+        let elf = Reader { data, is_64 };
+        let machine = match elf.u16(18)? {
+            EM_ARM => Machine::Arm,
+            EM_RISCV => Machine::RiscV,
+            other => return Err(format!("unsupported ELF machine {}", other)),
+        };
+
+        let (shoff, shentsize, shnum, shstrndx) = if is_64 {
+            (
+                elf.u64(40)? as usize,
+                elf.u16(58)? as usize,
+                elf.u16(60)? as usize,
+                elf.u16(62)? as usize,
+            )
+        } else {
+            (
+                elf.u32(32)? as usize,
+                elf.u16(46)? as usize,
+                elf.u16(48)? as usize,
+                elf.u16(50)? as usize,
+            )
+        };
+
+        // Name offset, type, flags, address, offset, size and linked section
+        // of each section header.
+        let mut headers = Vec::new();
+        for index in 0..shnum {
+            let base = shoff + index * shentsize;
+            let header = if is_64 {
+                (
+                    elf.u32(base)?,
+                    elf.u32(base + 4)?,
+                    elf.u64(base + 8)?,
+                    elf.u64(base + 16)?,
+                    elf.u64(base + 24)? as usize,
+                    elf.u64(base + 32)? as usize,
+                    elf.u32(base + 40)? as usize,
+                )
+            } else {
+                (
+                    elf.u32(base)?,
+                    elf.u32(base + 4)?,
+                    elf.u32(base + 8)? as u64,
+                    elf.u32(base + 12)? as u64,
+                    elf.u32(base + 16)? as usize,
+                    elf.u32(base + 20)? as usize,
+                    elf.u32(base + 24)? as usize,
+                )
+            };
+            headers.push(header);
+        }
+        let names = headers
+            .get(shstrndx)
+            .map(|header| header.4)
+            .ok_or("missing section name table")?;
+
+        let mut sections = Vec::new();
+        let mut symbols = Vec::new();
+        for &(name, kind, flags, address, offset, size, link) in &headers {
+            sections.push(Section {
+                name: elf.string(names + name as usize)?,
+                address,
+                offset,
+                size,
+                kind,
+                flags,
+            });
+            if kind != SHT_SYMTAB {
+                continue;
+            }
+            let strtab = headers.get(link).ok_or("missing string table")?.4;
+            let entsize = if is_64 { 24 } else { 16 };
+            for symbol in (offset..offset + size).step_by(entsize) {
+                let (info, value, size) = if is_64 {
+                    (
+                        elf.u8(symbol + 4)?,
+                        elf.u64(symbol + 8)?,
+                        elf.u64(symbol + 16)?,
+                    )
+                } else {
+                    (
+                        elf.u8(symbol + 12)?,
+                        elf.word(symbol + 4)?,
+                        elf.word(symbol + 8)?,
+                    )
+                };
+                let is_function = info & 0xF == STT_FUNC;
+                symbols.push(Symbol {
+                    address: if is_function && machine == Machine::Arm {
+                        value & !1
+                    } else {
+                        value
+                    },
+                    size,
+                    name: demangle(&elf.string(strtab + elf.u32(symbol)? as usize)?),
+                    is_function,
+                });
+            }
+        }
+
+        Ok(Elf {
+            data,
+            is_64,
+            machine,
+            sections,
+            symbols,
+        })
+    }
+
+    /// The contents of `section`.
+    pub fn section_data(&self, section: &Section) -> Result<&'a [u8]> {
+        if section.kind == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        section
+            .offset
+            .checked_add(section.size)
+            .and_then(|end| self.data.get(section.offset..end))
+            .ok_or_else(|| format!("section {} is truncated", section.name))
+    }
+
+    /// The `len` bytes of the loaded image at `address`, if they are in one
+    /// section.
+    pub fn read(&self, address: u64, len: u64) -> Option<&'a [u8]> {
+        self.sections
+            .iter()
+            .filter(|section| section.flags & SHF_ALLOC != 0 && section.kind != SHT_NOBITS)
+            .find(|section| {
+                address >= section.address && address + len <= section.address + section.size as u64
+            })
+            .and_then(|section| {
+                let start = section.offset + (address - section.address) as usize;
+                self.data.get(start..start + len as usize)
+            })
+    }
+}
+
+/// Demangle a legacy Rust symbol (`_ZN...E`), dropping the hash. Other names
+/// are returned unchanged.
+pub fn demangle(name: &str) -> String {
+    let mut rest = match name.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return name.to_string(),
+    };
+    let mut path: Vec<String> = Vec::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()) {
+        if digits == 0 {
+            break;
+        }
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) => len,
+            Err(_) => return name.to_string(),
+        };
+        let segment = match rest.get(digits..digits + len) {
+            Some(segment) => segment,
+            None => return name.to_string(),
+        };
+        path.push(unescape(segment));
+        rest = &rest[digits + len..];
+    }
+    if !rest.starts_with('E') || path.is_empty() {
+        return name.to_string();
+    }
+    let is_hash = |segment: &String| {
+        segment.len() == 17
+            && segment.starts_with('h')
+            && segment[1..].chars().all(|c| c.is_ascii_hexdigit())
+    };
+    if path.last().map_or(false, is_hash) {
+        path.pop();
+    }
+    path.join("::")
+}
+
+fn unescape(segment: &str) -> String {
+    const ESCAPES: &[(&str, &str)] = &[
+        ("$SP$", "@"),
+        ("$BP$", "*"),
+        ("$RF$", "&"),
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+    ];
+    let mut segment = segment
+        .strip_prefix("_$")
+        .map_or(segment.to_string(), |s| format!("${}", s));
+    for (escape, replacement) in ESCAPES {
+        segment = segment.replace(escape, replacement);
+    }
+    segment.replace("..", "::")
+}
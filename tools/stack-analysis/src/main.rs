@@ -0,0 +1,575 @@
+//! Worst-case kernel stack usage, from the call graph of a kernel ELF.
+//!
+//! The kernel must be built with `-Z emit-stack-sizes`, which records the
+//! stack frame of every function in `.stack_sizes` sections. The call graph
+//! comes from disassembling the code of each function, and the stack depth
+//! of an entry point is the deepest path through it. Entry points are the
+//! interrupt and exception handlers and each `Driver::command`, which the
+//! kernel only calls through a trait object.
+//!
+//! Recursion and indirect calls cannot be bounded this way. Entry points
+//! that reach them are reported as lower bounds, and the cycles and call
+//! sites are listed so they can be checked by hand.
+
+mod disasm;
+mod elf;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::process;
+
+use disasm::Op;
+use elf::{Elf, Machine};
+
+const USAGE: &str = "\
+usage: stack-analysis [--paths] [--function <name>]... <kernel.elf>
+
+Reports the worst-case stack depth of each interrupt handler and each
+Driver::command of a kernel built with -Z emit-stack-sizes. Depths marked
+with + are lower bounds, because of recursion or indirect calls.
+
+  --paths            print the deepest call chain under each entry point
+  --function <name>  also report the function <name>";
+
+type Result<T> = std::result::Result<T, String>;
+
+/// The suffix of the demangled name of a `Driver::command` implementation.
+const DRIVER_COMMAND: &str = " as kernel::driver::Driver>::command";
+
+/// The RISC-V trap handlers, which are the only interrupt entry points.
+const RISCV_TRAP_HANDLERS: &[&str] = &["_start_trap", "_start_trap_vectored"];
+
+struct Function {
+    name: String,
+    address: u64,
+    /// The stack frame from `.stack_sizes`, if the function has one.
+    frame: Option<u64>,
+    /// The stack frame from the instructions that lower the stack pointer.
+    estimated_frame: u64,
+    callees: BTreeSet<usize>,
+    /// Addresses of the indirect calls, and of direct calls to addresses
+    /// that are not in any function.
+    indirect: Vec<u64>,
+}
+
+impl Function {
+    fn frame(&self) -> u64 {
+        self.frame.unwrap_or(self.estimated_frame)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Depth {
+    bytes: u64,
+    /// Whether recursion or an indirect call was reached, so `bytes` is only
+    /// a lower bound.
+    unbounded: bool,
+    /// The callee on the deepest path.
+    next: Option<usize>,
+}
+
+enum State {
+    Unvisited,
+    InProgress,
+    Done(Depth),
+}
+
+struct Analysis<'a> {
+    functions: &'a [Function],
+    states: Vec<State>,
+    stack: Vec<usize>,
+    cycles: BTreeSet<Vec<usize>>,
+    reached: BTreeSet<usize>,
+}
+
+impl<'a> Analysis<'a> {
+    fn new(functions: &'a [Function]) -> Analysis<'a> {
+        Analysis {
+            functions,
+            states: functions.iter().map(|_| State::Unvisited).collect(),
+            stack: Vec::new(),
+            cycles: BTreeSet::new(),
+            reached: BTreeSet::new(),
+        }
+    }
+
+    fn depth(&mut self, index: usize) -> Depth {
+        self.reached.insert(index);
+        let function = &self.functions[index];
+        self.states[index] = State::InProgress;
+        self.stack.push(index);
+        let mut deepest = Depth {
+            bytes: 0,
+            unbounded: !function.indirect.is_empty(),
+            next: None,
+        };
+        for &callee in &function.callees {
+            let depth = match self.states[callee] {
+                State::Done(depth) => depth,
+                State::Unvisited => self.depth(callee),
+                State::InProgress => {
+                    let start = self.stack.iter().position(|&i| i == callee).unwrap();
+                    let mut cycle = self.stack[start..].to_vec();
+                    // Record each cycle once, whichever function it was
+                    // entered from.
+                    let first = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap();
+                    cycle.rotate_left(first);
+                    self.cycles.insert(cycle);
+                    deepest.unbounded = true;
+                    continue;
+                }
+            };
+            deepest.unbounded |= depth.unbounded;
+            if deepest.next.is_none() || depth.bytes > deepest.bytes {
+                deepest.bytes = depth.bytes;
+                deepest.next = Some(callee);
+            }
+        }
+        deepest.bytes += function.frame();
+        self.stack.pop();
+        self.states[index] = State::Done(deepest);
+        deepest
+    }
+}
+
+/// Read the `(address, frame)` entries of the `.stack_sizes` sections, with
+/// the Thumb bit cleared from the addresses.
+fn stack_sizes(elf: &Elf) -> Result<BTreeMap<u64, u64>> {
+    let mut sizes = BTreeMap::new();
+    let word = if elf.is_64 { 8 } else { 4 };
+    for section in elf.sections.iter().filter(|s| s.name == ".stack_sizes") {
+        let data = elf.section_data(section)?;
+        let mut i = 0;
+        while i + word <= data.len() {
+            let mut bytes = [0; 8];
+            bytes[..word].copy_from_slice(&data[i..i + word]);
+            let mut address = u64::from_le_bytes(bytes);
+            i += word;
+            let mut size = 0;
+            let mut shift = 0;
+            loop {
+                let byte = *data.get(i).ok_or("truncated .stack_sizes section")?;
+                i += 1;
+                size |= ((byte & 0x7F) as u64) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            if elf.machine == Machine::Arm {
+                address &= !1;
+            }
+            sizes.insert(address, size);
+        }
+    }
+    if sizes.is_empty() {
+        return Err("no .stack_sizes section, build the kernel with -Z emit-stack-sizes".into());
+    }
+    Ok(sizes)
+}
+
+/// Find the functions, decode their code and build the call graph.
+fn functions(elf: &Elf) -> Result<Vec<Function>> {
+    let sizes = stack_sizes(elf)?;
+
+    // Functions by address. Aliases share the first name seen.
+    let mut symbols: BTreeMap<u64, (&str, u64)> = BTreeMap::new();
+    for symbol in elf
+        .symbols
+        .iter()
+        .filter(|s| s.is_function && s.address != 0)
+    {
+        let entry = symbols.entry(symbol.address).or_insert((&symbol.name, 0));
+        entry.1 = entry.1.max(symbol.size);
+    }
+    // ARM mapping symbols, which mark data such as literal pools in code.
+    let data: BTreeMap<u64, bool> = elf
+        .symbols
+        .iter()
+        .filter(|s| !s.is_function && s.name.starts_with('$'))
+        .filter_map(|s| match s.name.get(..2) {
+            Some("$d") => Some((s.address, true)),
+            Some("$t") | Some("$a") | Some("$x") => Some((s.address, false)),
+            _ => None,
+        })
+        .collect();
+
+    let addresses: Vec<u64> = symbols.keys().copied().collect();
+    let mut functions = Vec::new();
+    let mut code = Vec::new();
+    for (index, (&address, &(name, size))) in symbols.iter().enumerate() {
+        // Functions written in assembly may have no size, so they are
+        // assumed to run up to the next function.
+        let size = match (size, addresses.get(index + 1)) {
+            (0, Some(next)) => next - address,
+            (size, _) => size,
+        };
+        let mut ops = Vec::new();
+        let mut start = address;
+        while start < address + size {
+            let end = data
+                .range(start + 1..address + size)
+                .find(|(_, &is_data)| is_data)
+                .map_or(address + size, |(&end, _)| end);
+            let bytes = elf.read(start, end - start).unwrap_or(&[]);
+            ops.extend(match elf.machine {
+                Machine::Arm => disasm::thumb(bytes, start),
+                Machine::RiscV => disasm::riscv(bytes, start, elf.is_64),
+            });
+            start = data
+                .range(end..address + size)
+                .find(|(_, &is_data)| !is_data)
+                .map_or(address + size, |(&start, _)| start);
+        }
+        functions.push(Function {
+            name: name.to_string(),
+            address,
+            frame: sizes.get(&address).copied(),
+            estimated_frame: 0,
+            callees: BTreeSet::new(),
+            indirect: Vec::new(),
+        });
+        code.push((size, ops));
+    }
+
+    let containing = |target: u64| match addresses.binary_search(&target) {
+        Ok(index) => Some(index),
+        Err(0) => None,
+        Err(index) if target < addresses[index - 1] + code[index - 1].0 => Some(index - 1),
+        Err(_) => None,
+    };
+    for (index, (size, ops)) in code.iter().enumerate() {
+        let function = &functions[index];
+        let within = |target: u64| target >= function.address && target < function.address + size;
+        let mut callees = BTreeSet::new();
+        let mut indirect = Vec::new();
+        let mut estimated_frame = 0;
+        for &(site, op) in ops {
+            let target = match op {
+                Op::Call(target) => target,
+                // Jumps out of the function are tail calls. Treating them
+                // as calls counts the frame of this function twice at
+                // worst.
+                Op::Jump(target) if !within(target) => target,
+                Op::Jump(_) => continue,
+                Op::Indirect => {
+                    indirect.push(site);
+                    continue;
+                }
+                Op::StackGrow(bytes) => {
+                    estimated_frame += bytes;
+                    continue;
+                }
+            };
+            match containing(target) {
+                Some(callee) => {
+                    callees.insert(callee);
+                }
+                None => indirect.push(site),
+            }
+        }
+        let function = &mut functions[index];
+        function.callees = callees;
+        function.indirect = indirect;
+        function.estimated_frame = estimated_frame;
+    }
+    Ok(functions)
+}
+
+/// The handlers in the ARM vector table at the start of the text, skipping
+/// the initial stack pointer.
+fn vector_table(elf: &Elf, functions: &BTreeMap<u64, usize>) -> Vec<usize> {
+    let text = match elf.sections.iter().find(|s| s.name == ".text") {
+        Some(text) => text.address,
+        None => return Vec::new(),
+    };
+    let mut handlers = Vec::new();
+    let mut address = text + 4;
+    while let Some(bytes) = elf.read(address, 4) {
+        let vector = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64;
+        address += 4;
+        if vector == 0 {
+            // A reserved entry.
+            continue;
+        }
+        match functions.get(&(vector & !1)) {
+            Some(&index) if vector & 1 == 1 => {
+                if !handlers.contains(&index) {
+                    handlers.push(index);
+                }
+            }
+            _ => break,
+        }
+    }
+    handlers
+}
+
+fn format_depth(depth: &Depth) -> String {
+    format!("{}{}", depth.bytes, if depth.unbounded { "+" } else { "" })
+}
+
+fn report(
+    title: &str,
+    roots: &[usize],
+    analysis: &mut Analysis,
+    functions: &[Function],
+    paths: bool,
+) {
+    println!("{}:", title);
+    if roots.is_empty() {
+        println!("  (none)");
+    }
+    let mut depths: Vec<(usize, Depth)> = roots
+        .iter()
+        .map(|&root| (root, analysis.depth(root)))
+        .collect();
+    depths.sort_by_key(|&(root, depth)| (std::cmp::Reverse(depth.bytes), root));
+    for (root, depth) in depths {
+        println!("  {:>8}  {}", format_depth(&depth), functions[root].name);
+        if !paths {
+            continue;
+        }
+        let mut next = depth.next;
+        while let Some(index) = next {
+            let function = &functions[index];
+            println!("  {:>8}    {}", function.frame(), function.name);
+            next = match analysis.states[index] {
+                State::Done(depth) => depth.next,
+                _ => None,
+            };
+        }
+    }
+    println!();
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let mut paths = false;
+    let mut extra = Vec::new();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--paths" => paths = true,
+            "--function" => extra.push(args.next().ok_or(USAGE)?.as_str()),
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let path = path.ok_or(USAGE)?;
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let elf = Elf::parse(&data).map_err(|e| format!("{}: {}", path, e))?;
+    let functions = functions(&elf).map_err(|e| format!("{}: {}", path, e))?;
+    let by_address: BTreeMap<u64, usize> = functions
+        .iter()
+        .enumerate()
+        .map(|(index, function)| (function.address, index))
+        .collect();
+    let by_name = |name: &str| functions.iter().position(|f| f.name == name);
+
+    let handlers = match elf.machine {
+        Machine::Arm => vector_table(&elf, &by_address),
+        Machine::RiscV => RISCV_TRAP_HANDLERS
+            .iter()
+            .filter_map(|name| by_name(name))
+            .collect(),
+    };
+    let commands: Vec<usize> = (0..functions.len())
+        .filter(|&index| functions[index].name.ends_with(DRIVER_COMMAND))
+        .collect();
+    let mut requested = Vec::new();
+    for name in extra {
+        requested.push(by_name(name).ok_or(format!("no function named {}", name))?);
+    }
+
+    let mut analysis = Analysis::new(&functions);
+    report(
+        "Interrupt and exception handlers",
+        &handlers,
+        &mut analysis,
+        &functions,
+        paths,
+    );
+    report(
+        "Driver::command entry points",
+        &commands,
+        &mut analysis,
+        &functions,
+        paths,
+    );
+    if !requested.is_empty() {
+        report("Functions", &requested, &mut analysis, &functions, paths);
+    }
+
+    if !analysis.cycles.is_empty() {
+        println!("Recursion:");
+        for cycle in &analysis.cycles {
+            for &index in cycle {
+                println!("  {}", functions[index].name);
+            }
+            println!("    -> {}", functions[cycle[0]].name);
+        }
+        println!();
+    }
+    let indirect: Vec<&Function> = analysis
+        .reached
+        .iter()
+        .map(|&index| &functions[index])
+        .filter(|function| !function.indirect.is_empty())
+        .collect();
+    if !indirect.is_empty() {
+        println!("Indirect calls:");
+        for function in indirect {
+            let sites: Vec<String> = function
+                .indirect
+                .iter()
+                .map(|site| format!("{:#x}", site))
+                .collect();
+            println!("  {} at {}", function.name, sites.join(", "));
+        }
+        println!();
+    }
+    let estimated: Vec<&Function> = analysis
+        .reached
+        .iter()
+        .map(|&index| &functions[index])
+        .filter(|function| function.frame.is_none())
+        .collect();
+    if !estimated.is_empty() {
+        println!("Frames estimated from the code, without .stack_sizes entries:");
+        for function in estimated {
+            println!("  {:>8}  {}", function.estimated_frame, function.name);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EM_RISCV: u16 = 243;
+    const NOP: u32 = 0x0000_0013;
+    const RET: u32 = 0x0000_8067;
+    const TEXT: u64 = 0x1000;
+
+    fn jal(rd: u32, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (imm >> 20 & 1) << 31
+            | (imm >> 1 & 0x3FF) << 21
+            | (imm >> 11 & 1) << 20
+            | (imm >> 12 & 0xFF) << 12
+            | rd << 7
+            | 0x6F
+    }
+
+    /// A 32 bit RISC-V ELF file with the 16 byte `functions` one after the
+    /// other from `TEXT`, and `.stack_sizes` entries for those with a frame.
+    fn elf(functions: &[(&str, [u32; 4], Option<u8>)]) -> Vec<u8> {
+        let mut text = Vec::new();
+        let mut stack_sizes = Vec::new();
+        let mut symtab = vec![0; 16];
+        let mut strtab = vec![0];
+        for (index, (name, code, frame)) in functions.iter().enumerate() {
+            let address = TEXT as u32 + 16 * index as u32;
+            for word in code {
+                text.extend(&word.to_le_bytes());
+            }
+            if let Some(frame) = frame {
+                stack_sizes.extend(&address.to_le_bytes());
+                stack_sizes.push(*frame);
+            }
+            symtab.extend(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend(&address.to_le_bytes());
+            symtab.extend(&16u32.to_le_bytes());
+            symtab.extend(&[2, 0, 1, 0]); // STT_FUNC, in .text
+            strtab.extend(name.as_bytes());
+            strtab.push(0);
+        }
+        let shstrtab = b"\0.text\0.stack_sizes\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+
+        let mut contents: Vec<u8> = Vec::new();
+        let mut headers: Vec<u8> = Vec::new();
+        let mut section =
+            |name: u32, kind: u32, flags: u32, address: u32, link: u32, data: &[u8]| {
+                let offset = 52 + contents.len() as u32;
+                contents.extend(data);
+                for field in &[name, kind, flags, address, offset, data.len() as u32, link] {
+                    headers.extend(&field.to_le_bytes());
+                }
+                headers.extend(&[0; 12]);
+            };
+        section(0, 0, 0, 0, 0, &[]);
+        section(1, 1, 0x6, TEXT as u32, 0, &text);
+        section(7, 1, 0, 0, 0, &stack_sizes);
+        section(20, 2, 0, 0, 4, &symtab);
+        section(28, 3, 0, 0, 0, &strtab);
+        section(36, 3, 0, 0, 0, &shstrtab);
+
+        let mut data = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        data.resize(16, 0);
+        data.extend(&2u16.to_le_bytes()); // e_type: executable
+        data.extend(&EM_RISCV.to_le_bytes());
+        data.extend(&1u32.to_le_bytes()); // e_version
+        data.extend(&[0; 8]); // e_entry, e_phoff
+        data.extend(&(52 + contents.len() as u32).to_le_bytes());
+        data.extend(&[0; 4]); // e_flags
+        data.extend(&52u16.to_le_bytes()); // e_ehsize
+        data.extend(&[0; 4]); // e_phentsize, e_phnum
+        data.extend(&40u16.to_le_bytes()); // e_shentsize
+        data.extend(&6u16.to_le_bytes()); // e_shnum
+        data.extend(&5u16.to_le_bytes()); // e_shstrndx
+        data.extend(contents);
+        data.extend(headers);
+        data
+    }
+
+    #[test]
+    fn call_graph_with_recursion_and_an_indirect_call() {
+        let data = elf(&[
+            // Calls a and dispatch.
+            ("handler", [jal(1, 0x10), jal(1, 0x2C), RET, NOP], Some(16)),
+            // a and b call each other.
+            ("a", [jal(1, 0x10), RET, NOP, NOP], Some(32)),
+            ("b", [jal(1, -0x10), RET, NOP, NOP], Some(8)),
+            // Calls through a5, then tail calls leaf.
+            ("dispatch", [0x000780E7, jal(0, 0xC), NOP, NOP], Some(24)),
+            // addi sp, sp, -32, with no .stack_sizes entry.
+            ("leaf", [0xFE010113, RET, NOP, NOP], None),
+        ]);
+        let elf = Elf::parse(&data).unwrap();
+        let functions = functions(&elf).unwrap();
+        let names: Vec<&str> = functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["handler", "a", "b", "dispatch", "leaf"]);
+        let callees: Vec<Vec<usize>> = functions
+            .iter()
+            .map(|f| f.callees.iter().copied().collect())
+            .collect();
+        assert_eq!(callees, [vec![1, 3], vec![2], vec![1], vec![4], vec![]]);
+        assert_eq!(functions[3].indirect, [0x1030]);
+        assert_eq!(functions[4].frame, None);
+        assert_eq!(functions[4].frame(), 32);
+
+        let mut analysis = Analysis::new(&functions);
+        let leaf = analysis.depth(4);
+        assert_eq!((leaf.bytes, leaf.unbounded), (32, false));
+        let handler = analysis.depth(0);
+        assert_eq!((handler.bytes, handler.unbounded), (16 + 24 + 32, true));
+        assert_eq!(handler.next, Some(3));
+        match analysis.states[1] {
+            State::Done(a) => assert_eq!((a.bytes, a.unbounded), (32 + 8, true)),
+            _ => panic!("a was not visited"),
+        }
+        assert_eq!(analysis.cycles.iter().collect::<Vec<_>>(), [&vec![1, 2]]);
+        assert_eq!(analysis.reached.len(), 5);
+    }
+}
@@ -0,0 +1,657 @@
+//! Inspect, validate, edit and concatenate Tock Binary Format (TBF) images.
+//!
+//! Every command accepts either a single TBF image or an app flash blob of
+//! several images back to back, as the kernel finds them in flash.
+
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::process;
+
+use tock_tbf::parse::{parse_tbf_header, parse_tbf_header_lengths};
+use tock_tbf::serialize::{serialize_tbf_header, serialize_tbf_padding, tbf_header_size};
+use tock_tbf::types::{InitialTbfParseError, TbfHeader, TbfHeaderV2, TbfHeaderV2Main};
+
+const USAGE: &str = "\
+usage: tbf-tool dump <file>...
+       tbf-tool validate <file>...
+       tbf-tool edit <in> <out> [--name <name>] [--min-ram <bytes>]
+                     [--fixed-ram <addr>|none] [--fixed-flash <addr>|none]
+                     [--enable|--disable]
+       tbf-tool concat <out> [--align <bytes>] <file>...
+
+Numbers may be decimal or hexadecimal with a 0x prefix.";
+
+/// Size of the base header, and so of a padding header.
+const BASE_HEADER_SIZE: usize = 16;
+
+/// Writeable flash regions the parser keeps. Headers with more cannot be
+/// edited without losing some.
+const MAX_WRITEABLE_FLASH_REGIONS: usize = 4;
+
+type Result<T> = std::result::Result<T, String>;
+
+/// One TBF image found in a file.
+struct Image {
+    /// Offset of the image in the file.
+    offset: usize,
+    /// The whole image, header included.
+    data: &'static [u8],
+    header_size: usize,
+    header: Result<TbfHeader>,
+}
+
+/// Read a file. It is leaked because the TBF parser needs `'static` data.
+fn load(path: &str) -> Result<&'static [u8]> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Box::leak(data.into_boxed_slice()))
+}
+
+/// Split `data` into the TBF images it holds. The images end at the first
+/// bytes that are not a TBF header, as they do for the kernel. Returns the
+/// images and the offset where they end.
+fn images(data: &'static [u8]) -> (Vec<Image>, usize) {
+    let mut images = Vec::new();
+    let mut offset = 0;
+    while let Some(lengths) = data.get(offset..offset + 8) {
+        let (version, header_size, total_size) =
+            match parse_tbf_header_lengths(lengths.try_into().unwrap()) {
+                Ok(lengths) => lengths,
+                Err(InitialTbfParseError::InvalidHeader(total_size)) => {
+                    (0, 0, total_size.max(BASE_HEADER_SIZE as u32))
+                }
+                Err(InitialTbfParseError::UnableToParse) => break,
+            };
+        let image = match data.get(offset..offset + total_size as usize) {
+            Some(image) => image,
+            None => break,
+        };
+        let header = if version == 0 {
+            Err("header size does not fit the image".to_string())
+        } else {
+            parse_tbf_header(&image[..header_size as usize], version)
+                .map_err(|e| format!("{:?}", e))
+        };
+        images.push(Image {
+            offset,
+            data: image,
+            header_size: header_size as usize,
+            header,
+        });
+        offset += total_size as usize;
+    }
+    (images, offset)
+}
+
+/// The type and value of each TLV entry in a header, including the ones the
+/// parser does not understand.
+fn tlvs(header: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut tlvs = Vec::new();
+    let mut rest = header.get(BASE_HEADER_SIZE..).unwrap_or(&[]);
+    while rest.len() >= 4 {
+        let tipe = u16::from_le_bytes([rest[0], rest[1]]);
+        let length = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let value = &rest[4..rest.len().min(4 + length)];
+        tlvs.push((tipe, value));
+        rest = rest.get(4 + (length + 3) / 4 * 4..).unwrap_or(&[]);
+    }
+    tlvs
+}
+
+fn tlv_name(tipe: u16) -> &'static str {
+    match tipe {
+        1 => "main",
+        2 => "writeable flash regions",
+        3 => "package name",
+        5 => "fixed addresses",
+        6 => "permissions",
+        7 => "persistent storage",
+        _ => "unknown",
+    }
+}
+
+fn is_known_tlv(tipe: u16) -> bool {
+    tlv_name(tipe) != "unknown"
+}
+
+fn number_writeable_flash_regions(header: &[u8]) -> usize {
+    tlvs(header)
+        .iter()
+        .filter(|(tipe, _)| *tipe == 2)
+        .map(|(_, value)| value.len() / 8)
+        .sum()
+}
+
+fn parse_number(value: &str) -> Result<u32> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number: {}", value))
+}
+
+fn parse_address(value: &str) -> Result<Option<u32>> {
+    match value {
+        "none" => Ok(None),
+        _ => parse_number(value).map(Some),
+    }
+}
+
+fn dump(paths: &[String]) -> Result<()> {
+    for path in paths {
+        let data = load(path)?;
+        let (images, end) = images(data);
+        println!("{}: {} image(s)", path, images.len());
+        for image in &images {
+            dump_image(image);
+        }
+        if end < data.len() {
+            println!(
+                "{:#x}: {} bytes after the last image",
+                end,
+                data.len() - end
+            );
+        }
+    }
+    Ok(())
+}
+
+fn dump_image(image: &Image) {
+    let header = match &image.header {
+        Ok(header) => header,
+        Err(e) => {
+            println!("{:#x}: invalid header: {}", image.offset, e);
+            return;
+        }
+    };
+    let kind = match header {
+        TbfHeader::TbfHeaderV2(_) => "app",
+        TbfHeader::Padding(_) => "padding",
+    };
+    println!(
+        "{:#x}: {} of {:#x} bytes, header {:#x} bytes",
+        image.offset,
+        kind,
+        image.data.len(),
+        image.header_size
+    );
+    if !header.is_app() {
+        return;
+    }
+
+    println!(
+        "  package name:        {}",
+        header.get_package_name().unwrap_or("")
+    );
+    println!("  enabled:             {}", header.enabled());
+    println!(
+        "  minimum RAM size:    {:#x}",
+        header.get_minimum_app_ram_size()
+    );
+    println!("  protected size:      {:#x}", header.get_protected_size());
+    println!(
+        "  init function:       {:#x}",
+        header.get_init_function_offset()
+    );
+    for i in 0..header.number_writeable_flash_regions() {
+        let (offset, size) = header.get_writeable_flash_region(i);
+        println!("  writeable flash:     {:#x} + {:#x}", offset, size);
+    }
+    if let Some(ram) = header.get_fixed_address_ram() {
+        println!("  fixed RAM address:   {:#x}", ram);
+    }
+    if let Some(flash) = header.get_fixed_address_flash() {
+        println!("  fixed flash address: {:#x}", flash);
+    }
+    if let Some((id, size)) = header.get_persistent_storage_request() {
+        println!("  persistent storage:  id {:#x}, {:#x} bytes", id, size);
+    }
+    if let Some(permissions) = header.get_permissions() {
+        for permission in permissions.iter() {
+            println!(
+                "  permission:          driver {:#x}, commands {}+: {:#018x}",
+                permission.driver_number,
+                permission.offset * 64,
+                permission.allowed_commands
+            );
+        }
+    }
+    for (tipe, value) in tlvs(&image.data[..image.header_size]) {
+        println!("  TLV {}: {} ({} bytes)", tipe, tlv_name(tipe), value.len());
+    }
+}
+
+/// The problems with `image` that would stop the kernel from loading it.
+/// Padding has none once its header parses.
+fn check_image(image: &Image) -> Vec<String> {
+    let header = match &image.header {
+        Ok(header) => header,
+        Err(e) => return vec![format!("invalid header: {}", e)],
+    };
+    if !header.is_app() {
+        return Vec::new();
+    }
+
+    let mut problems = Vec::new();
+    let total_size = image.data.len() as u32;
+    let protected_size = header.get_protected_size();
+    if protected_size > total_size {
+        problems.push(format!(
+            "protected region of {:#x} bytes is larger than the image",
+            protected_size
+        ));
+    }
+    let init_fn_offset = header.get_init_function_offset();
+    if header.enabled() && !(protected_size..total_size).contains(&init_fn_offset) {
+        problems.push(format!(
+            "init function at {:#x} is not in the app binary",
+            init_fn_offset
+        ));
+    }
+    for i in 0..header.number_writeable_flash_regions() {
+        let (offset, size) = header.get_writeable_flash_region(i);
+        if offset
+            .checked_add(size)
+            .map_or(true, |end| end > total_size)
+        {
+            problems.push(format!(
+                "writeable flash region {:#x} + {:#x} is outside the image",
+                offset, size
+            ));
+        }
+    }
+    let regions = number_writeable_flash_regions(&image.data[..image.header_size]);
+    if regions > MAX_WRITEABLE_FLASH_REGIONS {
+        problems.push(format!(
+            "{} writeable flash regions, only {} are used",
+            regions, MAX_WRITEABLE_FLASH_REGIONS
+        ));
+    }
+    problems
+}
+
+fn validate(paths: &[String]) -> Result<()> {
+    let mut valid = true;
+    for path in paths {
+        let data = load(path)?;
+        let (images, end) = images(data);
+        if images.is_empty() {
+            println!("{}: no TBF images", path);
+            valid = false;
+        }
+        for image in &images {
+            let problems = check_image(image);
+            valid &= problems.is_empty();
+            for problem in problems {
+                println!("{}: {:#x}: {}", path, image.offset, problem);
+            }
+        }
+        // The kernel stops at the first header it cannot parse, so apps after
+        // a truncated image or other trailing data are never found.
+        if data[end..].iter().any(|&b| b != 0 && b != 0xFF) {
+            println!("{}: {:#x}: data after the last image", path, end);
+            valid = false;
+        }
+    }
+    if valid {
+        Ok(())
+    } else {
+        Err("validation failed".to_string())
+    }
+}
+
+fn edit(args: &[String]) -> Result<()> {
+    let (input, output) = match args {
+        [input, output, ..] => (input, output),
+        _ => return Err(USAGE.to_string()),
+    };
+    let data = load(input)?;
+    let (images, end) = images(data);
+    let image = match (&images[..], end == data.len()) {
+        ([image], true) => image,
+        _ => return Err(format!("{}: not a single TBF image", input)),
+    };
+    let mut header: TbfHeaderV2 = match &image.header {
+        Ok(TbfHeader::TbfHeaderV2(header)) => *header,
+        Ok(TbfHeader::Padding(_)) => return Err(format!("{}: image is padding", input)),
+        Err(e) => return Err(format!("{}: invalid header: {}", input, e)),
+    };
+
+    // Rewriting the header keeps only what the parser understood.
+    let old_header = &image.data[..image.header_size];
+    if let Some((tipe, _)) = tlvs(old_header)
+        .into_iter()
+        .find(|(tipe, _)| !is_known_tlv(*tipe))
+    {
+        return Err(format!("{}: cannot rewrite unknown TLV {}", input, tipe));
+    }
+    if number_writeable_flash_regions(old_header) > MAX_WRITEABLE_FLASH_REGIONS {
+        return Err(format!(
+            "{}: cannot rewrite more than {} writeable flash regions",
+            input, MAX_WRITEABLE_FLASH_REGIONS
+        ));
+    }
+
+    let tbf_header = TbfHeader::TbfHeaderV2(header);
+    let mut fixed_ram = tbf_header.get_fixed_address_ram();
+    let mut fixed_flash = tbf_header.get_fixed_address_flash();
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || {
+            options
+                .next()
+                .ok_or_else(|| format!("{} needs a value", option))
+        };
+        match option.as_str() {
+            "--name" => {
+                let name = value()?.clone();
+                header.set_package_name(Some(Box::leak(name.into_boxed_str())));
+            }
+            "--min-ram" => {
+                let minimum_ram_size = parse_number(value()?)?;
+                let main = header
+                    .main()
+                    .ok_or_else(|| format!("{}: header has no main TLV", input))?;
+                header.set_main(Some(TbfHeaderV2Main::new(
+                    main.init_fn_offset(),
+                    main.protected_size(),
+                    minimum_ram_size,
+                )));
+            }
+            "--fixed-ram" => fixed_ram = parse_address(value()?)?,
+            "--fixed-flash" => fixed_flash = parse_address(value()?)?,
+            "--enable" => header.set_enabled(true),
+            "--disable" => header.set_enabled(false),
+            _ => return Err(format!("unknown option {}\n{}", option, USAGE)),
+        }
+    }
+    header.set_fixed_addresses(fixed_ram, fixed_flash);
+
+    // The app binary has to stay where it is, so a header that changes size
+    // takes the difference from, or gives it to, the protected region.
+    let new_header_size = tbf_header_size(&header).map_err(|e| format!("{:?}", e))?;
+    if new_header_size != image.header_size {
+        let main = header
+            .main()
+            .ok_or_else(|| format!("{}: header has no main TLV", input))?;
+        let grow = new_header_size as i64 - image.header_size as i64;
+        let protected_size = main.protected_size() as i64 - grow;
+        let init_fn_offset = main.init_fn_offset() as i64 - grow;
+        if protected_size < 0 || init_fn_offset < 0 {
+            return Err(format!(
+                "{}: header grows by {} bytes, but only {} bytes of protected region are free",
+                input,
+                grow,
+                main.protected_size()
+            ));
+        }
+        header.set_main(Some(TbfHeaderV2Main::new(
+            init_fn_offset as u32,
+            protected_size as u32,
+            main.minimum_ram_size(),
+        )));
+    }
+
+    let mut out = image.data.to_vec();
+    for byte in &mut out[..image.header_size.max(new_header_size)] {
+        *byte = 0;
+    }
+    serialize_tbf_header(&header, &mut out).map_err(|e| format!("{:?}", e))?;
+    fs::write(output, out).map_err(|e| format!("{}: {}", output, e))
+}
+
+fn concat(args: &[String]) -> Result<()> {
+    let output = args.first().ok_or_else(|| USAGE.to_string())?;
+    let mut align = 1;
+    let mut inputs = Vec::new();
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--align" => {
+                let value = args.next().ok_or("--align needs a value")?;
+                align = parse_number(value)? as usize;
+                if !align.is_power_of_two() || align < 4 {
+                    return Err("alignment must be a power of two of at least 4".to_string());
+                }
+            }
+            _ => inputs.push(arg),
+        }
+    }
+
+    let mut blob = Vec::new();
+    for path in inputs {
+        let data = load(path)?;
+        let (images, end) = images(data);
+        if images.is_empty() || data[end..].iter().any(|&b| b != 0 && b != 0xFF) {
+            return Err(format!("{}: not a TBF image or app flash blob", path));
+        }
+        // Padding in the inputs is dropped, the blob is laid out again.
+        for image in images {
+            match image.header {
+                Ok(TbfHeader::TbfHeaderV2(_)) => {}
+                Ok(TbfHeader::Padding(_)) => continue,
+                Err(e) => return Err(format!("{}: {:#x}: {}", path, image.offset, e)),
+            }
+            let mut gap = (align - blob.len() % align) % align;
+            if gap > 0 && gap < BASE_HEADER_SIZE {
+                gap += align;
+            }
+            if gap > 0 {
+                let start = blob.len();
+                blob.resize(start + gap, 0);
+                serialize_tbf_padding(gap as u32, &mut blob[start..])
+                    .map_err(|e| format!("{:?}", e))?;
+            }
+            blob.extend_from_slice(image.data);
+        }
+    }
+    fs::write(output, blob).map_err(|e| format!("{}: {}", output, e))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.split_first() {
+        Some((command, rest)) if !rest.is_empty() => match command.as_str() {
+            "dump" => dump(rest),
+            "validate" => validate(rest),
+            "edit" => edit(rest),
+            "concat" => concat(rest),
+            _ => Err(USAGE.to_string()),
+        },
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("tbf-tool: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes of app binary after the header of each test image.
+    const BINARY_SIZE: usize = 100;
+
+    /// A TBF image named `name`, with `protected_size` bytes between the
+    /// header and an app binary that counts up from `first_byte`. The app
+    /// starts executing at the start of the binary.
+    fn app_image(name: &'static str, protected_size: u32, first_byte: u8) -> Vec<u8> {
+        let mut header = TbfHeaderV2::new(0, true);
+        header.set_package_name(Some(name));
+        header.set_main(Some(TbfHeaderV2Main::new(
+            protected_size,
+            protected_size,
+            0x1000,
+        )));
+        let binary_start = tbf_header_size(&header).unwrap() as u32 + protected_size;
+        header.set_total_size(binary_start + BINARY_SIZE as u32);
+
+        let mut image = vec![0; binary_start as usize];
+        serialize_tbf_header(&header, &mut image).unwrap();
+        image.extend((0..BINARY_SIZE).map(|i| first_byte.wrapping_add(i as u8)));
+        image
+    }
+
+    /// A path for a test file that no other test uses.
+    fn temp_file(name: &str) -> String {
+        let mut path = env::temp_dir();
+        path.push(format!("tbf-tool-{}-{}", process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn write(name: &str, data: &[u8]) -> String {
+        let path = temp_file(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// The checksum of a header: the XOR of its words, with the checksum
+    /// itself taken as zero.
+    fn checksum(header: &[u8]) -> u32 {
+        header
+            .chunks(4)
+            .enumerate()
+            .filter(|(i, _)| *i != 3)
+            .fold(0, |sum, (_, word)| {
+                sum ^ u32::from_le_bytes(word.try_into().unwrap())
+            })
+    }
+
+    #[test]
+    fn edit_keeps_the_binary_in_place() {
+        let original = app_image("blink", 64, 0x40);
+        let input = write("edit-in.tbf", &original);
+        let output = temp_file("edit-out.tbf");
+        edit(&args(&[
+            &input,
+            &output,
+            "--name",
+            "a much longer package name",
+            "--min-ram",
+            "0x2000",
+            "--fixed-flash",
+            "0x40030000",
+            "--disable",
+        ]))
+        .unwrap();
+
+        let edited = load(&output).unwrap();
+        let (images, end) = images(edited);
+        assert_eq!((images.len(), end), (1, original.len()));
+        let header = images[0].header.as_ref().unwrap();
+        assert_eq!(
+            header.get_package_name(),
+            Some("a much longer package name")
+        );
+        assert_eq!(header.get_minimum_app_ram_size(), 0x2000);
+        assert_eq!(header.get_fixed_address_flash(), Some(0x40030000));
+        assert_eq!(header.get_fixed_address_ram(), None);
+        assert!(!header.enabled());
+
+        // The header grew into the protected region, and the binary and the
+        // init function did not move.
+        let binary_start = original.len() - BINARY_SIZE;
+        assert!(images[0].header_size > binary_start - 64);
+        assert_eq!(header.get_protected_size() as usize, binary_start);
+        assert_eq!(header.get_init_function_offset() as usize, binary_start);
+        assert_eq!(&edited[binary_start..], &original[binary_start..]);
+    }
+
+    #[test]
+    fn edit_recomputes_the_checksum() {
+        let input = write("checksum-in.tbf", &app_image("blink", 64, 0));
+        let output = temp_file("checksum-out.tbf");
+        edit(&args(&[&input, &output, "--min-ram", "0x3000"])).unwrap();
+
+        let edited = fs::read(&output).unwrap();
+        let header_size = u16::from_le_bytes([edited[2], edited[3]]) as usize;
+        let stored = u32::from_le_bytes(edited[12..16].try_into().unwrap());
+        assert_eq!(stored, checksum(&edited[..header_size]));
+        assert!(validate(&args(&[&output])).is_ok());
+
+        // A header changed without updating the checksum is rejected.
+        let mut corrupted = edited;
+        corrupted[header_size - 1] ^= 1;
+        let corrupted = write("checksum-bad.tbf", &corrupted);
+        assert!(validate(&args(&[&corrupted])).is_err());
+        assert!(edit(&args(&[&corrupted, &output, "--enable"])).is_err());
+    }
+
+    #[test]
+    fn concat_pads_to_the_alignment() {
+        let first = app_image("first", 0, 1);
+        let second = app_image("second", 0, 2);
+        let first_path = write("concat-first.tbf", &first);
+        let second_path = write("concat-second.tbf", &second);
+        let output = temp_file("concat-out.tbf");
+        concat(&args(&[
+            &output,
+            "--align",
+            "0x100",
+            &first_path,
+            &second_path,
+        ]))
+        .unwrap();
+
+        let blob = load(&output).unwrap();
+        let (images, end) = images(blob);
+        assert_eq!(end, blob.len());
+        let layout: Vec<(usize, usize, bool)> = images
+            .iter()
+            .map(|image| {
+                let is_app = image.header.as_ref().unwrap().is_app();
+                (image.offset, image.data.len(), is_app)
+            })
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                (0, first.len(), true),
+                (first.len(), 0x100 - first.len(), false),
+                (0x100, second.len(), true),
+            ]
+        );
+        assert_eq!(images[0].data, &first[..]);
+        assert_eq!(images[2].data, &second[..]);
+
+        // Concatenating again drops the old padding and lays the apps out the
+        // same way.
+        let again = temp_file("concat-again.tbf");
+        concat(&args(&[&again, "--align", "0x100", &output])).unwrap();
+        assert_eq!(fs::read(&again).unwrap(), blob);
+    }
+
+    #[test]
+    fn concat_never_leaves_a_gap_smaller_than_a_padding_header() {
+        // An app 8 bytes short of the alignment leaves a gap too small for a
+        // padding header, so the gap grows by the alignment.
+        let header_size = app_image("first", 0, 1).len() - BINARY_SIZE;
+        let first = app_image("first", (0xf8 - header_size - BINARY_SIZE) as u32, 1);
+        assert_eq!(first.len(), 0xf8);
+        let first_path = write("gap-first.tbf", &first);
+        let output = temp_file("gap-out.tbf");
+        concat(&args(&[
+            &output,
+            "--align",
+            "256",
+            &first_path,
+            &first_path,
+        ]))
+        .unwrap();
+
+        let blob = load(&output).unwrap();
+        let (images, end) = images(blob);
+        assert_eq!(end, blob.len());
+        let layout: Vec<(usize, usize)> = images
+            .iter()
+            .map(|image| (image.offset, image.data.len()))
+            .collect();
+        assert_eq!(layout, vec![(0, 0xf8), (0xf8, 0x108), (0x200, 0xf8)]);
+        assert!(!images[1].header.as_ref().unwrap().is_app());
+    }
+}